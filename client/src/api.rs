@@ -0,0 +1,229 @@
+//! Typed calls to a margo daemon's JSON API, including the ones that
+//! change it, over blocking HTTP. Built with the `api-client` feature;
+//! unlike the rest of the crate, this does its own I/O.
+
+use semver::Version;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{collections::BTreeMap, io, time::Duration};
+use url::Url;
+
+use crate::{common::CrateName, index_entry};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const USER_AGENT: &str = concat!("margo/", env!("CARGO_PKG_VERSION"));
+
+/// Calls to a margo daemon's JSON API. The token, when there is one,
+/// is sent as-is in `Authorization`, as Cargo sends it; failures the
+/// daemon explains come back as [`Error::Refused`] with its error code.
+#[derive(Debug)]
+pub struct Api {
+    agent: ureq::Agent,
+    base_url: Url,
+    token: Option<String>,
+}
+
+impl Api {
+    pub fn new(mut base_url: Url, token: Option<String>) -> Self {
+        // So that the API's paths are joined below it
+        if let Ok(mut segments) = base_url.path_segments_mut() {
+            segments.pop_if_empty().push("");
+        }
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(USER_AGENT)
+            .build();
+
+        Self {
+            agent,
+            base_url,
+            token,
+        }
+    }
+
+    pub fn search(&self, q: &str) -> Result<Vec<SearchHit>, Error> {
+        let mut url = self.url("api/v1/search")?;
+        url.query_pairs_mut().append_pair("q", q);
+
+        let response: SearchResponse = self.call("GET", url, None)?;
+        Ok(response.hits)
+    }
+
+    pub fn get_crate(&self, name: &CrateName) -> Result<CrateInfo, Error> {
+        let url = self.url(&format!("api/v1/crates/{name}"))?;
+        self.call("GET", url, None)
+    }
+
+    /// margo reads everything it needs from the `.crate` file, so the
+    /// metadata Cargo sends ahead of it is left empty.
+    pub fn publish(&self, crate_file: &[u8]) -> Result<PublishWarnings, Error> {
+        let metadata = b"{}";
+
+        let mut body = Vec::with_capacity(8 + metadata.len() + crate_file.len());
+        for part in [&metadata[..], crate_file] {
+            body.extend_from_slice(&(part.len() as u32).to_le_bytes());
+            body.extend_from_slice(part);
+        }
+
+        let url = self.url("api/v1/crates/new")?;
+        let response: PublishResponse = self.call("PUT", url, Some(&body))?;
+        Ok(response.warnings)
+    }
+
+    pub fn yank(&self, name: &CrateName, version: &Version, yanked: bool) -> Result<(), Error> {
+        let (method, action) = if yanked {
+            ("DELETE", "yank")
+        } else {
+            ("PUT", "unyank")
+        };
+
+        let url = self.url(&format!("api/v1/crates/{name}/{version}/{action}"))?;
+        self.call::<serde::de::IgnoredAny>(method, url, None)?;
+        Ok(())
+    }
+
+    /// The downloads the daemon has counted since it started.
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let url = self.url("api/v1/status")?;
+        self.call("GET", url, None)
+    }
+
+    fn url(&self, path: &str) -> Result<Url, Error> {
+        self.base_url.join(path).context(error::UrlSnafu)
+    }
+
+    fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        url: Url,
+        body: Option<&[u8]>,
+    ) -> Result<T, Error> {
+        use error::*;
+
+        let mut request = self.agent.request_url(method, &url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", token);
+        }
+
+        let response = match body {
+            Some(body) => request.send_bytes(body),
+            None => request.call(),
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_json::<RefusalBody>().unwrap_or_default();
+                return RefusedSnafu {
+                    url,
+                    status,
+                    code: body.code,
+                    message: body.message,
+                }
+                .fail();
+            }
+            Err(e) => return Err(e).context(RequestSnafu { url }),
+        };
+
+        response.into_json().context(ResponseSnafu { url })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub name: String,
+    pub newest_version: Option<Version>,
+
+    /// The name of the registry hosting the crate, which differs for
+    /// hits from federated peers.
+    pub registry: String,
+
+    pub index: Url,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    hits: Vec<SearchHit>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrateInfo {
+    pub name: CrateName,
+    pub versions: Vec<VersionInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionInfo {
+    #[serde(flatten)]
+    pub entry: index_entry::Root,
+
+    pub downloads: u64,
+    pub readme: Option<Url>,
+    pub docs: Option<Url>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishResponse {
+    warnings: PublishWarnings,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PublishWarnings {
+    pub invalid_categories: Vec<String>,
+    pub invalid_badges: Vec<String>,
+    pub other: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    /// By crate, then by version.
+    #[serde(default)]
+    pub downloads: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+/// What the daemon says when it refuses a request.
+#[derive(Debug, Default, Deserialize)]
+struct RefusalBody {
+    code: String,
+    message: String,
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not build a URL of the API"))]
+    Url { source: url::ParseError },
+
+    #[snafu(display("Could not call {url}"))]
+    Request {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: Url,
+    },
+
+    #[snafu(display("{url} answered {status} {code}: {message}"))]
+    Refused {
+        url: Url,
+        status: u16,
+        code: String,
+        message: String,
+    },
+
+    #[snafu(display("Could not deserialize the response from {url}"))]
+    Response { source: io::Error, url: Url },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Url { .. } => "E_BAD_URL",
+            Self::Request { .. } | Self::Response { .. } => "E_UPSTREAM",
+            Self::Refused { status, .. } => match status {
+                401 => "E_UNAUTHORIZED",
+                403 => "E_FORBIDDEN",
+                _ => "E_UPSTREAM",
+            },
+        }
+    }
+}
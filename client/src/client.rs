@@ -0,0 +1,220 @@
+//! A read-only client for margo registries.
+//!
+//! [`Client`] performs no I/O of its own: it tells the caller which
+//! URLs to fetch and interprets the bytes that come back. Keeping it
+//! free of filesystem and async runtime requirements means it can be
+//! compiled to `wasm32-unknown-unknown` and driven by the browser's
+//! `fetch`, while the CLI drives it with whatever it has at hand.
+
+use semver::Version;
+use snafu::prelude::*;
+use url::Url;
+
+use crate::{common::CrateName, config_json, index_entry, Index};
+
+#[derive(Debug)]
+pub struct Client {
+    base_url: Url,
+    config: config_json::Root,
+}
+
+impl Client {
+    /// The URL of the registry's `config.json`, which must be fetched
+    /// before a [`Client`] can be created.
+    pub fn config_url(base_url: &Url) -> Result<Url, Error> {
+        use error::*;
+
+        base_url.join("config.json").context(UrlSnafu)
+    }
+
+    pub fn new(base_url: Url, config_json: &[u8]) -> Result<Self, Error> {
+        use error::*;
+
+        let config = serde_json::from_slice(config_json).context(ConfigSnafu)?;
+
+        Ok(Self { base_url, config })
+    }
+
+    pub fn auth_required(&self) -> bool {
+        self.config.auth_required
+    }
+
+    /// The URL of the sparse index file for the crate, lowercase as
+    /// Cargo asks for it.
+    pub fn index_url(&self, name: &CrateName) -> Result<Url, Error> {
+        let mut url = self.base_url.clone();
+
+        url.path_segments_mut()
+            .map_err(|_| Error::CannotBeABase {
+                url: self.base_url.clone(),
+            })?
+            .pop_if_empty()
+            .extend(
+                name.prefix_directories()
+                    .iter()
+                    .map(|d| d.to_ascii_lowercase()),
+            )
+            .push(&name.as_str().to_ascii_lowercase());
+
+        Ok(url)
+    }
+
+    pub fn parse_index(&self, data: &[u8]) -> Result<Index, Error> {
+        use error::*;
+
+        crate::parse_index_lines(data).context(IndexSnafu)
+    }
+
+    /// The URL of the `.crate` file for the index entry, following
+    /// the same template substitution rules that Cargo uses.
+    pub fn crate_url(&self, entry: &index_entry::Root) -> Result<Url, Error> {
+        use error::*;
+
+        let url = download_url(&self.config.dl, &entry.name, &entry.vers, &entry.cksum);
+
+        Url::parse(&url).context(UrlSnafu)
+    }
+
+    /// Check that downloaded crate data matches the checksum recorded
+    /// in the index.
+    pub fn verify(entry: &index_entry::Root, data: &[u8]) -> Result<(), Error> {
+        use sha2::Digest;
+
+        let actual = hex::encode(sha2::Sha256::digest(data));
+
+        ensure!(
+            actual.eq_ignore_ascii_case(&entry.cksum),
+            error::ChecksumSnafu {
+                name: entry.name.clone(),
+                version: entry.vers.clone(),
+                expected: &entry.cksum,
+                actual,
+            }
+        );
+
+        Ok(())
+    }
+}
+
+/// The placeholders Cargo fills in a `dl` template.
+const DL_MARKERS: [&str; 5] = [
+    "{crate}",
+    "{version}",
+    "{prefix}",
+    "{lowerprefix}",
+    "{sha256-checksum}",
+];
+
+/// Fills in a `dl` template from a registry's `config.json`, or
+/// Cargo's default layout when it has no markers.
+pub fn download_url(dl: &str, name: &CrateName, version: &Version, cksum: &str) -> String {
+    let has_markers = DL_MARKERS.iter().any(|m| dl.contains(m));
+
+    if has_markers {
+        let prefix = name.prefix_directories().join("/");
+
+        dl.replace("{crate}", name.as_str())
+            .replace("{version}", &version.to_string())
+            .replace("{lowerprefix}", &prefix.to_ascii_lowercase())
+            .replace("{prefix}", &prefix)
+            .replace("{sha256-checksum}", cksum)
+    } else {
+        format!("{}/{name}/{version}/download", dl.trim_end_matches('/'))
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not parse the registry's public configuration"))]
+    Config { source: serde_json::Error },
+
+    #[snafu(display("The registry URL {url} cannot contain a path"))]
+    CannotBeABase { url: Url },
+
+    #[snafu(display("Could not build a registry URL"))]
+    Url { source: url::ParseError },
+
+    #[snafu(display("Could not parse the crate's index file"))]
+    Index { source: crate::ParseIndexError },
+
+    #[snafu(display("The checksum of {name} {version} is {actual}, but the index expects {expected}"))]
+    Checksum {
+        name: CrateName,
+        version: Version,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Config { .. } => "E_CONFIG_INVALID",
+            Self::CannotBeABase { .. } | Self::Url { .. } => "E_BAD_URL",
+            Self::Index { .. } => "E_INDEX_CORRUPT",
+            Self::Checksum { .. } => "E_BAD_CHECKSUM",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client(dl: &str) -> Client {
+        let config = format!(r#"{{"dl":"{dl}","api":null,"auth-required":false}}"#);
+        Client::new("https://example.com/reg/".parse().unwrap(), config.as_bytes()).unwrap()
+    }
+
+    fn entry(name: &str, cksum: &str) -> index_entry::Root {
+        let line = format!(
+            r#"{{"name":"{name}","vers":"1.2.3","deps":[],"cksum":"{cksum}","features":{{}},"yanked":false,"v":2}}"#
+        );
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn index_url_uses_prefix_directories() {
+        let c = client("https://example.com/reg/crates/{lowerprefix}/{crate}/{version}.crate");
+
+        let url = c.index_url(&"serde".parse().unwrap()).unwrap();
+        assert_eq!("https://example.com/reg/se/rd/serde", url.as_str());
+
+        let url = c.index_url(&"syn".parse().unwrap()).unwrap();
+        assert_eq!("https://example.com/reg/3/s/syn", url.as_str());
+    }
+
+    #[test]
+    fn crate_url_substitutes_markers() {
+        let c = client("https://example.com/reg/crates/{lowerprefix}/{crate}/{version}.crate");
+
+        let url = c.crate_url(&entry("Serde", "00")).unwrap();
+        assert_eq!(
+            "https://example.com/reg/crates/se/rd/Serde/1.2.3.crate",
+            url.as_str(),
+        );
+    }
+
+    #[test]
+    fn crate_url_without_markers_uses_the_default_layout() {
+        let c = client("https://example.com/api/v1/crates");
+
+        let url = c.crate_url(&entry("serde", "00")).unwrap();
+        assert_eq!(
+            "https://example.com/api/v1/crates/serde/1.2.3/download",
+            url.as_str(),
+        );
+    }
+
+    #[test]
+    fn verify_rejects_mismatched_checksums() {
+        // SHA-256 of the empty string
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        Client::verify(&entry("serde", empty), b"").unwrap();
+
+        let e = Client::verify(&entry("serde", empty), b"tampered").unwrap_err();
+        assert_eq!("E_BAD_CHECKSUM", e.code());
+    }
+}
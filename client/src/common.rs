@@ -0,0 +1,324 @@
+//! Crate names and Rust versions, as the index spells them.
+
+use ascii::{AsciiChar, AsciiStr, AsciiString};
+use semver::Version;
+use serde::{de::Error, Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    borrow::Cow,
+    fmt, ops,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The longest name crates.io accepts.
+const MAX_UNTRUSTED_LEN: usize = 64;
+
+/// Contains only alphanumeric, `-`, or `_` characters.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CrateName(AsciiString);
+
+impl CrateName {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Parses a name that came from a peer, an upstream or a
+    /// snapshot rather than from the registry's own users. On top
+    /// of the usual rules, the name must be no longer than
+    /// crates.io allows, and must not be punycode, which some tools
+    /// show as the Unicode name it encodes.
+    pub fn from_untrusted(name: &str) -> Result<Self, CrateNameError> {
+        use crate_name_error::*;
+
+        let name = Self::try_from(name)?;
+
+        let len = name.len();
+        ensure!(len <= MAX_UNTRUSTED_LEN, TooLongSnafu { len });
+        let prefix = name.as_str().get(..4);
+        let punycode = prefix.is_some_and(|p| p.eq_ignore_ascii_case("xn--"));
+        ensure!(!punycode, PunycodeSnafu);
+
+        Ok(name)
+    }
+
+    pub fn prefix_directories(&self) -> Vec<&str> {
+        match self.len() {
+            0 => unreachable!(),
+            1 => vec!["1"],
+            2 => vec!["2"],
+            3 => {
+                let a = &self[0..1];
+
+                vec!["3", a.as_str()]
+            }
+            _ => {
+                let ab = &self[0..2];
+                let cd = &self[2..4];
+
+                vec![ab.as_str(), cd.as_str()]
+            }
+        }
+    }
+
+    pub fn append_prefix_directories(&self, index_path: &mut PathBuf) {
+        index_path.extend(self.prefix_directories());
+    }
+
+    /// Where the crate's index file is, relative to the registry:
+    /// lowercase, as Cargo asks for it.
+    pub fn index_path(&self) -> PathBuf {
+        let mut index_path = PathBuf::from_iter(
+            self.prefix_directories()
+                .iter()
+                .map(|d| d.to_ascii_lowercase()),
+        );
+        index_path.push(self.as_str().to_ascii_lowercase());
+        index_path
+    }
+}
+
+impl fmt::Display for CrateName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for CrateName {
+    type Err = CrateNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.try_into()
+    }
+}
+
+impl TryFrom<&str> for CrateName {
+    type Error = CrateNameError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.to_owned().try_into()
+    }
+}
+
+impl TryFrom<String> for CrateName {
+    type Error = CrateNameError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        AsciiString::from_ascii(value)
+            .map_err(|e| e.ascii_error())?
+            .try_into()
+    }
+}
+
+impl TryFrom<AsciiString> for CrateName {
+    type Error = CrateNameError;
+
+    fn try_from(value: AsciiString) -> Result<Self, Self::Error> {
+        use crate_name_error::*;
+
+        let first = value.first().context(EmptySnafu)?;
+        ensure!(first.is_alphabetic(), InitialAlphaSnafu);
+
+        if let Some(chr) = value.chars().find(|&chr| !valid_crate_name_char(chr)) {
+            return ContainsInvalidCharSnafu { chr }.fail();
+        }
+
+        ensure!(
+            !is_windows_device_name(value.as_str()),
+            WindowsDeviceSnafu {
+                name: value.as_str()
+            }
+        );
+
+        Ok(Self(value))
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum CrateNameError {
+    #[snafu(display("The crate name cannot be empty"))]
+    Empty,
+
+    #[snafu(display("The crate name must start with an alphabetic character"))]
+    InitialAlpha,
+
+    #[snafu(display("The crate name must only contain alphanumeric characters, hyphen (-) or underscore (_), not {chr}"))]
+    ContainsInvalidChar { chr: char },
+
+    #[snafu(display("The crate name `{name}` is a reserved file name on Windows"))]
+    WindowsDevice { name: String },
+
+    #[snafu(display("The crate name is {len} characters long, more than {MAX_UNTRUSTED_LEN}"))]
+    TooLong { len: usize },
+
+    #[snafu(display("The crate name must not be punycode (start with `xn--`)"))]
+    Punycode,
+
+    #[snafu(transparent)]
+    NotAscii { source: ascii::AsAsciiStrError },
+}
+
+impl<'de> Deserialize<'de> for CrateName {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let ascii: AsciiString = Deserialize::deserialize(deserializer)?;
+        Self::try_from(ascii).map_err(D::Error::custom)
+    }
+}
+
+impl ops::Index<ops::Range<usize>> for CrateName {
+    type Output = AsciiStr;
+
+    fn index(&self, index: ops::Range<usize>) -> &Self::Output {
+        self.0.index(index)
+    }
+}
+
+impl AsRef<Path> for CrateName {
+    fn as_ref(&self) -> &Path {
+        self.0.as_str().as_ref()
+    }
+}
+
+fn valid_crate_name_char(chr: AsciiChar) -> bool {
+    chr.is_alphanumeric() || chr == AsciiChar::UnderScore || chr == AsciiChar::Minus
+}
+
+/// Windows cannot create a file or directory with one of these
+/// names, whatever its case, so neither the index file nor the
+/// crate directory could exist there. Cargo and crates.io refuse
+/// them too.
+fn is_windows_device_name(name: &str) -> bool {
+    const DEVICES: &[&str] = &["con", "prn", "aux", "nul"];
+    const NUMBERED: &[&str] = &["com", "lpt"];
+
+    let name = name.to_ascii_lowercase();
+    let numbered = name.len() == 4
+        && NUMBERED.contains(&&name[..3])
+        && matches!(name.as_bytes()[3], b'1'..=b'9');
+
+    DEVICES.contains(&name.as_str()) || numbered
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RustVersion(Version);
+
+impl FromStr for RustVersion {
+    type Err = RustVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use rust_version_error::*;
+
+        let v: Version = match s.parse() {
+            Ok(v) => v,
+            Err(e) => {
+                let version = [s, ".0"].concat();
+                match version.parse() {
+                    Ok(v) => v,
+                    Err(_) => return Err(e)?,
+                }
+            }
+        };
+
+        ensure!(v.pre.is_empty(), PrereleaseSnafu);
+        ensure!(v.build.is_empty(), BuildSnafu);
+
+        Ok(Self(v))
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum RustVersionError {
+    #[snafu(transparent)]
+    Semver { source: semver::Error },
+
+    #[snafu(display("May not specify a prerelease version"))]
+    Prerelease,
+
+    #[snafu(display("May not specify a version with build metadata"))]
+    Build,
+}
+
+impl From<RustVersion> for Version {
+    fn from(value: RustVersion) -> Self {
+        value.0
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RustVersion {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let version = Cow::<str>::deserialize(deserializer)?;
+        version.parse().map_err(D::Error::custom)
+    }
+}
+
+impl serde::Serialize for RustVersion {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn windows_device_names_are_not_crate_names() {
+        for name in ["con", "NUL", "Aux", "prn", "com1", "LPT9"] {
+            assert!(name.parse::<CrateName>().is_err(), "{name}");
+        }
+        for name in ["console", "com0", "lpt10", "nul_", "a"] {
+            assert!(name.parse::<CrateName>().is_ok(), "{name}");
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn device_names_are_refused(name in "(?i)(con|prn|aux|nul|com[1-9]|lpt[1-9])") {
+            prop_assert!(is_windows_device_name(&name));
+            prop_assert!(CrateName::from_untrusted(&name).is_err());
+        }
+
+        #[test]
+        fn only_device_names_are_device_names(name in "(?i)[a-z][a-z0-9]{0,5}") {
+            let lower = name.to_ascii_lowercase();
+            let device = matches!(lower.as_str(), "con" | "prn" | "aux" | "nul")
+                || (lower.len() == 4
+                    && (lower.starts_with("com") || lower.starts_with("lpt"))
+                    && lower.as_bytes()[3].is_ascii_digit()
+                    && lower.as_bytes()[3] != b'0');
+            prop_assert_eq!(device, is_windows_device_name(&name));
+        }
+
+        #[test]
+        fn untrusted_names_are_plain(name in "(?i)(xn--)?[a-z0-9_.-]{0,70}|\\PC{0,20}") {
+            let Ok(parsed) = CrateName::from_untrusted(&name) else {
+                return Ok(());
+            };
+
+            prop_assert_eq!(name.as_str(), parsed.as_str());
+            prop_assert!(parsed.len() <= MAX_UNTRUSTED_LEN);
+            prop_assert!(name.starts_with(|c: char| c.is_ascii_alphabetic()));
+            prop_assert!(name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
+            prop_assert!(!name.to_ascii_lowercase().starts_with("xn--"));
+            prop_assert!(!is_windows_device_name(&name));
+        }
+    }
+}
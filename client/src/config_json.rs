@@ -0,0 +1,22 @@
+//! A registry's `config.json`, as Cargo reads it.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Root {
+    // This field cannot be a `url::Url` because that type
+    // percent-escapes the `{` and `}` characters. Cargo performs
+    // string-replacement on this value based on those literal `{`
+    // and `}` characters.
+    pub dl: String,
+
+    pub api: Option<String>, // Modified
+
+    /// A private registry requires all operations to be authenticated.
+    ///
+    /// This includes API requests, crate downloads and sparse
+    /// index updates.
+    #[serde(default)]
+    pub auth_required: bool,
+}
@@ -0,0 +1,156 @@
+//! What the registry knows about a version beyond Cargo's index schema.
+//!
+//! Each index entry may carry an `x-gnostr` object, which Cargo ignores
+//! like every field it does not know:
+//!
+//! ```json
+//! "x-gnostr": {
+//!   "cid": "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku",
+//!   "nostr-event-id": "5c83da77af1dec6d7289834998ad7aafbd9e2191396d75ec3cc27f5a77226f36",
+//!   "provenance": [
+//!     { "predicate-type": "https://slsa.dev/provenance/v1", "digest": "9f86d0..." }
+//!   ],
+//!   "artifacts": [
+//!     { "target": "x86_64-unknown-linux-gnu", "file": "tool", "cksum": "2c26b4..." }
+//!   ]
+//! }
+//! ```
+//!
+//! - `cid` is the CIDv1 of the `.crate` file as stored: its raw bytes
+//!   hashed with SHA-256, in base32. It is set when the version is
+//!   added.
+//! - `nostr-event-id` is the hex id of a nostr event about the version,
+//!   for tooling that publishes one.
+//! - `provenance` lists the attestations attached to the version, by
+//!   predicate type and the SHA-256 of the attestation, which names its
+//!   file.
+//! - `artifacts` lists the version's prebuilt artifacts with their
+//!   SHA-256.
+//!
+//! Every field is optional, and the object is left out when it would
+//! be empty. Fields this version of margo does not know are kept as
+//! they are.
+
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Extensions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nostr_event_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provenance: Vec<Provenance>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<Artifact>,
+
+    #[serde(flatten)]
+    pub other: BTreeMap<String, serde_json::Value>,
+}
+
+impl Extensions {
+    pub fn is_empty(&self) -> bool {
+        self.cid.is_none()
+            && self.nostr_event_id.is_none()
+            && self.provenance.is_empty()
+            && self.artifacts.is_empty()
+            && self.other.is_empty()
+    }
+
+    /// Takes everything from the extensions of the version this one
+    /// replaces, except what depends on the `.crate` file itself.
+    pub fn inherit(&mut self, replaced: &Self) {
+        *self = Self {
+            cid: self.cid.take(),
+            ..replaced.clone()
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Provenance {
+    pub predicate_type: String,
+
+    /// The SHA-256 of the attestation, hex encoded.
+    pub digest: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Artifact {
+    pub target: String,
+    pub file: String,
+
+    /// The SHA-256 of the file, hex encoded.
+    pub cksum: String,
+}
+
+/// The CIDv1 of the data as a raw block with a SHA-256 multihash,
+/// multibase encoded as lowercase base32.
+pub fn cid_of(data: &[u8]) -> String {
+    const VERSION: u8 = 0x01;
+    const RAW: u8 = 0x55;
+    const SHA2_256: u8 = 0x12;
+
+    let digest = sha2::Sha256::digest(data);
+    let mut cid = vec![VERSION, RAW, SHA2_256, digest.len() as u8];
+    cid.extend_from_slice(&digest);
+
+    format!("b{}", base32(&cid))
+}
+
+/// RFC 4648 base32, lowercase and without padding.
+fn base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+    let mut encoded = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(char::from(ALPHABET[(buffer >> bits) as usize & 31]));
+        }
+    }
+    if bits > 0 {
+        encoded.push(char::from(ALPHABET[(buffer << (5 - bits)) as usize & 31]));
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cids_name_the_raw_bytes() {
+        assert_eq!(
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku",
+            cid_of(b"")
+        );
+        assert_eq!(
+            "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq",
+            cid_of(b"hello")
+        );
+
+        let extensions = serde_json::from_value::<Extensions>(serde_json::json!({
+            "cid": "bafkrei",
+            "x-newer": [1, 2],
+        }))
+        .unwrap();
+        assert_eq!(
+            serde_json::json!({ "cid": "bafkrei", "x-newer": [1, 2] }),
+            serde_json::to_value(&extensions).unwrap()
+        );
+        assert!(Extensions::default().is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//! One line of an index file, as Cargo reads it.
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use url::Url;
+
+use crate::{
+    common::{CrateName, RustVersion},
+    extensions::Extensions,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Root {
+    /// The name of the package.
+    pub name: CrateName,
+
+    /// The version of the package this row is describing.
+    ///
+    /// This must be a valid version number according to the
+    /// Semantic Versioning 2.0.0 spec at https://semver.org/.
+    pub vers: Version,
+
+    /// Direct dependencies of the package.
+    pub deps: Vec<Dependency>,
+
+    /// A SHA256 checksum of the `.crate` file.
+    pub cksum: String,
+
+    /// Set of features defined for the package.
+    ///
+    /// Each feature maps to features or dependencies it enables.
+    pub features: BTreeMap<String, Vec<String>>,
+
+    /// Boolean of whether or not this version has been yanked.
+    pub yanked: bool,
+
+    /// Whether an operator has quarantined this version, so that
+    /// its `.crate` file is not served. Not part of Cargo's schema;
+    /// Cargo ignores it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub withheld: bool,
+
+    /// A SHA256 checksum of the `.crate` file before the registry
+    /// encrypted it, when it did; `cksum` is then that of the encrypted
+    /// file. Not part of Cargo's schema; Cargo ignores it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decrypted_cksum: Option<String>,
+
+    /// The `links` value from the package's manifest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub links: Option<String>,
+
+    /// The schema version of this entry.
+    //
+    /// If this not specified, it should be interpreted as the default of 1.
+    //
+    /// Cargo (starting with version 1.51) will ignore versions it does not
+    /// recognize. This provides a method to safely introduce changes to index
+    /// entries and allow older versions of cargo to ignore newer entries it
+    /// doesn't understand. Versions older than 1.51 ignore this field, and
+    /// thus may misinterpret the meaning of the index entry.
+    //
+    /// The current values are:
+    //
+    /// * 1: The schema as documented here, not including newer additions.
+    ///   This is honored in Rust version 1.51 and newer.
+    /// * 2: The addition of the `features2` field.
+    ///   This is honored in Rust version 1.60 and newer.
+    pub v: u32,
+
+    /// Features with new, extended syntax, such as namespaced
+    /// features (`dep:`) and weak dependencies (`pkg?/feat`).
+    //
+    /// This is separated from `features` because versions older than 1.19
+    /// will fail to load due to not being able to parse the new syntax, even
+    /// with a `Cargo.lock` file.
+    //
+    /// Cargo will merge any values listed here with the "features" field.
+    //
+    /// If this field is included, the "v" field should be set to at least 2.
+    //
+    /// Registries are not required to use this field for extended feature
+    /// syntax, they are allowed to include those in the "features" field.
+    /// Using this is only necessary if the registry wants to support cargo
+    /// versions older than 1.19, which in practice is only crates.io since
+    /// those older versions do not support other registries.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features2: BTreeMap<String, Vec<String>>,
+
+    /// The minimal supported Rust version
+    ///
+    /// This must be a valid version requirement without an operator (e.g. no `=`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<RustVersion>,
+
+    /// The edition from the package's manifest. Not part of
+    /// Cargo's schema; Cargo ignores it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edition: Option<String>,
+
+    /// What the registry knows about the version beyond this
+    /// schema. Cargo ignores it.
+    #[serde(
+        rename = "x-gnostr",
+        default,
+        skip_serializing_if = "Extensions::is_empty"
+    )]
+    pub extensions: Extensions,
+}
+
+impl Root {
+    /// Whether the version's `rust-version` allows building it
+    /// with the toolchain. Versions that do not declare one are
+    /// assumed to.
+    pub fn builds_with(&self, toolchain: &RustVersion) -> bool {
+        self.rust_version.as_ref().map_or(true, |v| v <= toolchain)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dependency {
+    /// Name of the dependency.
+    ///
+    /// If the dependency is renamed from the original package
+    /// name, this is the new name. The original package name is
+    /// stored in the `package` field.
+    pub name: String,
+
+    /// The SemVer requirement for this dependency.
+    ///
+    /// This must be a valid version requirement defined at
+    /// https://doc.rust-lang.org/cargo/reference/specifying-dependencies.html.
+    pub req: VersionReq,
+
+    /// Features enabled for this dependency.
+    pub features: Vec<String>,
+
+    /// Whether or not this is an optional dependency.
+    pub optional: bool,
+
+    /// Whether or not default features are enabled.
+    pub default_features: bool,
+
+    /// The target platform for the dependency.
+    ///
+    /// A string such as `cfg(windows)`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+
+    /// The dependency kind.
+    ///
+    /// Note: this is a required field, but a small number of entries
+    /// exist in the crates.io index with either a missing or null
+    /// `kind` field due to implementation bugs.
+    pub kind: DependencyKind,
+
+    /// The URL of the index of the registry where this dependency
+    /// is from.
+    ///
+    /// If not specified or null, it is assumed the dependency is
+    /// in the current registry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registry: Option<Url>,
+
+    /// If the dependency is renamed, this is the actual package
+    /// name.
+    ///
+    /// If not specified or null, this dependency is not renamed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyKind {
+    #[allow(unused)]
+    // Stored in the index, but not actually used by Cargo
+    Dev,
+    Build,
+    Normal,
+}
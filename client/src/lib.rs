@@ -0,0 +1,61 @@
+//! Reading margo registries: the index format, crate names, and a
+//! [`Client`](client::Client) that turns what a registry serves into
+//! index entries and checked `.crate` files.
+//!
+//! Nothing here touches the filesystem or needs an async runtime, so
+//! the crate builds for `wasm32-unknown-unknown`. The `margo` binary
+//! and the C interface in `margo-ffi` are built on it, so all three
+//! read the index the same way. The `api` module, which calls a
+//! daemon's JSON API over HTTP, is only built with the `api-client`
+//! feature.
+
+use semver::Version;
+use snafu::prelude::*;
+use std::{collections::BTreeMap, io};
+
+#[cfg(feature = "api-client")]
+pub mod api;
+pub mod client;
+pub mod common;
+pub mod config_json;
+pub mod extensions;
+pub mod index_entry;
+
+/// Every version in an index file.
+pub type Index = BTreeMap<Version, index_entry::Root>;
+
+/// Each entry of the index file and its line number, counting from
+/// zero.
+pub fn index_lines(data: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    data.split(|&b| b == b'\n')
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+}
+
+pub fn parse_index_lines(index_file: &[u8]) -> Result<Index, ParseIndexError> {
+    use parse_index_error::*;
+
+    let mut index = BTreeMap::new();
+
+    for (i, line) in index_lines(index_file) {
+        let entry =
+            serde_json::from_slice::<index_entry::Root>(line).context(ParseSnafu { line: i })?;
+
+        index.insert(entry.vers.clone(), entry);
+    }
+
+    Ok(index)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module, visibility(pub))]
+pub enum ParseIndexError {
+    #[snafu(display("Could not open the file"))]
+    Open { source: io::Error },
+
+    #[snafu(display("Could not parse line {line}"))]
+    Parse {
+        source: serde_json::Error,
+        line: usize,
+    },
+}
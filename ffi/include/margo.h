@@ -0,0 +1,97 @@
+#ifndef MARGO_H
+#define MARGO_H
+
+/* Generated by `cargo xtask ffi-header` from src/lib.rs. Do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum MargoStatus {
+  MARGO_STATUS_OK = 0,
+  // The crate or version is not in the index, or its `.crate` file is
+  // not on disk.
+  MARGO_STATUS_NOT_FOUND = 1,
+  // An argument is null or not UTF-8, or a file could not be parsed.
+  MARGO_STATUS_INVALID = 2,
+  // A file could not be read.
+  MARGO_STATUS_IO = 3,
+  // The `.crate` file does not match the checksum in the index.
+  MARGO_STATUS_MISMATCH = 4,
+} MargoStatus;
+
+// An open registry.
+typedef struct MargoRegistry MargoRegistry;
+
+#ifdef __cplusplus
+extern "C" {
+#endif  // __cplusplus
+
+// Opens the registry in the directory at `path`. Returns null when
+// there is no registry there, or when it needs `margo migrate` first.
+// Close it with `margo_registry_close`.
+//
+// # Safety
+//
+// `path` must be null or point to a NUL-terminated string.
+MargoRegistry *margo_registry_open(const char *path);
+
+// # Safety
+//
+// `registry` must be null or come from `margo_registry_open`, and not
+// have been closed.
+void margo_registry_close(MargoRegistry *registry);
+
+// Writes the greatest version of the crate that is not yanked to
+// `*version`, to be freed with `margo_string_free`.
+//
+// # Safety
+//
+// `registry` must come from `margo_registry_open`, `name` must point
+// to a NUL-terminated string, and `version` to a `char *`.
+MargoStatus margo_registry_newest_version(const MargoRegistry *registry,
+                                          const char *name,
+                                          char **version);
+
+// Writes the path of the version's `.crate` file to `*path`, to be
+// freed with `margo_string_free`. The file may have been moved to a
+// cold tier; `margo_registry_verify` says whether it is there.
+//
+// # Safety
+//
+// `registry` must come from `margo_registry_open`, `name` and
+// `version` must point to NUL-terminated strings, and `path` to a
+// `char *`.
+MargoStatus margo_registry_crate_path(const MargoRegistry *registry,
+                                      const char *name,
+                                      const char *version,
+                                      char **path);
+
+// Checks that the version's `.crate` file matches the checksum in the
+// index.
+//
+// # Safety
+//
+// `registry` must come from `margo_registry_open`, and `name` and
+// `version` must point to NUL-terminated strings.
+MargoStatus margo_registry_verify(const MargoRegistry *registry,
+                                  const char *name,
+                                  const char *version);
+
+// Frees a string written by another function. Null is ignored.
+//
+// # Safety
+//
+// `s` must be null or come from this library, and not have been freed.
+void margo_string_free(char *s);
+
+// Describes the last failure on this thread. The string is owned by
+// the library and valid until the next call that fails.
+const char *margo_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MARGO_H */
@@ -0,0 +1,428 @@
+//! A C interface to margo registries on disk, for build systems that
+//! are not written in Rust.
+//!
+//! The functions are declared in `include/margo.h`, which
+//! `cargo xtask ffi-header` generates from this file. They read the
+//! registry's index and `.crate` files directly, with the same
+//! [`margo_client`] code the `margo` binary uses, and never change them.
+//! Functions that fail return a status other than `MARGO_STATUS_OK`, or
+//! a null pointer, and `margo_last_error` describes the failure.
+//!
+//! ```c
+//! MargoRegistry *r = margo_registry_open("/srv/registry");
+//! char *version = NULL;
+//! char *path = NULL;
+//! if (margo_registry_newest_version(r, "demo", &version) == MARGO_STATUS_OK &&
+//!     margo_registry_crate_path(r, "demo", version, &path) == MARGO_STATUS_OK &&
+//!     margo_registry_verify(r, "demo", version) == MARGO_STATUS_OK) {
+//!     printf("%s\n", path);
+//! } else {
+//!     fprintf(stderr, "%s\n", margo_last_error());
+//! }
+//! margo_string_free(path);
+//! margo_string_free(version);
+//! margo_registry_close(r);
+//! ```
+
+use margo_client::{client::Client, common::CrateName, index_entry, parse_index_lines};
+use semver::Version;
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    fs, io,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+const CONFIG_JSON_NAME: &str = "config.json";
+const MARGO_CONFIG_NAME: &str = "margo-config.toml";
+const CRATE_DIR_NAME: &str = "crates";
+
+/// The first registry format with index files at lowercase paths,
+/// which is where this looks for them. `margo migrate` upgrades older
+/// registries.
+const LOWERCASE_INDEX_FORMAT: u32 = 2;
+
+/// An open registry.
+pub struct MargoRegistry {
+    path: PathBuf,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MargoStatus {
+    Ok = 0,
+
+    /// The crate or version is not in the index, or its `.crate` file is
+    /// not on disk.
+    NotFound = 1,
+
+    /// An argument is null or not UTF-8, or a file could not be parsed.
+    Invalid = 2,
+
+    /// A file could not be read.
+    Io = 3,
+
+    /// The `.crate` file does not match the checksum in the index.
+    Mismatch = 4,
+}
+
+#[derive(Debug)]
+struct Failure {
+    status: MargoStatus,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: MargoStatus, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn io(path: &Path, e: io::Error) -> Self {
+        let status = match e.kind() {
+            io::ErrorKind::NotFound => MargoStatus::NotFound,
+            _ => MargoStatus::Io,
+        };
+        Self::new(status, format!("Could not read {}: {e}", path.display()))
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn finish(f: impl FnOnce() -> Result<(), Failure>) -> MargoStatus {
+    match f() {
+        Ok(()) => MargoStatus::Ok,
+        Err(failure) => {
+            let status = failure.status;
+            set_last_error(failure.message);
+            status
+        }
+    }
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+}
+
+impl MargoRegistry {
+    fn open(path: PathBuf) -> Result<Self, Failure> {
+        let config = path.join(CONFIG_JSON_NAME);
+        fs::metadata(&config).map_err(|e| Failure::io(&config, e))?;
+
+        let format = registry_format(&path.join(MARGO_CONFIG_NAME))?;
+        if format < LOWERCASE_INDEX_FORMAT {
+            let message = format!(
+                "The registry is in format {format}, from before index files were at \
+                 lowercase paths; upgrade it with `margo migrate`"
+            );
+            return Err(Failure::new(MargoStatus::Invalid, message));
+        }
+
+        Ok(Self { path })
+    }
+
+    /// The versions in the crate's index file that the registry serves.
+    fn index(&self, name: &str) -> Result<Vec<index_entry::Root>, Failure> {
+        let name = name.parse::<CrateName>().map_err(|e| {
+            let message = format!("`{name}` is not a crate name: {e}");
+            Failure::new(MargoStatus::Invalid, message)
+        })?;
+
+        let path = self.path.join(name.index_path());
+        let data = fs::read(&path).map_err(|e| Failure::io(&path, e))?;
+        let index = parse_index_lines(&data).map_err(|e| {
+            let message = format!("Could not parse {}: {e}", path.display());
+            Failure::new(MargoStatus::Invalid, message)
+        })?;
+
+        Ok(index.into_values().filter(|e| !e.withheld).collect())
+    }
+
+    fn entry(&self, name: &str, version: &str) -> Result<index_entry::Root, Failure> {
+        let version = Version::parse(version).map_err(|e| {
+            let message = format!("`{version}` is not a version: {e}");
+            Failure::new(MargoStatus::Invalid, message)
+        })?;
+
+        self.index(name)?
+            .into_iter()
+            .find(|entry| entry.vers == version)
+            .ok_or_else(|| {
+                let message = format!("{name} {version} is not in the registry");
+                Failure::new(MargoStatus::NotFound, message)
+            })
+    }
+
+    /// The greatest version that is not yanked, as Cargo would pick.
+    fn newest_version(&self, name: &str) -> Result<Version, Failure> {
+        self.index(name)?
+            .into_iter()
+            .filter(|entry| !entry.yanked)
+            .map(|entry| entry.vers)
+            .max()
+            .ok_or_else(|| {
+                let message = format!("{name} has no versions that are not yanked");
+                Failure::new(MargoStatus::NotFound, message)
+            })
+    }
+
+    /// Where the `.crate` file is, spelled as the index spells the name.
+    fn crate_path(&self, entry: &index_entry::Root) -> PathBuf {
+        let mut path = self.path.join(CRATE_DIR_NAME);
+        entry.name.append_prefix_directories(&mut path);
+        path.push(&entry.name);
+        path.push(format!("{}.crate", entry.vers));
+        path
+    }
+
+    fn verify(&self, entry: &index_entry::Root) -> Result<(), Failure> {
+        let path = self.crate_path(entry);
+        let data = fs::read(&path).map_err(|e| Failure::io(&path, e))?;
+
+        Client::verify(entry, &data).map_err(|e| Failure::new(MargoStatus::Mismatch, e.to_string()))
+    }
+}
+
+/// The `format` margo records at the top of the registry's
+/// configuration, which is 1 when there is none.
+fn registry_format(config_path: &Path) -> Result<u32, Failure> {
+    let config = fs::read_to_string(config_path).map_err(|e| Failure::io(config_path, e))?;
+
+    let format = config
+        .lines()
+        .take_while(|l| !l.trim_start().starts_with('['))
+        .filter_map(|l| l.split_once('='))
+        .find(|(key, _)| key.trim() == "format")
+        .map(|(_, value)| value.trim());
+
+    match format {
+        Some(format) => format.parse().map_err(|_| {
+            let message = format!("`{format}` in {} is not a format", config_path.display());
+            Failure::new(MargoStatus::Invalid, message)
+        }),
+        None => Ok(1),
+    }
+}
+
+/// # Safety
+///
+/// `s` must be null or point to a NUL-terminated string.
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Result<&'a str, Failure> {
+    if s.is_null() {
+        return Err(Failure::new(
+            MargoStatus::Invalid,
+            format!("The {what} is null"),
+        ));
+    }
+
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| Failure::new(MargoStatus::Invalid, format!("The {what} is not UTF-8")))
+}
+
+/// # Safety
+///
+/// `registry` must be null or come from `margo_registry_open`, and not
+/// have been closed.
+unsafe fn registry_arg<'a>(registry: *const MargoRegistry) -> Result<&'a MargoRegistry, Failure> {
+    registry
+        .as_ref()
+        .ok_or_else(|| Failure::new(MargoStatus::Invalid, "The registry is null"))
+}
+
+/// # Safety
+///
+/// `out` must be null or point to a `char *` that can be written.
+unsafe fn write_string(out: *mut *mut c_char, s: String) -> Result<(), Failure> {
+    if out.is_null() {
+        return Err(Failure::new(MargoStatus::Invalid, "The output is null"));
+    }
+
+    let s = CString::new(s)
+        .map_err(|_| Failure::new(MargoStatus::Invalid, "The string contains a NUL byte"))?;
+    *out = s.into_raw();
+    Ok(())
+}
+
+/// Opens the registry in the directory at `path`. Returns null when
+/// there is no registry there, or when it needs `margo migrate` first.
+/// Close it with `margo_registry_close`.
+///
+/// # Safety
+///
+/// `path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn margo_registry_open(path: *const c_char) -> *mut MargoRegistry {
+    let registry = str_arg(path, "path").and_then(|p| MargoRegistry::open(p.into()));
+
+    match registry {
+        Ok(registry) => Box::into_raw(Box::new(registry)),
+        Err(failure) => {
+            set_last_error(failure.message);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// # Safety
+///
+/// `registry` must be null or come from `margo_registry_open`, and not
+/// have been closed.
+#[no_mangle]
+pub unsafe extern "C" fn margo_registry_close(registry: *mut MargoRegistry) {
+    if !registry.is_null() {
+        drop(Box::from_raw(registry));
+    }
+}
+
+/// Writes the greatest version of the crate that is not yanked to
+/// `*version`, to be freed with `margo_string_free`.
+///
+/// # Safety
+///
+/// `registry` must come from `margo_registry_open`, `name` must point
+/// to a NUL-terminated string, and `version` to a `char *`.
+#[no_mangle]
+pub unsafe extern "C" fn margo_registry_newest_version(
+    registry: *const MargoRegistry,
+    name: *const c_char,
+    version: *mut *mut c_char,
+) -> MargoStatus {
+    finish(|| {
+        let registry = registry_arg(registry)?;
+        let name = str_arg(name, "name")?;
+
+        let newest = registry.newest_version(name)?;
+        write_string(version, newest.to_string())
+    })
+}
+
+/// Writes the path of the version's `.crate` file to `*path`, to be
+/// freed with `margo_string_free`. The file may have been moved to a
+/// cold tier; `margo_registry_verify` says whether it is there.
+///
+/// # Safety
+///
+/// `registry` must come from `margo_registry_open`, `name` and
+/// `version` must point to NUL-terminated strings, and `path` to a
+/// `char *`.
+#[no_mangle]
+pub unsafe extern "C" fn margo_registry_crate_path(
+    registry: *const MargoRegistry,
+    name: *const c_char,
+    version: *const c_char,
+    path: *mut *mut c_char,
+) -> MargoStatus {
+    finish(|| {
+        let registry = registry_arg(registry)?;
+        let name = str_arg(name, "name")?;
+        let version = str_arg(version, "version")?;
+
+        let entry = registry.entry(name, version)?;
+        let crate_path = registry.crate_path(&entry);
+        let crate_path = crate_path
+            .to_str()
+            .ok_or_else(|| Failure::new(MargoStatus::Invalid, "The path is not UTF-8"))?;
+        write_string(path, crate_path.to_owned())
+    })
+}
+
+/// Checks that the version's `.crate` file matches the checksum in the
+/// index.
+///
+/// # Safety
+///
+/// `registry` must come from `margo_registry_open`, and `name` and
+/// `version` must point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn margo_registry_verify(
+    registry: *const MargoRegistry,
+    name: *const c_char,
+    version: *const c_char,
+) -> MargoStatus {
+    finish(|| {
+        let registry = registry_arg(registry)?;
+        let name = str_arg(name, "name")?;
+        let version = str_arg(version, "version")?;
+
+        let entry = registry.entry(name, version)?;
+        registry.verify(&entry)
+    })
+}
+
+/// Frees a string written by another function. Null is ignored.
+///
+/// # Safety
+///
+/// `s` must be null or come from this library, and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn margo_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Describes the last failure on this thread. The string is owned by
+/// the library and valid until the next call that fails.
+#[no_mangle]
+pub extern "C" fn margo_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn versions_are_found_and_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let data = b"not really a crate";
+        let cksum = "ac1c15fc6b29f3fa1d02d334864db09f40eb7527d2c20125aaf7847b74fb57bd";
+
+        let index = dir.join("de/mo/demo");
+        fs::create_dir_all(index.parent().unwrap()).unwrap();
+        fs::write(dir.join(CONFIG_JSON_NAME), "{}").unwrap();
+        fs::write(dir.join(MARGO_CONFIG_NAME), "version = \"1\"\n").unwrap();
+        let old = MargoRegistry::open(dir.to_path_buf()).unwrap_err();
+        assert_eq!(MargoStatus::Invalid, old.status);
+        let config = "version = \"1\"\nformat = 3\n[html]\n";
+        fs::write(dir.join(MARGO_CONFIG_NAME), config).unwrap();
+        let line = |vers: &str, rest: &str| {
+            format!(
+                r#"{{"name":"Demo","vers":"{vers}","deps":[],"cksum":"{cksum}","features":{{}},"v":2,{rest}}}"#
+            )
+        };
+        let lines = [
+            line("1.0.0", r#""yanked":false"#),
+            line("1.1.0", r#""yanked":true"#),
+            line("2.0.0", r#""yanked":false,"withheld":true"#),
+        ];
+        fs::write(&index, lines.join("\n")).unwrap();
+
+        let crate_path = dir.join("crates/De/mo/Demo/1.0.0.crate");
+        fs::create_dir_all(crate_path.parent().unwrap()).unwrap();
+        fs::write(&crate_path, data).unwrap();
+
+        let registry = MargoRegistry::open(dir.to_path_buf()).unwrap();
+        let newest = registry.newest_version("demo").unwrap();
+        assert_eq!("1.0.0", newest.to_string());
+
+        let entry = registry.entry("DEMO", "1.0.0").unwrap();
+        assert_eq!(crate_path, registry.crate_path(&entry));
+        registry.verify(&entry).unwrap();
+
+        let missing = registry.entry("demo", "2.0.0").unwrap_err();
+        assert_eq!(MargoStatus::NotFound, missing.status);
+
+        fs::write(&crate_path, b"tampered").unwrap();
+        let mismatch = registry.verify(&entry).unwrap_err();
+        assert_eq!(MargoStatus::Mismatch, mismatch.status);
+    }
+}
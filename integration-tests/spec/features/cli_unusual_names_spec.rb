@@ -0,0 +1,50 @@
+# frozen_string_literal: true
+
+require 'scratch_space'
+
+RSpec.describe 'Crates with unusual names' do
+  let(:scratch) { ScratchSpace.new }
+  let(:registry) { scratch.registry }
+
+  # One crate at each depth of the index, and one with the longest name
+  # and a long version, for the deepest paths
+  let(:data) do
+    [
+      ['a', '0.1.0'],
+      ['ab', '0.1.0'],
+      ['abc', '0.1.0'],
+      ['Mixed_Case-name', '2.0.0-beta.1'],
+      ["deep-#{'a_b-' * 14}end", "1.0.0-#{'rc.' * 20}1+build.#{'0' * 40}"],
+    ]
+  end
+
+  before do
+    registry.start
+
+    data.each do |name, version|
+      scratch
+        .crate(name:, version:)
+        .lib_rs(%(pub const ID: &str = "#{name}--#{version}";))
+        .publish_to(registry)
+    end
+  end
+
+  after do
+    registry.stop
+    scratch.cleanup
+  end
+
+  it 'lists every crate and version' do
+    output = registry.list
+
+    aggregate_failures do
+      data.each do |name, version|
+        expect(output).to match(/#{Regexp.quote(name)}.*#{Regexp.quote(version)}/)
+      end
+    end
+  end
+
+  it 'finds every crate file where the index says it is' do
+    expect(registry.verify).to be(true)
+  end
+end
@@ -42,6 +42,16 @@ class Registry
     )
   end
 
+  def verify
+    system(
+      MARGO_BINARY,
+      'verify',
+      '--registry',
+      @root.to_s,
+      %i[out err] => File::NULL,
+    )
+  end
+
   def list
     cmd = [
       MARGO_BINARY,
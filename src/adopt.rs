@@ -0,0 +1,130 @@
+//! Taking over a registry made by upstream margo.
+//!
+//! Upstream margo writes the `margo-config.toml`, `config.json`, index
+//! and `crates/` layout that this registry started from, but records no
+//! `format` and keeps no audit log; that is how such a registry is
+//! recognized. `margo adopt` upgrades it in place with the
+//! [migrations](crate::migrate), then checks it the way `margo verify`
+//! does. Neither `config.json` nor the `.crate` files are touched, so
+//! every version keeps its download URL and checksum, and the lockfiles
+//! and caches of its users stay valid.
+
+use snafu::prelude::*;
+use std::{collections::BTreeSet, fs, io, path::Path};
+use url::Url;
+
+use crate::{migrate, progress, OpenError, Registry, VerifyError, VerifyProblem};
+
+/// What was found in a registry made by upstream margo.
+#[derive(Debug)]
+pub struct Layout {
+    pub base_url: Url,
+
+    /// Crates with at least one `.crate` file.
+    pub crates: usize,
+
+    /// `.crate` files.
+    pub versions: usize,
+
+    /// What adopting it changes.
+    pub steps: Vec<&'static migrate::Step>,
+}
+
+/// Whether the registry was made by upstream margo rather than by this
+/// one.
+pub fn is_upstream(registry: &Registry) -> bool {
+    registry.config.format == migrate::unversioned() && !registry.audit_log_path().exists()
+}
+
+/// Reads the layout of the registry at `path` without changing it.
+pub fn inspect(path: &Path) -> Result<Layout, Error> {
+    use error::*;
+
+    let registry = Registry::open_any_format(path)?;
+    ensure!(
+        is_upstream(&registry),
+        NotUpstreamSnafu {
+            format: registry.config.format
+        }
+    );
+
+    let crate_dir = registry.crate_dir();
+    let mut crates = BTreeSet::new();
+    let mut versions = 0;
+    if crate_dir.exists() {
+        for entry in Registry::list_crate_files(&crate_dir) {
+            let path = entry.context(WalkSnafu)?.into_path();
+            if let Some(dir) = path.parent() {
+                crates.insert(dir.to_owned());
+            }
+            versions += 1;
+        }
+    }
+
+    Ok(Layout {
+        base_url: registry.config.base_url.clone(),
+        crates: crates.len(),
+        versions,
+        steps: migrate::migrate(path, true)?,
+    })
+}
+
+/// Upgrades the registry at `path` and verifies it, returning the
+/// problems found. `config.json` must come out unchanged.
+pub fn adopt(path: &Path, mode: progress::Mode) -> Result<Vec<VerifyProblem>, Error> {
+    use error::*;
+
+    inspect(path)?;
+
+    let registry = Registry::open_any_format(path)?;
+    let config_json_path = registry.config_json_path();
+    let config_json = fs::read(&config_json_path).context(ConfigJsonSnafu)?;
+
+    migrate::migrate(path, false)?;
+
+    let after = fs::read(&config_json_path).context(ConfigJsonSnafu)?;
+    ensure!(config_json == after, ConfigJsonChangedSnafu);
+
+    let registry = Registry::open(path)?;
+    Ok(registry.verify(mode)?)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(transparent)]
+    Open { source: OpenError },
+
+    #[snafu(display(
+        "The registry already records format {format}, so it was not made by upstream margo; use `margo migrate`"
+    ))]
+    NotUpstream { format: u32 },
+
+    #[snafu(display("Could not walk the crate files"))]
+    Walk { source: walkdir::Error },
+
+    #[snafu(display("Could not read the registry's config.json"))]
+    ConfigJson { source: io::Error },
+
+    #[snafu(display("Adopting the registry changed its config.json"))]
+    ConfigJsonChanged,
+
+    #[snafu(transparent)]
+    Migrate { source: migrate::Error },
+
+    #[snafu(transparent)]
+    Verify { source: VerifyError },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Open { source } => source.code(),
+            Self::NotUpstream { .. } => "E_NOT_UPSTREAM_REGISTRY",
+            Self::Walk { .. } | Self::ConfigJson { .. } => "E_STORAGE_READ",
+            Self::ConfigJsonChanged => "E_CONFIG_CHANGED",
+            Self::Migrate { source } => source.code(),
+            Self::Verify { source } => source.code(),
+        }
+    }
+}
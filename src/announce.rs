@@ -0,0 +1,433 @@
+//! Periodic nostr events advertising that a registry exists and is
+//! alive, from which a public directory of registries can be built.
+//!
+//! Announcements are NIP-78 application data: kind 30078, which relays
+//! treat as parameterized replaceable, so each registry has at most one
+//! current announcement per key. The `d` tag is the registry's base
+//! URL and the `t` tag is [`TAG`]; the content is a `registry`
+//! [payload](crate::payload) carrying an [`Announcement`].
+//!
+//! `margo registry-directory` reads the announcements back from relays
+//! to list the registries they know of. Each event goes through the
+//! [validation](crate::validation) pipeline first, so only well-formed, correctly signed
+//! announcements from trusted operators are listed, and each event only
+//! once however many relays return it.
+//!
+//! ```toml
+//! [tenant.announce]
+//! relays = ["wss://relay.example.com"]
+//! interval-secs = 3600
+//! ```
+
+use nostr::{
+    ClientMessage, Event, EventBuilder, Filter, JsonUtil, Kind, RelayMessage, SubscriptionId, Tag,
+    TagStandard,
+};
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+use tungstenite::Message;
+use url::Url;
+
+use crate::{
+    key_rotation, merkle, notify,
+    payload::{Body, Payload},
+    signer::{self, SharedSigner, Signer},
+    status::SharedStatus,
+    tenant::SharedRegistry,
+    timestamp::Timestamp,
+    validation::{Reject, Subject, Validator},
+    Registry,
+};
+
+pub const KIND: u16 = 30078;
+
+/// The hashtag that marks an application-data event as a registry
+/// announcement.
+pub const TAG: &str = "gnostr-registry";
+
+/// Gives the P2P node time to start listening before the first
+/// announcement, so it includes the node's addresses.
+const STARTUP_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AnnounceConfig {
+    relays: Vec<Url>,
+
+    #[serde(default = "AnnounceConfig::default_interval_secs")]
+    interval_secs: u64,
+}
+
+impl AnnounceConfig {
+    fn default_interval_secs() -> u64 {
+        60 * 60
+    }
+}
+
+/// The content of an announcement event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    pub base_url: Url,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub multiaddrs: Vec<String>,
+
+    pub crates: usize,
+
+    /// The [Merkle root](merkle) of the index, hex encoded.
+    pub merkle_root: String,
+
+    /// The version of margo serving the registry.
+    pub version: String,
+}
+
+#[derive(Debug)]
+pub struct Announcer {
+    signer: SharedSigner,
+    relays: RwLock<Vec<Url>>,
+    interval: Duration,
+}
+
+impl Announcer {
+    /// `signer` is the operator's.
+    pub fn new(config: AnnounceConfig, signer: SharedSigner) -> Self {
+        Self {
+            signer,
+            relays: RwLock::new(config.relays),
+            interval: Duration::from_secs(config.interval_secs.max(60)),
+        }
+    }
+
+    /// Announces the registry every interval until the process exits.
+    pub fn start(self: Arc<Self>, registry: SharedRegistry, status: SharedStatus) {
+        std::thread::spawn(move || {
+            std::thread::sleep(STARTUP_DELAY);
+
+            loop {
+                self.announce(&registry.get(), &status);
+                std::thread::sleep(self.interval);
+            }
+        });
+    }
+
+    /// Announces the registry once, as when the key was
+    /// [rotated](crate::key_rotation). Failures are only warned about.
+    pub fn announce(&self, registry: &Registry, status: &SharedStatus) {
+        match self.build(registry, status) {
+            Ok(event) => {
+                for relay in &self.relays() {
+                    let sent = notify::send(relay, std::slice::from_ref(&event));
+                    if let Err(e) = &sent {
+                        eprintln!("Warning: {e}");
+                    }
+                    let error = sent.err().map(|e| e.to_string());
+                    status.update(|s| s.record_relay(relay.as_str(), error));
+                }
+            }
+            Err(e) => eprintln!("Warning: {e}"),
+        }
+    }
+
+    pub fn relays(&self) -> Vec<Url> {
+        self.relays
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns the relays it replaced.
+    pub fn set_relays(&self, relays: Vec<Url>) -> Vec<Url> {
+        let mut current = self.relays.write().unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut *current, relays)
+    }
+
+    fn build(&self, registry: &Registry, status: &SharedStatus) -> Result<nostr::Event, Error> {
+        use error::*;
+
+        let (root, crates) = merkle::index_root(registry).context(MerkleSnafu)?;
+        let (peer_id, multiaddrs) =
+            status.update(|s| (s.local_peer_id.clone(), s.listen_addrs.clone()));
+
+        let announcement = Announcement {
+            base_url: registry.config.base_url.clone(),
+            peer_id,
+            multiaddrs,
+            crates,
+            merkle_root: hex::encode(root),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        };
+
+        event(&*self.signer, &announcement)
+    }
+}
+
+fn event(signer: &dyn Signer, announcement: &Announcement) -> Result<Event, Error> {
+    use error::*;
+
+    let payload = Payload::new(Body::Registry(announcement.clone()));
+    let content = serde_json::to_string(&payload).context(SerializeSnafu)?;
+
+    let tags = [
+        Tag::identifier(announcement.base_url.as_str()),
+        Tag::hashtag(TAG),
+    ];
+
+    let builder = EventBuilder::new(Kind::from(KIND), content, tags);
+    signer::sign(signer, builder).context(SignSnafu)
+}
+
+/// A registry found on a relay.
+#[derive(Debug, Serialize)]
+pub struct Listing {
+    /// The operator's public key, hex encoded.
+    pub pubkey: String,
+
+    pub announced_at: Timestamp,
+
+    #[serde(flatten)]
+    pub announcement: Announcement,
+}
+
+impl Listing {
+    /// Only events that `validator` accepts are listings.
+    fn from_event(event: &Event, validator: &mut Validator) -> Option<Self> {
+        let announcement = parse(event);
+        let subject = match &announcement {
+            Some(_) => Ok(Subject::Listing(event.id.to_hex())),
+            None => Err(Reject::Schema),
+        };
+        let signer = event.verify().ok().map(|()| event.pubkey.to_hex());
+
+        validator.check(signer.as_deref(), subject).ok()?;
+
+        Some(Self {
+            pubkey: event.pubkey.to_hex(),
+            announced_at: Timestamp(event.created_at.as_u64()),
+            announcement: announcement?,
+        })
+    }
+}
+
+/// Rejects events that are not announcements, or announce a different
+/// URL than they are keyed by.
+fn parse(event: &Event) -> Option<Announcement> {
+    let tagged = event
+        .tags
+        .iter()
+        .filter_map(Tag::as_standardized)
+        .any(|t| matches!(t, TagStandard::Hashtag(h) if h == TAG));
+    if event.kind != Kind::from(KIND) || !tagged {
+        return None;
+    }
+
+    let announcement = match Payload::parse(event.content.as_bytes()) {
+        Some(Payload {
+            body: Body::Registry(announcement),
+            ..
+        }) => announcement,
+        Some(_) => return None,
+        // Published before announcements were versioned
+        None => serde_json::from_str(&event.content).ok()?,
+    };
+    if event.identifier() != Some(announcement.base_url.as_str()) {
+        return None;
+    }
+
+    Some(announcement)
+}
+
+/// Asks each relay for announcements and keeps the newest from each
+/// operator for each base URL, ordered by base URL. Trust follows the
+/// [key rotations](key_rotation) the relays return. A relay that
+/// cannot be queried only produces a warning, unless none can.
+pub fn directory(relays: &[Url], validator: &mut Validator) -> Result<Vec<Listing>, Error> {
+    use error::*;
+
+    ensure!(!relays.is_empty(), NoRelaysSnafu);
+
+    let mut events = vec![];
+    let mut last_error = None;
+    let mut answered = false;
+
+    let filter = Filter::new()
+        .kind(Kind::from(KIND))
+        .hashtags([TAG, key_rotation::TAG]);
+
+    for relay in relays {
+        match query(relay, filter.clone()) {
+            Ok(found) => {
+                events.extend(found);
+                answered = true;
+            }
+            Err(e) => {
+                eprintln!("Warning: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if let Some(e) = last_error.filter(|_| !answered) {
+        return Err(e);
+    }
+
+    let (statements, events): (Vec<_>, Vec<_>) =
+        events.into_iter().partition(key_rotation::is_statement);
+    let rotations = statements
+        .iter()
+        .filter_map(key_rotation::parse)
+        .collect::<Vec<_>>();
+    validator.follow(&rotations);
+
+    let mut listings = BTreeMap::new();
+    for listing in events
+        .iter()
+        .filter_map(|e| Listing::from_event(e, validator))
+    {
+        let key = (
+            listing.announcement.base_url.clone(),
+            listing.pubkey.clone(),
+        );
+        let newer = listings
+            .get(&key)
+            .map_or(true, |l: &Listing| l.announced_at < listing.announced_at);
+        if newer {
+            listings.insert(key, listing);
+        }
+    }
+
+    Ok(listings.into_values().collect())
+}
+
+/// The events stored by `relay` that match `filter`.
+pub fn query(relay: &Url, filter: Filter) -> Result<Vec<Event>, Error> {
+    use error::*;
+
+    let mut socket = notify::connect(relay).context(RelaySnafu)?;
+
+    let id = SubscriptionId::generate();
+    let request = ClientMessage::req(id.clone(), vec![filter]).as_json();
+    socket
+        .send(Message::Text(request))
+        .context(QuerySnafu { relay })?;
+
+    // Stored events are followed by `["EOSE", id]`
+    let mut events = vec![];
+    loop {
+        let reply = socket.read().context(QuerySnafu { relay })?;
+        let Message::Text(reply) = reply else {
+            continue;
+        };
+
+        match RelayMessage::from_json(&reply) {
+            Ok(RelayMessage::Event {
+                subscription_id,
+                event,
+            }) if subscription_id == id => events.push(*event),
+            Ok(RelayMessage::EndOfStoredEvents(subscription_id)) if subscription_id == id => break,
+            Ok(RelayMessage::Closed {
+                subscription_id,
+                message,
+            }) if subscription_id == id => return ClosedSnafu { relay, message }.fail(),
+            _ => {}
+        }
+    }
+
+    _ = socket.send(Message::Text(ClientMessage::close(id).as_json()));
+    _ = socket.close(None);
+
+    Ok(events)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not compute the index's Merkle root"))]
+    Merkle { source: merkle::Error },
+
+    #[snafu(display("Could not serialize the registry announcement"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not sign the registry announcement"))]
+    Sign { source: signer::Error },
+
+    #[snafu(display("No relays were given to query"))]
+    NoRelays,
+
+    #[snafu(transparent)]
+    Relay { source: notify::Error },
+
+    #[snafu(display("Could not query the nostr relay {relay}"))]
+    Query {
+        #[snafu(source(from(tungstenite::Error, Box::new)))]
+        source: Box<tungstenite::Error>,
+        relay: Url,
+    },
+
+    #[snafu(display("{relay} refused the query: {message}"))]
+    Closed { relay: Url, message: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Merkle { .. } => "E_STORAGE_READ",
+            Self::Serialize { .. } => "E_INTERNAL",
+            Self::Sign { source } => source.code(),
+            Self::NoRelays => "E_CONFIG_INVALID",
+            Self::Relay { source } => source.code(),
+            Self::Query { .. } | Self::Closed { .. } => "E_NOTIFY",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn listings_must_match_their_identifier() {
+        let keys = nostr::Keys::generate();
+        let announcement = Announcement {
+            base_url: "https://registry.example.com/acme/".parse().unwrap(),
+            peer_id: None,
+            multiaddrs: vec![],
+            crates: 3,
+            merkle_root: "00".repeat(32),
+            version: "0.0.0".to_owned(),
+        };
+
+        let mut validator = Validator::default();
+        let good = event(&signer::LocalSigner::new(keys.clone()), &announcement).unwrap();
+        let listing = Listing::from_event(&good, &mut validator).unwrap();
+        assert_eq!(keys.public_key().to_hex(), listing.pubkey);
+        assert_eq!(3, listing.announcement.crates);
+
+        let content = serde_json::to_string(&announcement).unwrap();
+        let tags = [
+            Tag::identifier("https://other.example.com/"),
+            Tag::hashtag(TAG),
+        ];
+        let mismatched = EventBuilder::new(Kind::from(KIND), content, tags)
+            .to_event(&keys)
+            .unwrap();
+        assert!(Listing::from_event(&mismatched, &mut validator).is_none());
+
+        // The same event from a second relay, and operators that are not
+        // trusted
+        assert!(Listing::from_event(&good, &mut validator).is_none());
+        let mut trusting = Validator::new(["0".repeat(64)].into());
+        assert!(Listing::from_event(&good, &mut trusting).is_none());
+        assert_eq!(
+            Some("2 announcement(s) rejected (1 schema, 1 duplicate)"),
+            validator.summary().as_deref()
+        );
+    }
+}
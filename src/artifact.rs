@@ -0,0 +1,323 @@
+//! Prebuilt binaries distributed alongside crate versions.
+//!
+//! Each version can carry any number of files per target triple, such
+//! as a command-line tool or a `cdylib` built for
+//! `x86_64-unknown-linux-gnu`. They are stored beside the `.crate` file
+//! as `{version}.artifacts/{target}/{file}`, where they are served like
+//! every other registry file, and `{version}.artifacts/artifacts.json`
+//! lists them with their checksums.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audit, common::CrateName, extensions, timestamp::Timestamp, ExtensionsError, ParseIndexError,
+    Registry,
+};
+
+pub const DIR_EXTENSION: &str = "artifacts";
+
+const MANIFEST_NAME: &str = "artifacts.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Artifact {
+    pub target: String,
+    pub file: String,
+    pub size: u64,
+
+    /// The SHA-256 of the file, hex encoded.
+    pub cksum: String,
+
+    pub added_at: Timestamp,
+}
+
+impl From<&Artifact> for extensions::Artifact {
+    fn from(a: &Artifact) -> Self {
+        Self {
+            target: a.target.clone(),
+            file: a.file.clone(),
+            cksum: a.cksum.clone(),
+        }
+    }
+}
+
+/// Stores the file, replacing a previous one with the same target and
+/// name.
+pub fn add(
+    registry: &Registry,
+    name: &CrateName,
+    version: &Version,
+    target: &str,
+    file: &str,
+    data: &[u8],
+) -> Result<Artifact, Error> {
+    use error::*;
+
+    ensure_version_exists(registry, name, version)?;
+    ensure!(is_target_triple(target), TargetSnafu { target });
+    ensure!(is_file_name(file), FileNameSnafu { file });
+
+    let dir = registry.artifacts_dir_for(name, version);
+    let target_dir = dir.join(target);
+    fs::create_dir_all(&target_dir).context(WriteSnafu { path: &target_dir })?;
+
+    let path = target_dir.join(file);
+    write_atomically(&path, data)?;
+
+    let artifact = Artifact {
+        target: target.to_owned(),
+        file: file.to_owned(),
+        size: data.len() as u64,
+        cksum: hex::encode(sha2::Sha256::digest(data)),
+        added_at: Timestamp::now(),
+    };
+
+    let mut artifacts = read_manifest(&dir)?;
+    artifacts.retain(|a| !(a.target == target && a.file == file));
+    artifacts.push(artifact.clone());
+    write_manifest(&dir, &mut artifacts)?;
+    record_in_index(registry, name, version, &artifacts)?;
+
+    registry
+        .record(audit::Event::AddArtifact {
+            name: name.clone(),
+            vers: version.clone(),
+            target: artifact.target.clone(),
+            file: artifact.file.clone(),
+            cksum: artifact.cksum.clone(),
+        })
+        .context(AuditSnafu)?;
+
+    Ok(artifact)
+}
+
+pub fn list(
+    registry: &Registry,
+    name: &CrateName,
+    version: &Version,
+) -> Result<Vec<Artifact>, Error> {
+    ensure_version_exists(registry, name, version)?;
+    read_manifest(&registry.artifacts_dir_for(name, version))
+}
+
+pub fn remove(
+    registry: &Registry,
+    name: &CrateName,
+    version: &Version,
+    target: &str,
+    file: &str,
+) -> Result<Artifact, Error> {
+    use error::*;
+
+    let dir = registry.artifacts_dir_for(name, version);
+    let mut artifacts = read_manifest(&dir)?;
+
+    let i = artifacts
+        .iter()
+        .position(|a| a.target == target && a.file == file)
+        .context(UnknownSnafu { target, file })?;
+    let artifact = artifacts.remove(i);
+
+    let path = dir.join(&artifact.target).join(&artifact.file);
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(WriteSnafu { path }),
+    }
+
+    write_manifest(&dir, &mut artifacts)?;
+    record_in_index(registry, name, version, &artifacts)?;
+
+    registry
+        .record(audit::Event::RemoveArtifact {
+            name: name.clone(),
+            vers: version.clone(),
+            target: artifact.target.clone(),
+            file: artifact.file.clone(),
+        })
+        .context(AuditSnafu)?;
+
+    Ok(artifact)
+}
+
+fn ensure_version_exists(
+    registry: &Registry,
+    name: &CrateName,
+    version: &Version,
+) -> Result<(), Error> {
+    use error::*;
+
+    let index =
+        Registry::parse_index_file(&registry.index_file_path_for(name)).context(IndexSnafu)?;
+    ensure!(
+        index.contains_key(version),
+        UnknownVersionSnafu {
+            name: name.clone(),
+            version: version.clone(),
+        }
+    );
+
+    Ok(())
+}
+
+/// Lists the artifacts in the version's index entry too, for tools
+/// that only read the index.
+fn record_in_index(
+    registry: &Registry,
+    name: &CrateName,
+    version: &Version,
+    artifacts: &[Artifact],
+) -> Result<(), Error> {
+    use error::*;
+
+    registry
+        .update_extensions(name, version, |x| {
+            x.artifacts = artifacts.iter().map(Into::into).collect();
+        })
+        .context(ExtensionsSnafu)
+}
+
+/// A missing manifest lists nothing.
+fn read_manifest(dir: &Path) -> Result<Vec<Artifact>, Error> {
+    use error::*;
+
+    let path = dir.join(MANIFEST_NAME);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+
+    serde_json::from_slice(&data).context(ParseSnafu { path })
+}
+
+fn write_manifest(dir: &Path, artifacts: &mut [Artifact]) -> Result<(), Error> {
+    use error::*;
+
+    artifacts.sort_by(|a, b| (&a.target, &a.file).cmp(&(&b.target, &b.file)));
+    let data = serde_json::to_vec_pretty(artifacts).context(SerializeSnafu)?;
+
+    write_atomically(&dir.join(MANIFEST_NAME), &data)
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Error> {
+    use error::*;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, path).context(WriteSnafu { path })
+}
+
+/// Target triples are dash-separated, like `aarch64-apple-darwin`;
+/// custom target names may also contain `_` and `.`.
+fn is_target_triple(target: &str) -> bool {
+    target.split('-').count() >= 2
+        && target.split('-').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        })
+}
+
+/// A plain file name that cannot escape the target's directory or
+/// clash with the manifest.
+fn is_file_name(file: &str) -> bool {
+    !file.is_empty()
+        && !file.starts_with('.')
+        && !file.ends_with(".tmp")
+        && file
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: ParseIndexError },
+
+    #[snafu(display("{name} {version} is not in the registry"))]
+    UnknownVersion { name: CrateName, version: Version },
+
+    #[snafu(display("`{target}` is not a target triple"))]
+    Target { target: String },
+
+    #[snafu(display("`{file}` is not a valid artifact file name"))]
+    FileName { file: String },
+
+    #[snafu(display("There is no artifact `{file}` for `{target}`"))]
+    Unknown { target: String, file: String },
+
+    #[snafu(display("Could not read the artifact {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the artifact list at {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the artifact list"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write the artifact to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not list the artifacts in the index"))]
+    Extensions { source: ExtensionsError },
+
+    #[snafu(display("Could not record the artifact in the audit log"))]
+    Audit { source: audit::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Index { .. } => "E_INDEX_CORRUPT",
+            Self::UnknownVersion { .. } => "E_VERSION_NOT_FOUND",
+            Self::Target { .. } | Self::FileName { .. } => "E_BAD_ARTIFACT",
+            Self::Unknown { .. } => "E_ARTIFACT_NOT_FOUND",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_ARTIFACTS_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Extensions { source } => source.code(),
+            Self::Audit { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn targets_and_file_names_stay_inside_the_directory() {
+        assert!(is_target_triple("x86_64-unknown-linux-gnu"));
+        assert!(is_target_triple("aarch64-apple-darwin"));
+        assert!(is_target_triple("thumbv7em-none-eabihf"));
+
+        assert!(!is_target_triple("linux"));
+        assert!(!is_target_triple("x86_64--linux"));
+        assert!(!is_target_triple("../../etc"));
+        assert!(!is_target_triple("x86_64-unknown/linux"));
+
+        assert!(is_file_name("margo"));
+        assert!(is_file_name("margo-1.0.0.tar.gz"));
+        assert!(is_file_name("libdemo.so"));
+
+        assert!(!is_file_name(""));
+        assert!(!is_file_name(".."));
+        assert!(!is_file_name("bin/margo"));
+        assert!(!is_file_name("artifacts.json.tmp"));
+    }
+}
@@ -0,0 +1,401 @@
+//! The registry as it was at a moment in the past, for building old
+//! commits exactly as they were built then.
+//!
+//! `margo as-of 2024-06-01 --out DIR` replays the audit log up to that
+//! moment, midnight UTC for a bare date, and writes a registry to `DIR`
+//! whose index holds only the versions added by then and not removed
+//! since, each yanked as it was then. The `.crate` files are hard linked
+//! from the registry when the file system allows it. Its `margo.toml`,
+//! `config.json` and `visibility.json` are copied, so Cargo can use the
+//! index directly and `margo serve --registry DIR` serves it.
+//!
+//! The log can only bring back what is still on disk: versions removed
+//! since, or replaced by a different `.crate` file, are reported and
+//! left out. Versions in the index that the log never mentions were
+//! added before the log was kept, and are taken as they are now. A
+//! version quarantined now stays withheld even if it was served then.
+
+use semver::Version;
+use snafu::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audit, common::CrateName, names, timestamp::Timestamp, visibility, ListAll, ListAllError,
+    Registry, WriteIndexError,
+};
+
+/// What the log says about a version at the moment.
+#[derive(Debug, Clone)]
+struct Known {
+    name: CrateName,
+
+    /// The checksum it was added with; `None` when it was added before
+    /// the log was kept.
+    cksum: Option<String>,
+
+    yanked: bool,
+    withheld: bool,
+    removed: bool,
+}
+
+/// The log replayed up to a moment.
+#[derive(Debug, Default)]
+pub struct History {
+    /// By normalized name and version.
+    versions: BTreeMap<(String, Version), Known>,
+
+    /// Versions first added after the moment.
+    added_later: BTreeSet<(String, Version)>,
+
+    /// When a snapshot was last restored before the moment. The log
+    /// does not say what the restore changed.
+    pub restored: Option<Timestamp>,
+}
+
+pub fn replay(entries: &[audit::Entry], at: Timestamp) -> History {
+    use audit::Event;
+
+    let mut history = History::default();
+
+    for entry in entries {
+        let key =
+            |name: &CrateName, vers: &Version| (names::normalize(name.as_str()), vers.clone());
+
+        if entry.time > at {
+            if let Event::Add { name, vers, .. } = &entry.event {
+                let key = key(name, vers);
+                if !history.versions.contains_key(&key) {
+                    history.added_later.insert(key);
+                }
+            }
+            continue;
+        }
+
+        let known = |history: &mut History, name: &CrateName, vers: &Version| {
+            history
+                .versions
+                .entry(key(name, vers))
+                .or_insert_with(|| Known {
+                    name: name.clone(),
+                    cksum: None,
+                    yanked: false,
+                    withheld: false,
+                    removed: false,
+                })
+                .clone()
+        };
+
+        let (name, vers, state) = match &entry.event {
+            Event::Add { name, vers, cksum } => {
+                let state = Known {
+                    name: name.clone(),
+                    cksum: Some(cksum.clone()),
+                    yanked: false,
+                    withheld: false,
+                    removed: false,
+                };
+                (name, vers, state)
+            }
+            Event::Remove { name, vers } => {
+                let state = known(&mut history, name, vers);
+                let removed = true;
+                (name, vers, Known { removed, ..state })
+            }
+            Event::Yank { name, vers } | Event::Unyank { name, vers } => {
+                let yanked = matches!(entry.event, Event::Yank { .. });
+                let state = known(&mut history, name, vers);
+                (name, vers, Known { yanked, ..state })
+            }
+            Event::Quarantine { name, vers, .. } | Event::Release { name, vers } => {
+                let withheld = matches!(entry.event, Event::Quarantine { .. });
+                let state = known(&mut history, name, vers);
+                (name, vers, Known { withheld, ..state })
+            }
+            Event::RestoreSnapshot { .. } => {
+                history.restored = Some(entry.time);
+                continue;
+            }
+            _ => continue,
+        };
+        history.versions.insert(key(name, vers), state);
+    }
+
+    history
+}
+
+/// The index as it was, and what could not be brought back.
+#[derive(Debug, Default)]
+pub struct View {
+    pub crates: ListAll,
+
+    /// Versions in the index then whose `.crate` files are gone, having
+    /// been removed or replaced since.
+    pub missing: Vec<(CrateName, Version)>,
+
+    /// How many versions the log never mentions.
+    pub unlogged: usize,
+}
+
+/// Narrows the current index to what it was at the moment replayed.
+pub fn apply(history: &History, current: ListAll) -> View {
+    let mut view = View::default();
+    let mut present = BTreeSet::new();
+
+    for (name, index) in current {
+        let normalized = names::normalize(name.as_str());
+        let mut then = crate::Index::new();
+
+        for (vers, mut entry) in index {
+            let key = (normalized.clone(), vers.clone());
+
+            match history.versions.get(&key) {
+                Some(known) if known.removed => {}
+                Some(known) => {
+                    present.insert(key);
+
+                    let same = known.cksum.as_ref().map_or(true, |cksum| {
+                        *cksum == entry.cksum || Some(cksum) == entry.decrypted_cksum.as_ref()
+                    });
+                    if !same {
+                        view.missing.push((name.clone(), vers));
+                        continue;
+                    }
+
+                    entry.yanked = known.yanked;
+                    entry.withheld |= known.withheld;
+                    then.insert(vers, entry);
+                }
+                None if history.added_later.contains(&key) => {}
+                None => {
+                    view.unlogged += 1;
+                    then.insert(vers, entry);
+                }
+            }
+        }
+
+        if !then.is_empty() {
+            view.crates.insert(name, then);
+        }
+    }
+
+    for (key, known) in &history.versions {
+        if !known.removed && !present.contains(key) {
+            view.missing.push((known.name.clone(), key.1.clone()));
+        }
+    }
+
+    view
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub versions: usize,
+
+    /// How many of the `.crate` files are hard links to the registry's.
+    pub linked: usize,
+
+    pub missing: Vec<(CrateName, Version)>,
+    pub unlogged: usize,
+    pub restored: Option<Timestamp>,
+}
+
+/// Writes the registry as it was at `at` to `out`, which must not exist
+/// yet.
+pub fn export(registry: &Registry, at: Timestamp, out: &Path) -> Result<Report, Error> {
+    use error::*;
+
+    ensure!(!out.exists(), ExistsSnafu { path: out });
+
+    let entries = audit::read(&registry.audit_log_path()).context(AuditSnafu)?;
+    let history = replay(&entries, at);
+    let view = apply(&history, registry.list_all().context(ListSnafu)?);
+
+    // The same place below `out` as below the registry
+    let moved = |path: PathBuf| match path.strip_prefix(&registry.path) {
+        Ok(relative) => out.join(relative),
+        Err(_) => path,
+    };
+
+    fs::create_dir_all(out).context(WriteSnafu { path: out })?;
+    for from in [
+        registry.margo_config_toml_path(),
+        registry.config_json_path(),
+        registry.path.join(visibility::FILE_NAME),
+    ] {
+        let to = out.join(from.file_name().unwrap_or_default());
+        match fs::copy(&from, &to) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(WriteSnafu { path: to }),
+        }
+    }
+
+    let mut versions = 0;
+    let mut linked = 0;
+    for (name, index) in view.crates {
+        for vers in index.keys() {
+            let from = registry.crate_file_path_for(&name, vers);
+            let to = moved(from.clone());
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+            }
+
+            versions += 1;
+            if fs::hard_link(&from, &to).is_ok() {
+                linked += 1;
+                continue;
+            }
+            fs::copy(&from, &to).context(WriteSnafu { path: to })?;
+        }
+
+        let path = moved(registry.index_file_path_for(&name));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+        }
+        Registry::write_index_file(index, &path).context(IndexSnafu { path })?;
+    }
+
+    Ok(Report {
+        versions,
+        linked,
+        missing: view.missing,
+        unlogged: view.unlogged,
+        restored: history.restored,
+    })
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("{} already exists", path.display()))]
+    Exists { path: PathBuf },
+
+    #[snafu(display("Could not read the audit log"))]
+    Audit { source: audit::Error },
+
+    #[snafu(display("Could not list the crates in the registry"))]
+    List { source: ListAllError },
+
+    #[snafu(display("Could not write the index file {}", path.display()))]
+    Index {
+        source: WriteIndexError,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Exists { .. } => "E_PATH_EXISTS",
+            Self::Audit { source } => source.code(),
+            Self::List { source } => source.code(),
+            Self::Index { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index_entry;
+
+    fn entry(name: &str, vers: &str, cksum: &str) -> index_entry::Root {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "vers": vers,
+            "deps": [],
+            "cksum": cksum,
+            "features": {},
+            "yanked": false,
+            "v": 2,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn the_index_is_narrowed_to_what_it_was() {
+        use audit::Event;
+
+        let name = |n: &str| n.parse::<CrateName>().unwrap();
+        let vers = |v: &str| v.parse::<Version>().unwrap();
+        let add = |n: &str, v: &str, cksum: &str| Event::Add {
+            name: name(n),
+            vers: vers(v),
+            cksum: cksum.into(),
+        };
+        let yank = |v: &str| Event::Yank {
+            name: name("demo"),
+            vers: vers(v),
+        };
+        let unyank = |v: &str| Event::Unyank {
+            name: name("demo"),
+            vers: vers(v),
+        };
+        let remove = |n: &str, v: &str| Event::Remove {
+            name: name(n),
+            vers: vers(v),
+        };
+        let events = [
+            (10, add("demo", "1.0.0", "a")),
+            (20, add("demo", "1.1.0", "b")),
+            (30, yank("1.0.0")),
+            (40, add("gone", "0.1.0", "c")),
+            (50, add("Demo", "1.1.0", "d")),
+            (60, unyank("1.0.0")),
+            (70, add("demo", "2.0.0", "e")),
+            (80, remove("gone", "0.1.0")),
+        ];
+        let entries = events
+            .into_iter()
+            .enumerate()
+            .map(|(i, (time, event))| audit::Entry {
+                seq: i as u64 + 1,
+                time: Timestamp(time),
+                event,
+            })
+            .collect::<Vec<_>>();
+
+        let current = || {
+            let mut current = ListAll::new();
+            for (n, v, cksum) in [
+                ("demo", "1.0.0", "a"),
+                ("demo", "1.1.0", "d"),
+                ("demo", "2.0.0", "e"),
+                ("old", "0.0.1", "f"),
+            ] {
+                current
+                    .entry(name(n))
+                    .or_default()
+                    .insert(vers(v), entry(n, v, cksum));
+            }
+            current
+        };
+
+        let view = apply(&replay(&entries, Timestamp(45)), current());
+
+        let demo = &view.crates[&name("demo")];
+        assert_eq!(vec![&vers("1.0.0")], demo.keys().collect::<Vec<_>>());
+        assert!(demo[&vers("1.0.0")].yanked);
+        assert!(view.crates.contains_key(&name("old")));
+        assert_eq!(1, view.unlogged);
+
+        // 1.1.0 was replaced and gone was removed since
+        let missing = view.missing.iter().map(|(n, v)| format!("{n}@{v}"));
+        assert_eq!(
+            vec!["demo@1.1.0", "gone@0.1.0"],
+            missing.collect::<Vec<_>>(),
+        );
+
+        let view = apply(&replay(&entries, Timestamp(100)), current());
+        assert_eq!(3, view.crates[&name("demo")].len());
+        assert!(!view.crates[&name("demo")][&vers("1.0.0")].yanked);
+        assert!(view.missing.is_empty());
+    }
+}
@@ -0,0 +1,311 @@
+//! Build attestations attached to crate versions.
+//!
+//! An attestation is an [in-toto] statement, such as SLSA provenance,
+//! either as plain JSON or wrapped in a DSSE envelope. It is only
+//! accepted when one of its subjects has the SHA-256 of the version's
+//! `.crate` file, and it is stored unchanged beside that file as
+//! `{version}.attestations/{sha256 of the attestation}.json`, where it
+//! is served like every other registry file.
+//!
+//! Envelope signatures are not checked here; that is up to whoever
+//! downloads the attestation and knows which builders to trust.
+//!
+//! [in-toto]: https://github.com/in-toto/attestation
+
+use base64::Engine;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use snafu::prelude::*;
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+use crate::{audit, common::CrateName, extensions, ExtensionsError, ParseIndexError, Registry};
+
+pub const DIR_EXTENSION: &str = "attestations";
+
+const STATEMENT_TYPE_PREFIX: &str = "https://in-toto.io/Statement/";
+
+const IN_TOTO_PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    payload_type: String,
+    payload: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Statement {
+    #[serde(rename = "_type")]
+    type_: String,
+
+    subject: Vec<Subject>,
+
+    #[serde(rename = "predicateType")]
+    predicate_type: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Subject {
+    digest: BTreeMap<String, String>,
+}
+
+impl Statement {
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        use error::*;
+
+        let statement = match serde_json::from_slice::<Envelope>(data) {
+            Ok(envelope) => {
+                ensure!(
+                    envelope.payload_type == IN_TOTO_PAYLOAD_TYPE,
+                    PayloadTypeSnafu {
+                        payload_type: envelope.payload_type
+                    }
+                );
+                let payload = base64::engine::general_purpose::STANDARD
+                    .decode(&envelope.payload)
+                    .context(PayloadSnafu)?;
+                serde_json::from_slice::<Self>(&payload).context(ParseSnafu)?
+            }
+            Err(_) => serde_json::from_slice::<Self>(data).context(ParseSnafu)?,
+        };
+
+        ensure!(
+            statement.type_.starts_with(STATEMENT_TYPE_PREFIX),
+            StatementTypeSnafu {
+                type_: statement.type_
+            }
+        );
+
+        Ok(statement)
+    }
+
+    fn is_about(&self, cksum: &str) -> bool {
+        self.subject.iter().any(|s| {
+            s.digest
+                .get("sha256")
+                .is_some_and(|d| d.eq_ignore_ascii_case(cksum))
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Attestation {
+    /// The SHA-256 of the attestation itself, which names its file.
+    pub digest: String,
+
+    pub predicate_type: String,
+
+    /// Whether a subject still matches the version's checksum.
+    pub verified: bool,
+}
+
+/// Checks the attestation against the version and stores it. Attaching
+/// the same attestation again does nothing.
+pub fn attach(
+    registry: &Registry,
+    name: &CrateName,
+    version: &Version,
+    data: &[u8],
+) -> Result<Attestation, Error> {
+    use error::*;
+
+    let cksum = checksum_of(registry, name, version)?;
+
+    let statement = Statement::parse(data)?;
+    ensure!(
+        statement.is_about(&cksum),
+        SubjectSnafu {
+            name: name.clone(),
+            version: version.clone(),
+        }
+    );
+
+    let digest = hex::encode(sha2::Sha256::digest(data));
+
+    let dir = registry.attestations_dir_for(name, version);
+    fs::create_dir_all(&dir).context(WriteSnafu { path: &dir })?;
+
+    let path = dir.join(format!("{digest}.json"));
+    if !path.exists() {
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+        fs::rename(&tmp, &path).context(WriteSnafu { path })?;
+
+        registry
+            .record(audit::Event::Attest {
+                name: name.clone(),
+                vers: version.clone(),
+                digest: digest.clone(),
+                predicate_type: statement.predicate_type.clone(),
+            })
+            .context(AuditSnafu)?;
+    }
+
+    let provenance = extensions::Provenance {
+        predicate_type: statement.predicate_type.clone(),
+        digest: digest.clone(),
+    };
+    registry
+        .update_extensions(name, version, |x| {
+            if !x.provenance.contains(&provenance) {
+                x.provenance.push(provenance);
+            }
+        })
+        .context(ExtensionsSnafu)?;
+
+    Ok(Attestation {
+        digest,
+        predicate_type: statement.predicate_type,
+        verified: true,
+    })
+}
+
+/// Every attestation of the version, each checked again against the
+/// version's current checksum.
+pub fn list(
+    registry: &Registry,
+    name: &CrateName,
+    version: &Version,
+) -> Result<Vec<Attestation>, Error> {
+    use error::*;
+
+    let cksum = checksum_of(registry, name, version)?;
+
+    let dir = registry.attestations_dir_for(name, version);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(ReadSnafu { path: dir }),
+    };
+
+    let mut attestations = vec![];
+    for entry in entries {
+        let entry = entry.context(ReadSnafu { path: &dir })?;
+        let path = entry.path();
+        if path.extension().map_or(true, |e| e != "json") {
+            continue;
+        }
+
+        let data = fs::read(&path).context(ReadSnafu { path: &path })?;
+        let statement = Statement::parse(&data)?;
+
+        attestations.push(Attestation {
+            digest: hex::encode(sha2::Sha256::digest(&data)),
+            predicate_type: statement.predicate_type.clone(),
+            verified: statement.is_about(&cksum),
+        });
+    }
+    attestations.sort_by(|a, b| a.digest.cmp(&b.digest));
+
+    Ok(attestations)
+}
+
+fn checksum_of(registry: &Registry, name: &CrateName, version: &Version) -> Result<String, Error> {
+    use error::*;
+
+    let index =
+        Registry::parse_index_file(&registry.index_file_path_for(name)).context(IndexSnafu)?;
+    let entry = index.get(version).context(UnknownVersionSnafu {
+        name: name.clone(),
+        version: version.clone(),
+    })?;
+
+    Ok(entry.cksum.clone())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: ParseIndexError },
+
+    #[snafu(display("{name} {version} is not in the registry"))]
+    UnknownVersion { name: CrateName, version: Version },
+
+    #[snafu(display("Could not read the attestation {}", path.display()))]
+    ReadFile { source: io::Error, path: PathBuf },
+
+    #[snafu(display("The attestation is not valid JSON"))]
+    Parse { source: serde_json::Error },
+
+    #[snafu(display("The attestation envelope's payload is not valid base64"))]
+    Payload { source: base64::DecodeError },
+
+    #[snafu(display(
+        "The attestation envelope carries `{payload_type}`, not an in-toto statement"
+    ))]
+    PayloadType { payload_type: String },
+
+    #[snafu(display("The attestation is a `{type_}`, not an in-toto statement"))]
+    StatementType { type_: String },
+
+    #[snafu(display("No subject of the attestation is the `.crate` file of {name} {version}"))]
+    Subject { name: CrateName, version: Version },
+
+    #[snafu(display("Could not read the attestations at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not write the attestation to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not list the attestation in the index"))]
+    Extensions { source: ExtensionsError },
+
+    #[snafu(display("Could not record the attestation in the audit log"))]
+    Audit { source: audit::Error },
+
+    #[snafu(display("{count} attestation(s) do not match the crate's checksum"))]
+    Failed { count: usize },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Index { .. } => "E_INDEX_CORRUPT",
+            Self::UnknownVersion { .. } => "E_VERSION_NOT_FOUND",
+            Self::Parse { .. }
+            | Self::Payload { .. }
+            | Self::PayloadType { .. }
+            | Self::StatementType { .. } => "E_BAD_ATTESTATION",
+            Self::Subject { .. } | Self::Failed { .. } => "E_ATTESTATION_MISMATCH",
+            Self::ReadFile { .. } => "E_ATTESTATION_READ",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Extensions { source } => source.code(),
+            Self::Audit { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STATEMENT: &str = r#"{
+        "_type": "https://in-toto.io/Statement/v1",
+        "subject": [{"name": "demo-1.0.0.crate", "digest": {"sha256": "AABB"}}],
+        "predicateType": "https://slsa.dev/provenance/v1",
+        "predicate": {}
+    }"#;
+
+    #[test]
+    fn statements_are_read_plain_or_enveloped() {
+        let statement = Statement::parse(STATEMENT.as_bytes()).unwrap();
+        assert_eq!("https://slsa.dev/provenance/v1", statement.predicate_type);
+        assert!(statement.is_about("aabb"));
+        assert!(!statement.is_about("ccdd"));
+
+        let envelope = serde_json::json!({
+            "payloadType": IN_TOTO_PAYLOAD_TYPE,
+            "payload": base64::engine::general_purpose::STANDARD.encode(STATEMENT),
+            "signatures": [],
+        });
+        let statement = Statement::parse(envelope.to_string().as_bytes()).unwrap();
+        assert!(statement.is_about("aabb"));
+
+        let other =
+            serde_json::json!({"_type": "something-else", "subject": [], "predicateType": ""});
+        assert!(Statement::parse(other.to_string().as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,436 @@
+//! An append-only log of the changes made to the registry.
+//!
+//! Each line of `audit.jsonl` is one [`Entry`]. Sequence numbers start
+//! at 1 and increase by one with every entry.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{common::CrateName, timestamp::Timestamp, visibility::Visibility};
+
+pub const FILE_NAME: &str = "audit.jsonl";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    pub time: Timestamp,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Event {
+    Add {
+        name: CrateName,
+        vers: Version,
+        cksum: String,
+    },
+    Remove {
+        name: CrateName,
+        vers: Version,
+    },
+    Yank {
+        name: CrateName,
+        vers: Version,
+    },
+    Unyank {
+        name: CrateName,
+        vers: Version,
+    },
+    Attest {
+        name: CrateName,
+        vers: Version,
+        digest: String,
+        predicate_type: String,
+    },
+    AddArtifact {
+        name: CrateName,
+        vers: Version,
+        target: String,
+        file: String,
+        cksum: String,
+    },
+    RemoveArtifact {
+        name: CrateName,
+        vers: Version,
+        target: String,
+        file: String,
+    },
+    PutBlob {
+        digest: String,
+        size: u64,
+    },
+    RemoveBlob {
+        digest: String,
+    },
+    /// The registry was rolled back to a snapshot, after saving its
+    /// previous state as `saved_as`.
+    RestoreSnapshot {
+        name: String,
+        saved_as: String,
+    },
+    /// Sources disagreed about the checksum of a version, which is
+    /// quarantined until the conflict is resolved.
+    Conflict {
+        name: CrateName,
+        vers: Version,
+        checksums: BTreeMap<String, String>,
+    },
+    ResolveConflict {
+        name: CrateName,
+        vers: Version,
+        removed: bool,
+    },
+    /// An operator stopped serving the version.
+    Quarantine {
+        name: CrateName,
+        vers: Version,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    Release {
+        name: CrateName,
+        vers: Version,
+    },
+    /// An owner or the operator changed who may see the crate.
+    SetVisibility {
+        name: CrateName,
+        visibility: Visibility,
+    },
+    /// A publish held for approval was approved by `reviewer`. The
+    /// `Add` of the version follows.
+    Approve {
+        name: CrateName,
+        vers: Version,
+        publisher: String,
+        reviewer: String,
+    },
+    /// The daemon refused requests from `source` for `seconds` after
+    /// too many failed authentications.
+    AuthLockout {
+        source: String,
+        failures: u32,
+        seconds: u64,
+    },
+    /// The daemon applied its configuration again, after the file
+    /// changed, on `SIGHUP` or when asked to.
+    ReloadConfig {
+        trigger: String,
+    },
+    /// The operator's nostr key was replaced; see `key_rotation`.
+    RotateKey {
+        old_pubkey: String,
+        new_pubkey: String,
+    },
+    /// Enough operators approved an operation that needs a quorum; see
+    /// `quorum`. The operation's own entry precedes this one.
+    Authorize {
+        operation: String,
+        approvers: Vec<String>,
+    },
+}
+
+impl Event {
+    /// Whether the event changed the index, so that mirrors need to
+    /// fetch the index file of [`Event::crate_name`] again, or every
+    /// index file when it has none.
+    pub fn changes_index(&self) -> bool {
+        matches!(
+            self,
+            Self::Add { .. }
+                | Self::Remove { .. }
+                | Self::Yank { .. }
+                | Self::Unyank { .. }
+                | Self::ResolveConflict { .. }
+                | Self::Quarantine { .. }
+                | Self::Release { .. }
+                | Self::RestoreSnapshot { .. }
+        )
+    }
+
+    pub fn crate_name(&self) -> Option<&CrateName> {
+        match self {
+            Self::Add { name, .. }
+            | Self::Remove { name, .. }
+            | Self::Yank { name, .. }
+            | Self::Unyank { name, .. }
+            | Self::Attest { name, .. }
+            | Self::AddArtifact { name, .. }
+            | Self::RemoveArtifact { name, .. }
+            | Self::Conflict { name, .. }
+            | Self::ResolveConflict { name, .. }
+            | Self::Quarantine { name, .. }
+            | Self::Release { name, .. }
+            | Self::SetVisibility { name, .. }
+            | Self::Approve { name, .. } => Some(name),
+            Self::PutBlob { .. }
+            | Self::RemoveBlob { .. }
+            | Self::RestoreSnapshot { .. }
+            | Self::AuthLockout { .. }
+            | Self::ReloadConfig { .. }
+            | Self::RotateKey { .. }
+            | Self::Authorize { .. } => None,
+        }
+    }
+}
+
+/// A run of entries for a standby to replay.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page {
+    /// The sequence number of the newest entry in the log, 0 when it is
+    /// empty.
+    pub head: u64,
+
+    pub entries: Vec<Entry>,
+}
+
+/// Reads every entry, oldest first. A missing log is empty.
+pub fn read(path: &Path) -> Result<Vec<Entry>, Error> {
+    use error::*;
+
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(OpenSnafu { path }),
+    };
+
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let line = line.context(ReadSnafu { path, line: i })?;
+            serde_json::from_str(&line).context(ParseSnafu { path, line: i })
+        })
+        .collect()
+}
+
+/// At most `limit` of the entries after sequence number `after`, oldest
+/// first.
+pub fn page(path: &Path, after: u64, limit: usize) -> Result<Page, Error> {
+    let entries = read(path)?;
+    let head = entries.last().map_or(0, |e| e.seq);
+    let entries = entries
+        .into_iter()
+        .filter(|e| e.seq > after)
+        .take(limit)
+        .collect();
+
+    Ok(Page { head, entries })
+}
+
+/// At most `limit` of the entries after sequence number `since` that
+/// changed the index, leaving out those of crates that are not
+/// `visible`, oldest first.
+pub fn changes(
+    path: &Path,
+    since: u64,
+    limit: usize,
+    visible: impl Fn(&CrateName) -> bool,
+) -> Result<Page, Error> {
+    let entries = read(path)?;
+    let head = entries.last().map_or(0, |e| e.seq);
+    let entries = entries
+        .into_iter()
+        .filter(|e| e.seq > since && e.event.changes_index())
+        .filter(|e| e.event.crate_name().map_or(true, &visible))
+        .take(limit)
+        .collect();
+
+    Ok(Page { head, entries })
+}
+
+/// The complete entries from byte `offset` on, and the offset just past
+/// them, for following the log as it grows. A log shorter than
+/// `offset` was replaced, and is read again from the start.
+#[cfg(feature = "server")]
+pub fn tail(path: &Path, offset: u64) -> Result<(Vec<Entry>, u64), Error> {
+    use error::*;
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(e).context(OpenSnafu { path }),
+    };
+
+    let len = file.metadata().context(ReadAtSnafu { path, offset })?.len();
+    let offset = if len < offset { 0 } else { offset };
+    if len == offset {
+        return Ok((vec![], offset));
+    }
+
+    let mut data = vec![];
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_to_end(&mut data))
+        .context(ReadAtSnafu { path, offset })?;
+
+    // A line still being written is read next time
+    let complete = data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+
+    let mut entries = vec![];
+    let mut at = offset;
+    for line in data[..complete].split_inclusive(|&b| b == b'\n') {
+        let entry = serde_json::from_slice(line).context(ParseAtSnafu { path, offset: at })?;
+        entries.push(entry);
+        at += line.len() as u64;
+    }
+
+    Ok((entries, at))
+}
+
+/// The offset just past the last complete entry, for following the
+/// log with [`tail`] from now on.
+#[cfg(feature = "server")]
+pub fn end(path: &Path) -> Result<u64, Error> {
+    use error::*;
+
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).context(ReadAtSnafu { path, offset: 0u64 }),
+    };
+
+    Ok(data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1) as u64)
+}
+
+pub fn append(path: &Path, event: Event) -> Result<Entry, Error> {
+    use error::*;
+
+    // Crates in different shards of the index are changed at the same
+    // time, and each entry needs the sequence number after the last
+    static APPENDING: Mutex<()> = Mutex::new(());
+    let _appending = APPENDING.lock().unwrap_or_else(PoisonError::into_inner);
+
+    // FUTURE: Avoid re-reading the whole log to find the next sequence number
+    let seq = read(path)?.last().map_or(1, |e| e.seq + 1);
+
+    let entry = Entry {
+        seq,
+        time: Timestamp::now(),
+        event,
+    };
+
+    let mut line = serde_json::to_vec(&entry).context(SerializeSnafu)?;
+    line.push(b'\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(OpenSnafu { path })?;
+    file.write_all(&line).context(WriteSnafu { path })?;
+
+    Ok(entry)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not open the audit log at {}", path.display()))]
+    Open { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not read line {line} of the audit log at {}", path.display()))]
+    Read {
+        source: io::Error,
+        path: PathBuf,
+        line: usize,
+    },
+
+    #[snafu(display("Could not parse line {line} of the audit log at {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+        line: usize,
+    },
+
+    #[cfg(feature = "server")]
+    #[snafu(display("Could not read the audit log at {} from byte {offset}", path.display()))]
+    ReadAt {
+        source: io::Error,
+        path: PathBuf,
+        offset: u64,
+    },
+
+    #[cfg(feature = "server")]
+    #[snafu(display("Could not parse the audit log entry at byte {offset} of {}", path.display()))]
+    ParseAt {
+        source: serde_json::Error,
+        path: PathBuf,
+        offset: u64,
+    },
+
+    #[snafu(display("Could not serialize the audit log entry"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not append to the audit log at {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Open { .. } | Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_AUDIT_CORRUPT",
+            #[cfg(feature = "server")]
+            Self::ReadAt { .. } => "E_STORAGE_READ",
+            #[cfg(feature = "server")]
+            Self::ParseAt { .. } => "E_AUDIT_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn changes_are_the_entries_that_touched_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join(FILE_NAME);
+
+        let name = |n: &str| n.parse::<CrateName>().unwrap();
+        let vers = Version::new(1, 0, 0);
+        let events = [
+            Event::Add {
+                name: name("public"),
+                vers: vers.clone(),
+                cksum: "00".into(),
+            },
+            Event::PutBlob {
+                digest: "00".into(),
+                size: 1,
+            },
+            Event::Yank {
+                name: name("secret"),
+                vers: vers.clone(),
+            },
+            Event::Yank {
+                name: name("public"),
+                vers,
+            },
+        ];
+        for event in events {
+            append(&path, event).unwrap();
+        }
+
+        let visible = |name: &CrateName| name.as_str() != "secret";
+        let seqs = |page: Page| page.entries.iter().map(|e| e.seq).collect::<Vec<_>>();
+
+        let page = changes(&path, 0, 10, visible).unwrap();
+        assert_eq!(4, page.head);
+        assert_eq!(vec![1, 4], seqs(page));
+        assert_eq!(vec![4], seqs(changes(&path, 1, 10, visible).unwrap()));
+        assert_eq!(vec![1], seqs(changes(&path, 0, 1, visible).unwrap()));
+    }
+}
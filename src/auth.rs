@@ -0,0 +1,753 @@
+//! Who may publish to a tenant, and which crates they own.
+//!
+//! Publishers present either one of the tenant's static tokens or a
+//! token minted by `POST /api/v1/tokens` from an SSO identity (an OIDC
+//! access token, or an LDAP username and password). Either way the
+//! token resolves to a stable [`UserId`], and that is what owner
+//! records store, so replacing a token does not change who owns a
+//! crate.
+//!
+//! Every token carries [`Scope`]s limiting what it may do, and minted
+//! tokens may also expire. Any valid token may read the registry.
+//!
+//! Minted tokens and owner records are kept in the tenant's
+//! `data-dir`, which must not be inside the registry: everything in
+//! the registry is served to the public. `margo token list` and
+//! `margo token revoke` manage the minted tokens there; a running
+//! daemon notices revocations the next time the token is used.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+
+use crate::{
+    common::{glob_matches, CrateName},
+    index_lock::IndexLocks,
+    timestamp::Timestamp,
+};
+
+/// A user, prefixed by how they authenticated (`token:`, `oidc:`, or
+/// `ldap:`).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UserId(String);
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// What a token may do.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Scope {
+    /// `publish:{glob}`: publish crates whose names match the glob,
+    /// where `*` matches any run of characters.
+    Publish(String),
+
+    /// `yank`: yank and unyank versions of crates the user owns.
+    Yank,
+
+    /// `blob`: store content-addressed files.
+    Blob,
+
+    /// `approve`: approve or reject publishes waiting for approval.
+    Approve,
+
+    /// `admin`: everything, regardless of who owns a crate.
+    Admin,
+}
+
+impl Scope {
+    /// What static tokens, and minted tokens that don't ask for
+    /// anything narrower, are allowed.
+    pub fn defaults() -> BTreeSet<Self> {
+        [Self::Publish("*".to_owned()), Self::Yank].into()
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Publish(glob) => write!(f, "publish:{glob}"),
+            Self::Yank => "yank".fmt(f),
+            Self::Blob => "blob".fmt(f),
+            Self::Approve => "approve".fmt(f),
+            Self::Admin => "admin".fmt(f),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ScopeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yank" => return Ok(Self::Yank),
+            "blob" => return Ok(Self::Blob),
+            "approve" => return Ok(Self::Approve),
+            "admin" => return Ok(Self::Admin),
+            _ => {}
+        }
+
+        let glob = s.strip_prefix("publish:").filter(|glob| {
+            !glob.is_empty()
+                && glob
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '*'))
+        });
+
+        match glob {
+            Some(glob) => Ok(Self::Publish(glob.to_owned())),
+            None => ScopeSnafu { scope: s }.fail(),
+        }
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = ScopeError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.to_string()
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(display("`{scope}` is not a scope; expected `publish:{{glob}}`, `yank`, or `admin`"))]
+pub struct ScopeError {
+    scope: String,
+}
+
+/// The user behind a token and what the token allows them to do.
+#[derive(Debug, Clone)]
+pub struct Grant {
+    pub user: UserId,
+    pub scopes: BTreeSet<Scope>,
+}
+
+impl Grant {
+    pub fn is_admin(&self) -> bool {
+        self.scopes.contains(&Scope::Admin)
+    }
+
+    pub fn may_publish(&self, name: &CrateName) -> bool {
+        self.is_admin()
+            || self.scopes.iter().any(|scope| match scope {
+                Scope::Publish(glob) => glob_matches(glob, name.as_str()),
+                _ => false,
+            })
+    }
+
+    pub fn may_yank(&self) -> bool {
+        self.is_admin() || self.scopes.contains(&Scope::Yank)
+    }
+
+    pub fn may_put_blobs(&self) -> bool {
+        self.is_admin() || self.scopes.contains(&Scope::Blob)
+    }
+
+    pub fn may_approve(&self) -> bool {
+        self.is_admin() || self.scopes.contains(&Scope::Approve)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PublishConfig {
+    data_dir: PathBuf,
+
+    /// Hold every publish until a reviewer approves it.
+    #[serde(default)]
+    require_approval: bool,
+
+    /// Users who may mint tokens with the `admin` scope.
+    #[cfg(any(feature = "oidc", feature = "ldap"))]
+    #[serde(default)]
+    admins: BTreeSet<UserId>,
+
+    #[cfg(feature = "oidc")]
+    #[serde(default)]
+    oidc: Option<OidcConfig>,
+
+    #[cfg(feature = "ldap")]
+    #[serde(default)]
+    ldap: Option<LdapConfig>,
+}
+
+#[cfg(feature = "oidc")]
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct OidcConfig {
+    issuer: url::Url,
+
+    /// Discovered from the issuer when not given.
+    #[serde(default)]
+    userinfo_endpoint: Option<url::Url>,
+}
+
+#[cfg(feature = "ldap")]
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct LdapConfig {
+    url: String,
+
+    /// The DN to bind as, with `{user}` replaced by the username.
+    bind_dn: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Tokens {
+    /// Keyed by the SHA-256 of the token.
+    tokens: BTreeMap<String, MintedToken>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MintedToken {
+    user: UserId,
+
+    created_at: Timestamp,
+
+    #[serde(default = "Scope::defaults")]
+    scopes: BTreeSet<Scope>,
+
+    #[serde(default)]
+    expires_at: Option<Timestamp>,
+}
+
+impl MintedToken {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| t <= Timestamp::now())
+    }
+}
+
+/// What a new token should be allowed to do; the body of
+/// `POST /api/v1/tokens`.
+#[cfg(any(feature = "oidc", feature = "ldap"))]
+#[derive(Debug, Default, Deserialize)]
+pub struct MintRequest {
+    /// Defaults to [`Scope::defaults`].
+    #[serde(default)]
+    pub scopes: Option<BTreeSet<Scope>>,
+
+    #[serde(default)]
+    pub expires_at: Option<Timestamp>,
+}
+
+/// A minted token as shown by `margo token list`. The ID is the start
+/// of the token's hash, so it can be shared without revealing the
+/// token.
+#[derive(Debug, Serialize)]
+pub struct TokenSummary {
+    pub id: String,
+    pub user: UserId,
+    pub scopes: BTreeSet<Scope>,
+    pub created_at: Timestamp,
+    pub expires_at: Option<Timestamp>,
+}
+
+impl TokenSummary {
+    fn new(hash: &str, token: &MintedToken) -> Self {
+        Self {
+            id: token_id(hash).to_owned(),
+            user: token.user.clone(),
+            scopes: token.scopes.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+        }
+    }
+}
+
+fn token_id(hash: &str) -> &str {
+    hash.get(..16).unwrap_or(hash)
+}
+
+/// The minted tokens, along with when their file was last read.
+#[derive(Debug)]
+struct TokenFile {
+    tokens: Tokens,
+    modified: Option<SystemTime>,
+}
+
+impl TokenFile {
+    fn load(path: &Path) -> Result<Self, Error> {
+        let modified = modified_time(path);
+        let tokens = load(path)?;
+        Ok(Self { tokens, modified })
+    }
+
+    /// Rereads the file if something else, such as `margo token
+    /// revoke`, has changed it.
+    fn refresh(&mut self, path: &Path) {
+        if modified_time(path) == self.modified {
+            return;
+        }
+
+        match Self::load(path) {
+            Ok(file) => *self = file,
+            Err(e) => eprintln!("Warning: {e}"),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Owners {
+    crates: BTreeMap<CrateName, BTreeSet<UserId>>,
+}
+
+#[derive(Debug)]
+pub struct Publisher {
+    config: PublishConfig,
+    tokens: Mutex<TokenFile>,
+    owners: Mutex<Owners>,
+    crates: IndexLocks,
+    queue: Mutex<()>,
+}
+
+const TOKENS_FILE_NAME: &str = "tokens.json";
+const OWNERS_FILE_NAME: &str = "owners.json";
+
+impl Publisher {
+    pub fn new(config: PublishConfig) -> Result<Self, Error> {
+        use error::*;
+
+        let data_dir = &config.data_dir;
+        fs::create_dir_all(data_dir).context(DataDirSnafu { path: data_dir })?;
+
+        let tokens = TokenFile::load(&data_dir.join(TOKENS_FILE_NAME))?;
+        let owners = load(&data_dir.join(OWNERS_FILE_NAME))?;
+
+        Ok(Self {
+            config,
+            tokens: Mutex::new(tokens),
+            owners: Mutex::new(owners),
+            crates: IndexLocks::default(),
+            queue: Mutex::new(()),
+        })
+    }
+
+    fn lock_tokens(&self) -> MutexGuard<'_, TokenFile> {
+        let mut tokens = self.tokens.lock().unwrap_or_else(PoisonError::into_inner);
+        tokens.refresh(&self.config.data_dir.join(TOKENS_FILE_NAME));
+        tokens
+    }
+
+    /// What a minted token allows, unless it has expired or been
+    /// revoked.
+    pub fn grant_for_token(&self, token_hash: &str) -> Option<Grant> {
+        let tokens = self.lock_tokens();
+        let token = tokens.tokens.tokens.get(token_hash)?;
+
+        if token.is_expired() {
+            return None;
+        }
+
+        Some(Grant {
+            user: token.user.clone(),
+            scopes: token.scopes.clone(),
+        })
+    }
+
+    /// Exchanges the credentials in an `Authorization` header for a new
+    /// registry token.
+    #[cfg(any(feature = "oidc", feature = "ldap"))]
+    pub fn mint(
+        &self,
+        authorization: &str,
+        request: MintRequest,
+    ) -> Result<(TokenSummary, String), Error> {
+        use error::*;
+
+        let user = self.authenticate(authorization)?;
+
+        let scopes = request.scopes.unwrap_or_else(Scope::defaults);
+        ensure!(
+            !scopes.contains(&Scope::Admin) || self.config.admins.contains(&user),
+            NotAdminSnafu { user }
+        );
+
+        let mut secret = [0; 32];
+        getrandom::getrandom(&mut secret).context(RandomSnafu)?;
+        let token = format!("margo_{}", hex::encode(secret));
+        let hash = crate::tenant::hash_token(&token);
+
+        let minted = MintedToken {
+            user,
+            created_at: Timestamp::now(),
+            scopes,
+            expires_at: request.expires_at,
+        };
+        let summary = TokenSummary::new(&hash, &minted);
+
+        let path = self.config.data_dir.join(TOKENS_FILE_NAME);
+        let mut tokens = self.lock_tokens();
+        tokens.tokens.tokens.insert(hash, minted);
+        save(&path, &tokens.tokens)?;
+        tokens.modified = modified_time(&path);
+
+        Ok((summary, token))
+    }
+
+    #[cfg(any(feature = "oidc", feature = "ldap"))]
+    fn authenticate(&self, authorization: &str) -> Result<UserId, Error> {
+        #[cfg(feature = "oidc")]
+        if let (Some(config), Some(token)) =
+            (&self.config.oidc, authorization.strip_prefix("Bearer "))
+        {
+            return oidc_user(config, token);
+        }
+
+        #[cfg(feature = "ldap")]
+        if let (Some(config), Some(basic)) =
+            (&self.config.ldap, authorization.strip_prefix("Basic "))
+        {
+            return ldap_user(config, basic);
+        }
+
+        _ = authorization;
+        error::NoProviderSnafu.fail()
+    }
+
+    /// Where minted tokens, owner records, publishes queued during
+    /// maintenance, and uploads being received are kept.
+    pub fn data_dir(&self) -> &Path {
+        &self.config.data_dir
+    }
+
+    pub fn requires_approval(&self) -> bool {
+        self.config.require_approval
+    }
+
+    /// Holds the crate's shard of the index locked until the returned
+    /// guard is dropped, so that checking ownership, changing the crate,
+    /// and recording a new owner happen as one step, while crates in
+    /// other shards are changed at the same time.
+    pub fn lock_crate(&self, name: &CrateName) -> MutexGuard<'_, ()> {
+        self.crates.lock(name)
+    }
+
+    /// Holds the publish queue locked until the returned guard is
+    /// dropped, so that a queued crate is only added once.
+    pub fn lock_queue(&self) -> MutexGuard<'_, ()> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Holds the owner records locked until the returned guard is
+    /// dropped. Only for as long as reading or recording an owner
+    /// takes; [`Self::lock_crate`] keeps a crate's owners from changing
+    /// under a publish.
+    pub fn lock_owners(&self) -> OwnersGuard<'_> {
+        OwnersGuard {
+            owners: self.owners.lock().unwrap_or_else(PoisonError::into_inner),
+            path: self.config.data_dir.join(OWNERS_FILE_NAME),
+        }
+    }
+}
+
+pub struct OwnersGuard<'a> {
+    owners: MutexGuard<'a, Owners>,
+    path: PathBuf,
+}
+
+impl OwnersGuard<'_> {
+    /// A crate nobody owns yet may be published by anyone.
+    pub fn may_publish(&self, name: &CrateName, user: &UserId) -> bool {
+        self.owners
+            .crates
+            .get(name)
+            .map_or(true, |owners| owners.contains(user))
+    }
+
+    pub fn is_owner(&self, name: &CrateName, user: &UserId) -> bool {
+        self.owners
+            .crates
+            .get(name)
+            .is_some_and(|owners| owners.contains(user))
+    }
+
+    #[cfg(feature = "nostr")]
+    pub fn owners_of(&self, name: &CrateName) -> BTreeSet<UserId> {
+        self.owners.crates.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn claim(&mut self, name: &CrateName, user: &UserId) -> Result<(), Error> {
+        let owners = self.owners.crates.entry(name.clone()).or_default();
+        if owners.insert(user.clone()) {
+            save(&self.path, &*self.owners)?;
+        }
+        Ok(())
+    }
+}
+
+impl UserId {
+    pub fn static_token(token_hash: &str) -> Self {
+        Self(format!("token:{}", token_id(token_hash)))
+    }
+}
+
+/// Every minted token in a tenant's data directory, including expired
+/// ones.
+pub fn list_tokens(data_dir: &Path) -> Result<Vec<TokenSummary>, Error> {
+    let tokens: Tokens = load(&data_dir.join(TOKENS_FILE_NAME))?;
+
+    Ok(tokens
+        .tokens
+        .iter()
+        .map(|(hash, token)| TokenSummary::new(hash, token))
+        .collect())
+}
+
+/// Deletes the minted token whose ID starts with `id`.
+pub fn revoke_token(data_dir: &Path, id: &str) -> Result<TokenSummary, Error> {
+    use error::*;
+
+    let path = data_dir.join(TOKENS_FILE_NAME);
+    let mut tokens: Tokens = load(&path)?;
+
+    let mut matches = tokens
+        .tokens
+        .keys()
+        .filter(|hash| !id.is_empty() && hash.starts_with(id));
+    let hash = matches.next().context(UnknownTokenSnafu { id })?.clone();
+    ensure!(matches.next().is_none(), AmbiguousTokenSnafu { id });
+
+    let token = tokens
+        .tokens
+        .remove(&hash)
+        .expect("The token was just found");
+    save(&path, &tokens)?;
+
+    Ok(TokenSummary::new(&hash, &token))
+}
+
+#[cfg(feature = "oidc")]
+fn oidc_user(config: &OidcConfig, access_token: &str) -> Result<UserId, Error> {
+    use error::*;
+
+    #[derive(Deserialize)]
+    struct Discovery {
+        userinfo_endpoint: String,
+    }
+
+    #[derive(Deserialize)]
+    struct UserInfo {
+        sub: String,
+    }
+
+    let endpoint = match &config.userinfo_endpoint {
+        Some(e) => e.to_string(),
+        None => {
+            let issuer = config.issuer.as_str().trim_end_matches('/');
+            let url = format!("{issuer}/.well-known/openid-configuration");
+            let discovery: Discovery = ureq::get(&url)
+                .call()
+                .context(OidcRequestSnafu { url: &url })?
+                .into_json()
+                .context(OidcResponseSnafu { url })?;
+            discovery.userinfo_endpoint
+        }
+    };
+
+    let info: UserInfo = ureq::get(&endpoint)
+        .set("Authorization", &format!("Bearer {access_token}"))
+        .call()
+        .context(OidcRequestSnafu { url: &endpoint })?
+        .into_json()
+        .context(OidcResponseSnafu { url: endpoint })?;
+
+    Ok(UserId(format!("oidc:{}#{}", config.issuer, info.sub)))
+}
+
+#[cfg(feature = "ldap")]
+fn ldap_user(config: &LdapConfig, basic: &str) -> Result<UserId, Error> {
+    use base64::Engine;
+    use error::*;
+
+    let credentials = base64::engine::general_purpose::STANDARD
+        .decode(basic.trim())
+        .ok()
+        .and_then(|c| String::from_utf8(c).ok())
+        .context(MalformedCredentialsSnafu)?;
+    let (user, password) = credentials
+        .split_once(':')
+        .context(MalformedCredentialsSnafu)?;
+
+    // Anything else would need escaping inside a DN, and an empty
+    // password is an anonymous bind that always succeeds.
+    let valid_user = !user.is_empty()
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    ensure!(
+        valid_user && !password.is_empty(),
+        MalformedCredentialsSnafu
+    );
+
+    let dn = config.bind_dn.replace("{user}", user);
+
+    let mut conn = ldap3::LdapConn::new(&config.url).context(LdapSnafu)?;
+    conn.simple_bind(&dn, password)
+        .and_then(|r| r.success())
+        .context(LdapSnafu)?;
+    _ = conn.unbind();
+
+    Ok(UserId(format!("ldap:{dn}")))
+}
+
+/// The crates `user` owns, by the owner records in a tenant's
+/// `data-dir`.
+pub fn crates_owned_by(data_dir: &Path, user: &str) -> Result<BTreeSet<CrateName>, Error> {
+    let owners: Owners = load(&data_dir.join(OWNERS_FILE_NAME))?;
+
+    let owned = owners
+        .crates
+        .into_iter()
+        .filter(|(_, owners)| owners.iter().any(|o| o.0 == user))
+        .map(|(name, _)| name)
+        .collect();
+    Ok(owned)
+}
+
+fn load<T: Default + for<'de> Deserialize<'de>>(path: &Path) -> Result<T, Error> {
+    use error::*;
+
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).context(DeserializeSnafu { path }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e).context(ReadSnafu { path }),
+    }
+}
+
+fn save(path: &Path, value: &impl Serialize) -> Result<(), Error> {
+    use error::*;
+
+    let data = serde_json::to_vec_pretty(value).context(SerializeSnafu)?;
+    fs::write(path, data).context(WriteSnafu { path })
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not create the data directory {}", path.display()))]
+    DataDir { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not deserialize {}", path.display()))]
+    Deserialize {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the publishing data"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("No token has an ID starting with `{id}`"))]
+    UnknownToken { id: String },
+
+    #[snafu(display("More than one token has an ID starting with `{id}`"))]
+    AmbiguousToken { id: String },
+
+    #[cfg(any(feature = "oidc", feature = "ldap"))]
+    #[snafu(display("`{user}` may not mint tokens with the `admin` scope"))]
+    NotAdmin { user: UserId },
+
+    #[cfg(any(feature = "oidc", feature = "ldap"))]
+    #[snafu(display("Could not generate a token"))]
+    Random { source: getrandom::Error },
+
+    #[cfg(any(feature = "oidc", feature = "ldap"))]
+    #[snafu(display("No identity provider accepts these credentials"))]
+    NoProvider,
+
+    #[cfg(feature = "oidc")]
+    #[snafu(display("The OIDC request to {url} failed"))]
+    OidcRequest {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: String,
+    },
+
+    #[cfg(feature = "oidc")]
+    #[snafu(display("Could not deserialize the OIDC response from {url}"))]
+    OidcResponse { source: io::Error, url: String },
+
+    #[cfg(feature = "ldap")]
+    #[snafu(display("The credentials are not a valid `username:password` pair"))]
+    MalformedCredentials,
+
+    #[cfg(feature = "ldap")]
+    #[snafu(display("The LDAP bind failed"))]
+    Ldap { source: ldap3::LdapError },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::DataDir { .. } | Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Deserialize { .. } => "E_AUTH_DATA_CORRUPT",
+            Self::UnknownToken { .. } => "E_TOKEN_NOT_FOUND",
+            Self::AmbiguousToken { .. } => "E_TOKEN_AMBIGUOUS",
+            #[cfg(any(feature = "oidc", feature = "ldap"))]
+            Self::NotAdmin { .. } => "E_FORBIDDEN",
+            #[cfg(any(feature = "oidc", feature = "ldap"))]
+            Self::Random { .. } => "E_INTERNAL",
+            #[cfg(any(feature = "oidc", feature = "ldap"))]
+            Self::NoProvider => "E_UNAUTHORIZED",
+            #[cfg(feature = "oidc")]
+            Self::OidcRequest { .. } | Self::OidcResponse { .. } => "E_UNAUTHORIZED",
+            #[cfg(feature = "ldap")]
+            Self::MalformedCredentials | Self::Ldap { .. } => "E_UNAUTHORIZED",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn publish_globs_match_crate_names() {
+        assert!(glob_matches("*", "serde"));
+        assert!(glob_matches("serde", "Serde"));
+        assert!(glob_matches("serde-*", "serde-json"));
+        assert!(glob_matches("*-derive", "serde-derive"));
+        assert!(glob_matches("a*b*c", "abc"));
+        assert!(!glob_matches("serde", "serde-json"));
+        assert!(!glob_matches("serde-*", "serde"));
+        assert!(!glob_matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn scopes_round_trip_as_strings() {
+        for scope in ["publish:acme-*", "yank", "blob", "approve", "admin"] {
+            assert_eq!(scope, scope.parse::<Scope>().unwrap().to_string());
+        }
+
+        assert!("publish:".parse::<Scope>().is_err());
+        assert!("publish:a/b".parse::<Scope>().is_err());
+        assert!("owner".parse::<Scope>().is_err());
+    }
+}
@@ -0,0 +1,29 @@
+//! `cargo gnostr-registry ARGS` runs `margo ARGS`, from the directory
+//! this is installed in when margo is there too.
+
+// The package's dependencies are all margo's
+#![allow(unused_crate_dependencies)]
+
+use std::{env, process};
+
+fn main() {
+    let mut args = env::args_os().skip(1).peekable();
+
+    // Cargo passes the name of the subcommand first
+    args.next_if(|a| a == "gnostr-registry");
+
+    let name = format!("margo{}", env::consts::EXE_SUFFIX);
+    let margo = env::current_exe()
+        .map(|exe| exe.with_file_name(&name))
+        .ok()
+        .filter(|path| path.is_file())
+        .unwrap_or_else(|| name.into());
+
+    match process::Command::new(&margo).args(args).status() {
+        Ok(status) => process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            eprintln!("Error: Could not run {}: {e}", margo.display());
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+//! Content-addressed files that are not crates.
+//!
+//! Toolchain tarballs and other assets can be hosted next to the
+//! crates. Each blob is stored once under `blobs/sha256/{aa}/{digest}`
+//! in the registry, where `{aa}` is the first two characters of its
+//! SHA-256, and is served like every other registry file. Since the
+//! name is the hash, a blob fetched from anywhere, including a peer,
+//! can be checked against the name it was asked for.
+
+use sha2::Digest;
+use snafu::prelude::*;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{audit, Registry};
+
+pub const DIR_NAME: &str = "blobs";
+
+const ALGORITHM: &str = "sha256";
+
+/// How much of a blob is hashed at a time by [`verify`] and
+/// [`store_file`].
+const CHUNK_LEN: usize = 256 * 1024;
+
+pub fn digest_of(data: &[u8]) -> String {
+    hex::encode(sha2::Sha256::digest(data))
+}
+
+pub fn is_digest(digest: &str) -> bool {
+    digest.len() == 64
+        && digest
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Where the blob lives below `blobs_dir`, relative to it, as a URL
+/// path.
+#[cfg(feature = "server")]
+pub fn relative_path(digest: &str) -> String {
+    format!("{ALGORITHM}/{}/{digest}", &digest[..2])
+}
+
+/// Where the blob lives on disk. Built a component at a time, as
+/// Windows long paths do not accept `/` as a separator.
+pub fn path_in(blobs_dir: &Path, digest: &str) -> PathBuf {
+    let mut path = blobs_dir.to_owned();
+    path.extend([ALGORITHM, &digest[..2], digest]);
+    path
+}
+
+/// Stores the blob unless it already exists. Returns its digest and
+/// whether it was new.
+pub fn put(registry: &Registry, data: &[u8]) -> Result<(String, bool), Error> {
+    use error::*;
+
+    let digest = digest_of(data);
+    let new = store(&registry.blobs_dir(), &digest, data)?;
+
+    if new {
+        record_put(registry, &digest, data.len() as u64)?;
+    }
+
+    Ok((digest, new))
+}
+
+/// Stores the file at `path` as [`put`] does, without reading all of it
+/// into memory. Returns its digest, its length and whether it was new.
+pub fn put_file(registry: &Registry, path: &Path) -> Result<(String, u64, bool), Error> {
+    let (digest, len, new) = store_file(&registry.blobs_dir(), path)?;
+
+    if new {
+        record_put(registry, &digest, len)?;
+    }
+
+    Ok((digest, len, new))
+}
+
+fn record_put(registry: &Registry, digest: &str, size: u64) -> Result<(), Error> {
+    use error::*;
+
+    registry
+        .record(audit::Event::PutBlob {
+            digest: digest.to_owned(),
+            size,
+        })
+        .context(AuditSnafu)
+}
+
+/// Writes the blob to `blobs_dir` without recording it. The digest
+/// must already have been checked against the data.
+pub fn store(blobs_dir: &Path, digest: &str, data: &[u8]) -> Result<bool, Error> {
+    use error::*;
+
+    let path = path_in(blobs_dir, digest);
+    if path.exists() {
+        return Ok(false);
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, &path).context(WriteSnafu { path })?;
+
+    Ok(true)
+}
+
+/// Copies the file at `path` into `blobs_dir` a chunk at a time,
+/// hashing it as it goes, unless the blob is already there. Returns its
+/// digest, its length and whether it was new.
+pub fn store_file(blobs_dir: &Path, path: &Path) -> Result<(String, u64, bool), Error> {
+    use error::*;
+
+    let mut source = fs::File::open(path).context(ReadSnafu { path })?;
+
+    // The blob's name is not known until all of it has been read
+    fs::create_dir_all(blobs_dir).context(WriteSnafu { path: blobs_dir })?;
+    let tmp = blobs_dir.join(format!("{}.tmp", std::process::id()));
+    let mut file = fs::File::create(&tmp).context(WriteSnafu { path: &tmp })?;
+    let copied = hash_chunks(&mut source, path, |chunk| {
+        file.write_all(chunk).context(WriteSnafu { path: &tmp })
+    });
+    drop(file);
+    let (digest, len) = match copied {
+        Ok(copied) => copied,
+        Err(e) => {
+            _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+
+    let stored = path_in(blobs_dir, &digest);
+    if stored.exists() {
+        _ = fs::remove_file(&tmp);
+        return Ok((digest, len, false));
+    }
+
+    if let Some(parent) = stored.parent() {
+        fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+    }
+    fs::rename(&tmp, &stored).context(WriteSnafu { path: stored })?;
+
+    Ok((digest, len, true))
+}
+
+/// Reads the blob and checks that it still has the digest it is stored
+/// under.
+pub fn get(blobs_dir: &Path, digest: &str) -> Result<Vec<u8>, Error> {
+    use error::*;
+
+    ensure!(is_digest(digest), DigestSnafu { digest });
+
+    let path = path_in(blobs_dir, digest);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return UnknownSnafu { digest }.fail();
+        }
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+
+    let actual = digest_of(&data);
+    ensure!(
+        actual == digest,
+        CorruptSnafu {
+            expected: digest,
+            actual,
+        }
+    );
+
+    Ok(data)
+}
+
+/// Opens the blob to be sent, along with its length, without hashing
+/// it again: [`store`] only writes blobs whose digest has been checked,
+/// and whoever fetches one can check it against the digest they asked
+/// for. [`verify`] finds blobs that have changed on disk since.
+pub fn open(blobs_dir: &Path, digest: &str) -> Result<(fs::File, u64), Error> {
+    use error::*;
+
+    ensure!(is_digest(digest), DigestSnafu { digest });
+
+    let path = path_in(blobs_dir, digest);
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return UnknownSnafu { digest }.fail();
+        }
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+    let len = file.metadata().context(ReadSnafu { path })?.len();
+
+    Ok((file, len))
+}
+
+/// Checks that the blob still has the digest it is stored under,
+/// reading it a chunk at a time so that blobs of any size can be
+/// checked, and returns where it is and its length.
+pub fn verify(blobs_dir: &Path, digest: &str) -> Result<(PathBuf, u64), Error> {
+    use error::*;
+
+    let (mut file, _) = open(blobs_dir, digest)?;
+    let path = path_in(blobs_dir, digest);
+
+    let (actual, len) = hash_chunks(&mut file, &path, |_| Ok(()))?;
+    ensure!(
+        actual == digest,
+        CorruptSnafu {
+            expected: digest,
+            actual,
+        }
+    );
+
+    Ok((path, len))
+}
+
+/// Reads `from`, the file at `path`, to its end a chunk at a time,
+/// handing each chunk to `f`, and returns the digest and length of all
+/// of it.
+fn hash_chunks(
+    from: &mut fs::File,
+    path: &Path,
+    mut f: impl FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<(String, u64), Error> {
+    use error::*;
+
+    let mut sha256 = sha2::Sha256::new();
+    let mut chunk = vec![0; CHUNK_LEN];
+    let mut len = 0;
+    loop {
+        let n = match from.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context(ReadSnafu { path }),
+        };
+        sha256.update(&chunk[..n]);
+        f(&chunk[..n])?;
+        len += n as u64;
+    }
+
+    Ok((hex::encode(sha256.finalize()), len))
+}
+
+/// Every stored blob's digest and size, in digest order.
+pub fn list(blobs_dir: &Path) -> Result<Vec<(String, u64)>, Error> {
+    use error::*;
+
+    let root = blobs_dir.join(ALGORITHM);
+    if !root.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut blobs = vec![];
+    for entry in walkdir::WalkDir::new(&root).min_depth(2).max_depth(2) {
+        let entry = entry.context(WalkSnafu { path: &root })?;
+        let digest = entry.file_name().to_string_lossy();
+        if !is_digest(&digest) {
+            continue;
+        }
+
+        let metadata = entry.metadata().context(WalkSnafu { path: &root })?;
+        blobs.push((digest.into_owned(), metadata.len()));
+    }
+    blobs.sort();
+
+    Ok(blobs)
+}
+
+pub fn remove(registry: &Registry, digest: &str) -> Result<(), Error> {
+    use error::*;
+
+    ensure!(is_digest(digest), DigestSnafu { digest });
+
+    let path = path_in(&registry.blobs_dir(), digest);
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return UnknownSnafu { digest }.fail();
+        }
+        Err(e) => return Err(e).context(WriteSnafu { path }),
+    }
+
+    registry
+        .record(audit::Event::RemoveBlob {
+            digest: digest.to_owned(),
+        })
+        .context(AuditSnafu)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("`{digest}` is not a SHA-256 digest"))]
+    Digest { digest: String },
+
+    #[snafu(display("There is no blob `{digest}`"))]
+    Unknown { digest: String },
+
+    #[snafu(display("The blob stored as {expected} has the digest {actual}"))]
+    Corrupt { expected: String, actual: String },
+
+    #[snafu(display("Could not read the blob {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not list the blobs in {}", path.display()))]
+    Walk {
+        source: walkdir::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not write the blob to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not record the blob in the audit log"))]
+    Audit { source: audit::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Digest { .. } => "E_BAD_DIGEST",
+            Self::Unknown { .. } => "E_BLOB_NOT_FOUND",
+            Self::Corrupt { .. } => "E_BAD_CHECKSUM",
+            Self::Read { .. } | Self::Walk { .. } => "E_STORAGE_READ",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Audit { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blobs_are_found_by_their_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let data = b"toolchain";
+        let digest = digest_of(data);
+        assert!(is_digest(&digest));
+
+        assert!(store(dir, &digest, data).unwrap());
+        assert!(!store(dir, &digest, data).unwrap());
+        assert_eq!(data.to_vec(), get(dir, &digest).unwrap());
+        assert_eq!(vec![(digest.clone(), 9)], list(dir).unwrap());
+        assert_eq!((path_in(dir, &digest), 9), verify(dir, &digest).unwrap());
+        assert_eq!(9, open(dir, &digest).unwrap().1);
+
+        let large = vec![7; CHUNK_LEN * 2 + 1];
+        let large_digest = digest_of(&large);
+        let large_path = dir.join("large");
+        fs::write(&large_path, &large).unwrap();
+        let stored = store_file(dir, &large_path).unwrap();
+        assert_eq!((large_digest.clone(), large.len() as u64, true), stored);
+        assert!(!store_file(dir, &large_path).unwrap().2);
+        assert_eq!(large.len() as u64, verify(dir, &large_digest).unwrap().1);
+
+        let path = path_in(dir, &digest);
+        fs::write(&path, b"tampered").unwrap();
+        assert!(get(dir, &digest).is_err());
+        assert_eq!("E_BAD_CHECKSUM", verify(dir, &digest).unwrap_err().code());
+        let missing = verify(dir, &digest_of(b"")).unwrap_err();
+        assert_eq!("E_BLOB_NOT_FOUND", missing.code());
+        let missing = open(dir, &digest_of(b"")).unwrap_err();
+        assert_eq!("E_BLOB_NOT_FOUND", missing.code());
+        let bad = open(dir, "../../etc/passwd").unwrap_err();
+        assert_eq!("E_BAD_DIGEST", bad.code());
+
+        assert!(get(dir, "../../etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,436 @@
+//! A copy of the registry to carry to a machine with no network access.
+//!
+//! `margo bundle --name NAME --out DIR` writes three things to `DIR`:
+//! the registry's crates and index in `registry/`, a
+//! `cargo-config.toml` to add to the Cargo configuration there, and a
+//! `README.txt` saying how. Withheld versions are left out.
+//!
+//! With `--layout local`, the default, `registry/` is a Cargo local
+//! registry: an `index/` laid out as a git index and each `.crate` file
+//! as `{name}-{version}.crate`, which Cargo reads straight from disk.
+//! With `--layout sparse`, it is a margo registry for `margo serve` to
+//! serve on the offline network at `--base-url`, by default the same
+//! URL as now.
+//!
+//! Either way, the configuration replaces the registry's own source,
+//! and crates.io with `--replace-crates-io`, so that manifests naming
+//! the registry need no changes. Cargo wants absolute paths for a local
+//! registry, so `--target-dir` says where the bundle will be copied to.
+
+use semver::Version;
+use snafu::prelude::*;
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use url::Url;
+
+use crate::{common::CrateName, config_json, visibility, ListAllError, Registry, WriteIndexError};
+
+pub const REGISTRY_DIR_NAME: &str = "registry";
+
+pub const CARGO_CONFIG_FILE_NAME: &str = "cargo-config.toml";
+
+pub const INSTRUCTIONS_FILE_NAME: &str = "README.txt";
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Layout {
+    /// A Cargo local registry.
+    #[default]
+    Local,
+
+    /// A margo registry, served over HTTP.
+    Sparse,
+}
+
+impl FromStr for Layout {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Self::Local),
+            "sparse" => Ok(Self::Sparse),
+            _ => error::LayoutSnafu { layout: s }.fail(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Options<'a> {
+    /// What Cargo should call the registry.
+    pub name: &'a str,
+
+    pub layout: Layout,
+
+    /// Where the bundle will be on the offline machine.
+    pub target_dir: &'a Path,
+
+    /// Where the offline network will serve a sparse bundle.
+    pub base_url: &'a Url,
+
+    pub replace_crates_io: bool,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub versions: usize,
+
+    /// How many of the `.crate` files are hard links to the registry's.
+    pub linked: usize,
+
+    pub withheld: usize,
+}
+
+/// Writes the bundle to `out`, which must not exist yet.
+pub fn write(registry: &Registry, options: &Options<'_>, out: &Path) -> Result<Report, Error> {
+    use error::*;
+
+    ensure!(!out.exists(), ExistsSnafu { path: out });
+
+    let crates = registry.list_all().context(ListSnafu)?;
+    let dir = out.join(REGISTRY_DIR_NAME);
+
+    // The same place below `dir` as below the registry
+    let moved = |path: PathBuf| match path.strip_prefix(&registry.path) {
+        Ok(relative) => dir.join(relative),
+        Err(_) => path,
+    };
+
+    fs::create_dir_all(&dir).context(WriteSnafu { path: &dir })?;
+    if options.layout == Layout::Sparse {
+        write_config(registry, options.base_url, &dir)?;
+    }
+
+    let mut report = Report {
+        versions: 0,
+        linked: 0,
+        withheld: 0,
+    };
+    for (name, mut index) in crates {
+        let before = index.len();
+        index.retain(|_, entry| !entry.withheld);
+        report.withheld += before - index.len();
+        if index.is_empty() {
+            continue;
+        }
+
+        for vers in index.keys() {
+            let present = registry.promote(&name, vers).context(PromoteSnafu)?;
+            ensure!(
+                present,
+                MissingSnafu {
+                    name: name.clone(),
+                    version: vers.clone(),
+                }
+            );
+
+            let from = registry.crate_file_path_for(&name, vers);
+            let to = match options.layout {
+                Layout::Local => dir.join(format!("{name}-{vers}.crate")),
+                Layout::Sparse => moved(from.clone()),
+            };
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+            }
+
+            report.versions += 1;
+            if fs::hard_link(&from, &to).is_ok() {
+                report.linked += 1;
+                continue;
+            }
+            fs::copy(&from, &to).context(WriteSnafu { path: to })?;
+        }
+
+        let path = match options.layout {
+            Layout::Local => local_index_file_path(&dir, &name),
+            Layout::Sparse => moved(registry.index_file_path_for(&name)),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+        }
+        Registry::write_index_file(index, &path).context(IndexSnafu { path })?;
+    }
+
+    let registry_url = registry.config.base_url.as_str();
+    for (file_name, contents) in [
+        (CARGO_CONFIG_FILE_NAME, cargo_config(options, registry_url)),
+        (INSTRUCTIONS_FILE_NAME, instructions(options)),
+    ] {
+        let path = out.join(file_name);
+        fs::write(&path, contents).context(WriteSnafu { path })?;
+    }
+
+    Ok(report)
+}
+
+/// Where a local registry keeps the crate's index, as a git index
+/// would.
+fn local_index_file_path(dir: &Path, name: &CrateName) -> PathBuf {
+    let mut path = dir.join("index");
+    path.extend(
+        name.prefix_directories()
+            .iter()
+            .map(|d| d.to_ascii_lowercase()),
+    );
+    path.push(name.as_str().to_ascii_lowercase());
+    path
+}
+
+/// Copies the registry's configuration to `dir`, served at `base_url`.
+/// The copy holds every `.crate` file, so it has no cold tier.
+fn write_config(registry: &Registry, base_url: &Url, dir: &Path) -> Result<(), Error> {
+    use error::*;
+
+    let path = registry.margo_config_toml_path();
+    let config = fs::read_to_string(&path).context(ReadSnafu { path: &path })?;
+    let mut config: toml::Table = toml::from_str(&config).context(ParseConfigSnafu { path })?;
+    config.insert("base_url".into(), base_url.to_string().into());
+    config.remove("tiering");
+
+    let config = toml::to_string(&config).context(SerializeConfigSnafu)?;
+    let path = dir.join(crate::CONFIG_FILE_NAME);
+    fs::write(&path, config).context(WriteSnafu { path })?;
+
+    let path = registry.config_json_path();
+    let config = fs::read_to_string(&path).context(ReadSnafu { path: &path })?;
+    let mut config: config_json::Root =
+        serde_json::from_str(&config).context(ParseConfigJsonSnafu { path })?;
+    config.dl = format!("{base_url}crates/{{lowerprefix}}/{{crate}}/{{version}}.crate");
+    config.api = config.api.map(|_| base_url.to_string());
+
+    let config = serde_json::to_string(&config).context(SerializeConfigJsonSnafu)?;
+    let path = dir.join("config.json");
+    fs::write(&path, config).context(WriteSnafu { path })?;
+
+    let from = registry.path.join(visibility::FILE_NAME);
+    let to = dir.join(visibility::FILE_NAME);
+    match fs::copy(&from, &to) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context(WriteSnafu { path: to }),
+    }
+}
+
+/// The Cargo configuration for the offline machine. `registry_url` is
+/// where the registry is served now.
+pub fn cargo_config(options: &Options<'_>, registry_url: &str) -> String {
+    let name = options.name;
+    let offline = format!("{name}-offline");
+    let index = format!("sparse+{registry_url}");
+    let mut config = String::new();
+
+    _ = writeln!(config, "[registries.{name}]");
+    _ = writeln!(config, "index = {index:?}");
+    _ = writeln!(config);
+
+    // Serving the bundle where the registry is served now needs no
+    // replacement
+    let served_as_now =
+        options.layout == Layout::Sparse && options.base_url.as_str() == registry_url;
+    let replacement = if served_as_now {
+        name
+    } else {
+        _ = writeln!(config, "[source.{index:?}]");
+        _ = writeln!(config, "registry = {index:?}");
+        _ = writeln!(config, "replace-with = {offline:?}");
+        _ = writeln!(config);
+
+        _ = writeln!(config, "[source.{offline}]");
+        match options.layout {
+            Layout::Local => {
+                let dir = options.target_dir.join(REGISTRY_DIR_NAME);
+                _ = writeln!(config, "local-registry = {:?}", dir.display().to_string());
+            }
+            Layout::Sparse => {
+                _ = writeln!(config, "registry = \"sparse+{}\"", options.base_url);
+            }
+        }
+        _ = writeln!(config);
+
+        &offline
+    };
+
+    if options.replace_crates_io {
+        _ = writeln!(config, "[source.crates-io]");
+        _ = writeln!(config, "replace-with = {replacement:?}");
+        _ = writeln!(config);
+    }
+
+    config.truncate(config.trim_end().len());
+    config.push('\n');
+    config
+}
+
+fn instructions(options: &Options<'_>) -> String {
+    let name = options.name;
+    let target_dir = options.target_dir.display();
+    let mut text = String::new();
+
+    _ = writeln!(text, "An offline copy of the `{name}` registry");
+    _ = writeln!(text);
+    _ = writeln!(
+        text,
+        "1. Copy this directory to {target_dir} on the offline machine."
+    );
+
+    match options.layout {
+        Layout::Local => {
+            _ = writeln!(
+                text,
+                "   Cargo reads the crates from {target_dir}/{REGISTRY_DIR_NAME} directly;"
+            );
+            _ = writeln!(
+                text,
+                "   to keep them elsewhere, change `local-registry` in {CARGO_CONFIG_FILE_NAME}."
+            );
+        }
+        Layout::Sparse => {
+            _ = writeln!(text, "2. Serve the registry at {}:", options.base_url);
+            _ = writeln!(text);
+            _ = writeln!(
+                text,
+                "   margo serve --registry {target_dir}/{REGISTRY_DIR_NAME}"
+            );
+            _ = writeln!(text);
+        }
+    }
+
+    let step = match options.layout {
+        Layout::Local => 2,
+        Layout::Sparse => 3,
+    };
+    _ = writeln!(
+        text,
+        "{step}. Add {CARGO_CONFIG_FILE_NAME} to ~/.cargo/config.toml, or to .cargo/config.toml"
+    );
+    _ = writeln!(text, "   in a project, and build as usual.");
+
+    if options.replace_crates_io {
+        _ = writeln!(text);
+        _ = writeln!(
+            text,
+            "Dependencies from crates.io are also taken from the bundle, so it must"
+        );
+        _ = writeln!(text, "hold every crate the projects use.");
+    }
+
+    text
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("`{layout}` is not a bundle layout; use `local` or `sparse`"))]
+    Layout { layout: String },
+
+    #[snafu(display("{} already exists", path.display()))]
+    Exists { path: PathBuf },
+
+    #[snafu(display("Could not list the crates in the registry"))]
+    List { source: ListAllError },
+
+    #[snafu(display("Could not bring the crate back from the cold tier"))]
+    Promote { source: crate::tier::Error },
+
+    #[snafu(display("The `.crate` file of {name} {version} is gone"))]
+    Missing { name: CrateName, version: Version },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse {}", path.display()))]
+    ParseConfig {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the registry's configuration"))]
+    SerializeConfig { source: toml::ser::Error },
+
+    #[snafu(display("Could not parse {}", path.display()))]
+    ParseConfigJson {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize `config.json`"))]
+    SerializeConfigJson { source: serde_json::Error },
+
+    #[snafu(display("Could not write the index file {}", path.display()))]
+    Index {
+        source: WriteIndexError,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Layout { .. } => "E_BAD_LAYOUT",
+            Self::Exists { .. } => "E_PATH_EXISTS",
+            Self::List { source } => source.code(),
+            Self::Promote { source } => source.code(),
+            Self::Missing { .. } => "E_VERSION_NOT_FOUND",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::ParseConfig { .. } | Self::ParseConfigJson { .. } => "E_CONFIG_INVALID",
+            Self::SerializeConfig { .. } | Self::SerializeConfigJson { .. } => "E_INTERNAL",
+            Self::Index { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_registry_source_is_replaced() {
+        let base_url = "https://registry.example.com/".parse().unwrap();
+        let options = Options {
+            name: "internal",
+            layout: Layout::Local,
+            target_dir: Path::new("/mnt/bundle"),
+            base_url: &base_url,
+            replace_crates_io: true,
+        };
+
+        assert_eq!(
+            concat!(
+                "[registries.internal]\n",
+                "index = \"sparse+https://registry.example.com/\"\n",
+                "\n",
+                "[source.\"sparse+https://registry.example.com/\"]\n",
+                "registry = \"sparse+https://registry.example.com/\"\n",
+                "replace-with = \"internal-offline\"\n",
+                "\n",
+                "[source.internal-offline]\n",
+                "local-registry = \"/mnt/bundle/registry\"\n",
+                "\n",
+                "[source.crates-io]\n",
+                "replace-with = \"internal-offline\"\n",
+            ),
+            cargo_config(&options, base_url.as_str()),
+        );
+
+        // Served where it is now, the registry needs no replacement
+        let options = Options {
+            layout: Layout::Sparse,
+            ..options
+        };
+        assert_eq!(
+            concat!(
+                "[registries.internal]\n",
+                "index = \"sparse+https://registry.example.com/\"\n",
+                "\n",
+                "[source.crates-io]\n",
+                "replace-with = \"internal\"\n",
+            ),
+            cargo_config(&options, base_url.as_str()),
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! What Cargo would use in the current directory, for commands run as
+//! `cargo gnostr-registry` from inside a workspace.
+//!
+//! Installing margo also installs `cargo-gnostr-registry`, which Cargo
+//! runs for `cargo gnostr-registry ARGS` and which runs `margo ARGS`.
+//! Commands that act on a workspace find it with
+//! `cargo locate-project --workspace`, and find registries the way Cargo
+//! does: in `.cargo/config.toml` in the current directory and each of
+//! its parents, then in `$CARGO_HOME/config.toml`, the closest file
+//! winning when two name the same registry. Without `--registry`, they
+//! use `[registry] default`, or the only registry configured.
+
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use url::Url;
+
+use crate::sandbox;
+
+/// The file names Cargo reads in each `.cargo` directory, preferred
+/// first.
+const CONFIG_FILE_NAMES: [&str; 2] = ["config.toml", "config"];
+
+#[derive(Debug, Default)]
+pub struct Context {
+    /// The directory of the workspace's root `Cargo.toml`, when there
+    /// is one.
+    pub workspace_root: Option<PathBuf>,
+
+    pub registries: BTreeMap<String, Registry>,
+
+    /// `[registry] default`.
+    pub default_registry: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Registry {
+    /// As configured, such as `sparse+https://crates.example.com/`.
+    pub index: String,
+
+    /// The configuration file that names it.
+    pub defined_in: PathBuf,
+}
+
+impl Registry {
+    /// The URL of the index over HTTP, which for a margo registry is
+    /// where the daemon serves it.
+    pub fn base_url(&self) -> Option<Url> {
+        let index = self.index.strip_prefix("sparse+")?;
+        let mut url = Url::parse(index).ok()?;
+        crate::ensure_last_segment_empty(&mut url);
+        Some(url)
+    }
+}
+
+pub fn detect() -> Result<Context, Error> {
+    let cwd = env::current_dir().context(error::CurrentDirSnafu)?;
+
+    let mut context = read_configs(&cwd, cargo_home().as_deref())?;
+    context.workspace_root = workspace_root(&cwd)?;
+    Ok(context)
+}
+
+impl Context {
+    /// The registry to use: `name`, or the default one.
+    pub fn registry(&self, name: Option<&str>) -> Result<(&str, &Registry), Error> {
+        use error::*;
+
+        let name = match name.or(self.default_registry.as_deref()) {
+            Some(name) => name,
+            None => {
+                let mut names = self.registries.keys();
+                match (names.next(), names.next()) {
+                    (Some(name), None) => name.as_str(),
+                    (None, _) => return NoRegistriesSnafu.fail(),
+                    (Some(_), Some(_)) => {
+                        let known = self.known();
+                        return AmbiguousSnafu { known }.fail();
+                    }
+                }
+            }
+        };
+
+        let (name, registry) = self.registries.get_key_value(name).context(UnknownSnafu {
+            name,
+            known: self.known(),
+        })?;
+        Ok((name, registry))
+    }
+
+    fn known(&self) -> String {
+        let names = self.registries.keys().map(String::as_str);
+        names.collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// The `cargo` to run: the one running this as its subcommand, or the
+/// one on the PATH.
+pub fn cargo() -> Option<PathBuf> {
+    env::var_os("CARGO")
+        .map(PathBuf::from)
+        .or_else(|| sandbox::find_program("cargo"))
+}
+
+/// The environment variable Cargo reads the registry's token from.
+pub fn token_var(registry: &str) -> String {
+    let name = registry.to_ascii_uppercase().replace('-', "_");
+    format!("CARGO_REGISTRIES_{name}_TOKEN")
+}
+
+pub fn cargo_home() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("CARGO_HOME") {
+        return Some(path.into());
+    }
+
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(".cargo"))
+}
+
+/// The Cargo configuration files that apply in `cwd`, closest first.
+fn config_files(cwd: &Path, cargo_home: Option<&Path>) -> Vec<PathBuf> {
+    let dirs = cwd.ancestors().map(|dir| dir.join(".cargo"));
+    let dirs = dirs.chain(cargo_home.map(Path::to_path_buf));
+
+    let mut files = vec![];
+    for dir in dirs {
+        let found = CONFIG_FILE_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file());
+        if let Some(path) = found {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+fn read_configs(cwd: &Path, cargo_home: Option<&Path>) -> Result<Context, Error> {
+    use error::*;
+
+    #[derive(Deserialize)]
+    struct CargoConfig {
+        #[serde(default)]
+        registries: BTreeMap<String, CargoRegistry>,
+
+        #[serde(default)]
+        registry: Option<CargoRegistryDefault>,
+    }
+
+    #[derive(Deserialize)]
+    struct CargoRegistry {
+        index: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct CargoRegistryDefault {
+        default: Option<String>,
+    }
+
+    let mut context = Context::default();
+
+    for path in config_files(cwd, cargo_home) {
+        let config = match fs::read_to_string(&path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).context(ReadSnafu { path }),
+        };
+        let config: CargoConfig = toml::from_str(&config).context(ParseSnafu { path: &path })?;
+
+        for (name, registry) in config.registries {
+            let Some(index) = registry.index else {
+                continue;
+            };
+            context.registries.entry(name).or_insert_with(|| Registry {
+                index,
+                defined_in: path.clone(),
+            });
+        }
+
+        let default = config.registry.and_then(|r| r.default);
+        if context.default_registry.is_none() {
+            context.default_registry = default;
+        }
+    }
+
+    Ok(context)
+}
+
+fn workspace_root(cwd: &Path) -> Result<Option<PathBuf>, Error> {
+    use error::*;
+
+    let cargo = cargo().context(NoCargoSnafu)?;
+
+    let output = Command::new(cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .current_dir(cwd)
+        .output()
+        .context(SpawnSnafu)?;
+
+    // Outside a package, there is no workspace to use
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let manifest = String::from_utf8_lossy(&output.stdout);
+    let manifest = Path::new(manifest.trim());
+    Ok(manifest.parent().map(Path::to_path_buf))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not determine the current directory"))]
+    CurrentDir { source: io::Error },
+
+    #[snafu(display("Could not read the Cargo configuration at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the Cargo configuration at {}", path.display()))]
+    Parse {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not find `cargo` on the PATH"))]
+    NoCargo,
+
+    #[snafu(display("Could not run `cargo locate-project`"))]
+    Spawn { source: io::Error },
+
+    #[snafu(display("No registries are configured in `.cargo/config.toml`"))]
+    NoRegistries,
+
+    #[snafu(display(
+        "Several registries are configured ({known}); pass --registry or set `[registry] default`"
+    ))]
+    Ambiguous { known: String },
+
+    #[snafu(display("The registry `{name}` is not configured; the registries are: {known}"))]
+    Unknown { name: String, known: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::CurrentDir { .. } => "E_CURRENT_DIR",
+            Self::Read { .. } => "E_CONFIG_READ",
+            Self::Parse { .. } => "E_CONFIG_INVALID",
+            Self::NoCargo | Self::Spawn { .. } => "E_CARGO_UNAVAILABLE",
+            Self::NoRegistries | Self::Unknown { .. } => "E_REGISTRY_UNKNOWN",
+            Self::Ambiguous { .. } => "E_REGISTRY_AMBIGUOUS",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_closest_configuration_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let member = dir.join("workspace/member");
+        let home = dir.join("cargo-home");
+        fs::create_dir_all(member.join(".cargo")).unwrap();
+        fs::create_dir_all(dir.join("workspace/.cargo")).unwrap();
+        fs::create_dir_all(&home).unwrap();
+
+        let write = |path: PathBuf, config: &str| fs::write(path, config).unwrap();
+        write(
+            home.join("config.toml"),
+            r#"
+            registry.default = "home"
+            registries.home.index = "sparse+https://home.example.com"
+            registries.internal.index = "sparse+https://old.example.com/"
+            "#,
+        );
+        write(
+            dir.join("workspace/.cargo/config"),
+            r#"registries.internal.index = "sparse+https://crates.example.com/""#,
+        );
+        write(member.join(".cargo/config.toml"), "[build]\njobs = 1\n");
+
+        let context = read_configs(&member, Some(&home)).unwrap();
+
+        let (name, home_registry) = context.registry(None).unwrap();
+        assert_eq!("home", name);
+        assert_eq!(
+            "https://home.example.com/",
+            home_registry.base_url().unwrap().as_str(),
+        );
+
+        let (_, internal) = context.registry(Some("internal")).unwrap();
+        let base_url = internal.base_url().unwrap();
+        assert_eq!("https://crates.example.com/", base_url.as_str());
+        assert_eq!(dir.join("workspace/.cargo/config"), internal.defined_in);
+
+        assert!(matches!(
+            context.registry(Some("other")),
+            Err(Error::Unknown { .. }),
+        ));
+    }
+}
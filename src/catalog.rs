@@ -0,0 +1,176 @@
+//! Every crate's summary, kept in memory for listings and search.
+//!
+//! Reading every index file for each `/api/v1/crates` request or search
+//! takes seconds on a registry of tens of thousands of crates, so each
+//! tenant keeps a [`Catalog`] instead. It is loaded when the daemon
+//! starts, with the index files shared out between a thread per CPU,
+//! while the daemon already answers: until it is loaded, listings read
+//! the index files themselves and `/api/v1/ready` answers `503`.
+//!
+//! Once loaded, the catalog follows the audit log. Before each use, the
+//! index file of each crate that new entries changed is read again, and
+//! after a snapshot is restored, every index file is.
+
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{audit, common::CrateName, index_file, ListIndexFilesError, ParseIndexError, Registry};
+
+pub type Summaries = BTreeMap<CrateName, index_file::Summary>;
+
+#[derive(Debug, Default)]
+pub struct Catalog {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// `None` until loaded.
+    summaries: Option<Arc<Summaries>>,
+
+    /// Where in the audit log the summaries are up to.
+    audit_offset: u64,
+
+    /// How long the last full load took.
+    loaded_in: Duration,
+}
+
+impl Catalog {
+    /// Reads every index file. Requests can use the catalog as soon as
+    /// this returns.
+    pub fn load(&self, registry: &Registry) -> Result<(), Error> {
+        // Not locked while reading, so requests meanwhile go on without
+        let loaded = read_all(registry)?;
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = loaded;
+        Ok(())
+    }
+
+    /// The summaries, brought up to date with the audit log. `None`
+    /// while the catalog is still loading.
+    pub fn get(&self, registry: &Registry) -> Result<Option<Arc<Summaries>>, Error> {
+        use error::*;
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        if state.summaries.is_none() {
+            return Ok(None);
+        }
+
+        let path = registry.audit_log_path();
+        let (entries, offset) = audit::tail(&path, state.audit_offset).context(AuditSnafu)?;
+
+        // A shorter log was replaced, and may have lost entries
+        let mut full = offset < state.audit_offset;
+        let mut changed = vec![];
+        for entry in entries.iter().filter(|e| e.event.changes_index()) {
+            match entry.event.crate_name() {
+                Some(name) => changed.push(name),
+                None => full = true,
+            }
+        }
+
+        if full {
+            *state = read_all(registry)?;
+        } else if let Some(summaries) = &mut state.summaries {
+            let summaries = Arc::make_mut(summaries);
+            for name in changed {
+                let path = registry.index_file_path_for(name);
+                let summary =
+                    Registry::summarize_index_file(&path).context(ParseSnafu { path: &path })?;
+
+                match summary {
+                    Some((name, summary)) => summaries.insert(name, summary),
+                    None => summaries.remove(name),
+                };
+            }
+            state.audit_offset = offset;
+        }
+
+        Ok(state.summaries.clone())
+    }
+
+    /// How many crates there are and how long loading them took, once
+    /// the catalog is loaded.
+    pub fn loaded(&self) -> Option<(usize, Duration)> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let summaries = state.summaries.as_ref()?;
+        Some((summaries.len(), state.loaded_in))
+    }
+}
+
+fn read_all(registry: &Registry) -> Result<State, Error> {
+    use error::*;
+
+    let start = Instant::now();
+
+    // Taken first, so that whatever changes while loading is read again
+    let audit_offset = audit::end(&registry.audit_log_path()).context(AuditSnafu)?;
+
+    let paths = registry.list_index_files().context(ListSnafu)?;
+    let paths = paths.into_iter().collect::<Vec<_>>();
+
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_len = paths.len().div_ceil(threads).max(1);
+
+    let chunks = thread::scope(|s| {
+        let workers = paths
+            .chunks(chunk_len)
+            .map(|paths| s.spawn(|| summarize(paths)))
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .map(|w| w.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    Ok(State {
+        summaries: Some(Arc::new(chunks.into_iter().flatten().collect())),
+        audit_offset,
+        loaded_in: start.elapsed(),
+    })
+}
+
+fn summarize(paths: &[PathBuf]) -> Result<Vec<(CrateName, index_file::Summary)>, Error> {
+    use error::*;
+
+    let mut summaries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let summary = Registry::summarize_index_file(path).context(ParseSnafu { path })?;
+        summaries.extend(summary);
+    }
+
+    Ok(summaries)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not follow the audit log"))]
+    Audit { source: audit::Error },
+
+    #[snafu(display("Could not list the index files"))]
+    List { source: ListIndexFilesError },
+
+    #[snafu(display("Could not parse the index file {}", path.display()))]
+    Parse {
+        source: ParseIndexError,
+        path: PathBuf,
+    },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Audit { source } => source.code(),
+            Self::List { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_INDEX_CORRUPT",
+        }
+    }
+}
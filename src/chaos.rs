@@ -0,0 +1,140 @@
+//! Faults injected into P2P transfers, for testing how peers retry and
+//! check what they fetch.
+//!
+//! Built with the `chaos` feature, a node with `[announcements.chaos]`
+//! misbehaves when it sends crates, blobs and commit data to peers: it
+//! waits `latency-ms` before each chunk, cuts off every `drop-every`th
+//! transfer halfway, and changes a byte of the contents of every
+//! `corrupt-every`th, which the peer then finds does not match its
+//! checksum. Transfers are counted from when the daemon starts, rather
+//! than faults being left to chance, so a test that makes the same
+//! transfers sees the same faults.
+//!
+//! ```toml
+//! [announcements.chaos]
+//! latency-ms = 50
+//! drop-every = 3
+//! corrupt-every = 2
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Policy {
+    /// How long to wait before sending each chunk of a transfer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+
+    /// Cut off one transfer in this many.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drop_every: Option<u64>,
+
+    /// Change the contents of one transfer in this many.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    corrupt_every: Option<u64>,
+}
+
+impl Policy {
+    pub fn is_enabled(&self) -> bool {
+        [self.latency_ms, self.drop_every, self.corrupt_every]
+            .iter()
+            .any(|n| n.is_some_and(|n| n > 0))
+    }
+}
+
+/// What to do to one transfer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Faults {
+    pub latency: Duration,
+    pub drop: bool,
+    pub corrupt: bool,
+}
+
+/// Decides the faults of each transfer. Every stream gets a clone,
+/// which shares the count of transfers.
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    policy: Policy,
+    transfers: Arc<AtomicU64>,
+}
+
+impl Chaos {
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            transfers: Default::default(),
+        }
+    }
+
+    /// The faults of the next transfer.
+    pub fn next(&self) -> Faults {
+        let n = self.transfers.fetch_add(1, Ordering::AcqRel) + 1;
+        let nth = |every: Option<u64>| every.is_some_and(|every| every > 0 && n % every == 0);
+
+        Faults {
+            latency: Duration::from_millis(self.policy.latency_ms.unwrap_or(0)),
+            drop: nth(self.policy.drop_every),
+            corrupt: nth(self.policy.corrupt_every),
+        }
+    }
+}
+
+/// Changes the first character of base64-encoded `data` to another
+/// one, so that it still decodes, but to different bytes.
+pub fn corrupt(data: &mut String) {
+    let replacement = match data.chars().next() {
+        None | Some('=') => return,
+        Some('A') => "B",
+        Some(_) => "A",
+    };
+    data.replace_range(..1, replacement);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn faults_follow_the_count_of_transfers() {
+        let policy = Policy {
+            latency_ms: Some(5),
+            drop_every: Some(3),
+            corrupt_every: Some(2),
+        };
+        assert!(policy.is_enabled());
+        assert!(!Policy::default().is_enabled());
+
+        let chaos = Chaos::new(policy);
+        let other_stream = chaos.clone();
+        let faults = (0..6)
+            .map(|i| if i % 2 == 0 { &chaos } else { &other_stream }.next())
+            .map(|f| (f.drop, f.corrupt))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (false, false),
+                (false, true),
+                (true, false),
+                (false, true),
+                (false, false),
+                (true, true),
+            ],
+            faults,
+        );
+        assert_eq!(Duration::from_millis(5), chaos.next().latency);
+
+        let mut data = "AAEC".to_owned();
+        corrupt(&mut data);
+        assert_eq!("BAEC", data);
+        corrupt(&mut data);
+        assert_eq!("AAEC", data);
+    }
+}
@@ -0,0 +1,183 @@
+//! Versions whose checksums differ between sources.
+//!
+//! When `margo sync`, `margo mirror verify`, or a proxy finds that the
+//! local registry, crates.io, or an upstream registry disagree about
+//! the checksum of a crate version, the version is recorded in
+//! `conflicts.json` and in the audit log. It stays quarantined until an
+//! operator resolves it with `margo conflicts resolve`: the daemon
+//! refuses to serve its `.crate` file, whichever source it would come
+//! from, and `margo sync` leaves it alone.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{common::CrateName, timestamp::Timestamp};
+
+pub const FILE_NAME: &str = "conflicts.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conflict {
+    pub name: CrateName,
+    pub vers: Version,
+
+    /// Each source's checksum, keyed by a description of the source
+    /// such as `local` or `crates.io`.
+    pub checksums: BTreeMap<String, String>,
+
+    pub detected_at: Timestamp,
+}
+
+impl Conflict {
+    fn is_for(&self, name: &str, vers: &Version) -> bool {
+        self.name.as_str().eq_ignore_ascii_case(name) && self.vers == *vers
+    }
+}
+
+/// A missing file has no conflicts.
+pub fn read(path: &Path) -> Result<Vec<Conflict>, Error> {
+    use error::*;
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+
+    serde_json::from_slice(&data).context(ParseSnafu { path })
+}
+
+fn write(path: &Path, conflicts: &[Conflict]) -> Result<(), Error> {
+    use error::*;
+
+    let data = serde_json::to_vec_pretty(conflicts).context(SerializeSnafu)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, path).context(WriteSnafu { path })
+}
+
+#[cfg(feature = "server")]
+pub fn is_quarantined(path: &Path, name: &str, vers: &Version) -> Result<bool, Error> {
+    Ok(read(path)?.iter().any(|c| c.is_for(name, vers)))
+}
+
+/// Adds the checksums to an existing conflict for the same version.
+/// Returns the conflict when it is new.
+#[cfg(any(feature = "proxy", feature = "replicate", feature = "sync-crates-io"))]
+pub fn record(path: &Path, conflict: Conflict) -> Result<Option<Conflict>, Error> {
+    let mut conflicts = read(path)?;
+
+    let existing = conflicts
+        .iter_mut()
+        .find(|c| c.is_for(conflict.name.as_str(), &conflict.vers));
+    let new = match existing {
+        Some(existing) => {
+            existing.checksums.extend(conflict.checksums);
+            None
+        }
+        None => {
+            conflicts.push(conflict.clone());
+            Some(conflict)
+        }
+    };
+
+    write(path, &conflicts)?;
+
+    Ok(new)
+}
+
+/// Lifts the quarantine.
+pub fn resolve(path: &Path, name: &CrateName, vers: &Version) -> Result<Conflict, Error> {
+    use error::*;
+
+    let mut conflicts = read(path)?;
+
+    let i = conflicts
+        .iter()
+        .position(|c| c.is_for(name.as_str(), vers))
+        .context(UnknownSnafu {
+            name: name.clone(),
+            vers: vers.clone(),
+        })?;
+    let conflict = conflicts.remove(i);
+
+    write(path, &conflicts)?;
+
+    Ok(conflict)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the conflicts at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the conflicts at {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the conflicts"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write the conflicts to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("There is no conflict about {name} {vers}"))]
+    Unknown { name: CrateName, vers: Version },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_CONFLICTS_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Unknown { .. } => "E_NO_CONFLICT",
+        }
+    }
+}
+
+#[cfg(all(
+    test,
+    any(feature = "proxy", feature = "replicate", feature = "sync-crates-io")
+))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conflicts_are_merged_until_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join(FILE_NAME);
+
+        let name: CrateName = "demo".parse().unwrap();
+        let vers: Version = "1.0.0".parse().unwrap();
+        let conflict = |source: &str, cksum: &str| Conflict {
+            name: name.clone(),
+            vers: vers.clone(),
+            checksums: [(source.to_owned(), cksum.to_owned())].into(),
+            detected_at: Timestamp::now(),
+        };
+
+        assert!(record(&path, conflict("local", "aa")).unwrap().is_some());
+        assert!(record(&path, conflict("crates.io", "bb"))
+            .unwrap()
+            .is_none());
+
+        let conflicts = read(&path).unwrap();
+        assert_eq!(1, conflicts.len());
+        assert_eq!(2, conflicts[0].checksums.len());
+
+        resolve(&path, &name, &vers).unwrap();
+        assert!(read(&path).unwrap().is_empty());
+        assert!(resolve(&path, &name, &vers).is_err());
+    }
+}
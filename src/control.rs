@@ -0,0 +1,466 @@
+//! Administering a running daemon through a local socket.
+//!
+//! `margo serve` listens on a local socket for each registry it serves:
+//! on Unix, `control.sock` in the registry, which only the user running
+//! the daemon may connect to; on Windows, a named pipe derived from the
+//! registry's path, which refuses remote clients. A connection carries
+//! one request and then one response, each a line of JSON:
+//!
+//! ```json
+//! {"command":"peer-ban","peer-id":"12D3KooW..."}
+//! {"result":"done"}
+//! ```
+//!
+//! Commands for the P2P node are passed to its event loop, which
+//! answers them between swarm events.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{
+    reload::{self, Trigger},
+    tenant::Tenant,
+};
+
+#[cfg(feature = "p2p")]
+use tokio::sync::{mpsc, oneshot};
+
+#[cfg(unix)]
+pub const FILE_NAME: &str = "control.sock";
+
+/// Longer requests are refused without being parsed.
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// The daemon's [status](crate::status) for the registry.
+    Status,
+
+    /// Asks every connected peer for its HEAD and the announcements
+    /// this node missed.
+    Sync,
+
+    /// Reads the registry's configuration again and applies what can
+    /// change while the daemon runs.
+    Reload,
+
+    /// Reads the registry's nostr key again, after it was replaced.
+    RotateKey,
+
+    /// Connected and banned peers.
+    PeerList,
+
+    #[serde(rename_all = "kebab-case")]
+    PeerDial { addr: String },
+
+    /// Disconnects a peer and refuses its connections until the daemon
+    /// restarts.
+    #[serde(rename_all = "kebab-case")]
+    PeerBan { peer_id: String },
+
+    #[serde(rename_all = "kebab-case")]
+    PeerInfo { peer_id: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum Response {
+    Status { status: serde_json::Value },
+    Syncing { peers: usize },
+    Key { pubkey: String },
+    Peers { peers: Vec<PeerInfo> },
+    Peer(PeerInfo),
+    Done,
+    Error { message: String },
+}
+
+impl Response {
+    pub fn error(message: impl ToString) -> Self {
+        Self::Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub connected: bool,
+    pub banned: bool,
+
+    /// The remote address of the connection, while connected.
+    pub address: Option<String>,
+
+    pub agent: Option<String>,
+
+    /// The round-trip time of the latest ping.
+    pub rtt_ms: Option<u64>,
+
+    /// The gossipsub topics the peer is subscribed to.
+    pub topics: Vec<String>,
+}
+
+/// A request for the P2P node and where to send its response.
+#[cfg(feature = "p2p")]
+pub type PeerCommand = (Request, oneshot::Sender<Response>);
+
+/// What the control socket of one registry acts on.
+#[derive(Debug, Clone)]
+pub struct Daemon {
+    pub tenant: Tenant,
+
+    /// The file passed to `margo serve --config`, when the daemon
+    /// serves several registries.
+    pub server_config: Option<PathBuf>,
+
+    /// Held while the configuration is reloaded, so that two reloads
+    /// never interleave.
+    pub reloading: Arc<tokio::sync::Mutex<()>>,
+
+    #[cfg(feature = "p2p")]
+    pub peers: mpsc::Sender<PeerCommand>,
+}
+
+impl Daemon {
+    async fn answer(&self, request: Request) -> Response {
+        match request {
+            Request::Status => {
+                let status = self
+                    .tenant
+                    .status
+                    .update(|status| {
+                        let mut value = serde_json::to_value(&*status)?;
+                        value["auth_failures"] = serde_json::to_value(&status.auth_failures)?;
+                        Ok::<_, serde_json::Error>(value)
+                    })
+                    .expect("The status is always serializable");
+                Response::Status { status }
+            }
+
+            #[cfg(feature = "nostr")]
+            Request::RotateKey => {
+                let Some(signer) = self.tenant.nostr_signer.clone() else {
+                    return Response::error("The registry has no nostr key");
+                };
+                // A remote signer is asked over the network
+                let reloaded = tokio::task::spawn_blocking(move || signer.reload()).await;
+                match reloaded {
+                    Ok(Ok(pubkey)) => {
+                        let pubkey = pubkey.to_hex();
+                        println!("Rotated the nostr key to {pubkey}");
+
+                        // Re-signs the index's current Merkle root
+                        if let Some(announcer) = self.tenant.announcer.clone() {
+                            let tenant = self.tenant.clone();
+                            tokio::task::spawn_blocking(move || {
+                                announcer.announce(&tenant.registry(), &tenant.status)
+                            });
+                        }
+
+                        Response::Key { pubkey }
+                    }
+                    Ok(Err(e)) => Response::error(e),
+                    Err(e) => Response::error(e),
+                }
+            }
+
+            #[cfg(not(feature = "nostr"))]
+            Request::RotateKey => Response::error("The daemon was built without nostr support"),
+
+            Request::Reload => match reload::reload(self, Trigger::Request).await {
+                Ok(()) => Response::Done,
+                Err(e) => Response::error(e),
+            },
+
+            #[cfg(feature = "p2p")]
+            request => self.ask_node(request).await,
+
+            #[cfg(not(feature = "p2p"))]
+            _ => Response::error("The daemon was built without P2P support"),
+        }
+    }
+
+    /// Passes `request` to the P2P node and waits for its response.
+    #[cfg(feature = "p2p")]
+    pub async fn ask_node(&self, request: Request) -> Response {
+        let (respond, response) = oneshot::channel();
+        if self.peers.send((request, respond)).await.is_err() {
+            return Response::error("The P2P node is not running");
+        }
+        response
+            .await
+            .unwrap_or_else(|_| Response::error("The P2P node stopped"))
+    }
+}
+
+/// Where the control socket of the registry at `registry` is.
+#[cfg(unix)]
+pub fn address(registry: &Path) -> PathBuf {
+    registry.join(FILE_NAME)
+}
+
+/// Named pipes live in their own namespace, so the name is derived
+/// from the registry's full path.
+#[cfg(windows)]
+pub fn address(registry: &Path) -> PathBuf {
+    use sha2::Digest;
+
+    let registry = std::fs::canonicalize(registry).unwrap_or_else(|_| registry.to_owned());
+    let digest = sha2::Sha256::digest(registry.as_os_str().as_encoded_bytes());
+    PathBuf::from(format!(r"\\.\pipe\margo-{}", hex::encode(&digest[..8])))
+}
+
+/// Listens on the daemon's control socket. Only returns on error.
+#[cfg(unix)]
+pub async fn run(daemon: Daemon) -> Result<(), Error> {
+    use error::*;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let path = address(&daemon.tenant.registry().path);
+
+    // A socket left behind by a daemon that died can be replaced, but
+    // not one a running daemon still answers on.
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return InUseSnafu { path }.fail();
+    }
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(BindSnafu { path }),
+    }
+
+    let listener = tokio::net::UnixListener::bind(&path).context(BindSnafu { path: &path })?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .context(BindSnafu { path: &path })?;
+
+    loop {
+        let (stream, _) = listener.accept().await.context(AcceptSnafu)?;
+        spawn_answer(stream, daemon.clone());
+    }
+}
+
+/// Listens on the daemon's control pipe. Only returns on error.
+#[cfg(windows)]
+pub async fn run(daemon: Daemon) -> Result<(), Error> {
+    use error::*;
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let path = address(&daemon.tenant.registry().path);
+
+    // Fails if another daemon serves the same registry
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&path)
+        .context(BindSnafu { path: &path })?;
+
+    loop {
+        server.connect().await.context(AcceptSnafu)?;
+        let connected = server;
+        server = ServerOptions::new()
+            .create(&path)
+            .context(BindSnafu { path: &path })?;
+        spawn_answer(connected, daemon.clone());
+    }
+}
+
+fn spawn_answer<S>(stream: S, daemon: Daemon)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = answer(stream, &daemon).await {
+            println!("Control request failed: {e}");
+        }
+    });
+}
+
+async fn answer<S>(stream: S, daemon: &Daemon) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    use error::*;
+
+    let (read, mut write) = tokio::io::split(stream);
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_REQUEST_LEN))
+        .read_line(&mut line)
+        .await
+        .context(ReceiveSnafu)?;
+
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => daemon.answer(request).await,
+        Err(e) => Response::error(format!("Could not parse the request: {e}")),
+    };
+
+    let mut data = serde_json::to_vec(&response).context(SerializeSnafu)?;
+    data.push(b'\n');
+    write.write_all(&data).await.context(SendSnafu)
+}
+
+/// Sends `request` to the daemon serving the registry at `registry`. A
+/// request the daemon refuses is an error.
+pub fn request(registry: &Path, request: &Request) -> Result<Response, Error> {
+    use error::*;
+    use std::io::{BufRead, BufReader, Write};
+
+    let path = address(registry);
+
+    #[cfg(unix)]
+    let mut stream =
+        std::os::unix::net::UnixStream::connect(&path).context(ConnectSnafu { path })?;
+
+    #[cfg(windows)]
+    let mut stream = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .context(ConnectSnafu { path })?;
+
+    let mut data = serde_json::to_vec(request).context(SerializeSnafu)?;
+    data.push(b'\n');
+    stream.write_all(&data).context(SendSnafu)?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .context(ReceiveSnafu)?;
+
+    match serde_json::from_str(&line).context(ParseSnafu)? {
+        Response::Error { message } => RefusedSnafu { message }.fail(),
+        response => Ok(response),
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("A daemon is already listening on {}", path.display()))]
+    InUse { path: PathBuf },
+
+    #[snafu(display("Could not listen on {}", path.display()))]
+    Bind { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not accept a connection on the control socket"))]
+    Accept { source: io::Error },
+
+    #[snafu(display("Could not connect to a daemon at {}", path.display()))]
+    Connect { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not serialize the control message"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not send the control message"))]
+    Send { source: io::Error },
+
+    #[snafu(display("Could not receive the control message"))]
+    Receive { source: io::Error },
+
+    #[snafu(display("Could not parse the daemon's response"))]
+    Parse { source: serde_json::Error },
+
+    #[snafu(display("The daemon refused the request: {message}"))]
+    Refused { message: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InUse { .. } | Self::Bind { .. } | Self::Accept { .. } => "E_CONTROL_LISTEN",
+            Self::Connect { .. } => "E_DAEMON_UNREACHABLE",
+            Self::Serialize { .. }
+            | Self::Send { .. }
+            | Self::Receive { .. }
+            | Self::Parse { .. } => "E_CONTROL_PROTOCOL",
+            Self::Refused { .. } => "E_CONTROL_REFUSED",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_tagged_by_command() {
+        let ban = Request::PeerBan {
+            peer_id: "12D3KooW".to_owned(),
+        };
+        assert_eq!(
+            r#"{"command":"peer-ban","peer-id":"12D3KooW"}"#,
+            serde_json::to_string(&ban).unwrap()
+        );
+        assert!(matches!(
+            serde_json::from_str(r#"{"command":"rotate-key"}"#).unwrap(),
+            Request::RotateKey
+        ));
+
+        assert_eq!(
+            r#"{"result":"done"}"#,
+            serde_json::to_string(&Response::Done).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn requests_reach_the_daemon_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let config = "version = \"1\"\nformat = 3\nbase_url = \"http://example.com/\"\n";
+        std::fs::write(dir.join(crate::CONFIG_FILE_NAME), config).unwrap();
+        let registry = crate::Registry::open(dir).unwrap();
+
+        let tenant = Tenant::single(
+            registry,
+            #[cfg(feature = "p2p")]
+            "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+        );
+        tenant
+            .status
+            .update(|s| s.local_peer_id = Some("12D3KooW".to_owned()));
+
+        #[cfg(feature = "p2p")]
+        let (peers, mut commands) = mpsc::channel::<PeerCommand>(1);
+        #[cfg(feature = "p2p")]
+        tokio::spawn(async move {
+            while let Some((_, respond)) = commands.recv().await {
+                _ = respond.send(Response::error("unknown peer"));
+            }
+        });
+
+        let daemon = Daemon {
+            tenant,
+            server_config: None,
+            reloading: Default::default(),
+            #[cfg(feature = "p2p")]
+            peers,
+        };
+        tokio::spawn(run(daemon));
+        while !address(dir).exists() {
+            tokio::task::yield_now().await;
+        }
+
+        let request = |r| {
+            let dir = dir.to_path_buf();
+            tokio::task::spawn_blocking(move || super::request(&dir, &r))
+        };
+        assert!(matches!(
+            request(Request::Status).await.unwrap(),
+            Ok(Response::Status { status }) if status["local_peer_id"] == "12D3KooW"
+        ));
+        let info = Request::PeerInfo {
+            peer_id: "nobody".to_owned(),
+        };
+        assert!(matches!(
+            request(info).await.unwrap(),
+            Err(Error::Refused { .. })
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+//! Recompressed copies of `.crate` files, for sending to peers.
+//!
+//! Cargo checks each `.crate` file against the checksum in the index,
+//! so the file is always stored and served as it was published. With
+//! `[announcements] copies = ["zstd"]`, a copy of it in each of those
+//! encodings is kept beside it as well, such as `1.0.0.crate.zst`, when
+//! the copy is smaller. Peers say which encodings they read when they
+//! connect, and are sent a copy in place of the file when they read its
+//! encoding. Decoding it gives back the very same bytes, so the file
+//! still matches its checksum.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Copies are made once, as crates are added, so this favours size
+/// over speed.
+const ZSTD_LEVEL: i32 = 19;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    Zstd,
+}
+
+impl Encoding {
+    /// Every encoding this build reads and writes.
+    pub const ALL: &'static [Encoding] = &[Self::Zstd];
+
+    /// How peers name the encoding.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|e| e.name() == name)
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Zstd => "zst",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+        }
+    }
+
+    /// Refuses to decode more than `max` bytes, however small `data` is.
+    pub fn decode(self, data: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::bulk::decompress(data, max),
+        }
+    }
+}
+
+/// Where the copy of the `.crate` file at `crate_file` is kept.
+fn path_of(crate_file: &Path, encoding: Encoding) -> PathBuf {
+    crate_file.with_extension(format!("crate.{}", encoding.extension()))
+}
+
+/// Where every copy of the `.crate` file could be, for removing them
+/// along with it.
+pub fn paths_of(crate_file: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    Encoding::ALL.iter().map(|&e| path_of(crate_file, e))
+}
+
+/// Keeps a copy of the `.crate` file in each of `encodings` that makes
+/// it smaller, and removes any older copy that would not be.
+pub fn write(crate_file: &Path, data: &[u8], encodings: &BTreeSet<Encoding>) -> io::Result<()> {
+    for &encoding in encodings {
+        let path = path_of(crate_file, encoding);
+        let encoded = encoding.encode(data)?;
+
+        if encoded.len() >= data.len() {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => continue,
+            }
+        }
+
+        let tmp_path = path.with_extension(format!("{}.tmp", encoding.extension()));
+        fs::write(&tmp_path, &encoded)?;
+        fs::rename(&tmp_path, &path)?;
+    }
+
+    Ok(())
+}
+
+/// The copy of the `.crate` file in the first of `accepted` there is
+/// one in. A copy is only used if it decodes to `data`, the file
+/// itself, since it may have been made from an earlier file.
+pub fn substitute(
+    crate_file: &Path,
+    data: &[u8],
+    accepted: &BTreeSet<Encoding>,
+) -> Option<(Encoding, Vec<u8>)> {
+    accepted.iter().find_map(|&encoding| {
+        let copy = fs::read(path_of(crate_file, encoding)).ok()?;
+        let decoded = encoding.decode(&copy, data.len()).ok()?;
+        (decoded == data).then_some((encoding, copy))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn copies_decode_to_the_original_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let crate_file = dir.join("1.0.0.crate");
+        let data = b"the same few bytes, over and over. ".repeat(100);
+        let encodings = BTreeSet::from([Encoding::Zstd]);
+
+        write(&crate_file, &data, &encodings).unwrap();
+        assert!(dir.join("1.0.0.crate.zst").exists());
+
+        let (encoding, copy) = substitute(&crate_file, &data, &encodings).unwrap();
+        assert!(copy.len() < data.len());
+        assert_eq!(data, encoding.decode(&copy, data.len()).unwrap());
+
+        // A copy of another file is not sent in its place
+        assert!(substitute(&crate_file, b"other bytes", &encodings).is_none());
+        assert!(substitute(&crate_file, &data, &BTreeSet::new()).is_none());
+
+        // Nor is a copy kept when it is no smaller
+        write(&crate_file, b"x", &encodings).unwrap();
+        assert!(!dir.join("1.0.0.crate.zst").exists());
+    }
+}
@@ -2,11 +2,15 @@
 
 use semver::Version;
 use serde::Deserialize;
+use sha2::Digest;
 use snafu::prelude::*;
-use std::io::Read;
+use std::{io::Read, sync::mpsc, thread};
+
+use crate::{client, common::CrateName};
 
 const CRATES_IO_API_BASE: &str = "https://crates.io/api/v1";
-const CRATES_IO_STATIC_BASE: &str = "https://static.crates.io/crates";
+/// Where crates.io serves `.crate` files, as a Cargo `dl` template.
+pub const CRATES_IO_DL: &str = "https://static.crates.io/crates/{crate}/{crate}-{version}.crate";
 const USER_AGENT: &str = concat!(
     "gnostr-registry/",
     env!("CARGO_PKG_VERSION"),
@@ -49,37 +53,116 @@ impl Client {
         Ok(versions)
     }
 
-    /// Download the `.crate` file for `krate` at `version`.
-    pub fn download_crate(&self, krate: &str, version: &str) -> Result<Vec<u8>, Error> {
+    /// Return the logins of the users and teams that own `krate`.
+    pub fn fetch_owners(&self, krate: &str) -> Result<Vec<String>, Error> {
         use error::*;
 
-        let url = format!("{CRATES_IO_STATIC_BASE}/{krate}/{krate}-{version}.crate");
+        let url = format!("{CRATES_IO_API_BASE}/crates/{krate}/owners");
 
-        let response = self
+        let response: OwnersResponse = self
             .inner
             .get(&url)
             .call()
-            .context(RequestSnafu { url: &url })?;
+            .context(RequestSnafu { url: &url })?
+            .into_json()
+            .context(DeserializeSnafu { url: &url })?;
+
+        Ok(response.users.into_iter().map(|u| u.login).collect())
+    }
+
+    /// Downloads the `.crate` file from every source at once and returns
+    /// the first copy whose SHA-256 is `cksum`, with the URL it came
+    /// from. Each source is a Cargo `dl` template, such as
+    /// [`CRATES_IO_DL`] or another mirror's.
+    pub fn download_first(
+        &self,
+        sources: &[String],
+        krate: &CrateName,
+        version: &Version,
+        cksum: &str,
+    ) -> Result<(Vec<u8>, String), Error> {
+        let attempts = sources
+            .iter()
+            .map(|dl| {
+                let url = client::download_url(dl, krate, version, cksum);
+                let agent = self.inner.clone();
+                let attempt = {
+                    let url = url.clone();
+                    move || get_bytes(&agent, &url)
+                };
+                (url, attempt)
+            })
+            .collect();
 
-        let mut data = Vec::new();
-        response
-            .into_reader()
-            .read_to_end(&mut data)
-            .context(ReadBodySnafu { url: &url })?;
+        first_verified(attempts, cksum)
+    }
+}
+
+fn get_bytes(agent: &ureq::Agent, url: &str) -> Result<Vec<u8>, Error> {
+    use error::*;
+
+    let response = agent.get(url).call().context(RequestSnafu { url })?;
+
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut data)
+        .context(ReadBodySnafu { url })?;
+
+    Ok(data)
+}
 
-        Ok(data)
+/// Runs every attempt on its own thread and returns the first result
+/// with the SHA-256 `cksum`, with the URL it came from. The slower
+/// attempts are left to finish in the background.
+fn first_verified<F>(attempts: Vec<(String, F)>, cksum: &str) -> Result<(Vec<u8>, String), Error>
+where
+    F: FnOnce() -> Result<Vec<u8>, Error> + Send + 'static,
+{
+    use error::*;
+
+    let (tx, rx) = mpsc::channel();
+    for (url, attempt) in attempts {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            // The receiver is gone once another source has won
+            let _ = tx.send((url, attempt()));
+        });
     }
+    drop(tx);
+
+    let mut failures = vec![];
+    for (url, result) in rx {
+        match result {
+            Ok(data) => {
+                let actual = hex::encode(sha2::Sha256::digest(&data));
+                if actual.eq_ignore_ascii_case(cksum) {
+                    return Ok((data, url));
+                }
+                failures.push(format!("{url} sent a copy with the checksum {actual}"));
+            }
+            Err(e) => failures.push(e.to_string()),
+        }
+    }
+
+    SourcesSnafu { failures }.fail()
 }
 
 /// One version entry returned by the crates.io versions API.
 #[derive(Debug, Deserialize)]
 pub struct CrateVersion {
+    /// Assigned by crates.io in publication order.
+    pub id: u64,
+
     /// Parsed semver version number.
     #[serde(rename = "num")]
     pub num: Version,
 
     /// Whether this version has been yanked.
     pub yanked: bool,
+
+    /// The SHA256 checksum of the `.crate` file.
+    pub checksum: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -87,6 +170,16 @@ struct VersionsResponse {
     versions: Vec<CrateVersion>,
 }
 
+#[derive(Debug, Deserialize)]
+struct OwnersResponse {
+    users: Vec<Owner>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Owner {
+    login: String,
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(module)]
 pub enum Error {
@@ -107,12 +200,45 @@ pub enum Error {
         source: std::io::Error,
         url: String,
     },
+
+    #[snafu(display("No source had a verified copy: {}", failures.join("; ")))]
+    Sources { failures: Vec<String> },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn the_first_verified_copy_wins() {
+        let good = b"crate".to_vec();
+        let cksum = hex::encode(sha2::Sha256::digest(&good));
+
+        type Attempt = Box<dyn FnOnce() -> Result<Vec<u8>, Error> + Send>;
+        let attempts: Vec<(String, Attempt)> = vec![
+            ("corrupt".to_owned(), Box::new(|| Ok(b"tampered".to_vec()))),
+            (
+                "slow".to_owned(),
+                Box::new(move || {
+                    thread::sleep(std::time::Duration::from_millis(50));
+                    Ok(good)
+                }),
+            ),
+            (
+                "down".to_owned(),
+                Box::new(|| error::SourcesSnafu { failures: vec![] }.fail()),
+            ),
+        ];
+
+        let (data, url) = first_verified(attempts, &cksum).unwrap();
+        assert_eq!((b"crate".to_vec(), "slow".to_owned()), (data, url));
+
+        let attempts: Vec<(String, Attempt)> =
+            vec![("corrupt".to_owned(), Box::new(|| Ok(b"tampered".to_vec())))];
+        let e = first_verified(attempts, &cksum).unwrap_err();
+        assert!(matches!(e, Error::Sources { failures } if failures.len() == 1));
+    }
+
     /// Verify that the crates.io versions API returns at least one version
     /// for the `margo` crate and that version numbers can be parsed as semver.
     ///
@@ -182,8 +308,10 @@ mod tests {
             .first()
             .expect("margo should have at least one version");
 
-        let data = client
-            .download_crate("margo", &first.num.to_string())
+        let sources = [CRATES_IO_DL.to_owned()];
+        let name = "margo".parse().unwrap();
+        let (data, _) = client
+            .download_first(&sources, &name, &first.num, &first.checksum)
             .expect("should be able to download the margo crate");
 
         assert!(
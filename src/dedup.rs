@@ -0,0 +1,229 @@
+//! Storing identical files once across registries.
+//!
+//! A host that keeps a crates.io mirror next to its own registries
+//! often holds the same `.crate` file several times. `margo dedup` looks
+//! at the `.crate` files and blobs of every registry it is given and
+//! replaces each copy with a hard link to the first file with the same
+//! SHA-256. Those files are only ever replaced, never modified in place,
+//! so a later write to one registry cannot change what another serves.
+//! Files that cannot be linked, for example because they are on
+//! different file systems, are left alone.
+
+use sha2::Digest;
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    progress::{self, Progress},
+    registry_snapshot,
+};
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// How many `.crate` files and blobs were looked at.
+    pub files: usize,
+
+    /// How many of them were replaced by hard links, or would be.
+    pub linked: usize,
+
+    /// The bytes those copies took up.
+    pub reclaimed: u64,
+}
+
+/// Links identical files below each of `roots`. With `dry_run`, only
+/// reports what would be linked.
+pub fn dedup(roots: &[&Path], dry_run: bool, mode: progress::Mode) -> Result<Summary, Error> {
+    let mut summary = Summary::default();
+
+    // Only files of the same size can be identical, so only those are
+    // hashed.
+    let mut by_size = BTreeMap::<u64, Vec<PathBuf>>::new();
+    for root in roots {
+        for (path, len) in candidates(root)? {
+            summary.files += 1;
+            by_size.entry(len).or_default().push(path);
+        }
+    }
+
+    by_size.retain(|_, paths| paths.len() > 1);
+    let to_hash = by_size.values().map(Vec::len).sum::<usize>();
+    let mut progress = Progress::new(mode, "dedup", Some(to_hash as u64));
+
+    for (len, paths) in by_size {
+        let mut by_digest = BTreeMap::<String, Vec<PathBuf>>::new();
+        for path in paths {
+            let digest = digest_of(&path)?;
+            progress.add_bytes(len);
+            progress.inc(path.display());
+            by_digest.entry(digest).or_default().push(path);
+        }
+
+        for paths in by_digest.values() {
+            let (first, copies) = paths.split_first().expect("groups are never empty");
+            for copy in copies {
+                if is_same_file(first, copy)? {
+                    continue;
+                }
+                if dry_run || link_over(first, copy)? {
+                    summary.linked += 1;
+                    summary.reclaimed += len;
+                }
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// The `.crate` files and blobs below `root`, with their sizes.
+fn candidates(root: &Path) -> Result<Vec<(PathBuf, u64)>, Error> {
+    use error::*;
+
+    let mut found = vec![];
+    for entry in walkdir::WalkDir::new(root) {
+        let entry = entry.context(WalkSnafu { path: root })?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let is_partial = path.extension().is_some_and(|e| e == "tmp");
+        if is_partial || !registry_snapshot::is_immutable(relative) {
+            continue;
+        }
+
+        let metadata = entry.metadata().context(WalkSnafu { path })?;
+        found.push((path.to_owned(), metadata.len()));
+    }
+
+    Ok(found)
+}
+
+fn digest_of(path: &Path) -> Result<String, Error> {
+    use error::*;
+
+    let data = fs::read(path).context(ReadSnafu { path })?;
+
+    Ok(hex::encode(sha2::Sha256::digest(data)))
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> Result<bool, Error> {
+    use error::*;
+    use std::os::unix::fs::MetadataExt;
+
+    let a = fs::metadata(a).context(ReadSnafu { path: a })?;
+    let b = fs::metadata(b).context(ReadSnafu { path: b })?;
+
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+/// Without inode numbers an existing link is linked again, which is
+/// harmless.
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> Result<bool, Error> {
+    Ok(false)
+}
+
+/// Replaces `copy` with a hard link to `original`. Returns `false`
+/// when the file system cannot link them.
+fn link_over(original: &Path, copy: &Path) -> Result<bool, Error> {
+    use error::*;
+
+    // Linked under a temporary name and renamed over the copy, so the
+    // copy is never missing.
+    let tmp = copy.with_extension("dedup.tmp");
+    match fs::remove_file(&tmp) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(WriteSnafu { path: tmp }),
+    }
+
+    if fs::hard_link(original, &tmp).is_err() {
+        return Ok(false);
+    }
+    fs::rename(&tmp, copy).context(WriteSnafu { path: copy })?;
+
+    Ok(true)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not walk {}", path.display()))]
+    Walk {
+        source: walkdir::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Walk { .. } | Self::Read { .. } => "E_STORAGE_READ",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn identical_crates_are_stored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let crate_path = |registry: &str, vers: &str| {
+            dir.join(registry)
+                .join(format!("crates/de/mo/demo/{vers}.crate"))
+        };
+
+        for (registry, vers, data) in [
+            ("local", "1.0.0", "same"),
+            ("mirror", "1.0.0", "same"),
+            ("mirror", "1.1.0", "other"),
+        ] {
+            let path = crate_path(registry, vers);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, data).unwrap();
+        }
+        fs::write(dir.join("local/config.json"), "same").unwrap();
+
+        let local = dir.join("local");
+        let mirror = dir.join("mirror");
+        let roots = [local.as_path(), mirror.as_path()];
+
+        let summary = dedup(&roots, true, progress::Mode::Quiet).unwrap();
+        assert_eq!(
+            (3, 1, 4),
+            (summary.files, summary.linked, summary.reclaimed)
+        );
+
+        let summary = dedup(&roots, false, progress::Mode::Quiet).unwrap();
+        assert_eq!(1, summary.linked);
+        assert_eq!(
+            "same",
+            fs::read_to_string(crate_path("mirror", "1.0.0")).unwrap()
+        );
+
+        #[cfg(unix)]
+        {
+            let (first, copy) = (crate_path("local", "1.0.0"), crate_path("mirror", "1.0.0"));
+            assert!(is_same_file(&first, &copy).unwrap());
+            let summary = dedup(&roots, false, progress::Mode::Quiet).unwrap();
+            assert_eq!(0, summary.linked);
+        }
+    }
+}
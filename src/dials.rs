@@ -0,0 +1,91 @@
+//! How many peers the node stays connected to.
+//!
+//! Peers found on the local network are remembered rather than all
+//! dialed at once. Every ten seconds, the node works out how many peers
+//! it wants from its backlog, the requests for crates and blobs that
+//! peers have not answered yet: `min-peers`, and one more for each
+//! `backlog-per-peer` requests, up to `max-peers`. It dials remembered
+//! peers until it has that many, and once the backlog is empty it
+//! disconnects the peers it dialed for it, down to `min-peers`. Peers
+//! that dial the node, and those dialed with `margo peer dial`, are
+//! never disconnected for this.
+//!
+//! ```toml
+//! [announcements.dials]
+//! min-peers = 4
+//! max-peers = 32
+//! backlog-per-peer = 16
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_MIN_PEERS: usize = 4;
+const DEFAULT_MAX_PEERS: usize = 32;
+const DEFAULT_BACKLOG_PER_PEER: usize = 16;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Policy {
+    /// How many peers to stay connected to when idle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_peers: Option<usize>,
+
+    /// The most peers to dial for a backlog.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_peers: Option<usize>,
+
+    /// How many unanswered requests warrant another peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backlog_per_peer: Option<usize>,
+}
+
+impl Policy {
+    pub fn min_peers(&self) -> usize {
+        self.min_peers.unwrap_or(DEFAULT_MIN_PEERS)
+    }
+
+    fn max_peers(&self) -> usize {
+        self.max_peers
+            .unwrap_or(DEFAULT_MAX_PEERS)
+            .max(self.min_peers())
+    }
+
+    fn backlog_per_peer(&self) -> usize {
+        self.backlog_per_peer
+            .unwrap_or(DEFAULT_BACKLOG_PER_PEER)
+            .max(1)
+    }
+
+    /// How many peers to be connected to with `backlog` requests
+    /// unanswered.
+    pub fn target(&self, backlog: usize) -> usize {
+        let extra = backlog.div_ceil(self.backlog_per_peer());
+        (self.min_peers() + extra).min(self.max_peers())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn a_backlog_wants_more_peers_up_to_the_limit() {
+        let policy = Policy {
+            min_peers: Some(2),
+            max_peers: Some(5),
+            backlog_per_peer: Some(10),
+        };
+
+        assert_eq!(2, policy.target(0));
+        assert_eq!(3, policy.target(1));
+        assert_eq!(3, policy.target(10));
+        assert_eq!(4, policy.target(11));
+        assert_eq!(5, policy.target(1000));
+
+        let policy = Policy {
+            max_peers: Some(1),
+            ..Policy::default()
+        };
+        assert_eq!(DEFAULT_MIN_PEERS, policy.target(1000));
+    }
+}
@@ -0,0 +1,315 @@
+//! Finding registries by a `name@domain` address, in the style of
+//! NIP-05.
+//!
+//! `margo serve` answers `/.well-known/margo.json` with a
+//! [`Document`] describing each registry it hosts, keyed by tenant
+//! name, or `_` for a registry served at the root. `margo discover
+//! name@domain` fetches it and adds the registry to Cargo's
+//! configuration.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[cfg(feature = "discover")]
+use snafu::prelude::*;
+#[cfg(feature = "discover")]
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub const WELL_KNOWN_PATH: &str = "/.well-known/margo.json";
+
+/// The name of a registry served at the root of its domain.
+pub const ROOT_NAME: &str = "_";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Document {
+    pub registries: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// The index URL as Cargo expects it, including `sparse+`.
+    pub index: String,
+
+    /// The operator's nostr public key, hex encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub multiaddrs: Vec<String>,
+}
+
+/// A `name@domain` registry address. A bare domain means `_@domain`.
+#[cfg(feature = "discover")]
+#[derive(Debug, Clone)]
+pub struct Address {
+    pub name: String,
+    pub domain: String,
+}
+
+#[cfg(feature = "discover")]
+impl Address {
+    /// What Cargo should call the registry: the name, or the domain
+    /// for a registry at the root.
+    pub fn registry_name(&self) -> String {
+        if self.name == ROOT_NAME {
+            self.domain.replace('.', "-")
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+#[cfg(feature = "discover")]
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use error::*;
+
+        let (name, domain) = s.split_once('@').unwrap_or((ROOT_NAME, s));
+
+        let valid_name = name == ROOT_NAME || is_valid_name(name);
+        let valid_domain = !domain.is_empty()
+            && domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':'));
+        ensure!(valid_name && valid_domain, AddressSnafu { address: s });
+
+        Ok(Self {
+            name: name.to_owned(),
+            domain: domain.to_owned(),
+        })
+    }
+}
+
+#[cfg(feature = "discover")]
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+/// Fetches the address's well-known document and returns its entry.
+#[cfg(feature = "discover")]
+pub fn resolve(address: &Address) -> Result<Entry, Error> {
+    use error::*;
+
+    let Address { name, domain } = address;
+    let url = format!("https://{domain}{WELL_KNOWN_PATH}?name={name}");
+
+    let mut document: Document = ureq::get(&url)
+        .call()
+        .context(RequestSnafu { url: &url })?
+        .into_json()
+        .context(ResponseSnafu { url })?;
+
+    let entry = document.registries.remove(name).context(NotListedSnafu {
+        address: address.clone(),
+    })?;
+
+    let index_url = entry.index.strip_prefix("sparse+");
+    ensure!(
+        index_url.is_some_and(|u| u.starts_with("https://") || u.starts_with("http://")),
+        IndexSnafu {
+            index: &entry.index
+        }
+    );
+
+    Ok(entry)
+}
+
+/// The user's Cargo configuration file.
+#[cfg(feature = "discover")]
+pub fn default_cargo_config() -> Option<PathBuf> {
+    let cargo_home = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+            Some(PathBuf::from(home).join(".cargo"))
+        })?;
+
+    Some(cargo_home.join("config.toml"))
+}
+
+/// Adds `[registries.{name}]` to the Cargo configuration at `path`,
+/// leaving the rest of the file untouched. Returns `false` if the
+/// registry was already configured with the same index.
+#[cfg(feature = "discover")]
+pub fn configure_cargo(path: &Path, name: &str, index: &str) -> Result<bool, Error> {
+    use error::*;
+
+    #[derive(Deserialize)]
+    struct CargoConfig {
+        #[serde(default)]
+        registries: BTreeMap<String, CargoRegistry>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct CargoRegistry {
+        index: Option<String>,
+    }
+
+    ensure!(is_valid_name(name), RegistryNameSnafu { name });
+
+    let mut config = match fs::read_to_string(path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(ReadConfigSnafu { path }),
+    };
+
+    let existing: CargoConfig = toml::from_str(&config).context(ParseConfigSnafu { path })?;
+    if let Some(existing) = existing.registries.get(name) {
+        let existing = existing.index.as_deref().unwrap_or_default();
+        ensure!(
+            existing == index,
+            ConflictSnafu {
+                name,
+                path,
+                existing
+            }
+        );
+        return Ok(false);
+    }
+
+    let registry = BTreeMap::from([(
+        "registries",
+        BTreeMap::from([(
+            name,
+            CargoRegistry {
+                index: Some(index.to_owned()),
+            },
+        )]),
+    )]);
+    let section = toml::to_string(&registry).context(SerializeSnafu)?;
+
+    if !config.is_empty() && !config.ends_with('\n') {
+        config.push('\n');
+    }
+    if !config.is_empty() {
+        config.push('\n');
+    }
+    config.push_str(&section);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(WriteConfigSnafu { path })?;
+    }
+    fs::write(path, config).context(WriteConfigSnafu { path })?;
+
+    Ok(true)
+}
+
+#[cfg(feature = "discover")]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("`{address}` is not a `name@domain` address"))]
+    Address { address: String },
+
+    #[snafu(display("Could not fetch {url}"))]
+    Request {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: String,
+    },
+
+    #[snafu(display("Could not deserialize the discovery document at {url}"))]
+    Response { source: io::Error, url: String },
+
+    #[snafu(display("{} does not list a registry named `{}`", address.domain, address.name))]
+    NotListed { address: Address },
+
+    #[snafu(display("The discovered index `{index}` is not a sparse HTTP index"))]
+    Index { index: String },
+
+    #[snafu(display("`{name}` is not a valid Cargo registry name"))]
+    RegistryName { name: String },
+
+    #[snafu(display("Could not read the Cargo configuration at {}", path.display()))]
+    ReadConfig { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the Cargo configuration at {}", path.display()))]
+    ParseConfig {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display(
+        "The registry `{name}` is already configured in {} with the index `{existing}`",
+        path.display()
+    ))]
+    Conflict {
+        name: String,
+        path: PathBuf,
+        existing: String,
+    },
+
+    #[snafu(display("Could not serialize the Cargo configuration"))]
+    Serialize { source: toml::ser::Error },
+
+    #[snafu(display("Could not write the Cargo configuration at {}", path.display()))]
+    WriteConfig { source: io::Error, path: PathBuf },
+}
+
+#[cfg(feature = "discover")]
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Address { .. } | Self::RegistryName { .. } => "E_BAD_ADDRESS",
+            Self::Request { .. } | Self::Response { .. } => "E_DISCOVERY",
+            Self::NotListed { .. } => "E_REGISTRY_NOT_FOUND",
+            Self::Index { .. } => "E_DISCOVERY",
+            Self::ReadConfig { .. } => "E_CONFIG_READ",
+            Self::ParseConfig { .. } | Self::Conflict { .. } => "E_CONFIG_INVALID",
+            Self::Serialize { .. } | Self::WriteConfig { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(all(test, feature = "discover"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn addresses_default_to_the_root_name() {
+        let address: Address = "example.com".parse().unwrap();
+        assert_eq!(ROOT_NAME, address.name);
+        assert_eq!("example-com", address.registry_name());
+
+        let address: Address = "acme@example.com".parse().unwrap();
+        assert_eq!("acme", address.registry_name());
+
+        assert!("a b@example.com".parse::<Address>().is_err());
+        assert!("acme@example.com/evil".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn cargo_configuration_is_appended_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join("config.toml");
+        fs::write(&path, "# keep me\n[net]\nretry = 3").unwrap();
+
+        let index = "sparse+https://example.com/acme/";
+        assert!(configure_cargo(&path, "acme", index).unwrap());
+        assert!(!configure_cargo(&path, "acme", index).unwrap());
+        assert!(configure_cargo(&path, "acme", "sparse+https://other.example/").is_err());
+
+        let config = fs::read_to_string(&path).unwrap();
+        assert!(
+            config.starts_with("# keep me\n[net]\nretry = 3\n"),
+            "{config}"
+        );
+        assert!(
+            config.contains(r#"index = "sparse+https://example.com/acme/""#),
+            "{config}"
+        );
+    }
+}
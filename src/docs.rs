@@ -0,0 +1,233 @@
+//! Building rustdoc for crates in the registry.
+//!
+//! The package is unpacked into a scratch directory under the system
+//! temporary directory, which is new and which only margo's user can
+//! enter, and documented with `cargo doc --no-deps`. The
+//! output is copied to `docs/{name}/{version}/` in the registry, where
+//! it is served alongside everything else.
+//!
+//! Building documentation runs the crate's build script and procedural
+//! macros. Dependencies are fetched first, which runs nothing from the
+//! crate: Cargo runs in the registry's directory rather than the
+//! package's, so it ignores any `.cargo/config.toml` the crate ships.
+//! `cargo doc` then runs offline in a [sandbox](crate::sandbox) that
+//! can read the toolchain and the fetched dependencies, but not the
+//! rest of `CARGO_HOME`, such as the credentials kept there, and can
+//! write only to the scratch directory. That makes a hostile crate
+//! harder to host, not harmless, so still only enable this for crates
+//! you trust.
+
+use semver::Version;
+use snafu::prelude::*;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+};
+
+use crate::{
+    common::CrateName,
+    extract,
+    sandbox::{self, Backend, Sandbox},
+    Registry,
+};
+
+pub const DIR_NAME: &str = "docs";
+
+/// What `cargo fetch` is held to, whatever the configuration around
+/// the registry says: git is never run, so its own configuration and
+/// hooks cannot run anything either.
+const FETCH_CONFIG: &[&str] = &["net.git-fetch-with-cli=false", "net.offline=false"];
+
+/// The parts of `CARGO_HOME` that `cargo doc` needs: the Cargo and
+/// rustup proxies, and the index and packages it resolves offline.
+const CARGO_HOME_READ_ONLY: &[&str] = &["bin", "registry/index", "registry/cache", "registry/src"];
+
+/// Where Cargo keeps registry tokens, in case a path tools may read
+/// holds them.
+const CREDENTIAL_FILES: &[&str] = &["credentials.toml", "credentials"];
+
+/// Builds the documentation for one version of a crate, replacing any
+/// previous build. Returns the directory the docs were written to.
+pub fn build(registry: &Registry, name: &CrateName, version: &Version) -> Result<PathBuf, Error> {
+    use error::*;
+
+    registry.promote(name, version).context(PromoteSnafu)?;
+    let crate_path = registry.crate_file_path_for(name, version);
+    let crate_data = fs::read(&crate_path).context(ReadCrateSnafu { path: &crate_path })?;
+
+    let scratch = tempfile::Builder::new()
+        .prefix(&format!("margo-docs-{name}-{version}-"))
+        .tempdir()
+        .context(ScratchSnafu {
+            path: env::temp_dir(),
+        })?;
+    let scratch = scratch.path();
+
+    let prefix = format!("{name}-{version}");
+    let package_dir = scratch.join(&prefix);
+    let limits = registry.unpack_limits();
+    let files = extract::files(&crate_data, &prefix, &limits).context(UnpackSnafu)?;
+    extract::write(&files, &package_dir).context(UnpackSnafu)?;
+
+    let manifest_path = package_dir.join("Cargo.toml");
+    let target_dir = scratch.join("target");
+
+    println!("Building documentation for {name} {version}");
+
+    let cargo = sandbox::find_program("cargo").context(NoCargoSnafu)?;
+    let mut command = Command::new(&cargo);
+    command
+        .arg("fetch")
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .current_dir(&registry.path);
+    for config in FETCH_CONFIG {
+        command.args(["--config", config]);
+    }
+    let status = command.status().context(SpawnSnafu)?;
+    ensure!(status.success(), FailedSnafu { status });
+
+    let mut sandbox = Sandbox::new(&registry.config.sandbox, scratch).context(SandboxSnafu)?;
+    if let Some(dir) = cargo.parent() {
+        sandbox.read_only(dir);
+    }
+    if let Some(home) = tool_home("CARGO_HOME", ".cargo") {
+        for dir in CARGO_HOME_READ_ONLY {
+            sandbox.read_only(home.join(dir));
+        }
+        for file in CREDENTIAL_FILES {
+            sandbox.hide(home.join(file));
+        }
+        sandbox.env("CARGO_HOME", &home);
+    }
+    if let Some(home) = tool_home("RUSTUP_HOME", ".rustup") {
+        sandbox.read_only(&home).env("RUSTUP_HOME", &home);
+    }
+    if let Some(toolchain) = env::var_os("RUSTUP_TOOLCHAIN") {
+        sandbox.env("RUSTUP_TOOLCHAIN", toolchain);
+    }
+    if *sandbox.backend() == Backend::None {
+        eprintln!("Warning: no operating system sandbox is available; `cargo doc` runs with only resource limits");
+    }
+
+    // Outside the package, so that its `.cargo/config.toml` is ignored
+    // here too
+    let mut command = sandbox.command(&cargo, scratch);
+    command
+        .args(["doc", "--no-deps", "--offline", "--manifest-path"])
+        .arg(&manifest_path)
+        .arg("--target-dir")
+        .arg(&target_dir);
+    let status = sandbox.run(&mut command).context(SandboxSnafu)?;
+    ensure!(status.success(), FailedSnafu { status });
+
+    let out_dir = registry.docs_dir_for(name, version);
+    match fs::remove_dir_all(&out_dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(ClearSnafu { path: out_dir }),
+    }
+
+    copy_dir(&target_dir.join("doc"), &out_dir)?;
+
+    // rustdoc puts each library in its own directory; send visitors
+    // of the version's directory there.
+    let lib_name = name.as_str().replace('-', "_");
+    if out_dir.join(&lib_name).join("index.html").exists() {
+        let redirect =
+            format!(r#"<meta http-equiv="refresh" content="0; url={lib_name}/index.html">"#);
+        let path = out_dir.join("index.html");
+        fs::write(&path, redirect).context(WriteSnafu { path })?;
+    }
+
+    println!("Wrote documentation to `{}`", out_dir.display());
+
+    Ok(out_dir)
+}
+
+/// Where a Cargo or rustup installation lives: `var`, or `dir_name`
+/// in the home directory.
+fn tool_home(var: &str, dir_name: &str) -> Option<PathBuf> {
+    if let Some(path) = env::var_os(var) {
+        return Some(path.into());
+    }
+
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(dir_name))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), Error> {
+    use error::*;
+
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry.context(WalkSnafu { path: from })?;
+        let relative = entry
+            .path()
+            .strip_prefix(from)
+            .expect("walkdir only yields paths under its root");
+        let dest = to.join(relative);
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest).context(WriteSnafu { path: dest })?;
+        } else {
+            fs::copy(entry.path(), &dest).context(WriteSnafu { path: dest })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not bring the crate back from the cold tier"))]
+    Promote { source: crate::tier::Error },
+
+    #[snafu(display("Could not read the crate {}", path.display()))]
+    ReadCrate { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not create a scratch directory in {}", path.display()))]
+    Scratch { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not unpack the crate package"))]
+    Unpack { source: extract::Error },
+
+    #[snafu(display("Could not find `cargo` on the PATH"))]
+    NoCargo,
+
+    #[snafu(display("Could not run `cargo fetch`"))]
+    Spawn { source: io::Error },
+
+    #[snafu(display("Could not run `cargo doc` in the sandbox"))]
+    Sandbox { source: sandbox::Error },
+
+    #[snafu(display("Cargo failed ({status})"))]
+    Failed { status: ExitStatus },
+
+    #[snafu(display("Could not remove the previous documentation at {}", path.display()))]
+    Clear { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not read the generated documentation at {}", path.display()))]
+    Walk {
+        source: walkdir::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not write the documentation to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Promote { source } => source.code(),
+            Self::ReadCrate { .. } => "E_CRATE_READ",
+            Self::Unpack { source } => source.code(),
+            Self::NoCargo | Self::Spawn { .. } | Self::Failed { .. } => "E_DOCS_BUILD",
+            Self::Sandbox { source } => source.code(),
+            Self::Walk { .. } => "E_STORAGE_READ",
+            Self::Scratch { .. } | Self::Clear { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Documentation waiting to be built by the daemon.
+//!
+//! `cargo doc` can run for as long as the [sandbox](crate::sandbox)
+//! allows, so a publish does not wait for it. Once the crate is added,
+//! the daemon leaves an empty `docs-queue/{name}/{version}` in the
+//! tenant's publishing `data-dir` and wakes the tenant's docs worker,
+//! which builds the queued crates one at a time and removes each one's
+//! entry once it is done, whether or not the build worked. Entries left
+//! by a daemon that stopped are built when it starts again.
+
+use semver::Version;
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex, PoisonError},
+};
+
+use crate::common::CrateName;
+
+const DIR_NAME: &str = "docs-queue";
+
+/// Wakes the docs worker when a crate is queued.
+#[derive(Debug, Default)]
+pub struct Signal {
+    pending: Mutex<bool>,
+    requested: Condvar,
+}
+
+impl Signal {
+    pub fn request(&self) {
+        *self.pending.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.requested.notify_one();
+    }
+
+    /// Blocks until a crate has been queued since the last call.
+    pub fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*pending {
+            pending = self
+                .requested
+                .wait(pending)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *pending = false;
+    }
+}
+
+pub fn enqueue(data_dir: &Path, name: &CrateName, version: &Version) -> Result<(), Error> {
+    use error::*;
+
+    let path = entry_path(data_dir, name, version);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(WriteSnafu { path: dir })?;
+    }
+    fs::write(&path, b"").context(WriteSnafu { path })
+}
+
+/// Every queued crate, in no particular order.
+pub fn list(data_dir: &Path) -> Result<Vec<(CrateName, Version)>, Error> {
+    use error::*;
+
+    let dir = data_dir.join(DIR_NAME);
+    let mut queued = vec![];
+    for name_dir in read_dir(&dir)? {
+        let Some(name) = file_name(&name_dir).and_then(|n| n.parse::<CrateName>().ok()) else {
+            continue;
+        };
+
+        for version in read_dir(&name_dir)? {
+            let Some(version) = file_name(&version).and_then(|v| v.parse().ok()) else {
+                continue;
+            };
+            queued.push((name.clone(), version));
+        }
+    }
+
+    Ok(queued)
+}
+
+pub fn dequeue(data_dir: &Path, name: &CrateName, version: &Version) -> Result<(), Error> {
+    use error::*;
+
+    let path = entry_path(data_dir, name, version);
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(WriteSnafu { path }),
+    }
+
+    // Only succeeds once no other version of the crate is queued
+    if let Some(dir) = path.parent() {
+        _ = fs::remove_dir(dir);
+    }
+
+    Ok(())
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    use error::*;
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(ReadSnafu { path: dir }),
+    };
+
+    entries
+        .map(|entry| entry.map(|e| e.path()).context(ReadSnafu { path: dir }))
+        .collect()
+}
+
+fn file_name(path: &Path) -> Option<&str> {
+    path.file_name()?.to_str()
+}
+
+fn entry_path(data_dir: &Path, name: &CrateName, version: &Version) -> PathBuf {
+    let mut path = data_dir.join(DIR_NAME);
+    path.push(name);
+    path.push(version.to_string());
+    path
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queued_docs_are_listed_until_built() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let name = "demo".parse::<CrateName>().unwrap();
+        let one = "1.0.0".parse::<Version>().unwrap();
+        let two = "2.0.0".parse::<Version>().unwrap();
+        enqueue(dir, &name, &one).unwrap();
+        enqueue(dir, &name, &two).unwrap();
+        enqueue(dir, &name, &two).unwrap();
+
+        let mut queued = list(dir).unwrap();
+        queued.sort();
+        assert_eq!(
+            vec![(name.clone(), one.clone()), (name.clone(), two.clone())],
+            queued
+        );
+
+        dequeue(dir, &name, &one).unwrap();
+        assert_eq!(vec![(name.clone(), two.clone())], list(dir).unwrap());
+
+        dequeue(dir, &name, &two).unwrap();
+        dequeue(dir, &name, &two).unwrap();
+        assert!(list(dir).unwrap().is_empty());
+        assert!(!dir.join(DIR_NAME).join("demo").exists());
+    }
+}
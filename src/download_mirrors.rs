@@ -0,0 +1,419 @@
+//! Sending each download to the nearest healthy copy of the registry.
+//!
+//! A tenant whose `.crate` files are also served from other places,
+//! such as a static copy in each region, lists them as endpoints:
+//!
+//! ```toml
+//! [tenant.download-mirrors]
+//! # Set by a CDN or load balancer in front of the daemon
+//! region-header = "CF-IPCountry"
+//! check-interval-secs = 30
+//!
+//! [[tenant.download-mirrors.endpoints]]
+//! name = "eu"
+//! url = "https://eu.mirror.example.com/acme/"
+//! regions = ["DE", "FR", "NL"]
+//! networks = ["10.1.0.0/16"]
+//!
+//! [[tenant.download-mirrors.endpoints]]
+//! name = "us"
+//! url = "https://us.mirror.example.com/acme/"
+//! regions = ["US", "CA"]
+//! ```
+//!
+//! The daemon answers each `.crate` download with a redirect to the
+//! same path on an endpoint. Endpoints whose `networks` hold the
+//! client's address, or whose `regions` hold the value of the region
+//! header, are preferred; among them, or among all endpoints when none
+//! match, the one that answered its last health check fastest is
+//! chosen.
+//!
+//! Every `check-interval-secs`, each endpoint's `config.json` is
+//! fetched. An endpoint that fails `unhealthy-after` checks in a row is
+//! taken out of rotation until a check succeeds again. With no healthy
+//! endpoint, the daemon serves the file itself.
+
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+const DEFAULT_CHECK_INTERVAL_SECS: u64 = 30;
+
+const DEFAULT_UNHEALTHY_AFTER: u32 = 3;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The file fetched to check an endpoint.
+const CHECK_PATH: &str = "config.json";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DownloadMirrorsConfig {
+    /// A request header naming the client's region, such as a country
+    /// code added by a CDN.
+    #[serde(default)]
+    region_header: Option<String>,
+
+    #[serde(default = "DownloadMirrorsConfig::default_check_interval_secs")]
+    check_interval_secs: u64,
+
+    #[serde(default = "DownloadMirrorsConfig::default_unhealthy_after")]
+    unhealthy_after: u32,
+
+    endpoints: Vec<EndpointConfig>,
+}
+
+impl DownloadMirrorsConfig {
+    fn default_check_interval_secs() -> u64 {
+        DEFAULT_CHECK_INTERVAL_SECS
+    }
+
+    fn default_unhealthy_after() -> u32 {
+        DEFAULT_UNHEALTHY_AFTER
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct EndpointConfig {
+    name: String,
+
+    /// Where the registry's root is, with a trailing slash.
+    url: Url,
+
+    #[serde(default)]
+    regions: Vec<String>,
+
+    /// CIDR blocks, such as `10.1.0.0/16`.
+    #[serde(default)]
+    networks: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    name: String,
+    url: Url,
+    regions: Vec<String>,
+    networks: Vec<Network>,
+    health: Mutex<Health>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    /// Failed checks since the last one that succeeded.
+    failures: u32,
+
+    /// How long the last successful check took.
+    latency: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct DownloadMirrors {
+    agent: ureq::Agent,
+    region_header: Option<String>,
+    interval: Duration,
+    unhealthy_after: u32,
+    endpoints: Vec<Endpoint>,
+}
+
+impl DownloadMirrors {
+    pub fn new(config: DownloadMirrorsConfig) -> Result<Self, Error> {
+        use error::*;
+
+        ensure!(!config.endpoints.is_empty(), NoEndpointsSnafu);
+        ensure!(config.check_interval_secs > 0, IntervalSnafu);
+
+        let endpoints = config
+            .endpoints
+            .into_iter()
+            .map(|e| {
+                ensure!(
+                    e.url.path().ends_with('/'),
+                    UrlSnafu {
+                        name: e.name,
+                        url: e.url
+                    }
+                );
+
+                let networks = e
+                    .networks
+                    .iter()
+                    .map(|n| n.parse())
+                    .collect::<Result<_, _>>()?;
+
+                Ok(Endpoint {
+                    name: e.name,
+                    url: e.url,
+                    regions: e.regions,
+                    networks,
+                    health: Default::default(),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let agent = ureq::AgentBuilder::new().timeout(CHECK_TIMEOUT).build();
+
+        Ok(Self {
+            agent,
+            region_header: config.region_header,
+            interval: Duration::from_secs(config.check_interval_secs),
+            unhealthy_after: config.unhealthy_after.max(1),
+            endpoints,
+        })
+    }
+
+    pub fn region_header(&self) -> Option<&str> {
+        self.region_header.as_deref()
+    }
+
+    /// Checks the endpoints' health until the daemon stops.
+    pub fn start(self: Arc<Self>) {
+        std::thread::spawn(move || loop {
+            self.check();
+            std::thread::sleep(self.interval);
+        });
+    }
+
+    /// Checks every endpoint, in parallel.
+    pub fn check(&self) {
+        std::thread::scope(|s| {
+            for endpoint in &self.endpoints {
+                s.spawn(move || self.record(endpoint, self.probe(endpoint)));
+            }
+        });
+    }
+
+    fn probe(&self, endpoint: &Endpoint) -> Result<Duration, Error> {
+        use error::*;
+
+        let url = endpoint.url.join(CHECK_PATH).context(CheckUrlSnafu {
+            name: &endpoint.name,
+        })?;
+
+        let start = Instant::now();
+        let response = self.agent.request_url("GET", &url).call();
+        response.context(CheckSnafu {
+            name: &endpoint.name,
+        })?;
+
+        Ok(start.elapsed())
+    }
+
+    fn record(&self, endpoint: &Endpoint, probe: Result<Duration, Error>) {
+        let mut health = endpoint
+            .health
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let was_healthy = health.failures < self.unhealthy_after;
+
+        match probe {
+            Ok(latency) => {
+                if !was_healthy {
+                    println!("Download mirror `{}` is healthy again", endpoint.name);
+                }
+                health.failures = 0;
+                health.latency = Some(latency);
+            }
+            Err(e) => {
+                health.failures = health.failures.saturating_add(1);
+                if was_healthy && health.failures >= self.unhealthy_after {
+                    eprintln!("Warning: {e}; taking it out of rotation");
+                }
+            }
+        }
+    }
+
+    /// Where the file at `path`, relative to the registry's root, should
+    /// be fetched from by a client at `client` in `region`. `None` when
+    /// no endpoint is healthy.
+    pub fn redirect(
+        &self,
+        path: &str,
+        client: Option<IpAddr>,
+        region: Option<&str>,
+    ) -> Option<Url> {
+        let healthy = self
+            .endpoints
+            .iter()
+            .filter_map(|e| {
+                let health = e.health.lock().unwrap_or_else(PoisonError::into_inner);
+                let healthy = health.failures < self.unhealthy_after;
+                // Endpoints not checked yet come after those that were
+                healthy.then(|| (e, health.latency.unwrap_or(Duration::MAX)))
+            })
+            .collect::<Vec<_>>();
+
+        let nearby = healthy
+            .iter()
+            .filter(|(e, _)| {
+                let in_network = client.is_some_and(|ip| e.networks.iter().any(|n| n.contains(ip)));
+                let in_region = region
+                    .is_some_and(|region| e.regions.iter().any(|r| r.eq_ignore_ascii_case(region)));
+                in_network || in_region
+            })
+            .collect::<Vec<_>>();
+
+        let candidates = if nearby.is_empty() {
+            healthy.iter().collect()
+        } else {
+            nearby
+        };
+        let (endpoint, _) = candidates.into_iter().min_by_key(|(_, latency)| *latency)?;
+
+        endpoint.url.join(path.trim_start_matches('/')).ok()
+    }
+}
+
+/// A block of addresses, as in `10.1.0.0/16`. A bare address is a block
+/// of one.
+#[derive(Debug, Clone, Copy)]
+struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || error::NetworkSnafu { network: s }.build();
+
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        ensure!(prefix <= max, error::NetworkSnafu { network: s });
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("At least one download mirror endpoint must be configured"))]
+    NoEndpoints,
+
+    #[snafu(display("The check interval of the download mirrors must be at least a second"))]
+    Interval,
+
+    #[snafu(display("The URL {url} of download mirror `{name}` must end with a slash"))]
+    Url { name: String, url: Url },
+
+    #[snafu(display("`{network}` is not an IP address or CIDR block"))]
+    Network { network: String },
+
+    #[snafu(display("The check URL of download mirror `{name}` is not valid"))]
+    CheckUrl {
+        source: url::ParseError,
+        name: String,
+    },
+
+    #[snafu(display("Download mirror `{name}` failed its health check"))]
+    Check {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        name: String,
+    },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NoEndpoints | Self::Interval | Self::Url { .. } | Self::Network { .. } => {
+                "E_CONFIG_INVALID"
+            }
+            Self::CheckUrl { .. } | Self::Check { .. } => "E_UPSTREAM",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn downloads_go_to_the_nearest_healthy_endpoint() {
+        let config = toml::from_str(
+            r#"
+            unhealthy-after = 2
+
+            [[endpoints]]
+            name = "eu"
+            url = "https://eu.example.com/"
+            regions = ["DE"]
+            networks = ["10.1.0.0/16"]
+
+            [[endpoints]]
+            name = "us"
+            url = "https://us.example.com/"
+            regions = ["US"]
+            "#,
+        )
+        .unwrap();
+        let mirrors = DownloadMirrors::new(config).unwrap();
+        let [eu, us] = &mirrors.endpoints[..] else {
+            panic!("Expected two endpoints");
+        };
+        let failed = || error::NoEndpointsSnafu.build();
+
+        mirrors.record(eu, Ok(Duration::from_millis(80)));
+        mirrors.record(us, Ok(Duration::from_millis(20)));
+
+        let path = "/crates/se/rd/serde/1.0.0.crate";
+        let host = |client: Option<&str>, region| {
+            let client = client.map(|c| c.parse().unwrap());
+            let url = mirrors.redirect(path, client, region)?;
+            Some(url.host_str()?.to_owned())
+        };
+
+        assert_eq!(Some("eu.example.com".into()), host(Some("10.1.2.3"), None));
+        assert_eq!(Some("eu.example.com".into()), host(None, Some("de")));
+        assert_eq!(
+            Some("us.example.com".into()),
+            host(Some("192.0.2.1"), Some("JP"))
+        );
+        assert_eq!(
+            "https://us.example.com/crates/se/rd/serde/1.0.0.crate",
+            mirrors.redirect(path, None, None).unwrap().as_str(),
+        );
+
+        mirrors.record(eu, Err(failed()));
+        assert_eq!(Some("eu.example.com".into()), host(None, Some("DE")));
+        mirrors.record(eu, Err(failed()));
+        assert_eq!(Some("us.example.com".into()), host(None, Some("DE")));
+
+        mirrors.record(us, Err(failed()));
+        mirrors.record(us, Err(failed()));
+        assert_eq!(None, host(None, None));
+
+        mirrors.record(eu, Ok(Duration::from_millis(80)));
+        assert_eq!(Some("eu.example.com".into()), host(None, None));
+    }
+}
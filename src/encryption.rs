@@ -0,0 +1,266 @@
+//! Storing `.crate` files so that only holders of a key can read them.
+//!
+//! With a key configured, `margo add` encrypts each `.crate` file with
+//! XChaCha20-Poly1305 before writing it to the registry:
+//!
+//! ```toml
+//! [encryption]
+//! key-file = "/home/alice/.config/margo/acme.key"
+//! ```
+//!
+//! The key never needs to reach the host: the registry is built where
+//! the key is, and only encrypted files are copied to the host, its
+//! replicas and P2P peers. Each stores and serves the encrypted file
+//! like any other, and the index's `cksum` is that of the encrypted
+//! file, so their checks still hold. The checksum of the decrypted file
+//! is kept next to it as `decrypted_cksum`. READMEs and documentation
+//! are not published for an encrypted registry, since they would show
+//! its code.
+//!
+//! Consumers are given the key out of band and read the registry
+//! through a margo [proxy](crate::proxy) with a `decryption-key-file`,
+//! which decrypts each `.crate` file it fetches and gives Cargo the
+//! index with the decrypted checksums.
+//!
+//! An encrypted file is [`MAGIC`], a random nonce, and the ciphertext.
+//! The crate's name and version are authenticated with it, so a file
+//! cannot pass for another crate's.
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use semver::Version;
+use sha2::Digest;
+use snafu::prelude::*;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::common::CrateName;
+
+/// The start of every encrypted `.crate` file.
+pub const MAGIC: &[u8] = b"margo-encrypted-v1\n";
+
+const KEY_LEN: usize = 32;
+
+const NONCE_LEN: usize = 24;
+
+/// Shared with consumers out of band.
+pub struct Key([u8; KEY_LEN]);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    pub fn generate() -> Result<Self, Error> {
+        let mut key = [0; KEY_LEN];
+        getrandom::getrandom(&mut key).context(error::RandomSnafu)?;
+        Ok(Self(key))
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&self.0))
+    }
+}
+
+/// Reads a key file, the key hex encoded.
+pub fn read_key(path: &Path) -> Result<Key, Error> {
+    use error::*;
+
+    let key = fs::read_to_string(path).context(ReadKeySnafu { path })?;
+    let key = hex::decode(key.trim()).ok();
+    let key = key.and_then(|k| <[u8; KEY_LEN]>::try_from(k).ok());
+    let key = key.context(ParseKeySnafu { path })?;
+
+    Ok(Key(key))
+}
+
+/// Writes a key file that only its owner may read.
+pub fn write_key(path: &Path, key: &Key) -> Result<(), Error> {
+    use error::*;
+    use std::io::Write;
+
+    let mut options = fs::File::options();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path).context(WriteKeySnafu { path })?;
+    writeln!(file, "{}", hex::encode(key.0)).context(WriteKeySnafu { path })
+}
+
+pub fn encrypt(
+    key: &Key,
+    name: &CrateName,
+    vers: &Version,
+    crate_file: &[u8],
+) -> Result<Vec<u8>, Error> {
+    use error::*;
+
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).context(RandomSnafu)?;
+
+    let aad = associated_data(name, vers);
+    let payload = Payload {
+        msg: crate_file,
+        aad: aad.as_bytes(),
+    };
+    let ciphertext = key
+        .cipher()
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .ok()
+        .context(EncryptSnafu)?;
+
+    Ok([MAGIC, &nonce[..], &ciphertext[..]].concat())
+}
+
+/// Decrypts the `.crate` file of `name` and `vers`, and checks that it
+/// has the checksum `cksum`.
+pub fn decrypt(
+    key: &Key,
+    name: &CrateName,
+    vers: &Version,
+    data: &[u8],
+    cksum: &str,
+) -> Result<Vec<u8>, Error> {
+    use error::*;
+
+    let data = data.strip_prefix(MAGIC).context(NotEncryptedSnafu)?;
+    ensure!(data.len() >= NONCE_LEN, NotEncryptedSnafu);
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    let aad = associated_data(name, vers);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: aad.as_bytes(),
+    };
+    let crate_file = key
+        .cipher()
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .ok()
+        .context(DecryptSnafu)?;
+
+    let actual = hex::encode(sha2::Sha256::digest(&crate_file));
+    ensure!(
+        actual.eq_ignore_ascii_case(cksum),
+        ChecksumSnafu {
+            expected: cksum,
+            actual
+        }
+    );
+
+    Ok(crate_file)
+}
+
+/// Replaces each entry's `cksum` with its `decrypted_cksum`, for Cargo
+/// to check the decrypted files against. Lines that are not entries are
+/// kept as they are.
+pub fn decrypted_index(index: &[u8]) -> Vec<u8> {
+    let mut decrypted = Vec::with_capacity(index.len());
+
+    for line in index.split_inclusive(|&b| b == b'\n') {
+        let entry = serde_json::from_slice::<serde_json::Value>(line);
+        let Ok(serde_json::Value::Object(mut entry)) = entry else {
+            decrypted.extend_from_slice(line);
+            continue;
+        };
+
+        if let Some(cksum) = entry.remove("decrypted_cksum") {
+            entry.insert("cksum".to_owned(), cksum);
+        }
+        serde_json::to_writer(&mut decrypted, &entry)
+            .expect("Index entries are always serializable");
+        decrypted.push(b'\n');
+    }
+
+    decrypted
+}
+
+fn associated_data(name: &CrateName, vers: &Version) -> String {
+    format!("{name} {vers}")
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the encryption key {}", path.display()))]
+    ReadKey { source: io::Error, path: PathBuf },
+
+    #[snafu(display("The encryption key {} is not {KEY_LEN} hex-encoded bytes", path.display()))]
+    ParseKey { path: PathBuf },
+
+    #[snafu(display("Could not write the encryption key {}", path.display()))]
+    WriteKey { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not generate random bytes"))]
+    Random { source: getrandom::Error },
+
+    #[snafu(display("Could not encrypt the crate"))]
+    Encrypt,
+
+    #[snafu(display("The crate is not encrypted"))]
+    NotEncrypted,
+
+    #[snafu(display("Could not decrypt the crate; it was encrypted with another key or altered"))]
+    Decrypt,
+
+    #[snafu(display("The decrypted crate has the checksum {actual}, not {expected}"))]
+    Checksum { expected: String, actual: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ReadKey { .. } => "E_KEY_READ",
+            Self::ParseKey { .. } => "E_CONFIG_INVALID",
+            Self::WriteKey { .. } => "E_STORAGE_WRITE",
+            Self::Random { .. } | Self::Encrypt => "E_INTERNAL",
+            Self::NotEncrypted | Self::Decrypt => "E_DECRYPT",
+            Self::Checksum { .. } => "E_BAD_CHECKSUM",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crates_decrypt_only_with_the_key_and_their_name() {
+        let key = Key::generate().unwrap();
+        let name: CrateName = "demo".parse().unwrap();
+        let vers: Version = "1.0.0".parse().unwrap();
+        let crate_file = b"not really a tarball";
+        let cksum = hex::encode(sha2::Sha256::digest(crate_file));
+
+        let encrypted = encrypt(&key, &name, &vers, crate_file).unwrap();
+        assert!(encrypted.starts_with(MAGIC));
+        assert_eq!(
+            crate_file,
+            &*decrypt(&key, &name, &vers, &encrypted, &cksum).unwrap()
+        );
+
+        let other_key = Key::generate().unwrap();
+        let e = decrypt(&other_key, &name, &vers, &encrypted, &cksum).unwrap_err();
+        assert!(matches!(e, Error::Decrypt), "{e}");
+
+        let other_vers = "1.0.1".parse().unwrap();
+        let e = decrypt(&key, &name, &other_vers, &encrypted, &cksum).unwrap_err();
+        assert!(matches!(e, Error::Decrypt), "{e}");
+
+        let index = format!(
+            "{{\"name\":\"demo\",\"cksum\":\"{}\",\"decrypted_cksum\":\"{cksum}\"}}\n",
+            hex::encode(sha2::Sha256::digest(&encrypted)),
+        );
+        let decrypted = decrypted_index(index.as_bytes());
+        let entry: serde_json::Value = serde_json::from_slice(&decrypted).unwrap();
+        assert_eq!(cksum, entry["cksum"]);
+        assert!(entry.get("decrypted_cksum").is_none());
+    }
+}
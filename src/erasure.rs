@@ -0,0 +1,300 @@
+//! Erasure-coded shards of `.crate` files, for archival registries.
+//!
+//! With `[announcements.erasure]`, each `.crate` file added is split
+//! into `data-shards` pieces, and `parity-shards` more are computed from
+//! them, so that any `data-shards` of the shards give the file back.
+//! The shards are kept as [blobs](crate::blob), and which shards make up
+//! which file is recorded in `shards/{name}/{version}.json`.
+//!
+//! Every hour, the daemon offers the shards of each public version to
+//! the volunteers that [seed](crate::seeding), a shard to each while
+//! there are enough of them, and they fetch the shards they lack as
+//! blobs. When the `.crate` file of a version with shards is missing,
+//! the daemon asks its peers for the shards it lacks. It rebuilds the
+//! file from any `data-shards` whose digests match, and only keeps it
+//! when it matches its checksum, so the file survives the loss of any
+//! `parity-shards` of the nodes holding shards.
+//!
+//! ```toml
+//! [announcements.erasure]
+//! data-shards = 4
+//! parity-shards = 2
+//! ```
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::blob;
+
+pub const DIR_NAME: &str = "shards";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Scheme {
+    /// How many shards the file is split into, and so how many it takes
+    /// to rebuild it.
+    pub data_shards: usize,
+
+    /// How many more shards are computed, and so how many may be lost.
+    pub parity_shards: usize,
+}
+
+/// Which shards make up a `.crate` file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    pub vers: Version,
+
+    /// The checksum of the `.crate` file.
+    pub cksum: String,
+
+    /// The length of the `.crate` file, without the padding that makes
+    /// every shard as long.
+    pub len: u64,
+
+    pub data_shards: usize,
+
+    /// The digests of the data shards, then those of the parity shards.
+    pub shards: Vec<String>,
+}
+
+/// Splits the `.crate` file into the scheme's shards.
+pub fn split(
+    name: &str,
+    vers: &Version,
+    data: &[u8],
+    scheme: Scheme,
+) -> Result<(Manifest, Vec<Vec<u8>>), Error> {
+    use error::*;
+
+    let coder = ReedSolomon::new(scheme.data_shards, scheme.parity_shards).context(SchemeSnafu)?;
+
+    let shard_len = data.len().div_ceil(scheme.data_shards).max(1);
+    let mut shards = data
+        .chunks(shard_len)
+        .map(<[u8]>::to_vec)
+        .collect::<Vec<_>>();
+    shards.resize(scheme.data_shards + scheme.parity_shards, vec![]);
+    for shard in &mut shards {
+        shard.resize(shard_len, 0);
+    }
+
+    coder.encode(&mut shards).context(CodeSnafu)?;
+
+    let manifest = Manifest {
+        name: name.to_owned(),
+        vers: vers.clone(),
+        cksum: blob::digest_of(data),
+        len: data.len() as u64,
+        data_shards: scheme.data_shards,
+        shards: shards.iter().map(|s| blob::digest_of(s)).collect(),
+    };
+
+    Ok((manifest, shards))
+}
+
+/// Rebuilds the `.crate` file from the shards that were found, in the
+/// order of the manifest. Shards whose digests do not match are
+/// ignored, and the file must match its checksum.
+pub fn join(manifest: &Manifest, mut shards: Vec<Option<Vec<u8>>>) -> Result<Vec<u8>, Error> {
+    use error::*;
+
+    let total = manifest.shards.len();
+    ensure!(shards.len() == total, MismatchSnafu);
+    for (shard, digest) in shards.iter_mut().zip(&manifest.shards) {
+        let damaged = shard
+            .as_ref()
+            .is_some_and(|s| blob::digest_of(s) != *digest);
+        if damaged {
+            *shard = None;
+        }
+    }
+
+    let have = shards.iter().flatten().count();
+    let need = manifest.data_shards;
+    ensure!(have >= need, TooFewSnafu { have, need });
+
+    let parity_shards = total.checked_sub(need).context(MismatchSnafu)?;
+    let coder = ReedSolomon::new(need, parity_shards).context(SchemeSnafu)?;
+    coder.reconstruct_data(&mut shards).context(CodeSnafu)?;
+
+    let mut data = shards
+        .into_iter()
+        .take(need)
+        .flatten()
+        .flatten()
+        .collect::<Vec<_>>();
+    data.truncate(manifest.len.try_into().unwrap_or(usize::MAX));
+    ensure!(blob::digest_of(&data) == manifest.cksum, ChecksumSnafu);
+
+    Ok(data)
+}
+
+/// Rebuilds the `.crate` file from the shards in `blobs_dir`.
+pub fn rebuild(blobs_dir: &Path, manifest: &Manifest) -> Result<Vec<u8>, Error> {
+    let shards = manifest
+        .shards
+        .iter()
+        .map(|digest| blob::get(blobs_dir, digest).ok())
+        .collect();
+    join(manifest, shards)
+}
+
+/// Where the manifest of the version is kept below `dir`.
+pub fn manifest_path(dir: &Path, name: &str, vers: &Version) -> PathBuf {
+    let mut path = dir.join(name.to_ascii_lowercase());
+    path.push(format!("{vers}.json"));
+    path
+}
+
+pub fn write(dir: &Path, manifest: &Manifest) -> Result<(), Error> {
+    use error::*;
+
+    let path = manifest_path(dir, &manifest.name, &manifest.vers);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+    }
+
+    let data = serde_json::to_vec_pretty(manifest).context(SerializeSnafu)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, &path).context(WriteSnafu { path })
+}
+
+/// Every manifest below `dir`. A missing directory has none.
+pub fn read_all(dir: &Path) -> Result<Vec<Manifest>, Error> {
+    use error::*;
+
+    let crate_dirs = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(ReadSnafu { path: dir }),
+    };
+
+    let mut manifests = vec![];
+    for crate_dir in crate_dirs {
+        let crate_dir = crate_dir.context(ReadSnafu { path: dir })?.path();
+        let entries = fs::read_dir(&crate_dir).context(ReadSnafu { path: &crate_dir })?;
+        for entry in entries {
+            let path = entry.context(ReadSnafu { path: &crate_dir })?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                let data = fs::read(&path).context(ReadSnafu { path: &path })?;
+                let manifest = serde_json::from_slice(&data).context(ParseSnafu { path })?;
+                manifests.push(manifest);
+            }
+        }
+    }
+
+    Ok(manifests)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("The erasure coding scheme is not usable"))]
+    Scheme { source: reed_solomon_erasure::Error },
+
+    #[snafu(display("Could not compute the shards"))]
+    Code { source: reed_solomon_erasure::Error },
+
+    #[snafu(display("The shards do not match the manifest"))]
+    Mismatch,
+
+    #[snafu(display("Only {have} of the {need} shards needed were found"))]
+    TooFew { have: usize, need: usize },
+
+    #[snafu(display("The rebuilt file does not match its checksum"))]
+    Checksum,
+
+    #[snafu(display("Could not read the shard manifests at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the shard manifest at {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the shard manifest"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write the shard manifest to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Scheme { .. } => "E_CONFIG_INVALID",
+            Self::Code { .. } | Self::Mismatch | Self::TooFew { .. } | Self::Checksum => {
+                "E_SHARDS_UNUSABLE"
+            }
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_SHARDS_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn any_data_shards_give_the_file_back() {
+        let data = (0..1000u32).flat_map(u32::to_le_bytes).collect::<Vec<_>>();
+        let vers = "1.0.0".parse().unwrap();
+        let scheme = Scheme {
+            data_shards: 4,
+            parity_shards: 2,
+        };
+
+        let (manifest, shards) = split("demo", &vers, &data, scheme).unwrap();
+        assert_eq!(6, manifest.shards.len());
+
+        let without = |lost: &[usize]| {
+            let shards = shards.iter().enumerate();
+            let shards = shards.map(|(i, s)| (!lost.contains(&i)).then(|| s.clone()));
+            join(&manifest, shards.collect())
+        };
+        assert_eq!(data, without(&[]).unwrap());
+        assert_eq!(data, without(&[0, 3]).unwrap());
+        assert_eq!(data, without(&[4, 5]).unwrap());
+        assert!(matches!(
+            without(&[0, 1, 2]),
+            Err(Error::TooFew { have: 3, need: 4 }),
+        ));
+
+        // A damaged shard counts as lost
+        let mut damaged = shards.iter().cloned().map(Some).collect::<Vec<_>>();
+        damaged[1].as_mut().unwrap()[0] ^= 1;
+        assert_eq!(data, join(&manifest, damaged.clone()).unwrap());
+        damaged[2] = None;
+        damaged[5] = None;
+        assert!(join(&manifest, damaged).is_err());
+    }
+
+    #[test]
+    fn manifests_are_found_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let vers = "1.0.0".parse().unwrap();
+        let scheme = Scheme {
+            data_shards: 2,
+            parity_shards: 1,
+        };
+
+        let (manifest, _) = split("Demo", &vers, b"tiny", scheme).unwrap();
+        write(dir, &manifest).unwrap();
+
+        assert!(manifest_path(dir, "demo", &vers).exists());
+        assert_eq!(vec![manifest], read_all(dir).unwrap());
+    }
+}
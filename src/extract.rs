@@ -0,0 +1,363 @@
+//! Unpacking `.crate` files, which hold whatever their publisher put
+//! in them.
+//!
+//! `tar`'s own unpacking keeps entries from climbing out with `..`, but
+//! still creates links and device files, and a symlink unpacked first
+//! can carry a later entry anywhere. `cargo package` writes nothing but
+//! regular files and directories, all below `{name}-{version}/`, so
+//! that is all this accepts: any other entry type, an absolute path, a
+//! `..`, or a path outside the package directory refuses the whole
+//! package. Files are then written a path component at a time below
+//! the output directory, which no entry could have changed.
+//!
+//! A few kilobytes of gzip can decompress to gigabytes, so entries are
+//! checked as they are decompressed, and a package with more entries
+//! or more bytes unpacked than its [`Limits`] is refused before all of
+//! it is read.
+
+use snafu::prelude::*;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_MAX_UNPACKED_BYTES: u64 = 512 * 1024 * 1024;
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// How much a package may unpack to, from `[limits]` in
+/// `margo-config.toml`.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The bytes of all its files together.
+    pub max_unpacked_bytes: u64,
+
+    /// Its files and directories.
+    pub max_entries: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_unpacked_bytes: DEFAULT_MAX_UNPACKED_BYTES,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+/// A regular file of a package.
+#[derive(Debug)]
+pub struct File {
+    /// Relative to the package directory, with `/` separators.
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+/// Reads every file of the package, whose entries must all be below
+/// `prefix`, the `{name}-{version}` directory.
+pub fn files(crate_data: &[u8], prefix: &str, limits: &Limits) -> Result<Vec<File>, Error> {
+    use error::*;
+
+    let mut files = vec![];
+    each_file(crate_data, prefix, limits, |path, entry| {
+        let mut data = vec![];
+        entry.read_to_end(&mut data).context(ReadSnafu)?;
+        files.push(File {
+            path: path.to_owned(),
+            data,
+        });
+        Ok(())
+    })?;
+
+    Ok(files)
+}
+
+/// Checks the package as [`files`] would, without keeping any of it.
+pub fn check(crate_data: &[u8], prefix: &str, limits: &Limits) -> Result<(), Error> {
+    use error::*;
+
+    each_file(crate_data, prefix, limits, |_, entry| {
+        io::copy(entry, &mut io::sink()).context(ReadSnafu)?;
+        Ok(())
+    })
+}
+
+/// Checks each entry of the package, handing each file to `f` with its
+/// path in the package directory once its size is within the limits.
+fn each_file(
+    crate_data: &[u8],
+    prefix: &str,
+    limits: &Limits,
+    mut f: impl FnMut(&str, &mut dyn Read) -> Result<(), Error>,
+) -> Result<(), Error> {
+    use error::*;
+
+    ensure!(is_plain(prefix), OutsideSnafu { path: prefix });
+
+    let gz = flate2::read::GzDecoder::new(crate_data);
+    let mut tar = tar::Archive::new(gz);
+
+    let mut entries = 0;
+    let mut unpacked = 0;
+    for entry in tar.entries().context(ReadSnafu)? {
+        let mut entry = entry.context(ReadSnafu)?;
+
+        entries += 1;
+        ensure!(
+            entries <= limits.max_entries,
+            TooManyEntriesSnafu {
+                max: limits.max_entries
+            }
+        );
+
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let entry_type = entry.header().entry_type();
+        if entry_type == tar::EntryType::XGlobalHeader {
+            continue;
+        }
+        ensure!(
+            entry_type.is_file() || entry_type.is_dir(),
+            EntryTypeSnafu {
+                path,
+                entry_type: format!("{entry_type:?}"),
+            }
+        );
+
+        let relative = package_path(&path, prefix).context(OutsideSnafu { path: &path })?;
+        if entry_type.is_dir() {
+            continue;
+        }
+        ensure!(!relative.is_empty(), OutsideSnafu { path: &path });
+
+        // An entry reads no further than its size, so that is all the
+        // file can hold
+        let max = limits.max_unpacked_bytes;
+        unpacked += entry.size();
+        ensure!(unpacked <= max, TooLargeSnafu { max });
+
+        f(relative, &mut entry)?;
+    }
+
+    Ok(())
+}
+
+/// The directory the package's first entry is in, which should be
+/// `{name}-{version}`. Nothing has checked the package yet, so a
+/// directory that is not a single plain path component, such as `..`
+/// or `C:`, refuses it.
+pub fn package_dir(crate_data: &[u8]) -> Result<String, Error> {
+    use error::*;
+
+    let gz = flate2::read::GzDecoder::new(crate_data);
+    let mut tar = tar::Archive::new(gz);
+
+    for entry in tar.entries().context(ReadSnafu)? {
+        let entry = entry.context(ReadSnafu)?;
+        if entry.header().entry_type() == tar::EntryType::XGlobalHeader {
+            continue;
+        }
+
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let dir = path.split('/').next().unwrap_or_default();
+        ensure!(is_plain(dir), OutsideSnafu { path: &path });
+        return Ok(dir.to_owned());
+    }
+
+    EmptySnafu.fail()
+}
+
+/// Writes the files below `out`, creating directories as needed.
+pub fn write(files: &[File], out: &Path) -> Result<(), Error> {
+    use error::*;
+
+    for file in files {
+        let path = file
+            .path
+            .split('/')
+            .fold(out.to_owned(), |path, s| path.join(s));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+        }
+        fs::write(&path, &file.data).context(WriteSnafu { path })?;
+    }
+
+    Ok(())
+}
+
+/// The path of an entry inside the package directory. `None` for
+/// anything that is not plainly below it. Directories end in `/`, and
+/// the package directory itself is allowed.
+fn package_path<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let relative = path.strip_prefix(prefix)?;
+    if relative.is_empty() || relative == "/" {
+        return Some("");
+    }
+
+    let relative = relative.strip_prefix('/')?;
+    let relative = relative.strip_suffix('/').unwrap_or(relative);
+
+    relative.split('/').all(is_plain).then_some(relative)
+}
+
+/// Whether `component` names something inside the directory it is
+/// joined to, on every platform.
+fn is_plain(component: &str) -> bool {
+    // `\` and `:` separate paths and drives on Windows
+    !component.is_empty()
+        && component != "."
+        && component != ".."
+        && !component.contains(['\\', ':', '\0'])
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the crate package"))]
+    Read { source: io::Error },
+
+    #[snafu(display("The crate package is empty"))]
+    Empty,
+
+    #[snafu(display("The crate package contains `{path}`, a {entry_type} entry"))]
+    EntryType { path: String, entry_type: String },
+
+    #[snafu(display("The crate package contains `{path}`, outside its package directory"))]
+    Outside { path: String },
+
+    #[snafu(display("The crate package unpacks to more than {max} bytes"))]
+    TooLarge { max: u64 },
+
+    #[snafu(display("The crate package has more than {max} entries"))]
+    TooManyEntries { max: usize },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } | Self::Empty | Self::EntryType { .. } | Self::Outside { .. } => {
+                "E_BAD_PACKAGE"
+            }
+            Self::TooLarge { .. } | Self::TooManyEntries { .. } => "E_PACKAGE_TOO_LARGE",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds a package from raw headers, as a malicious publisher
+    /// would, so that nothing checks the entries on the way in.
+    fn package(entries: &[(&str, tar::EntryType, &str)]) -> Vec<u8> {
+        let gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        let mut tar = tar::Builder::new(gz);
+
+        for &(path, entry_type, content) in entries {
+            let is_link = entry_type.is_symlink() || entry_type.is_hard_link();
+            let data = if entry_type.is_file() { content } else { "" };
+
+            let mut header = tar::Header::new_gnu();
+            let old = header.as_old_mut();
+            old.name[..path.len()].copy_from_slice(path.as_bytes());
+            if is_link {
+                old.linkname[..content.len()].copy_from_slice(content.as_bytes());
+            }
+            header.set_entry_type(entry_type);
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            tar.append(&header, data.as_bytes()).unwrap();
+        }
+
+        tar.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn only_files_below_the_package_directory_are_unpacked() {
+        use tar::EntryType as T;
+
+        let good = package(&[
+            ("demo-1.0.0/", T::Directory, ""),
+            ("demo-1.0.0/Cargo.toml", T::Regular, "[package]"),
+            ("demo-1.0.0/src/lib.rs", T::Regular, "pub fn demo() {}"),
+        ]);
+        assert_eq!("demo-1.0.0", package_dir(&good).unwrap());
+        let files = files(&good, "demo-1.0.0", &Limits::default()).unwrap();
+        let paths = files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(["Cargo.toml", "src/lib.rs"], *paths);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        write(&files, dir).unwrap();
+        assert_eq!(
+            "pub fn demo() {}",
+            fs::read_to_string(dir.join("src").join("lib.rs")).unwrap()
+        );
+
+        // Claims a directory outside of wherever it is unpacked
+        let outside = ["../evil", "./evil", "/etc/evil", "C:evil/x", "..\\evil"];
+        for first in outside {
+            let data = package(&[(first, T::Regular, "x"), ("../evil", T::Regular, "x")]);
+            let e = package_dir(&data).unwrap_err();
+            assert_eq!("E_BAD_PACKAGE", e.code(), "{first}");
+        }
+        let data = package(&[("../evil", T::Regular, "x")]);
+        let e = files(&data, "..", &Limits::default()).unwrap_err();
+        assert_eq!("E_BAD_PACKAGE", e.code());
+
+        let malicious = [
+            ("demo-1.0.0/../evil", T::Regular, "x"),
+            ("/etc/evil", T::Regular, "x"),
+            ("other-1.0.0/Cargo.toml", T::Regular, "x"),
+            ("demo-1.0.0evil/Cargo.toml", T::Regular, "x"),
+            ("demo-1.0.0/./Cargo.toml", T::Regular, "x"),
+            ("demo-1.0.0/..\\..\\evil", T::Regular, "x"),
+            ("demo-1.0.0/C:evil", T::Regular, "x"),
+            ("demo-1.0.0/link", T::Symlink, "/etc"),
+            ("demo-1.0.0/hard", T::Link, "/etc/passwd"),
+            ("demo-1.0.0/tty", T::Char, ""),
+            ("demo-1.0.0/disk", T::Block, ""),
+            ("demo-1.0.0/pipe", T::Fifo, ""),
+            ("demo-1.0.0", T::Regular, "x"),
+        ];
+        for entry in malicious {
+            let data = package(&[("demo-1.0.0/Cargo.toml", T::Regular, "[package]"), entry]);
+            let e = files(&data, "demo-1.0.0", &Limits::default()).unwrap_err();
+            assert_eq!("E_BAD_PACKAGE", e.code(), "{}", entry.0);
+        }
+    }
+
+    #[test]
+    fn packages_that_unpack_to_too_much_are_refused() {
+        use tar::EntryType as T;
+
+        let data = package(&[
+            ("demo-1.0.0/", T::Directory, ""),
+            ("demo-1.0.0/Cargo.toml", T::Regular, "[package]"),
+            ("demo-1.0.0/src/lib.rs", T::Regular, "pub fn demo() {}"),
+        ]);
+        let limits = Limits {
+            max_unpacked_bytes: 25,
+            max_entries: 3,
+        };
+        check(&data, "demo-1.0.0", &limits).unwrap();
+
+        let small = Limits {
+            max_unpacked_bytes: 24,
+            ..limits
+        };
+        let e = check(&data, "demo-1.0.0", &small).unwrap_err();
+        assert!(matches!(e, Error::TooLarge { max: 24 }), "{e}");
+
+        let few = Limits {
+            max_entries: 2,
+            ..limits
+        };
+        let e = files(&data, "demo-1.0.0", &few).unwrap_err();
+        assert!(matches!(e, Error::TooManyEntries { max: 2 }), "{e}");
+    }
+}
@@ -0,0 +1,102 @@
+//! The features each version offers.
+//!
+//! Features that use the newer syntax, `dep:` dependencies and weak
+//! `pkg?/feat` ones, are kept in the index entry's `features2`, as on
+//! crates.io, so that Cargos too old to parse them only skip those.
+//! [`all`] puts the two back together, with the feature Cargo makes
+//! for each optional dependency.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "server")]
+use std::collections::BTreeSet;
+
+#[cfg(feature = "server")]
+use crate::index_entry;
+
+/// What each feature enables.
+pub type Features = BTreeMap<String, Vec<String>>;
+
+/// Splits the features of a manifest into those older Cargos can read
+/// and those that use the newer syntax.
+pub fn split(features: Features) -> (Features, Features) {
+    features
+        .into_iter()
+        .partition(|(_, enables)| !enables.iter().any(|e| uses_newer_syntax(e)))
+}
+
+fn uses_newer_syntax(enable: &str) -> bool {
+    enable.starts_with("dep:") || enable.contains("?/")
+}
+
+/// Every feature of the version. An optional dependency is also a
+/// feature of the same name, unless some feature enables it as
+/// `dep:`.
+#[cfg(feature = "server")]
+pub fn all(entry: &index_entry::Root) -> Features {
+    let mut all = entry.features.clone();
+    for (name, enables) in &entry.features2 {
+        all.entry(name.clone())
+            .or_default()
+            .extend(enables.iter().cloned());
+    }
+
+    let explicit = all
+        .values()
+        .flatten()
+        .filter_map(|e| e.strip_prefix("dep:"))
+        .map(str::to_owned)
+        .collect::<BTreeSet<_>>();
+    for dep in entry.deps.iter().filter(|d| d.optional) {
+        if !explicit.contains(&dep.name) {
+            all.entry(dep.name.clone())
+                .or_insert_with(|| vec![format!("dep:{}", dep.name)]);
+        }
+    }
+
+    all
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn optional_dependencies_are_features_unless_named_with_dep() {
+        let manifest = serde_json::from_value::<Features>(serde_json::json!({
+            "default": ["std"],
+            "std": ["serde?/std"],
+            "json": ["dep:serde_json"],
+        }))
+        .unwrap();
+        let (features, features2) = split(manifest);
+        assert_eq!(["default"], *features.keys().collect::<Vec<_>>());
+        assert_eq!(["json", "std"], *features2.keys().collect::<Vec<_>>());
+
+        #[cfg(feature = "server")]
+        {
+            let entry = serde_json::from_value::<index_entry::Root>(serde_json::json!({
+                "name": "demo",
+                "vers": "1.0.0",
+                "deps": [
+                    { "name": "serde", "req": "^1", "features": [], "optional": true,
+                      "default_features": true, "target": null, "kind": "normal" },
+                    { "name": "serde_json", "req": "^1", "features": [], "optional": true,
+                      "default_features": true, "target": null, "kind": "normal" },
+                ],
+                "cksum": "",
+                "features": features,
+                "features2": features2,
+                "yanked": false,
+                "v": 2,
+            }))
+            .unwrap();
+
+            let all = all(&entry);
+            assert_eq!(
+                ["default", "json", "serde", "std"],
+                *all.keys().collect::<Vec<_>>()
+            );
+            assert_eq!(["dep:serde"], *all["serde"]);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! An Atom feed of newly added crate versions, built from the audit
+//! log.
+
+use snafu::prelude::*;
+use std::{fmt::Write as _, fs, io, path::PathBuf};
+
+use crate::{audit, visibility, Registry};
+
+pub const FILE_NAME: &str = "feed.xml";
+
+/// How many of the most recent versions are included.
+const MAX_ENTRIES: usize = 50;
+
+pub fn write(registry: &Registry) -> Result<(), Error> {
+    use error::*;
+
+    let feed = rendered(registry)?;
+
+    let path = registry.path.join(FILE_NAME);
+    fs::write(&path, feed).context(WriteSnafu { path })?;
+
+    Ok(())
+}
+
+/// The feed as [`write`] would write it.
+pub fn rendered(registry: &Registry) -> Result<String, Error> {
+    let log = registry.audit_log()?;
+    let levels = registry.visibility()?;
+    Ok(render(registry, &log, &levels))
+}
+
+/// Crates that are not public are left out.
+fn render(registry: &Registry, log: &[audit::Entry], levels: &visibility::Levels) -> String {
+    let base_url = registry.config.base_url.as_str();
+    let registry_name = registry.config.html.suggested_registry_name();
+
+    let added = log
+        .iter()
+        .rev()
+        .filter_map(|e| match &e.event {
+            audit::Event::Add { name, vers, cksum } if levels.is_public(name.as_str()) => {
+                Some((e.time, name, vers, cksum))
+            }
+            _ => None,
+        })
+        .take(MAX_ENTRIES)
+        .collect::<Vec<_>>();
+
+    let updated = added.first().map(|(time, ..)| *time).unwrap_or_default();
+
+    let title = escape(registry_name);
+    let base_url = escape(base_url);
+
+    let mut feed = String::new();
+
+    // Writing to a `String` cannot fail.
+    _ = writeln!(feed, r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    _ = writeln!(feed, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+    _ = writeln!(feed, "  <title>{title} releases</title>");
+    _ = writeln!(feed, "  <id>{base_url}{FILE_NAME}</id>");
+    _ = writeln!(feed, r#"  <link rel="self" href="{base_url}{FILE_NAME}"/>"#);
+    _ = writeln!(feed, r#"  <link href="{base_url}"/>"#);
+    _ = writeln!(feed, "  <author><name>{title}</name></author>");
+    _ = writeln!(feed, "  <updated>{updated}</updated>");
+
+    for (time, name, vers, cksum) in added {
+        let vers = escape(&vers.to_string());
+        let cksum = escape(cksum);
+
+        _ = writeln!(feed, "  <entry>");
+        _ = writeln!(feed, "    <title>{name} {vers}</title>");
+        _ = writeln!(feed, "    <id>urn:sha256:{cksum}</id>");
+        _ = writeln!(feed, r#"    <link href="{base_url}"/>"#);
+        _ = writeln!(feed, "    <updated>{time}</updated>");
+        _ = writeln!(
+            feed,
+            r#"    <content type="text">cargo add --registry {title} {name}@{vers}</content>"#
+        );
+        _ = writeln!(feed, "  </entry>");
+    }
+
+    feed.push_str("</feed>\n");
+
+    feed
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(transparent)]
+    Audit { source: audit::Error },
+
+    #[snafu(transparent)]
+    Visibility { source: visibility::Error },
+
+    #[snafu(display("Could not write the feed to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Audit { source } => source.code(),
+            Self::Visibility { source } => source.code(),
+            Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
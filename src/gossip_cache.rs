@@ -0,0 +1,177 @@
+//! Gossiped announcements, kept so peers that were offline can catch up.
+//!
+//! Gossipsub only delivers a message to the peers connected when it is
+//! published. Every node keeps the announcements it has published or
+//! received in `gossip-cache.json`, keyed by their gossipsub message ID.
+//! On connecting to a peer, a node asks for the announcements the peer
+//! received since the newest one in its own cache, and adds those it has
+//! not seen. Entries are dropped after a week, or once there are too
+//! many.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const FILE_NAME: &str = "gossip-cache.json";
+
+const MAX_ENTRIES: usize = 1000;
+
+const MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Peers' clocks differ, so a replay starts this much before the newest
+/// cached announcement. Anything seen twice is recognized by its ID.
+const REPLAY_MARGIN_SECS: u64 = 5 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cached {
+    /// The gossipsub message ID, the same on every node.
+    pub id: String,
+
+    /// The peer ID that published it.
+    pub from: String,
+
+    pub message: String,
+
+    /// Seconds since the Unix epoch, by this node's clock.
+    pub received_at: u64,
+}
+
+/// Oldest announcement first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    entries: VecDeque<Cached>,
+}
+
+impl Cache {
+    /// A missing file is an empty cache.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        use error::*;
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).context(ReadSnafu { path }),
+        };
+
+        serde_json::from_slice(&data).context(ParseSnafu { path })
+    }
+
+    /// Written to a temporary file first, so an interruption never
+    /// leaves a truncated cache behind.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        use error::*;
+
+        let data = serde_json::to_vec_pretty(self).context(SerializeSnafu)?;
+
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+        fs::rename(&tmp, path).context(WriteSnafu { path })
+    }
+
+    /// Returns `false` if the announcement was already cached.
+    pub fn insert(&mut self, announcement: Cached, now: u64) -> bool {
+        if self.contains(&announcement.id) {
+            return false;
+        }
+
+        self.entries.push_back(announcement);
+
+        let oldest_kept = now.saturating_sub(MAX_AGE_SECS);
+        self.entries.retain(|c| c.received_at >= oldest_kept);
+        let excess = self.entries.len().saturating_sub(MAX_ENTRIES);
+        self.entries.drain(..excess);
+
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.entries.iter().any(|c| c.id == id)
+    }
+
+    /// The time to ask peers for announcements since.
+    pub fn replay_from(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|c| c.received_at)
+            .max()
+            .map_or(0, |newest| newest.saturating_sub(REPLAY_MARGIN_SECS))
+    }
+
+    pub fn since(&self, since: u64) -> Vec<Cached> {
+        self.entries
+            .iter()
+            .filter(|c| c.received_at >= since)
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the gossip cache at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the gossip cache at {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the gossip cache"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write the gossip cache to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_GOSSIP_CACHE_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cached(id: &str, received_at: u64) -> Cached {
+        Cached {
+            id: id.to_owned(),
+            from: "peer".to_owned(),
+            message: "0123abcd".to_owned(),
+            received_at,
+        }
+    }
+
+    #[test]
+    fn announcements_are_replayed_once() {
+        let now = 10 * MAX_AGE_SECS;
+        let mut cache = Cache::default();
+        assert_eq!(0, cache.replay_from());
+
+        assert!(cache.insert(cached("a", now - MAX_AGE_SECS - 1), now));
+        assert!(cache.insert(cached("b", now - 60), now));
+        assert!(!cache.insert(cached("b", now), now));
+        assert!(cache.insert(cached("c", now), now));
+
+        // `a` is too old to keep
+        let ids = |c: Vec<Cached>| c.into_iter().map(|c| c.id).collect::<Vec<_>>();
+        assert_eq!(vec!["b", "c"], ids(cache.since(0)));
+        assert_eq!(vec!["c"], ids(cache.since(now)));
+        assert_eq!(now - REPLAY_MARGIN_SECS, cache.replay_from());
+
+        for i in 0..MAX_ENTRIES {
+            cache.insert(cached(&i.to_string(), now), now);
+        }
+        assert_eq!(MAX_ENTRIES, cache.since(0).len());
+    }
+}
@@ -4,7 +4,10 @@ use semver::Version;
 use snafu::prelude::*;
 use std::{fs, io, path::PathBuf};
 
-use crate::{index_entry, ConfigV1, Index, ListAll, Registry};
+use crate::{common::CrateName, index_entry, newest_version, readme, Index, ListAll, Registry};
+
+#[cfg(feature = "server")]
+use crate::{features, status::Status};
 
 #[rustfmt::skip]
 mod assets;
@@ -12,8 +15,7 @@ mod assets;
 pub fn write(registry: &Registry) -> Result<(), Error> {
     use error::*;
 
-    let crates = registry.list_all()?;
-    let index = index(&registry.config, &crates).into_string();
+    let index = rendered_index(registry)?;
     let index_path = registry.path.join("index.html");
     fs::write(&index_path, index).context(WriteIndexSnafu { path: index_path })?;
 
@@ -45,6 +47,15 @@ pub fn write(registry: &Registry) -> Result<(), Error> {
     Ok(())
 }
 
+/// `index.html` as [`write`] would write it.
+pub fn rendered_index(registry: &Registry) -> Result<String, Error> {
+    let levels = registry.visibility()?;
+    let mut crates = registry.list_all()?;
+    crates.retain(|name, _| levels.is_public(name.as_str()));
+
+    Ok(index(registry, &crates).into_string())
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(module)]
 pub enum Error {
@@ -52,6 +63,10 @@ pub enum Error {
     #[snafu(context(false))]
     ListAll { source: crate::ListAllError },
 
+    #[snafu(display("Could not read the crate visibility"))]
+    #[snafu(context(false))]
+    Visibility { source: crate::visibility::Error },
+
     #[snafu(display("Could not write the HTML index page to {}", path.display()))]
     WriteIndex { source: io::Error, path: PathBuf },
 
@@ -71,63 +86,72 @@ pub enum Error {
     JsMap { source: io::Error, path: PathBuf },
 }
 
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ListAll { source } => source.code(),
+            Self::Visibility { source } => source.code(),
+            Self::WriteIndex { .. }
+            | Self::AssetDir { .. }
+            | Self::Css { .. }
+            | Self::CssMap { .. }
+            | Self::Js { .. }
+            | Self::JsMap { .. } => "E_HTML_WRITE",
+        }
+    }
+}
+
 const CARGO_DOCS: &str =
     "https://doc.rust-lang.org/cargo/reference/registries.html#using-an-alternate-registry";
 
-fn index(config: &ConfigV1, crates: &ListAll) -> Markup {
-    let base_url = &config.base_url;
-    let suggested_name = config.html.suggested_registry_name();
-
-    let asset_head_elements = PreEscaped(assets::INDEX);
-
-    fn link(href: &str, content: &str) -> Markup {
-        html! {
-            a href=(href) class="underline text-blue-600 hover:text-blue-800 visited:text-purple-600" {
-                (content)
-            }
+fn link(href: &str, content: &str) -> Markup {
+    html! {
+        a href=(href) class="underline text-blue-600 hover:text-blue-800 visited:text-purple-600" {
+            (content)
         }
     }
+}
 
-    fn section(name: &str, id: &str, content: Markup) -> Markup {
-        html! {
-            section class="p-1" {
-                h1 class="text-2xl" {
-                    a class="hover:after:content-['_§']" id=(id) href={"#" (id)} {
-                        (name)
-                    }
+fn section(name: &str, id: &str, content: Markup) -> Markup {
+    html! {
+        section class="p-1" {
+            h1 class="text-2xl" {
+                a class="hover:after:content-['_§']" id=(id) href={"#" (id)} {
+                    (name)
                 }
-
-                (content)
             }
+
+            (content)
         }
     }
+}
 
-    fn code_block(content: impl AsRef<str>) -> Markup {
-        let content = content.as_ref();
+fn code_block(content: impl AsRef<str>) -> Markup {
+    let content = content.as_ref();
 
-        let span_class = "col-start-1 row-start-1 leading-none p-1";
+    let span_class = "col-start-1 row-start-1 leading-none p-1";
 
-        html! {
-            mg-copy {
-                pre class="relative border border-black bg-theme-rose-light m-1 p-1 overflow-x-auto" {
-                    button class="hidden absolute top-0 right-0 grid" data-target="copy" {
-                        span class=(span_class) data-target="state0" { "Copy" }
-                        span class={(span_class) " invisible"} data-target="state1" { "Copied" }
-                    }
-                    code data-target="content" { (content) }
+    html! {
+        mg-copy {
+            pre class="relative border border-black bg-theme-rose-light m-1 p-1 overflow-x-auto" {
+                button class="hidden absolute top-0 right-0 grid" data-target="copy" {
+                    span class=(span_class) data-target="state0" { "Copy" }
+                    span class={(span_class) " invisible"} data-target="state1" { "Copied" }
                 }
+                code data-target="content" { (content) }
             }
         }
     }
+}
 
-    let config_stanza = formatdoc! {r#"
-        [registries]
-        {suggested_name} = {{ index = "sparse+{base_url}" }}
-    "#};
-
-    let cargo_add_stanza = formatdoc! {"
-        cargo add --registry {suggested_name} some-crate-name
-    "};
+/// `root` is the path from the page to the registry root, where the
+/// assets live; pages at the root pass an empty string.
+fn page(root: &str, content: Markup) -> Markup {
+    let asset_head_elements = if root.is_empty() {
+        PreEscaped(assets::INDEX.to_owned())
+    } else {
+        PreEscaped(assets::INDEX.replace("assets/", &format!("{root}assets/")))
+    };
 
     html! {
         (DOCTYPE)
@@ -146,70 +170,336 @@ fn index(config: &ConfigV1, crates: &ListAll) -> Markup {
                     }
                 }
 
-                (section("Getting started", "getting-started", html! {
-                    ol class="list-inside list-decimal" {
-                        li {
-                            "Add the registry definition to your "
-                            code { ".cargo/config.toml" }
-                            ":"
+                (content)
 
-                            (code_block(config_stanza))
+                footer class="grow place-content-end text-center" {
+                    span class="border-t border-dashed border-theme-purple" {
+                        "Powered by "
+                        (link("https://github.com/integer32llc/margo", "Margo"))
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn index(registry: &Registry, crates: &ListAll) -> Markup {
+    let config = &registry.config;
+    let base_url = &config.base_url;
+    let suggested_name = config.html.suggested_registry_name();
+
+    let config_stanza = formatdoc! {r#"
+        [registries]
+        {suggested_name} = {{ index = "sparse+{base_url}" }}
+    "#};
+
+    let cargo_add_stanza = formatdoc! {"
+        cargo add --registry {suggested_name} some-crate-name
+    "};
+
+    let content = html! {
+        (section("Getting started", "getting-started", html! {
+            ol class="list-inside list-decimal" {
+                li {
+                    "Add the registry definition to your "
+                    code { ".cargo/config.toml" }
+                    ":"
+
+                    (code_block(config_stanza))
+                }
+
+                li {
+                    "Add your dependency to your project:"
+
+                    (code_block(cargo_add_stanza))
+                }
+            }
+
+            "For complete details, check the "
+            (link(CARGO_DOCS, "Cargo documentation"))
+            "."
+        }))
+
+        (section("Available crates", "crates", html! {
+            table class="table-fixed w-full" {
+                thead {
+                    tr {
+                        th class="w-4/5 text-left" { "Name" }
+                        th { "Versions" }
+                    }
+                }
+
+                tbody {
+                    @for (c, v) in crates {
+                        tr class="hover:bg-theme-orange" {
+                            td {
+                                @let readme_href = newest_version(v).and_then(|nv| registry.readme_href_for(c, nv));
+                                span class="truncate" {
+                                    @if let Some(href) = readme_href {
+                                        (link(&href, c.as_str()))
+                                    } @else {
+                                        (c.as_str())
+                                    }
+                                }
+                            }
+                            td {
+                                select class="w-full bg-white" name="version" {
+                                    @for (v, c, select) in most_interesting(v) {
+                                        @let suffix = version_suffix(c);
+                                        option selected[select] { (v) (suffix) }
+                                    }
+                                }
+                            }
                         }
+                    }
+                }
+            }
+        }))
+    };
+
+    page("", content)
+}
 
-                        li {
-                            "Add your dependency to your project:"
+/// The front page of the `serve` dashboard.
+#[cfg(feature = "server")]
+pub fn dashboard(root: &str, crates: &ListAll, status: &Status) -> Markup {
+    let content = html! {
+        (section("Crates", "crates", html! {
+            table class="table-fixed w-full" {
+                thead {
+                    tr {
+                        th class="w-3/5 text-left" { "Name" }
+                        th { "Newest" }
+                        th { "Versions" }
+                        th { "Downloads" }
+                    }
+                }
 
-                            (code_block(cargo_add_stanza))
+                tbody {
+                    @for (name, index) in crates {
+                        tr class="hover:bg-theme-orange" {
+                            td class="truncate" {
+                                (link(&format!("{root}ui/crates/{name}"), name.as_str()))
+                            }
+                            td class="text-center" {
+                                @if let Some(v) = newest_version(index) { (v) } @else { "-" }
+                            }
+                            td class="text-center" { (index.len()) }
+                            td class="text-center" { (status.downloads_of(name.as_str())) }
+                        }
+                    }
+                }
+            }
+        }))
+
+        (section("Connected peers", "peers", html! {
+            @if status.peers.is_empty() {
+                p { "No peers are connected." }
+            } @else {
+                table class="table-fixed w-full" {
+                    thead {
+                        tr {
+                            th class="w-1/2 text-left" { "Peer" }
+                            th class="text-left" { "Address" }
+                            th class="text-left" { "Agent" }
                         }
                     }
 
-                    "For complete details, check the "
-                    (link(CARGO_DOCS, "Cargo documentation"))
-                    "."
-                }))
+                    tbody {
+                        @for (id, peer) in &status.peers {
+                            tr class="hover:bg-theme-orange" {
+                                td class="truncate" { code { (id) } }
+                                td class="truncate" { (peer.address.as_deref().unwrap_or("")) }
+                                td class="truncate" { (peer.agent.as_deref().unwrap_or("")) }
+                            }
+                        }
+                    }
+                }
+            }
+        }))
+
+        (section("Recent announcements", "announcements", html! {
+            @if status.announcements.is_empty() {
+                p { "No announcements have been received." }
+            } @else {
+                ul class="list-inside list-disc" {
+                    @for a in &status.announcements {
+                        li class="truncate" {
+                            code { (a.from) }
+                            ": "
+                            (a.message)
+                        }
+                    }
+                }
+            }
+        }))
+    };
+
+    page(root, content)
+}
+
+/// The page for a single crate in the `serve` dashboard.
+#[cfg(feature = "server")]
+pub fn dashboard_crate(
+    root: &str,
+    registry: &Registry,
+    name: &CrateName,
+    index: &Index,
+    status: &Status,
+) -> Markup {
+    let content = html! {
+        (section(name.as_str(), "versions", html! {
+            table class="table-fixed w-full" {
+                thead {
+                    tr {
+                        th class="w-1/5 text-left" { "Version" }
+                        th class="w-1/5" { "Downloads" }
+                        th class="w-1/5" { "README" }
+                        th class="w-1/5" { "Docs" }
+                        th class="text-left" { "Checksum" }
+                    }
+                }
+
+                tbody {
+                    @for (version, entry) in index.iter().rev() {
+                        @let suffix = version_suffix(entry);
+                        tr class="hover:bg-theme-orange" {
+                            td { (version) (suffix) }
+                            td class="text-center" {
+                                (status.downloads_of_version(name.as_str(), &version.to_string()))
+                            }
+                            td class="text-center" {
+                                @if let Some(href) = registry.readme_href_for(name, version) {
+                                    (link(&format!("{root}{href}"), "Read"))
+                                } @else {
+                                    "-"
+                                }
+                            }
+                            td class="text-center" {
+                                @if let Some(url) = registry.docs_url_for(name, version) {
+                                    (link(url.as_str(), "Browse"))
+                                } @else {
+                                    "-"
+                                }
+                            }
+                            td class="truncate" { code { (entry.cksum) } }
+                        }
+                    }
+                }
+            }
+
+            (link(&format!("{root}ui"), "All crates"))
+        }))
 
-                (section("Available crates", "crates", html! {
+        @if let Some(entry) = newest_version(index).and_then(|v| index.get(v)) {
+            @let features = features::all(entry);
+            @let default = features.get("default").map_or(&[][..], |d| &d[..]);
+            @if !features.is_empty() {
+                (section(&format!("Features of {}", entry.vers), "features", html! {
                     table class="table-fixed w-full" {
                         thead {
                             tr {
-                                th class="w-4/5 text-left" { "Name" }
-                                th { "Versions" }
+                                th class="w-1/4 text-left" { "Feature" }
+                                th class="text-left" { "Enables" }
                             }
                         }
 
                         tbody {
-                            @for (c, v) in crates {
+                            @for (feature, enables) in &features {
                                 tr class="hover:bg-theme-orange" {
                                     td {
-                                        span class="truncate" { (c.as_str()) }
-                                    }
-                                    td {
-                                        select class="w-full bg-white" name="version" {
-                                            @for (v, c, select) in most_interesting(v) {
-                                                @let suffix = if c.yanked { " (yanked)" } else { "" };
-                                                option selected[select] { (v) (suffix) }
-                                            }
-                                        }
+                                        code { (feature) }
+                                        @if default.contains(feature) { " (default)" }
                                     }
+                                    td { code { (enables.join(", ")) } }
                                 }
                             }
                         }
                     }
                 }))
+            }
+        }
+    };
 
-                footer class="grow place-content-end text-center" {
-                    span class="border-t border-dashed border-theme-purple" {
-                        "Powered by "
-                        (link("https://github.com/integer32llc/margo", "Margo"))
-                    }
-                }
+    page(root, content)
+}
+
+/// Where the API documentation loads Swagger UI from, pinned to a
+/// version.
+#[cfg(feature = "openapi")]
+const SWAGGER_UI_URL: &str = "https://unpkg.com/swagger-ui-dist@5.17.14";
+
+/// Swagger UI showing the OpenAPI document at `spec_url`, relative to
+/// the page.
+#[cfg(feature = "openapi")]
+pub fn swagger_ui(spec_url: &str) -> Markup {
+    let spec_url = serde_json::Value::from(spec_url);
+    let init = format!(
+        r##"window.onload = () => {{ window.ui = SwaggerUIBundle({{ url: {spec_url}, dom_id: "#swagger-ui" }}); }};"##
+    );
+
+    html! {
+        (DOCTYPE)
+        html lang="en-US" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { "Margo API" };
+                link rel="stylesheet" href=(format!("{SWAGGER_UI_URL}/swagger-ui.css"));
+            }
+
+            body {
+                div id="swagger-ui" {}
+                script src=(format!("{SWAGGER_UI_URL}/swagger-ui-bundle.js")) {}
+                script { (PreEscaped(init)) }
             }
         }
     }
 }
 
+/// A README rendered by [`readme::write`].
+pub fn readme(
+    root: &str,
+    name: &CrateName,
+    version: &Version,
+    metadata: &readme::Metadata,
+    rendered: &str,
+) -> Markup {
+    let title = format!("{name} {version}");
+
+    let content = html! {
+        (section(&title, "readme", html! {
+            @if let Some(description) = &metadata.description {
+                p class="italic" { (description) }
+            }
+
+            ul class="list-inside list-disc" {
+                @for (label, url) in metadata.links() {
+                    li { (label) ": " (link(url, url)) }
+                }
+            }
+
+            article class="p-1" {
+                (PreEscaped(rendered))
+            }
+        }))
+    };
+
+    page(root, content)
+}
+
+fn version_suffix(entry: &index_entry::Root) -> &'static str {
+    if entry.withheld {
+        " (withheld)"
+    } else if entry.yanked {
+        " (yanked)"
+    } else {
+        ""
+    }
+}
+
 fn most_interesting(i: &Index) -> impl Iterator<Item = (&Version, &index_entry::Root, bool)> {
-    let last_non_yanked = i.iter().rfind(|(_, c)| !c.yanked).map(|(v, _)| v);
+    let last_non_yanked = newest_version(i);
 
     i.iter()
         .map(move |(v, c)| (v, c, Some(v) == last_non_yanked))
@@ -0,0 +1,139 @@
+//! Reading index files with as little copying as possible.
+//!
+//! A server reads every index file when it starts, and for each crate
+//! listing and search until it has them all, which on a registry of
+//! tens of thousands of crates is a lot of reading. Each file is read whole, or with the `mmap` feature mapped
+//! into memory, and entries are parsed straight from its bytes rather
+//! than copied into a `String` a line at a time. Listings that only
+//! need each crate's newest version parse just the few fields of each
+//! entry that say so, skipping dependencies and features.
+//!
+//! A mapping stays valid only while nobody truncates the file, so index
+//! files are always replaced by renaming a new file over them, which
+//! leaves existing mappings of the old file as they were.
+
+use semver::Version;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{self, Read},
+    ops::Deref,
+    path::Path,
+};
+
+use crate::common::CrateName;
+
+/// The contents of an index file.
+#[derive(Debug)]
+pub enum Data {
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+    Read(Vec<u8>),
+}
+
+impl Deref for Data {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(feature = "mmap")]
+            Self::Mapped(map) => map,
+            Self::Read(data) => data,
+        }
+    }
+}
+
+/// Maps or reads the index file. `None` when there is none.
+pub fn open(path: &Path) -> io::Result<Option<Data>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    // Empty files cannot be mapped
+    #[cfg(feature = "mmap")]
+    if file.metadata()?.len() > 0 {
+        // SAFETY: margo never changes an index file in place; it
+        // renames a new one over it. Anything else editing the index
+        // while the daemon runs could make reads fail.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        return Ok(Some(Data::Mapped(map)));
+    }
+
+    let mut data = vec![];
+    (&file).read_to_end(&mut data)?;
+    Ok(Some(Data::Read(data)))
+}
+
+pub use margo_client::index_lines as lines;
+
+/// The fields of an index entry that listings need. The others are
+/// skipped when parsing.
+#[derive(Debug, Deserialize)]
+pub struct Head {
+    pub name: CrateName,
+    pub vers: Version,
+    pub yanked: bool,
+}
+
+/// What listings show of a crate.
+#[derive(Debug, Default, Clone)]
+pub struct Summary {
+    pub versions: usize,
+
+    /// The greatest version that is not yanked.
+    pub newest_version: Option<Version>,
+}
+
+impl Summary {
+    pub fn add(&mut self, head: Head) {
+        self.versions += 1;
+        let newer = match &self.newest_version {
+            Some(newest) => head.vers > *newest,
+            None => true,
+        };
+        if !head.yanked && newer {
+            self.newest_version = Some(head.vers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summaries_skip_yanked_versions() {
+        let data = concat!(
+            r#"{"name":"demo","vers":"1.0.0","deps":[],"cksum":"aa","features":{},"yanked":false}"#,
+            "\n",
+            r#"{"name":"demo","vers":"1.1.0","deps":[],"cksum":"bb","features":{},"yanked":true}"#,
+            "\n",
+        );
+
+        let mut summary = Summary::default();
+        for (_, line) in lines(data.as_bytes()) {
+            summary.add(serde_json::from_slice(line).unwrap());
+        }
+
+        assert_eq!(2, summary.versions);
+        assert_eq!(Some(Version::new(1, 0, 0)), summary.newest_version);
+    }
+
+    #[test]
+    fn files_are_read_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        assert!(open(&dir.join("missing")).unwrap().is_none());
+
+        let path = dir.join("demo");
+        std::fs::write(&path, b"{}\n").unwrap();
+        assert_eq!(b"{}\n", &*open(&path).unwrap().unwrap());
+
+        let empty = dir.join("empty");
+        std::fs::write(&empty, b"").unwrap();
+        assert!(open(&empty).unwrap().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+//! Locks on the index, one per shard of crate names.
+//!
+//! Publishes, yanks and the other changes the daemon makes to a crate
+//! each take the lock of the crate's shard for as long as they check
+//! and change it, so changes to one crate happen one after another, in
+//! the order they took the lock, while changes to crates in other
+//! shards go on at the same time. A crate's shard follows from the
+//! first four characters of its name as Cargo compares names, which is
+//! the index directory its file is in, so every spelling of a name
+//! shares a lock.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{common::CrateName, names::normalize};
+
+/// Enough that unrelated publishes rarely wait for each other.
+const SHARDS: usize = 64;
+
+#[derive(Debug)]
+pub struct IndexLocks {
+    shards: [Mutex<()>; SHARDS],
+}
+
+impl Default for IndexLocks {
+    fn default() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| Mutex::new(())),
+        }
+    }
+}
+
+impl IndexLocks {
+    /// Holds the crate's shard locked until the guard is dropped.
+    pub fn lock(&self, name: &CrateName) -> MutexGuard<'_, ()> {
+        self.shards[shard_of(name)]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn shard_of(name: &CrateName) -> usize {
+    let prefix = normalize(name.as_str()).chars().take(4).collect::<String>();
+
+    let mut hasher = DefaultHasher::new();
+    prefix.hash(&mut hasher);
+    (hasher.finish() % SHARDS as u64) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn shard(name: &str) -> usize {
+        shard_of(&name.parse().unwrap())
+    }
+
+    #[test]
+    fn spellings_of_a_name_share_a_shard() {
+        assert_eq!(shard("serde_json"), shard("Serde-JSON"));
+        assert_eq!(shard("a_b"), shard("a-b"));
+    }
+
+    #[test]
+    fn crates_in_other_shards_are_not_held_up() {
+        let locks = IndexLocks::default();
+        let (a, b) = ("serde".parse().unwrap(), "tokio".parse().unwrap());
+        assert_ne!(shard_of(&a), shard_of(&b));
+
+        let _a = locks.lock(&a);
+        assert!(locks.shards[shard_of(&b)].try_lock().is_ok());
+        assert!(locks.shards[shard_of(&a)].try_lock().is_err());
+    }
+}
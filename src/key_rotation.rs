@@ -0,0 +1,304 @@
+//! Replacing the operator's nostr key without losing consumers' trust.
+//!
+//! `margo key rotate` generates a new key and has the old key sign a
+//! statement naming it: a NIP-78 event, like an
+//! [announcement](crate::announce), tagged [`TAG`] and carrying a
+//! `key-rotation` [payload](crate::payload). The statement is published
+//! to relays and appended to `key-rotations.json`, which is served next
+//! to the index's `config.json`. A running daemon is then told to sign
+//! with the new key, and announces the index's current Merkle root
+//! signed by it.
+//!
+//! A consumer that trusts the old key extends its trust to the new one
+//! by [following](follow) the statements, as `margo registry-directory`
+//! does for its trusted public keys. The old key is kept beside the new
+//! one, with an `.old` suffix.
+//!
+//! When the registry configures a [quorum](crate::quorum), the rotation
+//! needs its approval first.
+
+use nostr::{Event, EventBuilder, Keys, Kind, PublicKey, Tag, TagStandard};
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+use crate::{
+    announce, audit,
+    payload::{Body, Payload},
+    quorum, signer, Registry,
+};
+
+pub const FILE_NAME: &str = "key-rotations.json";
+
+/// The hashtag that marks an application-data event as a key rotation
+/// statement.
+pub const TAG: &str = "gnostr-registry-key-rotation";
+
+/// The content of a rotation statement.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rotation {
+    pub base_url: Url,
+
+    /// The key that signed the statement, hex encoded.
+    pub old_pubkey: String,
+
+    /// The key that replaces it, hex encoded.
+    pub new_pubkey: String,
+}
+
+#[derive(Debug)]
+pub struct Rotated {
+    pub statement: Event,
+    pub rotation: Rotation,
+}
+
+/// Replaces the key at `key_path` with a new one and records the
+/// statement in the registry. The statement is not published.
+pub fn rotate(registry: &Registry, key_path: &Path) -> Result<Rotated, Error> {
+    use error::*;
+
+    let old = signer::read_keys(key_path).context(KeySnafu)?;
+    let operation = quorum::Operation::RotateKey {
+        old_pubkey: old.public_key().to_hex(),
+    };
+    let grant = quorum::require(registry, &operation).context(QuorumSnafu)?;
+    let new = Keys::generate();
+
+    let statement = statement(&old, &new.public_key(), &registry.config.base_url)?;
+    let rotation = parse(&statement).context(InvalidSnafu)?;
+
+    // The new key is written in full before anything refers to it
+    let tmp = key_path.with_extension("tmp");
+    write_key(&tmp, &new)?;
+
+    let mut statements = read(registry)?;
+    statements.push(statement.clone());
+    write(registry, &statements)?;
+
+    let backup = backup_path(key_path);
+    fs::rename(key_path, &backup).context(WriteSnafu { path: backup })?;
+    fs::rename(&tmp, key_path).context(WriteSnafu { path: key_path })?;
+
+    registry
+        .record(audit::Event::RotateKey {
+            old_pubkey: rotation.old_pubkey.clone(),
+            new_pubkey: rotation.new_pubkey.clone(),
+        })
+        .context(AuditSnafu)?;
+    grant.spend(registry).context(QuorumSnafu)?;
+
+    Ok(Rotated {
+        statement,
+        rotation,
+    })
+}
+
+/// `old` signs that `new` replaces it as the key of the registry at
+/// `base_url`.
+pub fn statement(old: &Keys, new: &PublicKey, base_url: &Url) -> Result<Event, Error> {
+    use error::*;
+
+    let rotation = Rotation {
+        base_url: base_url.clone(),
+        old_pubkey: old.public_key().to_hex(),
+        new_pubkey: new.to_hex(),
+    };
+    let payload = Payload::new(Body::KeyRotation(rotation));
+    let content = serde_json::to_string(&payload).context(SerializeSnafu)?;
+
+    let tags = [
+        Tag::identifier(format!("{TAG}:{base_url}")),
+        Tag::hashtag(TAG),
+        Tag::public_key(*new),
+    ];
+
+    EventBuilder::new(Kind::from(announce::KIND), content, tags)
+        .to_event(old)
+        .context(BuildSnafu)
+}
+
+pub fn is_statement(event: &Event) -> bool {
+    event.kind == Kind::from(announce::KIND)
+        && event
+            .tags
+            .iter()
+            .filter_map(Tag::as_standardized)
+            .any(|t| matches!(t, TagStandard::Hashtag(h) if h == TAG))
+}
+
+/// Only statements signed by the key they rotate away from are
+/// rotations.
+pub fn parse(event: &Event) -> Option<Rotation> {
+    if !is_statement(event) || event.verify().is_err() {
+        return None;
+    }
+
+    let rotation = match Payload::parse(event.content.as_bytes())?.body {
+        Body::KeyRotation(rotation) => rotation,
+        _ => return None,
+    };
+    if rotation.old_pubkey != event.pubkey.to_hex() {
+        return None;
+    }
+
+    Some(rotation)
+}
+
+/// Adds to `trusted` every key that a trusted key rotated to, however
+/// many rotations ago.
+pub fn follow(trusted: &mut BTreeSet<String>, rotations: &[Rotation]) {
+    loop {
+        let added = rotations
+            .iter()
+            .filter(|r| trusted.contains(&r.old_pubkey))
+            .map(|r| r.new_pubkey.clone())
+            .collect::<Vec<_>>();
+
+        let before = trusted.len();
+        trusted.extend(added);
+        if trusted.len() == before {
+            return;
+        }
+    }
+}
+
+/// Every statement, oldest first. A missing file has none.
+pub fn read(registry: &Registry) -> Result<Vec<Event>, Error> {
+    use error::*;
+
+    let path = registry.path.join(FILE_NAME);
+    match fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).context(ParseSnafu { path }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e).context(ReadSnafu { path }),
+    }
+}
+
+fn write(registry: &Registry, statements: &[Event]) -> Result<(), Error> {
+    use error::*;
+
+    let path = registry.path.join(FILE_NAME);
+    let data = serde_json::to_vec_pretty(statements).context(SerializeSnafu)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, &path).context(WriteSnafu { path })
+}
+
+fn write_key(path: &Path, keys: &Keys) -> Result<(), Error> {
+    use error::*;
+    use std::io::Write;
+
+    let mut options = fs::File::options();
+    options.write(true).create(true).truncate(true);
+
+    // Only the operator may read the secret key
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path).context(WriteSnafu { path })?;
+    writeln!(file, "{}", keys.secret_key().to_secret_hex()).context(WriteSnafu { path })
+}
+
+fn backup_path(key_path: &Path) -> PathBuf {
+    let mut name = key_path.file_name().unwrap_or_default().to_owned();
+    name.push(".old");
+    key_path.with_file_name(name)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the current key"))]
+    Key { source: signer::Error },
+
+    #[snafu(display("Could not serialize the key rotation statement"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not build the key rotation statement"))]
+    Build {
+        source: nostr::event::builder::Error,
+    },
+
+    #[snafu(display("The key rotation statement does not verify"))]
+    Invalid,
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the key rotation statements in {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not record the key rotation in the audit log"))]
+    Audit { source: audit::Error },
+
+    #[snafu(display("Could not check the approvals of the key rotation"))]
+    Quorum { source: quorum::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Key { source } => source.code(),
+            Self::Serialize { .. } | Self::Build { .. } | Self::Invalid => "E_INTERNAL",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_KEY_ROTATIONS_CORRUPT",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Audit { source } => source.code(),
+            Self::Quorum { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trust_follows_rotations_signed_by_the_old_key() {
+        let base_url: Url = "https://registry.example.com/".parse().unwrap();
+        let keys = [Keys::generate(), Keys::generate(), Keys::generate()];
+        let hex = |k: &Keys| k.public_key().to_hex();
+
+        let first = statement(&keys[0], &keys[1].public_key(), &base_url).unwrap();
+        let second = statement(&keys[1], &keys[2].public_key(), &base_url).unwrap();
+        let rotations = [&second, &first]
+            .into_iter()
+            .filter_map(parse)
+            .collect::<Vec<_>>();
+        assert_eq!(2, rotations.len());
+
+        let mut trusted = [hex(&keys[0])].into();
+        follow(&mut trusted, &rotations);
+        assert_eq!(keys.iter().map(hex).collect::<BTreeSet<_>>(), trusted);
+
+        // Only the old key can hand its trust on
+        let mallory = Keys::generate();
+        let claimed = Rotation {
+            old_pubkey: hex(&keys[0]),
+            new_pubkey: hex(&mallory),
+            base_url,
+        };
+        let content = Payload::new(Body::KeyRotation(claimed)).to_vec();
+        let forged = EventBuilder::new(
+            Kind::from(announce::KIND),
+            String::from_utf8(content).unwrap(),
+            [Tag::hashtag(TAG)],
+        )
+        .to_event(&mallory)
+        .unwrap();
+        assert!(is_statement(&forged));
+        assert!(parse(&forged).is_none());
+    }
+}
@@ -0,0 +1,281 @@
+//! Choosing and ordering the versions `margo list` prints.
+//!
+//! When each crate last changed comes from the audit log, so crates
+//! added before the registry kept one have no date. Download counts
+//! come from a running daemon, when there is one.
+
+use semver::Version;
+use serde::Serialize;
+use snafu::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+use crate::{
+    audit,
+    common::{CrateName, RustVersion},
+    names,
+    timestamp::Timestamp,
+    ListAll,
+};
+
+/// Downloads by crate name, then version, as the daemon counts them.
+pub type Downloads = BTreeMap<String, BTreeMap<String, u64>>;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SortBy {
+    /// By name.
+    #[default]
+    Name,
+
+    /// Most downloaded first.
+    Downloads,
+
+    /// Most recently changed first.
+    Date,
+}
+
+impl FromStr for SortBy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(Self::Name),
+            "downloads" => Ok(Self::Downloads),
+            "date" => Ok(Self::Date),
+            _ => error::SortSnafu { sort: s }.fail(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Only crates whose name starts with this, ignoring case and
+    /// treating `-` and `_` alike.
+    pub prefix: Option<String>,
+
+    /// Only these crates.
+    pub owned: Option<BTreeSet<CrateName>>,
+
+    /// Only yanked versions.
+    pub yanked: bool,
+
+    /// Only crates that changed at or after this time.
+    pub updated_since: Option<Timestamp>,
+
+    /// Only versions this toolchain can build, going by their
+    /// `rust-version`.
+    pub msrv_compatible: Option<RustVersion>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Row {
+    pub name: CrateName,
+    pub vers: Version,
+    pub yanked: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<RustVersion>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edition: Option<String>,
+
+    /// When the crate last changed.
+    pub updated: Option<Timestamp>,
+
+    /// `None` when no daemon was asked.
+    pub downloads: Option<u64>,
+}
+
+/// When each crate's index file last changed.
+pub fn last_updated(entries: &[audit::Entry]) -> BTreeMap<CrateName, Timestamp> {
+    entries
+        .iter()
+        .filter(|e| e.event.changes_index())
+        .filter_map(|e| Some((e.event.crate_name()?.clone(), e.time)))
+        .collect()
+}
+
+pub fn rows(
+    crates: &ListAll,
+    filter: &Filter,
+    sort: SortBy,
+    updated: &BTreeMap<CrateName, Timestamp>,
+    downloads: Option<&Downloads>,
+) -> Vec<Row> {
+    let prefix = filter.prefix.as_deref().map(names::normalize);
+
+    let mut chosen = crates
+        .iter()
+        .filter(|(name, _)| {
+            prefix
+                .as_deref()
+                .map_or(true, |p| names::normalize(name.as_str()).starts_with(p))
+        })
+        .filter(|(name, _)| filter.owned.as_ref().map_or(true, |o| o.contains(*name)))
+        .map(|(name, index)| (name, index, updated.get(name).copied()))
+        .filter(|(_, _, updated)| {
+            filter
+                .updated_since
+                .map_or(true, |since| updated.is_some_and(|u| u >= since))
+        })
+        .collect::<Vec<_>>();
+
+    let downloads_of = |name: &CrateName, vers: &Version| {
+        downloads.map(|d| {
+            d.get(name.as_str())
+                .and_then(|versions| versions.get(&vers.to_string()))
+                .copied()
+                .unwrap_or(0)
+        })
+    };
+
+    // The crates are already in name order, which the sorts keep for
+    // ties
+    match sort {
+        SortBy::Name => {}
+        SortBy::Downloads => chosen.sort_by_key(|(name, index, _)| {
+            let total = index
+                .keys()
+                .filter_map(|vers| downloads_of(name, vers))
+                .sum::<u64>();
+            std::cmp::Reverse(total)
+        }),
+        SortBy::Date => chosen.sort_by_key(|&(_, _, updated)| std::cmp::Reverse(updated)),
+    }
+
+    chosen
+        .into_iter()
+        .flat_map(|(name, index, updated)| {
+            index
+                .iter()
+                .filter(|(_, entry)| !filter.yanked || entry.yanked)
+                .filter(|(_, entry)| {
+                    filter
+                        .msrv_compatible
+                        .as_ref()
+                        .map_or(true, |toolchain| entry.builds_with(toolchain))
+                })
+                .map(move |(vers, entry)| Row {
+                    name: name.clone(),
+                    vers: vers.clone(),
+                    yanked: entry.yanked,
+                    rust_version: entry.rust_version.clone(),
+                    edition: entry.edition.clone(),
+                    updated,
+                    downloads: downloads_of(name, vers),
+                })
+        })
+        .collect()
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("`{sort}` is not one of `name`, `downloads` or `date`"))]
+    Sort { sort: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Sort { .. } => "E_BAD_SORT",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index_entry;
+
+    fn entry(name: &str, vers: &str, yanked: bool) -> index_entry::Root {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "vers": vers,
+            "deps": [],
+            "cksum": "",
+            "features": {},
+            "yanked": yanked,
+            "v": 2,
+            "rust_version": if name == "beta" { Some("1.80") } else { None },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn versions_are_filtered_and_sorted() {
+        let mut crates = ListAll::new();
+        for (name, vers, yanked) in [
+            ("alpha", "1.0.0", false),
+            ("alpha", "1.1.0", true),
+            ("beta", "2.0.0", false),
+            ("gamma", "0.1.0", true),
+        ] {
+            crates
+                .entry(name.parse().unwrap())
+                .or_default()
+                .insert(vers.parse().unwrap(), entry(name, vers, yanked));
+        }
+        let updated = [("alpha", 100), ("beta", 300)]
+            .into_iter()
+            .map(|(name, time)| (name.parse().unwrap(), Timestamp(time)))
+            .collect();
+        let downloads = serde_json::from_value(serde_json::json!({
+            "alpha": { "1.0.0": 5 },
+            "beta": { "2.0.0": 9 },
+        }))
+        .unwrap();
+
+        let listed = |filter: &Filter, sort| {
+            rows(&crates, filter, sort, &updated, Some(&downloads))
+                .into_iter()
+                .map(|r| format!("{} {}", r.name, r.vers))
+                .collect::<Vec<_>>()
+        };
+
+        let yanked = Filter {
+            yanked: true,
+            ..Filter::default()
+        };
+        assert_eq!(
+            ["alpha 1.1.0", "gamma 0.1.0"],
+            *listed(&yanked, SortBy::Name)
+        );
+
+        let recent = Filter {
+            updated_since: Some(Timestamp(200)),
+            ..Filter::default()
+        };
+        assert_eq!(["beta 2.0.0"], *listed(&recent, SortBy::Name));
+
+        let prefix = Filter {
+            prefix: Some("AL".to_owned()),
+            ..Filter::default()
+        };
+        assert_eq!(
+            ["alpha 1.0.0", "alpha 1.1.0"],
+            *listed(&prefix, SortBy::Name)
+        );
+
+        let msrv = Filter {
+            msrv_compatible: Some("1.70".parse().unwrap()),
+            ..Filter::default()
+        };
+        assert_eq!(
+            ["alpha 1.0.0", "alpha 1.1.0", "gamma 0.1.0"],
+            *listed(&msrv, SortBy::Name)
+        );
+
+        let all = Filter::default();
+        assert_eq!(
+            ["beta 2.0.0", "alpha 1.0.0", "alpha 1.1.0", "gamma 0.1.0"],
+            *listed(&all, SortBy::Downloads),
+        );
+        assert_eq!(
+            ["beta 2.0.0", "alpha 1.0.0", "alpha 1.1.0", "gamma 0.1.0"],
+            *listed(&all, SortBy::Date),
+        );
+    }
+}
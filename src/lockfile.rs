@@ -0,0 +1,228 @@
+//! Checking a `Cargo.lock` against the registry.
+//!
+//! Every package in the lockfile that came from a registry, whichever
+//! one, is looked up in this registry, so a mirror can be checked
+//! before going offline. Path and git dependencies are skipped.
+//! Lockfiles older than version 2 keep checksums elsewhere; for those,
+//! only the presence of each version is checked.
+
+use semver::Version;
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{common::CrateName, ListAll, ListAllError, Registry};
+
+#[derive(Debug, Deserialize)]
+pub struct Lockfile {
+    #[serde(default)]
+    pub package: Vec<Package>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Package {
+    pub name: CrateName,
+    pub version: Version,
+
+    #[serde(default)]
+    pub source: Option<String>,
+
+    #[serde(default)]
+    pub checksum: Option<String>,
+}
+
+impl Lockfile {
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        use error::*;
+
+        let lockfile = fs::read_to_string(path).context(ReadSnafu { path })?;
+        toml::from_str(&lockfile).context(ParseSnafu { path })
+    }
+}
+
+impl Package {
+    pub fn from_registry(&self) -> bool {
+        self.source
+            .as_deref()
+            .is_some_and(|s| s.starts_with("registry+") || s.starts_with("sparse+"))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    Missing {
+        name: CrateName,
+        version: Version,
+    },
+    Checksum {
+        name: CrateName,
+        version: Version,
+        locked: String,
+        local: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { name, version } => {
+                write!(f, "{name} {version} is not in the registry")
+            }
+            Self::Checksum {
+                name,
+                version,
+                locked,
+                local,
+            } => write!(
+                f,
+                "The checksum of {name} {version} is {local} in the registry but {locked} in the lockfile",
+            ),
+        }
+    }
+}
+
+/// Returns how many registry packages were checked and what is wrong
+/// with them. Yanked versions are fine: Cargo still uses them when
+/// they are locked.
+pub fn check(path: &Path, registry: &Registry) -> Result<(usize, Vec<Problem>), Error> {
+    use error::*;
+
+    let lockfile = Lockfile::read(path)?;
+    let crates = registry.list_all().context(ListSnafu)?;
+
+    Ok(compare(&lockfile, &crates))
+}
+
+fn compare(lockfile: &Lockfile, crates: &ListAll) -> (usize, Vec<Problem>) {
+    let packages = lockfile
+        .package
+        .iter()
+        .filter(|p| p.from_registry())
+        .collect::<Vec<_>>();
+
+    let mut problems = vec![];
+    for p in &packages {
+        let Some(entry) = crates.get(&p.name).and_then(|index| index.get(&p.version)) else {
+            problems.push(Problem::Missing {
+                name: p.name.clone(),
+                version: p.version.clone(),
+            });
+            continue;
+        };
+
+        if let Some(locked) = &p.checksum {
+            if !locked.eq_ignore_ascii_case(&entry.cksum) {
+                problems.push(Problem::Checksum {
+                    name: p.name.clone(),
+                    version: p.version.clone(),
+                    locked: locked.clone(),
+                    local: entry.cksum.clone(),
+                });
+            }
+        }
+    }
+
+    (packages.len(), problems)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the lockfile {}", path.display()))]
+    Read {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not parse the lockfile {}", path.display()))]
+    Parse {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not list the registry's crates"))]
+    List { source: ListAllError },
+
+    #[snafu(display("Found {count} problem(s) with the lockfile"))]
+    Failed { count: usize },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_LOCKFILE_READ",
+            Self::Parse { .. } => "E_LOCKFILE_PARSE",
+            Self::List { source } => source.code(),
+            Self::Failed { .. } => "E_LOCKFILE_MISMATCH",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn registry_packages_are_checked() {
+        let lockfile = toml::from_str::<Lockfile>(
+            r#"
+            version = 3
+
+            [[package]]
+            name = "app"
+            version = "0.1.0"
+
+            [[package]]
+            name = "demo"
+            version = "1.0.0"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+            checksum = "aa"
+
+            [[package]]
+            name = "demo"
+            version = "1.1.0"
+            source = "sparse+https://registry.example.com/"
+            checksum = "ff"
+
+            [[package]]
+            name = "gone"
+            version = "0.2.0"
+            source = "sparse+https://registry.example.com/"
+            checksum = "cc"
+            "#,
+        )
+        .unwrap();
+
+        let index = crate::parse_index_lines(
+            concat!(
+                r#"{"name":"demo","vers":"1.0.0","deps":[],"cksum":"aa","features":{},"yanked":true,"v":1}"#,
+                "\n",
+                r#"{"name":"demo","vers":"1.1.0","deps":[],"cksum":"bb","features":{},"yanked":false,"v":1}"#,
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let crates = [("demo".parse().unwrap(), index)].into();
+
+        let (checked, problems) = compare(&lockfile, &crates);
+        assert_eq!(3, checked);
+        assert_eq!(
+            vec![
+                Problem::Checksum {
+                    name: "demo".parse().unwrap(),
+                    version: "1.1.0".parse().unwrap(),
+                    locked: "ff".to_owned(),
+                    local: "bb".to_owned(),
+                },
+                Problem::Missing {
+                    name: "gone".parse().unwrap(),
+                    version: "0.2.0".parse().unwrap(),
+                },
+            ],
+            problems,
+        );
+    }
+}
@@ -1,29 +1,220 @@
-use common::CrateName;
-use semver::Version;
+use common::{CrateName, CrateVersion};
+use margo_client::{
+    client, config_json, extensions, index_entry, parse_index_error, parse_index_lines, Index,
+    ParseIndexError,
+};
+
+#[cfg(feature = "api-client")]
+use margo_client::api;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use std::{
     collections::{BTreeMap, BTreeSet},
     env, fmt,
     fs::{self, File},
-    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    io::{self, BufWriter, Read, Write},
     path::{Component, Path, PathBuf},
     str,
 };
 use url::Url;
 
+mod adopt;
+mod artifact;
+mod as_of;
+mod attestation;
+mod audit;
+mod blob;
+mod bundle;
+mod cargo_context;
+mod conflicts;
+mod dedup;
+mod docs;
+mod extract;
+mod features;
+mod feed;
+mod index_file;
+mod listing;
+mod lockfile;
+mod migrate;
+mod names;
+mod new_consumer;
+mod progress;
+mod quarantine;
+mod registry_diff;
+mod registry_snapshot;
+mod reproducible;
+mod sandbox;
+mod scan;
+mod selftest;
+mod setup_cargo;
+mod tier;
+mod timestamp;
+mod vendor;
+mod visibility;
+
+#[cfg(feature = "nostr")]
+mod announce;
+
+#[cfg(feature = "server")]
+mod auth;
+
+#[cfg(feature = "server")]
+mod catalog;
+
+#[cfg(feature = "chaos")]
+mod chaos;
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+mod control;
+
+#[cfg(feature = "zstd")]
+mod copies;
+
+#[cfg(feature = "p2p")]
+mod dials;
+
+#[cfg(any(feature = "discover", feature = "server"))]
+mod discovery;
+
+#[cfg(feature = "server")]
+mod docs_queue;
+
+#[cfg(feature = "download-mirrors")]
+mod download_mirrors;
+
+#[cfg(feature = "encryption")]
+mod encryption;
+
+#[cfg(feature = "erasure")]
+mod erasure;
+
+#[cfg(feature = "p2p")]
+mod gossip_cache;
+
 #[cfg(feature = "html")]
 mod html;
 
+#[cfg(feature = "server")]
+mod index_lock;
+
+#[cfg(feature = "nostr")]
+mod key_rotation;
+
+#[cfg(feature = "server")]
+mod maintenance;
+
+#[cfg(feature = "server")]
+mod merkle;
+
+#[cfg(feature = "sync-crates-io")]
+mod mirror;
+
+#[cfg(feature = "notifications")]
+mod notifications;
+
+#[cfg(feature = "nostr")]
+mod notify;
+
 #[cfg(feature = "p2p")]
 mod p2p;
 
+#[cfg(feature = "p2p")]
+mod pins;
+
+#[cfg(any(feature = "nostr", feature = "p2p"))]
+mod payload;
+
+#[cfg(feature = "proxy")]
+mod proxy;
+
+#[cfg(feature = "server")]
+mod publish_queue;
+
+#[cfg(feature = "publish-workspace")]
+mod publish_workspace;
+
+#[cfg(feature = "python")]
+mod python;
+
+#[cfg(feature = "nostr")]
+mod quorum;
+
+#[cfg(feature = "html")]
+mod readme;
+
+#[cfg(feature = "server")]
+mod regenerate;
+
+#[cfg(feature = "server")]
+mod registry_info;
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+mod reload;
+
+#[cfg(feature = "replicate")]
+mod replication;
+
+#[cfg(any(feature = "proxy", feature = "sync-crates-io"))]
+mod rules;
+
+#[cfg(feature = "server")]
+mod search;
+
+#[cfg(feature = "p2p")]
+mod seeding;
+
+#[cfg(feature = "server")]
+mod server;
+
+#[cfg(feature = "nostr")]
+mod signer;
+
+#[cfg(feature = "signed-urls")]
+mod signed_url;
+
+#[cfg(feature = "server")]
+mod snapshot;
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+mod status;
+
+#[cfg(feature = "sync-crates-io")]
+mod sync_cursor;
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+mod telemetry;
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+mod tenant;
+
+#[cfg(feature = "tui")]
+mod top;
+
+#[cfg(feature = "server")]
+mod upload;
+
+#[cfg(feature = "server")]
+mod usage;
+
+#[cfg(any(feature = "nostr", feature = "p2p"))]
+mod validation;
+
 #[cfg(feature = "sync-crates-io")]
 mod crates_io;
 
 #[derive(Debug, argh::FromArgs)]
 /// Manage a static crate registry
 struct Args {
+    /// report failures as a JSON object carrying a stable error code
+    #[argh(switch)]
+    json: bool,
+
+    /// refuse every command and API request that would change the
+    /// registry
+    #[argh(switch)]
+    read_only: bool,
+
     #[argh(subcommand)]
     subcommand: Subcommand,
 }
@@ -35,12 +226,76 @@ enum Subcommand {
     Add(AddArgs),
     Remove(RemoveArgs),
     Yank(YankArgs),
+    Attestation(AttestationArgs),
+    Artifact(ArtifactArgs),
+    Blob(BlobArgs),
     List(ListArgs),
+    Resolve(ResolveArgs),
+    CheckLock(CheckLockArgs),
+    Vendor(VendorArgs),
+    Verify(VerifyArgs),
+    Migrate(MigrateArgs),
+    Adopt(AdoptArgs),
+    DiffRegistry(DiffRegistryArgs),
+    Docs(DocsArgs),
     GenerateHtml(GenerateHtmlArgs),
+    #[cfg(feature = "encryption")]
+    GenerateEncryptionKey(GenerateEncryptionKeyArgs),
+    Conflicts(ConflictsArgs),
+    Quarantine(QuarantineArgs),
+    Release(ReleaseArgs),
+    Visibility(VisibilityArgs),
+    Snapshot(SnapshotArgs),
+    AsOf(AsOfArgs),
+    Bundle(BundleArgs),
+    Dedup(DedupArgs),
+    Tier(TierArgs),
+    Context(ContextArgs),
+    SetupCargo(SetupCargoArgs),
+    NewConsumer(NewConsumerArgs),
+    Selftest(SelftestArgs),
+    #[cfg(feature = "publish-workspace")]
+    PublishWorkspace(PublishWorkspaceArgs),
     #[cfg(feature = "sync-crates-io")]
     Sync(SyncArgs),
-    #[cfg(feature = "p2p")]
+    #[cfg(feature = "sync-crates-io")]
+    Mirror(MirrorArgs),
+    #[cfg(feature = "replicate")]
+    Replicate(ReplicateArgs),
+    #[cfg(any(feature = "p2p", feature = "server"))]
     Serve(ServeArgs),
+    #[cfg(feature = "p2p")]
+    Transfers(TransfersArgs),
+    #[cfg(feature = "p2p")]
+    Peer(PeerArgs),
+    #[cfg(feature = "p2p")]
+    Pin(PinArgs),
+    #[cfg(feature = "p2p")]
+    Unpin(UnpinArgs),
+    #[cfg(any(feature = "p2p", feature = "server"))]
+    Daemon(DaemonArgs),
+    #[cfg(feature = "tui")]
+    Top(TopArgs),
+    #[cfg(feature = "server")]
+    Token(TokenArgs),
+    #[cfg(feature = "server")]
+    Maintenance(MaintenanceArgs),
+    #[cfg(feature = "server")]
+    Approval(ApprovalArgs),
+    #[cfg(feature = "server")]
+    Usage(UsageArgs),
+    #[cfg(feature = "discover")]
+    Discover(DiscoverArgs),
+    #[cfg(feature = "api-client")]
+    Api(ApiArgs),
+    #[cfg(feature = "python")]
+    Python(PythonArgs),
+    #[cfg(feature = "nostr")]
+    RegistryDirectory(RegistryDirectoryArgs),
+    #[cfg(feature = "nostr")]
+    Key(KeyArgs),
+    #[cfg(feature = "nostr")]
+    Quorum(QuorumArgs),
 }
 
 /// Initialize a new registry
@@ -68,6 +323,10 @@ struct InitArgs {
     #[argh(option)]
     html_suggested_registry_name: Option<String>,
 
+    /// generate an Atom feed of newly added crate versions
+    #[argh(option)]
+    feed: Option<bool>,
+
     #[argh(positional)]
     path: PathBuf,
 }
@@ -81,6 +340,10 @@ struct AddArgs {
     #[argh(option)]
     registry: Option<PathBuf>,
 
+    /// add new crates even when their names break the `[names]` policy
+    #[argh(switch)]
+    allow_name: bool,
+
     #[argh(positional)]
     path: Vec<PathBuf>,
 }
@@ -113,8 +376,19 @@ struct GenerateHtmlArgs {
     registry: Option<PathBuf>,
 }
 
-/// Start a libp2p P2P node for the registry
-#[cfg(feature = "p2p")]
+/// Generate a key to encrypt the registry's crates with
+#[cfg(feature = "encryption")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "generate-encryption-key")]
+struct GenerateEncryptionKeyArgs {
+    /// where to write the key; an existing file is not replaced
+    #[argh(positional)]
+    path: PathBuf,
+}
+
+/// Run the registry daemon (libp2p node and/or HTTP server)
+#[cfg(any(feature = "p2p", feature = "server"))]
 #[derive(Debug, argh::FromArgs)]
 #[argh(subcommand)]
 #[argh(name = "serve")]
@@ -123,930 +397,6556 @@ struct ServeArgs {
     #[argh(option)]
     registry: Option<PathBuf>,
 
+    /// path to a server configuration listing several registries to
+    /// serve, instead of `--registry`
+    #[argh(option)]
+    config: Option<PathBuf>,
+
     /// multiaddr to listen on (default: /ip4/0.0.0.0/tcp/0)
+    #[cfg(feature = "p2p")]
     #[argh(option)]
     listen: Option<String>,
+
+    /// address for the HTTP server to listen on (default: 127.0.0.1:8080)
+    #[cfg(feature = "server")]
+    #[argh(option)]
+    http: Option<std::net::SocketAddr>,
+
+    /// OTLP/HTTP endpoint to send traces to, such as
+    /// http://127.0.0.1:4318/v1/traces (default: from
+    /// OTEL_EXPORTER_OTLP_ENDPOINT, or none)
+    #[cfg(feature = "telemetry")]
+    #[argh(option)]
+    otlp_endpoint: Option<Url>,
 }
 
-/// Yank a version of a crate from the registry
+/// Show the P2P transfers a running daemon has in progress
+#[cfg(feature = "p2p")]
 #[derive(Debug, argh::FromArgs)]
 #[argh(subcommand)]
-#[argh(name = "yank")]
-struct YankArgs {
-    /// path to the registry to modify
+#[argh(name = "transfers")]
+struct TransfersArgs {
+    /// the URL the daemon's HTTP server is at, ending in `/` (default:
+    /// http://127.0.0.1:8080/)
     #[argh(option)]
-    registry: Option<PathBuf>,
-
-    /// undo a previous yank
-    #[argh(switch)]
-    undo: bool,
+    url: Option<Url>,
 
-    /// the version of the crate
+    /// the token to send in `Authorization`, when the daemon needs one
     #[argh(option)]
-    version: Version,
+    token: Option<String>,
 
-    /// the name of the crate
-    #[argh(positional)]
-    name: CrateName,
+    /// print the transfers as JSON
+    #[argh(switch)]
+    json: bool,
 }
 
-/// List all crates and their versions in the registry
+/// Administer a running daemon through its control socket
+#[cfg(any(feature = "p2p", feature = "server"))]
 #[derive(Debug, argh::FromArgs)]
 #[argh(subcommand)]
-#[argh(name = "list")]
-struct ListArgs {
-    /// path to the registry to list
+#[argh(name = "daemon")]
+struct DaemonArgs {
+    /// path to the registry the daemon serves
     #[argh(option)]
     registry: Option<PathBuf>,
+
+    #[argh(subcommand)]
+    command: DaemonCommand,
 }
 
-/// Synchronize crate versions from crates.io into the registry
-#[cfg(feature = "sync-crates-io")]
+#[cfg(any(feature = "p2p", feature = "server"))]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum DaemonCommand {
+    Status(DaemonStatusArgs),
+    Sync(DaemonSyncArgs),
+    Reload(DaemonReloadArgs),
+    RotateKey(DaemonRotateKeyArgs),
+}
+
+/// Print the daemon's status for the registry as JSON
+#[cfg(any(feature = "p2p", feature = "server"))]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "status")]
+struct DaemonStatusArgs {}
+
+/// Ask the connected peers for their HEAD and any missed announcements
+#[cfg(any(feature = "p2p", feature = "server"))]
 #[derive(Debug, argh::FromArgs)]
 #[argh(subcommand)]
 #[argh(name = "sync")]
-struct SyncArgs {
-    /// path to the registry to sync into
+struct DaemonSyncArgs {}
+
+/// Apply the registry's configuration without restarting
+#[cfg(any(feature = "p2p", feature = "server"))]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "reload")]
+struct DaemonReloadArgs {}
+
+/// Sign with the registry's nostr key file again, after replacing it
+#[cfg(any(feature = "p2p", feature = "server"))]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "rotate-key")]
+struct DaemonRotateKeyArgs {}
+
+/// Watch a running daemon's peers, transfers, publishes and relays
+#[cfg(feature = "tui")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "top")]
+struct TopArgs {
+    /// path to the registry the daemon serves
     #[argh(option)]
     registry: Option<PathBuf>,
 
-    /// names of the crates to sync from crates.io
-    #[argh(positional)]
-    crates: Vec<String>,
+    /// milliseconds between refreshes (default: 1000)
+    #[argh(option)]
+    interval_ms: Option<u64>,
 }
 
-#[snafu::report]
-fn main() -> Result<(), Error> {
-    let args: Args = argh::from_env();
+/// Inspect and manage the peers of a running daemon
+#[cfg(feature = "p2p")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "peer")]
+struct PeerArgs {
+    #[argh(subcommand)]
+    command: PeerCommand,
+}
 
-    let global = Global::new()?;
-    let global = Box::leak(Box::new(global));
+#[cfg(feature = "p2p")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum PeerCommand {
+    List(PeerListArgs),
+    Dial(PeerDialArgs),
+    Ban(PeerBanArgs),
+    Info(PeerInfoArgs),
+}
 
-    match args.subcommand {
-        Subcommand::Init(init) => do_init(global, init)?,
-        Subcommand::Add(add) => do_add(global, add)?,
-        Subcommand::Remove(rm) => do_remove(global, rm)?,
-        Subcommand::Yank(yank) => do_yank(global, yank)?,
-        Subcommand::List(list) => do_list(global, list)?,
-        Subcommand::GenerateHtml(html) => do_generate_html(global, html)?,
-        #[cfg(feature = "sync-crates-io")]
-        Subcommand::Sync(sync) => do_sync(global, sync)?,
-        #[cfg(feature = "p2p")]
-        Subcommand::Serve(serve) => do_serve(global, serve)?,
-    }
+/// List the connected and banned peers
+#[cfg(feature = "p2p")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct PeerListArgs {
+    /// path to the registry the daemon serves
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    Ok(())
+    /// print the peers as JSON
+    #[argh(switch)]
+    json: bool,
 }
 
-#[derive(Debug, Snafu)]
-enum Error {
-    #[snafu(display("Could not initialize global variables"))]
-    #[snafu(context(false))]
-    Global {
-        #[snafu(source(from(GlobalError, Box::new)))]
-        source: Box<GlobalError>,
-    },
+/// Connect to a peer
+#[cfg(feature = "p2p")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "dial")]
+struct PeerDialArgs {
+    /// path to the registry the daemon serves
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    #[snafu(transparent)]
-    Initialize {
-        #[snafu(source(from(DoInitializeError, Box::new)))]
-        source: Box<DoInitializeError>,
-    },
+    /// the peer's multiaddr, such as `/ip4/192.0.2.1/tcp/4001`
+    #[argh(positional)]
+    addr: String,
+}
 
-    #[snafu(transparent)]
-    Open {
-        #[snafu(source(from(DiscoverRegistryError, Box::new)))]
-        source: Box<DiscoverRegistryError>,
-    },
+/// Disconnect a peer and refuse its connections until the daemon
+/// restarts
+#[cfg(feature = "p2p")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "ban")]
+struct PeerBanArgs {
+    /// path to the registry the daemon serves
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    #[snafu(transparent)]
-    Add {
-        #[snafu(source(from(AddError, Box::new)))]
-        source: Box<AddError>,
-    },
+    #[argh(positional)]
+    peer_id: String,
+}
 
-    #[snafu(transparent)]
-    Remove {
-        #[snafu(source(from(RemoveError, Box::new)))]
-        source: Box<RemoveError>,
-    },
+/// Show what the daemon knows about a peer
+#[cfg(feature = "p2p")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "info")]
+struct PeerInfoArgs {
+    /// path to the registry the daemon serves
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    #[snafu(transparent)]
-    Html {
-        #[snafu(source(from(HtmlError, Box::new)))]
-        source: Box<HtmlError>,
-    },
+    /// print the peer as JSON
+    #[argh(switch)]
+    json: bool,
 
-    #[snafu(transparent)]
-    Yank {
-        #[snafu(source(from(YankError, Box::new)))]
-        source: Box<YankError>,
-    },
+    #[argh(positional)]
+    peer_id: String,
+}
 
-    #[cfg(feature = "p2p")]
-    #[snafu(transparent)]
-    Serve {
-        #[snafu(source(from(ServeError, Box::new)))]
-        source: Box<ServeError>,
-    },
+/// Keep a crate, or one version of it, on at least a number of peers;
+/// without a crate, list the pinned ones
+#[cfg(feature = "p2p")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "pin")]
+struct PinArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    #[cfg(feature = "sync-crates-io")]
-    #[snafu(transparent)]
-    Sync {
-        #[snafu(source(from(SyncError, Box::new)))]
-        source: Box<SyncError>,
-    },
+    /// how many peers should hold each pinned version (default 3)
+    #[argh(option)]
+    replicas: Option<usize>,
+
+    /// the crate, as `{name}` or `{name}@{version}`
+    #[argh(positional)]
+    target: Option<pins::Target>,
 }
 
-trait UnwrapOrDialog<T> {
-    fn apply_default(self, use_default: bool, value: impl Into<T>) -> Self;
+/// Stop keeping a pinned crate on peers
+#[cfg(feature = "p2p")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "unpin")]
+struct UnpinArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    fn unwrap_or_dialog(self, f: impl FnOnce() -> dialoguer::Result<T>) -> dialoguer::Result<T>;
+    /// the crate, as `{name}` or `{name}@{version}`, as it was pinned
+    #[argh(positional)]
+    target: pins::Target,
 }
 
-impl<T> UnwrapOrDialog<T> for Option<T> {
-    fn apply_default(self, use_default: bool, value: impl Into<T>) -> Self {
-        if self.is_none() && use_default {
-            Some(value.into())
-        } else {
-            self
-        }
-    }
+/// Yank a version of a crate from the registry
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "yank")]
+struct YankArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    fn unwrap_or_dialog(self, f: impl FnOnce() -> dialoguer::Result<T>) -> dialoguer::Result<T> {
-        match self {
-            Some(v) => Ok(v),
-            None => f(),
-        }
-    }
+    /// undo a previous yank
+    #[argh(switch)]
+    undo: bool,
+
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
+
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
 }
 
-fn do_init(_global: &Global, init: InitArgs) -> Result<(), DoInitializeError> {
-    use do_initialize_error::*;
+/// Attach, list, or verify build attestations of crate versions
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "attestation")]
+struct AttestationArgs {
+    #[argh(subcommand)]
+    command: AttestationCommand,
+}
 
-    let base_url = init
-        .base_url
-        .unwrap_or_dialog(|| {
-            dialoguer::Input::new()
-                .with_prompt("What URL will the registry be served from")
-                .interact()
-        })
-        .context(BaseUrlSnafu)?;
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum AttestationCommand {
+    Add(AttestationAddArgs),
+    List(AttestationListArgs),
+    Verify(AttestationVerifyArgs),
+}
 
-    let auth_required = init
-        .auth_required
-        .apply_default(init.defaults, ConfigV1::USER_DEFAULT_AUTH_REQUIRED)
-        .unwrap_or_dialog(|| {
-            dialoguer::Confirm::new()
-                .default(ConfigV1::USER_DEFAULT_AUTH_REQUIRED)
-                .show_default(true)
-                .with_prompt("Require HTTP authentication to access crates?")
-                .interact()
-        })
-        .context(AuthRequiredSnafu)?;
+/// Attach an in-toto statement, plain or in a DSSE envelope, to a version
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "add")]
+struct AttestationAddArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    let enabled = init
-        .html
-        .apply_default(init.defaults, ConfigV1Html::USER_DEFAULT_ENABLED)
-        .unwrap_or_dialog(|| {
-            dialoguer::Confirm::new()
-                .default(ConfigV1Html::USER_DEFAULT_ENABLED)
-                .show_default(true)
-                .with_prompt("Enable HTML index generation?")
-                .interact()
-        })
-        .context(HtmlEnabledSnafu)?;
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
 
-    let suggested_registry_name = if enabled {
-        let name = init
-            .html_suggested_registry_name
-            .apply_default(
-                init.defaults,
-                ConfigV1Html::USER_DEFAULT_SUGGESTED_REGISTRY_NAME,
-            )
-            .unwrap_or_dialog(|| {
-                dialoguer::Input::new()
-                    .default(ConfigV1Html::USER_DEFAULT_SUGGESTED_REGISTRY_NAME.to_owned())
-                    .show_default(true)
-                    .with_prompt("Name you'd like to suggest other people call your registry")
-                    .interact()
-            })
-            .context(HtmlSuggestedRegistryNameSnafu)?;
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
 
-        Some(name)
-    } else {
-        None
-    };
+    /// the attestation file
+    #[argh(positional)]
+    path: PathBuf,
+}
 
-    let config = ConfigV1 {
-        base_url,
-        auth_required,
-        html: ConfigV1Html {
-            enabled,
-            suggested_registry_name,
-        },
-    };
+/// List the attestations of a version
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct AttestationListArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    let r = Registry::initialize(config, &init.path)?;
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
 
-    if r.config.html.enabled {
-        let res = r.generate_html();
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
+}
 
-        if cfg!(feature = "html") {
-            res?;
-        } else if let Err(e) = res {
-            eprintln!("Warning: {e}");
-        }
-    }
+/// Check that every attestation of a version is about its `.crate` file
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "verify")]
+struct AttestationVerifyArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    Ok(())
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
+
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
 }
 
-#[derive(Debug, Snafu)]
-#[snafu(module)]
-enum DoInitializeError {
-    #[snafu(display("Could not determine the base URL"))]
-    BaseUrl { source: dialoguer::Error },
+/// Add, list, or remove prebuilt binaries of crate versions
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "artifact")]
+struct ArtifactArgs {
+    #[argh(subcommand)]
+    command: ArtifactCommand,
+}
 
-    #[snafu(display("Could not determine if HTTP authorization is required"))]
-    AuthRequired { source: dialoguer::Error },
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum ArtifactCommand {
+    Add(ArtifactAddArgs),
+    List(ArtifactListArgs),
+    Remove(ArtifactRemoveArgs),
+}
 
-    #[snafu(display("Could not determine if HTML generation is enabled"))]
-    HtmlEnabled { source: dialoguer::Error },
+/// Add a prebuilt binary for one target to a version
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "add")]
+struct ArtifactAddArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    #[snafu(display("Could not determine the suggested registry name"))]
-    HtmlSuggestedRegistryName { source: dialoguer::Error },
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
 
-    #[snafu(transparent)]
-    Initialize { source: InitializeError },
+    /// the target triple the file was built for
+    #[argh(option)]
+    target: String,
 
-    #[snafu(transparent)]
-    Html { source: HtmlError },
+    /// the file name to publish it under [default: the name of the
+    /// file]
+    #[argh(option)]
+    file_name: Option<String>,
+
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
+
+    /// the file to add
+    #[argh(positional)]
+    path: PathBuf,
 }
 
-fn do_add(global: &Global, add: AddArgs) -> Result<(), Error> {
-    let r = discover_registry(add.registry)?;
+/// List the prebuilt binaries of a version
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct ArtifactListArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    for i in add.path {
-        r.add(global, i)?;
-    }
-    r.maybe_generate_html()?;
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
 
-    Ok(())
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
 }
 
-fn do_remove(_global: &Global, rm: RemoveArgs) -> Result<(), Error> {
-    let r = discover_registry(rm.registry)?;
+/// Remove a prebuilt binary from a version
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "remove")]
+struct ArtifactRemoveArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
 
-    r.remove(rm.name, rm.version)?;
-    r.maybe_generate_html()?;
+    /// the target triple of the file
+    #[argh(option)]
+    target: String,
 
-    Ok(())
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
+
+    /// the file name
+    #[argh(positional)]
+    file: String,
 }
 
-fn do_generate_html(_global: &Global, html: GenerateHtmlArgs) -> Result<(), Error> {
-    let r = discover_registry(html.registry)?;
-    r.generate_html()?;
-    Ok(())
+/// Store, fetch, list, or remove content-addressed files
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "blob")]
+struct BlobArgs {
+    #[argh(subcommand)]
+    command: BlobCommand,
 }
 
-fn do_yank(_global: &Global, yank: YankArgs) -> Result<(), Error> {
-    let r = discover_registry(yank.registry)?;
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum BlobCommand {
+    Put(BlobPutArgs),
+    Get(BlobGetArgs),
+    List(BlobListArgs),
+    Remove(BlobRemoveArgs),
+}
 
-    r.yank(yank.name, yank.version, !yank.undo)?;
-    r.maybe_generate_html()?;
+/// Store a file and print its SHA-256 digest
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "put")]
+struct BlobPutArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-    Ok(())
+    /// the file to store
+    #[argh(positional)]
+    path: PathBuf,
 }
 
-fn do_list(_global: &Global, list: ListArgs) -> Result<(), Error> {
-    let r = discover_registry(list.registry)?;
+/// Copy a stored file out of the registry, checking its digest
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "get")]
+struct BlobGetArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// where to write the file
+    #[argh(option)]
+    out: PathBuf,
 
-    let crates = r.list_all().unwrap();
+    /// the SHA-256 digest of the file
+    #[argh(positional)]
+    digest: String,
+}
 
-    #[derive(Default)]
-    struct Max(usize, String);
+/// List the stored files with their sizes
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct BlobListArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+}
 
-    impl Max {
-        fn push(&mut self, v: impl fmt::Display) {
-            use std::fmt::Write;
+/// Remove a stored file
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "remove")]
+struct BlobRemoveArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
 
-            let Self(m, s) = self;
+    /// the SHA-256 digest of the file
+    #[argh(positional)]
+    digest: String,
+}
 
-            s.clear();
-            _ = write!(s, "{v}");
-            *m = usize::max(*m, s.len());
+/// List or resolve versions whose checksums differ between sources
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "conflicts")]
+struct ConflictsArgs {
+    #[argh(subcommand)]
+    command: ConflictsCommand,
+}
+
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum ConflictsCommand {
+    List(ConflictsListArgs),
+    Resolve(ConflictsResolveArgs),
+}
+
+/// List the quarantined versions with each source's checksum
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct ConflictsListArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+}
+
+/// Lift the quarantine of a version
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "resolve")]
+struct ConflictsResolveArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// also remove the local copy, so that the next sync fetches the
+    /// upstream's
+    #[argh(switch)]
+    remove: bool,
+
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
+
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
+}
+
+/// Stop serving a version, for example after a malware report, while
+/// keeping it in the index marked as withheld; without a version, list
+/// the quarantined ones
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "quarantine")]
+struct QuarantineArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// why the version is withheld, recorded in the audit log
+    #[argh(option)]
+    reason: Option<String>,
+
+    /// the crate and version, as `{name}@{version}`
+    #[argh(positional)]
+    crate_version: Option<CrateVersion>,
+}
+
+/// Serve a quarantined version again
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "release")]
+struct ReleaseArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the crate and version, as `{name}@{version}`
+    #[argh(positional)]
+    crate_version: CrateVersion,
+}
+
+/// Set who may see a crate: `public`, `internal` for holders of any
+/// valid token, or `private` for its owners; without a level, show it,
+/// and without a crate, list those that are not public
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "visibility")]
+struct VisibilityArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the name of the crate
+    #[argh(positional)]
+    name: Option<CrateName>,
+
+    /// `public`, `internal`, or `private`
+    #[argh(positional)]
+    level: Option<visibility::Visibility>,
+}
+
+/// Take, list, restore, or delete copies of the whole registry
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "snapshot")]
+struct SnapshotArgs {
+    #[argh(subcommand)]
+    command: SnapshotCommand,
+}
+
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum SnapshotCommand {
+    Create(SnapshotCreateArgs),
+    List(SnapshotListArgs),
+    Restore(SnapshotRestoreArgs),
+    Delete(SnapshotDeleteArgs),
+}
+
+/// Copy the registry's current state under a name
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "create")]
+struct SnapshotCreateArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the name of the snapshot
+    #[argh(positional)]
+    name: String,
+}
+
+/// List the snapshots of the registry
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct SnapshotListArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+}
+
+/// Roll the registry back to a snapshot, saving its current state first
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "restore")]
+struct SnapshotRestoreArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the name of the snapshot
+    #[argh(positional)]
+    name: String,
+}
+
+/// Delete a snapshot
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "delete")]
+struct SnapshotDeleteArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the name of the snapshot
+    #[argh(positional)]
+    name: String,
+}
+
+/// Write a copy of the registry as it was at a moment in the past,
+/// replayed from the audit log
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "as-of")]
+struct AsOfArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the directory to write the copy to, which must not exist yet
+    #[argh(option)]
+    out: PathBuf,
+
+    /// the moment, as `2024-06-01` (midnight UTC) or
+    /// `2024-06-01T12:00:00Z`
+    #[argh(positional)]
+    at: timestamp::Timestamp,
+}
+
+/// Write a copy of the registry, with the Cargo configuration to use
+/// it, for a machine with no network access
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "bundle")]
+struct BundleArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the directory to write the bundle to, which must not exist yet
+    #[argh(option)]
+    out: PathBuf,
+
+    /// what Cargo should call the registry
+    #[argh(option)]
+    name: String,
+
+    /// `local` for a Cargo local registry, or `sparse` for a registry
+    /// to serve with `margo serve` [default: local]
+    #[argh(option, default = "Default::default()")]
+    layout: bundle::Layout,
+
+    /// where the bundle will be on the offline machine [default: the
+    /// --out directory]
+    #[argh(option)]
+    target_dir: Option<PathBuf>,
+
+    /// the URL the offline network will serve a sparse bundle at
+    /// [default: the registry's base URL]
+    #[argh(option)]
+    base_url: Option<Url>,
+
+    /// also use the bundle instead of crates.io
+    #[argh(switch)]
+    replace_crates_io: bool,
+}
+
+/// Store identical `.crate` files and blobs once, as hard links, across
+/// one or more registries on the same file system
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "dedup")]
+struct DedupArgs {
+    /// path to a registry to modify; may be given more than once
+    #[argh(option, long = "registry")]
+    registries: Vec<PathBuf>,
+
+    /// report what would be linked without changing anything
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+/// Move unused `.crate` files to the cold tier, or bring one back
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "tier")]
+struct TierArgs {
+    #[argh(subcommand)]
+    command: TierCommand,
+}
+
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum TierCommand {
+    Demote(TierDemoteArgs),
+    Promote(TierPromoteArgs),
+}
+
+/// Show the workspace and the registries Cargo would use in the current
+/// directory
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "context")]
+struct ContextArgs {
+    /// the registry to use, as Cargo names it [default: `[registry]
+    /// default`, or the only one configured]
+    #[argh(option)]
+    registry: Option<String>,
+}
+
+/// Add the registry to Cargo's configuration, or print what to add
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "setup-cargo")]
+struct SetupCargoArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// what Cargo should call the registry
+    #[argh(option)]
+    name: String,
+
+    /// also use the registry instead of crates.io, for a registry that
+    /// mirrors it
+    #[argh(switch)]
+    replace_crates_io: bool,
+
+    /// the Cargo configuration file to add the registry to [default:
+    /// $CARGO_HOME/config.toml]
+    #[argh(option)]
+    cargo_config: Option<PathBuf>,
+
+    /// print the configuration instead of writing it
+    #[argh(switch)]
+    print: bool,
+}
+
+/// Write a project that depends on a crate of the registry, to check
+/// that Cargo can resolve and build it
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "new-consumer")]
+struct NewConsumerArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// what Cargo should call the registry [default: the registry's
+    /// suggested name]
+    #[argh(option)]
+    name: Option<String>,
+
+    /// the version requirement the dependency must match; the newest
+    /// matching version is used [default: *]
+    #[argh(option, default = "VersionReq::STAR")]
+    req: VersionReq,
+
+    /// the directory to write the project to, which must not exist yet
+    /// [default: {crate}-consumer]
+    #[argh(option)]
+    out: Option<PathBuf>,
+
+    /// run `cargo build` in the project, which needs the registry to be
+    /// served
+    #[argh(switch)]
+    build: bool,
+
+    /// the crate to depend on
+    #[argh(positional)]
+    crate_name: CrateName,
+}
+
+/// Publish a throwaway crate through each frontend, fetch it back and
+/// build a project that depends on it
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "selftest")]
+struct SelftestArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the URL the daemon serves the registry at, to also publish
+    /// through its HTTP API
+    #[argh(option)]
+    url: Option<Url>,
+
+    /// the token to publish and fetch with over HTTP
+    #[argh(option)]
+    token: Option<String>,
+
+    /// what Cargo should call the registry [default: the registry's
+    /// suggested name]
+    #[argh(option)]
+    name: Option<String>,
+
+    /// do not build a project that depends on the crate
+    #[argh(switch)]
+    no_build: bool,
+
+    /// keep the versions published, instead of removing them afterwards
+    #[argh(switch)]
+    keep: bool,
+}
+
+/// Publish every crate of the current workspace to a registry, each
+/// after the crates it depends on
+#[cfg(feature = "publish-workspace")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "publish-workspace")]
+struct PublishWorkspaceArgs {
+    /// the registry to publish to, as Cargo names it [default: `[registry]
+    /// default`, or the only one configured]
+    #[argh(option)]
+    registry: Option<String>,
+
+    /// only publish the members this glob matches, such as `acme-*`; may
+    /// be repeated
+    #[argh(option)]
+    include: Vec<String>,
+
+    /// leave out the members this glob matches; may be repeated
+    #[argh(option)]
+    exclude: Vec<String>,
+
+    /// how long to wait for each version to appear in the index, in
+    /// seconds
+    #[argh(option, default = "300")]
+    timeout_secs: u64,
+
+    /// print the order the members would be published in, without
+    /// publishing them
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+/// Move `.crate` files nobody has downloaded for a while to the cold
+/// tier
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "demote")]
+struct TierDemoteArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// report what would move without changing anything
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+/// Copy a demoted `.crate` file back to local disk
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "promote")]
+struct TierPromoteArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the crate and version, as `{name}@{version}`
+    #[argh(positional)]
+    crate_version: CrateVersion,
+}
+
+/// List the crates and versions in the registry, with when each crate
+/// last changed and, from a running daemon, how often each version was
+/// downloaded
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct ListArgs {
+    /// path to the registry to list
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// only crates whose name starts with this
+    #[argh(option)]
+    prefix: Option<String>,
+
+    /// only crates this user owns, as in `token:3f2a9c81d04e7b65`
+    #[cfg(feature = "server")]
+    #[argh(option)]
+    owner: Option<String>,
+
+    /// the `data-dir` of the tenant's `[tenant.publish]` table, where
+    /// `--owner` finds the owners
+    #[cfg(feature = "server")]
+    #[argh(option)]
+    data_dir: Option<PathBuf>,
+
+    /// only yanked versions
+    #[argh(switch)]
+    yanked: bool,
+
+    /// only crates that changed since this date, as `2026-10-01`
+    #[argh(option)]
+    updated_since: Option<timestamp::Timestamp>,
+
+    /// only versions whose `rust-version` this toolchain meets, as
+    /// `1.70`
+    #[argh(option)]
+    msrv_compatible: Option<common::RustVersion>,
+
+    /// `name`, `downloads` (most first) or `date` (newest first)
+    /// [default: name]
+    #[argh(option, default = "Default::default()")]
+    sort: listing::SortBy,
+
+    /// print the versions as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+/// Find the version of a crate that Cargo would pick for a requirement
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "resolve")]
+struct ResolveArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// list every matching version, newest first
+    #[argh(switch)]
+    all: bool,
+
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
+
+    /// the version requirement, such as `^1.2` [default: *]
+    #[argh(positional, default = "VersionReq::STAR")]
+    req: VersionReq,
+}
+
+/// Check that every registry package in a lockfile is in the registry
+/// with the same checksum
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "check-lock")]
+struct CheckLockArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the `Cargo.lock` to check
+    #[argh(positional)]
+    lockfile: PathBuf,
+}
+
+/// Unpack the registry packages of a lockfile into a directory that
+/// Cargo can use instead of the registry
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "vendor")]
+struct VendorArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the `Cargo.lock` to vendor [default: Cargo.lock]
+    #[argh(option, default = "PathBuf::from(\"Cargo.lock\")")]
+    lockfile: PathBuf,
+
+    /// the directory to write the packages to [default: vendor]
+    #[argh(option, default = "PathBuf::from(\"vendor\")")]
+    out: PathBuf,
+}
+
+/// Check that every crate in the index can be downloaded and matches its checksum
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "verify")]
+struct VerifyArgs {
+    /// path to the registry to verify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// also check that the index, HTML, feed and index snapshot are
+    /// exactly what margo generates from the same inputs
+    #[argh(switch)]
+    check_reproducible: bool,
+}
+
+/// Upgrade a registry written by an older margo to the current format
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "migrate")]
+struct MigrateArgs {
+    /// path to the registry to upgrade [default: the current directory]
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// list the steps that would be taken without changing anything
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+/// Take over a registry made by upstream margo, upgrading it in place
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "adopt")]
+struct AdoptArgs {
+    /// path to the registry to adopt [default: the current directory]
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// describe the registry and what adopting it would change, without
+    /// changing anything
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+/// Compare the versions and checksums of two registries
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "diff-registry")]
+struct DiffRegistryArgs {
+    /// print the differences as JSON
+    #[argh(switch)]
+    json: bool,
+
+    /// the path of a registry or, with the `proxy` feature, the URL of a
+    /// margo daemon
+    #[argh(positional)]
+    a: registry_diff::Source,
+
+    /// the registry to compare it with
+    #[argh(positional)]
+    b: registry_diff::Source,
+}
+
+/// Build the rustdoc documentation for a version of a crate
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "docs")]
+struct DocsArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the version of the crate
+    #[argh(option)]
+    version: Version,
+
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
+}
+
+/// Synchronize crate versions from crates.io into the registry
+#[cfg(feature = "sync-crates-io")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "sync")]
+struct SyncArgs {
+    /// path to the registry to sync into
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// only sync crates matching this rule: a name glob such as
+    /// `tokio*`, or `owner:LOGIN`; may be given more than once
+    #[argh(option)]
+    include: Vec<rules::Rule>,
+
+    /// skip crates matching this rule, even if included; may be given
+    /// more than once
+    #[argh(option)]
+    exclude: Vec<rules::Rule>,
+
+    /// ignore where the previous sync left off and look at every
+    /// version again
+    #[argh(switch)]
+    full: bool,
+
+    /// also download `.crate` files from this mirror, a Cargo `dl`
+    /// template, racing it against crates.io; may be given more than
+    /// once
+    #[argh(option, long = "source")]
+    sources: Vec<String>,
+
+    /// names of the crates to sync from crates.io
+    #[argh(positional)]
+    crates: Vec<String>,
+}
+
+/// Check a mirror against the registry it mirrors
+#[cfg(feature = "sync-crates-io")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "mirror")]
+struct MirrorArgs {
+    #[argh(subcommand)]
+    command: MirrorCommand,
+}
+
+#[cfg(feature = "sync-crates-io")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum MirrorCommand {
+    Verify(MirrorVerifyArgs),
+}
+
+/// Report versions that are missing, have different checksums, or are
+/// yanked upstream but still live in the mirror
+#[cfg(feature = "sync-crates-io")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "verify")]
+struct MirrorVerifyArgs {
+    /// path to the mirror's registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the sparse index URL of the upstream registry [default:
+    /// https://index.crates.io/]
+    #[argh(option)]
+    upstream: Option<Url>,
+
+    /// names of the crates to check [default: every crate in the mirror]
+    #[argh(positional)]
+    crates: Vec<CrateName>,
+}
+
+/// Keep this registry a hot standby of another one
+#[cfg(feature = "replicate")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "replicate")]
+struct ReplicateArgs {
+    #[argh(subcommand)]
+    command: ReplicateCommand,
+}
+
+#[cfg(feature = "replicate")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum ReplicateCommand {
+    Run(ReplicateRunArgs),
+    Status(ReplicateStatusArgs),
+    Promote(ReplicatePromoteArgs),
+}
+
+/// Apply the primary's changes to this registry as they happen
+#[cfg(feature = "replicate")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "run")]
+struct ReplicateRunArgs {
+    /// path to the standby registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the URL the primary registry is served at, ending in `/`
+    #[argh(option)]
+    primary: Url,
+
+    /// the token to send in `Authorization`, when the primary needs one
+    #[argh(option)]
+    token: Option<String>,
+
+    /// seconds to wait between checks for new changes [default: 10]
+    #[argh(option, default = "10")]
+    interval: u64,
+
+    /// catch up once and exit, instead of following the primary
+    #[argh(switch)]
+    once: bool,
+}
+
+/// Show how far the standby is behind its primary
+#[cfg(feature = "replicate")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "status")]
+struct ReplicateStatusArgs {
+    /// path to the standby registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+}
+
+/// Stop following the primary, so this registry can take over from it
+#[cfg(feature = "replicate")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "promote")]
+struct ReplicatePromoteArgs {
+    /// path to the standby registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+}
+
+/// Find a registry by its `name@domain` address and add it to Cargo's
+/// configuration
+#[cfg(feature = "discover")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "discover")]
+struct DiscoverArgs {
+    /// the Cargo configuration file to add the registry to [default:
+    /// $CARGO_HOME/config.toml]
+    #[argh(option)]
+    cargo_config: Option<PathBuf>,
+
+    /// what Cargo should call the registry [default: the name from the
+    /// address]
+    #[argh(option)]
+    registry_name: Option<String>,
+
+    /// the registry's address, such as `acme@example.com`
+    #[argh(positional)]
+    address: discovery::Address,
+}
+
+/// Call a margo daemon's HTTP API and print its answer as JSON
+#[cfg(feature = "api-client")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "api")]
+struct ApiArgs {
+    /// the URL that the daemon serves the registry at
+    #[argh(option)]
+    url: Url,
+
+    /// the token to send in `Authorization`, when the daemon needs one
+    #[argh(option)]
+    token: Option<String>,
+
+    #[argh(subcommand)]
+    command: ApiCommand,
+}
+
+#[cfg(feature = "api-client")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum ApiCommand {
+    Search(ApiSearchArgs),
+    Crate(ApiCrateArgs),
+    Publish(ApiPublishArgs),
+    Yank(ApiYankArgs),
+    Stats(ApiStatsArgs),
+}
+
+/// Search for crates by name
+#[cfg(feature = "api-client")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "search")]
+struct ApiSearchArgs {
+    /// what to search for
+    #[argh(positional)]
+    q: String,
+}
+
+/// Show every version of a crate
+#[cfg(feature = "api-client")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "crate")]
+struct ApiCrateArgs {
+    /// the name of the crate
+    #[argh(positional)]
+    name: CrateName,
+}
+
+/// Publish a `.crate` file
+#[cfg(feature = "api-client")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "publish")]
+struct ApiPublishArgs {
+    /// the `.crate` file, as `cargo package` writes it
+    #[argh(positional)]
+    path: PathBuf,
+}
+
+/// Yank a version, or undo the yank
+#[cfg(feature = "api-client")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "yank")]
+struct ApiYankArgs {
+    /// unyank the version instead
+    #[argh(switch)]
+    undo: bool,
+
+    /// the crate and version, as `{name}@{version}`
+    #[argh(positional)]
+    crate_version: CrateVersion,
+}
+
+/// Show how often each version was downloaded
+#[cfg(feature = "api-client")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "stats")]
+struct ApiStatsArgs {}
+
+/// Run a Python script that can `import gnostr_registry`
+#[cfg(feature = "python")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "python")]
+struct PythonArgs {
+    /// the script to run
+    #[argh(positional)]
+    script: PathBuf,
+
+    /// the script's arguments, after `--` when they start with `-`
+    #[argh(positional)]
+    args: Vec<String>,
+}
+
+/// List the registries announced on nostr relays
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "registry-directory")]
+struct RegistryDirectoryArgs {
+    /// a relay to query; may be given more than once
+    #[argh(option, long = "relay")]
+    relays: Vec<Url>,
+
+    /// only list registries announced by this nostr public key (hex);
+    /// may be given more than once
+    #[argh(option, long = "trusted-pubkey")]
+    trusted_pubkeys: Vec<String>,
+
+    /// print the directory as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+/// Manage the operator's nostr key
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "key")]
+struct KeyArgs {
+    #[argh(subcommand)]
+    command: KeyCommand,
+}
+
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum KeyCommand {
+    Rotate(KeyRotateArgs),
+}
+
+/// Replace the key with a new one that the old key vouches for
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "rotate")]
+struct KeyRotateArgs {
+    /// path to the registry signed for
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the secret key file to replace, as given as the tenant's
+    /// `nostr-key`
+    #[argh(option)]
+    key: PathBuf,
+
+    /// a relay to publish the rotation statement to; may be given more
+    /// than once
+    #[argh(option, long = "relay")]
+    relays: Vec<Url>,
+}
+
+/// Approve the operations that need several operators' approval
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "quorum")]
+struct QuorumArgs {
+    #[argh(subcommand)]
+    command: QuorumCommand,
+}
+
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum QuorumCommand {
+    List(QuorumListArgs),
+    Approve(QuorumApproveArgs),
+    Collect(QuorumCollectArgs),
+}
+
+/// List the operations approved so far, and by whom
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct QuorumListArgs {
+    /// path to the registry
+    #[argh(option)]
+    registry: Option<PathBuf>,
+}
+
+/// Sign an approval of an operation, such as `remove demo 1.0.0`,
+/// `replace demo 1.0.0 <checksum>` or `rotate-key <pubkey>`
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "approve")]
+struct QuorumApproveArgs {
+    /// path to the registry the operation is on
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// the operator's secret key file
+    #[argh(option)]
+    key: Option<PathBuf>,
+
+    /// the `bunker://` URI of the operator's remote signer, instead of
+    /// a key file
+    #[argh(option)]
+    bunker: Option<String>,
+
+    /// a relay to publish the approval to; may be given more than once
+    #[argh(option, long = "relay")]
+    relays: Vec<Url>,
+
+    /// the operation
+    #[argh(positional)]
+    operation: Vec<String>,
+}
+
+/// Fetch the approvals that operators published to relays
+#[cfg(feature = "nostr")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "collect")]
+struct QuorumCollectArgs {
+    /// path to the registry to modify
+    #[argh(option)]
+    registry: Option<PathBuf>,
+
+    /// a relay to fetch approvals from; may be given more than once
+    #[argh(option, long = "relay")]
+    relays: Vec<Url>,
+}
+
+/// Manage the tokens minted for a tenant's publishers
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "token")]
+struct TokenArgs {
+    #[argh(subcommand)]
+    command: TokenCommand,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum TokenCommand {
+    List(TokenListArgs),
+    Revoke(TokenRevokeArgs),
+}
+
+/// List the minted tokens, including expired ones
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct TokenListArgs {
+    /// the tenant's publishing data directory
+    #[argh(option)]
+    data_dir: PathBuf,
+}
+
+/// Revoke a minted token
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "revoke")]
+struct TokenRevokeArgs {
+    /// the tenant's publishing data directory
+    #[argh(option)]
+    data_dir: PathBuf,
+
+    /// the token's ID, or a unique prefix of it
+    #[argh(positional)]
+    id: String,
+}
+
+/// Queue publishes while the registry is being worked on
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "maintenance")]
+struct MaintenanceArgs {
+    #[argh(subcommand)]
+    command: MaintenanceCommand,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum MaintenanceCommand {
+    Start(MaintenanceStartArgs),
+    End(MaintenanceEndArgs),
+    Status(MaintenanceStatusArgs),
+}
+
+/// Start queueing publishes instead of adding them
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "start")]
+struct MaintenanceStartArgs {
+    /// the tenant's publishing data directory
+    #[argh(option)]
+    data_dir: PathBuf,
+
+    /// why the registry is under maintenance
+    #[argh(option)]
+    reason: Option<String>,
+}
+
+/// Stop queueing publishes; the daemon then adds the queued crates
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "end")]
+struct MaintenanceEndArgs {
+    /// the tenant's publishing data directory
+    #[argh(option)]
+    data_dir: PathBuf,
+}
+
+/// Show whether the registry is under maintenance and what is queued
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "status")]
+struct MaintenanceStatusArgs {
+    /// the tenant's publishing data directory
+    #[argh(option)]
+    data_dir: PathBuf,
+}
+
+/// Review publishes that are waiting for approval
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "approval")]
+struct ApprovalArgs {
+    #[argh(subcommand)]
+    command: ApprovalCommand,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum ApprovalCommand {
+    List(ApprovalListArgs),
+    Approve(ApprovalApproveArgs),
+    Reject(ApprovalRejectArgs),
+}
+
+/// List the publishes waiting for approval
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "list")]
+struct ApprovalListArgs {
+    /// the tenant's publishing data directory
+    #[argh(option)]
+    data_dir: PathBuf,
+}
+
+/// Approve a publish; the daemon then adds it to the registry
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "approve")]
+struct ApprovalApproveArgs {
+    /// the tenant's publishing data directory
+    #[argh(option)]
+    data_dir: PathBuf,
+
+    /// who is approving it, recorded in the audit log
+    #[argh(option)]
+    reviewer: String,
+
+    /// the crate and version, as `{name}@{version}`
+    #[argh(positional)]
+    crate_version: CrateVersion,
+}
+
+/// Reject a publish, setting it aside so it is never added
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "reject")]
+struct ApprovalRejectArgs {
+    /// the tenant's publishing data directory
+    #[argh(option)]
+    data_dir: PathBuf,
+
+    /// the crate and version, as `{name}@{version}`
+    #[argh(positional)]
+    crate_version: CrateVersion,
+}
+
+/// Report the downloads each team made in a month
+#[cfg(feature = "server")]
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "usage")]
+struct UsageArgs {
+    /// the `data-dir` of the tenant's `[tenant.usage]` table
+    #[argh(option)]
+    data_dir: PathBuf,
+
+    /// the month, as `2026-10`; the current one by default
+    #[argh(option)]
+    month: Option<String>,
+
+    /// print the report as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+#[snafu::report]
+fn main() -> Result<(), Error> {
+    let args: Args = argh::from_env();
+    let json = args.json;
+
+    let res = run(args);
+
+    if let (true, Err(e)) = (json, &res) {
+        let body = ErrorBody::new(e.code(), e);
+        let body = serde_json::to_string(&body).expect("An error body is always serializable");
+        println!("{body}");
+        std::process::exit(1);
+    }
+
+    res
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let mut global = Global::new()?;
+    global.read_only = args.read_only;
+    global.progress = progress::Mode::detect(args.json);
+    let global = Box::leak(Box::new(global));
+
+    match args.subcommand {
+        Subcommand::Init(init) => do_init(global, init)?,
+        Subcommand::Add(add) => do_add(global, add)?,
+        Subcommand::Remove(rm) => do_remove(global, rm)?,
+        Subcommand::Yank(yank) => do_yank(global, yank)?,
+        Subcommand::Attestation(attestation) => do_attestation(global, attestation)?,
+        Subcommand::Artifact(artifact) => do_artifact(global, artifact)?,
+        Subcommand::Blob(blob) => do_blob(global, blob)?,
+        Subcommand::List(list) => do_list(global, list)?,
+        Subcommand::Resolve(resolve) => do_resolve(global, resolve)?,
+        Subcommand::CheckLock(check) => do_check_lock(global, check)?,
+        Subcommand::Vendor(vendor) => do_vendor(global, vendor)?,
+        Subcommand::Verify(verify) => do_verify(global, verify)?,
+        Subcommand::Migrate(migrate) => do_migrate(global, migrate)?,
+        Subcommand::Adopt(adopt) => do_adopt(global, adopt)?,
+        Subcommand::DiffRegistry(diff) => do_diff_registry(global, diff)?,
+        Subcommand::Docs(docs) => do_docs(global, docs)?,
+        Subcommand::GenerateHtml(html) => do_generate_html(global, html)?,
+        #[cfg(feature = "encryption")]
+        Subcommand::GenerateEncryptionKey(key) => do_generate_encryption_key(global, key)?,
+        Subcommand::Conflicts(conflicts) => do_conflicts(global, conflicts)?,
+        Subcommand::Quarantine(quarantine) => do_quarantine(global, quarantine)?,
+        Subcommand::Release(release) => do_release(global, release)?,
+        Subcommand::Visibility(visibility) => do_visibility(global, visibility)?,
+        Subcommand::Snapshot(snapshot) => do_snapshot(global, snapshot)?,
+        Subcommand::AsOf(as_of) => do_as_of(global, as_of)?,
+        Subcommand::Bundle(bundle) => do_bundle(global, bundle)?,
+        Subcommand::Dedup(dedup) => do_dedup(global, dedup)?,
+        Subcommand::Tier(tier) => do_tier(global, tier)?,
+        Subcommand::Context(context) => do_context(global, context)?,
+        Subcommand::SetupCargo(setup) => do_setup_cargo(global, setup)?,
+        Subcommand::NewConsumer(new) => do_new_consumer(global, new)?,
+        Subcommand::Selftest(selftest) => do_selftest(global, selftest)?,
+        #[cfg(feature = "publish-workspace")]
+        Subcommand::PublishWorkspace(publish) => do_publish_workspace(global, publish)?,
+        #[cfg(feature = "sync-crates-io")]
+        Subcommand::Sync(sync) => do_sync(global, sync)?,
+        #[cfg(feature = "sync-crates-io")]
+        Subcommand::Mirror(mirror) => do_mirror(global, mirror)?,
+        #[cfg(feature = "replicate")]
+        Subcommand::Replicate(replicate) => do_replicate(global, replicate)?,
+        #[cfg(any(feature = "p2p", feature = "server"))]
+        Subcommand::Serve(serve) => do_serve(global, serve)?,
+        #[cfg(feature = "p2p")]
+        Subcommand::Transfers(transfers) => do_transfers(global, transfers)?,
+        #[cfg(feature = "p2p")]
+        Subcommand::Peer(peer) => do_peer(global, peer)?,
+        #[cfg(feature = "p2p")]
+        Subcommand::Pin(pin) => do_pin(global, pin)?,
+        #[cfg(feature = "p2p")]
+        Subcommand::Unpin(unpin) => do_unpin(global, unpin)?,
+        #[cfg(any(feature = "p2p", feature = "server"))]
+        Subcommand::Daemon(daemon) => do_daemon(global, daemon)?,
+        #[cfg(feature = "tui")]
+        Subcommand::Top(top) => do_top(global, top)?,
+        #[cfg(feature = "server")]
+        Subcommand::Token(token) => do_token(global, token)?,
+        #[cfg(feature = "server")]
+        Subcommand::Maintenance(maintenance) => do_maintenance(global, maintenance)?,
+        #[cfg(feature = "server")]
+        Subcommand::Approval(approval) => do_approval(global, approval)?,
+        #[cfg(feature = "server")]
+        Subcommand::Usage(usage) => do_usage(global, usage)?,
+        #[cfg(feature = "discover")]
+        Subcommand::Discover(discover) => do_discover(global, discover)?,
+        #[cfg(feature = "api-client")]
+        Subcommand::Api(api) => do_api(global, api)?,
+        #[cfg(feature = "python")]
+        Subcommand::Python(python) => do_python(global, python)?,
+        #[cfg(feature = "nostr")]
+        Subcommand::RegistryDirectory(directory) => do_registry_directory(global, directory)?,
+        #[cfg(feature = "nostr")]
+        Subcommand::Key(key) => do_key(global, key)?,
+        #[cfg(feature = "nostr")]
+        Subcommand::Quorum(quorum) => do_quorum(global, quorum)?,
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+enum Error {
+    #[snafu(display("Could not initialize global variables"))]
+    #[snafu(context(false))]
+    Global {
+        #[snafu(source(from(GlobalError, Box::new)))]
+        source: Box<GlobalError>,
+    },
+
+    #[snafu(transparent)]
+    Initialize {
+        #[snafu(source(from(DoInitializeError, Box::new)))]
+        source: Box<DoInitializeError>,
+    },
+
+    #[snafu(transparent)]
+    Open {
+        #[snafu(source(from(DiscoverRegistryError, Box::new)))]
+        source: Box<DiscoverRegistryError>,
+    },
+
+    #[snafu(transparent)]
+    Add {
+        #[snafu(source(from(AddError, Box::new)))]
+        source: Box<AddError>,
+    },
+
+    #[snafu(transparent)]
+    Remove {
+        #[snafu(source(from(RemoveError, Box::new)))]
+        source: Box<RemoveError>,
+    },
+
+    #[snafu(transparent)]
+    Html {
+        #[snafu(source(from(HtmlError, Box::new)))]
+        source: Box<HtmlError>,
+    },
+
+    #[snafu(transparent)]
+    Feed {
+        #[snafu(source(from(feed::Error, Box::new)))]
+        source: Box<feed::Error>,
+    },
+
+    #[snafu(transparent)]
+    Verify {
+        #[snafu(source(from(VerifyError, Box::new)))]
+        source: Box<VerifyError>,
+    },
+
+    #[snafu(transparent)]
+    Docs {
+        #[snafu(source(from(docs::Error, Box::new)))]
+        source: Box<docs::Error>,
+    },
+
+    #[snafu(transparent)]
+    Yank {
+        #[snafu(source(from(YankError, Box::new)))]
+        source: Box<YankError>,
+    },
+
+    #[snafu(transparent)]
+    Attestation {
+        #[snafu(source(from(attestation::Error, Box::new)))]
+        source: Box<attestation::Error>,
+    },
+
+    #[snafu(transparent)]
+    Artifact {
+        #[snafu(source(from(artifact::Error, Box::new)))]
+        source: Box<artifact::Error>,
+    },
+
+    #[snafu(transparent)]
+    Blob {
+        #[snafu(source(from(blob::Error, Box::new)))]
+        source: Box<blob::Error>,
+    },
+
+    #[snafu(transparent)]
+    List {
+        #[snafu(source(from(ListError, Box::new)))]
+        source: Box<ListError>,
+    },
+
+    #[snafu(transparent)]
+    Resolve {
+        #[snafu(source(from(ResolveError, Box::new)))]
+        source: Box<ResolveError>,
+    },
+
+    #[snafu(transparent)]
+    CheckLock {
+        #[snafu(source(from(lockfile::Error, Box::new)))]
+        source: Box<lockfile::Error>,
+    },
+
+    #[snafu(transparent)]
+    Vendor {
+        #[snafu(source(from(vendor::Error, Box::new)))]
+        source: Box<vendor::Error>,
+    },
+
+    #[snafu(transparent)]
+    Conflicts {
+        #[snafu(source(from(ConflictError, Box::new)))]
+        source: Box<ConflictError>,
+    },
+
+    #[snafu(transparent)]
+    Quarantine {
+        #[snafu(source(from(QuarantineError, Box::new)))]
+        source: Box<QuarantineError>,
+    },
+
+    #[snafu(transparent)]
+    Visibility {
+        #[snafu(source(from(VisibilityError, Box::new)))]
+        source: Box<VisibilityError>,
+    },
+
+    #[snafu(transparent)]
+    Snapshot {
+        #[snafu(source(from(registry_snapshot::Error, Box::new)))]
+        source: Box<registry_snapshot::Error>,
+    },
+
+    #[snafu(transparent)]
+    AsOf {
+        #[snafu(source(from(as_of::Error, Box::new)))]
+        source: Box<as_of::Error>,
+    },
+
+    #[snafu(transparent)]
+    Bundle {
+        #[snafu(source(from(bundle::Error, Box::new)))]
+        source: Box<bundle::Error>,
+    },
+
+    #[snafu(transparent)]
+    Migrate {
+        #[snafu(source(from(migrate::Error, Box::new)))]
+        source: Box<migrate::Error>,
+    },
+
+    #[snafu(transparent)]
+    Adopt {
+        #[snafu(source(from(adopt::Error, Box::new)))]
+        source: Box<adopt::Error>,
+    },
+
+    #[snafu(transparent)]
+    DiffRegistry {
+        #[snafu(source(from(registry_diff::Error, Box::new)))]
+        source: Box<registry_diff::Error>,
+    },
+
+    #[snafu(transparent)]
+    Reproducible {
+        #[snafu(source(from(reproducible::Error, Box::new)))]
+        source: Box<reproducible::Error>,
+    },
+
+    #[snafu(transparent)]
+    Dedup {
+        #[snafu(source(from(dedup::Error, Box::new)))]
+        source: Box<dedup::Error>,
+    },
+
+    #[snafu(transparent)]
+    Tier {
+        #[snafu(source(from(tier::Error, Box::new)))]
+        source: Box<tier::Error>,
+    },
+
+    #[cfg(feature = "publish-workspace")]
+    #[snafu(transparent)]
+    PublishWorkspace {
+        #[snafu(source(from(publish_workspace::Error, Box::new)))]
+        source: Box<publish_workspace::Error>,
+    },
+
+    #[snafu(transparent)]
+    CargoContext {
+        #[snafu(source(from(cargo_context::Error, Box::new)))]
+        source: Box<cargo_context::Error>,
+    },
+
+    #[snafu(transparent)]
+    SetupCargo {
+        #[snafu(source(from(setup_cargo::Error, Box::new)))]
+        source: Box<setup_cargo::Error>,
+    },
+
+    #[snafu(transparent)]
+    NewConsumer {
+        #[snafu(source(from(new_consumer::Error, Box::new)))]
+        source: Box<new_consumer::Error>,
+    },
+
+    #[snafu(transparent)]
+    Selftest {
+        #[snafu(source(from(selftest::Error, Box::new)))]
+        source: Box<selftest::Error>,
+    },
+
+    #[cfg(any(feature = "p2p", feature = "server"))]
+    #[snafu(transparent)]
+    Serve {
+        #[snafu(source(from(ServeError, Box::new)))]
+        source: Box<ServeError>,
+    },
+
+    #[cfg(feature = "p2p")]
+    #[snafu(transparent)]
+    Transfers {
+        #[snafu(source(from(DoTransfersError, Box::new)))]
+        source: Box<DoTransfersError>,
+    },
+
+    #[cfg(feature = "p2p")]
+    #[snafu(transparent)]
+    Pin {
+        #[snafu(source(from(PinError, Box::new)))]
+        source: Box<PinError>,
+    },
+
+    #[cfg(any(feature = "p2p", feature = "server"))]
+    #[snafu(transparent)]
+    Control {
+        #[snafu(source(from(control::Error, Box::new)))]
+        source: Box<control::Error>,
+    },
+
+    #[cfg(feature = "discover")]
+    #[snafu(transparent)]
+    Discover {
+        #[snafu(source(from(DoDiscoverError, Box::new)))]
+        source: Box<DoDiscoverError>,
+    },
+
+    #[cfg(feature = "api-client")]
+    #[snafu(transparent)]
+    Api {
+        #[snafu(source(from(DoApiError, Box::new)))]
+        source: Box<DoApiError>,
+    },
+
+    #[cfg(feature = "python")]
+    #[snafu(transparent)]
+    Python {
+        #[snafu(source(from(python::Error, Box::new)))]
+        source: Box<python::Error>,
+    },
+
+    #[cfg(feature = "nostr")]
+    #[snafu(transparent)]
+    RegistryDirectory {
+        #[snafu(source(from(announce::Error, Box::new)))]
+        source: Box<announce::Error>,
+    },
+
+    #[cfg(feature = "nostr")]
+    #[snafu(transparent)]
+    KeyRotation {
+        #[snafu(source(from(key_rotation::Error, Box::new)))]
+        source: Box<key_rotation::Error>,
+    },
+
+    #[cfg(feature = "nostr")]
+    #[snafu(transparent)]
+    Quorum {
+        #[snafu(source(from(quorum::Error, Box::new)))]
+        source: Box<quorum::Error>,
+    },
+
+    #[cfg(feature = "encryption")]
+    #[snafu(transparent)]
+    Encryption {
+        #[snafu(source(from(encryption::Error, Box::new)))]
+        source: Box<encryption::Error>,
+    },
+
+    #[cfg(feature = "server")]
+    #[snafu(transparent)]
+    Token {
+        #[snafu(source(from(auth::Error, Box::new)))]
+        source: Box<auth::Error>,
+    },
+
+    #[cfg(feature = "server")]
+    #[snafu(transparent)]
+    Maintenance {
+        #[snafu(source(from(maintenance::Error, Box::new)))]
+        source: Box<maintenance::Error>,
+    },
+
+    #[cfg(feature = "server")]
+    #[snafu(transparent)]
+    Usage {
+        #[snafu(source(from(usage::Error, Box::new)))]
+        source: Box<usage::Error>,
+    },
+
+    #[cfg(feature = "tui")]
+    #[snafu(transparent)]
+    Top {
+        #[snafu(source(from(top::Error, Box::new)))]
+        source: Box<top::Error>,
+    },
+
+    #[cfg(feature = "server")]
+    #[snafu(transparent)]
+    PublishQueue {
+        #[snafu(source(from(publish_queue::Error, Box::new)))]
+        source: Box<publish_queue::Error>,
+    },
+
+    #[cfg(feature = "sync-crates-io")]
+    #[snafu(transparent)]
+    Sync {
+        #[snafu(source(from(SyncError, Box::new)))]
+        source: Box<SyncError>,
+    },
+
+    #[cfg(feature = "sync-crates-io")]
+    #[snafu(transparent)]
+    Mirror {
+        #[snafu(source(from(mirror::Error, Box::new)))]
+        source: Box<mirror::Error>,
+    },
+
+    #[cfg(feature = "replicate")]
+    #[snafu(transparent)]
+    Replicate {
+        #[snafu(source(from(replication::Error, Box::new)))]
+        source: Box<replication::Error>,
+    },
+}
+
+impl Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Global { source } => source.code(),
+            Self::Initialize { source } => source.code(),
+            Self::Open { source } => source.code(),
+            Self::Add { source } => source.code(),
+            Self::Remove { source } => source.code(),
+            Self::Html { source } => source.code(),
+            Self::Feed { source } => source.code(),
+            Self::Verify { source } => source.code(),
+            Self::Docs { source } => source.code(),
+            Self::Yank { source } => source.code(),
+            Self::Attestation { source } => source.code(),
+            Self::Artifact { source } => source.code(),
+            Self::Blob { source } => source.code(),
+            Self::List { source } => source.code(),
+            Self::Resolve { source } => source.code(),
+            Self::CheckLock { source } => source.code(),
+            Self::Vendor { source } => source.code(),
+            Self::Conflicts { source } => source.code(),
+            Self::Quarantine { source } => source.code(),
+            Self::Visibility { source } => source.code(),
+            Self::Snapshot { source } => source.code(),
+            Self::AsOf { source } => source.code(),
+            Self::Bundle { source } => source.code(),
+            Self::Migrate { source } => source.code(),
+            Self::Adopt { source } => source.code(),
+            Self::DiffRegistry { source } => source.code(),
+            Self::Reproducible { source } => source.code(),
+            Self::Dedup { source } => source.code(),
+            Self::Tier { source } => source.code(),
+            Self::CargoContext { source } => source.code(),
+            Self::SetupCargo { source } => source.code(),
+            Self::NewConsumer { source } => source.code(),
+            Self::Selftest { source } => source.code(),
+            #[cfg(feature = "publish-workspace")]
+            Self::PublishWorkspace { source } => source.code(),
+            #[cfg(any(feature = "p2p", feature = "server"))]
+            Self::Serve { source } => source.code(),
+            #[cfg(feature = "p2p")]
+            Self::Transfers { source } => source.code(),
+            #[cfg(feature = "p2p")]
+            Self::Pin { source } => source.code(),
+            #[cfg(any(feature = "p2p", feature = "server"))]
+            Self::Control { source } => source.code(),
+            #[cfg(feature = "server")]
+            Self::Token { source } => source.code(),
+            #[cfg(feature = "server")]
+            Self::Maintenance { source } => source.code(),
+            #[cfg(feature = "server")]
+            Self::Usage { source } => source.code(),
+            #[cfg(feature = "tui")]
+            Self::Top { source } => source.code(),
+            #[cfg(feature = "server")]
+            Self::PublishQueue { source } => source.code(),
+            #[cfg(feature = "discover")]
+            Self::Discover { source } => source.code(),
+            #[cfg(feature = "api-client")]
+            Self::Api { source } => source.code(),
+            #[cfg(feature = "python")]
+            Self::Python { source } => source.code(),
+            #[cfg(feature = "nostr")]
+            Self::RegistryDirectory { source } => source.code(),
+            #[cfg(feature = "nostr")]
+            Self::KeyRotation { source } => source.code(),
+            #[cfg(feature = "nostr")]
+            Self::Quorum { source } => source.code(),
+            #[cfg(feature = "encryption")]
+            Self::Encryption { source } => source.code(),
+            #[cfg(feature = "sync-crates-io")]
+            Self::Sync { source } => source.code(),
+            #[cfg(feature = "sync-crates-io")]
+            Self::Mirror { source } => source.code(),
+            #[cfg(feature = "replicate")]
+            Self::Replicate { source } => source.code(),
+        }
+    }
+}
+
+/// The machine-readable form of an error.
+///
+/// `code` is stable across releases so that scripts can branch on
+/// it; `message` and `causes` are for humans and may change.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+struct ErrorBody {
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    code: &'static str,
+    message: String,
+    causes: Vec<String>,
+}
+
+impl ErrorBody {
+    fn new(code: &'static str, error: &dyn std::error::Error) -> Self {
+        let message = error.to_string();
+
+        let mut causes = vec![];
+        let mut source = error.source();
+        while let Some(e) = source {
+            causes.push(e.to_string());
+            source = e.source();
+        }
+
+        Self {
+            code,
+            message,
+            causes,
+        }
+    }
+}
+
+trait UnwrapOrDialog<T> {
+    fn apply_default(self, use_default: bool, value: impl Into<T>) -> Self;
+
+    fn unwrap_or_dialog(self, f: impl FnOnce() -> dialoguer::Result<T>) -> dialoguer::Result<T>;
+}
+
+impl<T> UnwrapOrDialog<T> for Option<T> {
+    fn apply_default(self, use_default: bool, value: impl Into<T>) -> Self {
+        if self.is_none() && use_default {
+            Some(value.into())
+        } else {
+            self
+        }
+    }
+
+    fn unwrap_or_dialog(self, f: impl FnOnce() -> dialoguer::Result<T>) -> dialoguer::Result<T> {
+        match self {
+            Some(v) => Ok(v),
+            None => f(),
+        }
+    }
+}
+
+fn do_init(_global: &Global, init: InitArgs) -> Result<(), DoInitializeError> {
+    use do_initialize_error::*;
+
+    let base_url = init
+        .base_url
+        .unwrap_or_dialog(|| {
+            dialoguer::Input::new()
+                .with_prompt("What URL will the registry be served from")
+                .interact()
+        })
+        .context(BaseUrlSnafu)?;
+
+    let auth_required = init
+        .auth_required
+        .apply_default(init.defaults, ConfigV1::USER_DEFAULT_AUTH_REQUIRED)
+        .unwrap_or_dialog(|| {
+            dialoguer::Confirm::new()
+                .default(ConfigV1::USER_DEFAULT_AUTH_REQUIRED)
+                .show_default(true)
+                .with_prompt("Require HTTP authentication to access crates?")
+                .interact()
+        })
+        .context(AuthRequiredSnafu)?;
+
+    let enabled = init
+        .html
+        .apply_default(init.defaults, ConfigV1Html::USER_DEFAULT_ENABLED)
+        .unwrap_or_dialog(|| {
+            dialoguer::Confirm::new()
+                .default(ConfigV1Html::USER_DEFAULT_ENABLED)
+                .show_default(true)
+                .with_prompt("Enable HTML index generation?")
+                .interact()
+        })
+        .context(HtmlEnabledSnafu)?;
+
+    let suggested_registry_name = if enabled {
+        let name = init
+            .html_suggested_registry_name
+            .apply_default(
+                init.defaults,
+                ConfigV1Html::USER_DEFAULT_SUGGESTED_REGISTRY_NAME,
+            )
+            .unwrap_or_dialog(|| {
+                dialoguer::Input::new()
+                    .default(ConfigV1Html::USER_DEFAULT_SUGGESTED_REGISTRY_NAME.to_owned())
+                    .show_default(true)
+                    .with_prompt("Name you'd like to suggest other people call your registry")
+                    .interact()
+            })
+            .context(HtmlSuggestedRegistryNameSnafu)?;
+
+        Some(name)
+    } else {
+        None
+    };
+
+    let feed_enabled = init
+        .feed
+        .apply_default(init.defaults, ConfigV1Feed::USER_DEFAULT_ENABLED)
+        .unwrap_or_dialog(|| {
+            dialoguer::Confirm::new()
+                .default(ConfigV1Feed::USER_DEFAULT_ENABLED)
+                .show_default(true)
+                .with_prompt("Enable Atom feed generation?")
+                .interact()
+        })
+        .context(FeedEnabledSnafu)?;
+
+    let config = ConfigV1 {
+        base_url,
+        format: migrate::CURRENT,
+        auth_required,
+        read_only: false,
+        html: ConfigV1Html {
+            enabled,
+            suggested_registry_name,
+        },
+        feed: ConfigV1Feed {
+            enabled: feed_enabled,
+        },
+        docs: ConfigV1Docs::default(),
+        scan: ConfigV1Scan::default(),
+        sandbox: sandbox::Policy::default(),
+        limits: ConfigV1Limits::default(),
+        names: names::Policy::default(),
+        tiering: ConfigV1Tiering::default(),
+        regenerate: ConfigV1Regenerate::default(),
+        #[cfg(feature = "p2p")]
+        announcements: ConfigV1Announcements::default(),
+        #[cfg(feature = "nostr")]
+        quorum: ConfigV1Quorum::default(),
+        #[cfg(feature = "encryption")]
+        encryption: ConfigV1Encryption::default(),
+    };
+
+    let r = Registry::initialize(config, &init.path)?;
+
+    r.maybe_generate_feed()?;
+
+    if r.config.html.enabled {
+        let res = r.generate_html();
+
+        if cfg!(feature = "html") {
+            res?;
+        } else if let Err(e) = res {
+            eprintln!("Warning: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum DoInitializeError {
+    #[snafu(display("Could not determine the base URL"))]
+    BaseUrl { source: dialoguer::Error },
+
+    #[snafu(display("Could not determine if HTTP authorization is required"))]
+    AuthRequired { source: dialoguer::Error },
+
+    #[snafu(display("Could not determine if HTML generation is enabled"))]
+    HtmlEnabled { source: dialoguer::Error },
+
+    #[snafu(display("Could not determine the suggested registry name"))]
+    HtmlSuggestedRegistryName { source: dialoguer::Error },
+
+    #[snafu(display("Could not determine if feed generation is enabled"))]
+    FeedEnabled { source: dialoguer::Error },
+
+    #[snafu(transparent)]
+    Initialize { source: InitializeError },
+
+    #[snafu(transparent)]
+    Html { source: HtmlError },
+
+    #[snafu(transparent)]
+    Feed { source: feed::Error },
+}
+
+impl DoInitializeError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::BaseUrl { .. }
+            | Self::AuthRequired { .. }
+            | Self::HtmlEnabled { .. }
+            | Self::HtmlSuggestedRegistryName { .. }
+            | Self::FeedEnabled { .. } => "E_PROMPT",
+            Self::Initialize { source } => source.code(),
+            Self::Html { source } => source.code(),
+            Self::Feed { source } => source.code(),
+        }
+    }
+}
+
+fn do_add(global: &Global, add: AddArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, add.registry)?;
+
+    for i in add.path {
+        if !add.allow_name {
+            let crate_file = fs::read(&i).context(add_error::ReadCrateSnafu)?;
+            r.check_name(&crate_file)?;
+        }
+        let (name, version) = r.add(global, i)?;
+        r.maybe_build_docs(&name, &version);
+    }
+    r.maybe_generate_html()?;
+    r.maybe_generate_feed()?;
+
+    Ok(())
+}
+
+fn do_remove(global: &Global, rm: RemoveArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, rm.registry)?;
+
+    #[cfg(feature = "nostr")]
+    let grant = quorum::require(
+        &r,
+        &quorum::Operation::Remove {
+            name: rm.name.clone(),
+            vers: rm.version.clone(),
+        },
+    )?;
+
+    r.remove(rm.name, rm.version)?;
+
+    #[cfg(feature = "nostr")]
+    grant.spend(&r)?;
+
+    r.maybe_generate_html()?;
+    r.maybe_generate_feed()?;
+
+    Ok(())
+}
+
+fn do_docs(global: &Global, docs: DocsArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, docs.registry)?;
+
+    docs::build(&r, &docs.name, &docs.version)?;
+
+    Ok(())
+}
+
+fn do_generate_html(global: &Global, html: GenerateHtmlArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, html.registry)?;
+    r.generate_html()?;
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+fn do_generate_encryption_key(
+    _global: &Global,
+    key: GenerateEncryptionKeyArgs,
+) -> Result<(), Error> {
+    encryption::write_key(&key.path, &encryption::Key::generate()?)?;
+    println!("Wrote the key to {}", key.path.display());
+    println!("Set it as `[encryption] key-file` and give it to the registry's consumers");
+
+    Ok(())
+}
+
+fn do_yank(global: &Global, yank: YankArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, yank.registry)?;
+
+    r.yank(yank.name, yank.version, !yank.undo)?;
+    r.maybe_generate_html()?;
+    r.maybe_generate_feed()?;
+
+    Ok(())
+}
+
+fn do_resolve(_global: &Global, resolve: ResolveArgs) -> Result<(), Error> {
+    use resolve_error::*;
+
+    let r = discover_registry(resolve.registry)?;
+
+    let (name, index) = r
+        .find_crate(&resolve.name)
+        .context(IndexSnafu)?
+        .unwrap_or((resolve.name, Index::new()));
+
+    let resolution = resolve_versions(&index, &resolve.req);
+    ensure!(
+        resolution.best.is_some(),
+        NoMatchSnafu {
+            name,
+            req: resolve.req,
+        }
+    );
+
+    let shown = if resolve.all { usize::MAX } else { 1 };
+    for version in resolution.matches.iter().take(shown) {
+        println!("{name} {version}");
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum ResolveError {
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: FindCrateError },
+
+    #[snafu(display("No version of `{name}` matches `{req}`"))]
+    NoMatch { name: CrateName, req: VersionReq },
+}
+
+impl ResolveError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Index { source } => source.code(),
+            Self::NoMatch { .. } => "E_NO_MATCHING_VERSION",
+        }
+    }
+}
+
+fn do_attestation(global: &Global, attestation: AttestationArgs) -> Result<(), Error> {
+    match attestation.command {
+        AttestationCommand::Add(add) => {
+            let r = discover_writable_registry(global, add.registry)?;
+
+            let data = fs::read(&add.path).map_err(|source| attestation::Error::ReadFile {
+                source,
+                path: add.path.clone(),
+            })?;
+            let attestation = attestation::attach(&r, &add.name, &add.version, &data)?;
+
+            println!(
+                "Attached {} `{}` to {} {}",
+                attestation.predicate_type, attestation.digest, add.name, add.version,
+            );
+        }
+
+        AttestationCommand::List(list) => {
+            let r = discover_registry(list.registry)?;
+
+            let attestations = attestation::list(&r, &list.name, &list.version)?;
+            if attestations.is_empty() {
+                println!("{} {} has no attestations", list.name, list.version);
+            }
+            for a in attestations {
+                let mark = if a.verified { "" } else { " (does not match)" };
+                println!("{} {}{mark}", a.digest, a.predicate_type);
+            }
+        }
+
+        AttestationCommand::Verify(verify) => {
+            let r = discover_registry(verify.registry)?;
+
+            let attestations = attestation::list(&r, &verify.name, &verify.version)?;
+            let count = attestations.iter().filter(|a| !a.verified).count();
+            for a in attestations.iter().filter(|a| !a.verified) {
+                println!("{} is not about the `.crate` file", a.digest);
+            }
+
+            if count > 0 {
+                return Err(attestation::Error::Failed { count }.into());
+            }
+
+            println!(
+                "All {} attestations of {} {} match",
+                attestations.len(),
+                verify.name,
+                verify.version,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn do_artifact(global: &Global, artifact: ArtifactArgs) -> Result<(), Error> {
+    match artifact.command {
+        ArtifactCommand::Add(add) => {
+            let r = discover_writable_registry(global, add.registry)?;
+
+            let file = add.file_name.clone().unwrap_or_else(|| {
+                add.path
+                    .file_name()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+            let data = fs::read(&add.path).map_err(|source| artifact::Error::Read {
+                source,
+                path: add.path.clone(),
+            })?;
+
+            let artifact = artifact::add(&r, &add.name, &add.version, &add.target, &file, &data)?;
+
+            println!(
+                "Added {} for {} to {} {} ({})",
+                artifact.file, artifact.target, add.name, add.version, artifact.cksum,
+            );
+        }
+
+        ArtifactCommand::List(list) => {
+            let r = discover_registry(list.registry)?;
+
+            let artifacts = artifact::list(&r, &list.name, &list.version)?;
+            if artifacts.is_empty() {
+                println!("{} {} has no artifacts", list.name, list.version);
+            }
+            for a in artifacts {
+                println!("{} {} {} bytes {}", a.target, a.file, a.size, a.cksum);
+            }
+        }
+
+        ArtifactCommand::Remove(rm) => {
+            let r = discover_writable_registry(global, rm.registry)?;
+
+            artifact::remove(&r, &rm.name, &rm.version, &rm.target, &rm.file)?;
+
+            println!(
+                "Removed {} for {} from {} {}",
+                rm.file, rm.target, rm.name, rm.version,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn do_blob(global: &Global, blob: BlobArgs) -> Result<(), Error> {
+    match blob.command {
+        BlobCommand::Put(put) => {
+            let r = discover_writable_registry(global, put.registry)?;
+
+            let (digest, _, new) = blob::put_file(&r, &put.path)?;
+
+            if !new {
+                println!("The registry already has this blob");
+            }
+            println!("{digest}");
+        }
+
+        BlobCommand::Get(get) => {
+            let r = discover_registry(get.registry)?;
+
+            let (path, _) = blob::verify(&r.blobs_dir(), &get.digest)?;
+            fs::copy(path, &get.out).map_err(|source| blob::Error::Write {
+                source,
+                path: get.out.clone(),
+            })?;
+
+            println!("Wrote {} to `{}`", get.digest, get.out.display());
+        }
+
+        BlobCommand::List(list) => {
+            let r = discover_registry(list.registry)?;
+
+            for (digest, size) in blob::list(&r.blobs_dir())? {
+                println!("{digest} {size} bytes");
+            }
+        }
+
+        BlobCommand::Remove(rm) => {
+            let r = discover_writable_registry(global, rm.registry)?;
+
+            blob::remove(&r, &rm.digest)?;
+
+            println!("Removed {}", rm.digest);
+        }
+    }
+
+    Ok(())
+}
+
+fn do_check_lock(_global: &Global, check: CheckLockArgs) -> Result<(), Error> {
+    let r = discover_registry(check.registry)?;
+
+    let (checked, problems) = lockfile::check(&check.lockfile, &r)?;
+
+    for problem in &problems {
+        println!("{problem}");
+    }
+
+    if !problems.is_empty() {
+        return Err(lockfile::Error::Failed {
+            count: problems.len(),
+        }
+        .into());
+    }
+
+    println!("All {checked} registry packages are in the registry");
+
+    Ok(())
+}
+
+fn do_vendor(_global: &Global, vendor: VendorArgs) -> Result<(), Error> {
+    let r = discover_registry(vendor.registry)?;
+
+    let vendored = vendor::vendor(&r, &vendor.lockfile, &vendor.out)?;
+
+    println!(
+        "Vendored {} packages into `{}`",
+        vendored.packages,
+        vendor.out.display(),
+    );
+    println!();
+    println!("To use them, add this to `.cargo/config.toml`:");
+    println!();
+    println!("{}", vendor::cargo_config(&vendored.sources, &vendor.out));
+
+    Ok(())
+}
+
+fn do_snapshot(global: &Global, snapshot: SnapshotArgs) -> Result<(), Error> {
+    match snapshot.command {
+        SnapshotCommand::Create(create) => {
+            let r = discover_writable_registry(global, create.registry)?;
+
+            let manifest = registry_snapshot::create(&r, &create.name)?;
+
+            println!(
+                "Created snapshot `{}` of {} files ({} hard linked)",
+                manifest.name, manifest.files, manifest.linked,
+            );
+        }
+
+        SnapshotCommand::List(list) => {
+            let r = discover_registry(list.registry)?;
+
+            for manifest in registry_snapshot::list(&r)? {
+                println!(
+                    "{} {} {} files",
+                    manifest.name, manifest.created_at, manifest.files,
+                );
+            }
+        }
+
+        SnapshotCommand::Restore(restore) => {
+            let r = discover_writable_registry(global, restore.registry)?;
+
+            let before = registry_snapshot::restore(&r, &restore.name)?;
+
+            println!(
+                "Restored snapshot `{}`; the previous state is saved as `{}`",
+                restore.name, before.name,
+            );
+        }
+
+        SnapshotCommand::Delete(delete) => {
+            let r = discover_writable_registry(global, delete.registry)?;
+
+            registry_snapshot::delete(&r, &delete.name)?;
+
+            println!("Deleted snapshot `{}`", delete.name);
+        }
+    }
+
+    Ok(())
+}
+
+fn do_as_of(_global: &Global, as_of: AsOfArgs) -> Result<(), Error> {
+    let r = discover_registry(as_of.registry)?;
+
+    let report = as_of::export(&r, as_of.at, &as_of.out)?;
+
+    println!(
+        "Wrote {} versions as of {} to `{}` ({} hard linked)",
+        report.versions,
+        as_of.at,
+        as_of.out.display(),
+        report.linked,
+    );
+    for (name, vers) in &report.missing {
+        eprintln!("Warning: {name} {vers} was in the index then, but its `.crate` file is gone");
+    }
+    if report.unlogged > 0 {
+        eprintln!(
+            "Warning: the audit log does not mention {} versions, which were kept as they are now",
+            report.unlogged,
+        );
+    }
+    if let Some(restored) = report.restored {
+        eprintln!(
+            "Warning: a snapshot was restored at {restored}; the audit log does not say what that changed",
+        );
+    }
+
+    Ok(())
+}
+
+fn do_bundle(_global: &Global, bundle: BundleArgs) -> Result<(), Error> {
+    let r = discover_registry(bundle.registry)?;
+
+    let target_dir = match bundle.target_dir {
+        Some(dir) => dir,
+        None => std::path::absolute(&bundle.out).unwrap_or_else(|_| bundle.out.clone()),
+    };
+    let base_url = match bundle.base_url {
+        Some(mut url) => {
+            ensure_last_segment_empty(&mut url);
+            url
+        }
+        None => r.config.base_url.clone(),
+    };
+    let options = bundle::Options {
+        name: &bundle.name,
+        layout: bundle.layout,
+        target_dir: &target_dir,
+        base_url: &base_url,
+        replace_crates_io: bundle.replace_crates_io,
+    };
+
+    let report = bundle::write(&r, &options, &bundle.out)?;
+
+    println!(
+        "Wrote {} versions to `{}` ({} hard linked)",
+        report.versions,
+        bundle.out.display(),
+        report.linked,
+    );
+    if report.withheld > 0 {
+        eprintln!(
+            "Warning: {} withheld versions were left out",
+            report.withheld
+        );
+    }
+    println!(
+        "See `{}` for how to use it",
+        bundle.out.join(bundle::INSTRUCTIONS_FILE_NAME).display(),
+    );
+
+    Ok(())
+}
+
+fn do_dedup(global: &Global, dedup: DedupArgs) -> Result<(), Error> {
+    let paths = if dedup.registries.is_empty() {
+        vec![None]
+    } else {
+        dedup.registries.into_iter().map(Some).collect()
+    };
+
+    let mut registries = vec![];
+    for path in paths {
+        registries.push(discover_writable_registry(global, path)?);
+    }
+    let roots = registries
+        .iter()
+        .map(|r| r.path.as_path())
+        .collect::<Vec<_>>();
+
+    let summary = dedup::dedup(&roots, dedup.dry_run, global.progress)?;
+
+    let verb = if dedup.dry_run {
+        "Would link"
+    } else {
+        "Linked"
+    };
+    println!(
+        "{verb} {} of {} files, reclaiming {} bytes",
+        summary.linked, summary.files, summary.reclaimed,
+    );
+
+    Ok(())
+}
+
+fn do_tier(global: &Global, tier: TierArgs) -> Result<(), Error> {
+    match tier.command {
+        TierCommand::Demote(demote) => {
+            let r = discover_writable_registry(global, demote.registry)?;
+
+            let summary = r.demote(demote.dry_run)?;
+
+            let verb = if demote.dry_run {
+                "Would demote"
+            } else {
+                "Demoted"
+            };
+            println!(
+                "{verb} {} crates, {} bytes, to the cold tier",
+                summary.demoted, summary.bytes,
+            );
+        }
+
+        TierCommand::Promote(promote) => {
+            let r = discover_writable_registry(global, promote.registry)?;
+
+            let CrateVersion { name, version } = promote.crate_version;
+            if r.promote(&name, &version)? {
+                println!("{name} {version} is on local disk");
+            } else {
+                println!("{name} {version} is in neither tier");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn do_context(_global: &Global, context: ContextArgs) -> Result<(), Error> {
+    let c = cargo_context::detect()?;
+
+    match &c.workspace_root {
+        Some(root) => println!("workspace: {}", root.display()),
+        None => println!("workspace: none"),
+    }
+
+    let chosen = c.registry(context.registry.as_deref());
+    let chosen_name = chosen.as_ref().ok().map(|(name, _)| *name);
+    for (name, registry) in &c.registries {
+        let mark = if Some(name.as_str()) == chosen_name {
+            "*"
+        } else {
+            " "
+        };
+        println!(
+            "{mark} {name}: {} (from {})",
+            registry.index,
+            registry.defined_in.display(),
+        );
+    }
+
+    let (name, registry) = chosen?;
+    match registry.base_url() {
+        Some(url) => println!("Using `{name}`, served at {url}"),
+        None => eprintln!("Warning: `{name}` is not a sparse registry, which margo serves"),
+    }
+
+    Ok(())
+}
+
+fn do_setup_cargo(_global: &Global, setup: SetupCargoArgs) -> Result<(), Error> {
+    let r = discover_registry(setup.registry)?;
+
+    let name = setup.name.as_str();
+    let config = setup_cargo::Setup {
+        name,
+        index: format!("sparse+{}", r.config.base_url),
+        replace_crates_io: setup.replace_crates_io,
+    };
+
+    if setup.print {
+        print!("{}", config.config()?);
+        return Ok(());
+    }
+
+    let path = match setup.cargo_config {
+        Some(path) => path,
+        None => setup_cargo::default_path()?,
+    };
+
+    if config.write(&path)? {
+        println!("Added registry `{name}` to {}", path.display());
+    } else {
+        println!("Registry `{name}` is already in {}", path.display());
+    }
+    if setup.replace_crates_io {
+        println!("Cargo will fetch crates.io dependencies from `{name}`");
+    }
+    println!("To publish, log in with `cargo login --registry {name}`");
+
+    Ok(())
+}
+
+fn do_new_consumer(_global: &Global, new: NewConsumerArgs) -> Result<(), Error> {
+    let r = discover_registry(new.registry)?;
+
+    let (crate_name, version) = new_consumer::choose(&r, &new.crate_name, &new.req)?;
+    let registry_name = match &new.name {
+        Some(name) => name.as_str(),
+        None => r.config.html.suggested_registry_name(),
+    };
+    let consumer = new_consumer::Consumer {
+        crate_name: &crate_name,
+        version: &version,
+        registry_name,
+        index: format!("sparse+{}", r.config.base_url),
+    };
+
+    let out = new
+        .out
+        .unwrap_or_else(|| PathBuf::from(consumer.package_name()));
+    consumer.write(&out)?;
+    println!(
+        "Wrote `{}`, depending on {crate_name} {version} from `{registry_name}`",
+        out.display(),
+    );
+
+    if new.build {
+        new_consumer::build(&out, registry_name, None)?;
+        println!("Cargo resolved and built {crate_name} {version} from `{registry_name}`");
+    }
+
+    Ok(())
+}
+
+fn do_selftest(global: &Global, selftest: SelftestArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, selftest.registry)?;
+
+    let url = selftest.url.map(|mut url| {
+        ensure_last_segment_empty(&mut url);
+        url
+    });
+    let registry_name = match &selftest.name {
+        Some(name) => name.as_str(),
+        None => r.config.html.suggested_registry_name(),
+    };
+    let options = selftest::Options {
+        url: url.as_ref(),
+        token: selftest.token.as_deref(),
+        registry_name,
+        build: !selftest.no_build,
+        keep: selftest.keep,
+    };
+
+    selftest::run(global, &r, &options)?;
+    println!("The registry works");
+
+    Ok(())
+}
+
+#[cfg(feature = "publish-workspace")]
+fn do_publish_workspace(_global: &Global, publish: PublishWorkspaceArgs) -> Result<(), Error> {
+    use publish_workspace::{Index, Workspace};
+
+    let workspace = Workspace::detect(publish.registry.as_deref())?;
+    let registry = &workspace.registry;
+
+    let members = workspace.members()?;
+    let members = publish_workspace::select(members, &publish.include, &publish.exclude);
+    let members = publish_workspace::order(members)?;
+    if members.is_empty() {
+        println!("No members of the workspace may be published to `{registry}`");
+    }
+
+    let token = publish_workspace::token_for(registry);
+    let index = Index::new(workspace.base_url.clone(), token);
+    let timeout = std::time::Duration::from_secs(publish.timeout_secs);
+
+    for m in &members {
+        let (name, version) = (&m.name, &m.version);
+
+        if index.has(name, version)? {
+            println!("{name} {version} is already in `{registry}`");
+        } else if publish.dry_run {
+            println!("Would publish {name} {version}");
+        } else {
+            println!("Publishing {name} {version}");
+            workspace.publish(m)?;
+            index.wait_for(name, version, timeout)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn do_conflicts(global: &Global, conflicts: ConflictsArgs) -> Result<(), Error> {
+    match conflicts.command {
+        ConflictsCommand::List(list) => {
+            let r = discover_registry(list.registry)?;
+
+            let conflicts = r.conflicts().map_err(ConflictError::from)?;
+            if conflicts.is_empty() {
+                println!("No conflicts");
+            }
+
+            for c in conflicts {
+                println!("{} {} (since {})", c.name, c.vers, c.detected_at);
+                for (source, cksum) in &c.checksums {
+                    println!("  {source}: {cksum}");
+                }
+            }
+        }
+
+        ConflictsCommand::Resolve(resolve) => {
+            let r = discover_writable_registry(global, resolve.registry)?;
+
+            r.resolve_conflict(resolve.name, resolve.version, resolve.remove)?;
+            r.maybe_generate_html()?;
+            r.maybe_generate_feed()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn do_quarantine(global: &Global, quarantine: QuarantineArgs) -> Result<(), Error> {
+    let Some(CrateVersion { name, version }) = quarantine.crate_version else {
+        let r = discover_registry(quarantine.registry)?;
+
+        let withheld = r.withheld().map_err(QuarantineError::from)?;
+        if withheld.is_empty() {
+            println!("No quarantined versions");
+        }
+
+        for w in withheld {
+            println!("{} {} (since {})", w.name, w.vers, w.quarantined_at);
+            if let Some(reason) = w.reason {
+                println!("  {reason}");
+            }
+        }
+
+        return Ok(());
+    };
+
+    let r = discover_writable_registry(global, quarantine.registry)?;
+
+    r.quarantine(name, version, quarantine.reason)?;
+    r.maybe_generate_html()?;
+
+    Ok(())
+}
+
+fn do_release(global: &Global, release: ReleaseArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, release.registry)?;
+
+    let CrateVersion { name, version } = release.crate_version;
+    r.release(name, version)?;
+    r.maybe_generate_html()?;
+
+    Ok(())
+}
+
+fn do_visibility(global: &Global, visibility: VisibilityArgs) -> Result<(), Error> {
+    let Some(name) = visibility.name else {
+        let r = discover_registry(visibility.registry)?;
+
+        let levels = r.visibility().map_err(VisibilityError::from)?;
+        if levels.is_empty() {
+            println!("Every crate is public");
+        }
+
+        for (name, level) in levels.iter() {
+            println!("{name} {level}");
+        }
+
+        return Ok(());
+    };
+
+    let Some(level) = visibility.level else {
+        let r = discover_registry(visibility.registry)?;
+
+        let levels = r.visibility().map_err(VisibilityError::from)?;
+        println!("{name} {}", levels.of(name.as_str()));
+
+        return Ok(());
+    };
+
+    let r = discover_writable_registry(global, visibility.registry)?;
+
+    r.set_visibility(name, level)?;
+    r.maybe_generate_html()?;
+    r.maybe_generate_feed()?;
+
+    Ok(())
+}
+
+fn do_list(_global: &Global, list: ListArgs) -> Result<(), Error> {
+    use list_error::*;
+
+    let r = discover_registry(list.registry)?;
+
+    let crates = r.list_all().context(IndexSnafu)?;
+    let updated = listing::last_updated(&r.audit_log().context(AuditSnafu)?);
+
+    #[cfg(feature = "server")]
+    let owned = match list.owner {
+        Some(owner) => {
+            let data_dir = list.data_dir.context(NoDataDirSnafu)?;
+            Some(auth::crates_owned_by(&data_dir, &owner).context(OwnersSnafu)?)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "server"))]
+    let owned = None;
+
+    let filter = listing::Filter {
+        prefix: list.prefix,
+        owned,
+        yanked: list.yanked,
+        updated_since: list.updated_since,
+        msrv_compatible: list.msrv_compatible,
+    };
+    let downloads = daemon_downloads(&r.path);
+    let rows = listing::rows(&crates, &filter, list.sort, &updated, downloads.as_ref());
+
+    if list.json {
+        let rows =
+            serde_json::to_string_pretty(&rows).expect("The versions are always serializable");
+        println!("{rows}");
+        return Ok(());
+    }
+
+    let columns = rows
+        .iter()
+        .map(|row| {
+            [
+                row.name.to_string(),
+                row.vers.to_string(),
+                row.updated
+                    .map_or_else(|| "-".to_owned(), |u| u.to_string()[..10].to_owned()),
+                row.downloads
+                    .map_or_else(|| "-".to_owned(), |d| d.to_string()),
+                if row.yanked { "yanked" } else { "" }.to_owned(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let mut widths = [0; 5];
+    for row in &columns {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = usize::max(*width, column.len());
+        }
+    }
+
+    for [name, version, updated, downloads, yanked] in columns {
+        let [w_n, w_v, w_u, w_d, _] = widths;
+        let line =
+            format!("{name:<w_n$} {version:<w_v$} {updated:<w_u$} {downloads:>w_d$} {yanked}");
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+/// The download counts of the daemon serving the registry, when one is
+/// running.
+#[cfg(any(feature = "p2p", feature = "server"))]
+fn daemon_downloads(registry: &Path) -> Option<listing::Downloads> {
+    use control::{Request, Response};
+
+    match control::request(registry, &Request::Status) {
+        Ok(Response::Status { mut status }) => {
+            serde_json::from_value(status["downloads"].take()).ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(any(feature = "p2p", feature = "server")))]
+fn daemon_downloads(_registry: &Path) -> Option<listing::Downloads> {
+    None
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum ListError {
+    #[snafu(display("Could not read the index"))]
+    Index { source: ListAllError },
+
+    #[snafu(display("Could not read the audit log"))]
+    Audit { source: audit::Error },
+
+    #[cfg(feature = "server")]
+    #[snafu(display("`--owner` needs the `--data-dir` the owners are kept in"))]
+    NoDataDir,
+
+    #[cfg(feature = "server")]
+    #[snafu(display("Could not read the owners"))]
+    Owners { source: auth::Error },
+}
+
+impl ListError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Index { source } => source.code(),
+            Self::Audit { source } => source.code(),
+            #[cfg(feature = "server")]
+            Self::NoDataDir => "E_MISSING_DATA_DIR",
+            #[cfg(feature = "server")]
+            Self::Owners { source } => source.code(),
+        }
+    }
+}
+
+fn do_verify(global: &Global, verify: VerifyArgs) -> Result<(), Error> {
+    use verify_error::*;
+
+    let r = discover_registry(verify.registry)?;
+
+    let mut problems = r.verify(global.progress)?;
+
+    if verify.check_reproducible {
+        let differences = reproducible::check(&r)?;
+        problems.extend(
+            differences
+                .into_iter()
+                .map(|difference| VerifyProblem::NotReproducible { difference }),
+        );
+    }
+
+    for problem in &problems {
+        println!("{problem}");
+    }
+
+    ensure!(
+        problems.is_empty(),
+        FailedSnafu {
+            count: problems.len()
+        }
+    );
+
+    println!("All crates verified");
+
+    Ok(())
+}
+
+fn do_migrate(global: &Global, migrate: MigrateArgs) -> Result<(), Error> {
+    let path = migrate.registry.unwrap_or_else(|| PathBuf::from("."));
+    if !migrate.dry_run {
+        ensure_writable_any_format(global, &path)?;
+    }
+
+    let steps = migrate::migrate(&path, migrate.dry_run)?;
+
+    let verb = if migrate.dry_run {
+        "Would migrate"
+    } else {
+        "Migrated"
+    };
+    for step in &steps {
+        println!("{verb} to format {}: {}", step.to, step.description);
+    }
+    if steps.is_empty() {
+        println!("The registry is already in format {}", migrate::CURRENT);
+    }
+
+    Ok(())
+}
+
+fn do_adopt(global: &Global, adopt: AdoptArgs) -> Result<(), Error> {
+    use verify_error::*;
+
+    let path = adopt.registry.unwrap_or_else(|| PathBuf::from("."));
+
+    if adopt.dry_run {
+        let layout = adopt::inspect(&path)?;
+        println!(
+            "A margo registry at {} with {} crates and {} versions",
+            layout.base_url, layout.crates, layout.versions,
+        );
+        for step in &layout.steps {
+            println!("Would migrate to format {}: {}", step.to, step.description);
+        }
+        return Ok(());
+    }
+
+    ensure_writable_any_format(global, &path)?;
+    let problems = adopt::adopt(&path, global.progress)?;
+
+    for problem in &problems {
+        println!("{problem}");
+    }
+
+    ensure!(
+        problems.is_empty(),
+        FailedSnafu {
+            count: problems.len()
+        }
+    );
+
+    println!("Adopted the registry; all crates verified");
+
+    Ok(())
+}
+
+fn do_diff_registry(_global: &Global, diff: DiffRegistryArgs) -> Result<(), Error> {
+    let a = diff.a.load()?;
+    let b = diff.b.load()?;
+    let differences = registry_diff::diff(&a, &b);
+
+    if diff.json {
+        let d = serde_json::to_string_pretty(&differences)
+            .expect("The differences are always serializable");
+        println!("{d}");
+    } else {
+        for v in &differences.only_in_a {
+            println!("only in {}: {} {}", diff.a, v.name, v.vers);
+        }
+        for v in &differences.only_in_b {
+            println!("only in {}: {} {}", diff.b, v.name, v.vers);
+        }
+        for m in &differences.mismatched {
+            println!(
+                "checksums differ: {} {} ({} vs {})",
+                m.name, m.vers, m.cksum_a, m.cksum_b,
+            );
+        }
+    }
+
+    differences.ensure_same()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "discover")]
+fn do_discover(_global: &Global, discover: DiscoverArgs) -> Result<(), Error> {
+    use do_discover_error::*;
+
+    let address = discover.address;
+    let entry = discovery::resolve(&address).map_err(DoDiscoverError::from)?;
+
+    println!("{}@{}", address.name, address.domain);
+    println!("  index: {}", entry.index);
+    if let Some(pubkey) = &entry.pubkey {
+        println!("  nostr pubkey: {pubkey}");
+    }
+    if let Some(peer_id) = &entry.peer_id {
+        println!("  peer ID: {peer_id}");
+    }
+    for addr in &entry.multiaddrs {
+        println!("  multiaddr: {addr}");
+    }
+
+    let config_path = match discover.cargo_config {
+        Some(path) => path,
+        None => discovery::default_cargo_config().context(CargoHomeSnafu)?,
+    };
+    let name = discover
+        .registry_name
+        .unwrap_or_else(|| address.registry_name());
+
+    let added = discovery::configure_cargo(&config_path, &name, &entry.index)
+        .map_err(DoDiscoverError::from)?;
+
+    if added {
+        println!("Added registry `{name}` to {}", config_path.display());
+    } else {
+        println!("Registry `{name}` is already in {}", config_path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "discover")]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum DoDiscoverError {
+    #[snafu(transparent)]
+    Discovery { source: discovery::Error },
+
+    #[snafu(display("Could not find Cargo's home directory; pass --cargo-config"))]
+    CargoHome,
+}
+
+#[cfg(feature = "discover")]
+impl DoDiscoverError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Discovery { source } => source.code(),
+            Self::CargoHome => "E_CONFIG_READ",
+        }
+    }
+}
+
+#[cfg(feature = "api-client")]
+fn do_api(_global: &Global, api: ApiArgs) -> Result<(), Error> {
+    use do_api_error::*;
+
+    let client = api::Api::new(api.url, api.token);
+
+    let answer = match api.command {
+        ApiCommand::Search(search) => {
+            let hits = client.search(&search.q).map_err(DoApiError::from)?;
+            serde_json::to_value(hits)
+        }
+        ApiCommand::Crate(krate) => {
+            let info = client.get_crate(&krate.name).map_err(DoApiError::from)?;
+            serde_json::to_value(info)
+        }
+        ApiCommand::Publish(publish) => {
+            let path = publish.path;
+            let data = fs::read(&path).context(ReadSnafu { path })?;
+            let warnings = client.publish(&data).map_err(DoApiError::from)?;
+            serde_json::to_value(warnings)
+        }
+        ApiCommand::Yank(yank) => {
+            let CrateVersion { name, version } = yank.crate_version;
+            client
+                .yank(&name, &version, !yank.undo)
+                .map_err(DoApiError::from)?;
+            Ok(serde_json::json!({ "ok": true }))
+        }
+        ApiCommand::Stats(_) => {
+            let stats = client.stats().map_err(DoApiError::from)?;
+            serde_json::to_value(stats)
+        }
+    };
+
+    let answer = answer.expect("Answers are always serializable");
+    let answer = serde_json::to_string_pretty(&answer).expect("JSON is always serializable");
+    println!("{answer}");
+
+    Ok(())
+}
+
+#[cfg(feature = "api-client")]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum DoApiError {
+    #[snafu(transparent)]
+    Api { source: api::Error },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+}
+
+#[cfg(feature = "api-client")]
+impl DoApiError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Api { source } => source.code(),
+            Self::Read { .. } => "E_STORAGE_READ",
+        }
+    }
+}
+
+#[cfg(feature = "python")]
+fn do_python(_global: &Global, python: PythonArgs) -> Result<(), Error> {
+    python::run(&python.script, python.args)?;
+    Ok(())
+}
+
+#[cfg(feature = "nostr")]
+fn do_registry_directory(_global: &Global, directory: RegistryDirectoryArgs) -> Result<(), Error> {
+    let trusted = directory.trusted_pubkeys.into_iter().collect();
+    let mut validator = validation::Validator::new(trusted);
+    let listings = announce::directory(&directory.relays, &mut validator)?;
+    if let Some(summary) = validator.summary() {
+        eprintln!("Warning: {summary}");
+    }
+
+    if directory.json {
+        let listings =
+            serde_json::to_string_pretty(&listings).expect("Listings are always serializable");
+        println!("{listings}");
+        return Ok(());
+    }
+
+    for l in &listings {
+        let a = &l.announcement;
+        println!("{}", a.base_url);
+        println!("  crates: {}", a.crates);
+        println!("  index root: {}", a.merkle_root);
+        println!("  nostr pubkey: {}", l.pubkey);
+        if let Some(peer_id) = &a.peer_id {
+            println!("  peer ID: {peer_id}");
+        }
+        for addr in &a.multiaddrs {
+            println!("  multiaddr: {addr}");
+        }
+        println!("  announced: {} by margo {}", l.announced_at, a.version);
+    }
+
+    if listings.is_empty() {
+        println!("No registries have been announced on these relays");
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "nostr")]
+fn do_key(global: &Global, key: KeyArgs) -> Result<(), Error> {
+    match key.command {
+        KeyCommand::Rotate(rotate) => {
+            let r = discover_writable_registry(global, rotate.registry)?;
+            let rotated = key_rotation::rotate(&r, &rotate.key)?;
+            println!(
+                "Rotated {} to {}",
+                rotated.rotation.old_pubkey, rotated.rotation.new_pubkey,
+            );
+
+            for relay in &rotate.relays {
+                match notify::send(relay, std::slice::from_ref(&rotated.statement)) {
+                    Ok(()) => println!("Published the rotation statement to {relay}"),
+                    Err(e) => eprintln!("Warning: {e}"),
+                }
+            }
+
+            match control::request(&r.path, &control::Request::RotateKey) {
+                Ok(_) => println!("The daemon now signs with the new key"),
+                Err(control::Error::Connect { .. }) => {
+                    println!("No daemon serves the registry; it will sign with the new key")
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "nostr")]
+fn do_quorum(global: &Global, quorum: QuorumArgs) -> Result<(), Error> {
+    match quorum.command {
+        QuorumCommand::List(list) => {
+            let r = discover_registry(list.registry)?;
+            let threshold = r.config.quorum.threshold;
+
+            let pending = quorum::pending(&r)?;
+            for (operation, approvals) in &pending {
+                println!("{operation} ({}/{threshold})", approvals.len());
+                for pubkey in approvals.keys() {
+                    println!("  approved by {pubkey}");
+                }
+            }
+
+            if pending.is_empty() {
+                println!("No operations have been approved");
+            }
+        }
+
+        QuorumCommand::Approve(approve) => {
+            let r = discover_writable_registry(global, approve.registry)?;
+            let operation = approve.operation.join(" ").parse::<quorum::Operation>()?;
+            let signer = quorum::signer(approve.key.as_deref(), approve.bunker.as_deref())?;
+
+            let event = quorum::approve(&r, &*signer, operation.clone())?;
+            println!("Approved `{operation}` as {}", event.pubkey);
+
+            for relay in &approve.relays {
+                match notify::send(relay, std::slice::from_ref(&event)) {
+                    Ok(()) => println!("Published the approval to {relay}"),
+                    Err(e) => eprintln!("Warning: {e}"),
+                }
+            }
+        }
+
+        QuorumCommand::Collect(collect) => {
+            let r = discover_writable_registry(global, collect.registry)?;
+            let added = quorum::collect(&r, &collect.relays)?;
+            println!("Collected {added} new approval(s)");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn do_token(_global: &Global, token: TokenArgs) -> Result<(), Error> {
+    match token.command {
+        TokenCommand::List(list) => {
+            let tokens = auth::list_tokens(&list.data_dir)?;
+
+            let max_u = tokens.iter().map(|t| t.user.to_string().len()).max();
+            let max_u = max_u.unwrap_or(0);
+            let now = timestamp::Timestamp::now();
+
+            for t in tokens {
+                let user = t.user.to_string();
+                let scopes = t.scopes.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+                let scopes = scopes.join(",");
+                let expires = match t.expires_at {
+                    Some(e) if e <= now => format!("expired {e}"),
+                    Some(e) => format!("expires {e}"),
+                    None => "never expires".to_owned(),
+                };
+
+                println!("{id} {user:<max_u$} {expires} {scopes}", id = t.id);
+            }
+        }
+
+        TokenCommand::Revoke(revoke) => {
+            let t = auth::revoke_token(&revoke.data_dir, &revoke.id)?;
+            println!("Revoked token {} of {}", t.id, t.user);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn do_maintenance(_global: &Global, maintenance: MaintenanceArgs) -> Result<(), Error> {
+    match maintenance.command {
+        MaintenanceCommand::Start(start) => {
+            maintenance::start(&start.data_dir, start.reason)?;
+            println!("Publishes are now queued until maintenance ends");
+        }
+
+        MaintenanceCommand::End(end) => {
+            maintenance::end(&end.data_dir)?;
+
+            let queue = publish_queue::list(&end.data_dir)?;
+            let queued = queue.iter().filter(|(_, q)| q.is_ready()).count();
+            println!("Maintenance ended; the daemon will add {queued} queued crate(s)");
+        }
+
+        MaintenanceCommand::Status(status) => {
+            match maintenance::status(&status.data_dir)? {
+                Some(m) => {
+                    println!("Under maintenance since {}", m.started_at);
+                    if let Some(reason) = m.reason {
+                        println!("  {reason}");
+                    }
+                }
+                None => println!("Not under maintenance"),
+            }
+
+            for (id, q) in publish_queue::list(&status.data_dir)? {
+                println!(
+                    "{id} {} {} by {} at {}",
+                    q.name, q.vers, q.user, q.queued_at
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn do_approval(_global: &Global, approval: ApprovalArgs) -> Result<(), Error> {
+    match approval.command {
+        ApprovalCommand::List(list) => {
+            for (id, q) in publish_queue::list(&list.data_dir)? {
+                if !q.is_ready() {
+                    println!(
+                        "{id} {} {} by {} at {}",
+                        q.name, q.vers, q.user, q.queued_at
+                    );
+                    for finding in &q.findings {
+                        println!("  {finding}");
+                    }
+                }
+            }
+        }
+
+        ApprovalCommand::Approve(approve) => {
+            let CrateVersion { name, version } = approve.crate_version;
+            publish_queue::approve(&approve.data_dir, &name, &version, &approve.reviewer)?;
+            println!("Approved {name} {version}; the daemon will add it");
+        }
+
+        ApprovalCommand::Reject(reject) => {
+            let CrateVersion { name, version } = reject.crate_version;
+            publish_queue::reject(&reject.data_dir, &name, &version)?;
+            println!("Rejected {name} {version}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn do_usage(_global: &Global, usage: UsageArgs) -> Result<(), Error> {
+    let month = usage
+        .month
+        .unwrap_or_else(|| usage::month_of(timestamp::Timestamp::now()));
+
+    // The quotas are in the server configuration, not the data directory
+    let report = usage::read(&usage.data_dir.join(usage::FILE_NAME))?;
+    let report = report.report(&month, &Default::default());
+
+    if usage.json {
+        let report =
+            serde_json::to_string_pretty(&report).expect("Reports are always serializable");
+        println!("{report}");
+        return Ok(());
+    }
+
+    println!("Downloads in {month}");
+    for team in report.teams {
+        println!("{} {}", team.team, team.downloads);
+        for (user, downloads) in team.users {
+            println!("  {user} {downloads}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "sync-crates-io")]
+fn do_sync(global: &Global, sync: SyncArgs) -> Result<(), Error> {
+    use sync_error::*;
+
+    let r = discover_writable_registry(global, sync.registry)?;
+
+    let client = crates_io::Client::new();
+
+    let rules = rules::Rules {
+        include: sync.include,
+        exclude: sync.exclude,
+    };
+
+    let cursor_path = r.sync_cursor_path();
+    let mut cursor = sync_cursor::Cursor::load(&cursor_path).map_err(SyncError::from)?;
+    if sync.full {
+        cursor.reset();
+    }
+
+    let quarantined = r.conflicts().map_err(ConflictError::from)?;
+
+    let mut sources = vec![crates_io::CRATES_IO_DL.to_owned()];
+    sources.extend(sync.sources);
+
+    let done = cursor.resume(&sync.crates);
+    if done > 0 {
+        println!("Resuming an interrupted sync after {done} crates");
+    }
+
+    let remaining = &sync.crates[done..];
+    let mut progress =
+        progress::Progress::new(global.progress, "sync", Some(remaining.len() as u64));
+
+    for crate_name in remaining {
+        let owners = if rules.needs_owners() {
+            client.fetch_owners(crate_name).context(FetchOwnersSnafu {
+                crate_name: crate_name.as_str(),
+            })?
+        } else {
+            vec![]
+        };
+        let subject = rules::Subject {
+            name: crate_name,
+            owners: &owners,
+            pubkey: None,
+        };
+        if !rules.allows(&subject) {
+            progress.println(format_args!(
+                "Skipping `{crate_name}`, which the mirroring rules exclude"
+            ));
+            cursor.finish_crate(crate_name, None);
+            cursor.save(&cursor_path).map_err(SyncError::from)?;
+            progress.inc(crate_name);
+            continue;
+        }
+
+        progress.println(format_args!("Syncing `{crate_name}` from crates.io..."));
+
+        let versions = client
+            .fetch_versions(crate_name)
+            .context(FetchVersionsSnafu {
+                crate_name: crate_name.as_str(),
+            })?;
+
+        let watermark = cursor.watermark(crate_name);
+        let newest_id = versions.iter().map(|v| v.id).max();
+        let versions = versions
+            .iter()
+            .filter(|v| watermark.map_or(true, |w| v.id > w))
+            .collect::<Vec<_>>();
+
+        let crate_name_typed = crate_name
+            .parse::<common::CrateName>()
+            .context(CrateNameSnafu {
+                crate_name: crate_name.as_str(),
+            })?;
+
+        let known: BTreeMap<semver::Version, String> = r
+            .list_all()
+            .context(ListSnafu)?
+            .get(&crate_name_typed)
+            .map(|idx| {
+                idx.values()
+                    .map(|e| (e.vers.clone(), e.cksum.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for version in versions {
+            let is_quarantined = quarantined
+                .iter()
+                .any(|c| c.name == crate_name_typed && c.vers == version.num);
+            if is_quarantined {
+                progress.println(format_args!(
+                    "  {crate_name} {} is quarantined, skipping",
+                    version.num
+                ));
+                continue;
+            }
+
+            if let Some(cksum) = known.get(&version.num) {
+                if !cksum.eq_ignore_ascii_case(&version.checksum) {
+                    let checksums = [
+                        ("local".to_owned(), cksum.clone()),
+                        ("crates.io".to_owned(), version.checksum.clone()),
+                    ];
+                    let (name, vers) = (crate_name_typed.clone(), version.num.clone());
+                    r.record_conflict(name, vers, checksums.into())?;
+                    continue;
+                }
+
+                progress.println(format_args!(
+                    "  {crate_name} {} already in registry, skipping",
+                    version.num
+                ));
+                continue;
+            }
+
+            progress.println(format_args!(
+                "  Downloading {crate_name} {}...",
+                version.num
+            ));
+            let (crate_data, url) = client
+                .download_first(&sources, &crate_name_typed, &version.num, &version.checksum)
+                .context(DownloadSnafu {
+                    crate_name: crate_name.as_str(),
+                    version: version.num.to_string(),
+                })?;
+            progress.add_bytes(crate_data.len() as u64);
+            if sources.len() > 1 {
+                progress.println(format_args!(
+                    "  Downloaded {crate_name} {} from {url}",
+                    version.num
+                ));
+            }
+
+            if let Err(e) = r.check_size(&crate_data) {
+                progress.println(format_args!(
+                    "  {crate_name} {}: {e}, skipping",
+                    version.num
+                ));
+                continue;
+            }
+
+            let tmp_path = std::env::temp_dir()
+                .join(format!("{}-{}.crate", crate_name, version.num));
+            fs::write(&tmp_path, &crate_data).context(WriteTmpSnafu { path: &tmp_path })?;
+
+            r.add(global, &tmp_path)?;
+
+            if let Err(e) = fs::remove_file(&tmp_path) {
+                eprintln!(
+                    "Warning: could not remove temporary file {}: {e}",
+                    tmp_path.display(),
+                );
+            }
+        }
+
+        cursor.finish_crate(crate_name, newest_id);
+        cursor.save(&cursor_path).map_err(SyncError::from)?;
+        progress.inc(crate_name);
+    }
+    drop(progress);
+
+    cursor.finish_run();
+    cursor.save(&cursor_path).map_err(SyncError::from)?;
+
+    r.maybe_generate_html()?;
+    r.maybe_generate_feed()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "sync-crates-io")]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum SyncError {
+    #[snafu(display("Could not fetch versions for `{crate_name}` from crates.io"))]
+    FetchVersions {
+        source: crates_io::Error,
+        crate_name: String,
+    },
+
+    #[snafu(display("Could not fetch the owners of `{crate_name}` from crates.io"))]
+    FetchOwners {
+        source: crates_io::Error,
+        crate_name: String,
+    },
+
+    #[snafu(display("Invalid crate name `{crate_name}`"))]
+    CrateName {
+        source: common::CrateNameError,
+        crate_name: String,
+    },
+
+    #[snafu(display("Could not list registry contents"))]
+    List { source: ListAllError },
+
+    #[snafu(display("Could not download `{crate_name}` v{version} from crates.io"))]
+    Download {
+        source: crates_io::Error,
+        crate_name: String,
+        version: String,
+    },
+
+    #[snafu(display("Could not write temporary crate file to {}", path.display()))]
+    WriteTmp { source: io::Error, path: PathBuf },
+
+    #[snafu(transparent)]
+    Cursor { source: sync_cursor::Error },
+}
+
+#[cfg(feature = "sync-crates-io")]
+impl SyncError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::FetchVersions { .. } | Self::FetchOwners { .. } | Self::Download { .. } => {
+                "E_UPSTREAM"
+            }
+            Self::CrateName { .. } => "E_BAD_CRATE_NAME",
+            Self::List { source } => source.code(),
+            Self::WriteTmp { .. } => "E_STORAGE_WRITE",
+            Self::Cursor { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(feature = "sync-crates-io")]
+fn do_mirror(global: &Global, mirror: MirrorArgs) -> Result<(), Error> {
+    match mirror.command {
+        MirrorCommand::Verify(verify) => do_mirror_verify(global, verify),
+    }
+}
+
+#[cfg(feature = "sync-crates-io")]
+fn do_mirror_verify(global: &Global, verify: MirrorVerifyArgs) -> Result<(), Error> {
+    let r = discover_registry(verify.registry)?;
+
+    let upstream = match verify.upstream {
+        Some(url) => url,
+        None => mirror::CRATES_IO_INDEX
+            .parse()
+            .expect("The crates.io index URL is valid"),
+    };
+    let label = upstream.to_string();
+    let upstream = mirror::Upstream::connect(upstream)?;
+
+    let problems = mirror::verify(&r, &upstream, &verify.crates, global.progress)?;
+
+    for problem in &problems {
+        println!("{problem}");
+
+        if let mirror::Problem::Checksum {
+            name,
+            version,
+            local,
+            upstream,
+        } = problem
+        {
+            let checksums = [
+                ("local".to_owned(), local.clone()),
+                (label.clone(), upstream.clone()),
+            ];
+            r.record_conflict(name.clone(), version.clone(), checksums.into())?;
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(mirror::Error::Failed {
+            count: problems.len(),
+        }
+        .into());
+    }
+
+    println!("The mirror matches its upstream");
+
+    Ok(())
+}
+
+#[cfg(feature = "replicate")]
+fn do_replicate(global: &Global, replicate: ReplicateArgs) -> Result<(), Error> {
+    match replicate.command {
+        ReplicateCommand::Run(run) => do_replicate_run(global, run),
+        ReplicateCommand::Status(status) => do_replicate_status(global, status),
+        ReplicateCommand::Promote(promote) => do_replicate_promote(global, promote),
+    }
+}
+
+#[cfg(feature = "replicate")]
+fn do_replicate_run(global: &Global, run: ReplicateRunArgs) -> Result<(), Error> {
+    // Replication is how a read-only standby stays current, so it is
+    // not refused
+    let r = discover_registry(run.registry)?;
+
+    let path = r.replication_path();
+    let mut state = replication::State::follow(&path, run.primary)?;
+    let primary = replication::Primary::new(state.primary.clone(), run.token);
+
+    println!("Following {}", state.primary);
+
+    loop {
+        let applied = replication::catch_up(&r, global, &primary, &mut state, &path)?;
+
+        if applied > 0 {
+            r.maybe_generate_html()?;
+            r.maybe_generate_feed()?;
+        }
+
+        if applied > 0 || run.once {
+            let lag = state.lag();
+            println!(
+                "Applied {applied} change(s); up to entry {} of {}, {} behind",
+                state.applied_seq, state.primary_seq, lag.entries,
+            );
+        }
+
+        if run.once {
+            return Ok(());
+        }
+
+        std::thread::sleep(std::time::Duration::from_secs(run.interval));
+    }
+}
+
+#[cfg(feature = "replicate")]
+fn do_replicate_status(_global: &Global, status: ReplicateStatusArgs) -> Result<(), Error> {
+    let r = discover_registry(status.registry)?;
+
+    let state =
+        replication::State::load(&r.replication_path())?.ok_or(replication::Error::NotStandby)?;
+    let lag = state.lag();
+
+    println!("primary: {}", state.primary);
+    println!("applied: {} of {}", state.applied_seq, state.primary_seq);
+    println!("lag: {} change(s), {} second(s)", lag.entries, lag.seconds);
+    if let Some(checked_at) = state.checked_at {
+        println!("checked at: {checked_at}");
+    }
+    if let Some(promoted_at) = state.promoted_at {
+        println!("promoted at: {promoted_at}");
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "replicate")]
+fn do_replicate_promote(global: &Global, promote: ReplicatePromoteArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, promote.registry)?;
+
+    let path = r.replication_path();
+    let mut state = replication::State::load(&path)?.ok_or(replication::Error::NotStandby)?;
+    if state.promoted_at.is_none() {
+        state.promoted_at = Some(timestamp::Timestamp::now());
+        state.save(&path)?;
+    }
+
+    let lag = state.lag();
+    println!(
+        "Promoted; {} change(s) of {} had not been applied at the last check",
+        lag.entries, state.primary,
+    );
+
+    Ok(())
+}
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+fn do_serve(global: &'static Global, serve: ServeArgs) -> Result<(), Error> {
+    let tenants = match &serve.config {
+        Some(config) => tenant::load(config).map_err(ServeError::from)?,
+        None => {
+            let r = discover_registry(serve.registry)?;
+
+            #[cfg(feature = "p2p")]
+            let listen_addr: libp2p::Multiaddr = {
+                let default_addr = "/ip4/0.0.0.0/tcp/0";
+                let addr_str = serve.listen.as_deref().unwrap_or(default_addr);
+
+                addr_str.parse().map_err(|e| ServeError::ParseListenAddr {
+                    source: e,
+                    addr: addr_str.to_owned(),
+                })?
+            };
+
+            vec![tenant::Tenant::single(
+                r,
+                #[cfg(feature = "p2p")]
+                listen_addr,
+            )]
+        }
+    };
+
+    for t in &tenants {
+        println!(
+            "Serving `{}` at {}",
+            t.registry().path.display(),
+            t.base_path()
+        );
+        if let Some(key) = &t.nostr_key {
+            println!("  nostr key: {}", key.display());
+        }
+
+        // Connects to a remote signer before the first event needs it
+        #[cfg(feature = "nostr")]
+        if let Some(signer) = &t.nostr_signer {
+            match signer.public_key() {
+                Ok(pubkey) => println!("  nostr pubkey: {}", pubkey.to_hex()),
+                Err(e) => eprintln!("Warning: {e}"),
+            }
+        }
+
+        #[cfg(feature = "nostr")]
+        if let Some(announcer) = &t.announcer {
+            announcer
+                .clone()
+                .start(t.registry.clone(), t.status.clone());
+        }
+
+        #[cfg(feature = "download-mirrors")]
+        if let Some(mirrors) = &t.download_mirrors {
+            mirrors.clone().start();
+        }
+
+        #[cfg(feature = "notifications")]
+        if let Some(notifications) = &t.notifications {
+            notifications.clone().start(t.registry.clone());
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(usage) = &t.usage {
+            usage.clone().start();
+        }
+
+        #[cfg(feature = "server")]
+        server::start_queue_worker(t.clone(), global);
+
+        #[cfg(feature = "server")]
+        server::start_demotion_worker(t.clone());
+
+        #[cfg(feature = "server")]
+        server::start_catalog_loader(t.clone());
+
+        #[cfg(feature = "server")]
+        server::start_regenerator(t.clone());
+
+        #[cfg(feature = "server")]
+        server::start_docs_worker(t.clone());
+    }
+
+    #[cfg(feature = "server")]
+    let http_addr = serve.http.unwrap_or(ServeArgs::DEFAULT_HTTP);
+
+    // Each tenant's control socket passes requests for its P2P node on
+    #[cfg(feature = "p2p")]
+    let (peers, peer_commands): (Vec<_>, Vec<_>) = tenants
+        .iter()
+        .map(|_| tokio::sync::mpsc::channel(16))
+        .unzip();
+    #[cfg(feature = "p2p")]
+    let mut peers = peers.into_iter();
+
+    let daemons = tenants
+        .iter()
+        .map(|t| control::Daemon {
+            tenant: t.clone(),
+            server_config: serve.config.clone(),
+            reloading: Default::default(),
+            #[cfg(feature = "p2p")]
+            peers: peers.next().expect("There is a channel for each tenant"),
+        })
+        .collect::<Vec<_>>();
+
+    let rt = tokio::runtime::Runtime::new().map_err(|source| ServeError::Runtime { source })?;
+
+    // Dropped before the runtime, so the last spans are sent
+    #[cfg(feature = "telemetry")]
+    let _telemetry = {
+        let _entered = rt.enter();
+        telemetry::init(serve.otlp_endpoint.as_ref()).map_err(ServeError::from)?
+    };
+
+    rt.block_on(async {
+        let p2p = async {
+            #[cfg(feature = "p2p")]
+            let res = {
+                let nodes = tenants.iter().zip(peer_commands).map(|(t, peer_commands)| {
+                    p2p::start_node(
+                        t.p2p_listen.clone(),
+                        t.registry.clone(),
+                        validation::Validator::new(
+                            t.registry().config.announcements.trusted_peers.clone(),
+                        ),
+                        peer_commands,
+                        t.status.clone(),
+                    )
+                });
+
+                libp2p::futures::future::try_join_all(nodes)
+                    .await
+                    .map(drop)
+                    .map_err(ServeError::from)
+            };
+
+            #[cfg(not(feature = "p2p"))]
+            let res = std::future::pending::<Result<(), ServeError>>().await;
+
+            res
+        };
+
+        let http = async {
+            #[cfg(feature = "server")]
+            let res = server::run(http_addr, tenants.clone(), global)
+                .await
+                .map_err(ServeError::from);
+
+            #[cfg(not(feature = "server"))]
+            let res = {
+                _ = global;
+                std::future::pending::<Result<(), ServeError>>().await
+            };
+
+            res
+        };
+
+        let reload = reload::watch(daemons.clone());
+
+        let control = async {
+            let mut sockets = tokio::task::JoinSet::new();
+            for daemon in daemons {
+                sockets.spawn(control::run(daemon));
+            }
+
+            // Sockets only stop on error
+            match sockets.join_next().await {
+                Some(Ok(res)) => res.map_err(ServeError::from),
+                _ => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            res = p2p => res,
+            res = http => res,
+            res = control => res,
+            res = reload => res.map_err(ServeError::from),
+        }
+    })?;
+
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+impl ServeArgs {
+    const DEFAULT_HTTP: std::net::SocketAddr =
+        std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 8080);
+}
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+#[derive(Debug, Snafu)]
+enum ServeError {
+    #[cfg(feature = "p2p")]
+    #[snafu(display("Could not parse listen address `{addr}`"))]
+    ParseListenAddr {
+        source: libp2p::multiaddr::Error,
+        addr: String,
+    },
+
+    #[snafu(display("Could not create the async runtime"))]
+    Runtime { source: io::Error },
+
+    #[snafu(transparent)]
+    Open { source: DiscoverRegistryError },
+
+    #[snafu(transparent)]
+    Tenant { source: tenant::Error },
+
+    #[cfg(feature = "p2p")]
+    #[snafu(transparent)]
+    P2p { source: p2p::P2pError },
+
+    #[snafu(transparent)]
+    Control { source: control::Error },
+
+    #[snafu(transparent)]
+    Reload { source: reload::Error },
+
+    #[cfg(feature = "server")]
+    #[snafu(transparent)]
+    Http { source: server::Error },
+
+    #[cfg(feature = "telemetry")]
+    #[snafu(transparent)]
+    Telemetry { source: telemetry::Error },
+}
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+impl ServeError {
+    fn code(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "p2p")]
+            Self::ParseListenAddr { .. } => "E_BAD_ADDRESS",
+            Self::Runtime { .. } => "E_RUNTIME",
+            Self::Open { source } => source.code(),
+            Self::Tenant { source } => source.code(),
+            #[cfg(feature = "p2p")]
+            Self::P2p { source } => source.code(),
+            Self::Control { source } => source.code(),
+            Self::Reload { source } => source.code(),
+            #[cfg(feature = "server")]
+            Self::Http { source } => source.code(),
+            #[cfg(feature = "telemetry")]
+            Self::Telemetry { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(feature = "p2p")]
+fn do_transfers(_global: &Global, transfers: TransfersArgs) -> Result<(), Error> {
+    use do_transfers_error::*;
+    use status::Direction;
+
+    let base_url = match transfers.url {
+        Some(url) => url,
+        None => Url::parse("http://127.0.0.1:8080/").expect("The default URL is valid"),
+    };
+    let url = base_url.join("api/v1/transfers").context(UrlSnafu)?;
+
+    let mut request = ureq::request_url("GET", &url);
+    if let Some(token) = &transfers.token {
+        request = request.set("Authorization", token);
+    }
+    let list: Vec<status::Transfer> = request
+        .call()
+        .context(RequestSnafu { url: url.clone() })?
+        .into_json()
+        .context(ParseSnafu { url })?;
+
+    if transfers.json {
+        let list = serde_json::to_string_pretty(&list).expect("Transfers are always serializable");
+        println!("{list}");
+        return Ok(());
+    }
+
+    for t in &list {
+        let peer = t.peer.as_deref().unwrap_or("an unknown peer");
+        let to_or_from = match t.direction {
+            Direction::Send => "to",
+            Direction::Receive => "from",
+        };
+        println!("{} {} {to_or_from} {peer}", t.direction, t.what);
+
+        let Some(total) = t.total else {
+            println!("  not started");
+            continue;
+        };
+        println!(
+            "  {} of {total} bytes, {} bytes/s",
+            t.bytes, t.bytes_per_sec
+        );
+        if let Some(eta) = t.eta_secs {
+            println!("  {eta} second(s) left");
+        }
+    }
+
+    if list.is_empty() {
+        println!("No transfers are in progress");
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "p2p")]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum DoTransfersError {
+    #[snafu(display("Could not build the daemon's transfers URL"))]
+    Url { source: url::ParseError },
+
+    #[snafu(display("Could not fetch {url}"))]
+    Request {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: Url,
+    },
+
+    #[snafu(display("Could not parse the transfers from {url}"))]
+    Parse { source: io::Error, url: Url },
+}
+
+#[cfg(feature = "p2p")]
+impl DoTransfersError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Url { .. } | Self::Request { .. } | Self::Parse { .. } => "E_UPSTREAM",
+        }
+    }
+}
+
+#[cfg(any(feature = "p2p", feature = "server"))]
+fn do_daemon(_global: &Global, daemon: DaemonArgs) -> Result<(), Error> {
+    use control::{Request, Response};
+
+    let r = discover_registry(daemon.registry)?;
+
+    let request = match daemon.command {
+        DaemonCommand::Status(_) => Request::Status,
+        DaemonCommand::Sync(_) => Request::Sync,
+        DaemonCommand::Reload(_) => Request::Reload,
+        DaemonCommand::RotateKey(_) => Request::RotateKey,
+    };
+
+    match control::request(&r.path, &request)? {
+        Response::Status { status } => {
+            let status =
+                serde_json::to_string_pretty(&status).expect("The status is always serializable");
+            println!("{status}");
+        }
+        Response::Syncing { peers } => println!("Asked {peers} peer(s) for what they have"),
+        Response::Key { pubkey } => println!("The daemon now signs as {pubkey}"),
+        _ => println!("Done"),
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "tui")]
+fn do_top(_global: &Global, top: TopArgs) -> Result<(), Error> {
+    let r = discover_registry(top.registry)?;
+    let interval = std::time::Duration::from_millis(top.interval_ms.unwrap_or(1000));
+
+    top::run(&r.path, interval)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "p2p")]
+fn do_peer(_global: &Global, peer: PeerArgs) -> Result<(), Error> {
+    use control::{PeerInfo, Request, Response};
+
+    fn print_peer(p: &PeerInfo) {
+        let state = match (p.connected, p.banned) {
+            (_, true) => "banned",
+            (true, false) => "connected",
+            (false, false) => "disconnected",
+        };
+        println!("{} ({state})", p.peer_id);
+        if let Some(address) = &p.address {
+            println!("  address: {address}");
+        }
+        if let Some(agent) = &p.agent {
+            println!("  agent: {agent}");
+        }
+        if let Some(rtt) = p.rtt_ms {
+            println!("  ping: {rtt} ms");
+        }
+        if !p.topics.is_empty() {
+            println!("  topics: {}", p.topics.join(", "));
+        }
+    }
+
+    let registry = |path| -> Result<PathBuf, Error> { Ok(discover_registry(path)?.path) };
+
+    match peer.command {
+        PeerCommand::List(list) => {
+            let response = control::request(&registry(list.registry)?, &Request::PeerList)?;
+            let Response::Peers { peers } = response else {
+                return Ok(());
+            };
+
+            if list.json {
+                let peers =
+                    serde_json::to_string_pretty(&peers).expect("Peers are always serializable");
+                println!("{peers}");
+                return Ok(());
+            }
+
+            for p in &peers {
+                print_peer(p);
+            }
+            if peers.is_empty() {
+                println!("No peers are connected");
+            }
+        }
+
+        PeerCommand::Dial(dial) => {
+            let request = Request::PeerDial {
+                addr: dial.addr.clone(),
+            };
+            control::request(&registry(dial.registry)?, &request)?;
+            println!("Dialing {}", dial.addr);
+        }
+
+        PeerCommand::Ban(ban) => {
+            let request = Request::PeerBan {
+                peer_id: ban.peer_id.clone(),
+            };
+            control::request(&registry(ban.registry)?, &request)?;
+            println!("Banned {}", ban.peer_id);
+        }
+
+        PeerCommand::Info(info) => {
+            let request = Request::PeerInfo {
+                peer_id: info.peer_id,
+            };
+            let response = control::request(&registry(info.registry)?, &request)?;
+            let Response::Peer(p) = response else {
+                return Ok(());
+            };
+
+            if info.json {
+                let p = serde_json::to_string_pretty(&p).expect("Peers are always serializable");
+                println!("{p}");
+            } else {
+                print_peer(&p);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "p2p")]
+fn do_pin(global: &Global, pin: PinArgs) -> Result<(), Error> {
+    use pin_error::*;
+
+    let Some(target) = pin.target else {
+        let r = discover_registry(pin.registry)?;
+
+        let pins = pins::read(&r.path.join(pins::FILE_NAME)).map_err(PinError::from)?;
+        if pins.is_empty() {
+            println!("No crates are pinned");
+        }
+
+        // Only a running daemon knows how many peers hold them
+        let status = match control::request(&r.path, &control::Request::Status) {
+            Ok(control::Response::Status { status }) => Some(status),
+            _ => None,
+        };
+
+        for p in pins {
+            let target = p.target();
+            println!("{target} on {} peer(s) (since {})", p.replicas, p.pinned_at);
+            let Some(status) = &status else {
+                continue;
+            };
+            for (version, replication) in &status.pins {
+                let Some((name, vers)) = version.split_once('@') else {
+                    continue;
+                };
+                let vers = vers.parse::<Version>();
+                if vers.is_ok_and(|vers| p.covers(name, &vers)) {
+                    println!("  {version} is held by {} peer(s)", replication.providers);
+                }
+            }
+        }
+
+        return Ok(());
+    };
+
+    let r = discover_writable_registry(global, pin.registry)?;
+
+    let index_path = r.index_file_path_for(&target.name);
+    let index = Registry::parse_index_file(&index_path).context(IndexSnafu)?;
+    if let Some(vers) = &target.vers {
+        ensure!(index.contains_key(vers), VersionSnafu);
+    }
+
+    let replicas = pin.replicas.unwrap_or(pins::DEFAULT_REPLICAS);
+    let p = pins::Pin {
+        name: target.name.clone(),
+        vers: target.vers.clone(),
+        replicas,
+        pinned_at: timestamp::Timestamp::now(),
+    };
+    pins::set(&r.path.join(pins::FILE_NAME), p).map_err(PinError::from)?;
+    println!("Pinned {target} on {replicas} peer(s)");
+
+    Ok(())
+}
+
+#[cfg(feature = "p2p")]
+fn do_unpin(global: &Global, unpin: UnpinArgs) -> Result<(), Error> {
+    let r = discover_writable_registry(global, unpin.registry)?;
+
+    pins::remove(&r.path.join(pins::FILE_NAME), &unpin.target).map_err(PinError::from)?;
+    println!("Unpinned {}", unpin.target);
+
+    Ok(())
+}
+
+#[cfg(feature = "p2p")]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum PinError {
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: ParseIndexError },
+
+    #[snafu(display("The version does not exist in the index"))]
+    Version,
+
+    #[snafu(transparent)]
+    Pins { source: pins::Error },
+}
+
+#[cfg(feature = "p2p")]
+impl PinError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Index { .. } => "E_INDEX_CORRUPT",
+            Self::Version => "E_VERSION_NOT_FOUND",
+            Self::Pins { source } => source.code(),
+        }
+    }
+}
+
+fn discover_registry(path: Option<PathBuf>) -> Result<Registry, DiscoverRegistryError> {
+    use discover_registry_error::*;
+
+    match path {
+        Some(p) => Registry::open(p).context(OpenSnafu),
+        None => {
+            let cwd = env::current_dir().context(CurrentDirSnafu)?;
+
+            match Registry::open(cwd) {
+                Ok(r) => Ok(r),
+                Err(e) if e.is_not_found() => FallbackNotFoundSnafu.fail(),
+                Err(e) => Err(e).context(FallbackOpenSnafu)?,
+            }
+        }
+    }
+}
+
+/// Like [`discover_registry`], but refuses a registry that is
+/// read-only.
+fn discover_writable_registry(
+    global: &Global,
+    path: Option<PathBuf>,
+) -> Result<Registry, DiscoverRegistryError> {
+    use discover_registry_error::*;
+
+    let r = discover_registry(path)?;
+    ensure!(!r.is_read_only(global), ReadOnlySnafu { path: &r.path });
+
+    Ok(r)
+}
+
+/// Refuses a read-only registry in any format, for the commands that
+/// upgrade registries [`Registry::open`] does not accept yet.
+fn ensure_writable_any_format(global: &Global, path: &Path) -> Result<(), DiscoverRegistryError> {
+    use discover_registry_error::*;
+
+    let r = Registry::open_any_format(path).context(OpenSnafu)?;
+    ensure!(!r.is_read_only(global), ReadOnlySnafu { path });
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum DiscoverRegistryError {
+    #[snafu(display("Could not open the specified registry"))]
+    Open { source: OpenError },
+
+    #[snafu(display("Could not determine the current directory, {}", Self::TRY_THIS))]
+    CurrentDir { source: io::Error },
+
+    #[snafu(display(
+        "The current directory does not contain a registry, {}",
+        Self::TRY_THIS,
+    ))]
+    FallbackNotFound,
+
+    #[snafu(display("Could not open the registry in the current directory"))]
+    FallbackOpen { source: OpenError },
+
+    #[snafu(display("The registry at {} is read-only", path.display()))]
+    ReadOnly { path: PathBuf },
+}
+
+impl DiscoverRegistryError {
+    const TRY_THIS: &'static str = "please use the `--registry` command line option";
+
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Open { source } | Self::FallbackOpen { source } => source.code(),
+            Self::CurrentDir { .. } => "E_CURRENT_DIR",
+            Self::FallbackNotFound => "E_REGISTRY_NOT_FOUND",
+            Self::ReadOnly { .. } => "E_READ_ONLY",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Registry {
+    path: PathBuf,
+    config: ConfigV1,
+}
+
+type ListAll = BTreeMap<CrateName, Index>;
+
+/// The version Cargo would pick by default: the greatest one that is
+/// not yanked.
+#[cfg(feature = "html")]
+fn newest_version(index: &Index) -> Option<&Version> {
+    index.iter().rfind(|(_, c)| !c.yanked).map(|(v, _)| v)
+}
+
+/// What a version requirement resolves to.
+#[derive(Debug, Serialize)]
+struct Resolution<'a> {
+    req: &'a VersionReq,
+
+    /// The version Cargo would pick: the greatest match that is not
+    /// yanked.
+    best: Option<&'a index_entry::Root>,
+
+    /// Every match that is not yanked, newest first.
+    matches: Vec<&'a Version>,
+}
+
+/// As in Cargo, prereleases only match requirements that name a
+/// prerelease of the same version.
+fn resolve_versions<'a>(index: &'a Index, req: &'a VersionReq) -> Resolution<'a> {
+    let matches = index
+        .values()
+        .rev()
+        .filter(|e| !e.yanked && req.matches(&e.vers))
+        .collect::<Vec<_>>();
+
+    Resolution {
+        req,
+        best: matches.first().copied(),
+        matches: matches.iter().map(|e| &e.vers).collect(),
+    }
+}
+
+impl Registry {
+    fn initialize(config: ConfigV1, path: impl Into<PathBuf>) -> Result<Self, InitializeError> {
+        use initialize_error::*;
+
+        let config = config.normalize();
+        let path = long_path(path.into());
+
+        println!("Initializing registry in `{}`", path.display());
+
+        fs::create_dir_all(&path).context(RegistryCreateSnafu)?;
+
+        let config_toml_path = path.join(CONFIG_FILE_NAME);
+        let config = Config::V1(config);
+        let config_toml = toml::to_string(&config).context(ConfigTomlSerializeSnafu)?;
+        fs::write(&config_toml_path, config_toml).context(ConfigTomlWriteSnafu {
+            path: &config_toml_path,
+        })?;
+
+        let Config::V1(config) = config;
+
+        let dl = format!(
+            "{base_url}crates/{{lowerprefix}}/{{crate}}/{{version}}.crate",
+            base_url = config.base_url,
+        );
+        let auth_required = config.auth_required;
+
+        let this = Self { path, config };
+
+        let config_json_path = this.config_json_path();
+        let config_json = config_json::Root {
+            dl,
+            api: None,
+            auth_required,
+        };
+        let config_json = serde_json::to_string(&config_json).context(ConfigJsonSerializeSnafu)?;
+        fs::write(&config_json_path, config_json).context(ConfigJsonWriteSnafu {
+            path: &config_json_path,
+        })?;
+
+        Ok(this)
+    }
+
+    fn open(path: impl Into<PathBuf>) -> Result<Self, OpenError> {
+        use open_error::*;
+
+        let this = Self::open_any_format(path)?;
+
+        let format = this.config.format;
+        let path = &this.path;
+        ensure!(
+            format <= migrate::CURRENT,
+            FormatTooNewSnafu { path, format }
+        );
+        ensure!(!adopt::is_upstream(&this), UpstreamSnafu { path });
+        ensure!(
+            format == migrate::CURRENT,
+            FormatTooOldSnafu { path, format }
+        );
+
+        Ok(this)
+    }
+
+    /// Opens the registry whatever the format of its files, for
+    /// [`migrate`].
+    fn open_any_format(path: impl Into<PathBuf>) -> Result<Self, OpenError> {
+        use open_error::*;
+
+        let path = long_path(path.into());
+
+        let config_path = path.join(CONFIG_FILE_NAME);
+        let config = fs::read_to_string(&config_path).context(ReadSnafu { path: &config_path })?;
+        let Config::V1(config) =
+            toml::from_str(&config).context(DeserializeSnafu { path: &config_path })?;
+
+        Ok(Self { path, config })
+    }
+
+    fn add(
+        &self,
+        global: &Global,
+        crate_path: impl AsRef<Path>,
+    ) -> Result<(CrateName, Version), AddError> {
+        use add_error::*;
+
+        let crate_path = crate_path.as_ref();
+
+        println!("Adding crate `{}` to registry", crate_path.display());
+
+        let crate_file = fs::read(crate_path).context(ReadCrateSnafu)?;
+
+        let findings = self.scan(&crate_file).context(ScanSnafu)?;
+        if findings.is_empty() {
+            return self.add_package(global, &crate_file);
+        }
+
+        // Withheld before it is added, so that it is never served
+        let package = read_cargo_toml(&crate_file)?.package;
+        for finding in &findings {
+            eprintln!("Warning: {finding}");
+        }
+        let reason = format!("Held for review: {}", scan::summary(&findings));
+        self.withhold(&package.name, &package.version, Some(reason.clone()))
+            .context(HoldSnafu)?;
+
+        let (name, version) = match self.add_package(global, &crate_file) {
+            Ok(added) => added,
+            Err(e) => {
+                // Nothing was added, so there is nothing left to hold
+                let (name, vers) = (&package.name, &package.version);
+                if let Err(e) = quarantine::remove(&self.quarantine_path(), name, vers) {
+                    eprintln!("Warning: could not release {name} {vers}: {e}");
+                }
+                return Err(e);
+            }
+        };
+        self.mark_quarantined(name.clone(), version.clone(), Some(reason))
+            .context(HoldSnafu)?;
+        eprintln!("Warning: quarantined {name} {version} for review; `margo release` serves it");
+
+        Ok((name, version))
+    }
+
+    /// What the configured scanners find in the crate. Nothing, when
+    /// scanning is off.
+    fn scan(&self, crate_file: &[u8]) -> Result<Vec<scan::Finding>, scan::Error> {
+        if !self.config.scan.enabled {
+            return Ok(vec![]);
+        }
+
+        let scan = &self.config.scan;
+        let mut scanners = scan::builtin(&scan.known_bad);
+        let limits = self.unpack_limits();
+        scanners.extend(scan::external(&scan.commands, &self.config.sandbox, limits));
+
+        scan::scan(&scanners, crate_file)
+    }
+
+    fn add_package(
+        &self,
+        global: &Global,
+        crate_file: &[u8],
+    ) -> Result<(CrateName, Version), AddError> {
+        use sha2::Digest;
+        let checksum = sha2::Sha256::digest(crate_file);
+        let checksum_hex = hex::encode(checksum);
+
+        self.add_package_with_cksum(global, crate_file, checksum_hex)
+    }
+
+    /// Adds a package whose SHA-256 digest, `checksum_hex`, was taken
+    /// already, such as while it was received.
+    fn add_package_with_cksum(
+        &self,
+        global: &Global,
+        crate_file: &[u8],
+        checksum_hex: String,
+    ) -> Result<(CrateName, Version), AddError> {
+        use add_error::*;
+
+        self.check_size(crate_file)?;
+
+        let cargo_toml = read_cargo_toml(crate_file)?;
+
+        #[cfg(feature = "html")]
+        let metadata = cargo_toml.package.metadata.clone();
+
+        let index_entry =
+            adapt_cargo_toml_to_index_entry(global, &self.config, cargo_toml, checksum_hex);
+
+        // Documentation and vendoring unpack the package later, so
+        // refuse one that could write outside where it is unpacked, or
+        // unpacks to far more than it takes up
+        let package_dir = format!("{}-{}", index_entry.name, index_entry.vers);
+        extract::check(crate_file, &package_dir, &self.unpack_limits())?;
+
+        #[cfg(feature = "encryption")]
+        let (mut index_entry, stored) = self.encrypt(index_entry, crate_file)?;
+
+        #[cfg(not(feature = "encryption"))]
+        let (mut index_entry, stored) = (index_entry, crate_file);
+
+        index_entry.extensions.cid = Some(extensions::cid_of(&stored));
+
+        let index_path = self.index_file_path_for(&index_entry.name);
+        if let Some(path) = index_path.parent() {
+            fs::create_dir_all(path).context(IndexDirSnafu { path })?;
+        }
+
+        let crate_file_path = self.crate_file_path_for(&index_entry.name, &index_entry.vers);
+        if let Some(path) = crate_file_path.parent() {
+            fs::create_dir_all(path).context(CrateDirSnafu { path })?;
+        }
+
+        // FUTURE: Stronger file system consistency (atomic file overwrites, rollbacks on error)
+        // FUTURE: "transactional" adding of multiple crates
+
+        let name = index_entry.name.clone();
+        let vers = index_entry.vers.clone();
+        let cksum = index_entry.cksum.clone();
+
+        if let Some((existing, _)) = self.find_crate(&name).context(FindSnafu)? {
+            ensure!(existing == name, NameTakenSnafu { name, existing });
+        }
+
+        // Replacing a version with a different file needs a quorum.
+        // Files are compared as published, before any encryption.
+        #[cfg(feature = "nostr")]
+        let mut grant = None;
+        #[cfg(feature = "nostr")]
+        let published =
+            |e: &index_entry::Root| e.decrypted_cksum.clone().unwrap_or(e.cksum.clone());
+        #[cfg(feature = "nostr")]
+        let published_cksum = published(&index_entry);
+
+        self.read_modify_write(&name, |index_file| {
+            #[cfg(feature = "nostr")]
+            if index_file
+                .get(&vers)
+                .is_some_and(|e| published(e) != published_cksum)
+            {
+                let operation = quorum::Operation::Replace {
+                    name: name.clone(),
+                    vers: vers.clone(),
+                    cksum: published_cksum.clone(),
+                };
+                grant = Some(quorum::require(self, &operation).context(QuorumSnafu)?);
+            }
+
+            if let Some(replaced) = index_file.get(&vers) {
+                index_entry.extensions.inherit(&replaced.extensions);
+            }
+
+            index_file.insert(index_entry.vers.clone(), index_entry);
+            Ok::<_, AddError>(())
+        })?;
+
+        println!("Wrote crate index to `{}`", index_path.display());
+
+        // Replaced rather than overwritten, since snapshots may hard
+        // link the old file.
+        let tmp_path = crate_file_path.with_extension("crate.tmp");
+        fs::write(&tmp_path, &*stored).context(CrateWriteSnafu { path: &tmp_path })?;
+        fs::rename(&tmp_path, &crate_file_path).context(CrateWriteSnafu {
+            path: &crate_file_path,
+        })?;
+        println!("Wrote crate to `{}`", crate_file_path.display());
+
+        #[cfg(feature = "zstd")]
+        self.keep_copies(&crate_file_path, &stored);
+
+        #[cfg(feature = "erasure")]
+        self.keep_shards(&name, &vers, &stored);
+
+        #[cfg(feature = "html")]
+        if self.config.html.enabled && !self.encrypts() {
+            readme::write(self, &name, &vers, &metadata, crate_file)?;
+        }
+
+        self.record(audit::Event::Add {
+            name: name.clone(),
+            vers: vers.clone(),
+            cksum,
+        })?;
+
+        #[cfg(feature = "nostr")]
+        if let Some(grant) = grant {
+            grant.spend(self).context(QuorumSnafu)?;
+        }
+
+        self.retire_excess_versions(&name, &vers)
+            .context(RetireSnafu)?;
+
+        Ok((name, vers))
+    }
+
+    /// Encrypts the `.crate` file with the `[encryption]` key, if one is
+    /// configured, and gives the entry the checksum of the encrypted
+    /// file.
+    #[cfg(feature = "encryption")]
+    fn encrypt<'a>(
+        &self,
+        mut entry: index_entry::Root,
+        crate_file: &'a [u8],
+    ) -> Result<(index_entry::Root, std::borrow::Cow<'a, [u8]>), AddError> {
+        use add_error::*;
+        use sha2::Digest;
+        use std::{borrow::Cow, mem};
+
+        let Some(key_file) = &self.config.encryption.key_file else {
+            return Ok((entry, Cow::Borrowed(crate_file)));
+        };
+
+        let key = encryption::read_key(key_file).context(EncryptSnafu)?;
+        let encrypted = encryption::encrypt(&key, &entry.name, &entry.vers, crate_file)
+            .context(EncryptSnafu)?;
+
+        let cksum = hex::encode(sha2::Sha256::digest(&encrypted));
+        entry.decrypted_cksum = Some(mem::replace(&mut entry.cksum, cksum));
+
+        Ok((entry, Cow::Owned(encrypted)))
+    }
+
+    /// Whether `.crate` files are encrypted as they are added, so that
+    /// their READMEs and documentation must not be published.
+    #[cfg(feature = "encryption")]
+    fn encrypts(&self) -> bool {
+        self.config.encryption.key_file.is_some()
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn encrypts(&self) -> bool {
+        false
+    }
+
+    /// Peers are sent the `.crate` file itself when it has no copies,
+    /// so a failure is only a warning.
+    #[cfg(feature = "zstd")]
+    fn keep_copies(&self, crate_file: &Path, data: &[u8]) {
+        let encodings = &self.config.announcements.copies;
+        if let Err(e) = copies::write(crate_file, data, encodings) {
+            let path = crate_file.display();
+            eprintln!("Warning: Could not keep copies of `{path}`: {e}");
+        }
+    }
+
+    /// The `.crate` file itself is kept whole, so a failure is only a
+    /// warning.
+    #[cfg(feature = "erasure")]
+    fn keep_shards(&self, name: &CrateName, vers: &Version, data: &[u8]) {
+        let Some(scheme) = self.config.announcements.erasure else {
+            return;
+        };
+
+        let (manifest, shards) = match erasure::split(name.as_str(), vers, data, scheme) {
+            Ok(split) => split,
+            Err(e) => {
+                eprintln!("Warning: Could not split {name} {vers} into shards: {e}");
+                return;
+            }
+        };
+
+        let blobs_dir = self.blobs_dir();
+        for shard in shards {
+            if let Err(e) = blob::store(&blobs_dir, &blob::digest_of(&shard), &shard) {
+                eprintln!("Warning: Could not keep the shards of {name} {vers}: {e}");
+                return;
+            }
+        }
+
+        if let Err(e) = erasure::write(&self.shards_dir(), &manifest) {
+            eprintln!("Warning: Could not keep the shards of {name} {vers}: {e}");
+        }
+    }
+
+    /// Refuses a `.crate` file larger than `[limits] max-crate-size`.
+    fn check_size(&self, crate_file: &[u8]) -> Result<(), AddError> {
+        use add_error::*;
+
+        let size = crate_file.len() as u64;
+        if let Some(max) = self.config.limits.max_crate_size {
+            ensure!(size <= max, TooLargeSnafu { size, max });
+        }
+
+        Ok(())
+    }
+
+    /// How much a package may unpack to, from `[limits]`.
+    fn unpack_limits(&self) -> extract::Limits {
+        let limits = &self.config.limits;
+        let default = extract::Limits::default();
+        extract::Limits {
+            max_unpacked_bytes: limits
+                .max_unpacked_size
+                .unwrap_or(default.max_unpacked_bytes),
+            max_entries: limits.max_package_entries.unwrap_or(default.max_entries),
+        }
+    }
+
+    /// Refuses a crate new to the registry whose name breaks the
+    /// `[names]` policy.
+    fn check_name(&self, crate_file: &[u8]) -> Result<(), AddError> {
+        use add_error::*;
+
+        let package = read_cargo_toml(crate_file)?.package;
+        if self.index_file_path_for(&package.name).exists() {
+            return Ok(());
+        }
+
+        let existing = self.list_index_files().context(ListNamesSnafu)?;
+        let existing = existing.iter().filter_map(|p| p.file_name()?.to_str());
+        self.config
+            .names
+            .check(package.name.as_str(), package.description(), existing)
+            .context(NameSnafu)
+    }
+
+    /// Yanks or removes the oldest versions of the crate beyond
+    /// `[limits] max-versions`. The version just added is never
+    /// retired, even when it is older than the others.
+    fn retire_excess_versions(&self, name: &CrateName, added: &Version) -> Result<(), RetireError> {
+        use retire_error::*;
+
+        let limits = &self.config.limits;
+        let Some(max) = limits.max_versions else {
+            return Ok(());
+        };
+
+        let index = Self::parse_index_file(&self.index_file_path_for(name)).context(IndexSnafu)?;
+
+        // Oldest first
+        let kept = index
+            .values()
+            .filter(|e| limits.excess_versions == ExcessVersions::Remove || !e.yanked)
+            .map(|e| &e.vers)
+            .collect::<Vec<_>>();
+        let excess = kept
+            .iter()
+            .filter(|v| **v != added)
+            .take(kept.len().saturating_sub(max))
+            .map(|v| (*v).clone())
+            .collect::<Vec<_>>();
+
+        for vers in excess {
+            match limits.excess_versions {
+                ExcessVersions::Yank => self.yank(name.clone(), vers.clone(), true)?,
+                ExcessVersions::Remove => self.remove(name.clone(), vers.clone())?,
+            }
+            println!("Retired {name} {vers}, beyond the limit of {max} versions");
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, name: CrateName, version: Version) -> Result<(), RemoveError> {
+        use remove_error::*;
+
+        let removed = self.read_modify_write(&name, |index| {
+            Ok::<_, RemoveError>(index.remove(&version).is_some())
+        })?;
+
+        let crate_file = self.crate_file_path_for(&name, &version);
+        let readme_file = self.readme_file_path_for(&name, &version);
+        let mut files = vec![];
+        files.extend(self.cold_path_for(&crate_file));
+        #[cfg(feature = "zstd")]
+        files.extend(copies::paths_of(&crate_file));
+        #[cfg(feature = "erasure")]
+        files.push(self.shard_manifest_path_for(&name, &version));
+        files.extend([crate_file, readme_file]);
+        for path in files {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context(DeleteSnafu { path }),
+            }
+        }
+
+        let docs_dir = self.docs_dir_for(&name, &version);
+        let attestations_dir = self.attestations_dir_for(&name, &version);
+        let artifacts_dir = self.artifacts_dir_for(&name, &version);
+        for path in [docs_dir, attestations_dir, artifacts_dir] {
+            match fs::remove_dir_all(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context(DeleteSnafu { path }),
+            }
+        }
+
+        if removed {
+            self.record(audit::Event::Remove {
+                name,
+                vers: version,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "html")]
+    fn generate_html(&self) -> Result<(), HtmlError> {
+        html::write(self)
+    }
+
+    #[cfg(not(feature = "html"))]
+    fn generate_html(&self) -> Result<(), HtmlError> {
+        Err(HtmlError)
+    }
+
+    fn maybe_generate_html(&self) -> Result<(), HtmlError> {
+        if self.config.html.enabled {
+            self.generate_html()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn builds_docs(&self) -> bool {
+        self.config.docs.enabled && !self.encrypts()
+    }
+
+    /// The crate has already been added by the time its documentation
+    /// is built, so a failure is only a warning.
+    fn maybe_build_docs(&self, name: &CrateName, version: &Version) {
+        if !self.builds_docs() {
+            return;
+        }
+
+        if let Err(e) = docs::build(self, name, version) {
+            eprintln!("Warning: {e}");
+        }
+    }
+
+    fn maybe_generate_feed(&self) -> Result<(), feed::Error> {
+        if self.config.feed.enabled {
+            feed::write(self)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn demote(&self, dry_run: bool) -> Result<tier::Summary, tier::Error> {
+        let tiering = &self.config.tiering;
+        let Some(cold_dir) = &tiering.cold_dir else {
+            return Err(tier::Error::NotConfigured);
+        };
+
+        let days = tiering
+            .demote_after_days
+            .unwrap_or(tier::DEFAULT_DEMOTE_AFTER_DAYS);
+        let after = std::time::Duration::from_secs(days * 24 * 60 * 60);
+
+        tier::demote(&self.path, cold_dir, after, dry_run)
+    }
+
+    /// Copies a demoted `.crate` file back to local disk before it is
+    /// read. Returns whether the file is on local disk.
+    fn promote(&self, name: &CrateName, version: &Version) -> Result<bool, tier::Error> {
+        let path = self.crate_file_path_for(name, version);
+        let Some(cold_dir) = &self.config.tiering.cold_dir else {
+            return Ok(path.exists());
+        };
+
+        let relative = path.strip_prefix(&self.path).unwrap_or(&path);
+        tier::promote(&self.path, cold_dir, relative)
+    }
+
+    /// Where the demoted copy of the registry file at `path` is kept,
+    /// when the registry has a cold tier.
+    fn cold_path_for(&self, path: &Path) -> Option<PathBuf> {
+        let cold_dir = self.config.tiering.cold_dir.as_ref()?;
+        let relative = path.strip_prefix(&self.path).ok()?;
+
+        Some(cold_dir.join(relative))
+    }
+
+    fn yank(&self, name: CrateName, version: Version, yanked: bool) -> Result<(), YankError> {
+        use yank_error::*;
+
+        self.read_modify_write(&name, |index| {
+            let entry = index.get_mut(&version).context(VersionSnafu)?;
+            entry.yanked = yanked;
+            Ok(())
+        })?;
+
+        let event = if yanked {
+            audit::Event::Yank {
+                name,
+                vers: version,
+            }
+        } else {
+            audit::Event::Unyank {
+                name,
+                vers: version,
+            }
+        };
+        self.record(event)?;
+
+        Ok(())
+    }
+
+    fn record(&self, event: audit::Event) -> Result<audit::Entry, audit::Error> {
+        audit::append(&self.audit_log_path(), event)
+    }
+
+    fn audit_log(&self) -> Result<Vec<audit::Entry>, audit::Error> {
+        audit::read(&self.audit_log_path())
+    }
+
+    fn conflicts(&self) -> Result<Vec<conflicts::Conflict>, conflicts::Error> {
+        conflicts::read(&self.conflicts_path())
+    }
+
+    #[cfg(feature = "server")]
+    fn is_quarantined(&self, name: &str, vers: &Version) -> Result<bool, conflicts::Error> {
+        conflicts::is_quarantined(&self.conflicts_path(), name, vers)
+    }
+
+    #[cfg(feature = "server")]
+    fn find_withheld(
+        &self,
+        name: &str,
+        vers: &Version,
+    ) -> Result<Option<quarantine::Withheld>, quarantine::Error> {
+        quarantine::find(&self.quarantine_path(), name, vers)
+    }
+
+    /// Quarantines the version. Only a conflict that was not already
+    /// known is recorded in the audit log.
+    #[cfg(any(feature = "proxy", feature = "replicate", feature = "sync-crates-io"))]
+    fn record_conflict(
+        &self,
+        name: CrateName,
+        vers: Version,
+        checksums: BTreeMap<String, String>,
+    ) -> Result<(), ConflictError> {
+        let conflict = conflicts::Conflict {
+            name,
+            vers,
+            checksums,
+            detected_at: timestamp::Timestamp::now(),
+        };
+
+        if let Some(c) = conflicts::record(&self.conflicts_path(), conflict)? {
+            eprintln!(
+                "Warning: the checksums of {} {} differ between sources; quarantined it",
+                c.name, c.vers,
+            );
+            self.record(audit::Event::Conflict {
+                name: c.name,
+                vers: c.vers,
+                checksums: c.checksums,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn resolve_conflict(
+        &self,
+        name: CrateName,
+        vers: Version,
+        remove: bool,
+    ) -> Result<(), ConflictError> {
+        conflicts::resolve(&self.conflicts_path(), &name, &vers)?;
+
+        if remove {
+            self.remove(name.clone(), vers.clone())?;
+        }
+
+        self.record(audit::Event::ResolveConflict {
+            name,
+            vers,
+            removed: remove,
+        })?;
+
+        Ok(())
+    }
+
+    fn withheld(&self) -> Result<Vec<quarantine::Withheld>, quarantine::Error> {
+        quarantine::read(&self.quarantine_path())
+    }
+
+    /// Stops serving the version at once, then marks it withheld in the
+    /// index.
+    fn quarantine(
+        &self,
+        name: CrateName,
+        vers: Version,
+        reason: Option<String>,
+    ) -> Result<(), QuarantineError> {
+        use quarantine_error::*;
+
+        let index = Self::parse_index_file(&self.index_file_path_for(&name)).context(IndexSnafu)?;
+        ensure!(index.contains_key(&vers), VersionSnafu);
+
+        self.withhold(&name, &vers, reason.clone())?;
+        self.mark_quarantined(name, vers, reason)
+    }
+
+    /// Records the version in `quarantine.json`. That is all the daemon
+    /// checks, so it stops serving the version at once.
+    fn withhold(
+        &self,
+        name: &CrateName,
+        vers: &Version,
+        reason: Option<String>,
+    ) -> Result<(), QuarantineError> {
+        let withheld = quarantine::Withheld {
+            name: name.clone(),
+            vers: vers.clone(),
+            reason,
+            quarantined_at: timestamp::Timestamp::now(),
+        };
+        quarantine::add(&self.quarantine_path(), withheld)?;
+
+        Ok(())
+    }
+
+    /// Marks a withheld version in its index entry and the audit log.
+    fn mark_quarantined(
+        &self,
+        name: CrateName,
+        vers: Version,
+        reason: Option<String>,
+    ) -> Result<(), QuarantineError> {
+        self.set_withheld(&name, &vers, true)?;
+
+        self.record(audit::Event::Quarantine { name, vers, reason })?;
+
+        Ok(())
+    }
+
+    fn release(&self, name: CrateName, vers: Version) -> Result<(), QuarantineError> {
+        quarantine::remove(&self.quarantine_path(), &name, &vers)?;
+        self.set_withheld(&name, &vers, false)?;
+
+        self.record(audit::Event::Release { name, vers })?;
+
+        Ok(())
+    }
+
+    fn visibility(&self) -> Result<visibility::Levels, visibility::Error> {
+        visibility::read(&self.visibility_path())
+    }
+
+    /// Takes effect in the daemon at once. Only a change is recorded in
+    /// the audit log.
+    fn set_visibility(
+        &self,
+        name: CrateName,
+        visibility: visibility::Visibility,
+    ) -> Result<(), VisibilityError> {
+        use visibility_error::*;
+
+        let index = Self::parse_index_file(&self.index_file_path_for(&name)).context(IndexSnafu)?;
+        ensure!(!index.is_empty(), NotFoundSnafu { name });
+
+        if visibility::set(&self.visibility_path(), &name, visibility)? {
+            self.record(audit::Event::SetVisibility { name, visibility })?;
+        }
+
+        Ok(())
+    }
+
+    fn set_withheld(
+        &self,
+        name: &CrateName,
+        vers: &Version,
+        withheld: bool,
+    ) -> Result<(), QuarantineError> {
+        use quarantine_error::*;
+
+        self.read_modify_write(name, |index| {
+            let entry = index.get_mut(vers).context(VersionSnafu)?;
+            entry.withheld = withheld;
+            Ok(())
+        })
+    }
+
+    /// Changes what the version's index entry records beyond Cargo's
+    /// schema.
+    fn update_extensions(
+        &self,
+        name: &CrateName,
+        vers: &Version,
+        update: impl FnOnce(&mut extensions::Extensions),
+    ) -> Result<(), ExtensionsError> {
+        use extensions_error::*;
+
+        self.read_modify_write(name, |index| {
+            let entry = index.get_mut(vers).context(VersionSnafu)?;
+            update(&mut entry.extensions);
+            Ok(())
+        })
+    }
+
+    /// Walks the registry the way a client would, starting from the
+    /// published `config.json`, and reports anything that a client
+    /// could not download or that does not match the index.
+    fn verify(&self, mode: progress::Mode) -> Result<Vec<VerifyProblem>, VerifyError> {
+        use verify_error::*;
+
+        let config_json_path = self.config_json_path();
+        let config_json = fs::read(&config_json_path).context(ConfigJsonReadSnafu {
+            path: &config_json_path,
+        })?;
+        let client = client::Client::new(self.config.base_url.clone(), &config_json)?;
+
+        let mut problems = vec![];
+
+        let names = self.list_all()?.into_keys().collect::<Vec<_>>();
+        let mut progress = progress::Progress::new(mode, "verify", Some(names.len() as u64));
+
+        for name in names {
+            progress.inc(&name);
+
+            let url = client.index_url(&name)?;
+            let Some(path) = self.local_path_for(&url) else {
+                problems.push(VerifyProblem::OutsideRegistry { url });
+                continue;
+            };
+            let Some(index) = read_if_exists(&path).context(ReadSnafu { path: &path })? else {
+                problems.push(VerifyProblem::IndexMissing { name, path });
+                continue;
+            };
+            let index = client.parse_index(&index)?;
+
+            for entry in index.values() {
+                let url = client.crate_url(entry)?;
+                let Some(path) = self.local_path_for(&url) else {
+                    problems.push(VerifyProblem::OutsideRegistry { url });
+                    continue;
+                };
+                let mut data = read_if_exists(&path).context(ReadSnafu { path: &path })?;
+                if let (None, Some(cold)) = (&data, self.cold_path_for(&path)) {
+                    data = read_if_exists(&cold).context(ReadSnafu { path: cold })?;
+                }
+                let Some(data) = data else {
+                    problems.push(VerifyProblem::CrateMissing {
+                        name: entry.name.clone(),
+                        version: entry.vers.clone(),
+                        path,
+                    });
+                    continue;
+                };
+
+                progress.add_bytes(data.len() as u64);
+                if let Err(source) = client::Client::verify(entry, &data) {
+                    problems.push(VerifyProblem::Mismatch { source });
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// Maps a URL served by this registry back to the file on disk.
+    fn local_path_for(&self, url: &Url) -> Option<PathBuf> {
+        let relative = self.config.base_url.make_relative(url)?;
+
+        if relative.split('/').any(|s| s == "..") {
+            return None;
+        }
+
+        let mut path = self.path.clone();
+        path.extend(relative.split('/'));
+        Some(path)
+    }
+
+    fn read_modify_write<T, E>(
+        &self,
+        name: &CrateName,
+        modify: impl FnOnce(&mut Index) -> Result<T, E>,
+    ) -> Result<T, E>
+    where
+        E: From<ReadModifyWriteError>,
+    {
+        use read_modify_write_error::*;
+
+        let path = self.index_file_path_for(name);
+        let mut index = Self::parse_index_file(&path).context(IndexParseSnafu { path: &path })?;
+
+        let val = modify(&mut index)?;
+
+        Self::write_index_file(index, &path).context(IndexWriteSnafu { path })?;
+
+        Ok(val)
+    }
+
+    fn list_crate_files(
+        crate_dir: &Path,
+    ) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> {
+        walkdir::WalkDir::new(crate_dir)
+            .into_iter()
+            .flat_map(|entry| {
+                let Ok(entry) = entry else { return Some(entry) };
+
+                let fname = entry.path().file_name()?;
+                let fname = Path::new(fname);
+
+                let extension = fname.extension()?;
+                if extension == "crate" {
+                    Some(Ok(entry))
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn list_index_files(&self) -> Result<BTreeSet<PathBuf>, ListIndexFilesError> {
+        use list_index_files_error::*;
+
+        let crate_dir = self.crate_dir();
+
+        let index_files = Self::list_crate_files(&crate_dir)
+            .map(|entry| {
+                let entry = entry.context(WalkdirSnafu { path: &crate_dir })?;
+
+                let mut path = entry.into_path();
+                path.pop();
+
+                let subdir = path.strip_prefix(&crate_dir).context(PrefixSnafu {
+                    path: &path,
+                    prefix: &crate_dir,
+                })?;
+                // The crate directories keep the name's case
+                let subdir = subdir.to_string_lossy().to_ascii_lowercase();
+                let index_path = self.path.join(subdir);
+                Ok(index_path)
+            })
+            .collect::<Result<BTreeSet<_>, ListIndexFilesError>>();
+
+        match index_files {
+            Err(e) if e.is_not_found() => Ok(Default::default()),
+            r => r,
+        }
+    }
+
+    fn list_all(&self) -> Result<ListAll, ListAllError> {
+        use list_all_error::*;
+
+        let mut crates = BTreeMap::new();
+
+        for path in self.list_index_files()? {
+            let index = Self::parse_index_file(&path).context(ParseSnafu { path })?;
+
+            if let Some(entry) = index.values().next() {
+                crates.insert(entry.name.clone(), index);
+            }
         }
 
-        fn max(&self) -> usize {
-            self.0
-        }
+        Ok(crates)
     }
 
-    let mut max_c = Max::default();
-    let mut max_v = Max::default();
+    /// Like [`Self::list_all`], but summarizing each crate rather than
+    /// parsing every entry in full.
+    #[cfg(feature = "server")]
+    fn list_summaries(&self) -> Result<BTreeMap<CrateName, index_file::Summary>, ListAllError> {
+        use list_all_error::*;
 
-    for (crate_, versions) in &crates {
-        max_c.push(crate_);
-        for version in versions.keys() {
-            max_v.push(version);
-        }
-    }
+        let mut crates = BTreeMap::new();
 
-    let max_c = max_c.max();
-    let max_v = max_v.max();
+        for path in self.list_index_files()? {
+            let summary = Self::summarize_index_file(&path).context(ParseSnafu { path })?;
 
-    for (crate_, versions) in crates {
-        for version in versions.keys() {
-            println!("{crate_:<max_c$} {version:<max_v$}");
+            if let Some((name, summary)) = summary {
+                crates.insert(name, summary);
+            }
         }
-    }
 
-    Ok(())
-}
+        Ok(crates)
+    }
 
-#[cfg(feature = "sync-crates-io")]
-fn do_sync(global: &Global, sync: SyncArgs) -> Result<(), Error> {
-    use sync_error::*;
+    fn parse_index_file(path: &Path) -> Result<Index, ParseIndexError> {
+        use parse_index_error::*;
 
-    let r = discover_registry(sync.registry)?;
+        match index_file::open(path).context(OpenSnafu)? {
+            Some(data) => parse_index_lines(&data),
+            None => Ok(Default::default()),
+        }
+    }
 
-    let client = crates_io::Client::new();
+    /// The name and summary of the crate in the index file, parsing
+    /// only what the summary needs. `None` when the file has no entries.
+    #[cfg(feature = "server")]
+    fn summarize_index_file(
+        path: &Path,
+    ) -> Result<Option<(CrateName, index_file::Summary)>, ParseIndexError> {
+        use parse_index_error::*;
 
-    for crate_name in &sync.crates {
-        println!("Syncing `{crate_name}` from crates.io...");
+        let Some(data) = index_file::open(path).context(OpenSnafu)? else {
+            return Ok(None);
+        };
 
-        let versions = client
-            .fetch_versions(crate_name)
-            .context(FetchVersionsSnafu {
-                crate_name: crate_name.as_str(),
-            })?;
+        let mut summary = None;
+        for (i, line) in index_file::lines(&data) {
+            let head =
+                serde_json::from_slice::<index_file::Head>(line).context(ParseSnafu { line: i })?;
+            let (_, crate_summary) =
+                summary.get_or_insert_with(|| (head.name.clone(), Default::default()));
+            crate_summary.add(head);
+        }
 
-        let crate_name_typed = crate_name
-            .parse::<common::CrateName>()
-            .context(CrateNameSnafu {
-                crate_name: crate_name.as_str(),
-            })?;
+        Ok(summary)
+    }
 
-        let known: std::collections::BTreeSet<semver::Version> = r
-            .list_all()
-            .context(ListSnafu)?
-            .get(&crate_name_typed)
-            .map(|idx| idx.keys().cloned().collect())
-            .unwrap_or_default();
+    /// Replaces the index file by renaming a new one over it, so that
+    /// readers, including those that mapped the old one into memory,
+    /// see one whole file or the other.
+    fn write_index_file(index_file: Index, path: &Path) -> Result<(), WriteIndexError> {
+        use write_index_error::*;
 
-        for version in &versions {
-            if known.contains(&version.num) {
-                println!("  {crate_name} {} already in registry, skipping", version.num);
-                continue;
-            }
+        // Crate names cannot contain `.`, so this is no crate's file
+        let tmp = path.with_extension("tmp");
 
-            println!("  Downloading {crate_name} {}...", version.num);
-            let crate_data = client
-                .download_crate(crate_name, &version.num.to_string())
-                .context(DownloadSnafu {
-                    crate_name: crate_name.as_str(),
-                    version: version.num.to_string(),
-                })?;
+        let mut file = BufWriter::new(File::create(&tmp).context(OpenSnafu)?);
+        Self::serialize_index(&index_file, &mut file)?;
+        file.into_inner()
+            .map_err(io::IntoInnerError::into_error)
+            .context(FlushSnafu)?;
 
-            let tmp_path = std::env::temp_dir()
-                .join(format!("{}-{}.crate", crate_name, version.num));
-            fs::write(&tmp_path, &crate_data).context(WriteTmpSnafu { path: &tmp_path })?;
+        fs::rename(&tmp, path).context(RenameSnafu)
+    }
 
-            r.add(global, &tmp_path)?;
+    /// Writes one line per entry, oldest version first.
+    fn serialize_index(index_file: &Index, mut w: impl Write) -> Result<(), WriteIndexError> {
+        use write_index_error::*;
 
-            if let Err(e) = fs::remove_file(&tmp_path) {
-                eprintln!(
-                    "Warning: could not remove temporary file {}: {e}",
-                    tmp_path.display(),
-                );
-            }
+        for entry in index_file.values() {
+            serde_json::to_writer(&mut w, entry).context(EntrySerializeSnafu)?;
+            w.write_all(b"\n").context(EntryNewlineSnafu)?;
         }
-    }
-
-    r.maybe_generate_html()?;
 
-    Ok(())
-}
+        Ok(())
+    }
 
-#[cfg(feature = "sync-crates-io")]
-#[derive(Debug, Snafu)]
-#[snafu(module)]
-enum SyncError {
-    #[snafu(display("Could not fetch versions for `{crate_name}` from crates.io"))]
-    FetchVersions {
-        source: crates_io::Error,
-        crate_name: String,
-    },
+    /// Whether changes are refused, by `--read-only` or by the
+    /// registry's configuration.
+    fn is_read_only(&self, global: &Global) -> bool {
+        global.read_only || self.config.read_only
+    }
 
-    #[snafu(display("Invalid crate name `{crate_name}`"))]
-    CrateName {
-        source: common::CrateNameError,
-        crate_name: String,
-    },
+    fn crate_dir(&self) -> PathBuf {
+        self.path.join(CRATE_DIR_NAME)
+    }
 
-    #[snafu(display("Could not list registry contents"))]
-    List { source: ListAllError },
+    fn blobs_dir(&self) -> PathBuf {
+        self.path.join(blob::DIR_NAME)
+    }
 
-    #[snafu(display("Could not download `{crate_name}` v{version} from crates.io"))]
-    Download {
-        source: crates_io::Error,
-        crate_name: String,
-        version: String,
-    },
+    #[cfg(feature = "erasure")]
+    fn shards_dir(&self) -> PathBuf {
+        self.path.join(erasure::DIR_NAME)
+    }
 
-    #[snafu(display("Could not write temporary crate file to {}", path.display()))]
-    WriteTmp { source: io::Error, path: PathBuf },
-}
+    #[cfg(feature = "erasure")]
+    fn shard_manifest_path_for(&self, name: &CrateName, version: &Version) -> PathBuf {
+        erasure::manifest_path(&self.shards_dir(), name.as_str(), version)
+    }
 
-#[cfg(feature = "p2p")]
-fn do_serve(_global: &Global, serve: ServeArgs) -> Result<(), Error> {
-    use libp2p::Multiaddr;
+    #[cfg(feature = "server")]
+    fn blob_url_for(&self, digest: &str) -> Option<Url> {
+        let href = format!("{}/{}", blob::DIR_NAME, blob::relative_path(digest));
+        self.config.base_url.join(&href).ok()
+    }
 
-    let r = discover_registry(serve.registry)?;
+    fn margo_config_toml_path(&self) -> PathBuf {
+        self.path.join(CONFIG_FILE_NAME)
+    }
 
-    let default_addr = "/ip4/0.0.0.0/tcp/0";
-    let addr_str = serve.listen.as_deref().unwrap_or(default_addr);
+    fn config_json_path(&self) -> PathBuf {
+        self.path.join("config.json")
+    }
 
-    let listen_addr: Multiaddr =
-        addr_str
-            .parse()
-            .map_err(|e| ServeError::ParseListenAddr {
-                source: e,
-                addr: addr_str.to_owned(),
-            })?;
+    fn audit_log_path(&self) -> PathBuf {
+        self.path.join(audit::FILE_NAME)
+    }
 
-    let rt = tokio::runtime::Runtime::new().map_err(|source| ServeError::Runtime { source })?;
+    fn conflicts_path(&self) -> PathBuf {
+        self.path.join(conflicts::FILE_NAME)
+    }
 
-    rt.block_on(p2p::start_node(listen_addr, r.path))
-        .map_err(ServeError::from)?;
+    fn quarantine_path(&self) -> PathBuf {
+        self.path.join(quarantine::FILE_NAME)
+    }
 
-    Ok(())
-}
+    fn visibility_path(&self) -> PathBuf {
+        self.path.join(visibility::FILE_NAME)
+    }
 
-#[cfg(feature = "p2p")]
-#[derive(Debug, Snafu)]
-enum ServeError {
-    #[snafu(display("Could not parse listen address `{addr}`"))]
-    ParseListenAddr {
-        source: libp2p::multiaddr::Error,
-        addr: String,
-    },
+    #[cfg(feature = "sync-crates-io")]
+    fn sync_cursor_path(&self) -> PathBuf {
+        self.path.join(sync_cursor::FILE_NAME)
+    }
 
-    #[snafu(display("Could not create the async runtime"))]
-    Runtime { source: io::Error },
+    #[cfg(feature = "replicate")]
+    fn replication_path(&self) -> PathBuf {
+        self.path.join(replication::FILE_NAME)
+    }
 
-    #[snafu(transparent)]
-    Open { source: DiscoverRegistryError },
+    /// Index paths are lowercase, as Cargo asks for them.
+    fn index_file_path_for(&self, name: &CrateName) -> PathBuf {
+        self.path.join(name.index_path())
+    }
 
-    #[snafu(transparent)]
-    P2p { source: p2p::P2pError },
-}
+    /// The crate's index, under the name the registry has it by, which
+    /// may differ from `name` in case or in `-` and `_`. Cargo treats
+    /// those spellings as the same crate.
+    fn find_crate(&self, name: &CrateName) -> Result<Option<(CrateName, Index)>, FindCrateError> {
+        use find_crate_error::*;
+
+        let normalized = names::normalize(name.as_str());
+
+        // Index paths are lowercase, so other spellings can only be in
+        // other directories by a `-` or `_` in the prefix directories
+        let prefix = name.prefix_directories().join("/").to_ascii_lowercase();
+        let mut dirs = vec![String::new()];
+        for c in prefix.chars() {
+            let alternatives = match c {
+                '-' | '_' => vec!['-', '_'],
+                c => vec![c],
+            };
+            dirs = dirs
+                .into_iter()
+                .flat_map(|d| alternatives.iter().map(move |a| format!("{d}{a}")))
+                .collect();
+        }
 
-fn discover_registry(path: Option<PathBuf>) -> Result<Registry, DiscoverRegistryError> {
-    use discover_registry_error::*;
+        for dir in dirs {
+            let dir = dir
+                .split('/')
+                .fold(self.path.clone(), |path, d| path.join(d));
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context(ReadDirSnafu { path: dir }),
+            };
 
-    match path {
-        Some(p) => Registry::open(p).context(OpenSnafu),
-        None => {
-            let cwd = env::current_dir().context(CurrentDirSnafu)?;
+            for entry in entries {
+                let path = entry.context(ReadDirSnafu { path: &dir })?.path();
+                let same = path
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .is_some_and(|f| names::normalize(f) == normalized);
+                if !same || !path.is_file() {
+                    continue;
+                }
 
-            match Registry::open(cwd) {
-                Ok(r) => Ok(r),
-                Err(e) if e.is_not_found() => FallbackNotFoundSnafu.fail(),
-                Err(e) => Err(e).context(FallbackOpenSnafu)?,
+                let index = Self::parse_index_file(&path).context(ParseSnafu { path: &path })?;
+                if let Some(entry) = index.values().next() {
+                    return Ok(Some((entry.name.clone(), index)));
+                }
             }
         }
-    }
-}
-
-#[derive(Debug, Snafu)]
-#[snafu(module)]
-enum DiscoverRegistryError {
-    #[snafu(display("Could not open the specified registry"))]
-    Open { source: OpenError },
-
-    #[snafu(display("Could not determine the current directory, {}", Self::TRY_THIS))]
-    CurrentDir { source: io::Error },
 
-    #[snafu(display(
-        "The current directory does not contain a registry, {}",
-        Self::TRY_THIS,
-    ))]
-    FallbackNotFound,
+        Ok(None)
+    }
 
-    #[snafu(display("Could not open the registry in the current directory"))]
-    FallbackOpen { source: OpenError },
-}
+    fn crate_dir_for(&self, name: &CrateName) -> PathBuf {
+        let mut crate_dir = self.crate_dir();
+        name.append_prefix_directories(&mut crate_dir);
+        crate_dir.push(name);
+        crate_dir
+    }
 
-impl DiscoverRegistryError {
-    const TRY_THIS: &'static str = "please use the `--registry` command line option";
-}
+    fn crate_file_path_for(&self, name: &CrateName, version: &Version) -> PathBuf {
+        let mut crate_file_path = self.crate_dir_for(name);
+        crate_file_path.push(format!("{}.crate", version));
+        crate_file_path
+    }
 
-#[derive(Debug)]
-struct Registry {
-    path: PathBuf,
-    config: ConfigV1,
-}
+    fn docs_dir_for(&self, name: &CrateName, version: &Version) -> PathBuf {
+        let mut docs_dir = self.path.join(docs::DIR_NAME);
+        docs_dir.push(name);
+        docs_dir.push(version.to_string());
+        docs_dir
+    }
 
-type Index = BTreeMap<Version, index_entry::Root>;
-type ListAll = BTreeMap<CrateName, Index>;
+    #[cfg(feature = "server")]
+    fn docs_url_for(&self, name: &CrateName, version: &Version) -> Option<Url> {
+        if !self.docs_dir_for(name, version).join("index.html").exists() {
+            return None;
+        }
 
-impl Registry {
-    fn initialize(config: ConfigV1, path: impl Into<PathBuf>) -> Result<Self, InitializeError> {
-        use initialize_error::*;
+        let href = format!("{}/{name}/{version}/", docs::DIR_NAME);
+        self.config.base_url.join(&href).ok()
+    }
 
-        let config = config.normalize();
-        let path = path.into();
+    fn readme_file_path_for(&self, name: &CrateName, version: &Version) -> PathBuf {
+        let mut readme_file_path = self.crate_dir_for(name);
+        readme_file_path.push(format!("{}.readme.html", version));
+        readme_file_path
+    }
 
-        println!("Initializing registry in `{}`", path.display());
+    /// The location of the rendered README relative to the registry
+    /// root, if there is one.
+    #[cfg(feature = "html")]
+    fn readme_href_for(&self, name: &CrateName, version: &Version) -> Option<String> {
+        if !self.readme_file_path_for(name, version).exists() {
+            return None;
+        }
 
-        fs::create_dir_all(&path).context(RegistryCreateSnafu)?;
+        let prefix = name.prefix_directories().join("/");
+        Some(format!(
+            "{CRATE_DIR_NAME}/{prefix}/{name}/{version}.readme.html"
+        ))
+    }
 
-        let config_toml_path = path.join(CONFIG_FILE_NAME);
-        let config = Config::V1(config);
-        let config_toml = toml::to_string(&config).context(ConfigTomlSerializeSnafu)?;
-        fs::write(&config_toml_path, config_toml).context(ConfigTomlWriteSnafu {
-            path: &config_toml_path,
-        })?;
+    #[cfg(feature = "server")]
+    fn readme_url_for(&self, name: &CrateName, version: &Version) -> Option<Url> {
+        let href = self.readme_href_for(name, version)?;
+        self.config.base_url.join(&href).ok()
+    }
 
-        let Config::V1(config) = config;
+    fn artifacts_dir_for(&self, name: &CrateName, version: &Version) -> PathBuf {
+        let mut artifacts_dir = self.crate_dir_for(name);
+        artifacts_dir.push(format!("{}.{}", version, artifact::DIR_EXTENSION));
+        artifacts_dir
+    }
 
-        let dl = format!(
-            "{base_url}crates/{{lowerprefix}}/{{crate}}/{{version}}.crate",
-            base_url = config.base_url,
+    #[cfg(feature = "server")]
+    fn artifact_url_for(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        artifact: &artifact::Artifact,
+    ) -> Option<Url> {
+        let prefix = name.prefix_directories().join("/");
+        let href = format!(
+            "{CRATE_DIR_NAME}/{prefix}/{name}/{version}.{}/{}/{}",
+            artifact::DIR_EXTENSION,
+            artifact.target,
+            artifact.file,
         );
-        let auth_required = config.auth_required;
-
-        let this = Self { path, config };
-
-        let config_json_path = this.config_json_path();
-        let config_json = config_json::Root {
-            dl,
-            api: None,
-            auth_required,
-        };
-        let config_json = serde_json::to_string(&config_json).context(ConfigJsonSerializeSnafu)?;
-        fs::write(&config_json_path, config_json).context(ConfigJsonWriteSnafu {
-            path: &config_json_path,
-        })?;
+        self.config.base_url.join(&href).ok()
+    }
 
-        Ok(this)
+    fn attestations_dir_for(&self, name: &CrateName, version: &Version) -> PathBuf {
+        let mut attestations_dir = self.crate_dir_for(name);
+        attestations_dir.push(format!("{}.{}", version, attestation::DIR_EXTENSION));
+        attestations_dir
     }
 
-    fn open(path: impl Into<PathBuf>) -> Result<Self, OpenError> {
-        use open_error::*;
+    #[cfg(feature = "server")]
+    fn attestation_url_for(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        digest: &str,
+    ) -> Option<Url> {
+        let prefix = name.prefix_directories().join("/");
+        let href = format!(
+            "{CRATE_DIR_NAME}/{prefix}/{name}/{version}.{}/{digest}.json",
+            attestation::DIR_EXTENSION,
+        );
+        self.config.base_url.join(&href).ok()
+    }
+}
 
-        let path = path.into();
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum InitializeError {
+    #[snafu(display("Could not create the registry directory"))]
+    RegistryCreate { source: io::Error },
 
-        let config_path = path.join(CONFIG_FILE_NAME);
-        let config = fs::read_to_string(&config_path).context(ReadSnafu { path: &config_path })?;
-        let Config::V1(config) =
-            toml::from_str(&config).context(DeserializeSnafu { path: &config_path })?;
+    #[snafu(display("Could not serialize the registry's internal configuration"))]
+    ConfigTomlSerialize { source: toml::ser::Error },
 
-        Ok(Self { path, config })
-    }
+    #[snafu(display("Could not write the registry's internal configuration to {}", path.display()))]
+    ConfigTomlWrite { source: io::Error, path: PathBuf },
 
-    fn add(&self, global: &Global, crate_path: impl AsRef<Path>) -> Result<(), AddError> {
-        use add_error::*;
+    #[snafu(display("Could not serialize the registry's public configuration"))]
+    ConfigJsonSerialize { source: serde_json::Error },
 
-        let crate_path = crate_path.as_ref();
+    #[snafu(display("Could not write the registry's public configuration to {}", path.display()))]
+    ConfigJsonWrite { source: io::Error, path: PathBuf },
+}
 
-        println!("Adding crate `{}` to registry", crate_path.display());
+impl InitializeError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::RegistryCreate { .. } => "E_STORAGE_WRITE",
+            Self::ConfigTomlSerialize { .. }
+            | Self::ConfigTomlWrite { .. }
+            | Self::ConfigJsonSerialize { .. }
+            | Self::ConfigJsonWrite { .. } => "E_CONFIG_WRITE",
+        }
+    }
+}
 
-        let crate_file = fs::read(crate_path).context(ReadCrateSnafu)?;
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum OpenError {
+    #[snafu(display("Could not open the registry's internal configuration at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
 
-        use sha2::Digest;
-        let checksum = sha2::Sha256::digest(&crate_file);
-        let checksum_hex = hex::encode(checksum);
+    #[snafu(display("Could not deserialize the registry's internal configuration at {}", path.display()))]
+    Deserialize {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
 
-        let cargo_toml = extract_root_cargo_toml(&crate_file)?.context(CargoTomlMissingSnafu)?;
+    #[snafu(display(
+        "The registry at {} is in format {format}, newer than this margo knows ({}); use a newer margo",
+        path.display(),
+        migrate::CURRENT,
+    ))]
+    FormatTooNew { path: PathBuf, format: u32 },
 
-        let cargo_toml = String::from_utf8(cargo_toml).context(CargoTomlUtf8Snafu)?;
-        let cargo_toml = toml::from_str(&cargo_toml).context(CargoTomlMalformedSnafu)?;
+    #[snafu(display(
+        "The registry at {} is in format {format}; run `margo migrate` to upgrade it to format {}",
+        path.display(),
+        migrate::CURRENT,
+    ))]
+    FormatTooOld { path: PathBuf, format: u32 },
 
-        let index_entry =
-            adapt_cargo_toml_to_index_entry(global, &self.config, cargo_toml, checksum_hex);
+    #[snafu(display(
+        "The registry at {} was made by upstream margo; run `margo adopt` to take it over",
+        path.display(),
+    ))]
+    Upstream { path: PathBuf },
+}
 
-        let index_path = self.index_file_path_for(&index_entry.name);
-        if let Some(path) = index_path.parent() {
-            fs::create_dir_all(path).context(IndexDirSnafu { path })?;
+impl OpenError {
+    fn is_not_found(&self) -> bool {
+        match self {
+            Self::Read { source, .. } => source.kind() == io::ErrorKind::NotFound,
+            Self::Deserialize { .. }
+            | Self::FormatTooNew { .. }
+            | Self::FormatTooOld { .. }
+            | Self::Upstream { .. } => false,
         }
+    }
 
-        let crate_file_path = self.crate_file_path_for(&index_entry.name, &index_entry.vers);
-        if let Some(path) = crate_file_path.parent() {
-            fs::create_dir_all(path).context(CrateDirSnafu { path })?;
+    fn code(&self) -> &'static str {
+        match self {
+            _ if self.is_not_found() => "E_REGISTRY_NOT_FOUND",
+            Self::Read { .. } => "E_CONFIG_READ",
+            Self::Deserialize { .. } => "E_CONFIG_INVALID",
+            Self::FormatTooNew { .. } => "E_FORMAT_TOO_NEW",
+            Self::FormatTooOld { .. } => "E_FORMAT_TOO_OLD",
+            Self::Upstream { .. } => "E_UPSTREAM_REGISTRY",
         }
+    }
+}
 
-        // FUTURE: Stronger file system consistency (atomic file overwrites, rollbacks on error)
-        // FUTURE: "transactional" adding of multiple crates
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum AddError {
+    #[snafu(display("Could not read the crate package"))]
+    ReadCrate { source: io::Error },
 
-        self.read_modify_write(&index_entry.name.clone(), |index_file| {
-            index_file.insert(index_entry.vers.clone(), index_entry);
-            Ok::<_, AddError>(())
-        })?;
+    #[snafu(transparent)]
+    CargoTomlExtract { source: ExtractRootFileError },
 
-        println!("Wrote crate index to `{}`", index_path.display());
+    #[snafu(transparent)]
+    Unpack { source: extract::Error },
 
-        fs::write(&crate_file_path, &crate_file).context(CrateWriteSnafu {
-            path: &crate_file_path,
-        })?;
-        println!("Wrote crate to `{}`", crate_file_path.display());
+    #[snafu(display("The crate package does not contain a Cargo.toml file"))]
+    CargoTomlMissing,
 
-        Ok(())
-    }
+    #[snafu(display("The crate's Cargo.toml is not valid UTF-8"))]
+    CargoTomlUtf8 { source: std::string::FromUtf8Error },
 
-    fn remove(&self, name: CrateName, version: Version) -> Result<(), RemoveError> {
-        use remove_error::*;
+    #[snafu(display("The crate's Cargo.toml is malformed"))]
+    CargoTomlMalformed { source: toml::de::Error },
 
-        self.read_modify_write(&name, |index| {
-            index.remove(&version);
-            Ok::<_, RemoveError>(())
-        })?;
+    #[snafu(display("Could not create the crate's index directory {}", path.display()))]
+    IndexDir { source: io::Error, path: PathBuf },
 
-        let crate_file = self.crate_file_path_for(&name, &version);
-        match fs::remove_file(&crate_file) {
-            Ok(()) => Ok(()),
-            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
-            Err(e) => Err(e).context(DeleteSnafu { path: crate_file }),
-        }
-    }
+    #[snafu(transparent)]
+    IndexModify { source: ReadModifyWriteError },
 
-    #[cfg(feature = "html")]
-    fn generate_html(&self) -> Result<(), HtmlError> {
-        html::write(self)
-    }
+    #[snafu(display("Could not create the crate directory {}", path.display()))]
+    CrateDir { source: io::Error, path: PathBuf },
 
-    #[cfg(not(feature = "html"))]
-    fn generate_html(&self) -> Result<(), HtmlError> {
-        Err(HtmlError)
-    }
+    #[snafu(display("Could not write the crate {}", path.display()))]
+    CrateWrite { source: io::Error, path: PathBuf },
 
-    fn maybe_generate_html(&self) -> Result<(), HtmlError> {
-        if self.config.html.enabled {
-            self.generate_html()
-        } else {
-            Ok(())
-        }
-    }
+    #[cfg(feature = "html")]
+    #[snafu(transparent)]
+    Readme { source: readme::Error },
 
-    fn yank(&self, name: CrateName, version: Version, yanked: bool) -> Result<(), YankError> {
-        use yank_error::*;
+    #[snafu(display("Could not scan the crate"))]
+    Scan { source: scan::Error },
 
-        self.read_modify_write(&name, |index| {
-            let entry = index.get_mut(&version).context(VersionSnafu)?;
-            entry.yanked = yanked;
-            Ok(())
-        })
-    }
+    #[snafu(display("Could not hold the crate for review"))]
+    Hold { source: QuarantineError },
 
-    fn read_modify_write<T, E>(
-        &self,
-        name: &CrateName,
-        modify: impl FnOnce(&mut Index) -> Result<T, E>,
-    ) -> Result<T, E>
-    where
-        E: From<ReadModifyWriteError>,
-    {
-        use read_modify_write_error::*;
+    #[snafu(display("The crate is {size} bytes, more than the limit of {max}"))]
+    TooLarge { size: u64, max: u64 },
 
-        let path = self.index_file_path_for(name);
-        let mut index = Self::parse_index_file(&path).context(IndexParseSnafu { path: &path })?;
+    #[snafu(display("Could not list the crates to compare the name with"))]
+    ListNames { source: ListIndexFilesError },
 
-        let val = modify(&mut index)?;
+    #[snafu(display("The crate's name is not allowed"))]
+    Name { source: names::Error },
 
-        Self::write_index_file(index, &path).context(IndexWriteSnafu { path })?;
+    #[snafu(display("Could not look for the crate under other spellings"))]
+    Find { source: FindCrateError },
 
-        Ok(val)
-    }
+    #[snafu(display("`{name}` is already in the registry as `{existing}`"))]
+    NameTaken {
+        name: CrateName,
+        existing: CrateName,
+    },
 
-    fn list_crate_files(
-        crate_dir: &Path,
-    ) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> {
-        walkdir::WalkDir::new(crate_dir)
-            .into_iter()
-            .flat_map(|entry| {
-                let Ok(entry) = entry else { return Some(entry) };
+    #[snafu(display("Could not retire the crate's oldest versions"))]
+    Retire { source: RetireError },
 
-                let fname = entry.path().file_name()?;
-                let fname = Path::new(fname);
+    #[snafu(transparent)]
+    Audit { source: audit::Error },
 
-                let extension = fname.extension()?;
-                if extension == "crate" {
-                    Some(Ok(entry))
-                } else {
-                    None
-                }
-            })
-    }
+    #[cfg(feature = "nostr")]
+    #[snafu(display("Could not check the approvals of replacing the version"))]
+    Quorum { source: quorum::Error },
 
-    fn list_index_files(&self) -> Result<BTreeSet<PathBuf>, ListIndexFilesError> {
-        use list_index_files_error::*;
+    #[cfg(feature = "encryption")]
+    #[snafu(display("Could not encrypt the crate"))]
+    Encrypt { source: encryption::Error },
+}
 
-        let crate_dir = self.crate_dir();
+impl AddError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::ReadCrate { .. } => "E_CRATE_READ",
+            Self::CargoTomlExtract { .. } => "E_BAD_PACKAGE",
+            Self::Unpack { source } => source.code(),
+            Self::CargoTomlMissing => "E_MANIFEST_MISSING",
+            Self::CargoTomlUtf8 { .. } | Self::CargoTomlMalformed { .. } => "E_MANIFEST_INVALID",
+            Self::IndexModify { source } => source.code(),
+            Self::IndexDir { .. } | Self::CrateDir { .. } | Self::CrateWrite { .. } => {
+                "E_STORAGE_WRITE"
+            }
+            #[cfg(feature = "html")]
+            Self::Readme { source } => source.code(),
+            Self::Scan { source } => source.code(),
+            Self::Hold { source } => source.code(),
+            Self::TooLarge { .. } => "E_CRATE_TOO_LARGE",
+            Self::ListNames { .. } => "E_STORAGE_READ",
+            Self::Name { source } => source.code(),
+            Self::Find { source } => source.code(),
+            Self::NameTaken { .. } => "E_NAME_TAKEN",
+            Self::Retire { source } => source.code(),
+            Self::Audit { source } => source.code(),
+            #[cfg(feature = "nostr")]
+            Self::Quorum { source } => source.code(),
+            #[cfg(feature = "encryption")]
+            Self::Encrypt { source } => source.code(),
+        }
+    }
+}
 
-        let index_files = Self::list_crate_files(&crate_dir)
-            .map(|entry| {
-                let entry = entry.context(WalkdirSnafu { path: &crate_dir })?;
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum RemoveError {
+    #[snafu(transparent)]
+    IndexModify { source: ReadModifyWriteError },
 
-                let mut path = entry.into_path();
-                path.pop();
+    #[snafu(display("Could not delete the file {}", path.display()))]
+    Delete { source: io::Error, path: PathBuf },
 
-                let subdir = path.strip_prefix(&crate_dir).context(PrefixSnafu {
-                    path: &path,
-                    prefix: &crate_dir,
-                })?;
-                let index_path = self.path.join(subdir);
-                Ok(index_path)
-            })
-            .collect::<Result<BTreeSet<_>, ListIndexFilesError>>();
+    #[snafu(transparent)]
+    Audit { source: audit::Error },
+}
 
-        match index_files {
-            Err(e) if e.is_not_found() => Ok(Default::default()),
-            r => r,
+impl RemoveError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::IndexModify { source } => source.code(),
+            Self::Delete { .. } => "E_STORAGE_WRITE",
+            Self::Audit { source } => source.code(),
         }
     }
+}
 
-    fn list_all(&self) -> Result<ListAll, ListAllError> {
-        use list_all_error::*;
-
-        let mut crates = BTreeMap::new();
-
-        for path in self.list_index_files()? {
-            let index = Self::parse_index_file(&path).context(ParseSnafu { path })?;
+#[cfg(feature = "html")]
+use html::Error as HtmlError;
 
-            if let Some(entry) = index.values().next() {
-                crates.insert(entry.name.clone(), index);
-            }
-        }
+#[cfg(not(feature = "html"))]
+#[derive(Debug, Snafu)]
+#[snafu(display("Margo was not compiled with the HTML feature enabled. This binary will not be able to generate HTML files"))]
+struct HtmlError;
 
-        Ok(crates)
+#[cfg(not(feature = "html"))]
+impl HtmlError {
+    fn code(&self) -> &'static str {
+        "E_FEATURE_DISABLED"
     }
+}
 
-    fn parse_index_file(path: &Path) -> Result<Index, ParseIndexError> {
-        use parse_index_error::*;
-
-        let index_file = match File::open(path) {
-            Ok(f) => f,
-            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
-            Err(e) => Err(e).context(OpenSnafu)?,
-        };
-        let index_file = BufReader::new(index_file);
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum YankError {
+    #[snafu(display("The version does not exist in the index"))]
+    Version,
 
-        let mut index = BTreeMap::new();
+    #[snafu(transparent)]
+    Modify { source: ReadModifyWriteError },
 
-        for (i, line) in index_file.lines().enumerate() {
-            let line = line.context(ReadSnafu { line: i })?;
-            let entry =
-                serde_json::from_str::<index_entry::Root>(&line).context(ParseSnafu { line: i })?;
+    #[snafu(transparent)]
+    Audit { source: audit::Error },
+}
 
-            index.insert(entry.vers.clone(), entry);
+impl YankError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Version => "E_VERSION_NOT_FOUND",
+            Self::Modify { source } => source.code(),
+            Self::Audit { source } => source.code(),
         }
-
-        Ok(index)
     }
+}
 
-    fn write_index_file(index_file: Index, path: &Path) -> Result<(), WriteIndexError> {
-        use write_index_error::*;
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum RetireError {
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: ParseIndexError },
 
-        let file = File::create(path).context(OpenSnafu)?;
-        let mut file = BufWriter::new(file);
+    #[snafu(transparent)]
+    Yank { source: YankError },
 
-        for entry in index_file.values() {
-            serde_json::to_writer(&mut file, entry).context(EntrySerializeSnafu)?;
-            file.write_all(b"\n").context(EntryNewlineSnafu)?;
-        }
+    #[snafu(transparent)]
+    Remove { source: RemoveError },
+}
 
-        Ok(())
+impl RetireError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Index { .. } => "E_INDEX_CORRUPT",
+            Self::Yank { source } => source.code(),
+            Self::Remove { source } => source.code(),
+        }
     }
+}
 
-    fn crate_dir(&self) -> PathBuf {
-        self.path.join(CRATE_DIR_NAME)
-    }
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum QuarantineError {
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: ParseIndexError },
 
-    #[cfg(test)]
-    fn margo_config_toml_path(&self) -> PathBuf {
-        self.path.join(CONFIG_FILE_NAME)
-    }
+    #[snafu(display("The version does not exist in the index"))]
+    Version,
 
-    fn config_json_path(&self) -> PathBuf {
-        self.path.join("config.json")
-    }
+    #[snafu(transparent)]
+    Quarantine { source: quarantine::Error },
 
-    fn index_file_path_for(&self, name: &CrateName) -> PathBuf {
-        let mut index_path = self.path.clone();
-        name.append_prefix_directories(&mut index_path);
-        index_path.push(name);
-        index_path
-    }
+    #[snafu(transparent)]
+    Modify { source: ReadModifyWriteError },
 
-    fn crate_dir_for(&self, name: &CrateName) -> PathBuf {
-        let mut crate_dir = self.crate_dir();
-        name.append_prefix_directories(&mut crate_dir);
-        crate_dir.push(name);
-        crate_dir
-    }
+    #[snafu(transparent)]
+    Audit { source: audit::Error },
+}
 
-    fn crate_file_path_for(&self, name: &CrateName, version: &Version) -> PathBuf {
-        let mut crate_file_path = self.crate_dir_for(name);
-        crate_file_path.push(format!("{}.crate", version));
-        crate_file_path
+impl QuarantineError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Index { .. } => "E_INDEX_CORRUPT",
+            Self::Version => "E_VERSION_NOT_FOUND",
+            Self::Quarantine { source } => source.code(),
+            Self::Modify { source } => source.code(),
+            Self::Audit { source } => source.code(),
+        }
     }
 }
 
 #[derive(Debug, Snafu)]
 #[snafu(module)]
-enum InitializeError {
-    #[snafu(display("Could not create the registry directory"))]
-    RegistryCreate { source: io::Error },
+enum VisibilityError {
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: ParseIndexError },
 
-    #[snafu(display("Could not serialize the registry's internal configuration"))]
-    ConfigTomlSerialize { source: toml::ser::Error },
+    #[snafu(display("The crate `{name}` does not exist"))]
+    NotFound { name: CrateName },
 
-    #[snafu(display("Could not write the registry's internal configuration to {}", path.display()))]
-    ConfigTomlWrite { source: io::Error, path: PathBuf },
+    #[snafu(transparent)]
+    Visibility { source: visibility::Error },
 
-    #[snafu(display("Could not serialize the registry's public configuration"))]
-    ConfigJsonSerialize { source: serde_json::Error },
+    #[snafu(transparent)]
+    Audit { source: audit::Error },
+}
 
-    #[snafu(display("Could not write the registry's public configuration to {}", path.display()))]
-    ConfigJsonWrite { source: io::Error, path: PathBuf },
+impl VisibilityError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Index { .. } => "E_INDEX_CORRUPT",
+            Self::NotFound { .. } => "E_CRATE_NOT_FOUND",
+            Self::Visibility { source } => source.code(),
+            Self::Audit { source } => source.code(),
+        }
+    }
 }
 
 #[derive(Debug, Snafu)]
-#[snafu(module)]
-enum OpenError {
-    #[snafu(display("Could not open the registry's internal configuration at {}", path.display()))]
-    Read { source: io::Error, path: PathBuf },
+enum ConflictError {
+    #[snafu(transparent)]
+    Conflicts { source: conflicts::Error },
 
-    #[snafu(display("Could not deserialize the registry's internal configuration at {}", path.display()))]
-    Deserialize {
-        source: toml::de::Error,
-        path: PathBuf,
-    },
+    #[snafu(transparent)]
+    Remove { source: RemoveError },
+
+    #[snafu(transparent)]
+    Audit { source: audit::Error },
 }
 
-impl OpenError {
-    fn is_not_found(&self) -> bool {
+impl ConflictError {
+    fn code(&self) -> &'static str {
         match self {
-            Self::Read { source, .. } => source.kind() == io::ErrorKind::NotFound,
-            Self::Deserialize { .. } => false,
+            Self::Conflicts { source } => source.code(),
+            Self::Remove { source } => source.code(),
+            Self::Audit { source } => source.code(),
         }
     }
 }
 
 #[derive(Debug, Snafu)]
 #[snafu(module)]
-enum AddError {
-    #[snafu(display("Could not read the crate package"))]
-    ReadCrate { source: io::Error },
+enum VerifyError {
+    #[snafu(display("Could not read the registry's public configuration at {}", path.display()))]
+    ConfigJsonRead { source: io::Error, path: PathBuf },
 
     #[snafu(transparent)]
-    CargoTomlExtract { source: ExtractRootCargoTomlError },
-
-    #[snafu(display("The crate package does not contain a Cargo.toml file"))]
-    CargoTomlMissing,
+    Client { source: client::Error },
 
-    #[snafu(display("The crate's Cargo.toml is not valid UTF-8"))]
-    CargoTomlUtf8 { source: std::string::FromUtf8Error },
+    #[snafu(display("Could not list the crates"))]
+    #[snafu(context(false))]
+    ListAll { source: ListAllError },
 
-    #[snafu(display("The crate's Cargo.toml is malformed"))]
-    CargoTomlMalformed { source: toml::de::Error },
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
 
-    #[snafu(display("Could not create the crate's index directory {}", path.display()))]
-    IndexDir { source: io::Error, path: PathBuf },
+    #[snafu(display("Found {count} problem(s) in the registry"))]
+    Failed { count: usize },
+}
 
-    #[snafu(transparent)]
-    IndexModify { source: ReadModifyWriteError },
+impl VerifyError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::ConfigJsonRead { .. } => "E_CONFIG_READ",
+            Self::Client { source } => source.code(),
+            Self::ListAll { source } => source.code(),
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Failed { .. } => "E_VERIFY_FAILED",
+        }
+    }
+}
 
-    #[snafu(display("Could not create the crate directory {}", path.display()))]
-    CrateDir { source: io::Error, path: PathBuf },
+#[derive(Debug)]
+enum VerifyProblem {
+    OutsideRegistry {
+        url: Url,
+    },
+    IndexMissing {
+        name: CrateName,
+        path: PathBuf,
+    },
+    CrateMissing {
+        name: CrateName,
+        version: Version,
+        path: PathBuf,
+    },
+    Mismatch {
+        source: client::Error,
+    },
+    NotReproducible {
+        difference: reproducible::Difference,
+    },
+}
 
-    #[snafu(display("Could not write the crate {}", path.display()))]
-    CrateWrite { source: io::Error, path: PathBuf },
+impl fmt::Display for VerifyProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutsideRegistry { url } => write!(f, "{url} is not served by this registry"),
+            Self::IndexMissing { name, path } => write!(
+                f,
+                "The index file for {name} is missing from {}",
+                path.display(),
+            ),
+            Self::CrateMissing {
+                name,
+                version,
+                path,
+            } => write!(f, "{name} {version} is missing from {}", path.display()),
+            Self::Mismatch { source } => source.fmt(f),
+            Self::NotReproducible { difference } => difference.fmt(f),
+        }
+    }
 }
 
-#[derive(Debug, Snafu)]
-#[snafu(module)]
-enum RemoveError {
-    #[snafu(transparent)]
-    IndexModify { source: ReadModifyWriteError },
+/// Windows limits paths to 260 characters unless they are absolute and
+/// start with `\\?\`, and the crate directories of long names with
+/// long versions can go past that. Such paths are used as they are, so
+/// they must be built a component at a time rather than by joining
+/// strings containing `/`.
+#[cfg(windows)]
+fn long_path(path: PathBuf) -> PathBuf {
+    use std::{
+        ffi::OsString,
+        path::{Component, Prefix},
+    };
 
-    #[snafu(display("Could not delete the crate file {}", path.display()))]
-    Delete { source: io::Error, path: PathBuf },
+    let Ok(absolute) = std::path::absolute(&path) else {
+        return path;
+    };
+    let Some(Component::Prefix(prefix)) = absolute.components().next() else {
+        return absolute;
+    };
+
+    let mut long = OsString::new();
+    match prefix.kind() {
+        Prefix::Disk(_) => {
+            long.push(r"\\?\");
+            long.push(&absolute);
+        }
+        Prefix::UNC(..) => {
+            let Some(rest) = absolute.to_str().and_then(|p| p.strip_prefix(r"\\")) else {
+                return absolute;
+            };
+            long.push(r"\\?\UNC\");
+            long.push(rest);
+        }
+        _ => return absolute,
+    }
+
+    long.into()
 }
 
-#[cfg(feature = "html")]
-use html::Error as HtmlError;
+#[cfg(not(windows))]
+fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
 
-#[cfg(not(feature = "html"))]
-#[derive(Debug, Snafu)]
-#[snafu(display("Margo was not compiled with the HTML feature enabled. This binary will not be able to generate HTML files"))]
-struct HtmlError;
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
 #[derive(Debug, Snafu)]
 #[snafu(module)]
-enum YankError {
+enum ExtensionsError {
     #[snafu(display("The version does not exist in the index"))]
     Version,
 
@@ -1054,6 +6954,15 @@ enum YankError {
     Modify { source: ReadModifyWriteError },
 }
 
+impl ExtensionsError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Version => "E_VERSION_NOT_FOUND",
+            Self::Modify { source } => source.code(),
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(module)]
 enum ReadModifyWriteError {
@@ -1070,6 +6979,15 @@ enum ReadModifyWriteError {
     },
 }
 
+impl ReadModifyWriteError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::IndexParse { .. } => "E_INDEX_CORRUPT",
+            Self::IndexWrite { .. } => "E_INDEX_WRITE",
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(module)]
 enum ListIndexFilesError {
@@ -1119,22 +7037,37 @@ enum ListAllError {
     },
 }
 
+impl ListAllError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::ListIndex { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_INDEX_CORRUPT",
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(module)]
-enum ParseIndexError {
-    #[snafu(display("Could not open the file"))]
-    Open { source: io::Error },
-
-    #[snafu(display("Could not read line {line}"))]
-    Read { source: io::Error, line: usize },
+enum FindCrateError {
+    #[snafu(display("Could not list the index directory {}", path.display()))]
+    ReadDir { source: io::Error, path: PathBuf },
 
-    #[snafu(display("Could not parse line {line}"))]
+    #[snafu(display("Unable to parse the crate index file at `{}`", path.display()))]
     Parse {
-        source: serde_json::Error,
-        line: usize,
+        source: ParseIndexError,
+        path: PathBuf,
     },
 }
 
+impl FindCrateError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::ReadDir { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_INDEX_CORRUPT",
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(module)]
 enum WriteIndexError {
@@ -1146,12 +7079,35 @@ enum WriteIndexError {
 
     #[snafu(display("Could not write the entry's newline"))]
     EntryNewline { source: io::Error },
+
+    #[snafu(display("Could not finish writing the file"))]
+    Flush { source: io::Error },
+
+    #[snafu(display("Could not replace the file"))]
+    Rename { source: io::Error },
+}
+
+fn read_cargo_toml(crate_file: &[u8]) -> Result<cargo_toml::Root, AddError> {
+    use add_error::*;
+
+    let cargo_toml =
+        extract_root_file(crate_file, Path::new("Cargo.toml"))?.context(CargoTomlMissingSnafu)?;
+
+    let cargo_toml = String::from_utf8(cargo_toml).context(CargoTomlUtf8Snafu)?;
+    toml::from_str(&cargo_toml).context(CargoTomlMalformedSnafu)
 }
 
-fn extract_root_cargo_toml(
+/// Manifests and READMEs are read into memory whole, so one that would
+/// decompress to more than this is refused.
+const MAX_ROOT_FILE_LEN: u64 = 16 * 1024 * 1024;
+
+/// Reads the file at `fname`, relative to the package's top-level
+/// directory, out of a `.crate` tarball.
+fn extract_root_file(
     crate_data: &[u8],
-) -> Result<Option<Vec<u8>>, ExtractRootCargoTomlError> {
-    use extract_root_cargo_toml_error::*;
+    fname: &Path,
+) -> Result<Option<Vec<u8>>, ExtractRootFileError> {
+    use extract_root_file_error::*;
 
     let crate_data = flate2::read::GzDecoder::new(crate_data);
     let mut crate_data = tar::Archive::new(crate_data);
@@ -1175,9 +7131,12 @@ fn extract_root_cargo_toml(
             }
         };
 
-        let fname = path.strip_prefix(dirname).context(PrefixSnafu)?;
+        let entry_fname = path.strip_prefix(dirname).context(PrefixSnafu)?;
 
-        if fname == Path::new("Cargo.toml") {
+        // A link would stand in for a file the package does not have
+        if entry_fname == fname && entry.header().entry_type().is_file() {
+            let max = MAX_ROOT_FILE_LEN;
+            ensure!(entry.size() <= max, TooLargeSnafu { max });
             let mut data = vec![];
             entry.read_to_end(&mut data).context(ReadSnafu)?;
             return Ok(Some(data));
@@ -1189,7 +7148,7 @@ fn extract_root_cargo_toml(
 
 #[derive(Debug, Snafu)]
 #[snafu(module)]
-enum ExtractRootCargoTomlError {
+enum ExtractRootFileError {
     #[snafu(display("Could not get the entries of the crate package"))]
     Entries { source: io::Error },
 
@@ -1205,8 +7164,11 @@ enum ExtractRootCargoTomlError {
     #[snafu(display("Could not remove the path prefix from the crate package entry"))]
     Prefix { source: std::path::StripPrefixError },
 
-    #[snafu(display("Could not read the crate package entry for Cargo.toml"))]
+    #[snafu(display("Could not read the crate package entry"))]
     Read { source: io::Error },
+
+    #[snafu(display("The crate package entry unpacks to more than {max} bytes"))]
+    TooLarge { max: u64 },
 }
 
 fn adapt_cargo_toml_to_index_entry(
@@ -1266,17 +7228,23 @@ fn adapt_cargo_toml_to_index_entry(
 
     // FUTURE: Opt-in to checking that all dependencies already exist
 
+    let (features, features2) = features::split(cargo_toml.features);
+
     index_entry::Root {
         name: cargo_toml.package.name,
         vers: cargo_toml.package.version,
         deps,
         cksum: checksum_hex,
-        features: cargo_toml.features,
+        features,
         yanked: false,
+        withheld: false,
+        decrypted_cksum: None,
         links: cargo_toml.package.links,
         v: 2,
-        features2: Default::default(),
+        features2,
         rust_version: cargo_toml.package.rust_version,
+        edition: cargo_toml.package.edition,
+        extensions: Default::default(),
     }
 }
 
@@ -1367,6 +7335,29 @@ mod cargo_toml {
 
         #[serde(default)]
         pub rust_version: Option<RustVersion>,
+
+        #[serde(default)]
+        pub edition: Option<String>,
+
+        #[cfg(feature = "html")]
+        #[serde(flatten)]
+        pub metadata: crate::readme::Metadata,
+
+        #[cfg(not(feature = "html"))]
+        #[serde(default)]
+        description: Option<String>,
+    }
+
+    impl Package {
+        #[cfg(feature = "html")]
+        pub fn description(&self) -> Option<&str> {
+            self.metadata.description.as_deref()
+        }
+
+        #[cfg(not(feature = "html"))]
+        pub fn description(&self) -> Option<&str> {
+            self.description.as_deref()
+        }
     }
 
     #[derive(Debug, Deserialize)]
@@ -1409,6 +7400,13 @@ const CRATES_IO_INDEX_URL: &str = "https://github.com/rust-lang/crates.io-index"
 #[derive(Debug)]
 struct Global {
     crates_io_index_url: Url,
+
+    /// Set by `--read-only`; registries can also be read-only by
+    /// configuration.
+    read_only: bool,
+
+    /// How long commands show their progress.
+    progress: progress::Mode,
 }
 
 impl Global {
@@ -1417,6 +7415,8 @@ impl Global {
 
         Ok(Self {
             crates_io_index_url: CRATES_IO_INDEX_URL.parse().context(CratesIoIndexUrlSnafu)?,
+            read_only: false,
+            progress: progress::Mode::Quiet,
         })
     }
 }
@@ -1428,6 +7428,12 @@ enum GlobalError {
     CratesIoIndexUrl { source: url::ParseError },
 }
 
+impl GlobalError {
+    fn code(&self) -> &'static str {
+        "E_INTERNAL"
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "version")]
 enum Config {
@@ -1439,11 +7445,56 @@ enum Config {
 struct ConfigV1 {
     base_url: Url,
 
+    /// How the registry's files are laid out. See [`migrate`].
+    #[serde(default = "migrate::unversioned")]
+    format: u32,
+
     #[serde(default)]
     auth_required: bool,
 
+    /// Refuse changes, as for a public download mirror or during
+    /// maintenance.
+    #[serde(default)]
+    read_only: bool,
+
     #[serde(default)]
     html: ConfigV1Html,
+
+    #[serde(default)]
+    feed: ConfigV1Feed,
+
+    #[serde(default)]
+    docs: ConfigV1Docs,
+
+    #[serde(default)]
+    scan: ConfigV1Scan,
+
+    #[serde(default)]
+    sandbox: sandbox::Policy,
+
+    #[serde(default)]
+    limits: ConfigV1Limits,
+
+    #[serde(default)]
+    names: names::Policy,
+
+    #[serde(default)]
+    tiering: ConfigV1Tiering,
+
+    #[serde(default)]
+    regenerate: ConfigV1Regenerate,
+
+    #[cfg(feature = "p2p")]
+    #[serde(default)]
+    announcements: ConfigV1Announcements,
+
+    #[cfg(feature = "nostr")]
+    #[serde(default)]
+    quorum: ConfigV1Quorum,
+
+    #[cfg(feature = "encryption")]
+    #[serde(default)]
+    encryption: ConfigV1Encryption,
 }
 
 impl ConfigV1 {
@@ -1481,376 +7532,229 @@ impl ConfigV1Html {
     }
 }
 
-mod config_json {
-    use serde::Serialize;
-
-    #[derive(Debug, Serialize)]
-    #[serde(rename_all = "kebab-case")]
-    pub struct Root {
-        // This field cannot be a `url::Url` because that type
-        // percent-escapes the `{` and `}` characters. Cargo performs
-        // string-replacement on this value based on those literal `{`
-        // and `}` characters.
-        pub dl: String,
-
-        pub api: Option<String>, // Modified
-
-        /// A private registry requires all operations to be authenticated.
-        ///
-        /// This includes API requests, crate downloads and sparse
-        /// index updates.
-        pub auth_required: bool,
-    }
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConfigV1Feed {
+    #[serde(default)]
+    enabled: bool,
 }
 
-mod index_entry {
-    use semver::{Version, VersionReq};
-    use serde::{Deserialize, Serialize};
-    use std::collections::BTreeMap;
-    use url::Url;
-
-    use crate::common::{CrateName, RustVersion};
-
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct Root {
-        /// The name of the package.
-        pub name: CrateName,
-
-        /// The version of the package this row is describing.
-        ///
-        /// This must be a valid version number according to the
-        /// Semantic Versioning 2.0.0 spec at https://semver.org/.
-        pub vers: Version,
-
-        /// Direct dependencies of the package.
-        pub deps: Vec<Dependency>,
-
-        /// A SHA256 checksum of the `.crate` file.
-        pub cksum: String,
-
-        /// Set of features defined for the package.
-        ///
-        /// Each feature maps to features or dependencies it enables.
-        pub features: BTreeMap<String, Vec<String>>,
-
-        /// Boolean of whether or not this version has been yanked.
-        pub yanked: bool,
-
-        /// The `links` value from the package's manifest.
-        #[serde(skip_serializing_if = "Option::is_none")]
-        pub links: Option<String>,
-
-        /// The schema version of this entry.
-        //
-        /// If this not specified, it should be interpreted as the default of 1.
-        //
-        /// Cargo (starting with version 1.51) will ignore versions it does not
-        /// recognize. This provides a method to safely introduce changes to index
-        /// entries and allow older versions of cargo to ignore newer entries it
-        /// doesn't understand. Versions older than 1.51 ignore this field, and
-        /// thus may misinterpret the meaning of the index entry.
-        //
-        /// The current values are:
-        //
-        /// * 1: The schema as documented here, not including newer additions.
-        ///   This is honored in Rust version 1.51 and newer.
-        /// * 2: The addition of the `features2` field.
-        ///   This is honored in Rust version 1.60 and newer.
-        pub v: u32,
-
-        /// Features with new, extended syntax, such as namespaced
-        /// features (`dep:`) and weak dependencies (`pkg?/feat`).
-        //
-        /// This is separated from `features` because versions older than 1.19
-        /// will fail to load due to not being able to parse the new syntax, even
-        /// with a `Cargo.lock` file.
-        //
-        /// Cargo will merge any values listed here with the "features" field.
-        //
-        /// If this field is included, the "v" field should be set to at least 2.
-        //
-        /// Registries are not required to use this field for extended feature
-        /// syntax, they are allowed to include those in the "features" field.
-        /// Using this is only necessary if the registry wants to support cargo
-        /// versions older than 1.19, which in practice is only crates.io since
-        /// those older versions do not support other registries.
-        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
-        pub features2: BTreeMap<String, Vec<String>>,
-
-        /// The minimal supported Rust version
-        ///
-        /// This must be a valid version requirement without an operator (e.g. no `=`)
-        #[serde(skip_serializing_if = "Option::is_none")]
-        pub rust_version: Option<RustVersion>,
-    }
-
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct Dependency {
-        /// Name of the dependency.
-        ///
-        /// If the dependency is renamed from the original package
-        /// name, this is the new name. The original package name is
-        /// stored in the `package` field.
-        pub name: String,
-
-        /// The SemVer requirement for this dependency.
-        ///
-        /// This must be a valid version requirement defined at
-        /// https://doc.rust-lang.org/cargo/reference/specifying-dependencies.html.
-        pub req: VersionReq,
-
-        /// Features enabled for this dependency.
-        pub features: Vec<String>,
-
-        /// Whether or not this is an optional dependency.
-        pub optional: bool,
-
-        /// Whether or not default features are enabled.
-        pub default_features: bool,
-
-        /// The target platform for the dependency.
-        ///
-        /// A string such as `cfg(windows)`.
-        #[serde(skip_serializing_if = "Option::is_none")]
-        pub target: Option<String>,
-
-        /// The dependency kind.
-        ///
-        /// Note: this is a required field, but a small number of entries
-        /// exist in the crates.io index with either a missing or null
-        /// `kind` field due to implementation bugs.
-        pub kind: DependencyKind,
-
-        /// The URL of the index of the registry where this dependency
-        /// is from.
-        ///
-        /// If not specified or null, it is assumed the dependency is
-        /// in the current registry.
-        #[serde(skip_serializing_if = "Option::is_none")]
-        pub registry: Option<Url>,
-
-        /// If the dependency is renamed, this is the actual package
-        /// name.
-        ///
-        /// If not specified or null, this dependency is not renamed.
-        #[serde(skip_serializing_if = "Option::is_none")]
-        pub package: Option<String>,
-    }
-
-    #[derive(Debug, Serialize, Deserialize)]
-    #[serde(rename_all = "snake_case")]
-    pub enum DependencyKind {
-        #[allow(unused)]
-        // Stored in the index, but not actually used by Cargo
-        Dev,
-        Build,
-        Normal,
-    }
+impl ConfigV1Feed {
+    const USER_DEFAULT_ENABLED: bool = true;
 }
 
-mod common {
-    use ascii::{AsciiChar, AsciiStr, AsciiString};
-    use semver::Version;
-    use serde::{de::Error, Deserialize, Serialize};
-    use snafu::prelude::*;
-    use std::{
-        borrow::Cow,
-        fmt, ops,
-        path::{Path, PathBuf},
-        str::FromStr,
-    };
-
-    /// Contains only alphanumeric, `-`, or `_` characters.
-    #[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
-    pub struct CrateName(AsciiString);
-
-    impl CrateName {
-        pub fn as_str(&self) -> &str {
-            self.0.as_str()
-        }
+/// Building documentation runs code from the crate, so it is never
+/// enabled by default.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConfigV1Docs {
+    #[serde(default)]
+    enabled: bool,
+}
 
-        pub fn len(&self) -> usize {
-            self.0.len()
-        }
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigV1Scan {
+    #[serde(default)]
+    enabled: bool,
 
-        pub fn append_prefix_directories(&self, index_path: &mut PathBuf) {
-            match self.len() {
-                0 => unreachable!(),
-                1 => index_path.push("1"),
-                2 => index_path.push("2"),
-                3 => {
-                    let a = &self[0..1];
+    /// SHA-256 digests of crates or files that are always held.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    known_bad: BTreeSet<String>,
 
-                    index_path.push("3");
-                    index_path.push(a.as_str());
-                }
-                _ => {
-                    let ab = &self[0..2];
-                    let cd = &self[2..4];
+    /// External scanners, each a program and its arguments.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    commands: Vec<Vec<String>>,
+}
 
-                    index_path.push(ab.as_str());
-                    index_path.push(cd.as_str());
-                }
-            };
-        }
-    }
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigV1Limits {
+    /// The largest `.crate` file, in bytes, that may be added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_crate_size: Option<u64>,
 
-    impl fmt::Display for CrateName {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            self.0.fmt(f)
-        }
-    }
+    /// The most bytes a package's files may add up to once unpacked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_unpacked_size: Option<u64>,
 
-    impl FromStr for CrateName {
-        type Err = CrateNameError;
+    /// The most files and directories a package may have.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_package_entries: Option<usize>,
 
-        fn from_str(s: &str) -> Result<Self, Self::Err> {
-            s.try_into()
-        }
-    }
+    /// How many versions of each crate are kept. Adding another retires
+    /// the oldest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_versions: Option<usize>,
 
-    impl TryFrom<&str> for CrateName {
-        type Error = CrateNameError;
+    #[serde(default)]
+    excess_versions: ExcessVersions,
+}
 
-        fn try_from(value: &str) -> Result<Self, Self::Error> {
-            value.to_owned().try_into()
-        }
-    }
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigV1Tiering {
+    /// Where demoted `.crate` files are kept, usually mounted object
+    /// storage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cold_dir: Option<PathBuf>,
+
+    /// How long a `.crate` file may go without being downloaded before
+    /// it is demoted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    demote_after_days: Option<u64>,
+}
 
-    impl TryFrom<String> for CrateName {
-        type Error = CrateNameError;
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigV1Regenerate {
+    /// How long the daemon waits after a change, for more changes,
+    /// before regenerating the HTML, the feed and the index snapshot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quiet_ms: Option<u64>,
+}
 
-        fn try_from(value: String) -> Result<Self, Self::Error> {
-            AsciiString::from_ascii(value)
-                .map_err(|e| e.ascii_error())?
-                .try_into()
-        }
-    }
+#[cfg(feature = "p2p")]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigV1Announcements {
+    /// Peer IDs whose gossiped announcements are accepted. When empty,
+    /// any peer's are.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    trusted_peers: BTreeSet<String>,
 
-    impl TryFrom<AsciiString> for CrateName {
-        type Error = CrateNameError;
+    #[serde(default)]
+    limits: p2p::Limits,
 
-        fn try_from(value: AsciiString) -> Result<Self, Self::Error> {
-            use crate_name_error::*;
+    #[serde(default)]
+    seeding: seeding::Policy,
 
-            let first = value.first().context(EmptySnafu)?;
-            ensure!(first.is_alphabetic(), InitialAlphaSnafu);
+    #[serde(default)]
+    dials: dials::Policy,
 
-            if let Some(chr) = value.chars().find(|&chr| !valid_crate_name_char(chr)) {
-                return ContainsInvalidCharSnafu { chr }.fail();
-            }
+    /// Faults to inject into transfers to peers, for testing.
+    #[cfg(feature = "chaos")]
+    #[serde(default)]
+    chaos: chaos::Policy,
+
+    /// Encodings to keep recompressed copies of `.crate` files in, for
+    /// sending to peers.
+    #[cfg(feature = "zstd")]
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    copies: BTreeSet<copies::Encoding>,
+
+    /// How to split `.crate` files into shards for peers to keep. When
+    /// unset, they are not split.
+    #[cfg(feature = "erasure")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    erasure: Option<erasure::Scheme>,
+}
 
-            Ok(Self(value))
-        }
-    }
+#[cfg(feature = "nostr")]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigV1Quorum {
+    /// The nostr public keys, hex or `npub`, of the operators who
+    /// approve sensitive operations.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    operators: Vec<String>,
+
+    /// How many of the operators must approve removing a version,
+    /// replacing one, or rotating the key. When 0, none need to.
+    #[serde(default)]
+    threshold: usize,
+}
 
-    #[derive(Debug, Snafu)]
-    #[snafu(module)]
-    pub enum CrateNameError {
-        #[snafu(display("The crate name cannot be empty"))]
-        Empty,
+#[cfg(feature = "encryption")]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigV1Encryption {
+    /// The key that `.crate` files are encrypted with as they are
+    /// added. When unset, they are stored as they are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_file: Option<PathBuf>,
+}
 
-        #[snafu(display("The crate name must start with an alphabetic character"))]
-        InitialAlpha,
+/// What happens to versions beyond `max-versions`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ExcessVersions {
+    /// Yanked, so only unyanked versions count towards the limit.
+    #[default]
+    Yank,
 
-        #[snafu(display("The crate name must only contain alphanumeric characters, hyphen (-) or underscore (_), not {chr}"))]
-        ContainsInvalidChar { chr: char },
+    /// Removed from the index and from disk.
+    Remove,
+}
 
-        #[snafu(transparent)]
-        NotAscii { source: ascii::AsAsciiStrError },
-    }
+mod common {
+    use semver::Version;
+    use snafu::prelude::*;
+    use std::str::FromStr;
 
-    impl<'de> Deserialize<'de> for CrateName {
-        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-        where
-            D: serde::Deserializer<'de>,
-        {
-            let ascii: AsciiString = Deserialize::deserialize(deserializer)?;
-            Self::try_from(ascii).map_err(D::Error::custom)
-        }
-    }
+    pub use margo_client::common::*;
 
-    impl ops::Index<ops::Range<usize>> for CrateName {
-        type Output = AsciiStr;
+    /// Matches crate names case-insensitively; `*` matches any run of
+    /// characters.
+    #[cfg(any(
+        feature = "publish-workspace",
+        feature = "server",
+        feature = "sync-crates-io",
+    ))]
+    pub fn glob_matches(glob: &str, name: &str) -> bool {
+        let glob = glob.to_ascii_lowercase();
+        let name = name.to_ascii_lowercase();
+
+        let mut parts = glob.split('*');
+        let first = parts.next().unwrap_or_default();
+        let Some(mut rest) = name.strip_prefix(first) else {
+            return false;
+        };
+        let Some(last) = parts.next_back() else {
+            return rest.is_empty();
+        };
 
-        fn index(&self, index: ops::Range<usize>) -> &Self::Output {
-            self.0.index(index)
+        for part in parts {
+            match rest.find(part) {
+                Some(i) => rest = &rest[i + part.len()..],
+                None => return false,
+            }
         }
-    }
 
-    impl AsRef<Path> for CrateName {
-        fn as_ref(&self) -> &Path {
-            self.0.as_str().as_ref()
-        }
+        rest.ends_with(last)
     }
 
-    fn valid_crate_name_char(chr: AsciiChar) -> bool {
-        chr.is_alphanumeric() || chr == AsciiChar::UnderScore || chr == AsciiChar::Minus
+    /// A version of a crate, written `{name}@{version}`.
+    #[derive(Debug, Clone)]
+    pub struct CrateVersion {
+        pub name: CrateName,
+        pub version: Version,
     }
 
-    #[derive(Debug)]
-    pub struct RustVersion(Version);
-
-    impl FromStr for RustVersion {
-        type Err = RustVersionError;
+    impl FromStr for CrateVersion {
+        type Err = CrateVersionError;
 
         fn from_str(s: &str) -> Result<Self, Self::Err> {
-            use rust_version_error::*;
-
-            let v: Version = match s.parse() {
-                Ok(v) => v,
-                Err(e) => {
-                    let version = [s, ".0"].concat();
-                    match version.parse() {
-                        Ok(v) => v,
-                        Err(_) => return Err(e)?,
-                    }
-                }
-            };
+            use crate_version_error::*;
 
-            ensure!(v.pre.is_empty(), PrereleaseSnafu);
-            ensure!(v.build.is_empty(), BuildSnafu);
+            let (name, version) = s.split_once('@').context(MissingVersionSnafu)?;
 
-            Ok(Self(v))
+            Ok(Self {
+                name: name.parse()?,
+                version: version.parse()?,
+            })
         }
     }
 
     #[derive(Debug, Snafu)]
     #[snafu(module)]
-    pub enum RustVersionError {
-        #[snafu(transparent)]
-        Semver { source: semver::Error },
-
-        #[snafu(display("May not specify a prerelease version"))]
-        Prerelease,
-
-        #[snafu(display("May not specify a version with build metadata"))]
-        Build,
-    }
-
-    impl From<RustVersion> for Version {
-        fn from(value: RustVersion) -> Self {
-            value.0
-        }
-    }
+    pub enum CrateVersionError {
+        #[snafu(display("Expected {{name}}@{{version}}"))]
+        MissingVersion,
 
-    impl<'de> serde::Deserialize<'de> for RustVersion {
-        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-        where
-            D: serde::Deserializer<'de>,
-        {
-            let version = Cow::<str>::deserialize(deserializer)?;
-            version.parse().map_err(D::Error::custom)
-        }
-    }
+        #[snafu(transparent)]
+        Name { source: CrateNameError },
 
-    impl serde::Serialize for RustVersion {
-        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: serde::Serializer,
-        {
-            self.0.serialize(serializer)
-        }
+        #[snafu(transparent)]
+        Version { source: semver::Error },
     }
 }
 
@@ -1862,11 +7766,27 @@ mod test {
     fn default_config() -> ConfigV1 {
         ConfigV1 {
             base_url: "http://example.com".parse().unwrap(),
+            format: migrate::CURRENT,
             auth_required: false,
+            read_only: false,
             html: ConfigV1Html {
                 enabled: false,
                 suggested_registry_name: None,
             },
+            feed: ConfigV1Feed { enabled: false },
+            docs: ConfigV1Docs { enabled: false },
+            scan: ConfigV1Scan::default(),
+            sandbox: sandbox::Policy::default(),
+            limits: ConfigV1Limits::default(),
+            names: names::Policy::default(),
+            tiering: ConfigV1Tiering::default(),
+            regenerate: ConfigV1Regenerate::default(),
+            #[cfg(feature = "p2p")]
+            announcements: ConfigV1Announcements::default(),
+            #[cfg(feature = "nostr")]
+            quorum: ConfigV1Quorum::default(),
+            #[cfg(feature = "encryption")]
+            encryption: ConfigV1Encryption::default(),
         }
     }
 
@@ -1896,6 +7816,222 @@ mod test {
         assert_eq!(1, index_contents.lines().count());
     }
 
+    #[tokio::test]
+    async fn crates_spelt_differently_are_the_same_crate() {
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let r = Registry::initialize(default_config(), scratch.registry()).unwrap();
+
+        let c = Crate::new("Spelt_Out", "1.0.0")
+            .lib_rs(r#"pub const ID: u8 = 1;"#)
+            .create_in(&scratch)
+            .await
+            .unwrap();
+        r.add(&global, c.package().await.unwrap()).unwrap();
+
+        let other = "spelt-out".parse::<CrateName>().unwrap();
+        let (name, index) = r.find_crate(&other).unwrap().unwrap();
+        assert_eq!("Spelt_Out", name.as_str());
+        assert_eq!(1, index.len());
+        assert!(r.index_file_path_for(&name).ends_with("sp/el/spelt_out"));
+
+        let c = Crate::new("spelt-out", "2.0.0")
+            .lib_rs(r#"pub const ID: u8 = 2;"#)
+            .create_in(&scratch)
+            .await
+            .unwrap();
+        let e = r.add(&global, c.package().await.unwrap()).unwrap_err();
+        assert_eq!("E_NAME_TAKEN", e.code());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn the_catalog_follows_the_audit_log() {
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let r = Registry::initialize(default_config(), scratch.registry()).unwrap();
+        let catalog = catalog::Catalog::default();
+        assert!(catalog.get(&r).unwrap().is_none());
+
+        catalog.load(&r).unwrap();
+        assert_eq!(Some(0), catalog.loaded().map(|(crates, _)| crates));
+
+        let c = Crate::new("listed", "1.0.0")
+            .lib_rs(r#"pub const ID: u8 = 1;"#)
+            .create_in(&scratch)
+            .await
+            .unwrap();
+        let (name, vers) = r.add(&global, c.package().await.unwrap()).unwrap();
+
+        let summaries = catalog.get(&r).unwrap().unwrap();
+        assert_eq!(1, summaries[&name].versions);
+        assert_eq!(Some(&vers), summaries[&name].newest_version.as_ref());
+    }
+
+    #[tokio::test]
+    async fn registries_made_by_upstream_margo_can_be_adopted() {
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let r = Registry::initialize(default_config(), scratch.registry()).unwrap();
+        let c = Crate::new("adopted", "1.0.0")
+            .lib_rs(r#"pub const ID: u8 = 1;"#)
+            .create_in(&scratch)
+            .await
+            .unwrap();
+        let (name, vers) = r.add(&global, c.package().await.unwrap()).unwrap();
+
+        // Upstream margo records no format, CIDs or audit log
+        let index_path = r.index_file_path_for(&name);
+        let mut index = Registry::parse_index_file(&index_path).unwrap();
+        let cksum = index[&vers].cksum.clone();
+        index.get_mut(&vers).unwrap().extensions = Default::default();
+        Registry::write_index_file(index, &index_path).unwrap();
+        fs::remove_file(r.audit_log_path()).unwrap();
+        let config = fs::read_to_string(r.margo_config_toml_path()).unwrap();
+        let config = config
+            .lines()
+            .filter(|l| !l.starts_with("format"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(r.margo_config_toml_path(), config).unwrap();
+
+        let e = Registry::open(&r.path).unwrap_err();
+        assert_eq!("E_UPSTREAM_REGISTRY", e.code());
+
+        let problems = adopt::adopt(&r.path, progress::Mode::Quiet).unwrap();
+        assert!(problems.is_empty(), "{problems:?}");
+
+        let r = Registry::open(&r.path).unwrap();
+        assert_eq!(migrate::CURRENT, r.config.format);
+        let index = Registry::parse_index_file(&index_path).unwrap();
+        assert_eq!(cksum, index[&vers].cksum);
+        assert!(index[&vers].extensions.cid.is_some());
+    }
+
+    #[tokio::test]
+    async fn generated_files_can_be_checked_for_reproducibility() {
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let r = Registry::initialize(default_config(), scratch.registry()).unwrap();
+        let c = Crate::new("reproduced", "1.0.0")
+            .lib_rs(r#"pub const ID: u8 = 1;"#)
+            .create_in(&scratch)
+            .await
+            .unwrap();
+        let (name, _) = r.add(&global, c.package().await.unwrap()).unwrap();
+
+        let differences = reproducible::check(&r).unwrap();
+        assert!(differences.is_empty(), "{differences:?}");
+
+        // Same entries, but not as margo writes them
+        let index_path = r.index_file_path_for(&name);
+        let index = fs::read_to_string(&index_path).unwrap();
+        fs::write(&index_path, index.replace(',', ", ")).unwrap();
+
+        let differences = reproducible::check(&r).unwrap();
+        assert!(
+            matches!(
+                differences.as_slice(),
+                [reproducible::Difference::File { path }] if *path == index_path
+            ),
+            "{differences:?}",
+        );
+    }
+
+    /// Pieces of names that have caused trouble elsewhere: separators,
+    /// traversal, device names, look-alikes, punycode and bidi controls.
+    const NAME_PIECES: &[&str] = &[
+        "a", "Z", "0", "9", "-", "_", ".", "..", "/", "\\", ":", " ", "\0", "%2e", "é", "ſ", "Ａ",
+        "\u{202e}", "xn--", "XN--", "con", "aux", "com1", "serde",
+    ];
+
+    proptest::proptest! {
+        #![proptest_config(proptest::test_runner::Config::with_cases(2_000))]
+
+        #[test]
+        fn untrusted_crate_names_stay_inside_the_registry(
+            pieces in proptest::collection::vec(proptest::sample::select(NAME_PIECES), 0..24),
+        ) {
+            let name = pieces.concat();
+            let Ok(parsed) = CrateName::from_untrusted(&name) else {
+                return Ok(());
+            };
+
+            let r = Registry {
+                path: PathBuf::from("registry"),
+                config: default_config(),
+            };
+            for path in [
+                r.index_file_path_for(&parsed),
+                r.crate_file_path_for(&parsed, &Version::new(1, 0, 0)),
+            ] {
+                let relative = path.strip_prefix(&r.path).unwrap();
+                proptest::prop_assert!(
+                    relative
+                        .components()
+                        .all(|c| matches!(c, std::path::Component::Normal(_))),
+                    "{name}: {}",
+                    path.display(),
+                );
+            }
+        }
+
+        /// Paths of any depth come out absolute and verbatim, with the
+        /// same components.
+        #[cfg(windows)]
+        #[test]
+        fn long_paths_are_verbatim(
+            root in proptest::sample::select(&[r"C:\registry", r"\\server\share\registry"][..]),
+            parts in proptest::collection::vec("[A-Za-z0-9_-]{1,40}", 0..12),
+        ) {
+            let path = parts.iter().fold(PathBuf::from(root), |path, part| path.join(part));
+            let long = long_path(path.clone());
+
+            let verbatim = match root.strip_prefix(r"\\") {
+                Some(_) => path.to_str().unwrap().replacen(r"\\", r"\\?\UNC\", 1),
+                None => format!(r"\\?\{}", path.display()),
+            };
+            proptest::prop_assert_eq!(PathBuf::from(verbatim), long);
+        }
+    }
+
+    #[tokio::test]
+    async fn crates_with_deep_paths_and_unusual_names_can_be_added() {
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let long_name = format!("deep-{}", "a_b-".repeat(14)) + "end";
+        let long_version = format!("1.0.0-{}+build.{}", "rc.".repeat(20) + "1", "0".repeat(40));
+        let versions = [
+            ("a", "0.1.0"),
+            ("ab", "0.1.0"),
+            ("abc", "0.1.0"),
+            ("Mixed_Case-name", "2.0.0-beta.1"),
+            (long_name.as_str(), long_version.as_str()),
+        ];
+
+        let r = Registry::initialize(default_config(), scratch.registry()).unwrap();
+        for (name, version) in versions {
+            let c = Crate::new(name, version)
+                .lib_rs(r#"pub const ID: u8 = 1;"#)
+                .create_in(&scratch)
+                .await
+                .unwrap();
+            r.add(&global, c.package().await.unwrap()).unwrap();
+        }
+
+        let problems = r.verify(progress::Mode::Quiet).unwrap();
+        assert!(problems.is_empty(), "{problems:?}");
+
+        let spelling = "mixed-case_name".parse().unwrap();
+        let (found, _) = r.find_crate(&spelling).unwrap().unwrap();
+        assert_eq!("Mixed_Case-name", found.as_str());
+    }
+
     #[tokio::test]
     async fn base_url_requires_trailing_slash() {
         let scratch = ScratchSpace::new().await.unwrap();
@@ -1920,6 +8056,193 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn read_only_registries_refuse_changes() {
+        let mut global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let config = ConfigV1 {
+            read_only: true,
+            ..default_config()
+        };
+        let r = Registry::initialize(config, scratch.registry()).unwrap();
+
+        let path = Some(r.path.clone());
+        assert!(discover_registry(path.clone()).is_ok());
+        let e = discover_writable_registry(&global, path.clone()).unwrap_err();
+        assert_eq!("E_READ_ONLY", e.code());
+
+        let r = Registry::initialize(default_config(), scratch.registry()).unwrap();
+        assert!(discover_writable_registry(&global, path.clone()).is_ok());
+
+        global.read_only = true;
+        assert!(r.is_read_only(&global));
+        assert!(discover_writable_registry(&global, path).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_only_registries_refuse_every_command_that_writes() {
+        let mut global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let config = ConfigV1 {
+            read_only: true,
+            ..default_config()
+        };
+        let r = Registry::initialize(config, scratch.registry()).unwrap();
+        let registry = || Some(r.path.clone());
+        let refused = |result: Result<(), Error>| result.is_err_and(|e| e.code() == "E_READ_ONLY");
+
+        let docs = DocsArgs {
+            registry: registry(),
+            version: "1.0.0".parse().unwrap(),
+            name: "demo".parse().unwrap(),
+        };
+        assert!(refused(do_docs(&global, docs)));
+
+        let html = GenerateHtmlArgs {
+            registry: registry(),
+        };
+        assert!(refused(do_generate_html(&global, html)));
+
+        let create = SnapshotCreateArgs {
+            registry: registry(),
+            name: "before".to_owned(),
+        };
+        let snapshot = SnapshotArgs {
+            command: SnapshotCommand::Create(create),
+        };
+        assert!(refused(do_snapshot(&global, snapshot)));
+
+        let delete = SnapshotDeleteArgs {
+            registry: registry(),
+            name: "before".to_owned(),
+        };
+        let snapshot = SnapshotArgs {
+            command: SnapshotCommand::Delete(delete),
+        };
+        assert!(refused(do_snapshot(&global, snapshot)));
+
+        #[cfg(feature = "replicate")]
+        {
+            let promote = ReplicatePromoteArgs {
+                registry: registry(),
+            };
+            assert!(refused(do_replicate_promote(&global, promote)));
+        }
+
+        let migrate = || MigrateArgs {
+            registry: registry(),
+            dry_run: false,
+        };
+        assert!(refused(do_migrate(&global, migrate())));
+        let adopt = || AdoptArgs {
+            registry: registry(),
+            dry_run: false,
+        };
+        assert!(refused(do_adopt(&global, adopt())));
+
+        // Refused by the flag as well as by the configuration
+        let r = Registry::initialize(default_config(), scratch.registry()).unwrap();
+        global.read_only = true;
+        assert!(r.is_read_only(&global));
+        assert!(refused(do_migrate(&global, migrate())));
+        assert!(refused(do_adopt(&global, adopt())));
+    }
+
+    #[tokio::test]
+    async fn yanking_a_missing_version_has_a_stable_code() {
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let config = default_config();
+
+        let r = Registry::initialize(config, scratch.registry()).unwrap();
+
+        let name = "not-here".parse().unwrap();
+        let version = "1.0.0".parse().unwrap();
+
+        let e = r.yank(name, version, true).unwrap_err();
+        assert_eq!("E_VERSION_NOT_FOUND", e.code());
+    }
+
+    #[tokio::test]
+    async fn verify_detects_modified_crate_files() {
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let config = default_config();
+
+        let r = Registry::initialize(config, scratch.registry()).unwrap();
+
+        let c = Crate::new("verified", "1.0.0")
+            .lib_rs(r#"pub const ID: u8 = 1;"#)
+            .create_in(&scratch)
+            .await
+            .unwrap();
+        let p = c.package().await.unwrap();
+
+        r.add(&global, p).unwrap();
+
+        let problems = r.verify(progress::Mode::Quiet).unwrap();
+        assert!(problems.is_empty(), "{problems:?}");
+
+        let name = "verified".parse().unwrap();
+        let version = "1.0.0".parse().unwrap();
+        fs::write(r.crate_file_path_for(&name, &version), b"tampered").unwrap();
+
+        let problems = r.verify(progress::Mode::Quiet).unwrap();
+        assert!(
+            matches!(problems.as_slice(), [VerifyProblem::Mismatch { .. }]),
+            "{problems:?}",
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_are_recorded_in_the_audit_log_and_feed() {
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let mut config = default_config();
+        config.feed.enabled = true;
+
+        let r = Registry::initialize(config, scratch.registry()).unwrap();
+
+        let c = Crate::new("announced", "1.0.0")
+            .lib_rs(r#"pub const ID: u8 = 1;"#)
+            .create_in(&scratch)
+            .await
+            .unwrap();
+        let p = c.package().await.unwrap();
+
+        r.add(&global, p).unwrap();
+        r.yank("announced".parse().unwrap(), "1.0.0".parse().unwrap(), true)
+            .unwrap();
+        r.maybe_generate_feed().unwrap();
+
+        let log = r.audit_log().unwrap();
+        assert!(
+            matches!(
+                log.as_slice(),
+                [
+                    audit::Entry {
+                        seq: 1,
+                        event: audit::Event::Add { .. },
+                        ..
+                    },
+                    audit::Entry {
+                        seq: 2,
+                        event: audit::Event::Yank { .. },
+                        ..
+                    },
+                ]
+            ),
+            "{log:?}",
+        );
+
+        let feed = fs::read_to_string(scratch.registry().join(feed::FILE_NAME)).unwrap();
+        assert!(feed.contains("<title>announced 1.0.0</title>"), "{feed}");
+    }
+
     #[tokio::test]
     async fn removing_a_crate_deletes_from_disk() {
         let global = Global::new().unwrap();
@@ -1959,4 +8282,115 @@ mod test {
             crate_path.display(),
         );
     }
+
+    #[tokio::test]
+    async fn limits_cap_size_and_versions() {
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let mut config = default_config();
+        config.limits.max_versions = Some(2);
+
+        let mut r = Registry::initialize(config, scratch.registry()).unwrap();
+
+        let mut packages = vec![];
+        for version in ["1.0.0", "1.1.0", "1.2.0"] {
+            // Each version is packaged in its own scratch space
+            let work = ScratchSpace::new().await.unwrap();
+            let c = Crate::new("capped", version)
+                .lib_rs(r#"pub const ID: u8 = 1;"#)
+                .create_in(&work)
+                .await
+                .unwrap();
+            packages.push((c.package().await.unwrap(), work));
+        }
+
+        for (p, _) in &packages {
+            r.add(&global, p).unwrap();
+        }
+
+        let name = "capped".parse().unwrap();
+        let index = Registry::parse_index_file(&r.index_file_path_for(&name)).unwrap();
+        let yanked = index
+            .values()
+            .map(|e| (e.vers.to_string(), e.yanked))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("1.0.0".to_owned(), true),
+                ("1.1.0".to_owned(), false),
+                ("1.2.0".to_owned(), false),
+            ],
+            yanked,
+        );
+
+        r.config.limits.max_crate_size = Some(1);
+        let e = r.add(&global, &packages[0].0).unwrap_err();
+        assert_eq!("E_CRATE_TOO_LARGE", e.code());
+    }
+
+    #[tokio::test]
+    async fn flagged_crates_that_cannot_be_added_are_not_held() {
+        use sha2::Digest;
+
+        let global = Global::new().unwrap();
+        let scratch = ScratchSpace::new().await.unwrap();
+
+        let c = Crate::new("flagged", "1.0.0")
+            .lib_rs(r#"pub const ID: u8 = 1;"#)
+            .create_in(&scratch)
+            .await
+            .unwrap();
+        let p = c.package().await.unwrap();
+        let digest = hex::encode(sha2::Sha256::digest(fs::read(&p).unwrap()));
+
+        let mut config = default_config();
+        config.scan.enabled = true;
+        config.scan.known_bad.insert(digest);
+        config.limits.max_crate_size = Some(1);
+
+        let mut r = Registry::initialize(config, scratch.registry()).unwrap();
+
+        let e = r.add(&global, &p).unwrap_err();
+        assert_eq!("E_CRATE_TOO_LARGE", e.code());
+        assert!(r.withheld().unwrap().is_empty());
+
+        r.config.limits.max_crate_size = None;
+        r.add(&global, &p).unwrap();
+        let withheld = r.withheld().unwrap();
+        assert_eq!(1, withheld.len());
+        assert_eq!("flagged", withheld[0].name.as_str());
+    }
+
+    #[test]
+    fn requirements_resolve_to_the_newest_unyanked_match() {
+        let line = |vers: &str, yanked: bool| {
+            format!(
+                r#"{{"name":"demo","vers":"{vers}","deps":[],"cksum":"00","features":{{}},"yanked":{yanked},"v":1}}"#
+            )
+        };
+        let lines = [
+            line("1.1.0", false),
+            line("1.2.0", false),
+            line("1.2.5", true),
+            line("1.3.0-beta.1", false),
+            line("2.0.0", false),
+        ];
+        let index = parse_index_lines(lines.join("\n").as_bytes()).unwrap();
+
+        let resolve = |req: &str| {
+            let req = req.parse().unwrap();
+            let resolution = resolve_versions(&index, &req);
+            resolution
+                .matches
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(["1.2.0", "1.1.0"], *resolve("^1.1"));
+        assert_eq!(["2.0.0", "1.3.0-beta.1"], *resolve(">=1.3.0-beta.1"));
+        assert_eq!(["2.0.0"], *resolve("^2"));
+        assert!(resolve("^3").is_empty());
+    }
 }
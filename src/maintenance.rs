@@ -0,0 +1,132 @@
+//! Holding publishes back while the registry is being worked on.
+//!
+//! `margo maintenance start` writes `maintenance.json` to a tenant's
+//! publishing `data-dir`. While it is there, the daemon still checks
+//! each `cargo publish` but puts the crate in the publish queue instead
+//! of adding it, so index-wide work can run without rejecting anyone.
+//! Once `margo maintenance end` removes the file, the daemon adds the
+//! queued crates, oldest first.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::timestamp::Timestamp;
+
+const FILE_NAME: &str = "maintenance.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub started_at: Timestamp,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+pub fn start(data_dir: &Path, reason: Option<String>) -> Result<Maintenance, Error> {
+    use error::*;
+
+    ensure!(status(data_dir)?.is_none(), AlreadySnafu);
+
+    let maintenance = Maintenance {
+        started_at: Timestamp::now(),
+        reason,
+    };
+    let data = serde_json::to_vec_pretty(&maintenance).context(SerializeSnafu)?;
+
+    fs::create_dir_all(data_dir).context(WriteSnafu { path: data_dir })?;
+    let path = data_dir.join(FILE_NAME);
+    fs::write(&path, data).context(WriteSnafu { path })?;
+
+    Ok(maintenance)
+}
+
+pub fn end(data_dir: &Path) -> Result<(), Error> {
+    use error::*;
+
+    let path = data_dir.join(FILE_NAME);
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => NotActiveSnafu.fail(),
+        Err(e) => Err(e).context(WriteSnafu { path }),
+    }
+}
+
+/// `None` when the registry is not under maintenance.
+pub fn status(data_dir: &Path) -> Result<Option<Maintenance>, Error> {
+    use error::*;
+
+    let path = data_dir.join(FILE_NAME);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+
+    serde_json::from_slice(&data)
+        .context(ParseSnafu { path })
+        .map(Some)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("The registry is already under maintenance"))]
+    Already,
+
+    #[snafu(display("The registry is not under maintenance"))]
+    NotActive,
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the maintenance state"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Already => "E_MAINTENANCE_ACTIVE",
+            Self::NotActive => "E_MAINTENANCE_INACTIVE",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_MAINTENANCE_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maintenance_starts_and_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        assert!(status(dir).unwrap().is_none());
+        start(dir, Some("reindex".to_owned())).unwrap();
+        assert!(start(dir, None).is_err());
+
+        assert_eq!(
+            Some("reindex".to_owned()),
+            status(dir).unwrap().unwrap().reason
+        );
+
+        end(dir).unwrap();
+        assert!(end(dir).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! A Merkle root over the registry's index files, so that two copies
+//! of a registry can be compared by exchanging a single hash.
+//!
+//! Each leaf is the SHA-256 of an index file's path relative to the
+//! registry (with `/` separators), a NUL byte, and the file's
+//! contents, ordered by path. A parent is the SHA-256 of its two
+//! children; an odd node out is carried up unchanged. An empty
+//! registry's root is the SHA-256 of nothing.
+
+use sha2::{Digest, Sha256};
+use snafu::prelude::*;
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+use crate::{ListIndexFilesError, Registry};
+
+pub type Hash = [u8; 32];
+
+/// Each index file's path relative to the registry, with `/`
+/// separators, and its contents.
+pub type IndexFiles = BTreeMap<String, Vec<u8>>;
+
+pub fn read_index_files(registry: &Registry) -> Result<IndexFiles, Error> {
+    use error::*;
+
+    let paths = registry.list_index_files().context(ListSnafu)?;
+
+    paths
+        .iter()
+        .map(|path| {
+            let relative = path.strip_prefix(&registry.path).unwrap_or(path);
+            let relative = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            let contents = fs::read(path).context(ReadSnafu { path })?;
+            Ok((relative, contents))
+        })
+        .collect()
+}
+
+/// The root hash and the number of index files it covers.
+#[cfg(feature = "nostr")]
+pub fn index_root(registry: &Registry) -> Result<(Hash, usize), Error> {
+    let files = read_index_files(registry)?;
+    Ok((files_root(&files), files.len()))
+}
+
+pub fn files_root(files: &IndexFiles) -> Hash {
+    let leaves = files
+        .iter()
+        .map(|(relative, contents)| {
+            let mut leaf = Sha256::new();
+            leaf.update(relative.as_bytes());
+            leaf.update([0]);
+            leaf.update(contents);
+            leaf.finalize().into()
+        })
+        .collect();
+
+    root(leaves)
+}
+
+fn root(mut level: Vec<Hash>) -> Hash {
+    if level.is_empty() {
+        return Sha256::digest(b"").into();
+    }
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut parent = Sha256::new();
+                    parent.update(left);
+                    parent.update(right);
+                    parent.finalize().into()
+                }
+                [odd] => *odd,
+                _ => unreachable!("chunks(2) yields one or two items"),
+            })
+            .collect();
+    }
+
+    level[0]
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not list the index files"))]
+    List { source: ListIndexFilesError },
+
+    #[snafu(display("Could not read the index file {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn odd_nodes_are_carried_up() {
+        let a = [1; 32];
+        let b = [2; 32];
+        let c = [3; 32];
+
+        let ab: Hash = Sha256::new()
+            .chain_update(a)
+            .chain_update(b)
+            .finalize()
+            .into();
+        let abc: Hash = Sha256::new()
+            .chain_update(ab)
+            .chain_update(c)
+            .finalize()
+            .into();
+
+        assert_eq!(a, root(vec![a]));
+        assert_eq!(ab, root(vec![a, b]));
+        assert_eq!(abc, root(vec![a, b, c]));
+        assert_eq!(Hash::from(Sha256::digest(b"")), root(vec![]));
+    }
+}
@@ -0,0 +1,278 @@
+//! Upgrading the files of a registry written by an older margo.
+//!
+//! `format` in `margo-config.toml` records how the registry's files are
+//! laid out; registries from before it was recorded are format 1.
+//! margo only opens registries of the [`CURRENT`] format. An older one
+//! needs `margo migrate`, and a newer one a newer margo.
+//!
+//! | Format | Change                                                      |
+//! | ------ | ----------------------------------------------------------- |
+//! | 2      | Index files are at lowercase paths                          |
+//! | 3      | Index entries record the CID of their `.crate` file         |
+//!
+//! Each step can be run again if it is interrupted, and the format is
+//! recorded after each one, so a migration that stops part way carries
+//! on from the last finished step.
+
+use snafu::prelude::*;
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    extensions, read_if_exists, ListIndexFilesError, OpenError, ParseIndexError, Registry,
+    WriteIndexError,
+};
+
+pub const CURRENT: u32 = 3;
+
+/// The format of registries that do not record one.
+pub fn unversioned() -> u32 {
+    1
+}
+
+pub struct Step {
+    /// The format the step upgrades to.
+    pub to: u32,
+
+    pub description: &'static str,
+
+    run: fn(&Registry) -> Result<(), Error>,
+}
+
+const STEPS: &[Step] = &[
+    Step {
+        to: 2,
+        description: "Move index files to lowercase paths",
+        run: lowercase_index_paths,
+    },
+    Step {
+        to: 3,
+        description: "Record the CID of each `.crate` file in the index",
+        run: record_cids,
+    },
+];
+
+/// Upgrades the registry at `path` to the current format, returning
+/// the steps taken. With `dry_run`, only returns the steps that would
+/// be.
+pub fn migrate(path: &Path, dry_run: bool) -> Result<Vec<&'static Step>, Error> {
+    use error::*;
+
+    let mut registry = Registry::open_any_format(path)?;
+    let format = registry.config.format;
+    ensure!(format <= CURRENT, TooNewSnafu { format });
+
+    let steps = STEPS.iter().filter(|s| s.to > format).collect::<Vec<_>>();
+    if dry_run {
+        return Ok(steps);
+    }
+
+    for step in &steps {
+        (step.run)(&registry)?;
+        record_format(&registry.margo_config_toml_path(), step.to)?;
+        registry.config.format = step.to;
+    }
+
+    Ok(steps)
+}
+
+/// Sets `format` in the configuration, keeping the rest of the file as
+/// it is. Top-level keys may only come before the first table, so a
+/// missing `format` goes at the top.
+fn record_format(config_path: &Path, format: u32) -> Result<(), Error> {
+    use error::*;
+
+    let config = fs::read_to_string(config_path).context(ConfigSnafu { path: config_path })?;
+
+    let line = format!("format = {format}");
+    let mut lines = config.lines().map(str::to_owned).collect::<Vec<_>>();
+    let top_level = lines
+        .iter()
+        .position(|l| l.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    match lines[..top_level]
+        .iter()
+        .position(|l| l.split('=').next().is_some_and(|k| k.trim() == "format"))
+    {
+        Some(i) => lines[i] = line,
+        None => lines.insert(0, line),
+    }
+
+    let mut tmp = config_path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    fs::write(&tmp, lines.join("\n") + "\n").context(ConfigSnafu { path: &tmp })?;
+    fs::rename(&tmp, config_path).context(ConfigSnafu { path: config_path })
+}
+
+/// Index files used to keep the case of the crate's name in their path,
+/// while the crate directories still do.
+fn lowercase_index_paths(registry: &Registry) -> Result<(), Error> {
+    use error::*;
+
+    let crate_dir = registry.crate_dir();
+    if !crate_dir.exists() {
+        return Ok(());
+    }
+
+    let mut subdirs = BTreeSet::new();
+    for entry in Registry::list_crate_files(&crate_dir) {
+        let mut path = entry.context(WalkSnafu)?.into_path();
+        path.pop();
+        if let Ok(subdir) = path.strip_prefix(&crate_dir) {
+            subdirs.insert(subdir.to_owned());
+        }
+    }
+
+    for subdir in subdirs {
+        let lowercase = subdir.to_string_lossy().to_ascii_lowercase();
+        let old = registry.path.join(&subdir);
+        let new = registry.path.join(lowercase);
+        if old == new || !old.exists() {
+            continue;
+        }
+
+        if new.exists() {
+            // On a file system that ignores case they are the same file
+            let same = fs::canonicalize(&old).ok() == fs::canonicalize(&new).ok();
+            ensure!(same, ConflictSnafu { old, new });
+            continue;
+        }
+
+        if let Some(dir) = new.parent() {
+            fs::create_dir_all(dir).context(MoveSnafu { path: dir })?;
+        }
+        fs::rename(&old, &new).context(MoveSnafu { path: &old })?;
+
+        // Leave the directories that still hold other index files
+        for dir in old.ancestors().skip(1).take(2) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Entries added before CIDs were recorded get one from their stored
+/// `.crate` file, wherever it is kept. Versions whose file is missing
+/// are left without.
+fn record_cids(registry: &Registry) -> Result<(), Error> {
+    use error::*;
+
+    for path in registry.list_index_files().context(ListSnafu)? {
+        let mut index = Registry::parse_index_file(&path).context(ParseSnafu { path: &path })?;
+
+        let mut changed = false;
+        for entry in index.values_mut() {
+            if entry.extensions.cid.is_some() {
+                continue;
+            }
+
+            let crate_path = registry.crate_file_path_for(&entry.name, &entry.vers);
+            let mut data =
+                read_if_exists(&crate_path).context(ReadCrateSnafu { path: &crate_path })?;
+            if let (None, Some(cold)) = (&data, registry.cold_path_for(&crate_path)) {
+                data = read_if_exists(&cold).context(ReadCrateSnafu { path: cold })?;
+            }
+
+            if let Some(data) = data {
+                entry.extensions.cid = Some(extensions::cid_of(&data));
+                changed = true;
+            }
+        }
+
+        if changed {
+            Registry::write_index_file(index, &path).context(WriteSnafu { path })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(transparent)]
+    Open { source: OpenError },
+
+    #[snafu(display(
+        "The registry is in format {format}, newer than this margo knows ({CURRENT}); use a newer margo"
+    ))]
+    TooNew { format: u32 },
+
+    #[snafu(display("Could not update the format in {}", path.display()))]
+    Config { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not walk the crate files"))]
+    Walk { source: walkdir::Error },
+
+    #[snafu(display(
+        "Both {} and {} exist; remove the one that is out of date and migrate again",
+        old.display(),
+        new.display(),
+    ))]
+    Conflict { old: PathBuf, new: PathBuf },
+
+    #[snafu(display("Could not move the index file {}", path.display()))]
+    Move { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not list the index files"))]
+    List { source: ListIndexFilesError },
+
+    #[snafu(display("Could not parse the index file {}", path.display()))]
+    Parse {
+        source: ParseIndexError,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not read the crate file {}", path.display()))]
+    ReadCrate { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not write the index file {}", path.display()))]
+    Write {
+        source: WriteIndexError,
+        path: PathBuf,
+    },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Open { source } => source.code(),
+            Self::TooNew { .. } => "E_FORMAT_TOO_NEW",
+            Self::Conflict { .. } => "E_INDEX_CONFLICT",
+            Self::Walk { .. } | Self::List { .. } | Self::ReadCrate { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_INDEX_CORRUPT",
+            Self::Config { .. } | Self::Move { .. } => "E_STORAGE_WRITE",
+            Self::Write { .. } => "E_INDEX_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_format_is_set_before_any_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join("margo-config.toml");
+
+        let config = "# Hand written\nversion = \"1\"\n\n[names]\nformat = \"kept\"\n";
+        fs::write(&path, config).unwrap();
+        record_format(&path, 2).unwrap();
+        record_format(&path, 3).unwrap();
+
+        assert_eq!(
+            "format = 3\n# Hand written\nversion = \"1\"\n\n[names]\nformat = \"kept\"\n",
+            fs::read_to_string(&path).unwrap()
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! Comparing a mirror with the registry it mirrors.
+//!
+//! `margo mirror verify` fetches the upstream's sparse index file for
+//! each local crate and reports versions the mirror is missing,
+//! versions whose checksums differ, and versions yanked upstream that
+//! are still live locally. Crates the upstream does not know are
+//! skipped, since they were published to the mirror directly.
+
+use snafu::prelude::*;
+use std::{fmt, io::Read, time::Duration};
+use url::Url;
+
+use crate::{
+    client::Client,
+    common::CrateName,
+    progress::{self, Progress},
+    Index, ListAllError, Registry,
+};
+
+/// The sparse index that `margo sync` mirrors from.
+pub const CRATES_IO_INDEX: &str = "https://index.crates.io/";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const USER_AGENT: &str = concat!("margo/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug)]
+pub struct Upstream {
+    agent: ureq::Agent,
+    client: Client,
+}
+
+impl Upstream {
+    pub fn connect(base_url: Url) -> Result<Self, Error> {
+        use error::*;
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(USER_AGENT)
+            .build();
+
+        let url = Client::config_url(&base_url).context(ClientSnafu)?;
+        let config_json = get(&agent, &url)?.context(NoConfigSnafu { url })?;
+        let client = Client::new(base_url, &config_json).context(ClientSnafu)?;
+
+        Ok(Self { agent, client })
+    }
+
+    /// `None` when the upstream has no such crate.
+    pub fn index(&self, name: &CrateName) -> Result<Option<Index>, Error> {
+        use error::*;
+
+        let url = self.client.index_url(name).context(ClientSnafu)?;
+        let Some(data) = get(&self.agent, &url)? else {
+            return Ok(None);
+        };
+
+        self.client
+            .parse_index(&data)
+            .context(ClientSnafu)
+            .map(Some)
+    }
+}
+
+/// `None` when the upstream answers 404.
+fn get(agent: &ureq::Agent, url: &Url) -> Result<Option<Vec<u8>>, Error> {
+    use error::*;
+
+    let response = match agent.request_url("GET", url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => return Ok(None),
+        Err(e) => return Err(e).context(RequestSnafu { url: url.clone() }),
+    };
+
+    let mut data = vec![];
+    response
+        .into_reader()
+        .read_to_end(&mut data)
+        .context(ReadBodySnafu { url: url.clone() })?;
+
+    Ok(Some(data))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    Missing {
+        name: CrateName,
+        version: semver::Version,
+    },
+    Checksum {
+        name: CrateName,
+        version: semver::Version,
+        local: String,
+        upstream: String,
+    },
+    YankedUpstream {
+        name: CrateName,
+        version: semver::Version,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { name, version } => {
+                write!(f, "{name} {version} is upstream but not in the mirror")
+            }
+            Self::Checksum {
+                name,
+                version,
+                local,
+                upstream,
+            } => write!(
+                f,
+                "The checksum of {name} {version} is {local} in the mirror but {upstream} upstream",
+            ),
+            Self::YankedUpstream { name, version } => {
+                write!(
+                    f,
+                    "{name} {version} is yanked upstream but still live in the mirror"
+                )
+            }
+        }
+    }
+}
+
+/// Checks `crates`, or every crate in the registry when empty.
+pub fn verify(
+    registry: &Registry,
+    upstream: &Upstream,
+    crates: &[CrateName],
+    mode: progress::Mode,
+) -> Result<Vec<Problem>, Error> {
+    use error::*;
+
+    let local = registry.list_all().context(ListSnafu)?;
+    let local = local
+        .iter()
+        .filter(|(name, _)| crates.is_empty() || crates.contains(name))
+        .collect::<Vec<_>>();
+
+    let mut progress = Progress::new(mode, "mirror verify", Some(local.len() as u64));
+
+    let mut problems = vec![];
+    for (name, index) in local {
+        match upstream.index(name)? {
+            Some(upstream) => problems.extend(compare(name, index, &upstream)),
+            None => progress.println(format_args!(
+                "Skipping `{name}`, which the upstream does not have"
+            )),
+        }
+        progress.inc(name);
+    }
+
+    Ok(problems)
+}
+
+/// Yanked upstream versions are never mirrored, so their absence is
+/// not a problem.
+fn compare(name: &CrateName, local: &Index, upstream: &Index) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    for (version, theirs) in upstream {
+        let Some(ours) = local.get(version) else {
+            if !theirs.yanked {
+                problems.push(Problem::Missing {
+                    name: name.clone(),
+                    version: version.clone(),
+                });
+            }
+            continue;
+        };
+
+        if !ours.cksum.eq_ignore_ascii_case(&theirs.cksum) {
+            problems.push(Problem::Checksum {
+                name: name.clone(),
+                version: version.clone(),
+                local: ours.cksum.clone(),
+                upstream: theirs.cksum.clone(),
+            });
+        }
+
+        if theirs.yanked && !ours.yanked {
+            problems.push(Problem::YankedUpstream {
+                name: name.clone(),
+                version: version.clone(),
+            });
+        }
+    }
+
+    problems
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not list the mirror's crates"))]
+    List { source: ListAllError },
+
+    #[snafu(display("Could not fetch {url} from the upstream registry"))]
+    Request {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: Url,
+    },
+
+    #[snafu(display("Could not read {url} from the upstream registry"))]
+    ReadBody { source: std::io::Error, url: Url },
+
+    #[snafu(display("The upstream registry has no {url}"))]
+    NoConfig { url: Url },
+
+    #[snafu(display("The upstream registry could not be read"))]
+    Client { source: crate::client::Error },
+
+    #[snafu(display("Found {count} difference(s) between the mirror and its upstream"))]
+    Failed { count: usize },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::List { source } => source.code(),
+            Self::Request { .. } | Self::ReadBody { .. } | Self::NoConfig { .. } => "E_UPSTREAM",
+            Self::Client { source } => source.code(),
+            Self::Failed { .. } => "E_VERIFY_FAILED",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn index(entries: &[(&str, &str, bool)]) -> Index {
+        let lines = entries
+            .iter()
+            .map(|(vers, cksum, yanked)| {
+                format!(
+                    r#"{{"name":"demo","vers":"{vers}","deps":[],"cksum":"{cksum}","features":{{}},"yanked":{yanked},"v":1}}"#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        crate::parse_index_lines(lines.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn differences_are_reported() {
+        let name: CrateName = "demo".parse().unwrap();
+        let local = index(&[
+            ("1.0.0", "aa", false),
+            ("1.1.0", "bb", false),
+            ("1.2.0", "cc", false),
+        ]);
+        let upstream = index(&[
+            ("1.0.0", "aa", false),
+            ("1.1.0", "ff", false),
+            ("1.2.0", "cc", true),
+            ("1.3.0", "dd", false),
+            ("1.4.0", "ee", true),
+        ]);
+
+        let v = |s: &str| s.parse::<semver::Version>().unwrap();
+        assert_eq!(
+            vec![
+                Problem::Checksum {
+                    name: name.clone(),
+                    version: v("1.1.0"),
+                    local: "bb".to_owned(),
+                    upstream: "ff".to_owned(),
+                },
+                Problem::YankedUpstream {
+                    name: name.clone(),
+                    version: v("1.2.0"),
+                },
+                Problem::Missing {
+                    name: name.clone(),
+                    version: v("1.3.0"),
+                },
+            ],
+            compare(&name, &local, &upstream),
+        );
+    }
+}
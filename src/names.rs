@@ -0,0 +1,215 @@
+//! Keeping new crates from squatting on names or imitating others.
+//!
+//! ```toml
+//! [names]
+//! reserved = ["std", "core", "acme-internal"]
+//! min-description-length = 20
+//! max-edit-distance = 1
+//! homoglyphs = true
+//! # Let through despite the rules above
+//! allow = ["serde-jsonx"]
+//! ```
+//!
+//! The policy is checked when the first version of a crate is added
+//! with `margo add` or `cargo publish`; later versions and crates
+//! mirrored by `margo sync` are not checked. Names are compared
+//! ignoring case and treating `-` and `_` alike. With `homoglyphs`, a
+//! name that reads the same as an existing one once look-alike
+//! characters are swapped, such as `serde-js0n` for `serde-json`, is
+//! refused. `margo add --allow-name` and admin tokens skip the checks.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Policy {
+    /// Names no new crate may take.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    reserved: BTreeSet<String>,
+
+    /// Names that are never refused.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    allow: BTreeSet<String>,
+
+    /// The shortest `description` a new crate may have, in characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_description_length: Option<usize>,
+
+    /// New names this close to an existing one are refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_edit_distance: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    homoglyphs: bool,
+}
+
+impl Policy {
+    /// Whether a crate new to the registry may be called `name`, given
+    /// the names already taken.
+    pub fn check<'a>(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        existing: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), Error> {
+        use error::*;
+
+        let normalized = normalize(name);
+        if self.allow.iter().any(|a| normalize(a) == normalized) {
+            return Ok(());
+        }
+
+        ensure!(
+            !self.reserved.iter().any(|r| normalize(r) == normalized),
+            ReservedSnafu { name }
+        );
+
+        if let Some(min) = self.min_description_length {
+            let len = description.map_or(0, |d| d.trim().chars().count());
+            ensure!(len >= min, DescriptionSnafu { name, len, min });
+        }
+
+        let looks = skeleton(name);
+        for other in existing {
+            let other_normalized = normalize(other);
+            if other_normalized == normalized {
+                continue;
+            }
+
+            if let Some(max) = self.max_edit_distance {
+                let distance = edit_distance(&normalized, &other_normalized);
+                ensure!(distance > max, TooSimilarSnafu { name, other });
+            }
+
+            ensure!(
+                !self.homoglyphs || looks != skeleton(other),
+                LooksLikeSnafu { name, other }
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The name as Cargo compares it: lowercase, with `_` as `-`.
+pub fn normalize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '_' => '-',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// The name with look-alike characters replaced by one of each kind,
+/// and the separators dropped.
+fn skeleton(name: &str) -> String {
+    let name = normalize(name)
+        .replace('-', "")
+        .replace("rn", "m")
+        .replace("vv", "w")
+        .replace("cl", "d");
+
+    name.chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | 'i' => 'l',
+            '5' => 's',
+            '8' => 'b',
+            c => c,
+        })
+        .collect()
+}
+
+/// The Levenshtein distance.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.as_bytes();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.bytes().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("The name `{name}` is reserved"))]
+    Reserved { name: String },
+
+    #[snafu(display(
+        "The description of `{name}` is {len} characters long; new crates need at least {min}"
+    ))]
+    Description {
+        name: String,
+        len: usize,
+        min: usize,
+    },
+
+    #[snafu(display("The name `{name}` is too close to the existing crate `{other}`"))]
+    TooSimilar { name: String, other: String },
+
+    #[snafu(display("The name `{name}` looks like the existing crate `{other}`"))]
+    LooksLike { name: String, other: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Reserved { .. } => "E_NAME_RESERVED",
+            Self::Description { .. } => "E_DESCRIPTION_TOO_SHORT",
+            Self::TooSimilar { .. } | Self::LooksLike { .. } => "E_NAME_TOO_SIMILAR",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn new_names_are_checked_against_the_policy() {
+        let policy: Policy = toml::from_str(
+            r#"
+            reserved = ["std"]
+            allow = ["serde-jsno"]
+            min-description-length = 10
+            max-edit-distance = 1
+            homoglyphs = true
+            "#,
+        )
+        .unwrap();
+        let existing = ["serde-json", "tokio"];
+        let check = |name, description| {
+            policy
+                .check(name, Some(description), existing)
+                .map_err(|e| e.code())
+        };
+
+        assert_eq!(Ok(()), check("serde-yaml", "YAML for serde"));
+        assert_eq!(Ok(()), check("serde-jsno", "JSON, misspelt"));
+        assert_eq!(Err("E_NAME_RESERVED"), check("STD", "The standard library"));
+        assert_eq!(Err("E_DESCRIPTION_TOO_SHORT"), check("rand", " tiny "));
+        assert_eq!(
+            Err("E_NAME_TOO_SIMILAR"),
+            check("tokio2", "Another runtime")
+        );
+        assert_eq!(
+            Err("E_NAME_TOO_SIMILAR"),
+            check("5erde-js0n", "JSON for serde")
+        );
+
+        assert_eq!(3, edit_distance("kitten", "sitting"));
+        assert_eq!(skeleton("rnodel-1o"), skeleton("model_lo"));
+    }
+}
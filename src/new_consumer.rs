@@ -0,0 +1,222 @@
+//! A project that depends on a crate of the registry, for checking
+//! that Cargo can resolve and build it.
+//!
+//! `margo new-consumer demo --name internal` writes a binary package to
+//! `demo-consumer/` that depends on exactly the newest version of
+//! `demo`, or the newest matching `--req`, from the registry that
+//! `.cargo/config.toml` there calls `internal`. It declares an empty
+//! `[workspace]` so that it builds on its own wherever it is written.
+//! With `--build`, `cargo build` is run there, which fetches the crate
+//! from the registry as any consumer would, so the registry must be
+//! served.
+
+use semver::{Version, VersionReq};
+use snafu::prelude::*;
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+};
+
+use crate::{cargo_context, common::CrateName, setup_cargo, FindCrateError, Registry};
+
+#[derive(Debug)]
+pub struct Consumer<'a> {
+    /// The crate to depend on, as the registry has it.
+    pub crate_name: &'a CrateName,
+
+    pub version: &'a Version,
+
+    /// What Cargo should call the registry.
+    pub registry_name: &'a str,
+
+    /// The index URL as Cargo expects it, including `sparse+`.
+    pub index: String,
+}
+
+/// The crate and the version of it to depend on: the newest that
+/// matches `req` and is not yanked.
+pub fn choose(
+    registry: &Registry,
+    name: &CrateName,
+    req: &VersionReq,
+) -> Result<(CrateName, Version), Error> {
+    use error::*;
+
+    let found = registry.find_crate(name).context(IndexSnafu)?;
+    let (name, index) = found.context(NoCrateSnafu { name: name.clone() })?;
+
+    let best = crate::resolve_versions(&index, req)
+        .best
+        .map(|e| e.vers.clone());
+    let version = best.context(NoMatchSnafu {
+        name: name.clone(),
+        req: req.clone(),
+    })?;
+    Ok((name, version))
+}
+
+impl Consumer<'_> {
+    /// Writes the project to `out`, which must not exist yet.
+    pub fn write(&self, out: &Path) -> Result<(), Error> {
+        use error::*;
+
+        ensure!(!out.exists(), ExistsSnafu { path: out });
+
+        let cargo_config = setup_cargo::Setup {
+            name: self.registry_name,
+            index: self.index.clone(),
+            replace_crates_io: false,
+        };
+        let cargo_config = cargo_config.config().context(CargoConfigSnafu)?;
+
+        for (path, contents) in [
+            (out.join("Cargo.toml"), self.manifest()),
+            (out.join(".cargo/config.toml"), cargo_config),
+            (out.join("src/main.rs"), self.main_rs()),
+        ] {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).context(WriteSnafu { path: dir })?;
+            }
+            fs::write(&path, contents).context(WriteSnafu { path })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn package_name(&self) -> String {
+        format!("{}-consumer", self.crate_name)
+    }
+
+    fn manifest(&self) -> String {
+        let mut manifest = String::new();
+
+        _ = writeln!(manifest, "[package]");
+        _ = writeln!(manifest, "name = {:?}", self.package_name());
+        _ = writeln!(manifest, "version = \"0.1.0\"");
+        _ = writeln!(manifest, "edition = \"2021\"");
+        _ = writeln!(manifest, "publish = false");
+        _ = writeln!(manifest);
+        _ = writeln!(manifest, "[dependencies]");
+        _ = writeln!(
+            manifest,
+            "{} = {{ version = \"={}\", registry = {:?} }}",
+            self.crate_name, self.version, self.registry_name,
+        );
+        _ = writeln!(manifest);
+        _ = writeln!(manifest, "# Not part of any enclosing workspace");
+        _ = writeln!(manifest, "[workspace]");
+
+        manifest
+    }
+
+    fn main_rs(&self) -> String {
+        let lib = self.crate_name.as_str().replace('-', "_");
+        let mut main_rs = String::new();
+
+        _ = writeln!(main_rs, "use {lib} as _;");
+        _ = writeln!(main_rs);
+        _ = writeln!(main_rs, "fn main() {{");
+        _ = writeln!(
+            main_rs,
+            "    println!(\"{} {} resolved from `{}`\");",
+            self.crate_name, self.version, self.registry_name,
+        );
+        _ = writeln!(main_rs, "}}");
+
+        main_rs
+    }
+}
+
+/// Runs `cargo build` in the project at `dir`, giving Cargo `token`
+/// for the registry when there is one.
+pub fn build(dir: &Path, registry_name: &str, token: Option<&str>) -> Result<(), Error> {
+    use error::*;
+
+    let cargo = cargo_context::cargo().context(NoCargoSnafu)?;
+    let mut command = Command::new(cargo);
+    command.arg("build").current_dir(dir);
+    if let Some(token) = token {
+        command.env(cargo_context::token_var(registry_name), token);
+    }
+    let status = command.status().context(SpawnSnafu)?;
+    ensure!(status.success(), BuildSnafu { status });
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: FindCrateError },
+
+    #[snafu(display("`{name}` is not in the registry"))]
+    NoCrate { name: CrateName },
+
+    #[snafu(display("No version of `{name}` matches `{req}`"))]
+    NoMatch { name: CrateName, req: VersionReq },
+
+    #[snafu(display("{} already exists", path.display()))]
+    Exists { path: PathBuf },
+
+    #[snafu(display("Could not generate the Cargo configuration"))]
+    CargoConfig { source: setup_cargo::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not find `cargo` on the PATH"))]
+    NoCargo,
+
+    #[snafu(display("Could not run `cargo build`"))]
+    Spawn { source: io::Error },
+
+    #[snafu(display("`cargo build` failed ({status})"))]
+    Build { status: ExitStatus },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Index { source } => source.code(),
+            Self::NoCrate { .. } => "E_CRATE_NOT_FOUND",
+            Self::NoMatch { .. } => "E_VERSION_NOT_FOUND",
+            Self::Exists { .. } => "E_PATH_EXISTS",
+            Self::CargoConfig { source } => source.code(),
+            Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::NoCargo | Self::Spawn { .. } => "E_CARGO_UNAVAILABLE",
+            Self::Build { .. } => "E_BUILD_FAILED",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_dependency_is_pinned_to_the_registry() {
+        let crate_name = "demo-lib".parse().unwrap();
+        let consumer = Consumer {
+            crate_name: &crate_name,
+            version: &Version::new(1, 2, 3),
+            registry_name: "internal",
+            index: "sparse+https://registry.example.com/".into(),
+        };
+
+        let manifest = consumer.manifest();
+        assert!(
+            manifest.contains(r#"name = "demo-lib-consumer""#),
+            "{manifest}"
+        );
+        assert!(
+            manifest.contains(r#"demo-lib = { version = "=1.2.3", registry = "internal" }"#),
+            "{manifest}"
+        );
+        assert!(manifest.ends_with("[workspace]\n"), "{manifest}");
+
+        assert!(consumer.main_rs().starts_with("use demo_lib as _;\n"));
+    }
+}
@@ -0,0 +1,516 @@
+//! Messages about a registry's crates sent to chat rooms and mailboxes.
+//!
+//! Each [`Notifier`] is one place messages go. The daemon follows the
+//! tenant's audit log, so versions added from the command line are
+//! announced as well as those published to it, and sends a message
+//! for each event it is configured for to every notifier:
+//!
+//! ```toml
+//! [tenant.notifications]
+//! # The default is every event
+//! events = ["publish", "yank", "unyank", "advisory"]
+//!
+//! [tenant.notifications.templates]
+//! publish = ":package: {name} {version} is out on {registry}"
+//!
+//! [[tenant.notifications.sinks]]
+//! type = "slack"
+//! webhook-url-file = "/etc/margo/acme.slack-webhook"
+//!
+//! [[tenant.notifications.sinks]]
+//! type = "matrix"
+//! homeserver = "https://matrix.example.com/"
+//! room = "!releases:example.com"
+//! access-token-file = "/etc/margo/acme.matrix-token"
+//!
+//! [[tenant.notifications.sinks]]
+//! type = "email"
+//! from = "registry@example.com"
+//! to = ["releases@example.com"]
+//! ```
+//!
+//! An `advisory` is a version that stopped being served: quarantined
+//! by an operator or for a checksum conflict. Templates may use
+//! `{name}`, `{version}`, `{registry}` and `{reason}`. A notifier that
+//! fails only produces a warning.
+
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{
+    collections::BTreeSet,
+    fmt, fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use url::Url;
+
+use crate::{audit, tenant::SharedRegistry};
+
+/// How often the audit log is looked at for new entries.
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for a chat service to accept a message.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_SENDMAIL: &str = "/usr/sbin/sendmail";
+
+/// Somewhere messages about the registry are sent.
+pub trait Notifier: fmt::Debug + Send + Sync {
+    /// Names the notifier in warnings.
+    fn name(&self) -> String;
+
+    fn send(&self, message: &str) -> Result<(), Error>;
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NotificationsConfig {
+    #[serde(default = "Kind::all")]
+    events: BTreeSet<Kind>,
+
+    #[serde(default)]
+    templates: Templates,
+
+    sinks: Vec<SinkConfig>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Kind {
+    Publish,
+    Yank,
+    Unyank,
+    Advisory,
+}
+
+impl Kind {
+    fn all() -> BTreeSet<Self> {
+        [Self::Publish, Self::Yank, Self::Unyank, Self::Advisory].into()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Templates {
+    publish: Option<String>,
+    yank: Option<String>,
+    unyank: Option<String>,
+    advisory: Option<String>,
+}
+
+impl Templates {
+    fn get(&self, kind: Kind) -> &str {
+        let (template, default) = match kind {
+            Kind::Publish => (
+                &self.publish,
+                "{name} {version} was published to {registry}",
+            ),
+            Kind::Yank => (&self.yank, "{name} {version} was yanked from {registry}"),
+            Kind::Unyank => (&self.unyank, "{name} {version} was unyanked in {registry}"),
+            Kind::Advisory => (
+                &self.advisory,
+                "{name} {version} in {registry} is no longer served: {reason}",
+            ),
+        };
+        template.as_deref().unwrap_or(default)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum SinkConfig {
+    /// A Slack incoming webhook, or anything that takes the same
+    /// `{"text": ...}` body.
+    #[serde(rename_all = "kebab-case")]
+    Slack { webhook_url_file: PathBuf },
+
+    #[serde(rename_all = "kebab-case")]
+    Matrix {
+        homeserver: Url,
+        room: String,
+        access_token_file: PathBuf,
+    },
+
+    /// Handed to `sendmail -t`.
+    #[serde(rename_all = "kebab-case")]
+    Email {
+        from: String,
+        to: Vec<String>,
+        #[serde(default)]
+        sendmail: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Notifications {
+    events: BTreeSet<Kind>,
+    templates: Templates,
+    notifiers: Vec<Box<dyn Notifier>>,
+}
+
+impl Notifications {
+    pub fn new(config: NotificationsConfig) -> Result<Self, Error> {
+        use error::*;
+
+        ensure!(!config.sinks.is_empty(), NoSinksSnafu);
+
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+
+        let notifiers = config
+            .sinks
+            .into_iter()
+            .map(|sink| -> Result<Box<dyn Notifier>, Error> {
+                Ok(match sink {
+                    SinkConfig::Slack { webhook_url_file } => {
+                        let url = read_secret(&webhook_url_file)?;
+                        let url = url.parse().context(WebhookUrlSnafu {
+                            path: webhook_url_file,
+                        })?;
+                        Box::new(Slack {
+                            agent: agent.clone(),
+                            url,
+                        })
+                    }
+                    SinkConfig::Matrix {
+                        homeserver,
+                        room,
+                        access_token_file,
+                    } => Box::new(Matrix {
+                        agent: agent.clone(),
+                        homeserver,
+                        room,
+                        access_token: read_secret(&access_token_file)?,
+                        transactions: AtomicU64::new(0),
+                    }),
+                    SinkConfig::Email { from, to, sendmail } => {
+                        ensure!(!to.is_empty(), NoRecipientsSnafu);
+                        Box::new(Email {
+                            from,
+                            to,
+                            sendmail: sendmail.unwrap_or_else(|| DEFAULT_SENDMAIL.into()),
+                        })
+                    }
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            events: config.events,
+            templates: config.templates,
+            notifiers,
+        })
+    }
+
+    /// Follows the audit log from its current end until the daemon
+    /// stops.
+    pub fn start(self: Arc<Self>, registry: SharedRegistry) {
+        std::thread::spawn(move || {
+            let mut offset = 0;
+            let mut newest_seen = None;
+
+            loop {
+                let registry = registry.get();
+                match audit::tail(&registry.audit_log_path(), offset) {
+                    Ok((entries, next)) => {
+                        offset = next;
+
+                        // Skips the entries there before the daemon
+                        // started, and those read again after the log
+                        // was replaced
+                        if let Some(seen) = newest_seen {
+                            for entry in entries.iter().filter(|e| e.seq > seen) {
+                                if let Some(message) =
+                                    self.message(&entry.event, &registry.config.base_url)
+                                {
+                                    self.send(&message);
+                                }
+                            }
+                        }
+
+                        let newest = entries.last().map_or(0, |e| e.seq);
+                        newest_seen = Some(newest.max(newest_seen.unwrap_or(0)));
+                    }
+                    Err(e) => eprintln!("Warning: {e}"),
+                }
+
+                std::thread::sleep(INTERVAL);
+            }
+        });
+    }
+
+    /// The message for the event, if it is one to send.
+    fn message(&self, event: &audit::Event, registry: &Url) -> Option<String> {
+        use audit::Event;
+
+        let (kind, name, version, reason) = match event {
+            Event::Add { name, vers, .. } => (Kind::Publish, name, vers, None),
+            Event::Yank { name, vers } => (Kind::Yank, name, vers, None),
+            Event::Unyank { name, vers } => (Kind::Unyank, name, vers, None),
+            Event::Quarantine { name, vers, reason } => {
+                let reason = reason.as_deref().unwrap_or("quarantined by an operator");
+                (Kind::Advisory, name, vers, Some(reason))
+            }
+            Event::Conflict { name, vers, .. } => {
+                let reason = "its sources disagree about its checksum";
+                (Kind::Advisory, name, vers, Some(reason))
+            }
+            _ => return None,
+        };
+
+        if !self.events.contains(&kind) {
+            return None;
+        }
+
+        let message = self
+            .templates
+            .get(kind)
+            .replace("{name}", name.as_str())
+            .replace("{version}", &version.to_string())
+            .replace("{registry}", registry.as_str())
+            .replace("{reason}", reason.unwrap_or_default());
+        Some(message)
+    }
+
+    fn send(&self, message: &str) {
+        for notifier in &self.notifiers {
+            if let Err(e) = notifier.send(message) {
+                eprintln!("Warning: Could not notify {}: {e}", notifier.name());
+            }
+        }
+    }
+}
+
+fn read_secret(path: &Path) -> Result<String, Error> {
+    let secret = fs::read_to_string(path).context(error::ReadSecretSnafu { path })?;
+    Ok(secret.trim().to_owned())
+}
+
+struct Slack {
+    agent: ureq::Agent,
+    url: Url,
+}
+
+// The webhook URL is the secret
+impl fmt::Debug for Slack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Slack").finish_non_exhaustive()
+    }
+}
+
+impl Notifier for Slack {
+    fn name(&self) -> String {
+        format!(
+            "the Slack webhook at {}",
+            self.url.host_str().unwrap_or_default()
+        )
+    }
+
+    fn send(&self, message: &str) -> Result<(), Error> {
+        self.agent
+            .request_url("POST", &self.url)
+            .send_json(serde_json::json!({ "text": message }))
+            .context(error::SendSnafu)?;
+        Ok(())
+    }
+}
+
+struct Matrix {
+    agent: ureq::Agent,
+    homeserver: Url,
+    room: String,
+    access_token: String,
+
+    /// Matrix drops a message whose transaction ID it has seen, so
+    /// each one gets its own.
+    transactions: AtomicU64,
+}
+
+impl fmt::Debug for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Matrix")
+            .field("homeserver", &self.homeserver)
+            .field("room", &self.room)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Notifier for Matrix {
+    fn name(&self) -> String {
+        format!("the Matrix room {}", self.room)
+    }
+
+    fn send(&self, message: &str) -> Result<(), Error> {
+        use error::*;
+
+        let transaction = format!(
+            "margo-{}-{}",
+            crate::status::unix_now(),
+            self.transactions.fetch_add(1, Ordering::Relaxed),
+        );
+
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| {
+                HomeserverSnafu {
+                    url: self.homeserver.clone(),
+                }
+                .build()
+            })?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3", "rooms", &self.room])
+            .extend(["send", "m.room.message", &transaction]);
+
+        self.agent
+            .request_url("PUT", &url)
+            .set("Authorization", &format!("Bearer {}", self.access_token))
+            .send_json(serde_json::json!({ "msgtype": "m.text", "body": message }))
+            .context(SendSnafu)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Email {
+    from: String,
+    to: Vec<String>,
+    sendmail: PathBuf,
+}
+
+impl Notifier for Email {
+    fn name(&self) -> String {
+        self.to.join(", ")
+    }
+
+    fn send(&self, message: &str) -> Result<(), Error> {
+        use error::*;
+
+        let subject = message.lines().next().unwrap_or_default();
+        let mail = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {subject}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{message}\r\n",
+            self.from,
+            self.to.join(", "),
+        );
+
+        let sendmail = &self.sendmail;
+        let mut child = Command::new(sendmail)
+            .args(["-t", "-i"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context(SendmailSnafu { sendmail })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(mail.as_bytes())
+                .context(SendmailSnafu { sendmail })?;
+        }
+        let status = child.wait().context(SendmailSnafu { sendmail })?;
+        ensure!(status.success(), SendmailFailedSnafu { sendmail });
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("At least one notification sink must be configured"))]
+    NoSinks,
+
+    #[snafu(display("An email sink needs at least one recipient"))]
+    NoRecipients,
+
+    #[snafu(display("Could not read the secret {}", path.display()))]
+    ReadSecret { source: io::Error, path: PathBuf },
+
+    #[snafu(display("The webhook URL in {} is not valid", path.display()))]
+    WebhookUrl {
+        source: url::ParseError,
+        path: PathBuf,
+    },
+
+    #[snafu(display("The Matrix homeserver {url} cannot have paths"))]
+    Homeserver { url: Url },
+
+    #[snafu(display("Could not send the message"))]
+    Send {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+    },
+
+    #[snafu(display("Could not run {}", sendmail.display()))]
+    Sendmail {
+        source: io::Error,
+        sendmail: PathBuf,
+    },
+
+    #[snafu(display("{} did not accept the message", sendmail.display()))]
+    SendmailFailed { sendmail: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NoSinks
+            | Self::NoRecipients
+            | Self::WebhookUrl { .. }
+            | Self::Homeserver { .. } => "E_CONFIG_INVALID",
+            Self::ReadSecret { .. } => "E_KEY_READ",
+            Self::Send { .. } | Self::Sendmail { .. } | Self::SendmailFailed { .. } => "E_NOTIFY",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_follow_the_templates() {
+        let config = toml::from_str(
+            r#"
+            events = ["publish", "advisory"]
+
+            [templates]
+            publish = "New: {name} {version} on {registry}"
+
+            [[sinks]]
+            type = "email"
+            from = "registry@example.com"
+            to = ["team@example.com"]
+            "#,
+        )
+        .unwrap();
+        let notifications = Notifications::new(config).unwrap();
+        let registry = "https://registry.example.com/".parse().unwrap();
+
+        let name = "demo".parse::<crate::common::CrateName>().unwrap();
+        let vers = semver::Version::new(1, 2, 3);
+        let message = |event| notifications.message(&event, &registry);
+
+        assert_eq!(
+            Some("New: demo 1.2.3 on https://registry.example.com/".to_owned()),
+            message(audit::Event::Add {
+                name: name.clone(),
+                vers: vers.clone(),
+                cksum: "00".into(),
+            }),
+        );
+        assert_eq!(
+            Some(
+                "demo 1.2.3 in https://registry.example.com/ is no longer served: malware"
+                    .to_owned()
+            ),
+            message(audit::Event::Quarantine {
+                name: name.clone(),
+                vers: vers.clone(),
+                reason: Some("malware".into()),
+            }),
+        );
+        assert_eq!(None, message(audit::Event::Yank { name, vers }));
+    }
+}
@@ -0,0 +1,264 @@
+//! Encrypted nostr direct messages telling crate owners about changes
+//! to their crates.
+//!
+//! Messages are signed with the tenant's [signer](crate::signer) and
+//! published to the configured relays in the background; a relay that
+//! cannot be reached only produces a warning. NIP-17 messages need a
+//! `nostr-key`, as a remote signer cannot gift-wrap them.
+//!
+//! ```toml
+//! [tenant.notify]
+//! relays = ["wss://relay.example.com"]
+//! protocol = "nip17"  # or "nip04" for older clients
+//!
+//! [tenant.notify.pubkeys]
+//! "ldap:uid=alice,ou=people,dc=example,dc=com" = "npub1..."
+//! ```
+
+use nostr::{ClientMessage, EventBuilder, JsonUtil, Kind, PublicKey, RelayMessage, Tag};
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    net::TcpStream,
+    sync::{PoisonError, RwLock},
+    time::Duration,
+};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+use url::Url;
+
+use crate::{
+    auth::UserId,
+    signer::{self, SharedSigner},
+};
+
+/// How long to wait for a relay to accept a message.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NotifyConfig {
+    relays: Vec<Url>,
+
+    #[serde(default)]
+    protocol: Protocol,
+
+    /// Owners' public keys, hex or `npub`, by user ID. Owners without
+    /// one are not notified.
+    #[serde(default)]
+    pubkeys: BTreeMap<UserId, String>,
+}
+
+#[derive(Debug, Default, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Protocol {
+    /// Gift-wrapped private messages, which hide who is talking to whom.
+    #[default]
+    Nip17,
+
+    /// The original encrypted direct messages.
+    Nip04,
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    signer: SharedSigner,
+    relays: RwLock<Vec<Url>>,
+    protocol: Protocol,
+    pubkeys: BTreeMap<UserId, PublicKey>,
+}
+
+impl Notifier {
+    /// `signer` is the operator's.
+    pub fn new(config: NotifyConfig, signer: SharedSigner) -> Result<Self, Error> {
+        use error::*;
+
+        if let Protocol::Nip17 = config.protocol {
+            ensure!(signer.keys().is_some(), LocalKeySnafu);
+        }
+
+        let pubkeys = config
+            .pubkeys
+            .into_iter()
+            .map(|(user, pubkey)| {
+                let pubkey = PublicKey::parse(&pubkey).context(ParsePubkeySnafu { user: &user })?;
+                Ok((user, pubkey))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            signer,
+            relays: RwLock::new(config.relays),
+            protocol: config.protocol,
+            pubkeys,
+        })
+    }
+
+    /// Sends `message` to each owner with a known public key. Returns
+    /// once the messages are built; they are sent in the background.
+    pub fn notify<'a>(&self, owners: impl IntoIterator<Item = &'a UserId>, message: &str) {
+        let events = owners
+            .into_iter()
+            .filter_map(|owner| self.pubkeys.get(owner))
+            .map(|&pubkey| self.build(pubkey, message))
+            .collect::<Result<Vec<_>, _>>();
+
+        let events = match events {
+            Ok(events) if events.is_empty() => return,
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Warning: {e}");
+                return;
+            }
+        };
+
+        let relays = self.relays();
+        std::thread::spawn(move || {
+            for relay in &relays {
+                if let Err(e) = send(relay, &events) {
+                    eprintln!("Warning: {e}");
+                }
+            }
+        });
+    }
+
+    pub fn relays(&self) -> Vec<Url> {
+        self.relays
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns the relays it replaced.
+    pub fn set_relays(&self, relays: Vec<Url>) -> Vec<Url> {
+        let mut current = self.relays.write().unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut *current, relays)
+    }
+
+    fn build(&self, receiver: PublicKey, message: &str) -> Result<nostr::Event, Error> {
+        use error::*;
+
+        match self.protocol {
+            Protocol::Nip17 => {
+                let keys = self.signer.keys().context(LocalKeySnafu)?;
+                EventBuilder::private_msg(&keys, receiver, message, []).context(BuildSnafu)
+            }
+            Protocol::Nip04 => {
+                let content = self
+                    .signer
+                    .nip04_encrypt(&receiver, message)
+                    .context(SignSnafu)?;
+                let tags = [Tag::public_key(receiver)];
+                let builder = EventBuilder::new(Kind::EncryptedDirectMessage, content, tags);
+                signer::sign(&*self.signer, builder).context(SignSnafu)
+            }
+        }
+    }
+}
+
+/// Publishes the events to the relay, waiting for it to accept each.
+pub fn send(relay: &Url, events: &[nostr::Event]) -> Result<(), Error> {
+    use error::*;
+
+    let mut socket = connect(relay)?;
+
+    for event in events {
+        let request = ClientMessage::event(event.clone()).as_json();
+        socket
+            .send(Message::Text(request))
+            .context(ConnectSnafu { relay })?;
+
+        // Relays answer each event with `["OK", id, accepted, reason]`
+        loop {
+            let reply = socket.read().context(ConnectSnafu { relay })?;
+            let Message::Text(reply) = reply else {
+                continue;
+            };
+
+            if let Ok(RelayMessage::Ok {
+                event_id,
+                status,
+                message,
+            }) = RelayMessage::from_json(&reply)
+            {
+                if event_id != event.id {
+                    continue;
+                }
+                ensure!(status, RejectedSnafu { relay, message });
+                break;
+            }
+        }
+    }
+
+    _ = socket.close(None);
+
+    Ok(())
+}
+
+pub type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// Opens a websocket to the relay whose reads time out, so that a
+/// relay that never answers cannot hang the caller.
+pub fn connect(relay: &Url) -> Result<Socket, Error> {
+    use error::*;
+
+    let (socket, _) = tungstenite::connect(relay.as_str()).context(ConnectSnafu { relay })?;
+
+    let stream = match socket.get_ref() {
+        MaybeTlsStream::Plain(s) => Some(s),
+        MaybeTlsStream::Rustls(s) => Some(s.get_ref()),
+        _ => None,
+    };
+    if let Some(stream) = stream {
+        _ = stream.set_read_timeout(Some(RELAY_TIMEOUT));
+    }
+
+    Ok(socket)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("The nostr public key of `{user}` is not valid"))]
+    ParsePubkey {
+        source: nostr::key::Error,
+        user: UserId,
+    },
+
+    #[snafu(display("Could not build the nostr message"))]
+    Build {
+        source: nostr::event::builder::Error,
+    },
+
+    #[snafu(display("Could not sign the nostr message"))]
+    Sign {
+        #[snafu(source(from(signer::Error, Box::new)))]
+        source: Box<signer::Error>,
+    },
+
+    #[snafu(display(
+        "NIP-17 messages need a `nostr-key`; use protocol = \"nip04\" with a remote signer"
+    ))]
+    LocalKey,
+
+    #[snafu(display("Could not communicate with the nostr relay {relay}"))]
+    Connect {
+        #[snafu(source(from(tungstenite::Error, Box::new)))]
+        source: Box<tungstenite::Error>,
+        relay: Url,
+    },
+
+    #[snafu(display("{relay} rejected the nostr message: {message}"))]
+    Rejected { relay: Url, message: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ParsePubkey { .. } | Self::LocalKey => "E_CONFIG_INVALID",
+            Self::Build { .. } => "E_INTERNAL",
+            Self::Sign { source } => source.code(),
+            Self::Connect { .. } | Self::Rejected { .. } => "E_NOTIFY",
+        }
+    }
+}
@@ -1,25 +1,189 @@
 use libp2p::{
+    allow_block_list, connection_limits,
     futures::StreamExt,
     gossipsub, identify, mdns,
     multiaddr::Protocol,
     noise, ping,
-    request_response::{self, Codec, ProtocolSupport},
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, Multiaddr, PeerId, StreamProtocol, SwarmBuilder,
+    request_response::{self, Codec, OutboundRequestId, ProtocolSupport},
+    swarm::{dial_opts::DialOpts, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
 };
 use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     io,
     path::{Path, PathBuf},
     process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
+use crate::{
+    blob,
+    common::CrateName,
+    control::{self, PeerCommand, PeerInfo},
+    dials,
+    gossip_cache::{self, Cache, Cached},
+    payload::{Body, CrateAnnouncement, Payload},
+    pins, quarantine, seeding,
+    status::{self, Direction, Replication, SharedStatus},
+    telemetry,
+    tenant::SharedRegistry,
+    validation::{self, Reject, Validator},
+    visibility, Registry,
+};
+
+#[cfg(feature = "chaos")]
+use crate::chaos;
+
+#[cfg(feature = "zstd")]
+use crate::copies;
+
+#[cfg(feature = "erasure")]
+use crate::erasure;
+
 const COMMIT_TOPIC: &str = "margo/commit/v1";
 const COMMIT_PROTOCOL: StreamProtocol = StreamProtocol::new("/margo/commit/1.0.0");
 
+/// Bodies are read and written this much at a time, and their progress
+/// reported after each.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// How often the most downloaded crates are offered to volunteers.
+const SEED_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often peers are asked which pinned crates they hold.
+const PIN_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How often the number of peers is fitted to the backlog.
+const DIAL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keeps lists of crates or digests well within the default request
+/// limit.
+const MAX_LIST_LEN: usize = 200;
+
+/// Two nodes that discover each other may dial each other at once.
+const MAX_CONNECTIONS_PER_PEER: u32 = 2;
+
+/// The requests for crates and blobs that peers have not answered yet,
+/// and who they were sent to.
+type Backlog = HashMap<OutboundRequestId, PeerId>;
+
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_REQUEST_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_MAX_REQUESTS_PER_PEER: usize = 8;
+const DEFAULT_MAX_BUFFERED_BYTES: usize = 256 * 1024 * 1024;
+
+/// How much a peer may make this node hold in memory, from
+/// `[announcements.limits]` in `margo-config.toml`. They take effect
+/// when the daemon starts.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Limits {
+    /// The largest gossiped message, sent or received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_message_bytes: Option<usize>,
+
+    /// The largest request from a peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_request_bytes: Option<usize>,
+
+    /// The largest response from a peer, such as a blob or the files of
+    /// a commit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_response_bytes: Option<usize>,
+
+    /// Requests from one peer that may be in progress at once. Others
+    /// are refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_requests_per_peer: Option<usize>,
+
+    /// Bytes held by all requests and responses in progress, across
+    /// every peer. A transfer that would go over is refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_buffered_bytes: Option<usize>,
+}
+
+impl Limits {
+    fn max_message_bytes(&self) -> usize {
+        self.max_message_bytes.unwrap_or(DEFAULT_MAX_MESSAGE_BYTES)
+    }
+
+    fn max_request_bytes(&self) -> usize {
+        self.max_request_bytes.unwrap_or(DEFAULT_MAX_REQUEST_BYTES)
+    }
+
+    fn max_response_bytes(&self) -> usize {
+        self.max_response_bytes
+            .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES)
+    }
+
+    fn max_requests_per_peer(&self) -> usize {
+        self.max_requests_per_peer
+            .unwrap_or(DEFAULT_MAX_REQUESTS_PER_PEER)
+            .max(1)
+    }
+
+    fn max_buffered_bytes(&self) -> usize {
+        self.max_buffered_bytes
+            .unwrap_or(DEFAULT_MAX_BUFFERED_BYTES)
+    }
+}
+
+/// The bytes that requests and responses in progress hold, shared by
+/// every stream.
+#[derive(Debug, Clone)]
+struct Budget {
+    used: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl Budget {
+    fn new(max: usize) -> Self {
+        Self {
+            used: Default::default(),
+            max,
+        }
+    }
+
+    /// Takes `len` bytes of the budget until the reservation is
+    /// dropped, or fails if there are not that many left.
+    fn reserve(&self, len: usize) -> io::Result<Reservation> {
+        let max = self.max;
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(len).filter(|&n| n <= max)
+            })
+            .map_err(|used| {
+                let message =
+                    format!("{len} bytes would go over the {max} buffered ({used} in use)");
+                io::Error::new(io::ErrorKind::OutOfMemory, message)
+            })?;
+
+        Ok(Reservation {
+            used: self.used.clone(),
+            len,
+        })
+    }
+}
+
+struct Reservation {
+    used: Arc<AtomicUsize>,
+    len: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.len, Ordering::AcqRel);
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Git helpers
 // ---------------------------------------------------------------------------
@@ -86,19 +250,162 @@ fn collect_commit_files(dir: &Path, commit: &str) -> io::Result<Vec<(String, Vec
 // Request/response codec – simple length-prefixed JSON
 // ---------------------------------------------------------------------------
 
-#[derive(Debug, Clone, Default)]
-pub struct CommitCodec;
+/// Reports the progress of commit data and blobs in the node's status.
+/// Each stream gets its own clone, which remembers what the stream's
+/// request asked for. Messages over the [limits](Limits) are refused
+/// before anything is allocated for them.
+#[derive(Debug, Clone)]
+pub struct CommitCodec {
+    status: SharedStatus,
+    what: Option<String>,
+    max_request_bytes: usize,
+    max_response_bytes: usize,
+    budget: Budget,
+    #[cfg(feature = "chaos")]
+    chaos: Option<chaos::Chaos>,
+}
+
+impl CommitCodec {
+    pub fn new(status: SharedStatus, limits: &Limits) -> Self {
+        Self {
+            status,
+            what: None,
+            max_request_bytes: limits.max_request_bytes(),
+            max_response_bytes: limits.max_response_bytes(),
+            budget: Budget::new(limits.max_buffered_bytes()),
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+
+    /// Injects the faults `policy` asks for into the transfers this
+    /// node sends.
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, policy: &chaos::Policy) -> Self {
+        self.chaos = policy
+            .is_enabled()
+            .then(|| chaos::Chaos::new(policy.clone()));
+        self
+    }
+
+    fn progress(&mut self, direction: Direction) -> Option<Progress> {
+        let what = self.what.take()?;
+
+        let mut span = telemetry::Span::start(match direction {
+            Direction::Send => "p2p.send",
+            Direction::Receive => "p2p.receive",
+        });
+        span.set("p2p.transfer", &what);
+
+        Some(Progress {
+            status: self.status.clone(),
+            direction,
+            what,
+            _span: span,
+        })
+    }
+}
+
+/// Waits before sending the chunk at `sent` of a transfer `len` long,
+/// or fails halfway through a dropped transfer.
+#[cfg(feature = "chaos")]
+async fn misbehave(faults: &chaos::Faults, sent: usize, len: usize) -> io::Result<()> {
+    if !faults.latency.is_zero() {
+        tokio::time::sleep(faults.latency).await;
+    }
+    if faults.drop && sent >= len / 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "transfer dropped by [announcements.chaos]",
+        ));
+    }
+    Ok(())
+}
+
+/// A transfer shown in the status, which is removed however the stream
+/// ends. Its span ends with it.
+struct Progress {
+    status: SharedStatus,
+    direction: Direction,
+    what: String,
+    _span: telemetry::Span,
+}
+
+impl Progress {
+    fn report(&self, bytes: usize, total: usize) {
+        self.status.update(|s| {
+            s.progress_transfer(self.direction, &self.what, bytes as u64, total as u64)
+        });
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.status
+            .update(|s| s.finish_transfer(self.direction, &self.what));
+    }
+}
+
+/// What nodes tell each other when they connect.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Hello {
+    /// The encodings of `.crate` files the node reads.
+    pub encodings: Vec<String>,
+
+    /// Whether the node volunteers to keep its peers' popular crates.
+    #[serde(default)]
+    pub seeds: bool,
+}
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum CommitRequest {
+    /// Tell the peer what this node reads and volunteers for, and ask
+    /// the same of it.
+    Hello(Hello),
     /// Ask the peer for its current commit hash.
     GetHead,
     /// Ask the peer for the file listing at a specific commit.
     GetCommitData { commit: String },
+    /// Ask the peer for a content-addressed blob.
+    GetBlob { digest: String },
+    /// Ask the peer for the announcements it received since a time, in
+    /// seconds since the Unix epoch.
+    GetAnnouncements { since: u64 },
+    /// Offer a volunteer this node's most downloaded or pinned crate
+    /// versions.
+    Offer { crates: Vec<CrateAnnouncement> },
+    /// Ask the peer which of the crates it holds.
+    Holds { crates: Vec<CrateAnnouncement> },
+    /// Offer a volunteer blobs to keep, such as the erasure-coded
+    /// shards of this node's crates.
+    OfferBlobs { digests: Vec<String> },
+    /// Ask the peer for a `.crate` file, from its registry or from the
+    /// crates it seeds.
+    GetCrate(CrateAnnouncement),
+}
+
+impl CommitRequest {
+    /// How a transfer of the response is shown. Heads are too small to
+    /// be worth showing.
+    fn transfer(&self) -> Option<String> {
+        match self {
+            Self::Hello(_)
+            | Self::GetHead
+            | Self::GetAnnouncements { .. }
+            | Self::Offer { .. }
+            | Self::Holds { .. }
+            | Self::OfferBlobs { .. } => None,
+            Self::GetCommitData { commit } => Some(format!("commit {commit}")),
+            Self::GetBlob { digest } => Some(format!("blob {digest}")),
+            Self::GetCrate(c) => Some(format!("crate {} {}", c.name, c.vers)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum CommitResponse {
+    /// What the peer reads and volunteers for.
+    Hello(Hello),
     /// Current HEAD commit hash (if the registry is a git repo).
     Head { commit: Option<String> },
     /// Files tracked by git at the requested commit.
@@ -106,11 +413,52 @@ pub enum CommitResponse {
     CommitData {
         commit: String,
         files: Vec<(String, String)>,
+        /// The encoding of each file sent as a recompressed copy, by
+        /// path. The others are sent as they are.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        encodings: BTreeMap<String, String>,
+    },
+    /// A blob's base64-encoded contents.
+    Blob { digest: String, data: String },
+    /// Cached announcements, oldest first.
+    Announcements { announcements: Vec<Cached> },
+    /// The offered crates the volunteer does not have yet, and will
+    /// fetch.
+    Wanted { crates: Vec<CrateAnnouncement> },
+    /// The crates asked about that the peer holds and hands out.
+    Held { crates: Vec<CrateAnnouncement> },
+    /// The offered blobs the volunteer does not have yet, and will
+    /// fetch.
+    WantedBlobs { digests: Vec<String> },
+    /// A `.crate` file's base64-encoded contents, or those of its copy
+    /// in `encoding`.
+    Crate {
+        cksum: String,
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<String>,
     },
     /// The requested commit was not found or could not be read.
     Error { message: String },
 }
 
+#[cfg(feature = "chaos")]
+impl CommitResponse {
+    /// Changes the contents of a transfer, as `[announcements.chaos]`
+    /// asks.
+    fn corrupt(&mut self) {
+        match self {
+            Self::Blob { data, .. } | Self::Crate { data, .. } => chaos::corrupt(data),
+            Self::CommitData { files, .. } => {
+                if let Some((_, data)) = files.first_mut() {
+                    chaos::corrupt(data);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl Codec for CommitCodec {
     type Protocol = StreamProtocol;
@@ -128,15 +476,19 @@ impl Codec for CommitCodec {
         let mut len_buf = [0u8; 4];
         io.read_exact(&mut len_buf).await?;
         let len = u32::from_be_bytes(len_buf) as usize;
-        if len > 16 * 1024 * 1024 {
+        if len > self.max_request_bytes {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "request too large",
             ));
         }
+        let _reservation = self.budget.reserve(len)?;
         let mut buf = vec![0u8; len];
         io.read_exact(&mut buf).await?;
-        serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        let request: CommitRequest = serde_json::from_slice(&buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.what = request.transfer();
+        Ok(request)
     }
 
     async fn read_response<T>(
@@ -150,14 +502,23 @@ impl Codec for CommitCodec {
         let mut len_buf = [0u8; 4];
         io.read_exact(&mut len_buf).await?;
         let len = u32::from_be_bytes(len_buf) as usize;
-        if len > 64 * 1024 * 1024 {
+        if len > self.max_response_bytes {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "response too large",
             ));
         }
+        let _reservation = self.budget.reserve(len)?;
+        let progress = self.progress(Direction::Receive);
         let mut buf = vec![0u8; len];
-        io.read_exact(&mut buf).await?;
+        let mut read = 0;
+        for chunk in buf.chunks_mut(CHUNK_LEN) {
+            io.read_exact(chunk).await?;
+            read += chunk.len();
+            if let Some(progress) = &progress {
+                progress.report(read, len);
+            }
+        }
         serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 
@@ -170,6 +531,7 @@ impl Codec for CommitCodec {
     where
         T: AsyncWrite + Unpin + Send,
     {
+        self.what = req.transfer();
         let data =
             serde_json::to_vec(&req).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         io.write_all(&(data.len() as u32).to_be_bytes()).await?;
@@ -186,10 +548,34 @@ impl Codec for CommitCodec {
     where
         T: AsyncWrite + Unpin + Send,
     {
+        let progress = self.progress(Direction::Send);
+        #[cfg(feature = "chaos")]
+        let (resp, faults) = {
+            let mut resp = resp;
+            let faults = match (&self.chaos, &progress) {
+                (Some(chaos), Some(_)) => chaos.next(),
+                _ => chaos::Faults::default(),
+            };
+            if faults.corrupt {
+                resp.corrupt();
+            }
+            (resp, faults)
+        };
         let data = serde_json::to_vec(&resp)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        drop(resp);
+        let _reservation = self.budget.reserve(data.len())?;
         io.write_all(&(data.len() as u32).to_be_bytes()).await?;
-        io.write_all(&data).await?;
+        let mut written = 0;
+        for chunk in data.chunks(CHUNK_LEN) {
+            #[cfg(feature = "chaos")]
+            misbehave(&faults, written, data.len()).await?;
+            io.write_all(chunk).await?;
+            written += chunk.len();
+            if let Some(progress) = &progress {
+                progress.report(written, data.len());
+            }
+        }
         Ok(())
     }
 }
@@ -205,8 +591,12 @@ impl Codec for CommitCodec {
 /// - **Ping**: Monitor connection liveness.
 /// - **Gossipsub**: Broadcast git commit hashes to all peers.
 /// - **CommitRpc**: Request/response protocol for fetching commit data.
+/// - **Banned**: Refuses connections from peers banned at runtime.
+/// - **Connections**: Refuses more than a couple of connections per peer.
 #[derive(NetworkBehaviour)]
 struct Behaviour {
+    banned: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    connections: connection_limits::Behaviour,
     identify: identify::Behaviour,
     mdns: mdns::tokio::Behaviour,
     ping: ping::Behaviour,
@@ -222,14 +612,45 @@ struct Behaviour {
 ///
 /// The node will:
 /// 1. Detect the current git commit hash of the registry.
-/// 2. Broadcast it via gossipsub whenever a new peer subscribes.
+/// 2. Broadcast it via gossipsub, as a `head` [payload](crate::payload),
+///    whenever a new peer connects.
 /// 3. Answer `GetHead` / `GetCommitData` requests from peers.
+/// 4. Ask each new peer for the announcements it missed while offline.
+/// 5. Answer the [control](crate::control) requests on `peer_commands`,
+///    such as to sync with peers or to ban one.
+/// 6. Offer volunteers its most downloaded crates, and the
+///    [pinned](crate::pins) ones too few peers hold.
+/// 7. Dial the peers mDNS finds as its backlog needs them, as set out
+///    in [`dials`].
+///
+/// Announcements, gossiped or replayed, are only shown, cached and
+/// forwarded once `validator` accepts them. Connected peers, accepted
+/// announcements, rejects and transfers of commit data and blobs are
+/// recorded in `status`.
+/// `.crate` files larger than the registry's `max_crate_size` are not
+/// sent to peers. The limit and the trusted peers follow the registry's
+/// configuration when it is [reloaded](crate::reload). What peers may
+/// make the node hold in memory is bounded by its [`Limits`].
 pub async fn start_node(
     listen_addr: Multiaddr,
-    registry_path: PathBuf,
+    registry: SharedRegistry,
+    mut validator: Validator,
+    mut peer_commands: tokio::sync::mpsc::Receiver<PeerCommand>,
+    status: SharedStatus,
 ) -> Result<(), P2pError> {
     use p2p_error::*;
 
+    let registry_path = registry.get().path.clone();
+    let limits = registry.get().config.announcements.limits.clone();
+    let dials = registry.get().config.announcements.dials.clone();
+    #[cfg(feature = "chaos")]
+    let chaos = registry.get().config.announcements.chaos.clone();
+    #[cfg(feature = "chaos")]
+    if chaos.is_enabled() {
+        eprintln!(
+            "Warning: injecting faults into transfers to peers, as `[announcements.chaos]` asks"
+        );
+    }
     let head_commit = detect_git_commit(&registry_path);
     match &head_commit {
         Some(c) => println!("Registry git HEAD: {c}"),
@@ -261,8 +682,11 @@ pub async fn start_node(
                 ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(15)));
 
             // gossipsub for commit hash broadcasting
+            // messages are only forwarded once the validator accepts them
             let gossipsub_config = gossipsub::ConfigBuilder::default()
                 .heartbeat_interval(Duration::from_secs(10))
+                .max_transmit_size(limits.max_message_bytes())
+                .validate_messages()
                 .build()
                 .expect("valid gossipsub config");
             let gossipsub = gossipsub::Behaviour::new(
@@ -272,12 +696,26 @@ pub async fn start_node(
             .expect("valid gossipsub behaviour");
 
             // request-response for commit data fetching
-            let commit_rpc = request_response::Behaviour::new(
+            // at most this many streams are open, and so requests
+            // buffered, on each connection
+            let codec = CommitCodec::new(status.clone(), &limits);
+            #[cfg(feature = "chaos")]
+            let codec = codec.with_chaos(&chaos);
+            let commit_rpc = request_response::Behaviour::with_codec(
+                codec,
                 [(COMMIT_PROTOCOL, ProtocolSupport::Full)],
-                request_response::Config::default(),
+                request_response::Config::default()
+                    .with_max_concurrent_streams(limits.max_requests_per_peer()),
+            );
+
+            let connections = connection_limits::Behaviour::new(
+                connection_limits::ConnectionLimits::default()
+                    .with_max_established_per_peer(Some(MAX_CONNECTIONS_PER_PEER)),
             );
 
             Behaviour {
+                banned: Default::default(),
+                connections,
                 identify,
                 mdns,
                 ping,
@@ -300,20 +738,96 @@ pub async fn start_node(
     swarm.listen_on(listen_addr).context(ListenSnafu)?;
 
     println!("Local peer ID: {}", swarm.local_peer_id());
+    let local_peer_id = swarm.local_peer_id().to_string();
+    status.update(|s| s.local_peer_id = Some(local_peer_id));
+
+    let cache_path = registry_path.join(gossip_cache::FILE_NAME);
+    let mut cache = Cache::load(&cache_path).context(GossipCacheSnafu)?;
 
     // Track peers we've already announced to so we publish once per new peer.
     let mut announced_peers: HashMap<PeerId, bool> = HashMap::new();
 
+    // Requests from each peer whose responses are still being sent
+    let mut serving: HashMap<PeerId, usize> = HashMap::new();
+
+    // What each peer said when it connected
+    let mut hellos: HashMap<PeerId, Hello> = HashMap::new();
+
+    let mut seed_interval = tokio::time::interval(SEED_INTERVAL);
+
+    // Which peers said they hold each pinned crate, by checksum, once
+    // they have been asked
+    let mut holders: Option<HashMap<String, HashSet<PeerId>>> = None;
+
+    let mut pin_interval = tokio::time::interval(PIN_INTERVAL);
+
+    // Peers found on the local network, which are dialed as needed
+    let mut known: HashMap<PeerId, Multiaddr> = HashMap::new();
+
+    // Peers dialed for a backlog, disconnected again once it clears
+    let mut scaled: HashSet<PeerId> = HashSet::new();
+
+    let mut backlog = Backlog::new();
+
+    let mut dial_interval = tokio::time::interval(DIAL_INTERVAL);
+
+    // Versions whose `.crate` files are being rebuilt from shards, by
+    // checksum
+    #[cfg(feature = "erasure")]
+    let mut restoring: HashMap<String, erasure::Manifest> = HashMap::new();
+
     // -- event loop ----------------------------------------------------------
 
     loop {
-        match swarm.select_next_some().await {
+        let event = tokio::select! {
+            event = swarm.select_next_some() => event,
+            Some((request, respond)) = peer_commands.recv() => {
+                let response = handle_control_request(
+                    &mut swarm,
+                    &status,
+                    &registry,
+                    &mut validator,
+                    &cache,
+                    request,
+                );
+                _ = respond.send(response);
+                continue;
+            }
+            _ = seed_interval.tick() => {
+                offer_popular(&mut swarm, &registry.get(), &status, &hellos);
+                #[cfg(feature = "erasure")]
+                {
+                    let registry = registry.get();
+                    distribute_shards(&mut swarm, &registry, &hellos);
+                    restore_missing(&mut swarm, &registry, &hellos, &mut restoring, &mut backlog);
+                }
+                continue;
+            }
+            _ = pin_interval.tick() => {
+                check_pins(&mut swarm, &registry.get(), &status, &hellos, &mut holders);
+                continue;
+            }
+            _ = dial_interval.tick() => {
+                status.update(|s| s.backlog = backlog.len());
+                scale_dials(&mut swarm, &dials, &known, &mut scaled, &serving, backlog.len());
+                continue;
+            }
+        };
+
+        match event {
             // -- listen addresses -------------------------------------------
             SwarmEvent::NewListenAddr { address, .. } => {
                 let full_addr = address
                     .clone()
                     .with(Protocol::P2p(*swarm.local_peer_id()));
                 println!("Listening on {full_addr}");
+                status.update(|s| s.listen_addrs.push(full_addr.to_string()));
+            }
+
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                let full_addr = address.with(Protocol::P2p(*swarm.local_peer_id()));
+                let full_addr = full_addr.to_string();
+                status.update(|s| s.listen_addrs.retain(|a| *a != full_addr));
             }
 
             // -- mDNS -------------------------------------------------------
@@ -324,8 +838,10 @@ pub async fn start_node(
                         .behaviour_mut()
                         .gossipsub
                         .add_explicit_peer(&peer_id);
-                    swarm.dial(addr).ok();
+                    known.insert(peer_id, addr);
                 }
+                let backlog = backlog.len();
+                scale_dials(&mut swarm, &dials, &known, &mut scaled, &serving, backlog);
             }
 
             SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(peers))) => {
@@ -335,6 +851,7 @@ pub async fn start_node(
                         .behaviour_mut()
                         .gossipsub
                         .remove_explicit_peer(&peer_id);
+                    known.remove(&peer_id);
                 }
             }
 
@@ -348,6 +865,10 @@ pub async fn start_node(
                     "Identified peer {peer_id}: {} ({})",
                     info.protocol_version, info.agent_version,
                 );
+                status.update(|s| {
+                    s.peers.entry(peer_id.to_string()).or_default().agent =
+                        Some(info.agent_version.clone());
+                });
             }
 
             // -- ping -------------------------------------------------------
@@ -357,19 +878,55 @@ pub async fn start_node(
                 ..
             })) => {
                 println!("Ping from {peer}: {rtt:?}");
+                let rtt_ms = rtt.as_millis().try_into().unwrap_or(u64::MAX);
+                status.update(|s| {
+                    if let Some(p) = s.peers.get_mut(&peer.to_string()) {
+                        p.rtt_ms = Some(rtt_ms);
+                    }
+                });
             }
 
             // -- gossipsub --------------------------------------------------
             SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
                 propagation_source,
+                message_id,
                 message,
-                ..
             })) => {
-                if let Ok(commit) = String::from_utf8(message.data.clone()) {
-                    println!(
-                        "Received commit announcement from {propagation_source}: {commit}"
-                    );
-                }
+                // Strict validation drops unsigned messages, so `source`
+                // is the verified signer.
+                let signer = message.source.map(|p| p.to_string());
+                let subject = validation::parse_gossip(&message.data);
+                let acceptance = match validator.check(signer.as_deref(), subject) {
+                    Ok(subject) => {
+                        println!("Received announcement from {propagation_source}: {subject}");
+                        // Cached as sent, with any fields this node does
+                        // not know of, so replays pass them on
+                        let announcement = Cached {
+                            id: message_id.to_string(),
+                            from: signer.unwrap_or_default(),
+                            message: String::from_utf8_lossy(&message.data).into_owned(),
+                            received_at: status::unix_now(),
+                        };
+                        cache_announcement(&mut cache, &cache_path, announcement);
+                        let subject = subject.to_string();
+                        status.update(|s| s.announce(propagation_source.to_string(), subject));
+                        gossipsub::MessageAcceptance::Accept
+                    }
+                    Err(reject) => {
+                        println!("Rejected announcement from {propagation_source}: {reject}");
+                        status.update(|s| s.count_reject(reject.as_str()));
+                        match reject {
+                            Reject::Duplicate | Reject::Unsupported => {
+                                gossipsub::MessageAcceptance::Ignore
+                            }
+                            _ => gossipsub::MessageAcceptance::Reject,
+                        }
+                    }
+                };
+                _ = swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .report_message_validation_result(&message_id, &propagation_source, acceptance);
             }
 
             SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
@@ -388,12 +945,62 @@ pub async fn start_node(
                         },
                 },
             )) => {
-                let response = handle_commit_request(&registry_path, &request);
+                let in_progress = serving.entry(peer).or_default();
+                if *in_progress >= limits.max_requests_per_peer() {
+                    // Dropping the channel refuses the request
+                    println!("Refusing {request:?} from {peer}: too many requests in progress");
+                    continue;
+                }
+                *in_progress += 1;
+
+                if let CommitRequest::Hello(hello) = &request {
+                    hellos.insert(peer, hello.clone());
+                }
+
+                let response = telemetry::in_span("p2p.serve_request", || {
+                    handle_commit_request(&registry.get(), &cache, hellos.get(&peer), &request)
+                });
                 println!("Serving {request:?} to {peer}");
-                let _ = swarm
+                if let CommitResponse::Wanted { crates } = &response {
+                    for c in crates {
+                        let request = CommitRequest::GetCrate(c.clone());
+                        fetch(&mut swarm, &mut backlog, &peer, request);
+                    }
+                }
+                if let CommitResponse::WantedBlobs { digests } = &response {
+                    for digest in digests {
+                        let digest = digest.clone();
+                        let request = CommitRequest::GetBlob { digest };
+                        fetch(&mut swarm, &mut backlog, &peer, request);
+                    }
+                }
+                let transfer = request.transfer();
+                if let Some(what) = &transfer {
+                    status.update(|s| s.expect_transfer(Direction::Send, what, peer.to_string()));
+                }
+                let sent = swarm
                     .behaviour_mut()
                     .commit_rpc
                     .send_response(channel, response);
+                if sent.is_err() {
+                    finish_serving(&mut serving, peer);
+                    if let Some(what) = &transfer {
+                        status.update(|s| s.finish_transfer(Direction::Send, what));
+                    }
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::CommitRpc(
+                request_response::Event::ResponseSent { peer, .. }
+                | request_response::Event::InboundFailure { peer, .. },
+            )) => {
+                finish_serving(&mut serving, peer);
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::CommitRpc(
+                request_response::Event::OutboundFailure { request_id, .. },
+            )) => {
+                backlog.remove(&request_id);
             }
 
             // -- request-response: incoming responses -----------------------
@@ -402,19 +1009,99 @@ pub async fn start_node(
                     peer,
                     message:
                         request_response::Message::Response {
-                            response, ..
+                            request_id,
+                            response,
                         },
                 },
             )) => {
+                backlog.remove(&request_id);
                 match &response {
+                    CommitResponse::Hello(hello) => {
+                        hellos.insert(peer, hello.clone());
+                    }
                     CommitResponse::Head { commit } => {
                         println!("Peer {peer} HEAD: {commit:?}");
                     }
-                    CommitResponse::CommitData { commit, files } => {
-                        println!(
-                            "Received commit data for {commit} from {peer} ({} files)",
-                            files.len()
-                        );
+                    CommitResponse::CommitData {
+                        commit,
+                        files,
+                        encodings,
+                    } => {
+                        let max = limits.max_response_bytes();
+                        match decode_commit_files(files, encodings, max) {
+                            Ok(files) => println!(
+                                "Received commit data for {commit} from {peer} ({} files, {} as copies)",
+                                files.len(),
+                                encodings.len(),
+                            ),
+                            Err(e) => println!("Discarding commit data for {commit} from {peer}: {e}"),
+                        }
+                    }
+                    CommitResponse::Blob { digest, data } => {
+                        match receive_blob(&registry_path, digest, data) {
+                            Ok(()) => println!("Received blob {digest} from {peer}"),
+                            Err(e) => println!("Discarding blob {digest} from {peer}: {e}"),
+                        }
+                        #[cfg(feature = "erasure")]
+                        restoring.retain(|_, manifest| {
+                            !(manifest.shards.contains(digest)
+                                && restore(&registry.get(), manifest))
+                        });
+                    }
+                    CommitResponse::Announcements { announcements } => {
+                        // Replays carry no signatures; the peer replaying
+                        // them vouches for them.
+                        let peer_id = peer.to_string();
+                        let mut replayed = 0;
+                        for a in announcements {
+                            if cache.contains(&a.id) {
+                                continue;
+                            }
+                            let subject = validation::parse_gossip(a.message.as_bytes());
+                            let subject = match validator.check(Some(&peer_id), subject) {
+                                Ok(subject) => subject.to_string(),
+                                Err(reject) => {
+                                    status.update(|s| s.count_reject(reject.as_str()));
+                                    continue;
+                                }
+                            };
+                            let a = Cached {
+                                received_at: status::unix_now(),
+                                ..a.clone()
+                            };
+                            let from = a.from.clone();
+                            if cache_announcement(&mut cache, &cache_path, a) {
+                                status.update(|s| s.announce(from, subject));
+                                replayed += 1;
+                            }
+                        }
+                        if replayed > 0 {
+                            println!("Replayed {replayed} missed announcement(s) from {peer}");
+                        }
+                    }
+                    CommitResponse::Wanted { crates } => {
+                        println!("Peer {peer} will seed {} offered crate(s)", crates.len());
+                    }
+                    CommitResponse::WantedBlobs { digests } => {
+                        println!("Peer {peer} will keep {} offered blob(s)", digests.len());
+                    }
+                    CommitResponse::Held { crates } => {
+                        if let Some(holders) = &mut holders {
+                            for c in crates {
+                                holders.entry(c.cksum.clone()).or_default().insert(peer);
+                            }
+                        }
+                    }
+                    CommitResponse::Crate {
+                        cksum,
+                        data,
+                        encoding,
+                    } => {
+                        let max = limits.max_response_bytes();
+                        match receive_seed(&registry.get(), cksum, data, encoding.as_deref(), max) {
+                            Ok(()) => println!("Seeding crate {cksum} from {peer}"),
+                            Err(e) => println!("Discarding crate {cksum} from {peer}: {e}"),
+                        }
                     }
                     CommitResponse::Error { message } => {
                         println!("Peer {peer} error: {message}");
@@ -423,35 +1110,72 @@ pub async fn start_node(
             }
 
             // -- connections ------------------------------------------------
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
                 println!("Connected to {peer_id}");
+                status.update(|s| {
+                    s.peers.entry(peer_id.to_string()).or_default().address =
+                        Some(endpoint.get_remote_address().to_string());
+                });
 
                 // Publish our commit hash once per new peer.
                 if !announced_peers.contains_key(&peer_id) {
                     announced_peers.insert(peer_id, true);
                     if let Some(ref commit) = head_commit {
-                        if let Err(e) = swarm
+                        let payload = Payload::new(Body::Head {
+                            commit: commit.clone(),
+                        })
+                        .to_vec();
+                        match swarm
                             .behaviour_mut()
                             .gossipsub
-                            .publish(topic.clone(), commit.as_bytes())
+                            .publish(topic.clone(), payload.clone())
                         {
-                            println!("Failed to publish commit hash: {e}");
-                        } else {
-                            println!("Broadcast commit {commit} to network");
+                            Ok(message_id) => {
+                                println!("Broadcast commit {commit} to network");
+                                let announcement = Cached {
+                                    id: message_id.to_string(),
+                                    from: swarm.local_peer_id().to_string(),
+                                    message: String::from_utf8_lossy(&payload).into_owned(),
+                                    received_at: status::unix_now(),
+                                };
+                                cache_announcement(&mut cache, &cache_path, announcement);
+                            }
+                            Err(e) => println!("Failed to publish commit hash: {e}"),
                         }
                     }
                 }
 
-                // Also send a GetHead request to learn the peer's commit.
-                swarm
-                    .behaviour_mut()
-                    .commit_rpc
-                    .send_request(&peer_id, CommitRequest::GetHead);
+                // Also learn the peer's commit, and catch up on
+                // announcements missed while offline.
+                catch_up(&mut swarm, &registry.get(), &peer_id, cache.replay_from());
             }
 
-            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                cause,
+                num_established,
+                ..
+            } => {
                 println!("Disconnected from {peer_id}: {cause:?}");
                 announced_peers.remove(&peer_id);
+                if num_established == 0 {
+                    serving.remove(&peer_id);
+                    hellos.remove(&peer_id);
+                    scaled.remove(&peer_id);
+                    backlog.retain(|_, peer| *peer != peer_id);
+                    if let Some(holders) = &mut holders {
+                        for h in holders.values_mut() {
+                            h.remove(&peer_id);
+                        }
+                    }
+                    let peer_id = peer_id.to_string();
+                    status.update(|s| {
+                        s.peers.remove(&peer_id);
+                        s.forget_transfers_of(&peer_id);
+                    });
+                }
             }
 
             _ => {}
@@ -463,11 +1187,26 @@ pub async fn start_node(
 // Request handler
 // ---------------------------------------------------------------------------
 
-fn handle_commit_request(registry_path: &Path, request: &CommitRequest) -> CommitResponse {
+/// `.crate` files are sent as copies in an encoding the peer said it
+/// reads when there is one.
+fn handle_commit_request(
+    registry: &Registry,
+    cache: &Cache,
+    peer: Option<&Hello>,
+    request: &CommitRequest,
+) -> CommitResponse {
+    let registry_path = registry.path.as_path();
+    let max_crate_size = registry.config.limits.max_crate_size;
+    let accepted = peer.map_or(&[][..], |hello| hello.encodings.as_slice());
+
     match request {
+        CommitRequest::Hello(_) => CommitResponse::Hello(hello(registry)),
         CommitRequest::GetHead => CommitResponse::Head {
             commit: detect_git_commit(registry_path),
         },
+        CommitRequest::GetAnnouncements { since } => CommitResponse::Announcements {
+            announcements: cache.since(*since),
+        },
         CommitRequest::GetCommitData { commit } => {
             // Validate: only allow hex commit hashes (prevent command injection).
             if !commit.chars().all(|c| c.is_ascii_hexdigit()) || commit.is_empty() {
@@ -475,17 +1214,65 @@ fn handle_commit_request(registry_path: &Path, request: &CommitRequest) -> Commi
                     message: "invalid commit hash".into(),
                 };
             }
+            // Quarantined versions, crates that are not public, and
+            // crates over the size limit are not handed out, whichever
+            // commit is asked for.
+            let withheld = match quarantine::read(&registry_path.join(quarantine::FILE_NAME)) {
+                Ok(withheld) => withheld,
+                Err(e) => {
+                    return CommitResponse::Error {
+                        message: e.to_string(),
+                    }
+                }
+            };
+            let levels = match visibility::read(&registry_path.join(visibility::FILE_NAME)) {
+                Ok(levels) => levels,
+                Err(e) => {
+                    return CommitResponse::Error {
+                        message: e.to_string(),
+                    }
+                }
+            };
             match collect_commit_files(registry_path, commit) {
                 Ok(files) => {
                     use base64::Engine;
                     let engine = base64::engine::general_purpose::STANDARD;
+                    let mut encodings = BTreeMap::new();
                     let encoded: Vec<(String, String)> = files
                         .into_iter()
-                        .map(|(path, data)| (path, engine.encode(data)))
+                        // Copies are only ever sent in place of their
+                        // `.crate` files
+                        .filter(|(path, _)| !path.contains(".crate."))
+                        .filter(|(path, _)| !quarantine::withholds(&withheld, path))
+                        .filter(|(path, _)| {
+                            path != visibility::FILE_NAME
+                                && visibility::crate_of(path).map_or(true, |n| levels.is_public(n))
+                        })
+                        .filter(|(path, data)| {
+                            let too_large =
+                                max_crate_size.map_or(false, |max| data.len() as u64 > max);
+                            !(path.ends_with(".crate") && too_large)
+                        })
+                        .map(|(path, data)| {
+                            let copy = if path.ends_with(".crate") {
+                                substitute_copy(&registry_path.join(&path), &data, accepted)
+                            } else {
+                                None
+                            };
+                            let data = match copy {
+                                Some((encoding, copy)) => {
+                                    encodings.insert(path.clone(), encoding);
+                                    copy
+                                }
+                                None => data,
+                            };
+                            (path, engine.encode(data))
+                        })
                         .collect();
                     CommitResponse::CommitData {
                         commit: commit.clone(),
                         files: encoded,
+                        encodings,
                     }
                 }
                 Err(e) => CommitResponse::Error {
@@ -493,7 +1280,797 @@ fn handle_commit_request(registry_path: &Path, request: &CommitRequest) -> Commi
                 },
             }
         }
+        CommitRequest::GetBlob { digest } => {
+            match blob::get(&registry_path.join(blob::DIR_NAME), digest) {
+                Ok(data) => {
+                    use base64::Engine;
+                    CommitResponse::Blob {
+                        digest: digest.clone(),
+                        data: base64::engine::general_purpose::STANDARD.encode(data),
+                    }
+                }
+                Err(e) => CommitResponse::Error {
+                    message: e.to_string(),
+                },
+            }
+        }
+        CommitRequest::Offer { crates } => {
+            if !registry.config.announcements.seeding.volunteer {
+                return CommitResponse::Error {
+                    message: "this node does not seed".into(),
+                };
+            }
+            let seeds_dir = registry_path.join(seeding::DIR_NAME);
+            let wanted = crates
+                .iter()
+                .filter(|c| blob::is_digest(&c.cksum) && !seeding::has(&seeds_dir, &c.cksum))
+                .cloned()
+                .collect();
+            CommitResponse::Wanted { crates: wanted }
+        }
+        CommitRequest::OfferBlobs { digests } => {
+            if !registry.config.announcements.seeding.volunteer {
+                return CommitResponse::Error {
+                    message: "this node does not seed".into(),
+                };
+            }
+            let blobs_dir = registry_path.join(blob::DIR_NAME);
+            let wanted = digests
+                .iter()
+                .filter(|d| blob::is_digest(d) && !blob::path_in(&blobs_dir, d).exists())
+                .cloned()
+                .collect();
+            CommitResponse::WantedBlobs { digests: wanted }
+        }
+        CommitRequest::Holds { crates } => {
+            let held = crates.iter().filter(|c| holds(registry, c));
+            CommitResponse::Held {
+                crates: held.cloned().collect(),
+            }
+        }
+        CommitRequest::GetCrate(c) => match find_crate(registry, c) {
+            Ok(Some((path, data))) => {
+                use base64::Engine;
+                let engine = base64::engine::general_purpose::STANDARD;
+                let (encoding, data) = match substitute_copy(&path, &data, accepted) {
+                    Some((encoding, copy)) => (Some(encoding), copy),
+                    None => (None, data),
+                };
+                CommitResponse::Crate {
+                    cksum: c.cksum.clone(),
+                    data: engine.encode(data),
+                    encoding,
+                }
+            }
+            Ok(None) => CommitResponse::Error {
+                message: format!("{} {} is not available", c.name, c.vers),
+            },
+            Err(e) => CommitResponse::Error {
+                message: e.to_string(),
+            },
+        },
+    }
+}
+
+/// What this node tells its peers when they connect.
+fn hello(registry: &Registry) -> Hello {
+    Hello {
+        encodings: readable_encodings(),
+        seeds: registry.config.announcements.seeding.volunteer,
+    }
+}
+
+/// The `.crate` file with the announced checksum and where it is, from
+/// the registry if it may be handed out, or else from the crates this
+/// node seeds.
+fn find_crate(
+    registry: &Registry,
+    c: &CrateAnnouncement,
+) -> io::Result<Option<(PathBuf, Vec<u8>)>> {
+    if !blob::is_digest(&c.cksum) {
+        return Ok(None);
+    }
+
+    if let Ok(name) = CrateName::from_untrusted(&c.name) {
+        if may_hand_out(registry, &name, &c.vers) {
+            let path = registry.crate_file_path_for(&name, &c.vers);
+            match std::fs::read(&path) {
+                // The file may have been replaced since it was offered
+                Ok(data) if blob::digest_of(&data) == c.cksum => return Ok(Some((path, data))),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    let seeds_dir = registry.path.join(seeding::DIR_NAME);
+    let path = seeding::path_of(&seeds_dir, &c.cksum);
+    Ok(seeding::get(&seeds_dir, &c.cksum)?.map(|data| (path, data)))
+}
+
+/// Whether the version is public and not quarantined, so peers may be
+/// given or offered it.
+fn may_hand_out(registry: &Registry, name: &CrateName, vers: &semver::Version) -> bool {
+    let public = registry
+        .visibility()
+        .is_ok_and(|levels| levels.is_public(name.as_str()));
+    let withheld = registry.withheld().map_or(true, |withheld| {
+        withheld.iter().any(|w| w.name == *name && w.vers == *vers)
+    });
+    public && !withheld
+}
+
+/// Offers the versions the daemon served most to the peers that
+/// volunteered to seed.
+fn offer_popular(
+    swarm: &mut Swarm<Behaviour>,
+    registry: &Registry,
+    status: &SharedStatus,
+    hellos: &HashMap<PeerId, Hello>,
+) {
+    let volunteers = hellos
+        .iter()
+        .filter(|(_, hello)| hello.seeds)
+        .map(|(peer, _)| *peer)
+        .collect::<Vec<_>>();
+    if volunteers.is_empty() {
+        return;
+    }
+
+    let top = registry.config.announcements.seeding.top();
+    let downloads = status.update(|s| s.downloads.clone());
+    let crates = popular_crates(registry, &downloads, top);
+    if crates.is_empty() {
+        return;
     }
+
+    println!(
+        "Offering {} popular crate(s) to {} seeding peer(s)",
+        crates.len(),
+        volunteers.len(),
+    );
+    let commit_rpc = &mut swarm.behaviour_mut().commit_rpc;
+    for peer in volunteers {
+        let request = CommitRequest::Offer {
+            crates: crates.clone(),
+        };
+        commit_rpc.send_request(&peer, request);
+    }
+}
+
+/// Whether peers may be offered the version: it may be handed out, and
+/// its `.crate` file is there and within the size limit.
+fn may_offer(registry: &Registry, name: &CrateName, vers: &semver::Version) -> bool {
+    let max_crate_size = registry.config.limits.max_crate_size;
+    let path = registry.crate_file_path_for(name, vers);
+    let fits = std::fs::metadata(path)
+        .is_ok_and(|metadata| max_crate_size.map_or(true, |max| metadata.len() <= max));
+    fits && may_hand_out(registry, name, vers)
+}
+
+/// Whether this node holds the `.crate` file with the announced
+/// checksum, and would hand it out.
+fn holds(registry: &Registry, c: &CrateAnnouncement) -> bool {
+    if !blob::is_digest(&c.cksum) {
+        return false;
+    }
+
+    if seeding::has(&registry.path.join(seeding::DIR_NAME), &c.cksum) {
+        return true;
+    }
+
+    let Ok(name) = CrateName::from_untrusted(&c.name) else {
+        return false;
+    };
+    let index = Registry::parse_index_file(&registry.index_file_path_for(&name));
+    let listed = index.is_ok_and(|index| index.get(&c.vers).is_some_and(|e| e.cksum == c.cksum));
+    listed && may_offer(registry, &name, &c.vers)
+}
+
+fn popular_crates(
+    registry: &Registry,
+    downloads: &BTreeMap<String, BTreeMap<String, u64>>,
+    top: usize,
+) -> Vec<CrateAnnouncement> {
+    seeding::popular(downloads, top)
+        .into_iter()
+        .filter_map(|(name, vers)| {
+            let name = name.parse::<CrateName>().ok()?;
+            let vers = vers.parse::<semver::Version>().ok()?;
+            if !may_offer(registry, &name, &vers) {
+                return None;
+            }
+
+            let index = Registry::parse_index_file(&registry.index_file_path_for(&name)).ok()?;
+            let entry = index.get(&vers)?;
+
+            Some(CrateAnnouncement {
+                name: name.to_string(),
+                vers,
+                cksum: entry.cksum.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Offers the pinned versions that too few peers held when last asked
+/// to volunteers that lack them, then asks the peers again.
+fn check_pins(
+    swarm: &mut Swarm<Behaviour>,
+    registry: &Registry,
+    status: &SharedStatus,
+    hellos: &HashMap<PeerId, Hello>,
+    holders: &mut Option<HashMap<String, HashSet<PeerId>>>,
+) {
+    let pinned = pinned_crates(registry);
+
+    // Until the peers have been asked, every pin would look unheld
+    if let Some(holders) = holders.take() {
+        let mut offers: HashMap<PeerId, Vec<CrateAnnouncement>> = HashMap::new();
+        let mut replication = BTreeMap::new();
+        for (c, replicas) in &pinned {
+            let held_by = holders.get(&c.cksum);
+            let providers = held_by.map_or(0, HashSet::len);
+            replication.insert(
+                format!("{}@{}", c.name, c.vers),
+                Replication {
+                    providers,
+                    replicas: *replicas,
+                },
+            );
+
+            let volunteers = hellos
+                .iter()
+                .filter(|(peer, hello)| hello.seeds && !held_by.is_some_and(|h| h.contains(*peer)))
+                .map(|(peer, _)| *peer);
+            for peer in volunteers.take(replicas.saturating_sub(providers)) {
+                offers.entry(peer).or_default().push(c.clone());
+            }
+        }
+        status.update(|s| s.pins = replication);
+
+        if !offers.is_empty() {
+            println!(
+                "Offering pinned crates held by too few peers to {} seeding peer(s)",
+                offers.len(),
+            );
+        }
+        let commit_rpc = &mut swarm.behaviour_mut().commit_rpc;
+        for (peer, crates) in offers {
+            for crates in crates.chunks(MAX_LIST_LEN) {
+                let request = CommitRequest::Offer {
+                    crates: crates.to_vec(),
+                };
+                commit_rpc.send_request(&peer, request);
+            }
+        }
+    }
+
+    if pinned.is_empty() {
+        return;
+    }
+
+    // Peers that said hello understand the question
+    *holders = Some(HashMap::new());
+    let crates = pinned.into_iter().map(|(c, _)| c).collect::<Vec<_>>();
+    let commit_rpc = &mut swarm.behaviour_mut().commit_rpc;
+    for peer in hellos.keys() {
+        for crates in crates.chunks(MAX_LIST_LEN) {
+            let request = CommitRequest::Holds {
+                crates: crates.to_vec(),
+            };
+            commit_rpc.send_request(peer, request);
+        }
+    }
+}
+
+/// Every version pins cover that peers may be offered, with how many
+/// peers should hold it.
+fn pinned_crates(registry: &Registry) -> Vec<(CrateAnnouncement, usize)> {
+    let pins = match pins::read(&registry.path.join(pins::FILE_NAME)) {
+        Ok(pins) => pins,
+        Err(e) => {
+            eprintln!("Warning: {e}");
+            return vec![];
+        }
+    };
+
+    // Pins of a crate and of its versions share an index file
+    let names = pins
+        .iter()
+        .map(|p| (p.name.as_str().to_ascii_lowercase(), &p.name))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut crates = vec![];
+    for name in names.into_values() {
+        let Ok(index) = Registry::parse_index_file(&registry.index_file_path_for(name)) else {
+            continue;
+        };
+        for (vers, entry) in index {
+            let Some(replicas) = pins::replicas_of(&pins, name.as_str(), &vers) else {
+                continue;
+            };
+            if !may_offer(registry, name, &vers) {
+                continue;
+            }
+            let c = CrateAnnouncement {
+                name: name.to_string(),
+                vers,
+                cksum: entry.cksum,
+            };
+            crates.push((c, replicas));
+        }
+    }
+    crates
+}
+
+/// Offers the shards of each public version to the volunteers, a shard
+/// to each while there are enough of them. Each volunteer is offered
+/// the same shard of every version.
+#[cfg(feature = "erasure")]
+fn distribute_shards(
+    swarm: &mut Swarm<Behaviour>,
+    registry: &Registry,
+    hellos: &HashMap<PeerId, Hello>,
+) {
+    let mut volunteers = hellos
+        .iter()
+        .filter(|(_, hello)| hello.seeds)
+        .map(|(peer, _)| *peer)
+        .collect::<Vec<_>>();
+    if volunteers.is_empty() {
+        return;
+    }
+    volunteers.sort();
+
+    let manifests = match erasure::read_all(&registry.shards_dir()) {
+        Ok(manifests) => manifests,
+        Err(e) => {
+            eprintln!("Warning: {e}");
+            return;
+        }
+    };
+
+    let mut offers: HashMap<PeerId, Vec<String>> = HashMap::new();
+    for manifest in manifests {
+        let Ok(name) = CrateName::from_untrusted(&manifest.name) else {
+            continue;
+        };
+        if !may_hand_out(registry, &name, &manifest.vers) {
+            continue;
+        }
+        for (i, digest) in manifest.shards.into_iter().enumerate() {
+            let peer = volunteers[i % volunteers.len()];
+            offers.entry(peer).or_default().push(digest);
+        }
+    }
+
+    let commit_rpc = &mut swarm.behaviour_mut().commit_rpc;
+    for (peer, digests) in offers {
+        for digests in digests.chunks(MAX_LIST_LEN) {
+            let request = CommitRequest::OfferBlobs {
+                digests: digests.to_vec(),
+            };
+            commit_rpc.send_request(&peer, request);
+        }
+    }
+}
+
+/// Rebuilds the missing `.crate` files of versions with shards from
+/// the shards this node has, and asks its peers for the others.
+#[cfg(feature = "erasure")]
+fn restore_missing(
+    swarm: &mut Swarm<Behaviour>,
+    registry: &Registry,
+    hellos: &HashMap<PeerId, Hello>,
+    restoring: &mut HashMap<String, erasure::Manifest>,
+    backlog: &mut Backlog,
+) {
+    let manifests = match erasure::read_all(&registry.shards_dir()) {
+        Ok(manifests) => manifests,
+        Err(e) => {
+            eprintln!("Warning: {e}");
+            return;
+        }
+    };
+
+    let blobs_dir = registry.blobs_dir();
+    for manifest in manifests {
+        let Ok(name) = CrateName::from_untrusted(&manifest.name) else {
+            continue;
+        };
+        let path = registry.crate_file_path_for(&name, &manifest.vers);
+        let demoted = registry.cold_path_for(&path).is_some_and(|p| p.exists());
+        if path.exists() || demoted || restore(registry, &manifest) {
+            continue;
+        }
+
+        let missing = manifest
+            .shards
+            .iter()
+            .filter(|d| !blob::path_in(&blobs_dir, d).exists())
+            .collect::<Vec<_>>();
+        println!(
+            "Asking peers for {} shard(s) of {} {}",
+            missing.len(),
+            manifest.name,
+            manifest.vers,
+        );
+        for peer in hellos.keys() {
+            for &digest in &missing {
+                let digest = digest.clone();
+                fetch(swarm, backlog, peer, CommitRequest::GetBlob { digest });
+            }
+        }
+        restoring.insert(manifest.cksum.clone(), manifest);
+    }
+}
+
+/// Rebuilds the version's `.crate` file from the shards this node has,
+/// if there are enough of them and it matches the checksum in the
+/// index.
+#[cfg(feature = "erasure")]
+fn restore(registry: &Registry, manifest: &erasure::Manifest) -> bool {
+    let Ok(name) = CrateName::from_untrusted(&manifest.name) else {
+        return false;
+    };
+    let index = Registry::parse_index_file(&registry.index_file_path_for(&name));
+    let listed = index.is_ok_and(|index| {
+        index
+            .get(&manifest.vers)
+            .is_some_and(|e| e.cksum == manifest.cksum)
+    });
+    if !listed {
+        return false;
+    }
+
+    let Ok(data) = erasure::rebuild(&registry.blobs_dir(), manifest) else {
+        return false;
+    };
+
+    let path = registry.crate_file_path_for(&name, &manifest.vers);
+    let tmp_path = path.with_extension("crate.tmp");
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(&tmp_path, &data))
+        .and_then(|()| std::fs::rename(&tmp_path, &path));
+    match written {
+        Ok(()) => {
+            println!("Rebuilt {name} {} from its shards", manifest.vers);
+            true
+        }
+        Err(e) => {
+            let path = path.display();
+            eprintln!("Warning: Could not write `{path}`: {e}");
+            false
+        }
+    }
+}
+
+/// Keeps a crate a peer sent for seeding, but only if this node
+/// volunteers and it has the checksum it was asked for.
+fn receive_seed(
+    registry: &Registry,
+    cksum: &str,
+    data: &str,
+    encoding: Option<&str>,
+    max: usize,
+) -> Result<(), String> {
+    use base64::Engine;
+
+    let policy = &registry.config.announcements.seeding;
+    if !policy.volunteer {
+        return Err("this node does not seed".into());
+    }
+
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| e.to_string())?;
+    let data = match encoding {
+        Some(encoding) => decode_copy(cksum, encoding, &data, max)?,
+        None => data,
+    };
+
+    if !blob::is_digest(cksum) || blob::digest_of(&data) != cksum {
+        return Err("the contents do not match the checksum".into());
+    }
+
+    let seeds_dir = registry.path.join(seeding::DIR_NAME);
+    seeding::store(&seeds_dir, cksum, &data, policy.max_bytes()).map_err(|e| e.to_string())
+}
+
+/// The encodings of `.crate` files this build reads.
+#[cfg(feature = "zstd")]
+fn readable_encodings() -> Vec<String> {
+    copies::Encoding::ALL
+        .iter()
+        .map(|e| e.name().to_owned())
+        .collect()
+}
+
+#[cfg(not(feature = "zstd"))]
+fn readable_encodings() -> Vec<String> {
+    vec![]
+}
+
+/// The copy of the `.crate` file in an encoding the peer reads, and the
+/// encoding's name.
+#[cfg(feature = "zstd")]
+fn substitute_copy(
+    crate_file: &Path,
+    data: &[u8],
+    accepted: &[String],
+) -> Option<(String, Vec<u8>)> {
+    let accepted = accepted
+        .iter()
+        .filter_map(|name| copies::Encoding::from_name(name))
+        .collect();
+    let (encoding, copy) = copies::substitute(crate_file, data, &accepted)?;
+    Some((encoding.name().to_owned(), copy))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn substitute_copy(
+    _crate_file: &Path,
+    _data: &[u8],
+    _accepted: &[String],
+) -> Option<(String, Vec<u8>)> {
+    None
+}
+
+#[cfg(feature = "zstd")]
+fn decode_copy(path: &str, name: &str, data: &[u8], max: usize) -> Result<Vec<u8>, String> {
+    let Some(encoding) = copies::Encoding::from_name(name) else {
+        return Err(unreadable(path, name));
+    };
+    encoding
+        .decode(data, max)
+        .map_err(|e| format!("could not decode {path}: {e}"))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decode_copy(path: &str, name: &str, _data: &[u8], _max: usize) -> Result<Vec<u8>, String> {
+    Err(unreadable(path, name))
+}
+
+fn unreadable(path: &str, name: &str) -> String {
+    format!("{path} was sent as {name}, which this node does not read")
+}
+
+/// The files of commit data as they are stored, decoding those sent as
+/// copies.
+fn decode_commit_files(
+    files: &[(String, String)],
+    encodings: &BTreeMap<String, String>,
+    max: usize,
+) -> Result<Vec<(String, Vec<u8>)>, String> {
+    use base64::Engine;
+    let engine = base64::engine::general_purpose::STANDARD;
+
+    files
+        .iter()
+        .map(|(path, data)| {
+            let data = engine.decode(data).map_err(|e| e.to_string())?;
+            let data = match encodings.get(path) {
+                Some(encoding) => decode_copy(path, encoding, &data, max)?,
+                None => data,
+            };
+            Ok((path.clone(), data))
+        })
+        .collect()
+}
+
+/// Asks the peer for a crate or blob, which counts towards the backlog
+/// until it is answered.
+fn fetch(
+    swarm: &mut Swarm<Behaviour>,
+    backlog: &mut Backlog,
+    peer: &PeerId,
+    request: CommitRequest,
+) {
+    let request_id = swarm.behaviour_mut().commit_rpc.send_request(peer, request);
+    backlog.insert(request_id, *peer);
+}
+
+/// Dials the peers found on the local network until the node has as
+/// many as its backlog wants. Once the backlog has cleared, those
+/// dialed for it are disconnected again, unless they are being served.
+fn scale_dials(
+    swarm: &mut Swarm<Behaviour>,
+    dials: &dials::Policy,
+    known: &HashMap<PeerId, Multiaddr>,
+    scaled: &mut HashSet<PeerId>,
+    serving: &HashMap<PeerId, usize>,
+    backlog: usize,
+) {
+    let target = dials.target(backlog);
+    let connected = swarm.connected_peers().count();
+
+    if connected < target {
+        let unconnected = known
+            .iter()
+            .filter(|(peer, _)| !swarm.is_connected(peer))
+            .take(target - connected)
+            .map(|(peer, addr)| (*peer, addr.clone()))
+            .collect::<Vec<_>>();
+        for (i, (peer, addr)) in unconnected.into_iter().enumerate() {
+            let opts = DialOpts::peer_id(peer).addresses(vec![addr]).build();
+            if swarm.dial(opts).is_ok() && connected + i >= dials.min_peers() {
+                scaled.insert(peer);
+            }
+        }
+    } else if backlog == 0 {
+        let excess = connected.saturating_sub(dials.min_peers());
+        let idle = scaled
+            .iter()
+            .filter(|peer| swarm.is_connected(peer) && !serving.contains_key(*peer))
+            .take(excess)
+            .copied()
+            .collect::<Vec<_>>();
+        for peer in idle {
+            println!("Disconnecting {peer}, dialed for a backlog that has cleared");
+            scaled.remove(&peer);
+            _ = swarm.disconnect_peer_id(peer);
+        }
+    }
+}
+
+/// Forgets one request from `peer` once its response is sent or has
+/// failed.
+fn finish_serving(serving: &mut HashMap<PeerId, usize>, peer: PeerId) {
+    if let Some(n) = serving.get_mut(&peer) {
+        *n = n.saturating_sub(1);
+        if *n == 0 {
+            serving.remove(&peer);
+        }
+    }
+}
+
+/// Tells a peer what this node reads and volunteers for, and asks for
+/// the same, its HEAD and the announcements it received since `since`.
+fn catch_up(swarm: &mut Swarm<Behaviour>, registry: &Registry, peer: &PeerId, since: u64) {
+    let commit_rpc = &mut swarm.behaviour_mut().commit_rpc;
+    commit_rpc.send_request(peer, CommitRequest::Hello(hello(registry)));
+    commit_rpc.send_request(peer, CommitRequest::GetHead);
+    commit_rpc.send_request(peer, CommitRequest::GetAnnouncements { since });
+}
+
+fn handle_control_request(
+    swarm: &mut Swarm<Behaviour>,
+    status: &SharedStatus,
+    registry: &SharedRegistry,
+    validator: &mut Validator,
+    cache: &Cache,
+    request: control::Request,
+) -> control::Response {
+    use control::{Request, Response};
+
+    match request {
+        Request::Sync => {
+            let peers = swarm.connected_peers().copied().collect::<Vec<_>>();
+            let registry = registry.get();
+            for peer in &peers {
+                catch_up(swarm, &registry, peer, cache.replay_from());
+            }
+            Response::Syncing { peers: peers.len() }
+        }
+        Request::Reload => {
+            let trusted = registry.get().config.announcements.trusted_peers.clone();
+            validator.trust(trusted);
+            println!("Reloaded the trusted peers");
+            Response::Done
+        }
+        Request::Status | Request::RotateKey => Response::error("Not a request for the P2P node"),
+        Request::PeerList => {
+            let connected = status.update(|s| s.peers.keys().cloned().collect::<BTreeSet<_>>());
+            let banned = swarm
+                .behaviour()
+                .banned
+                .blocked_peers()
+                .iter()
+                .map(PeerId::to_string);
+            let peers = connected
+                .into_iter()
+                .chain(banned)
+                .collect::<BTreeSet<_>>()
+                .iter()
+                .filter_map(|p| p.parse().ok())
+                .map(|p| peer_info(swarm, status, p))
+                .collect();
+            Response::Peers { peers }
+        }
+        Request::PeerDial { addr } => {
+            let addr = match addr.parse::<Multiaddr>() {
+                Ok(addr) => addr,
+                Err(e) => return Response::error(format!("Invalid address `{addr}`: {e}")),
+            };
+            println!("Dialing {addr}");
+            match swarm.dial(addr) {
+                Ok(()) => Response::Done,
+                Err(e) => Response::error(e),
+            }
+        }
+        Request::PeerBan { peer_id } => {
+            let peer_id = match peer_id.parse::<PeerId>() {
+                Ok(p) => p,
+                Err(e) => return Response::error(format!("Invalid peer ID `{peer_id}`: {e}")),
+            };
+            println!("Banning {peer_id}");
+            let behaviour = swarm.behaviour_mut();
+            behaviour.banned.block_peer(peer_id);
+            behaviour.gossipsub.blacklist_peer(&peer_id);
+            behaviour.gossipsub.remove_explicit_peer(&peer_id);
+            _ = swarm.disconnect_peer_id(peer_id);
+            Response::Done
+        }
+        Request::PeerInfo { peer_id } => {
+            let peer_id = match peer_id.parse::<PeerId>() {
+                Ok(p) => p,
+                Err(e) => return Response::error(format!("Invalid peer ID `{peer_id}`: {e}")),
+            };
+            let banned = swarm.behaviour().banned.blocked_peers().contains(&peer_id);
+            if !swarm.is_connected(&peer_id) && !banned {
+                return Response::error(format!("Not connected to {peer_id}"));
+            }
+            Response::Peer(peer_info(swarm, status, peer_id))
+        }
+    }
+}
+
+fn peer_info(swarm: &Swarm<Behaviour>, status: &SharedStatus, peer_id: PeerId) -> PeerInfo {
+    let peer = status.update(|s| s.peers.get(&peer_id.to_string()).cloned());
+    let peer = peer.unwrap_or_default();
+
+    let topics = swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .find(|(p, _)| **p == peer_id)
+        .map(|(_, topics)| topics.iter().map(|t| t.to_string()).collect())
+        .unwrap_or_default();
+
+    PeerInfo {
+        peer_id: peer_id.to_string(),
+        connected: swarm.is_connected(&peer_id),
+        banned: swarm.behaviour().banned.blocked_peers().contains(&peer_id),
+        address: peer.address,
+        agent: peer.agent,
+        rtt_ms: peer.rtt_ms,
+        topics,
+    }
+}
+
+/// Adds an announcement to the cache and saves it. Returns `false` if
+/// it was already cached.
+fn cache_announcement(cache: &mut Cache, path: &Path, announcement: Cached) -> bool {
+    if !cache.insert(announcement, status::unix_now()) {
+        return false;
+    }
+    if let Err(e) = cache.save(path) {
+        println!("Could not save the gossip cache: {e}");
+    }
+
+    true
+}
+
+/// Stores a blob from a peer, but only if it has the digest it was
+/// sent as.
+fn receive_blob(registry_path: &Path, digest: &str, data: &str) -> Result<(), String> {
+    use base64::Engine;
+
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| e.to_string())?;
+
+    if !blob::is_digest(digest) || blob::digest_of(&data) != digest {
+        return Err("the contents do not match the digest".into());
+    }
+
+    blob::store(&registry_path.join(blob::DIR_NAME), digest, &data)
+        .map(drop)
+        .map_err(|e| e.to_string())
 }
 
 // ---------------------------------------------------------------------------
@@ -513,4 +2090,59 @@ pub enum P2pError {
 
     #[snafu(display("Could not subscribe to gossipsub topic"))]
     GossipsubSubscribe { source: gossipsub::SubscriptionError },
+
+    #[snafu(display("Could not load the gossip cache"))]
+    GossipCache { source: gossip_cache::Error },
+}
+
+impl P2pError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Transport { .. } => "E_P2P_TRANSPORT",
+            Self::Listen { .. } => "E_P2P_LISTEN",
+            Self::GossipsubSubscribe { .. } => "E_P2P_SUBSCRIBE",
+            Self::GossipCache { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libp2p::futures::io::Cursor;
+
+    fn framed(body: &[u8]) -> Cursor<Vec<u8>> {
+        let mut data = (body.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(body);
+        Cursor::new(data)
+    }
+
+    #[tokio::test]
+    async fn peers_cannot_make_the_node_buffer_too_much() {
+        let limits = Limits {
+            max_request_bytes: Some(64),
+            max_buffered_bytes: Some(100),
+            ..Limits::default()
+        };
+        let mut codec = CommitCodec::new(SharedStatus::default(), &limits);
+        let request = serde_json::to_vec(&CommitRequest::GetHead).unwrap();
+
+        let mut io = framed(&request);
+        let read = codec.read_request(&COMMIT_PROTOCOL, &mut io).await;
+        assert!(matches!(read.unwrap(), CommitRequest::GetHead));
+
+        let mut io = framed(&[b' '; 65]);
+        let read = codec.read_request(&COMMIT_PROTOCOL, &mut io).await;
+        assert_eq!(io::ErrorKind::InvalidData, read.unwrap_err().kind());
+
+        let held = codec.budget.reserve(90).unwrap();
+        let mut io = framed(&request);
+        let read = codec.read_request(&COMMIT_PROTOCOL, &mut io).await;
+        assert_eq!(io::ErrorKind::OutOfMemory, read.unwrap_err().kind());
+
+        drop(held);
+        let mut io = framed(&request);
+        assert!(codec.read_request(&COMMIT_PROTOCOL, &mut io).await.is_ok());
+        assert_eq!(0, codec.budget.used.load(Ordering::Acquire));
+    }
 }
@@ -0,0 +1,113 @@
+//! The messages nodes announce things with, over gossipsub and on nostr.
+//!
+//! A payload is a JSON object with the schema version `v` and a `kind`,
+//! next to the fields of that kind:
+//!
+//! ```json
+//! {"v":1,"kind":"crate","name":"demo","vers":"1.0.0","cksum":"..."}
+//! ```
+//!
+//! Nodes ignore fields they do not know, so a newer version may add
+//! fields and older nodes still read the ones they understand; `v`
+//! records which fields the sender knew of. A change that older nodes
+//! could not read gets a new kind instead. A kind a node does not know
+//! parses as [`Body::Unknown`], which is ignored rather than treated as
+//! malformed.
+
+use serde::{Deserialize, Serialize};
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Payload {
+    pub v: u32,
+
+    #[serde(flatten)]
+    pub body: Body,
+}
+
+impl Payload {
+    pub fn new(body: Body) -> Self {
+        Self { v: VERSION, body }
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Payloads are always serializable")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Body {
+    /// A registry's git HEAD, gossiped to peers.
+    #[cfg(feature = "p2p")]
+    Head { commit: String },
+
+    /// A newly available crate version, gossiped to peers.
+    #[cfg(feature = "p2p")]
+    Crate(CrateAnnouncement),
+
+    /// That a registry exists and is alive, published to nostr relays.
+    #[cfg(feature = "nostr")]
+    Registry(crate::announce::Announcement),
+
+    /// That the operator's key was replaced, signed by the old key and
+    /// published to nostr relays.
+    #[cfg(feature = "nostr")]
+    KeyRotation(crate::key_rotation::Rotation),
+
+    /// That an operator approves an operation that needs a
+    /// [quorum](crate::quorum), published to nostr relays.
+    #[cfg(feature = "nostr")]
+    Approval(crate::quorum::Approval),
+
+    /// A kind added by a newer version.
+    #[serde(other)]
+    Unknown,
+}
+
+#[cfg(feature = "p2p")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CrateAnnouncement {
+    pub name: String,
+    pub vers: semver::Version,
+    pub cksum: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "p2p")]
+    #[test]
+    fn newer_payloads_are_read_as_far_as_they_are_understood() {
+        let head = Payload::new(Body::Head {
+            commit: "ab".repeat(20),
+        });
+        let data = head.to_vec();
+        assert!(String::from_utf8_lossy(&data).contains(r#""kind":"head""#));
+        assert!(matches!(
+            Payload::parse(&data).unwrap().body,
+            Body::Head { .. }
+        ));
+
+        let added_field =
+            br#"{"v":2,"kind":"crate","name":"demo","vers":"1.0.0","cksum":"cd","yanked":false}"#;
+        let payload = Payload::parse(added_field).unwrap();
+        assert_eq!(2, payload.v);
+        assert!(matches!(payload.body, Body::Crate(c) if c.name == "demo"));
+
+        let added_kind = br#"{"v":3,"kind":"owner-change","name":"demo"}"#;
+        assert!(matches!(
+            Payload::parse(added_kind).unwrap().body,
+            Body::Unknown
+        ));
+
+        assert!(Payload::parse(br#"{"v":1}"#).is_none());
+        assert!(Payload::parse(b"not json").is_none());
+    }
+}
@@ -0,0 +1,231 @@
+//! Crates an operator wants kept by a number of peers.
+//!
+//! `margo pin NAME[@VERSION] --replicas N` records the crate in
+//! `pins.json`; without a version, every version of it is pinned. Every
+//! ten minutes, the daemon asks its peers which of the pinned versions
+//! they hold, and offers each version held by fewer than `replicas` of
+//! them to the volunteers that [seed](crate::seeding) and lack it, which
+//! fetch it. The nodes have no DHT, so holders are counted among the
+//! peers the daemon is connected to, and the counts are shown in its
+//! status. `margo unpin` stops this.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    common::{CrateName, CrateVersion, CrateVersionError},
+    timestamp::Timestamp,
+};
+
+pub const FILE_NAME: &str = "pins.json";
+
+pub const DEFAULT_REPLICAS: usize = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pin {
+    pub name: CrateName,
+
+    /// Every version, when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vers: Option<Version>,
+
+    /// How many peers should hold each version.
+    pub replicas: usize,
+
+    pub pinned_at: Timestamp,
+}
+
+impl Pin {
+    pub fn target(&self) -> Target {
+        Target {
+            name: self.name.clone(),
+            vers: self.vers.clone(),
+        }
+    }
+
+    pub fn covers(&self, name: &str, vers: &Version) -> bool {
+        self.name.as_str().eq_ignore_ascii_case(name)
+            && self.vers.as_ref().map_or(true, |v| v == vers)
+    }
+}
+
+/// A crate, or one version of it, written `{name}` or
+/// `{name}@{version}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub name: CrateName,
+    pub vers: Option<Version>,
+}
+
+impl Target {
+    fn is(&self, other: &Target) -> bool {
+        self.name.as_str().eq_ignore_ascii_case(other.name.as_str()) && self.vers == other.vers
+    }
+}
+
+impl FromStr for Target {
+    type Err = CrateVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.contains('@') {
+            return Ok(Self {
+                name: s.parse()?,
+                vers: None,
+            });
+        }
+
+        let CrateVersion { name, version } = s.parse()?;
+        Ok(Self {
+            name,
+            vers: Some(version),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.vers {
+            Some(vers) => write!(f, "{}@{vers}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// A missing file pins nothing.
+pub fn read(path: &Path) -> Result<Vec<Pin>, Error> {
+    use error::*;
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+
+    serde_json::from_slice(&data).context(ParseSnafu { path })
+}
+
+fn write(path: &Path, pins: &[Pin]) -> Result<(), Error> {
+    use error::*;
+
+    let data = serde_json::to_vec_pretty(pins).context(SerializeSnafu)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, path).context(WriteSnafu { path })
+}
+
+/// Records the pin, replacing any earlier pin of the same crate or
+/// version.
+pub fn set(path: &Path, pin: Pin) -> Result<(), Error> {
+    let mut all = read(path)?;
+
+    let target = pin.target();
+    all.retain(|p| !p.target().is(&target));
+    all.push(pin);
+
+    write(path, &all)
+}
+
+pub fn remove(path: &Path, target: &Target) -> Result<Pin, Error> {
+    use error::*;
+
+    let mut all = read(path)?;
+
+    let i = all
+        .iter()
+        .position(|p| p.target().is(target))
+        .context(UnknownSnafu {
+            target: target.clone(),
+        })?;
+    let pin = all.remove(i);
+
+    write(path, &all)?;
+
+    Ok(pin)
+}
+
+/// How many peers should hold the version: the most any pin covering
+/// it asks for.
+pub fn replicas_of(pins: &[Pin], name: &str, vers: &Version) -> Option<usize> {
+    pins.iter()
+        .filter(|p| p.covers(name, vers))
+        .map(|p| p.replicas)
+        .max()
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the pins at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the pins at {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the pins"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write the pins to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("{target} is not pinned"))]
+    Unknown { target: Target },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_PINS_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Unknown { .. } => "E_NOT_PINNED",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pins_are_replaced_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join(FILE_NAME);
+
+        let pin = |target: &str, replicas| {
+            let Target { name, vers } = target.parse().unwrap();
+            Pin {
+                name,
+                vers,
+                replicas,
+                pinned_at: Timestamp::now(),
+            }
+        };
+        let v1: Version = "1.0.0".parse().unwrap();
+        let v2: Version = "2.0.0".parse().unwrap();
+
+        set(&path, pin("demo", 2)).unwrap();
+        set(&path, pin("demo@1.0.0", 5)).unwrap();
+        set(&path, pin("Demo", 3)).unwrap();
+        assert_eq!(2, read(&path).unwrap().len());
+
+        let pins = read(&path).unwrap();
+        assert_eq!(Some(5), replicas_of(&pins, "demo", &v1));
+        assert_eq!(Some(3), replicas_of(&pins, "demo", &v2));
+        assert_eq!(None, replicas_of(&pins, "other", &v1));
+
+        remove(&path, &"demo@1.0.0".parse().unwrap()).unwrap();
+        assert_eq!(Some(3), replicas_of(&read(&path).unwrap(), "demo", &v1));
+        assert!(remove(&path, &"demo@1.0.0".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Showing how far a long command has got.
+//!
+//! On a terminal, `verify`, `sync`, `mirror verify` and `dedup` draw a
+//! bar on stderr with the items done, the rate, and the bytes moved
+//! when there are any:
+//!
+//! ```text
+//! verify [==============>               ] 120/250 41.3/s
+//! ```
+//!
+//! With `--json`, they instead write one JSON object per line to
+//! stderr as each item is done, and one when they finish, so a wrapper
+//! can show its own progress:
+//!
+//! ```json
+//! {"event":"progress","task":"verify","done":120,"total":250,"bytes":0,"elapsed_ms":2906,"item":"serde"}
+//! {"event":"finished","task":"verify","done":250,"total":250,"bytes":0,"elapsed_ms":6051}
+//! ```
+//!
+//! When stderr is not a terminal and `--json` is not given, nothing is
+//! shown.
+
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+/// How long the bar waits between redraws.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+const BAR_WIDTH: usize = 30;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Quiet,
+    Bar,
+    Json,
+}
+
+impl Mode {
+    pub fn detect(json: bool) -> Self {
+        if json {
+            Self::Json
+        } else if io::stderr().is_terminal() {
+            Self::Bar
+        } else {
+            Self::Quiet
+        }
+    }
+}
+
+/// The progress of one task. The bar is left showing the final counts,
+/// or the `finished` event written, when it is dropped.
+#[derive(Debug)]
+pub struct Progress {
+    mode: Mode,
+    task: &'static str,
+    total: Option<u64>,
+    done: u64,
+    bytes: u64,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+#[derive(Debug, Serialize)]
+struct Event<'a> {
+    event: &'static str,
+    task: &'static str,
+    done: u64,
+    total: Option<u64>,
+    bytes: u64,
+    elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    item: Option<&'a str>,
+}
+
+impl Progress {
+    pub fn new(mode: Mode, task: &'static str, total: Option<u64>) -> Self {
+        Self {
+            mode,
+            task,
+            total,
+            done: 0,
+            bytes: 0,
+            started: Instant::now(),
+            drawn: None,
+        }
+    }
+
+    /// For tasks that only find out how much there is to do once they
+    /// have started.
+    pub fn set_total(&mut self, total: u64) {
+        self.total = Some(total);
+    }
+
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    /// Counts `item` as done.
+    pub fn inc(&mut self, item: impl fmt::Display) {
+        self.done += 1;
+
+        match self.mode {
+            Mode::Quiet => {}
+            Mode::Bar => {
+                let now = Instant::now();
+                if self.drawn.map_or(true, |d| now - d >= REDRAW_INTERVAL) {
+                    self.drawn = Some(now);
+                    self.draw();
+                }
+            }
+            Mode::Json => self.emit("progress", Some(&item.to_string())),
+        }
+    }
+
+    /// Prints a line to stdout without it running into the bar.
+    pub fn println(&self, line: impl fmt::Display) {
+        if self.mode == Mode::Bar && self.drawn.is_some() {
+            eprint!("\r\x1b[K");
+            println!("{line}");
+            self.draw();
+        } else {
+            println!("{line}");
+        }
+    }
+
+    fn draw(&self) {
+        let mut stderr = io::stderr().lock();
+        // A bar that cannot be drawn is not worth failing over
+        let _ = write!(stderr, "\r\x1b[K{}", self.line(self.started.elapsed()));
+        let _ = stderr.flush();
+    }
+
+    fn emit(&self, event: &'static str, item: Option<&str>) {
+        let event = Event {
+            event,
+            task: self.task,
+            done: self.done,
+            total: self.total,
+            bytes: self.bytes,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            item,
+        };
+        let event = serde_json::to_string(&event).expect("Events are always serializable");
+        eprintln!("{event}");
+    }
+
+    fn line(&self, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64().max(0.001);
+        let rate = self.done as f64 / secs;
+
+        let mut line = match self.total {
+            Some(total) => {
+                let filled = match total {
+                    0 => BAR_WIDTH,
+                    _ => (self.done.min(total) as usize * BAR_WIDTH) / total as usize,
+                };
+                let head = if filled < BAR_WIDTH { ">" } else { "" };
+                let rest = BAR_WIDTH.saturating_sub(filled + head.len());
+                format!(
+                    "{} [{}{head}{}] {}/{total} {rate:.1}/s",
+                    self.task,
+                    "=".repeat(filled),
+                    " ".repeat(rest),
+                    self.done,
+                )
+            }
+            None => format!("{} {} {rate:.1}/s", self.task, self.done),
+        };
+
+        if self.bytes > 0 {
+            let mib = self.bytes as f64 / (1024.0 * 1024.0);
+            line.push_str(&format!(" {mib:.1} MiB {:.1} MiB/s", mib / secs));
+        }
+
+        line
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        match self.mode {
+            Mode::Quiet => {}
+            Mode::Bar => {
+                self.draw();
+                eprintln!();
+            }
+            Mode::Json => self.emit("finished", None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_line_shows_counts_rate_and_throughput() {
+        let mut progress = Progress::new(Mode::Quiet, "sync", Some(4));
+        progress.inc("a");
+        progress.add_bytes(2 * 1024 * 1024);
+
+        assert_eq!(
+            "sync [=======>                      ] 1/4 0.5/s 2.0 MiB 1.0 MiB/s",
+            progress.line(Duration::from_secs(2)),
+        );
+
+        progress.inc("b");
+        progress.inc("c");
+        progress.inc("d");
+        progress.set_total(4);
+        assert_eq!(
+            "sync [==============================] 4/4 2.0/s 2.0 MiB 1.0 MiB/s",
+            progress.line(Duration::from_secs(2)),
+        );
+
+        let mut unknown = Progress::new(Mode::Quiet, "dedup", None);
+        unknown.inc("a");
+        assert_eq!("dedup 1 1.0/s", unknown.line(Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,618 @@
+//! Serving another margo registry's crates through this one.
+//!
+//! When a tenant has an upstream, requests for index files and
+//! `.crate` files that the tenant's own registry does not have are
+//! answered from the upstream and kept in a cache directory. Crates
+//! published locally always win over the upstream's.
+//!
+//! Cached index files are refetched once they are older than
+//! `index-ttl-secs`, so new upstream versions show up; if the upstream
+//! cannot be reached, the stale copy is served instead. Cached crates
+//! never change. Every crate is checked against the checksum in the
+//! upstream's index before it is cached.
+//!
+//! On first use, with an empty cache, the whole upstream index is
+//! fetched at once as a [snapshot](crate::snapshot) and checked against
+//! its Merkle root, instead of one index file per request. After that,
+//! index files are refreshed one at a time as usual. Set `snapshot =
+//! false` to skip this, or when the upstream predates snapshots.
+//!
+//! When a refreshed index gives a different checksum for a version
+//! than the cached index did, the version is quarantined as a
+//! [conflict](crate::conflicts) until an operator resolves it.
+//!
+//! `include` and `exclude` [rules](crate::rules) limit which crates
+//! are proxied; excluded crates are answered with 404 without asking
+//! the upstream. A `pubkey:` rule is checked against the nostr key in
+//! the upstream's well-known document. Upstreams don't publish owners,
+//! so `owner:` rules never match here.
+//!
+//! An upstream whose crates are [encrypted](crate::encryption) is read
+//! with its key as `decryption-key-file`. Crates are then decrypted
+//! before they are cached, and index files are given to Cargo with the
+//! checksums of the decrypted crates.
+//!
+//! ```toml
+//! [tenant.upstream]
+//! url = "https://registry.partner.example/"
+//! cache-dir = "/var/cache/margo/acme"
+//! token = "..."  # sent as-is in `Authorization`, when the upstream needs one
+//! include = ["tokio*"]
+//! ```
+
+use semver::Version;
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Once, OnceLock},
+    time::{Duration, SystemTime},
+};
+use url::Url;
+
+use crate::{
+    client::Client,
+    common::CrateName,
+    discovery,
+    rules::{Rule, Rules, Subject},
+    snapshot, ConflictError, Registry,
+};
+
+#[cfg(feature = "encryption")]
+use crate::{encryption, index_entry};
+
+/// Larger than anything `cargo publish` accepts, to bound what a
+/// misbehaving upstream can make us store.
+const MAX_DOWNLOAD_BYTES: u64 = 32 * 1024 * 1024;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProxyConfig {
+    url: Url,
+
+    cache_dir: PathBuf,
+
+    #[serde(default)]
+    token: Option<String>,
+
+    #[serde(default = "ProxyConfig::default_index_ttl_secs")]
+    index_ttl_secs: u64,
+
+    #[serde(default = "ProxyConfig::default_snapshot")]
+    snapshot: bool,
+
+    #[serde(default)]
+    include: Vec<Rule>,
+
+    #[serde(default)]
+    exclude: Vec<Rule>,
+
+    #[cfg(feature = "encryption")]
+    #[serde(default)]
+    decryption_key_file: Option<PathBuf>,
+}
+
+impl ProxyConfig {
+    fn default_index_ttl_secs() -> u64 {
+        5 * 60
+    }
+
+    fn default_snapshot() -> bool {
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Proxy {
+    agent: ureq::Agent,
+    registry: Arc<Registry>,
+    url: Url,
+    token: Option<String>,
+    cache_dir: PathBuf,
+    index_ttl: Duration,
+    snapshot: bool,
+    rules: Rules,
+
+    /// Fills an empty index cache from a snapshot, once.
+    bootstrap: Once,
+
+    /// Built from the upstream's `config.json` on first use.
+    client: OnceLock<Client>,
+
+    /// The upstream operator's nostr public key, looked up on first
+    /// use when a rule needs it.
+    pubkey: OnceLock<Option<String>>,
+
+    #[cfg(feature = "encryption")]
+    decryption_key: Option<encryption::Key>,
+}
+
+/// A file fetched from the upstream or the cache.
+#[derive(Debug)]
+pub struct Fetched {
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Target {
+    Index(CrateName),
+    Crate(CrateName, Version),
+}
+
+impl Proxy {
+    /// Conflicts are recorded in `registry`.
+    pub fn new(config: ProxyConfig, registry: Arc<Registry>) -> Result<Self, Error> {
+        use error::*;
+
+        let cache_dir = config.cache_dir;
+        fs::create_dir_all(&cache_dir).context(CacheDirSnafu { path: &cache_dir })?;
+
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+
+        #[cfg(feature = "encryption")]
+        let decryption_key = config
+            .decryption_key_file
+            .as_deref()
+            .map(encryption::read_key)
+            .transpose()
+            .context(DecryptSnafu)?;
+
+        Ok(Self {
+            agent,
+            registry,
+            url: config.url,
+            token: config.token,
+            cache_dir,
+            index_ttl: Duration::from_secs(config.index_ttl_secs),
+            snapshot: config.snapshot,
+            rules: Rules {
+                include: config.include,
+                exclude: config.exclude,
+            },
+            bootstrap: Once::new(),
+            client: OnceLock::new(),
+            pubkey: OnceLock::new(),
+            #[cfg(feature = "encryption")]
+            decryption_key,
+        })
+    }
+
+    /// Answers a request for a path, relative to the registry root,
+    /// that the local registry could not. `None` means neither the
+    /// upstream nor the cache has it.
+    pub fn fetch(&self, path: &str) -> Result<Option<Fetched>, Error> {
+        let target = classify(path);
+
+        let name = match &target {
+            Some(Target::Index(name) | Target::Crate(name, _)) => name,
+            None => return Ok(None),
+        };
+        if !self.allows(name)? {
+            return Ok(None);
+        }
+
+        let fetched = match target {
+            Some(Target::Index(name)) => self.index(&name)?.map(|data| Fetched {
+                content_type: "text/plain; charset=utf-8",
+                data: self.for_cargo(data),
+            }),
+            Some(Target::Crate(name, version)) => {
+                self.crate_file(&name, &version)?.map(|data| Fetched {
+                    content_type: "application/gzip",
+                    data,
+                })
+            }
+            None => None,
+        };
+
+        Ok(fetched)
+    }
+
+    fn allows(&self, name: &CrateName) -> Result<bool, Error> {
+        let pubkey = if self.rules.needs_pubkey() {
+            self.pubkey()?
+        } else {
+            None
+        };
+
+        Ok(self.rules.allows(&Subject {
+            name: name.as_str(),
+            owners: &[],
+            pubkey,
+        }))
+    }
+
+    fn index(&self, name: &CrateName) -> Result<Option<Vec<u8>>, Error> {
+        use error::*;
+
+        self.bootstrap.call_once(|| {
+            if let Err(e) = self.bootstrap() {
+                eprintln!("Warning: {e}; fetching index files one at a time");
+            }
+        });
+
+        // Cargo asks for index files by lowercased path
+        let mut path = self.cache_dir.join("index");
+        path.extend(
+            name.prefix_directories()
+                .iter()
+                .map(|d| d.to_ascii_lowercase()),
+        );
+        path.push(name.as_str().to_ascii_lowercase());
+
+        let cached = read_if_exists(&path)?;
+        let fresh = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age < self.index_ttl);
+
+        if let (Some(cached), true) = (&cached, fresh) {
+            return Ok(Some(cached.clone()));
+        }
+
+        let fetched = self
+            .client()
+            .and_then(|client| client.index_url(name).context(ClientSnafu))
+            .and_then(|url| self.get(&url));
+
+        match (fetched, cached) {
+            (Ok(Some(data)), cached) => {
+                if let Some(cached) = cached {
+                    self.check_for_conflicts(&cached, &data)?;
+                }
+                write_atomically(&path, &data)?;
+                Ok(Some(data))
+            }
+            (Ok(None), _) => Ok(None),
+            (Err(e), Some(cached)) => {
+                eprintln!("Warning: {e}; serving the cached index of `{name}`");
+                Ok(Some(cached))
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    /// A version whose checksum changed upstream since its index was
+    /// cached conflicts with what was cached.
+    fn check_for_conflicts(&self, cached: &[u8], fetched: &[u8]) -> Result<(), Error> {
+        use error::*;
+
+        let client = self.client()?;
+        let cached = client.parse_index(cached).context(ClientSnafu)?;
+        let fetched = client.parse_index(fetched).context(ClientSnafu)?;
+
+        for (vers, theirs) in &fetched {
+            let Some(ours) = cached.get(vers) else {
+                continue;
+            };
+            if ours.cksum.eq_ignore_ascii_case(&theirs.cksum) {
+                continue;
+            }
+
+            let checksums = [
+                ("proxy cache".to_owned(), ours.cksum.clone()),
+                (self.url.to_string(), theirs.cksum.clone()),
+            ];
+            self.registry
+                .record_conflict(theirs.name.clone(), vers.clone(), checksums.into())
+                .context(ConflictSnafu)?;
+        }
+
+        Ok(())
+    }
+
+    /// Does nothing unless the index cache is empty. Excluded crates
+    /// are cached too, but [`Proxy::fetch`] never serves them.
+    fn bootstrap(&self) -> Result<(), Error> {
+        use error::*;
+
+        let dir = self.cache_dir.join("index");
+        if !self.snapshot || dir.exists() {
+            return Ok(());
+        }
+
+        let url = self
+            .url
+            .join("api/v1/index-snapshot")
+            .context(SnapshotUrlSnafu)?;
+        let Some(data) = self.get(&url)? else {
+            return Ok(());
+        };
+        let (manifest, files) = snapshot::unpack(&data).context(SnapshotSnafu { url })?;
+
+        for (path, data) in &files {
+            let path = path.to_ascii_lowercase();
+            let path = path.split('/').fold(dir.clone(), |path, d| path.join(d));
+            write_atomically(&path, data)?;
+        }
+
+        println!(
+            "Bootstrapped {} index files from {} (root {})",
+            manifest.files, self.url, manifest.root,
+        );
+
+        Ok(())
+    }
+
+    fn crate_file(&self, name: &CrateName, version: &Version) -> Result<Option<Vec<u8>>, Error> {
+        use error::*;
+
+        let path = self
+            .cache_dir
+            .join("crates")
+            .join(name.as_str().to_ascii_lowercase())
+            .join(format!("{version}.crate"));
+
+        if let Some(cached) = read_if_exists(&path)? {
+            return Ok(Some(cached));
+        }
+
+        let Some(index) = self.index(name)? else {
+            return Ok(None);
+        };
+        let client = self.client()?;
+        let index = client.parse_index(&index).context(ClientSnafu)?;
+        let Some(entry) = index.get(version) else {
+            return Ok(None);
+        };
+
+        let url = client.crate_url(entry).context(ClientSnafu)?;
+        let Some(data) = self.get(&url)? else {
+            return Ok(None);
+        };
+        Client::verify(entry, &data).context(ClientSnafu)?;
+
+        #[cfg(feature = "encryption")]
+        let data = self.decrypt(entry, data)?;
+
+        write_atomically(&path, &data)?;
+
+        Ok(Some(data))
+    }
+
+    /// Decrypts an encrypted crate, when the proxy has the key.
+    #[cfg(feature = "encryption")]
+    fn decrypt(&self, entry: &index_entry::Root, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        use error::*;
+
+        match (&self.decryption_key, &entry.decrypted_cksum) {
+            (Some(key), Some(cksum)) => {
+                encryption::decrypt(key, &entry.name, &entry.vers, &data, cksum)
+                    .context(DecryptSnafu)
+            }
+            _ => Ok(data),
+        }
+    }
+
+    /// An index file as Cargo is given it: with the checksums of the
+    /// decrypted crates, when the proxy decrypts them.
+    fn for_cargo(&self, index: Vec<u8>) -> Vec<u8> {
+        #[cfg(feature = "encryption")]
+        if self.decryption_key.is_some() {
+            return encryption::decrypted_index(&index);
+        }
+
+        index
+    }
+
+    fn client(&self) -> Result<&Client, Error> {
+        use error::*;
+
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+
+        let url = Client::config_url(&self.url).context(ClientSnafu)?;
+        let config_json = self.get(&url)?.context(NoConfigSnafu { url })?;
+        let client = Client::new(self.url.clone(), &config_json).context(ClientSnafu)?;
+
+        // Another request may have won the race; either copy will do
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    fn pubkey(&self) -> Result<Option<&str>, Error> {
+        use error::*;
+
+        if let Some(pubkey) = self.pubkey.get() {
+            return Ok(pubkey.as_deref());
+        }
+
+        let url = self
+            .url
+            .join(discovery::WELL_KNOWN_PATH)
+            .context(WellKnownUrlSnafu)?;
+        let document = self
+            .get(&url)?
+            .map(|data| serde_json::from_slice::<discovery::Document>(&data))
+            .transpose()
+            .context(WellKnownSnafu { url })?;
+
+        let index = format!("sparse+{}", self.url);
+        let pubkey = document
+            .and_then(|d| d.registries.into_values().find(|e| e.index == index))
+            .and_then(|e| e.pubkey);
+
+        Ok(self.pubkey.get_or_init(|| pubkey).as_deref())
+    }
+
+    /// `None` when the upstream answers 404.
+    fn get(&self, url: &Url) -> Result<Option<Vec<u8>>, Error> {
+        use error::*;
+
+        let mut request = self.agent.request_url("GET", url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", token);
+        }
+
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(e).context(RequestSnafu { url: url.clone() }),
+        };
+
+        let mut data = vec![];
+        response
+            .into_reader()
+            .take(MAX_DOWNLOAD_BYTES + 1)
+            .read_to_end(&mut data)
+            .context(ReadBodySnafu { url: url.clone() })?;
+        ensure!(
+            data.len() as u64 <= MAX_DOWNLOAD_BYTES,
+            TooLargeSnafu { url: url.clone() }
+        );
+
+        Ok(Some(data))
+    }
+}
+
+/// Recognizes index files (`se/rd/serde`) and crate files
+/// (`crates/se/rd/serde/1.0.0.crate`).
+fn classify(path: &str) -> Option<Target> {
+    let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+
+    match segments.as_slice() {
+        ["crates", .., name, file] => {
+            let version = file.strip_suffix(".crate")?.parse().ok()?;
+            Some(Target::Crate(name.parse().ok()?, version))
+        }
+        [dirs @ .., name] => {
+            let name: CrateName = name.parse().ok()?;
+            let expected = name.prefix_directories();
+            let matches = dirs.len() == expected.len()
+                && dirs
+                    .iter()
+                    .zip(&expected)
+                    .all(|(a, b)| a.eq_ignore_ascii_case(b));
+            matches.then_some(Target::Index(name))
+        }
+        [] => None,
+    }
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+    use error::*;
+
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context(ReadCacheSnafu { path }),
+    }
+}
+
+/// Concurrent requests for the same file each write their own
+/// temporary file, so a reader never sees a partial one.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Error> {
+    use error::*;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(WriteCacheSnafu { path: dir })?;
+    }
+
+    let tmp = path.with_extension(format!(
+        "tmp-{}-{:?}",
+        std::process::id(),
+        std::thread::current().id()
+    ));
+    fs::write(&tmp, data).context(WriteCacheSnafu { path: &tmp })?;
+    fs::rename(&tmp, path).context(WriteCacheSnafu { path })
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not create the proxy cache directory {}", path.display()))]
+    CacheDir { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not fetch {url} from the upstream registry"))]
+    Request {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: Url,
+    },
+
+    #[snafu(display("Could not read {url} from the upstream registry"))]
+    ReadBody { source: io::Error, url: Url },
+
+    #[snafu(display("{url} is larger than {MAX_DOWNLOAD_BYTES} bytes"))]
+    TooLarge { url: Url },
+
+    #[snafu(display("The upstream registry has no {url}"))]
+    NoConfig { url: Url },
+
+    #[snafu(display("Could not build the upstream's well-known URL"))]
+    WellKnownUrl { source: url::ParseError },
+
+    #[snafu(display("Could not deserialize the upstream's discovery document at {url}"))]
+    WellKnown { source: serde_json::Error, url: Url },
+
+    #[snafu(display("Could not build the upstream's index snapshot URL"))]
+    SnapshotUrl { source: url::ParseError },
+
+    #[snafu(display("The index snapshot at {url} was rejected"))]
+    Snapshot { source: snapshot::Error, url: Url },
+
+    #[snafu(display("The upstream registry could not be read"))]
+    Client { source: crate::client::Error },
+
+    #[snafu(display("Could not record a checksum conflict"))]
+    Conflict { source: ConflictError },
+
+    #[snafu(display("Could not read the cached file {}", path.display()))]
+    ReadCache { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not write the cached file {}", path.display()))]
+    WriteCache { source: io::Error, path: PathBuf },
+
+    #[cfg(feature = "encryption")]
+    #[snafu(display("Could not decrypt the upstream's crates"))]
+    Decrypt { source: encryption::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Request { .. }
+            | Self::ReadBody { .. }
+            | Self::TooLarge { .. }
+            | Self::NoConfig { .. }
+            | Self::WellKnownUrl { .. }
+            | Self::WellKnown { .. }
+            | Self::SnapshotUrl { .. } => "E_UPSTREAM",
+            Self::Snapshot { source, .. } => source.code(),
+            Self::Conflict { source } => source.code(),
+            Self::Client { source } => source.code(),
+            Self::ReadCache { .. } => "E_STORAGE_READ",
+            Self::CacheDir { .. } | Self::WriteCache { .. } => "E_STORAGE_WRITE",
+            #[cfg(feature = "encryption")]
+            Self::Decrypt { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths_are_classified() {
+        let serde: CrateName = "serde".parse().unwrap();
+
+        assert_eq!(Some(Target::Index(serde.clone())), classify("/se/rd/serde"));
+        assert_eq!(Some(Target::Index(serde.clone())), classify("SE/RD/serde"));
+        assert_eq!(
+            Some(Target::Crate(serde, "1.0.0".parse().unwrap())),
+            classify("/crates/se/rd/serde/1.0.0.crate"),
+        );
+
+        assert_eq!(None, classify("/xx/rd/serde"));
+        assert_eq!(None, classify("/config.json"));
+        assert_eq!(None, classify("/crates/se/rd/serde/1.0.0.tar"));
+        assert_eq!(None, classify("/se/rd/../serde"));
+    }
+}
@@ -0,0 +1,367 @@
+//! Crates that have been published but not yet added to the registry.
+//!
+//! The daemon stores a crate in `publish-queue/` in a tenant's
+//! publishing `data-dir` instead of adding it when the registry is under
+//! maintenance, when the tenant sets `require-approval`, or when a scan
+//! of the crate found something. Each entry is `{id}.crate` and
+//! `{id}.json`, and IDs only ever grow, so the queue is added in the
+//! order the crates were published. An entry that needs
+//! approval waits, without holding back the others, until a reviewer
+//! other than its publisher approves it. One that can no longer be
+//! added, because its version now exists, its publisher no longer owns
+//! the crate, or a reviewer rejected it, is moved to
+//! `publish-queue/failed/`.
+//!
+//! Like the owner records, the queue lives outside the registry so that
+//! unreleased crates are never served.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+};
+
+use crate::{auth::UserId, common::CrateName, timestamp::Timestamp};
+
+const DIR_NAME: &str = "publish-queue";
+
+const FAILED_DIR_NAME: &str = "failed";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Queued {
+    pub name: CrateName,
+    pub vers: Version,
+    pub user: UserId,
+    pub queued_at: Timestamp,
+
+    #[serde(default)]
+    pub needs_approval: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approval: Option<Approval>,
+
+    /// What the scanners found, when that is why the crate is held.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub findings: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Approval {
+    pub reviewer: String,
+    pub approved_at: Timestamp,
+}
+
+impl Queued {
+    /// Whether the crate may be added once the registry is not under
+    /// maintenance.
+    pub fn is_ready(&self) -> bool {
+        !self.needs_approval || self.approval.is_some()
+    }
+}
+
+/// Adds the crate to the end of the queue and returns its ID. Crates
+/// of different names may be queued at once, so the ID is claimed by
+/// creating its `.crate` file, taking the next one when another publish
+/// claimed it first.
+pub fn enqueue(data_dir: &Path, queued: &Queued, crate_file: &[u8]) -> Result<u64, Error> {
+    use error::*;
+
+    let dir = data_dir.join(DIR_NAME);
+    fs::create_dir_all(&dir).context(WriteSnafu { path: &dir })?;
+
+    // Crates set aside keep their IDs, so those are not reused either
+    let failed = ids(&dir.join(FAILED_DIR_NAME))?;
+    let mut id = ids(&dir)?.into_iter().chain(failed).max().unwrap_or(0) + 1;
+
+    // The crate first, since an entry is only listed once its
+    // description exists
+    let mut file = loop {
+        let path = crate_path(data_dir, id);
+        match fs::File::options().write(true).create_new(true).open(&path) {
+            Ok(file) => break file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => id += 1,
+            Err(e) => return Err(e).context(WriteSnafu { path }),
+        }
+    };
+    let path = crate_path(data_dir, id);
+    file.write_all(crate_file).context(WriteSnafu { path })?;
+    write_entry(data_dir, id, queued)?;
+
+    Ok(id)
+}
+
+/// Every queued crate with its ID, oldest first.
+pub fn list(data_dir: &Path) -> Result<Vec<(u64, Queued)>, Error> {
+    use error::*;
+
+    let mut queued = vec![];
+    for id in ids(&data_dir.join(DIR_NAME))? {
+        let path = entry_path(data_dir, id);
+        let data = fs::read(&path).context(ReadSnafu { path: &path })?;
+        let entry = serde_json::from_slice(&data).context(ParseSnafu { path })?;
+        queued.push((id, entry));
+    }
+
+    Ok(queued)
+}
+
+/// The queued crate with this name and version.
+pub fn find(data_dir: &Path, name: &CrateName, vers: &Version) -> Result<(u64, Queued), Error> {
+    use error::*;
+
+    list(data_dir)?
+        .into_iter()
+        .find(|(_, q)| q.name == *name && q.vers == *vers)
+        .context(UnknownSnafu {
+            name: name.clone(),
+            vers: vers.clone(),
+        })
+}
+
+/// Marks a crate that is waiting for approval as approved. The daemon
+/// adds it the next time it looks at the queue.
+pub fn approve(
+    data_dir: &Path,
+    name: &CrateName,
+    vers: &Version,
+    reviewer: &str,
+) -> Result<(u64, Queued), Error> {
+    use error::*;
+
+    let (id, mut queued) = find(data_dir, name, vers)?;
+    ensure!(
+        queued.needs_approval && queued.approval.is_none(),
+        NotPendingSnafu {
+            name: name.clone(),
+            vers: vers.clone(),
+        }
+    );
+    ensure!(
+        queued.user.to_string() != reviewer,
+        SelfApprovalSnafu { reviewer }
+    );
+
+    queued.approval = Some(Approval {
+        reviewer: reviewer.to_owned(),
+        approved_at: Timestamp::now(),
+    });
+    write_entry(data_dir, id, &queued)?;
+
+    Ok((id, queued))
+}
+
+/// Sets aside a crate that is waiting for approval, so it is never
+/// added.
+pub fn reject(data_dir: &Path, name: &CrateName, vers: &Version) -> Result<Queued, Error> {
+    use error::*;
+
+    let (id, queued) = find(data_dir, name, vers)?;
+    ensure!(
+        queued.needs_approval && queued.approval.is_none(),
+        NotPendingSnafu {
+            name: name.clone(),
+            vers: vers.clone(),
+        }
+    );
+    set_aside(data_dir, id)?;
+
+    Ok(queued)
+}
+
+/// The IDs of the entries in `dir`, in order.
+fn ids(dir: &Path) -> Result<Vec<u64>, Error> {
+    use error::*;
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(ReadSnafu { path: dir }),
+    };
+
+    let mut ids = vec![];
+    for entry in entries {
+        let entry = entry.context(ReadSnafu { path: dir })?;
+        let name = entry.file_name();
+        let id = name
+            .to_str()
+            .and_then(|n| n.strip_suffix(".json"))
+            .and_then(|id| id.parse().ok());
+        ids.extend(id);
+    }
+    ids.sort();
+
+    Ok(ids)
+}
+
+pub fn crate_file(data_dir: &Path, id: u64) -> Result<Vec<u8>, Error> {
+    use error::*;
+
+    let path = crate_path(data_dir, id);
+    fs::read(&path).context(ReadSnafu { path })
+}
+
+/// Removes a crate that has been added to the registry.
+pub fn dequeue(data_dir: &Path, id: u64) -> Result<(), Error> {
+    use error::*;
+
+    for path in [entry_path(data_dir, id), crate_path(data_dir, id)] {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(WriteSnafu { path }),
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves a crate that could not be added out of the queue, keeping it
+/// for an administrator to look at.
+pub fn set_aside(data_dir: &Path, id: u64) -> Result<(), Error> {
+    use error::*;
+
+    let failed = data_dir.join(DIR_NAME).join(FAILED_DIR_NAME);
+    fs::create_dir_all(&failed).context(WriteSnafu { path: &failed })?;
+
+    for path in [crate_path(data_dir, id), entry_path(data_dir, id)] {
+        let target = failed.join(path.file_name().unwrap_or_default());
+        fs::rename(&path, &target).context(WriteSnafu { path })?;
+    }
+
+    Ok(())
+}
+
+fn write_entry(data_dir: &Path, id: u64, queued: &Queued) -> Result<(), Error> {
+    use error::*;
+
+    let data = serde_json::to_vec_pretty(queued).context(SerializeSnafu)?;
+
+    let path = entry_path(data_dir, id);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, &path).context(WriteSnafu { path })
+}
+
+fn entry_path(data_dir: &Path, id: u64) -> PathBuf {
+    data_dir.join(DIR_NAME).join(format!("{id:08}.json"))
+}
+
+fn crate_path(data_dir: &Path, id: u64) -> PathBuf {
+    data_dir.join(DIR_NAME).join(format!("{id:08}.crate"))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("{name} {vers} is not in the publish queue"))]
+    Unknown { name: CrateName, vers: Version },
+
+    #[snafu(display("{name} {vers} is not waiting for approval"))]
+    NotPending { name: CrateName, vers: Version },
+
+    #[snafu(display("{reviewer} published the crate, so cannot approve it"))]
+    SelfApproval { reviewer: String },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the queued crate"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Unknown { .. } => "E_NOT_QUEUED",
+            Self::NotPending { .. } => "E_NOT_PENDING",
+            Self::SelfApproval { .. } => "E_SELF_APPROVAL",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_PUBLISH_QUEUE_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn queued(vers: &str, needs_approval: bool) -> Queued {
+        Queued {
+            name: "demo".parse().unwrap(),
+            vers: vers.parse().unwrap(),
+            user: UserId::static_token("aa"),
+            queued_at: Timestamp::now(),
+            needs_approval,
+            approval: None,
+            findings: vec![],
+        }
+    }
+
+    #[test]
+    fn publishes_queue_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        assert_eq!(1, enqueue(dir, &queued("1.0.0", false), b"one").unwrap());
+        assert_eq!(2, enqueue(dir, &queued("1.1.0", false), b"two").unwrap());
+
+        let ids = list(dir).unwrap();
+        assert_eq!(
+            vec![1, 2],
+            ids.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert_eq!(b"two".to_vec(), crate_file(dir, 2).unwrap());
+
+        dequeue(dir, 1).unwrap();
+        set_aside(dir, 2).unwrap();
+        assert!(list(dir).unwrap().is_empty());
+        assert_eq!(3, enqueue(dir, &queued("1.2.0", false), b"three").unwrap());
+
+        // Claimed by a publish that has not described its entry yet
+        fs::write(crate_path(dir, 4), b"four").unwrap();
+        assert_eq!(5, enqueue(dir, &queued("1.4.0", false), b"five").unwrap());
+    }
+
+    #[test]
+    fn another_reviewer_approves() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let name = "demo".parse().unwrap();
+        let vers = "1.0.0".parse().unwrap();
+        enqueue(dir, &queued("1.0.0", true), b"one").unwrap();
+        enqueue(dir, &queued("2.0.0", false), b"two").unwrap();
+
+        let ready = |dir| -> Vec<u64> {
+            let queue = list(dir).unwrap();
+            queue
+                .iter()
+                .filter(|(_, q)| q.is_ready())
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        assert_eq!(vec![2], ready(dir));
+
+        let publisher = UserId::static_token("aa").to_string();
+        assert!(approve(dir, &name, &vers, &publisher).is_err());
+        approve(dir, &name, &vers, "oidc:reviewer").unwrap();
+        assert!(approve(dir, &name, &vers, "oidc:reviewer").is_err());
+        assert_eq!(vec![1, 2], ready(dir));
+
+        let other = "2.0.0".parse().unwrap();
+        assert!(reject(dir, &name, &other).is_err());
+    }
+}
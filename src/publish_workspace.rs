@@ -0,0 +1,430 @@
+//! Publishing every crate of a workspace, dependencies first.
+//!
+//! `cargo gnostr-registry publish-workspace` asks `cargo metadata` for
+//! the workspace's members and keeps those that may be published to the
+//! registry: `publish` unset, or listing the registry by name. Members
+//! named by `--include` globs, when there are any, and not by
+//! `--exclude` globs are published, each after the members it depends
+//! on, ignoring dev-dependencies, which `cargo publish` drops when they
+//! have no version.
+//!
+//! Before each member is published, the registry's index is checked
+//! for its version, so a run that stopped part way can be run again.
+//! After it is published, the next member waits until the index serves
+//! it, so that Cargo can resolve the dependency. The index is fetched
+//! with the token in `CARGO_REGISTRIES_{NAME}_TOKEN` when it is set.
+
+use semver::Version;
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    process::{Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+use crate::{
+    cargo_context,
+    common::{glob_matches, CrateName},
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const USER_AGENT: &str = concat!("margo/", env!("CARGO_PKG_VERSION"));
+
+/// How often the index is fetched while waiting for a version.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub version: Version,
+
+    /// The names of the other members it depends on.
+    depends_on: BTreeSet<String>,
+}
+
+/// The workspace to publish from and the registry to publish to, as
+/// Cargo would find them in the current directory.
+#[derive(Debug)]
+pub struct Workspace {
+    pub root: PathBuf,
+
+    /// What Cargo calls the registry.
+    pub registry: String,
+
+    pub base_url: Url,
+    cargo: PathBuf,
+}
+
+impl Workspace {
+    pub fn detect(registry: Option<&str>) -> Result<Self, Error> {
+        use error::*;
+
+        let context = cargo_context::detect()?;
+        let root = context.workspace_root.clone().context(NoWorkspaceSnafu)?;
+        let (name, config) = context.registry(registry)?;
+        let base_url = config.base_url().context(NotSparseSnafu { name })?;
+        let cargo = cargo_context::cargo().context(NoCargoSnafu)?;
+
+        Ok(Self {
+            root,
+            registry: name.to_owned(),
+            base_url,
+            cargo,
+        })
+    }
+
+    /// The members that may be published to the registry.
+    pub fn members(&self) -> Result<Vec<Member>, Error> {
+        use error::*;
+
+        #[derive(Deserialize)]
+        struct Metadata {
+            packages: Vec<Package>,
+        }
+
+        #[derive(Deserialize)]
+        struct Package {
+            name: String,
+            version: Version,
+            publish: Option<Vec<String>>,
+            dependencies: Vec<Dependency>,
+        }
+
+        #[derive(Deserialize)]
+        struct Dependency {
+            name: String,
+            kind: Option<String>,
+            path: Option<PathBuf>,
+        }
+
+        let output = Command::new(&self.cargo)
+            .args(["metadata", "--format-version", "1", "--no-deps"])
+            .current_dir(&self.root)
+            .output()
+            .context(SpawnSnafu {
+                command: "metadata",
+            })?;
+        ensure!(
+            output.status.success(),
+            CargoSnafu {
+                command: "metadata",
+                status: output.status,
+            }
+        );
+
+        let metadata: Metadata = serde_json::from_slice(&output.stdout).context(MetadataSnafu)?;
+
+        let names = metadata
+            .packages
+            .iter()
+            .map(|p| p.name.clone())
+            .collect::<BTreeSet<_>>();
+
+        let members = metadata
+            .packages
+            .into_iter()
+            .filter(|p| {
+                let registries = p.publish.as_ref();
+                registries.map_or(true, |r| r.contains(&self.registry))
+            })
+            .map(|p| {
+                let depends_on = p
+                    .dependencies
+                    .into_iter()
+                    .filter(|d| d.path.is_some() && d.kind.as_deref() != Some("dev"))
+                    .map(|d| d.name)
+                    .filter(|name| names.contains(name) && *name != p.name)
+                    .collect();
+
+                Member {
+                    name: p.name,
+                    version: p.version,
+                    depends_on,
+                }
+            })
+            .collect();
+
+        Ok(members)
+    }
+
+    pub fn publish(&self, member: &Member) -> Result<(), Error> {
+        use error::*;
+
+        let status = Command::new(&self.cargo)
+            .args([
+                "publish",
+                "--package",
+                &member.name,
+                "--registry",
+                &self.registry,
+            ])
+            .current_dir(&self.root)
+            .status()
+            .context(SpawnSnafu { command: "publish" })?;
+        ensure!(
+            status.success(),
+            CargoSnafu {
+                command: "publish",
+                status,
+            }
+        );
+
+        Ok(())
+    }
+}
+
+/// The members named by `include`, or all of them when it is empty,
+/// and not by `exclude`.
+pub fn select(members: Vec<Member>, include: &[String], exclude: &[String]) -> Vec<Member> {
+    let named = |globs: &[String], name: &str| globs.iter().any(|g| glob_matches(g, name));
+
+    members
+        .into_iter()
+        .filter(|m| include.is_empty() || named(include, &m.name))
+        .filter(|m| !named(exclude, &m.name))
+        .collect()
+}
+
+/// The members in an order to publish them: each after the members it
+/// depends on, and otherwise by name. Members that were not selected
+/// are taken as published already.
+pub fn order(members: Vec<Member>) -> Result<Vec<Member>, Error> {
+    let mut waiting = members
+        .into_iter()
+        .map(|m| (m.name.clone(), m))
+        .collect::<BTreeMap<_, _>>();
+    let selected = waiting.keys().cloned().collect::<BTreeSet<_>>();
+
+    let mut ordered = Vec::<Member>::new();
+    while !waiting.is_empty() {
+        let ready = waiting
+            .values()
+            .filter(|m| {
+                m.depends_on
+                    .iter()
+                    .all(|d| !selected.contains(d) || ordered.iter().any(|o| o.name == *d))
+            })
+            .map(|m| m.name.clone())
+            .collect::<Vec<_>>();
+
+        if ready.is_empty() {
+            let names = waiting.into_keys().collect::<Vec<_>>().join(", ");
+            return error::CycleSnafu { names }.fail();
+        }
+
+        for name in ready {
+            ordered.extend(waiting.remove(&name));
+        }
+    }
+
+    Ok(ordered)
+}
+
+/// The index of the registry being published to, over HTTP.
+pub struct Index {
+    agent: ureq::Agent,
+    base_url: Url,
+    token: Option<String>,
+}
+
+impl Index {
+    pub fn new(base_url: Url, token: Option<String>) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(USER_AGENT)
+            .build();
+
+        Self {
+            agent,
+            base_url,
+            token,
+        }
+    }
+
+    /// Whether the index serves the version.
+    pub fn has(&self, name: &str, version: &Version) -> Result<bool, Error> {
+        use error::*;
+
+        #[derive(Deserialize)]
+        struct Entry {
+            vers: Version,
+        }
+
+        let crate_name = name.parse::<CrateName>().context(NameSnafu { name })?;
+        let mut path = crate_name.prefix_directories().join("/");
+        path.push('/');
+        path.push_str(crate_name.as_str());
+        let url = self
+            .base_url
+            .join(&path.to_ascii_lowercase())
+            .context(UrlSnafu)?;
+
+        let mut request = self.agent.request_url("GET", &url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", token);
+        }
+
+        let body = match request.call() {
+            Ok(response) => response.into_string().context(ReadSnafu { url })?,
+            Err(ureq::Error::Status(404, _)) => return Ok(false),
+            Err(e) => return Err(e).context(FetchSnafu { url }),
+        };
+
+        let found = body
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+            .any(|entry| entry.vers == *version);
+        Ok(found)
+    }
+
+    /// Waits for the index to serve the version.
+    pub fn wait_for(&self, name: &str, version: &Version, timeout: Duration) -> Result<(), Error> {
+        let start = Instant::now();
+
+        while !self.has(name, version)? {
+            ensure!(
+                start.elapsed() < timeout,
+                error::TimeoutSnafu {
+                    name,
+                    version: version.clone(),
+                    timeout,
+                }
+            );
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+}
+
+/// The token Cargo would send to the registry, from the environment.
+pub fn token_for(registry: &str) -> Option<String> {
+    std::env::var(cargo_context::token_var(registry)).ok()
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(transparent)]
+    Context {
+        #[snafu(source(from(cargo_context::Error, Box::new)))]
+        source: Box<cargo_context::Error>,
+    },
+
+    #[snafu(display("The current directory is not in a Cargo workspace"))]
+    NoWorkspace,
+
+    #[snafu(display("The registry `{name}` is not a sparse registry, which margo serves"))]
+    NotSparse { name: String },
+
+    #[snafu(display("Could not find `cargo` on the PATH"))]
+    NoCargo,
+
+    #[snafu(display("Could not run `cargo {command}`"))]
+    Spawn {
+        source: std::io::Error,
+        command: &'static str,
+    },
+
+    #[snafu(display("`cargo {command}` failed ({status})"))]
+    Cargo {
+        command: &'static str,
+        status: ExitStatus,
+    },
+
+    #[snafu(display("Could not parse the output of `cargo metadata`"))]
+    Metadata { source: serde_json::Error },
+
+    #[snafu(display("The members {names} depend on each other"))]
+    Cycle { names: String },
+
+    #[snafu(display("`{name}` is not a crate name"))]
+    Name {
+        source: crate::common::CrateNameError,
+        name: String,
+    },
+
+    #[snafu(display("Could not build the index URL"))]
+    Url { source: url::ParseError },
+
+    #[snafu(display("Could not fetch {url}"))]
+    Fetch {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: Url,
+    },
+
+    #[snafu(display("Could not read the response from {url}"))]
+    Read { source: std::io::Error, url: Url },
+
+    #[snafu(display("The index did not serve {name} {version} within {timeout:?}"))]
+    Timeout {
+        name: String,
+        version: Version,
+        timeout: Duration,
+    },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Context { source } => source.code(),
+            Self::NoWorkspace => "E_MANIFEST_MISSING",
+            Self::NotSparse { .. } => "E_UNSUPPORTED",
+            Self::NoCargo | Self::Spawn { .. } => "E_CARGO_UNAVAILABLE",
+            Self::Cargo { .. } => "E_PUBLISH_FAILED",
+            Self::Metadata { .. } => "E_MANIFEST_INVALID",
+            Self::Cycle { .. } => "E_DEPENDENCY_CYCLE",
+            Self::Name { .. } => "E_BAD_CRATE_NAME",
+            Self::Url { .. } => "E_BAD_URL",
+            Self::Fetch { .. } | Self::Read { .. } => "E_UPSTREAM",
+            Self::Timeout { .. } => "E_PUBLISH_TIMEOUT",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn member(name: &str, depends_on: &[&str]) -> Member {
+        Member {
+            name: name.into(),
+            version: Version::new(1, 0, 0),
+            depends_on: depends_on.iter().map(|&d| d.into()).collect(),
+        }
+    }
+
+    fn names(members: &[Member]) -> Vec<&str> {
+        members.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    #[test]
+    fn dependencies_are_published_first() {
+        let members = vec![
+            member("app", &["core", "macros"]),
+            member("core", &["macros"]),
+            member("macros", &[]),
+            member("tools-cli", &["app"]),
+            member("tools-lib", &[]),
+        ];
+
+        let ordered = order(members.clone()).unwrap();
+        assert_eq!(
+            vec!["macros", "tools-lib", "core", "app", "tools-cli"],
+            names(&ordered),
+        );
+
+        // Members left out are taken as published
+        let selected = select(members, &["*".into()], &["macros".into(), "tools-*".into()]);
+        assert_eq!(vec!["core", "app"], names(&order(selected).unwrap()));
+
+        let cycle = order(vec![member("a", &["b"]), member("b", &["a"])]);
+        assert!(matches!(cycle, Err(Error::Cycle { .. })));
+    }
+}
@@ -0,0 +1,195 @@
+//! Python scripts that manage the registry in-process.
+//!
+//! `margo python release.py -- ARGS` runs the script in an embedded
+//! interpreter, where it can `import gnostr_registry`:
+//!
+//! ```python
+//! import gnostr_registry
+//!
+//! registry = gnostr_registry.Registry("/srv/registry")
+//! name, version = registry.add("target/package/demo-1.0.0.crate")
+//! for hit in registry.search("demo"):
+//!     print(hit["name"], hit["newest_version"])
+//! assert not registry.verify()
+//! ```
+//!
+//! `Registry.list()` gives a dict for each version, and `verify()` a
+//! description of each problem found. Failures raise
+//! `gnostr_registry.RegistryError`, whose `args` are the error code and
+//! the message.
+
+use pyo3::{create_exception, exceptions::PyException, prelude::*, types::PyDict};
+use snafu::prelude::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{catalog::Catalog, discovery, search, Global, Registry};
+
+create_exception!(gnostr_registry, RegistryError, PyException);
+
+fn refused(code: &str, error: &dyn std::error::Error) -> PyErr {
+    RegistryError::new_err((code.to_owned(), error.to_string()))
+}
+
+fn refused_by(error: impl Into<crate::Error>) -> PyErr {
+    let error = error.into();
+    refused(error.code(), &error)
+}
+
+#[pyclass(name = "Registry", unsendable)]
+struct PyRegistry {
+    global: Global,
+    registry: Registry,
+}
+
+#[pymethods]
+impl PyRegistry {
+    #[new]
+    fn new(path: PathBuf) -> PyResult<Self> {
+        let global = Global::new().map_err(refused_by)?;
+        let registry = crate::discover_registry(Some(path)).map_err(refused_by)?;
+
+        Ok(Self { global, registry })
+    }
+
+    /// Adds the `.crate` file, as `margo add` does, and gives its name
+    /// and version.
+    #[pyo3(signature = (path, allow_name = false))]
+    fn add(&self, path: PathBuf, allow_name: bool) -> PyResult<(String, String)> {
+        let r = crate::discover_writable_registry(&self.global, Some(self.registry.path.clone()))
+            .map_err(refused_by)?;
+
+        if !allow_name {
+            let crate_file = fs::read(&path)
+                .context(crate::add_error::ReadCrateSnafu)
+                .map_err(refused_by)?;
+            r.check_name(&crate_file).map_err(refused_by)?;
+        }
+        let (name, version) = r.add(&self.global, &path).map_err(refused_by)?;
+        r.maybe_build_docs(&name, &version);
+        r.maybe_generate_html().map_err(refused_by)?;
+        r.maybe_generate_feed().map_err(refused_by)?;
+
+        Ok((name.to_string(), version.to_string()))
+    }
+
+    fn list<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let crates = self
+            .registry
+            .list_all()
+            .map_err(|e| refused(e.code(), &e))?;
+
+        let mut versions = vec![];
+        for (name, index) in crates {
+            for (vers, entry) in index {
+                let version = PyDict::new_bound(py);
+                version.set_item("name", name.to_string())?;
+                version.set_item("version", vers.to_string())?;
+                version.set_item("cksum", &entry.cksum)?;
+                version.set_item("yanked", entry.yanked)?;
+                versions.push(version);
+            }
+        }
+
+        Ok(versions)
+    }
+
+    fn verify(&self) -> PyResult<Vec<String>> {
+        let problems = self
+            .registry
+            .verify(self.global.progress)
+            .map_err(refused_by)?;
+
+        Ok(problems.iter().map(ToString::to_string).collect())
+    }
+
+    /// Crate names matching `q`, ranked as `/api/v1/search` ranks them.
+    fn search<'py>(&self, py: Python<'py>, q: &str) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let catalog = Catalog::default();
+        catalog
+            .load(&self.registry)
+            .map_err(|e| refused(e.code(), &e))?;
+        let crates = catalog
+            .get(&self.registry)
+            .map_err(|e| refused(e.code(), &e))?
+            .unwrap_or_default();
+
+        let hits = search::local(&self.registry, &crates, discovery::ROOT_NAME, q, |_| true);
+        hits.into_iter()
+            .map(|hit| {
+                let newest_version = hit.newest_version.map(|v| v.to_string());
+
+                let h = PyDict::new_bound(py);
+                h.set_item("name", hit.name)?;
+                h.set_item("newest_version", newest_version)?;
+                Ok(h)
+            })
+            .collect()
+    }
+}
+
+#[pymodule]
+fn gnostr_registry(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyRegistry>()?;
+    m.add("RegistryError", m.py().get_type_bound::<RegistryError>())?;
+    Ok(())
+}
+
+/// Runs the script with `args` as `sys.argv[1:]`. A traceback is printed
+/// when it raises; `sys.exit(0)` counts as success.
+pub fn run(script: &Path, args: Vec<String>) -> Result<(), Error> {
+    use error::*;
+
+    let code = fs::read_to_string(script).context(ReadSnafu { path: script })?;
+
+    pyo3::append_to_inittab!(gnostr_registry);
+    pyo3::prepare_freethreaded_python();
+
+    Python::with_gil(|py| {
+        let run = || {
+            let argv = std::iter::once(script.display().to_string()).chain(args);
+            py.import_bound("sys")?
+                .setattr("argv", argv.collect::<Vec<_>>())?;
+            py.run_bound(&code, None, None)
+        };
+
+        let Err(e) = run() else {
+            return Ok(());
+        };
+
+        if e.is_instance_of::<pyo3::exceptions::PySystemExit>(py) {
+            let status = e.value_bound(py).getattr("code")?;
+            if status.is_none() || status.extract::<i64>().is_ok_and(|s| s == 0) {
+                return Ok(());
+            }
+        }
+
+        e.print(py);
+        Err(e)
+    })
+    .context(ScriptSnafu { path: script })
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the script {}", path.display()))]
+    Read {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("The script {} failed", path.display()))]
+    Script { source: PyErr, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Script { .. } => "E_SCRIPT_FAILED",
+        }
+    }
+}
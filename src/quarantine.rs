@@ -0,0 +1,198 @@
+//! Versions taken down by an operator, for example after a malware
+//! report.
+//!
+//! `margo quarantine NAME@VERSION` records the version in
+//! `quarantine.json` and marks its index entry `withheld`. The version
+//! stays in the index, so lock files that name it still make sense, but
+//! the daemon refuses to serve its `.crate` file and peers are not sent
+//! it. `margo release NAME@VERSION` undoes both.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{common::CrateName, timestamp::Timestamp};
+
+pub const FILE_NAME: &str = "quarantine.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Withheld {
+    pub name: CrateName,
+    pub vers: Version,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    pub quarantined_at: Timestamp,
+}
+
+impl Withheld {
+    fn is_for(&self, name: &str, vers: &Version) -> bool {
+        self.name.as_str().eq_ignore_ascii_case(name) && self.vers == *vers
+    }
+}
+
+/// A missing file withholds nothing.
+pub fn read(path: &Path) -> Result<Vec<Withheld>, Error> {
+    use error::*;
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+
+    serde_json::from_slice(&data).context(ParseSnafu { path })
+}
+
+fn write(path: &Path, withheld: &[Withheld]) -> Result<(), Error> {
+    use error::*;
+
+    let data = serde_json::to_vec_pretty(withheld).context(SerializeSnafu)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, path).context(WriteSnafu { path })
+}
+
+#[cfg(feature = "server")]
+pub fn find(path: &Path, name: &str, vers: &Version) -> Result<Option<Withheld>, Error> {
+    Ok(read(path)?.into_iter().find(|w| w.is_for(name, vers)))
+}
+
+pub fn add(path: &Path, withheld: Withheld) -> Result<(), Error> {
+    use error::*;
+
+    let mut all = read(path)?;
+    let exists = all
+        .iter()
+        .any(|w| w.is_for(withheld.name.as_str(), &withheld.vers));
+    ensure!(
+        !exists,
+        AlreadySnafu {
+            name: withheld.name,
+            vers: withheld.vers,
+        }
+    );
+    all.push(withheld);
+
+    write(path, &all)
+}
+
+pub fn remove(path: &Path, name: &CrateName, vers: &Version) -> Result<Withheld, Error> {
+    use error::*;
+
+    let mut all = read(path)?;
+
+    let i = all
+        .iter()
+        .position(|w| w.is_for(name.as_str(), vers))
+        .context(UnknownSnafu {
+            name: name.clone(),
+            vers: vers.clone(),
+        })?;
+    let withheld = all.remove(i);
+
+    write(path, &all)?;
+
+    Ok(withheld)
+}
+
+/// Whether `relative`, a path within the registry, is the `.crate`
+/// file of a withheld version.
+#[cfg(feature = "p2p")]
+pub fn withholds(withheld: &[Withheld], relative: &str) -> bool {
+    let rest = relative.strip_prefix(crate::CRATE_DIR_NAME);
+    let Some(rest) = rest.and_then(|r| r.strip_prefix('/')) else {
+        return false;
+    };
+    let Some((dirs, file)) = rest.rsplit_once('/') else {
+        return false;
+    };
+    let Some(vers) = file.strip_suffix(".crate") else {
+        return false;
+    };
+    let name = dirs.rsplit('/').next().unwrap_or_default();
+
+    withheld
+        .iter()
+        .any(|w| w.name.as_str().eq_ignore_ascii_case(name) && w.vers.to_string() == vers)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the quarantine list at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the quarantine list at {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the quarantine list"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write the quarantine list to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("{name} {vers} is already quarantined"))]
+    Already { name: CrateName, vers: Version },
+
+    #[snafu(display("{name} {vers} is not quarantined"))]
+    Unknown { name: CrateName, vers: Version },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_QUARANTINE_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Already { .. } => "E_ALREADY_QUARANTINED",
+            Self::Unknown { .. } => "E_NOT_QUARANTINED",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn versions_are_withheld_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join(FILE_NAME);
+
+        let name: CrateName = "demo".parse().unwrap();
+        let vers: Version = "1.0.0".parse().unwrap();
+        let withheld = Withheld {
+            name: name.clone(),
+            vers: vers.clone(),
+            reason: Some("malware report".to_owned()),
+            quarantined_at: Timestamp::now(),
+        };
+
+        add(&path, withheld.clone()).unwrap();
+        assert!(add(&path, withheld).is_err());
+        assert_eq!(1, read(&path).unwrap().len());
+
+        #[cfg(feature = "p2p")]
+        {
+            let all = read(&path).unwrap();
+            assert!(withholds(&all, "crates/de/mo/demo/1.0.0.crate"));
+            assert!(!withholds(&all, "crates/de/mo/demo/1.1.0.crate"));
+            assert!(!withholds(&all, "de/mo/demo"));
+        }
+
+        remove(&path, &name, &vers).unwrap();
+        assert!(read(&path).unwrap().is_empty());
+        assert!(remove(&path, &name, &vers).is_err());
+    }
+}
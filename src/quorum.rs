@@ -0,0 +1,529 @@
+//! Operations that several operators must approve before they happen.
+//!
+//! With a quorum configured, removing a version, replacing a version's
+//! `.crate` file with a different one, and rotating the operator's key
+//! each need the approval of `threshold` of the listed `operators`:
+//!
+//! ```toml
+//! [quorum]
+//! operators = ["npub1...", "npub1...", "npub1..."]
+//! threshold = 2
+//! ```
+//!
+//! An operator approves with `margo quorum approve`, which signs a
+//! NIP-78 event, like an [announcement](crate::announce), tagged
+//! [`TAG`] and carrying an `approval` [payload](crate::payload) that
+//! names the registry and the operation. The approval is kept in the
+//! registry's `approvals.json`, and published to relays for operators
+//! who do not share the registry's disk, from where `margo quorum
+//! collect` fetches them. Approvals expire after a week.
+//!
+//! The operation checks its approvals before changing anything, and
+//! once it has succeeded they are used up and the approvers recorded in
+//! the audit log, so the same approvals do not allow it a second time.
+
+use nostr::{Event, EventBuilder, Filter, Kind, PublicKey, Tag, TagStandard, Timestamp};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use url::Url;
+
+use crate::{
+    announce, audit,
+    common::CrateName,
+    payload::{Body, Payload},
+    signer::{self, SharedSigner, Signer},
+    Registry,
+};
+
+pub const FILE_NAME: &str = "approvals.json";
+
+/// The hashtag that marks an application-data event as an approval.
+pub const TAG: &str = "gnostr-registry-approval";
+
+/// How long an approval is valid for, in seconds.
+const MAX_AGE: u64 = 7 * 24 * 60 * 60;
+
+/// An operation that needs a quorum.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    Remove {
+        name: CrateName,
+        vers: Version,
+    },
+
+    /// Adding a `.crate` file with the checksum `cksum` in place of a
+    /// different one. Checksums are of the files as published, before
+    /// any [encryption](crate::encryption).
+    Replace {
+        name: CrateName,
+        vers: Version,
+        cksum: String,
+    },
+
+    RotateKey {
+        old_pubkey: String,
+    },
+}
+
+/// As given to `margo quorum approve`.
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Remove { name, vers } => write!(f, "remove {name} {vers}"),
+            Self::Replace { name, vers, cksum } => write!(f, "replace {name} {vers} {cksum}"),
+            Self::RotateKey { old_pubkey } => write!(f, "rotate-key {old_pubkey}"),
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let operation = match words[..] {
+            ["remove", name, vers] => name
+                .parse()
+                .ok()
+                .zip(vers.parse().ok())
+                .map(|(name, vers)| Self::Remove { name, vers }),
+            ["replace", name, vers, cksum] => {
+                name.parse()
+                    .ok()
+                    .zip(vers.parse().ok())
+                    .map(|(name, vers)| Self::Replace {
+                        name,
+                        vers,
+                        cksum: cksum.to_owned(),
+                    })
+            }
+            ["rotate-key", old_pubkey] => {
+                PublicKey::parse(old_pubkey)
+                    .ok()
+                    .map(|old_pubkey| Self::RotateKey {
+                        old_pubkey: old_pubkey.to_hex(),
+                    })
+            }
+            _ => None,
+        };
+
+        operation.context(error::OperationSnafu { operation: s })
+    }
+}
+
+/// The content of an approval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Approval {
+    pub base_url: Url,
+    pub operation: Operation,
+}
+
+/// Approvals that allow an operation. See [`Grant::spend`].
+#[derive(Debug)]
+#[must_use]
+pub struct Grant {
+    operation: Operation,
+    approvals: Vec<Event>,
+}
+
+impl Grant {
+    /// Uses up the operation's approvals once it succeeded, and records
+    /// who gave them. Does nothing when no quorum is configured.
+    pub fn spend(self, registry: &Registry) -> Result<(), Error> {
+        use error::*;
+
+        if self.approvals.is_empty() {
+            return Ok(());
+        }
+
+        let mut events = read(registry)?;
+        events.retain(|e| parse(e).map_or(true, |a| a.operation != self.operation));
+        write(registry, &events)?;
+
+        let approvers = self.approvals.iter().map(|a| a.pubkey.to_hex()).collect();
+        registry
+            .record(audit::Event::Authorize {
+                operation: self.operation.to_string(),
+                approvers,
+            })
+            .context(AuditSnafu)?;
+
+        Ok(())
+    }
+}
+
+/// Allows `operation` if enough operators approved it, or if no quorum
+/// is configured.
+pub fn require(registry: &Registry, operation: &Operation) -> Result<Grant, Error> {
+    use error::*;
+
+    let threshold = registry.config.quorum.threshold;
+    let mut grant = Grant {
+        operation: operation.clone(),
+        approvals: vec![],
+    };
+    if threshold == 0 {
+        return Ok(grant);
+    }
+
+    let operators = operators(registry)?.len();
+    ensure!(
+        threshold <= operators,
+        UnreachableSnafu {
+            threshold,
+            operators
+        }
+    );
+
+    let approvals = pending(registry)?.remove(operation).unwrap_or_default();
+    ensure!(
+        approvals.len() >= threshold,
+        PendingSnafu {
+            operation: operation.clone(),
+            approvals: approvals.len(),
+            threshold,
+        }
+    );
+
+    grant.approvals = approvals.into_values().collect();
+    Ok(grant)
+}
+
+/// Has `signer` approve `operation` on the registry, and keeps the
+/// approval. It is not published.
+pub fn approve(
+    registry: &Registry,
+    signer: &dyn Signer,
+    operation: Operation,
+) -> Result<Event, Error> {
+    use error::*;
+
+    let pubkey = signer.public_key().context(SignSnafu)?.to_hex();
+    ensure!(
+        operators(registry)?.contains(&pubkey),
+        NotOperatorSnafu { pubkey }
+    );
+
+    let approval = Approval {
+        base_url: registry.config.base_url.clone(),
+        operation,
+    };
+    let event = event(signer, &approval)?;
+    store(registry, vec![event.clone()])?;
+
+    Ok(event)
+}
+
+/// Fetches the approvals published to `relays` and keeps those for the
+/// registry. Returns how many were new. A relay that cannot be queried
+/// only produces a warning, unless none can.
+pub fn collect(registry: &Registry, relays: &[Url]) -> Result<usize, Error> {
+    use error::*;
+
+    ensure!(!relays.is_empty(), NoRelaysSnafu);
+
+    let filter = Filter::new().kind(Kind::from(announce::KIND)).hashtag(TAG);
+    let mut events = vec![];
+    let mut last_error = None;
+    let mut answered = false;
+
+    for relay in relays {
+        match announce::query(relay, filter.clone()) {
+            Ok(found) => {
+                events.extend(found);
+                answered = true;
+            }
+            Err(e) => {
+                eprintln!("Warning: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if let Some(source) = last_error.filter(|_| !answered) {
+        return Err(source).context(QuerySnafu);
+    }
+
+    store(registry, events)
+}
+
+/// Who approved each operation: the valid approvals of the configured
+/// operators, keyed by their public keys.
+pub fn pending(registry: &Registry) -> Result<BTreeMap<Operation, BTreeMap<String, Event>>, Error> {
+    let operators = operators(registry)?;
+    let now = Timestamp::now();
+
+    let mut pending = BTreeMap::<_, BTreeMap<_, _>>::new();
+    for event in read(registry)? {
+        let pubkey = event.pubkey.to_hex();
+        let approval = parse(&event)
+            .filter(|a| a.base_url == registry.config.base_url)
+            .filter(|_| operators.contains(&pubkey) && is_fresh(&event, now));
+        if let Some(approval) = approval {
+            pending
+                .entry(approval.operation)
+                .or_default()
+                .insert(pubkey, event);
+        }
+    }
+
+    Ok(pending)
+}
+
+/// The signer an operator approves with: a secret key file or a
+/// NIP-46 bunker.
+pub fn signer(key: Option<&Path>, bunker: Option<&str>) -> Result<SharedSigner, Error> {
+    use error::*;
+
+    let signer: SharedSigner = match (key, bunker) {
+        (Some(path), None) => Arc::new(signer::LocalSigner::read(path).context(SignSnafu)?),
+        (None, Some(uri)) => Arc::new(signer::RemoteSigner::new(uri).context(SignSnafu)?),
+        _ => return SignersSnafu.fail(),
+    };
+
+    Ok(signer)
+}
+
+pub fn event(signer: &dyn Signer, approval: &Approval) -> Result<Event, Error> {
+    use error::*;
+
+    let payload = Payload::new(Body::Approval(approval.clone()));
+    let content = serde_json::to_string(&payload).context(SerializeSnafu)?;
+
+    let tags = [
+        Tag::identifier(format!(
+            "{TAG}:{}:{}",
+            approval.base_url, approval.operation
+        )),
+        Tag::hashtag(TAG),
+    ];
+
+    let builder = EventBuilder::new(Kind::from(announce::KIND), content, tags);
+    signer::sign(signer, builder).context(SignSnafu)
+}
+
+pub fn is_approval(event: &Event) -> bool {
+    event.kind == Kind::from(announce::KIND)
+        && event
+            .tags
+            .iter()
+            .filter_map(Tag::as_standardized)
+            .any(|t| matches!(t, TagStandard::Hashtag(h) if h == TAG))
+}
+
+pub fn parse(event: &Event) -> Option<Approval> {
+    if !is_approval(event) || event.verify().is_err() {
+        return None;
+    }
+
+    match Payload::parse(event.content.as_bytes())?.body {
+        Body::Approval(approval) => Some(approval),
+        _ => None,
+    }
+}
+
+fn is_fresh(event: &Event, now: Timestamp) -> bool {
+    event.created_at.as_u64() + MAX_AGE >= now.as_u64()
+}
+
+/// The configured operators' public keys, hex encoded.
+fn operators(registry: &Registry) -> Result<BTreeSet<String>, Error> {
+    use error::*;
+
+    registry
+        .config
+        .quorum
+        .operators
+        .iter()
+        .map(|operator| {
+            PublicKey::parse(operator)
+                .map(|p| p.to_hex())
+                .ok()
+                .context(InvalidOperatorSnafu { operator })
+        })
+        .collect()
+}
+
+/// Adds the new approvals of the configured operators to the ones
+/// kept, and drops those that expired. Returns how many were added.
+fn store(registry: &Registry, events: Vec<Event>) -> Result<usize, Error> {
+    let operators = operators(registry)?;
+    let now = Timestamp::now();
+
+    let mut kept = read(registry)?;
+    kept.retain(|e| is_fresh(e, now));
+    let before = kept.len();
+
+    for event in events {
+        let valid = parse(&event).is_some_and(|a| a.base_url == registry.config.base_url)
+            && operators.contains(&event.pubkey.to_hex())
+            && is_fresh(&event, now);
+        if valid && !kept.iter().any(|e| e.id == event.id) {
+            kept.push(event);
+        }
+    }
+
+    let added = kept.len() - before;
+    write(registry, &kept)?;
+
+    Ok(added)
+}
+
+/// Every approval kept, oldest first. A missing file has none.
+fn read(registry: &Registry) -> Result<Vec<Event>, Error> {
+    use error::*;
+
+    let path = registry.path.join(FILE_NAME);
+    match fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).context(ParseSnafu { path }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e).context(ReadSnafu { path }),
+    }
+}
+
+fn write(registry: &Registry, events: &[Event]) -> Result<(), Error> {
+    use error::*;
+
+    let path = registry.path.join(FILE_NAME);
+    let data = serde_json::to_vec_pretty(events).context(SerializeSnafu)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, &path).context(WriteSnafu { path })
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display(
+        "`{operation}` is not an operation that needs a quorum; expected \
+         `remove NAME VERSION`, `replace NAME VERSION CHECKSUM` or `rotate-key PUBKEY`"
+    ))]
+    Operation { operation: String },
+
+    #[snafu(display(
+        "`{operation}` needs the approval of {threshold} operators and has {approvals}; \
+         approve it with `margo quorum approve {operation}`"
+    ))]
+    Pending {
+        operation: Operation,
+        approvals: usize,
+        threshold: usize,
+    },
+
+    #[snafu(display(
+        "The quorum threshold of {threshold} is more than the {operators} operators configured"
+    ))]
+    Unreachable { threshold: usize, operators: usize },
+
+    #[snafu(display("The quorum operator `{operator}` is not a public key"))]
+    InvalidOperator { operator: String },
+
+    #[snafu(display("{pubkey} is not one of the registry's quorum operators"))]
+    NotOperator { pubkey: String },
+
+    #[snafu(display("Give either a key or a bunker to approve with"))]
+    Signers,
+
+    #[snafu(display("Could not sign the approval"))]
+    Sign { source: signer::Error },
+
+    #[snafu(display("Could not serialize the approvals"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("No relays were given to collect approvals from"))]
+    NoRelays,
+
+    #[snafu(display("Could not collect approvals"))]
+    Query { source: announce::Error },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the approvals in {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not record the approvers in the audit log"))]
+    Audit { source: audit::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Operation { .. } => "E_BAD_REQUEST",
+            Self::Signers | Self::NoRelays => "E_CONFIG_INVALID",
+            Self::Pending { .. } => "E_QUORUM_PENDING",
+            Self::Unreachable { .. } | Self::InvalidOperator { .. } => "E_CONFIG_INVALID",
+            Self::NotOperator { .. } => "E_FORBIDDEN",
+            Self::Sign { source } => source.code(),
+            Self::Serialize { .. } => "E_INTERNAL",
+            Self::Query { source } => source.code(),
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_APPROVALS_CORRUPT",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Audit { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{signer::LocalSigner, CONFIG_FILE_NAME};
+    use nostr::Keys;
+
+    #[test]
+    fn an_operation_needs_the_threshold_of_operators_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let keys = [Keys::generate(), Keys::generate(), Keys::generate()];
+        let operators = keys
+            .iter()
+            .map(|k| format!("\"{}\"", k.public_key().to_hex()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let config = format!(
+            "version = \"1\"\nformat = 3\nbase_url = \"http://example.com/\"\n\
+             [quorum]\noperators = [{operators}]\nthreshold = 2\n"
+        );
+        fs::write(dir.join(CONFIG_FILE_NAME), config).unwrap();
+        let registry = Registry::open(dir).unwrap();
+
+        let operation: Operation = "remove demo 1.0.0".parse().unwrap();
+        assert_eq!(operation.to_string(), "remove demo 1.0.0");
+        let approve =
+            |k: &Keys| approve(&registry, &LocalSigner::new(k.clone()), operation.clone());
+
+        approve(&keys[0]).unwrap();
+        approve(&keys[0]).unwrap();
+        let e = require(&registry, &operation).unwrap_err();
+        assert!(matches!(e, Error::Pending { approvals: 1, .. }), "{e}");
+
+        assert!(approve(&Keys::generate()).is_err());
+        approve(&keys[1]).unwrap();
+        require(&registry, &operation)
+            .unwrap()
+            .spend(&registry)
+            .unwrap();
+
+        // Spent approvals do not allow it again
+        assert!(require(&registry, &operation).is_err());
+        assert_eq!(1, registry.audit_log().unwrap().len());
+    }
+}
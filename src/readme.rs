@@ -0,0 +1,210 @@
+//! READMEs unpacked from crate packages when they are added, rendered
+//! to standalone HTML pages next to the `.crate` file.
+
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
+use semver::Version;
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{fs, io, path::Path, path::PathBuf};
+
+use crate::{common::CrateName, extract_root_file, html, ExtractRootFileError, Registry};
+
+/// The parts of `[package]` that describe a crate to people.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Metadata {
+    #[serde(default)]
+    pub description: Option<String>,
+
+    #[serde(default)]
+    pub homepage: Option<String>,
+
+    #[serde(default)]
+    pub repository: Option<String>,
+
+    #[serde(default)]
+    pub documentation: Option<String>,
+
+    #[serde(default)]
+    readme: Option<ReadmeField>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ReadmeField {
+    Path(String),
+    Enabled(bool),
+}
+
+impl Metadata {
+    fn readme_path(&self) -> Option<&str> {
+        match self.readme.as_ref()? {
+            ReadmeField::Path(p) => Some(p),
+            ReadmeField::Enabled(true) => Some("README.md"),
+            ReadmeField::Enabled(false) => None,
+        }
+    }
+
+    /// The links that are safe to show, labelled for display.
+    pub fn links(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("Homepage", &self.homepage),
+            ("Repository", &self.repository),
+            ("Documentation", &self.documentation),
+        ]
+        .into_iter()
+        .filter_map(|(label, url)| Some((label, url.as_deref()?)))
+        .filter(|(_, url)| is_safe_url(url))
+    }
+}
+
+/// Renders the package's README, if it has one, to
+/// [`Registry::readme_file_path_for`].
+pub fn write(
+    registry: &Registry,
+    name: &CrateName,
+    version: &Version,
+    metadata: &Metadata,
+    crate_data: &[u8],
+) -> Result<(), Error> {
+    use error::*;
+
+    let Some(readme_path) = metadata.readme_path() else {
+        return Ok(());
+    };
+
+    let readme = extract_root_file(crate_data, Path::new(readme_path))
+        .context(ExtractSnafu { path: readme_path })?;
+    let Some(readme) = readme else {
+        return Ok(());
+    };
+
+    let readme = String::from_utf8_lossy(&readme);
+    let content = if is_markdown(readme_path) {
+        render(&readme)
+    } else {
+        maud::html! { pre { (&*readme) } }.into_string()
+    };
+
+    // The page lives in `crates/{prefix...}/{name}/`
+    let depth = name.prefix_directories().len() + 2;
+    let root = "../".repeat(depth);
+    let page = html::readme(&root, name, version, metadata, &content).into_string();
+
+    let path = registry.readme_file_path_for(name, version);
+    fs::write(&path, page).context(WriteSnafu { path })?;
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not extract the README `{path}` from the crate package"))]
+    Extract {
+        source: ExtractRootFileError,
+        path: String,
+    },
+
+    #[snafu(display("Could not write the rendered README to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Extract { .. } => "E_BAD_PACKAGE",
+            Self::Write { .. } => "E_HTML_WRITE",
+        }
+    }
+}
+
+fn is_markdown(path: &str) -> bool {
+    let ext = Path::new(path).extension().and_then(|e| e.to_str());
+    !matches!(ext, Some("txt") | Some("rst"))
+}
+
+/// Renders Markdown to HTML. READMEs come from arbitrary packages, so
+/// raw HTML is shown as text and script-capable links are dropped.
+fn render(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+
+    let events = Parser::new_ext(markdown, options).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: sanitize_url(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: sanitize_url(dest_url),
+            title,
+            id,
+        }),
+        event => event,
+    });
+
+    let mut rendered = String::new();
+    pulldown_cmark::html::push_html(&mut rendered, events);
+    rendered
+}
+
+fn sanitize_url(url: CowStr<'_>) -> CowStr<'_> {
+    if is_safe_url(&url) {
+        url
+    } else {
+        CowStr::Borrowed("#")
+    }
+}
+
+fn is_safe_url(url: &str) -> bool {
+    match url.split_once(':') {
+        // Relative URLs may contain a colon after the first path segment
+        Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => {
+            let scheme = scheme.to_ascii_lowercase();
+            matches!(scheme.as_str(), "http" | "https" | "mailto")
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn raw_html_is_escaped() {
+        let rendered = render("# Hi\n\n<script>alert(1)</script>\n\nsome <b>bold</b> text\n");
+
+        assert!(rendered.contains("<h1>Hi</h1>"), "{rendered}");
+        assert!(!rendered.contains("<script>"), "{rendered}");
+        assert!(!rendered.contains("<b>"), "{rendered}");
+    }
+
+    #[test]
+    fn script_links_are_dropped() {
+        let rendered = render("[a](javascript:alert(1)) [b](https://example.com) [c](docs/x.md)");
+
+        assert!(!rendered.contains("javascript"), "{rendered}");
+        assert!(
+            rendered.contains(r#"href="https://example.com""#),
+            "{rendered}"
+        );
+        assert!(rendered.contains(r#"href="docs/x.md""#), "{rendered}");
+    }
+}
@@ -0,0 +1,209 @@
+//! Regenerating the HTML, the feed and the index snapshot once for a
+//! run of changes.
+//!
+//! Each of these is made from every index file, so regenerating them
+//! after each of the ten crates a CI pipeline publishes one after
+//! another reads the whole index ten times, and the later publishes
+//! wait behind the earlier regenerations. Changes the daemon makes
+//! instead ask the tenant's [`Regenerator`] for a pass, which runs once
+//! no change has asked for `[regenerate] quiet-ms`, or after ten of
+//! those windows when changes never stop.
+//!
+//! The snapshot is only built once somebody asks for it, and is then
+//! kept until the index changes. While a pass is waiting, the kept
+//! snapshot is served as it is.
+
+use axum::body::Bytes;
+use snafu::prelude::*;
+use std::{
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{audit, feed, snapshot, HtmlError, Registry};
+
+pub const DEFAULT_QUIET_MS: u64 = 2_000;
+
+/// How many quiet windows a pass may be put off for.
+const MAX_WINDOWS: u32 = 10;
+
+#[derive(Debug, Default)]
+pub struct Regenerator {
+    state: Mutex<State>,
+    requested: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// When the first and the last change since the last pass asked
+    /// for a pass.
+    pending: Option<(Instant, Instant)>,
+
+    snapshot: Option<Snapshot>,
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    /// Where the audit log was up to when it was built.
+    audit_offset: u64,
+    data: Bytes,
+}
+
+impl Regenerator {
+    /// Asks for a pass once changes stop.
+    pub fn request(&self) {
+        let mut state = self.lock();
+        let now = Instant::now();
+        let first = state.pending.map_or(now, |(first, _)| first);
+        state.pending = Some((first, now));
+        self.requested.notify_one();
+    }
+
+    /// Blocks until a pass is due.
+    pub fn wait(&self, quiet: Duration) {
+        let mut state = self.lock();
+        loop {
+            let Some((first, last)) = state.pending else {
+                state = self
+                    .requested
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            };
+
+            let due = (last + quiet).min(first + quiet * MAX_WINDOWS);
+            let now = Instant::now();
+            if now >= due {
+                state.pending = None;
+                return;
+            }
+
+            state = self
+                .requested
+                .wait_timeout(state, due - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Regenerates everything that is enabled, and the snapshot when
+    /// one is kept.
+    pub fn pass(&self, registry: &Registry) -> Result<(), Error> {
+        use error::*;
+
+        registry.maybe_generate_html().context(HtmlSnafu)?;
+        registry.maybe_generate_feed().context(FeedSnafu)?;
+
+        if self.lock().snapshot.is_some() {
+            let snapshot = build(registry)?;
+            self.lock().snapshot = Some(snapshot);
+        }
+
+        Ok(())
+    }
+
+    /// The index snapshot, built again only when the index changed
+    /// outside of a pass.
+    pub fn snapshot(&self, registry: &Registry) -> Result<Bytes, Error> {
+        use error::*;
+
+        let audit_offset = audit::end(&registry.audit_log_path()).context(AuditSnafu)?;
+        {
+            let state = self.lock();
+            if let Some(snapshot) = &state.snapshot {
+                if snapshot.audit_offset == audit_offset || state.pending.is_some() {
+                    return Ok(snapshot.data.clone());
+                }
+            }
+        }
+
+        let snapshot = build(registry)?;
+        let data = snapshot.data.clone();
+        self.lock().snapshot = Some(snapshot);
+        Ok(data)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn build(registry: &Registry) -> Result<Snapshot, Error> {
+    use error::*;
+
+    // Taken first, so that a change while building builds it again
+    let audit_offset = audit::end(&registry.audit_log_path()).context(AuditSnafu)?;
+    let data = snapshot::build(registry).context(SnapshotSnafu)?;
+
+    Ok(Snapshot {
+        audit_offset,
+        data: data.into(),
+    })
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not regenerate the HTML"))]
+    Html { source: HtmlError },
+
+    #[snafu(display("Could not regenerate the feed"))]
+    Feed { source: feed::Error },
+
+    #[snafu(display("Could not follow the audit log"))]
+    Audit { source: audit::Error },
+
+    #[snafu(display("Could not build the index snapshot"))]
+    Snapshot { source: snapshot::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Html { source } => source.code(),
+            Self::Feed { source } => source.code(),
+            Self::Audit { source } => source.code(),
+            Self::Snapshot { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn changes_in_a_row_wait_for_one_pass() {
+        let regenerator = Regenerator::default();
+        let quiet = Duration::from_millis(100);
+
+        let start = Instant::now();
+        regenerator.request();
+        std::thread::sleep(Duration::from_millis(50));
+        regenerator.request();
+
+        regenerator.wait(quiet);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert!(regenerator.lock().pending.is_none());
+    }
+
+    #[test]
+    fn passes_are_not_put_off_forever() {
+        let regenerator = Regenerator::default();
+        let quiet = Duration::from_millis(20);
+
+        let start = Instant::now();
+        regenerator.request();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while start.elapsed() < quiet * MAX_WINDOWS * 3 {
+                    regenerator.request();
+                    std::thread::sleep(quiet / 4);
+                }
+            });
+
+            regenerator.wait(quiet);
+            assert!(start.elapsed() < quiet * MAX_WINDOWS * 2);
+        });
+    }
+}
@@ -0,0 +1,358 @@
+//! Comparing the versions and checksums of two registries.
+//!
+//! Either side may be a registry on disk or, with the `proxy` feature,
+//! the URL of a margo daemon, whose whole index is fetched from
+//! `/api/v1/index-snapshot` once `/api/v1/registry` says it has one. Crates are matched ignoring case and
+//! treating `-` and `_` alike, and versions are compared by the
+//! checksum of the `.crate` file as published, before any encryption.
+
+use semver::Version;
+use serde::Serialize;
+use snafu::prelude::*;
+use std::{collections::BTreeMap, fmt, path::PathBuf, str::FromStr};
+
+use crate::{index_entry, names, Index, ListAll, ListAllError, OpenError, Registry};
+
+#[cfg(feature = "proxy")]
+use crate::registry_info::{self, Feature};
+#[cfg(feature = "proxy")]
+use url::Url;
+
+#[derive(Debug)]
+pub enum Source {
+    Path(PathBuf),
+
+    #[cfg(feature = "proxy")]
+    Url(Url),
+}
+
+impl FromStr for Source {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let is_url = s.starts_with("http://") || s.starts_with("https://");
+
+        #[cfg(feature = "proxy")]
+        if is_url {
+            let mut url = Url::parse(s).context(error::UrlSnafu { url: s })?;
+            crate::ensure_last_segment_empty(&mut url);
+            return Ok(Self::Url(url));
+        }
+
+        #[cfg(not(feature = "proxy"))]
+        ensure!(!is_url, error::RemoteSnafu { url: s });
+
+        Ok(Self::Path(s.into()))
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => path.display().fmt(f),
+            #[cfg(feature = "proxy")]
+            Self::Url(url) => url.fmt(f),
+        }
+    }
+}
+
+impl Source {
+    pub fn load(&self) -> Result<ListAll, Error> {
+        use error::*;
+
+        match self {
+            Self::Path(path) => {
+                let registry = Registry::open(path)?;
+                registry.list_all().context(ListSnafu)
+            }
+
+            #[cfg(feature = "proxy")]
+            Self::Url(url) => fetch(url),
+        }
+    }
+}
+
+#[cfg(feature = "proxy")]
+fn fetch(base_url: &Url) -> Result<ListAll, Error> {
+    use error::*;
+    use std::io::Read;
+
+    const MAX_SNAPSHOT_BYTES: u64 = 64 * 1024 * 1024;
+
+    // Daemons from before `/api/v1/registry` all serve snapshots
+    if let Some(info) = fetch_info(base_url)? {
+        let url = base_url.clone();
+        ensure!(
+            info.supports(Feature::IndexSnapshot),
+            NoSnapshotSnafu { url }
+        );
+    }
+
+    let url = base_url.join("api/v1/index-snapshot").context(UrlSnafu {
+        url: base_url.as_str(),
+    })?;
+    let response = ureq::request_url("GET", &url)
+        .call()
+        .context(RequestSnafu { url: &url })?;
+
+    let mut data = vec![];
+    response
+        .into_reader()
+        .take(MAX_SNAPSHOT_BYTES)
+        .read_to_end(&mut data)
+        .context(ReadBodySnafu { url: &url })?;
+
+    let (_, files) = crate::snapshot::unpack(&data).context(SnapshotSnafu { url: &url })?;
+
+    let mut crates = ListAll::new();
+    for (path, data) in files {
+        let index = crate::parse_index_lines(&data[..]).context(ParseSnafu { path })?;
+        if let Some(entry) = index.values().next() {
+            crates.insert(entry.name.clone(), index);
+        }
+    }
+
+    Ok(crates)
+}
+
+#[cfg(feature = "proxy")]
+fn fetch_info(base_url: &Url) -> Result<Option<registry_info::Info>, Error> {
+    use error::*;
+
+    let url = base_url.join(registry_info::PATH).context(UrlSnafu {
+        url: base_url.as_str(),
+    })?;
+    let response = match ureq::request_url("GET", &url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => return Ok(None),
+        Err(e) => return Err(e).context(RequestSnafu { url }),
+    };
+
+    let info = response.into_json().context(ReadBodySnafu { url })?;
+    Ok(Some(info))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diff {
+    pub only_in_a: Vec<VersionRef>,
+    pub only_in_b: Vec<VersionRef>,
+    pub mismatched: Vec<Mismatch>,
+}
+
+impl Diff {
+    pub fn count(&self) -> usize {
+        self.only_in_a.len() + self.only_in_b.len() + self.mismatched.len()
+    }
+
+    /// Fails unless both registries have the same versions with the
+    /// same checksums.
+    pub fn ensure_same(&self) -> Result<(), Error> {
+        let count = self.count();
+        ensure!(count == 0, error::DifferSnafu { count });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionRef {
+    pub name: String,
+    pub vers: Version,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    pub name: String,
+    pub vers: Version,
+    pub cksum_a: String,
+    pub cksum_b: String,
+}
+
+pub fn diff(a: &ListAll, b: &ListAll) -> Diff {
+    let a = by_normalized_name(a);
+    let b = by_normalized_name(b);
+
+    let mut diff = Diff {
+        only_in_a: only_in(&a, &b),
+        only_in_b: only_in(&b, &a),
+        mismatched: vec![],
+    };
+
+    for (key, &(name, index_a)) in &a {
+        let Some(&(_, index_b)) = b.get(key) else {
+            continue;
+        };
+        for (vers, entry_a) in index_a {
+            let Some(entry_b) = index_b.get(vers) else {
+                continue;
+            };
+            let (cksum_a, cksum_b) = (published(entry_a), published(entry_b));
+            if !cksum_a.eq_ignore_ascii_case(cksum_b) {
+                diff.mismatched.push(Mismatch {
+                    name: name.to_owned(),
+                    vers: vers.clone(),
+                    cksum_a: cksum_a.to_owned(),
+                    cksum_b: cksum_b.to_owned(),
+                });
+            }
+        }
+    }
+
+    diff
+}
+
+/// The crates by normalized name, with the name they go by.
+type Named<'a> = BTreeMap<String, (&'a str, &'a Index)>;
+
+fn by_normalized_name(crates: &ListAll) -> Named<'_> {
+    crates
+        .iter()
+        .map(|(name, index)| (names::normalize(name.as_str()), (name.as_str(), index)))
+        .collect()
+}
+
+fn only_in(this: &Named<'_>, other: &Named<'_>) -> Vec<VersionRef> {
+    this.iter()
+        .flat_map(|(key, &(name, index))| {
+            let theirs = other.get(key).map(|&(_, index)| index);
+            index
+                .keys()
+                .filter(move |vers| !theirs.is_some_and(|i| i.contains_key(*vers)))
+                .map(move |vers| VersionRef {
+                    name: name.to_owned(),
+                    vers: vers.clone(),
+                })
+        })
+        .collect()
+}
+
+fn published(entry: &index_entry::Root) -> &str {
+    entry.decrypted_cksum.as_deref().unwrap_or(&entry.cksum)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(transparent)]
+    Open { source: OpenError },
+
+    #[snafu(display("Could not list the crates"))]
+    List { source: ListAllError },
+
+    #[cfg(not(feature = "proxy"))]
+    #[snafu(display("Comparing with `{url}` needs the `proxy` feature"))]
+    Remote { url: String },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("`{url}` is not a valid registry URL"))]
+    Url {
+        source: url::ParseError,
+        url: String,
+    },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("Could not fetch {url}"))]
+    Request {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: Url,
+    },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("Could not read the response from {url}"))]
+    ReadBody { source: std::io::Error, url: Url },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("The index snapshot from {url} is not valid"))]
+    Snapshot {
+        source: crate::snapshot::Error,
+        url: Url,
+    },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("{url} does not serve index snapshots"))]
+    NoSnapshot { url: Url },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("Could not parse the index file {path}"))]
+    Parse {
+        source: crate::ParseIndexError,
+        path: String,
+    },
+
+    #[snafu(display("Found {count} difference(s) between the registries"))]
+    Differ { count: usize },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Open { source } => source.code(),
+            Self::List { source } => source.code(),
+            #[cfg(not(feature = "proxy"))]
+            Self::Remote { .. } => "E_FEATURE_DISABLED",
+            #[cfg(feature = "proxy")]
+            Self::Url { .. } => "E_BAD_URL",
+            #[cfg(feature = "proxy")]
+            Self::Request { .. } | Self::ReadBody { .. } => "E_UPSTREAM",
+            #[cfg(feature = "proxy")]
+            Self::Snapshot { source, .. } => source.code(),
+            #[cfg(feature = "proxy")]
+            Self::Parse { .. } => "E_INDEX_CORRUPT",
+            #[cfg(feature = "proxy")]
+            Self::NoSnapshot { .. } => "E_UNSUPPORTED",
+            Self::Differ { .. } => "E_REGISTRIES_DIFFER",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn crates(versions: &[(&str, &str, &str)]) -> ListAll {
+        let mut crates = ListAll::new();
+        for &(name, vers, cksum) in versions {
+            let entry = serde_json::from_value::<index_entry::Root>(serde_json::json!({
+                "name": name,
+                "vers": vers,
+                "deps": [],
+                "cksum": cksum,
+                "features": {},
+                "yanked": false,
+                "v": 2,
+            }))
+            .unwrap();
+            crates
+                .entry(name.parse().unwrap())
+                .or_default()
+                .insert(vers.parse().unwrap(), entry);
+        }
+        crates
+    }
+
+    #[test]
+    fn versions_are_matched_by_name_and_compared_by_checksum() {
+        let a = crates(&[
+            ("serde", "1.0.0", "aa"),
+            ("serde", "1.0.1", "bb"),
+            ("My_Crate", "0.1.0", "cc"),
+        ]);
+        let b = crates(&[
+            ("serde", "1.0.1", "BB"),
+            ("my-crate", "0.1.0", "dd"),
+            ("tokio", "1.0.0", "ee"),
+        ]);
+
+        let diff = diff(&a, &b);
+        let refs = |r: &[VersionRef]| {
+            r.iter()
+                .map(|r| format!("{} {}", r.name, r.vers))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(["serde 1.0.0"], *refs(&diff.only_in_a));
+        assert_eq!(["tokio 1.0.0"], *refs(&diff.only_in_b));
+        assert_eq!(1, diff.mismatched.len());
+        assert_eq!("My_Crate", diff.mismatched[0].name);
+        assert_eq!(3, diff.count());
+    }
+}
@@ -0,0 +1,100 @@
+//! What a margo daemon offers, for clients and peers to detect rather
+//! than guess.
+//!
+//! `GET /api/v1/registry` answers with an [`Info`]. `api` goes up when
+//! an endpoint changes in a way older clients would misread, and
+//! `format` is the on-disk format of the registry, as `margo migrate`
+//! counts it. Daemons from before this endpoint answer 404, and offer
+//! the features that every daemon offered then.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+pub const PATH: &str = "api/v1/registry";
+
+pub const API_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum Feature {
+    /// The index over plain HTTP, as Cargo's `sparse+` protocol.
+    Sparse,
+
+    /// Many index files in one response, from `/index-bundle`.
+    Bundles,
+
+    /// The whole index in one archive, from `/api/v1/index-snapshot`.
+    IndexSnapshot,
+
+    /// The audit log entries that changed the index, from
+    /// `/api/v1/changes`.
+    Changes,
+
+    /// In-toto attestations of versions.
+    Attestations,
+
+    /// `cargo publish`.
+    Publish,
+
+    /// Events signed with the operator's nostr key, `pubkey`.
+    Signatures,
+
+    /// Crates missing locally are fetched from an upstream registry.
+    Proxy,
+
+    /// `.crate` files are encrypted; index entries carry
+    /// `decrypted_cksum`.
+    Encryption,
+
+    /// Crates are exchanged with peers over libp2p, as `peer_id`.
+    P2p,
+
+    /// A feature of a newer daemon.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Info {
+    /// The version of margo serving the registry.
+    pub margo_version: String,
+
+    pub api: u32,
+    pub format: u32,
+    pub features: BTreeSet<Feature>,
+
+    /// The operator's nostr public key, hex encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<String>,
+}
+
+impl Info {
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn features_of_newer_daemons_are_tolerated() {
+        let info: Info = serde_json::from_value(serde_json::json!({
+            "margo_version": "9.0.0",
+            "api": 2,
+            "format": 7,
+            "features": ["sparse", "index-snapshot", "teleportation"],
+        }))
+        .unwrap();
+
+        assert!(info.supports(Feature::IndexSnapshot));
+        assert!(!info.supports(Feature::Publish));
+        assert!(info.supports(Feature::Unknown));
+    }
+}
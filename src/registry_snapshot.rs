@@ -0,0 +1,311 @@
+//! Named copies of the whole registry that it can be rolled back to.
+//!
+//! `margo snapshot create NAME` copies every file of the registry, other
+//! than its `.git` directory, to `NAME/` in a directory beside the
+//! registry called `{registry}.snapshots`. It lives outside the registry
+//! so that the daemon never serves it. `.crate` files and blobs never
+//! change once written, so they are hard linked rather than copied when
+//! the file system allows it; a snapshot of a large registry costs
+//! little more than its index.
+//!
+//! `margo snapshot restore NAME` first takes a snapshot of the current
+//! state, so that a restore can itself be undone, and then replaces the
+//! registry's files with the snapshot's.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{audit, blob, timestamp::Timestamp, Registry, CRATE_DIR_NAME};
+
+const DIR_SUFFIX: &str = ".snapshots";
+
+const GIT_DIR_NAME: &str = ".git";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    pub created_at: Timestamp,
+    pub files: usize,
+
+    /// How many of the files are hard links to the registry's.
+    pub linked: usize,
+}
+
+/// `{registry}.snapshots`, beside the registry.
+fn snapshots_dir(registry: &Registry) -> Result<PathBuf, Error> {
+    use error::*;
+
+    let path = fs::canonicalize(&registry.path).context(ReadSnafu {
+        path: &registry.path,
+    })?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    Ok(path.with_file_name(format!("{name}{DIR_SUFFIX}")))
+}
+
+pub fn create(registry: &Registry, name: &str) -> Result<Manifest, Error> {
+    use error::*;
+
+    ensure!(is_name(name), NameSnafu { name });
+
+    let snapshots_dir = snapshots_dir(registry)?;
+    let dir = snapshots_dir.join(name);
+    ensure!(!dir.exists(), ExistsSnafu { name });
+
+    // Copied under a temporary name first, so an interrupted snapshot
+    // is never mistaken for a complete one.
+    let tmp = snapshots_dir.join(format!(".{name}.tmp"));
+    remove_dir_if_exists(&tmp)?;
+
+    let (files, linked) = copy_tree(&registry.path, &tmp)?;
+    fs::rename(&tmp, &dir).context(WriteSnafu { path: &dir })?;
+
+    let manifest = Manifest {
+        name: name.to_owned(),
+        created_at: Timestamp::now(),
+        files,
+        linked,
+    };
+    let data = serde_json::to_vec_pretty(&manifest).context(SerializeSnafu)?;
+    let path = manifest_path(&snapshots_dir, name);
+    fs::write(&path, data).context(WriteSnafu { path })?;
+
+    Ok(manifest)
+}
+
+/// Every complete snapshot, oldest first.
+pub fn list(registry: &Registry) -> Result<Vec<Manifest>, Error> {
+    use error::*;
+
+    let snapshots_dir = snapshots_dir(registry)?;
+    let entries = match fs::read_dir(&snapshots_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => {
+            return Err(e).context(ReadSnafu {
+                path: snapshots_dir,
+            })
+        }
+    };
+
+    let mut manifests = vec![];
+    for entry in entries {
+        let entry = entry.context(ReadSnafu {
+            path: &snapshots_dir,
+        })?;
+        let path = entry.path();
+        if path.extension().map_or(true, |e| e != "json") {
+            continue;
+        }
+
+        let data = fs::read(&path).context(ReadSnafu { path: &path })?;
+        let manifest = serde_json::from_slice(&data).context(ParseSnafu { path })?;
+        manifests.push(manifest);
+    }
+    manifests.sort_by(|a: &Manifest, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+
+    Ok(manifests)
+}
+
+/// Returns the snapshot of the state that was replaced.
+pub fn restore(registry: &Registry, name: &str) -> Result<Manifest, Error> {
+    use error::*;
+
+    let snapshots_dir = snapshots_dir(registry)?;
+    let dir = snapshots_dir.join(name);
+    ensure!(
+        is_name(name) && manifest_path(&snapshots_dir, name).exists(),
+        UnknownSnafu { name }
+    );
+
+    let before = create(registry, &format!("before-restore-{}", Timestamp::now().0))?;
+
+    let entries = fs::read_dir(&registry.path).context(ReadSnafu {
+        path: &registry.path,
+    })?;
+    for entry in entries {
+        let entry = entry.context(ReadSnafu {
+            path: &registry.path,
+        })?;
+        if entry.file_name() == GIT_DIR_NAME {
+            continue;
+        }
+
+        let path = entry.path();
+        let removed = match entry.file_type() {
+            Ok(t) if t.is_dir() => fs::remove_dir_all(&path),
+            _ => fs::remove_file(&path),
+        };
+        removed.context(WriteSnafu { path })?;
+    }
+
+    copy_tree(&dir, &registry.path)?;
+
+    registry
+        .record(audit::Event::RestoreSnapshot {
+            name: name.to_owned(),
+            saved_as: before.name.clone(),
+        })
+        .context(AuditSnafu)?;
+
+    Ok(before)
+}
+
+pub fn delete(registry: &Registry, name: &str) -> Result<(), Error> {
+    use error::*;
+
+    let snapshots_dir = snapshots_dir(registry)?;
+    let path = manifest_path(&snapshots_dir, name);
+    ensure!(is_name(name) && path.exists(), UnknownSnafu { name });
+
+    // Without its manifest the snapshot is no longer listed, even if
+    // removing the files is interrupted.
+    fs::remove_file(&path).context(WriteSnafu { path })?;
+    remove_dir_if_exists(&snapshots_dir.join(name))
+}
+
+fn manifest_path(snapshots_dir: &Path, name: &str) -> PathBuf {
+    snapshots_dir.join(format!("{name}.json"))
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Files that are replaced rather than modified, so a hard link to one
+/// keeps its contents.
+pub fn is_immutable(relative: &Path) -> bool {
+    let top = relative.components().next().map(|c| c.as_os_str());
+
+    match top.and_then(|t| t.to_str()) {
+        Some(CRATE_DIR_NAME) => relative.extension().is_some_and(|e| e == "crate"),
+        Some(blob::DIR_NAME) => true,
+        _ => false,
+    }
+}
+
+/// Returns how many files were written and how many of them are hard
+/// links.
+fn copy_tree(from: &Path, to: &Path) -> Result<(usize, usize), Error> {
+    use error::*;
+
+    let mut files = 0;
+    let mut linked = 0;
+
+    let walk = walkdir::WalkDir::new(from)
+        .into_iter()
+        .filter_entry(|e| e.depth() != 1 || e.file_name() != GIT_DIR_NAME);
+    for entry in walk {
+        let entry = entry.context(WalkSnafu { path: from })?;
+        let relative = entry.path().strip_prefix(from).unwrap_or(entry.path());
+        let target = to.join(relative);
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target).context(WriteSnafu { path: target })?;
+            continue;
+        }
+
+        // Such as the daemon's control socket
+        let is_special = !entry.file_type().is_file() && !entry.file_type().is_symlink();
+        if is_special {
+            continue;
+        }
+
+        files += 1;
+        if is_immutable(relative) && fs::hard_link(entry.path(), &target).is_ok() {
+            linked += 1;
+            continue;
+        }
+
+        fs::copy(entry.path(), &target).context(WriteSnafu { path: target })?;
+    }
+
+    Ok((files, linked))
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<(), Error> {
+    use error::*;
+
+    match fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context(WriteSnafu { path }),
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("`{name}` is not a valid snapshot name"))]
+    Name { name: String },
+
+    #[snafu(display("There is already a snapshot called `{name}`"))]
+    Exists { name: String },
+
+    #[snafu(display("There is no snapshot called `{name}`"))]
+    Unknown { name: String },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not walk {}", path.display()))]
+    Walk {
+        source: walkdir::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not parse the snapshot manifest {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the snapshot manifest"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not record the restore in the audit log"))]
+    Audit { source: audit::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Name { .. } => "E_BAD_SNAPSHOT_NAME",
+            Self::Exists { .. } => "E_SNAPSHOT_EXISTS",
+            Self::Unknown { .. } => "E_SNAPSHOT_NOT_FOUND",
+            Self::Read { .. } | Self::Walk { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_SNAPSHOT_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Audit { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_write_once_files_are_linked() {
+        assert!(is_immutable(Path::new("crates/se/rd/serde/1.0.0.crate")));
+        assert!(is_immutable(Path::new("blobs/sha256/ab/abcd")));
+
+        assert!(!is_immutable(Path::new("se/rd/serde")));
+        assert!(!is_immutable(Path::new(
+            "crates/se/rd/serde/1.0.0.readme.html"
+        )));
+        assert!(!is_immutable(Path::new("audit.jsonl")));
+        assert!(!is_immutable(Path::new("1.0.0.crate")));
+    }
+}
@@ -0,0 +1,306 @@
+//! Applying a changed configuration without restarting the daemon.
+//!
+//! `margo serve` reads each registry's `margo-config.toml`, and the
+//! server configuration passed with `--config`, again when:
+//!
+//! - either file changes, which is checked every few seconds;
+//! - the daemon receives `SIGHUP`, on Unix;
+//! - `margo daemon reload` asks it to.
+//!
+//! The whole configuration is parsed and validated before anything is
+//! replaced, so a mistake leaves the daemon as it was. The daemon then
+//! swaps in the registry's settings, such as its limits, scanning and
+//! tiering policies and trusted peers, and the relays of its nostr
+//! notifications and announcements. If a later step fails, such as
+//! passing the trusted peers to the P2P node, the earlier ones are
+//! rolled back. Each reload is recorded in the audit log.
+//!
+//! Listen addresses, tokens, publishing and upstream settings are read
+//! when the daemon starts, and change only when it restarts.
+
+use snafu::prelude::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{audit, control::Daemon, tenant, OpenError, Registry, CONFIG_FILE_NAME};
+
+#[cfg(feature = "p2p")]
+use crate::control::{Request, Response};
+
+#[cfg(feature = "nostr")]
+use url::Url;
+
+/// How often the configuration files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Changed,
+    Hangup,
+    Request,
+}
+
+impl Trigger {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Changed => "changed",
+            Self::Hangup => "hangup",
+            Self::Request => "request",
+        }
+    }
+}
+
+/// What a reload replaces, as read from the configuration files.
+#[derive(Debug)]
+struct Loaded {
+    registry: Arc<Registry>,
+
+    #[cfg(feature = "nostr")]
+    notify_relays: Option<Vec<Url>>,
+
+    #[cfg(feature = "nostr")]
+    announce_relays: Option<Vec<Url>>,
+}
+
+/// Reads and validates the configuration of the daemon's tenant.
+fn load(daemon: &Daemon) -> Result<Loaded, Error> {
+    use error::*;
+
+    let tenant = &daemon.tenant;
+    let path = tenant.registry().path.clone();
+
+    let Some(server_config) = &daemon.server_config else {
+        let registry = Registry::open(&path).context(OpenSnafu { path })?;
+        return Ok(Loaded {
+            registry: Arc::new(registry),
+            #[cfg(feature = "nostr")]
+            notify_relays: None,
+            #[cfg(feature = "nostr")]
+            announce_relays: None,
+        });
+    };
+
+    let name = tenant.name.clone().unwrap_or_default();
+    let tenants = tenant::load(server_config)?;
+    let new = tenants
+        .into_iter()
+        .find(|t| t.name == tenant.name)
+        .context(RemovedSnafu { name: &name })?;
+    let registry = new.registry();
+    ensure!(registry.path == path, MovedSnafu { name });
+
+    Ok(Loaded {
+        registry,
+        #[cfg(feature = "nostr")]
+        notify_relays: new.notifier.map(|n| n.relays()),
+        #[cfg(feature = "nostr")]
+        announce_relays: new.announcer.map(|a| a.relays()),
+    })
+}
+
+/// Applies the current configuration of the daemon's tenant, or leaves
+/// the previous one in place on error.
+pub async fn reload(daemon: &Daemon, trigger: Trigger) -> Result<(), Error> {
+    use error::*;
+
+    let _reloading = daemon.reloading.lock().await;
+    let loaded = load(daemon)?;
+    let registry = loaded.registry.clone();
+
+    let previous = swap(daemon, loaded);
+
+    let recorded = registry.record(audit::Event::ReloadConfig {
+        trigger: trigger.as_str().to_owned(),
+    });
+    if let Err(source) = recorded {
+        swap(daemon, previous);
+        return Err(source).context(RecordSnafu);
+    }
+
+    #[cfg(feature = "p2p")]
+    if let Response::Error { message } = daemon.ask_node(Request::Reload).await {
+        swap(daemon, previous);
+        return NodeSnafu { message }.fail();
+    }
+
+    Ok(())
+}
+
+/// Returns what was replaced.
+fn swap(daemon: &Daemon, loaded: Loaded) -> Loaded {
+    let tenant = &daemon.tenant;
+
+    Loaded {
+        registry: tenant.registry.set(loaded.registry),
+
+        #[cfg(feature = "nostr")]
+        notify_relays: tenant
+            .notifier
+            .as_ref()
+            .zip(loaded.notify_relays)
+            .map(|(n, relays)| n.set_relays(relays)),
+
+        #[cfg(feature = "nostr")]
+        announce_relays: tenant
+            .announcer
+            .as_ref()
+            .zip(loaded.announce_relays)
+            .map(|(a, relays)| a.set_relays(relays)),
+    }
+}
+
+/// Reloads a daemon's configuration whenever one of its files changes
+/// and, on Unix, on `SIGHUP`. A configuration that fails to load is
+/// reported and otherwise ignored. Only returns on error.
+pub async fn watch(daemons: Vec<Daemon>) -> Result<(), Error> {
+    #[cfg(unix)]
+    let mut hangup = {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::hangup()).context(error::SignalSnafu)?
+    };
+
+    let mut last_modified = daemons.iter().map(modified).collect::<Vec<_>>();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        #[cfg(unix)]
+        let trigger = tokio::select! {
+            _ = poll.tick() => Trigger::Changed,
+            _ = hangup.recv() => Trigger::Hangup,
+        };
+
+        #[cfg(not(unix))]
+        let trigger = {
+            poll.tick().await;
+            Trigger::Changed
+        };
+
+        for (daemon, seen) in daemons.iter().zip(&mut last_modified) {
+            let now = modified(daemon);
+            if trigger == Trigger::Changed && now == *seen {
+                continue;
+            }
+            *seen = now;
+
+            let path = daemon.tenant.registry().path.clone();
+            match reload(daemon, trigger).await {
+                Ok(()) => println!("Reloaded the configuration of {}", path.display()),
+                Err(e) => eprintln!(
+                    "Warning: Kept the previous configuration of {}: {e}",
+                    path.display()
+                ),
+            }
+        }
+    }
+}
+
+/// When each of the daemon's configuration files last changed.
+fn modified(daemon: &Daemon) -> Vec<Option<SystemTime>> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let mut times = vec![modified(
+        &daemon.tenant.registry().path.join(CONFIG_FILE_NAME),
+    )];
+    if let Some(path) = &daemon.server_config {
+        times.push(modified(path));
+    }
+
+    times
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not load the configuration of the registry at {}", path.display()))]
+    Open { source: OpenError, path: PathBuf },
+
+    #[snafu(transparent)]
+    Tenants { source: tenant::Error },
+
+    #[snafu(display("The tenant `{name}` is no longer configured"))]
+    Removed { name: String },
+
+    #[snafu(display("The registry of tenant `{name}` moved, which needs a restart"))]
+    Moved { name: String },
+
+    #[snafu(display("Could not record the reload in the audit log"))]
+    Record { source: audit::Error },
+
+    #[cfg(feature = "p2p")]
+    #[snafu(display("The P2P node refused the configuration: {message}"))]
+    Node { message: String },
+
+    #[cfg(unix)]
+    #[snafu(display("Could not listen for SIGHUP"))]
+    Signal { source: std::io::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Open { source, .. } => source.code(),
+            Self::Tenants { source } => source.code(),
+            Self::Removed { .. } | Self::Moved { .. } => "E_CONFIG_INVALID",
+            Self::Record { source } => source.code(),
+            #[cfg(feature = "p2p")]
+            Self::Node { .. } => "E_RELOAD_FAILED",
+            #[cfg(unix)]
+            Self::Signal { .. } => "E_RUNTIME",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn an_invalid_configuration_is_not_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let config_path = dir.join(CONFIG_FILE_NAME);
+        let config = "version = \"1\"\nformat = 3\nbase_url = \"http://example.com/\"\n";
+        fs::write(&config_path, config).unwrap();
+
+        let tenant = tenant::Tenant::single(
+            Registry::open(dir).unwrap(),
+            #[cfg(feature = "p2p")]
+            "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+        );
+
+        #[cfg(feature = "p2p")]
+        let (peers, mut commands) = tokio::sync::mpsc::channel::<crate::control::PeerCommand>(1);
+        #[cfg(feature = "p2p")]
+        tokio::spawn(async move {
+            while let Some((_, respond)) = commands.recv().await {
+                _ = respond.send(Response::Done);
+            }
+        });
+
+        let daemon = Daemon {
+            tenant,
+            server_config: None,
+            reloading: Default::default(),
+            #[cfg(feature = "p2p")]
+            peers,
+        };
+        let max_crate_size = || daemon.tenant.registry().config.limits.max_crate_size;
+        assert_eq!(None, max_crate_size());
+
+        let limited = format!("{config}[limits]\nmax-crate-size = 1024\n");
+        fs::write(&config_path, limited).unwrap();
+        reload(&daemon, Trigger::Request).await.unwrap();
+        assert_eq!(Some(1024), max_crate_size());
+
+        fs::write(&config_path, "version = \"1\"\n").unwrap();
+        assert!(reload(&daemon, Trigger::Request).await.is_err());
+        assert_eq!(Some(1024), max_crate_size());
+
+        let reloads = daemon.tenant.registry().audit_log().unwrap();
+        assert_eq!(1, reloads.len());
+    }
+}
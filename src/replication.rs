@@ -0,0 +1,666 @@
+//! Keeping a standby registry in step with a primary.
+//!
+//! The primary needs nothing beyond `margo serve`: `GET /api/v1/audit`
+//! pages through its audit log. `margo replicate run` on the standby
+//! replays each entry after the last one it applied, fetching any files
+//! the change needs from the primary, and records its position in
+//! `replication.json`. That file is also what failover looks at: how
+//! far behind the primary the standby is, and whether it has been
+//! promoted, after which it no longer follows anyone.
+//!
+//! Only registry changes are replayed. An entry that can no longer be
+//! replayed because a later change undid it on the primary, such as an
+//! added version that was removed again, is skipped; the later entry
+//! brings the standby to the same state.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    io::Read,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use url::Url;
+
+use crate::{
+    artifact, attestation, audit, blob, common::CrateName, quarantine, timestamp::Timestamp,
+    AddError, ConflictError, Global, ParseIndexError, QuarantineError, Registry, RemoveError,
+    VisibilityError, YankError,
+};
+
+pub const FILE_NAME: &str = "replication.json";
+
+/// The path of the primary's audit log endpoint.
+pub const AUDIT_PATH: &str = "api/v1/audit";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const USER_AGENT: &str = concat!("margo/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    pub primary: Url,
+
+    /// The sequence number of the last entry of the primary's audit log
+    /// that was applied here.
+    pub applied_seq: u64,
+
+    /// When the primary made that change.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied_time: Option<Timestamp>,
+
+    /// The newest entry of the primary's log at the last check.
+    pub primary_seq: u64,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checked_at: Option<Timestamp>,
+
+    /// Set once the standby has taken over from the primary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub promoted_at: Option<Timestamp>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Lag {
+    /// Changes the primary has made that are not applied here.
+    pub entries: u64,
+
+    /// How long before the last check the newest applied change was
+    /// made, or 0 when nothing is outstanding.
+    pub seconds: u64,
+}
+
+impl State {
+    /// `None` when the registry has never replicated.
+    pub fn load(path: &Path) -> Result<Option<Self>, Error> {
+        use error::*;
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(ReadStateSnafu { path }),
+        };
+
+        serde_json::from_slice(&data)
+            .context(ParseStateSnafu { path })
+            .map(Some)
+    }
+
+    /// Resumes replicating from `primary`, which must be the primary
+    /// the standby followed before.
+    pub fn follow(path: &Path, primary: Url) -> Result<Self, Error> {
+        use error::*;
+
+        let Some(state) = Self::load(path)? else {
+            return Ok(Self {
+                primary,
+                applied_seq: 0,
+                applied_time: None,
+                primary_seq: 0,
+                checked_at: None,
+                promoted_at: None,
+            });
+        };
+
+        ensure!(
+            state.promoted_at.is_none(),
+            PromotedSnafu {
+                primary: state.primary
+            }
+        );
+        ensure!(
+            state.primary == primary,
+            OtherPrimarySnafu {
+                primary: state.primary
+            }
+        );
+
+        Ok(state)
+    }
+
+    /// Written to a temporary file first, so an interruption never
+    /// leaves a truncated state behind.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        use error::*;
+
+        let data = serde_json::to_vec_pretty(self).context(SerializeStateSnafu)?;
+
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, data).context(WriteStateSnafu { path: &tmp })?;
+        fs::rename(&tmp, path).context(WriteStateSnafu { path })
+    }
+
+    pub fn lag(&self) -> Lag {
+        let entries = self.primary_seq.saturating_sub(self.applied_seq);
+        let seconds = match (entries, self.checked_at, self.applied_time) {
+            (0, _, _) => 0,
+            (_, Some(checked), Some(applied)) => checked.0.saturating_sub(applied.0),
+            // Nothing applied yet; the lag is the whole history
+            (_, Some(checked), None) => checked.0,
+            (_, None, _) => 0,
+        };
+
+        Lag { entries, seconds }
+    }
+}
+
+#[derive(Debug)]
+pub struct Primary {
+    agent: ureq::Agent,
+    base_url: Url,
+    token: Option<String>,
+}
+
+impl Primary {
+    pub fn new(base_url: Url, token: Option<String>) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(USER_AGENT)
+            .build();
+
+        Self {
+            agent,
+            base_url,
+            token,
+        }
+    }
+
+    pub fn audit_after(&self, seq: u64) -> Result<audit::Page, Error> {
+        use error::*;
+
+        let mut url = self.base_url.join(AUDIT_PATH).context(UrlSnafu)?;
+        url.query_pairs_mut().append_pair("after", &seq.to_string());
+
+        let data = self
+            .get(&url)?
+            .context(NoAuditLogSnafu { url: url.clone() })?;
+
+        serde_json::from_slice(&data).context(ParseAuditLogSnafu { url })
+    }
+
+    /// Fetches the primary's copy of a file of the local registry, which
+    /// has the same layout. `None` when the primary no longer has it.
+    fn file(&self, registry: &Registry, path: &Path) -> Result<Option<Vec<u8>>, Error> {
+        use error::*;
+
+        let relative = path.strip_prefix(&registry.path).unwrap_or(path);
+        let segments = relative
+            .components()
+            .map(|c| match c {
+                Component::Normal(s) => s.to_str(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .context(PathSnafu { path })?;
+
+        let url = self.base_url.join(&segments.join("/")).context(UrlSnafu)?;
+
+        self.get(&url)
+    }
+
+    /// `None` when the primary answers 404.
+    fn get(&self, url: &Url) -> Result<Option<Vec<u8>>, Error> {
+        use error::*;
+
+        let mut request = self.agent.request_url("GET", url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", token);
+        }
+
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(e).context(RequestSnafu { url: url.clone() }),
+        };
+
+        let mut data = vec![];
+        response
+            .into_reader()
+            .read_to_end(&mut data)
+            .context(ReadBodySnafu { url: url.clone() })?;
+
+        Ok(Some(data))
+    }
+}
+
+/// Applies every change the primary has made since the last one applied
+/// here, saving the state after each. Returns how many were applied.
+pub fn catch_up(
+    registry: &Registry,
+    global: &Global,
+    primary: &Primary,
+    state: &mut State,
+    path: &Path,
+) -> Result<u64, Error> {
+    use error::*;
+
+    let mut applied = 0;
+    loop {
+        let page = primary.audit_after(state.applied_seq)?;
+        ensure!(
+            page.head >= state.applied_seq,
+            DivergedSnafu {
+                applied: state.applied_seq,
+                head: page.head,
+            }
+        );
+
+        state.primary_seq = page.head;
+        state.checked_at = Some(Timestamp::now());
+
+        if page.entries.is_empty() {
+            state.save(path)?;
+            return Ok(applied);
+        }
+
+        for entry in page.entries {
+            apply(registry, global, primary, &entry.event)?;
+
+            state.applied_seq = entry.seq;
+            state.applied_time = Some(entry.time);
+            state.save(path)?;
+            applied += 1;
+        }
+    }
+}
+
+fn apply(
+    registry: &Registry,
+    global: &Global,
+    primary: &Primary,
+    event: &audit::Event,
+) -> Result<(), Error> {
+    use audit::Event;
+    use error::*;
+
+    match event {
+        Event::Add { name, vers, cksum } => {
+            let path = registry.crate_file_path_for(name, vers);
+            let Some(data) = fetch_checked(registry, primary, &path, cksum)? else {
+                return Ok(());
+            };
+            registry.add_package(global, &data).context(AddSnafu)?;
+            registry.maybe_build_docs(name, vers);
+        }
+
+        Event::Remove { name, vers } => {
+            registry
+                .remove(name.clone(), vers.clone())
+                .context(RemoveSnafu)?;
+        }
+
+        Event::Yank { name, vers } | Event::Unyank { name, vers } => {
+            if !has_version(registry, name, vers)? {
+                println!("Skipping {event:?}, which no longer applies");
+                return Ok(());
+            }
+            let yanked = matches!(event, Event::Yank { .. });
+            registry
+                .yank(name.clone(), vers.clone(), yanked)
+                .context(YankSnafu)?;
+        }
+
+        Event::Attest {
+            name, vers, digest, ..
+        } => {
+            ensure!(blob::is_digest(digest), DigestSnafu { digest });
+            let path = registry
+                .attestations_dir_for(name, vers)
+                .join(format!("{digest}.json"));
+            let Some(data) = fetch_checked(registry, primary, &path, digest)? else {
+                return Ok(());
+            };
+            attestation::attach(registry, name, vers, &data).context(AttestationSnafu)?;
+        }
+
+        Event::AddArtifact {
+            name,
+            vers,
+            target,
+            file,
+            cksum,
+        } => {
+            let path = registry
+                .artifacts_dir_for(name, vers)
+                .join(target)
+                .join(file);
+            let Some(data) = fetch_checked(registry, primary, &path, cksum)? else {
+                return Ok(());
+            };
+            artifact::add(registry, name, vers, target, file, &data).context(ArtifactSnafu)?;
+        }
+
+        Event::RemoveArtifact {
+            name,
+            vers,
+            target,
+            file,
+        } => match artifact::remove(registry, name, vers, target, file) {
+            Ok(_) | Err(artifact::Error::Unknown { .. }) => {}
+            Err(e) => return Err(e).context(ArtifactSnafu),
+        },
+
+        Event::PutBlob { digest, .. } => {
+            ensure!(blob::is_digest(digest), DigestSnafu { digest });
+            let path = blob::path_in(&registry.blobs_dir(), digest);
+            let Some(data) = fetch_checked(registry, primary, &path, digest)? else {
+                return Ok(());
+            };
+            blob::put(registry, &data).context(BlobSnafu)?;
+        }
+
+        Event::RemoveBlob { digest } => match blob::remove(registry, digest) {
+            Ok(()) | Err(blob::Error::Unknown { .. }) => {}
+            Err(e) => return Err(e).context(BlobSnafu),
+        },
+
+        Event::RestoreSnapshot { name, .. } => return RestoredSnafu { name }.fail(),
+
+        Event::Conflict {
+            name,
+            vers,
+            checksums,
+        } => {
+            registry
+                .record_conflict(name.clone(), vers.clone(), checksums.clone())
+                .context(ConflictSnafu)?;
+        }
+
+        Event::ResolveConflict {
+            name,
+            vers,
+            removed,
+        } => {
+            registry
+                .resolve_conflict(name.clone(), vers.clone(), *removed)
+                .context(ConflictSnafu)?;
+        }
+
+        Event::Quarantine { name, vers, reason } => {
+            if !has_version(registry, name, vers)? {
+                println!("Skipping {event:?}, which no longer applies");
+                return Ok(());
+            }
+            registry
+                .quarantine(name.clone(), vers.clone(), reason.clone())
+                .context(QuarantineSnafu)?;
+        }
+
+        Event::Release { name, vers } => match registry.release(name.clone(), vers.clone()) {
+            Ok(())
+            | Err(QuarantineError::Quarantine {
+                source: quarantine::Error::Unknown { .. },
+            }) => {}
+            Err(e) => return Err(e).context(QuarantineSnafu),
+        },
+
+        Event::SetVisibility { name, visibility } => {
+            match registry.set_visibility(name.clone(), *visibility) {
+                Ok(()) => {}
+                Err(VisibilityError::NotFound { .. }) => {
+                    println!("Skipping {event:?}, which no longer applies");
+                }
+                Err(e) => return Err(e).context(VisibilitySnafu),
+            }
+        }
+
+        // Not a change to the registry
+        Event::Approve { .. }
+        | Event::AuthLockout { .. }
+        | Event::ReloadConfig { .. }
+        | Event::RotateKey { .. }
+        | Event::Authorize { .. } => {}
+    }
+
+    Ok(())
+}
+
+/// Fetches the primary's copy of the file and checks its SHA-256.
+/// `None`, after saying so, when a later change removed it from the
+/// primary.
+fn fetch_checked(
+    registry: &Registry,
+    primary: &Primary,
+    path: &Path,
+    cksum: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    use error::*;
+
+    let Some(data) = primary.file(registry, path)? else {
+        println!(
+            "Skipping {}, which the primary no longer has",
+            path.display()
+        );
+        return Ok(None);
+    };
+
+    let actual = blob::digest_of(&data);
+    ensure!(
+        actual.eq_ignore_ascii_case(cksum),
+        ChecksumSnafu {
+            path,
+            expected: cksum,
+            actual,
+        }
+    );
+
+    Ok(Some(data))
+}
+
+fn has_version(registry: &Registry, name: &CrateName, vers: &Version) -> Result<bool, Error> {
+    use error::*;
+
+    let path = registry.index_file_path_for(name);
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    let index = Registry::parse_index_file(&path).context(IndexSnafu)?;
+    Ok(index.contains_key(vers))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the replication state at {}", path.display()))]
+    ReadState { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the replication state at {}", path.display()))]
+    ParseState {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the replication state"))]
+    SerializeState { source: serde_json::Error },
+
+    #[snafu(display("Could not write the replication state to {}", path.display()))]
+    WriteState { source: io::Error, path: PathBuf },
+
+    #[snafu(display("This registry was promoted and no longer follows {primary}"))]
+    Promoted { primary: Url },
+
+    #[snafu(display(
+        "This registry follows {primary}; remove the replication state to follow another"
+    ))]
+    OtherPrimary { primary: Url },
+
+    #[snafu(display("This registry is not a standby"))]
+    NotStandby,
+
+    #[snafu(display("Could not build the primary's URL"))]
+    Url { source: url::ParseError },
+
+    #[snafu(display("{} cannot be fetched from the primary", path.display()))]
+    Path { path: PathBuf },
+
+    #[snafu(display("Could not fetch {url} from the primary"))]
+    Request {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: Url,
+    },
+
+    #[snafu(display("Could not read {url} from the primary"))]
+    ReadBody { source: io::Error, url: Url },
+
+    #[snafu(display("The primary does not serve its audit log at {url}"))]
+    NoAuditLog { url: Url },
+
+    #[snafu(display("Could not parse the primary's audit log from {url}"))]
+    ParseAuditLog { source: serde_json::Error, url: Url },
+
+    #[snafu(display(
+        "The primary's audit log ends at {head}, before the {applied} entries already applied; seed the standby again"
+    ))]
+    Diverged { applied: u64, head: u64 },
+
+    #[snafu(display(
+        "The primary was rolled back to its snapshot `{name}`; seed the standby again"
+    ))]
+    Restored { name: String },
+
+    #[snafu(display("The primary's audit log names `{digest}`, which is not a SHA-256 digest"))]
+    Digest { digest: String },
+
+    #[snafu(display("The primary's copy of {} has the checksum {actual}, not {expected}", path.display()))]
+    Checksum {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: ParseIndexError },
+
+    #[snafu(display("Could not add the crate"))]
+    Add { source: AddError },
+
+    #[snafu(display("Could not remove the crate"))]
+    Remove { source: RemoveError },
+
+    #[snafu(display("Could not yank or unyank the crate"))]
+    Yank { source: YankError },
+
+    #[snafu(display("Could not replay the checksum conflict"))]
+    Conflict { source: ConflictError },
+
+    #[snafu(display("Could not replay the quarantine"))]
+    Quarantine { source: QuarantineError },
+
+    #[snafu(display("Could not replay the visibility change"))]
+    Visibility { source: VisibilityError },
+
+    #[snafu(display("Could not replay the attestation"))]
+    Attestation { source: attestation::Error },
+
+    #[snafu(display("Could not replay the artifact"))]
+    Artifact { source: artifact::Error },
+
+    #[snafu(display("Could not replay the blob"))]
+    Blob { source: blob::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ReadState { .. } => "E_STORAGE_READ",
+            Self::ParseState { .. } => "E_REPLICATION_CORRUPT",
+            Self::SerializeState { .. } | Self::WriteState { .. } => "E_STORAGE_WRITE",
+            Self::Promoted { .. } | Self::OtherPrimary { .. } | Self::NotStandby => "E_NOT_STANDBY",
+            Self::Url { .. }
+            | Self::Path { .. }
+            | Self::Request { .. }
+            | Self::ReadBody { .. }
+            | Self::NoAuditLog { .. }
+            | Self::ParseAuditLog { .. } => "E_UPSTREAM",
+            Self::Diverged { .. } | Self::Restored { .. } => "E_REPLICATION_DIVERGED",
+            Self::Digest { .. } => "E_BAD_DIGEST",
+            Self::Checksum { .. } => "E_BAD_CHECKSUM",
+            Self::Index { .. } => "E_INDEX_CORRUPT",
+            Self::Add { source } => source.code(),
+            Self::Remove { source } => source.code(),
+            Self::Yank { source } => source.code(),
+            Self::Conflict { source } => source.code(),
+            Self::Quarantine { source } => source.code(),
+            Self::Visibility { source } => source.code(),
+            Self::Attestation { source } => source.code(),
+            Self::Artifact { source } => source.code(),
+            Self::Blob { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lag_counts_unapplied_changes() {
+        let mut state = State {
+            primary: "https://primary.example.com/".parse().unwrap(),
+            applied_seq: 0,
+            applied_time: None,
+            primary_seq: 0,
+            checked_at: None,
+            promoted_at: None,
+        };
+        assert_eq!(
+            Lag {
+                entries: 0,
+                seconds: 0
+            },
+            state.lag()
+        );
+
+        state.applied_seq = 7;
+        state.applied_time = Some(Timestamp(1_000));
+        state.primary_seq = 10;
+        state.checked_at = Some(Timestamp(1_090));
+        assert_eq!(
+            Lag {
+                entries: 3,
+                seconds: 90
+            },
+            state.lag()
+        );
+
+        state.applied_seq = 10;
+        assert_eq!(0, state.lag().seconds);
+    }
+
+    #[test]
+    fn digests_from_the_primary_are_checked_before_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let config = "version = \"1\"\nformat = 3\nbase_url = \"http://example.com/\"\n";
+        fs::write(dir.join(crate::CONFIG_FILE_NAME), config).unwrap();
+        let registry = Registry::open(dir).unwrap();
+        let global = Global::new().unwrap();
+        // Never contacted: the digests are refused first
+        let primary = Primary::new("http://127.0.0.1:9/".parse().unwrap(), None);
+
+        // 64 bytes, but not ASCII
+        let accented = "é".repeat(32);
+        for digest in ["", "ab", "../../config", &accented] {
+            let events = [
+                audit::Event::PutBlob {
+                    digest: digest.to_owned(),
+                    size: 0,
+                },
+                audit::Event::Attest {
+                    name: "demo".parse().unwrap(),
+                    vers: "1.0.0".parse().unwrap(),
+                    digest: digest.to_owned(),
+                    predicate_type: "https://slsa.dev/provenance/v1".to_owned(),
+                },
+            ];
+            for event in &events {
+                let e = apply(&registry, &global, &primary, event).unwrap_err();
+                assert_eq!("E_BAD_DIGEST", e.code(), "{event:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Checking that the files margo generates follow from its inputs.
+//!
+//! Index files, `index.html`, the feed and index snapshots depend only
+//! on the `.crate` files added, the audit log and the configuration:
+//! entries, crates and tarball members are written in sorted order,
+//! and the only clock reading, the time recorded in snapshots, comes
+//! from `SOURCE_DATE_EPOCH` when it is set. Audit log entries always
+//! record the real time; the log is an input, not a generated file.
+//! `margo verify --check-reproducible` regenerates each of them in
+//! memory and compares the bytes with what is on disk, so a signed
+//! snapshot of the registry says something about its inputs.
+//!
+//! Registries that encrypt crates are the exception: each encryption
+//! uses a fresh nonce, so the stored `.crate` files, and the checksums
+//! of them in the index, differ every time.
+
+use snafu::prelude::*;
+use std::{fmt, io, path::PathBuf};
+
+use crate::{read_if_exists, ListIndexFilesError, ParseIndexError, Registry, WriteIndexError};
+
+#[derive(Debug)]
+pub enum Difference {
+    /// The file on disk is not what margo would write.
+    File { path: PathBuf },
+
+    /// Building the index snapshot twice gave different bytes.
+    Snapshot,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File { path } => write!(
+                f,
+                "{} differs from what margo generates from the same inputs",
+                path.display(),
+            ),
+            Self::Snapshot => write!(f, "The index snapshot is not the same when built twice"),
+        }
+    }
+}
+
+/// Regenerates the registry's files and returns those that differ.
+pub fn check(registry: &Registry) -> Result<Vec<Difference>, Error> {
+    use error::*;
+
+    let mut differences = vec![];
+    let mut compare = |path: PathBuf, expected: &[u8]| -> Result<(), Error> {
+        let actual = read_if_exists(&path).context(ReadSnafu { path: &path })?;
+        if actual.as_deref() != Some(expected) {
+            differences.push(Difference::File { path });
+        }
+        Ok(())
+    };
+
+    for path in registry.list_index_files().context(ListSnafu)? {
+        let index = Registry::parse_index_file(&path).context(ParseSnafu { path: &path })?;
+        let mut expected = vec![];
+        Registry::serialize_index(&index, &mut expected).context(SerializeSnafu { path: &path })?;
+        compare(path, &expected)?;
+    }
+
+    #[cfg(feature = "html")]
+    if registry.config.html.enabled {
+        let index = crate::html::rendered_index(registry).context(HtmlSnafu)?;
+        compare(registry.path.join("index.html"), index.as_bytes())?;
+    }
+
+    if registry.config.feed.enabled {
+        let feed = crate::feed::rendered(registry).context(FeedSnafu)?;
+        compare(registry.path.join(crate::feed::FILE_NAME), feed.as_bytes())?;
+    }
+
+    #[cfg(feature = "server")]
+    {
+        use crate::{snapshot, timestamp::Timestamp};
+
+        let created_at = Timestamp::generated();
+        let first = snapshot::build_at(registry, created_at).context(SnapshotSnafu)?;
+        let second = snapshot::build_at(registry, created_at).context(SnapshotSnafu)?;
+        if first != second {
+            differences.push(Difference::Snapshot);
+        }
+    }
+
+    Ok(differences)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not list the index files"))]
+    List { source: ListIndexFilesError },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the index file {}", path.display()))]
+    Parse {
+        source: ParseIndexError,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the index file {}", path.display()))]
+    Serialize {
+        source: WriteIndexError,
+        path: PathBuf,
+    },
+
+    #[cfg(feature = "html")]
+    #[snafu(display("Could not render the HTML index page"))]
+    Html { source: crate::html::Error },
+
+    #[snafu(display("Could not render the feed"))]
+    Feed { source: crate::feed::Error },
+
+    #[cfg(feature = "server")]
+    #[snafu(display("Could not build the index snapshot"))]
+    Snapshot { source: crate::snapshot::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::List { .. } | Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_INDEX_CORRUPT",
+            Self::Serialize { .. } => "E_INDEX_WRITE",
+            #[cfg(feature = "html")]
+            Self::Html { source } => source.code(),
+            Self::Feed { source } => source.code(),
+            #[cfg(feature = "server")]
+            Self::Snapshot { source } => source.code(),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Include and exclude rules deciding which crates are mirrored.
+//!
+//! A rule is a crate name glob such as `tokio*`, `owner:LOGIN` for
+//! crates with that owner, or `pubkey:HEX` for crates from a registry
+//! announced with that nostr public key. Rules are checked before
+//! anything is downloaded. Excludes win over includes, and with no
+//! includes everything not excluded is allowed.
+
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::str::FromStr;
+
+use crate::common::glob_matches;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Rule {
+    Name(String),
+    Owner(String),
+    Pubkey(String),
+}
+
+impl Rule {
+    fn matches(&self, subject: &Subject<'_>) -> bool {
+        match self {
+            Self::Name(glob) => glob_matches(glob, subject.name),
+            Self::Owner(owner) => subject.owners.iter().any(|o| o.eq_ignore_ascii_case(owner)),
+            Self::Pubkey(pubkey) => subject
+                .pubkey
+                .is_some_and(|p| p.eq_ignore_ascii_case(pubkey)),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = RuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use rule_error::*;
+
+        let rule = if let Some(owner) = s.strip_prefix("owner:") {
+            ensure!(!owner.is_empty(), InvalidSnafu { rule: s });
+            Self::Owner(owner.to_owned())
+        } else if let Some(pubkey) = s.strip_prefix("pubkey:") {
+            let valid = pubkey.len() == 64 && pubkey.chars().all(|c| c.is_ascii_hexdigit());
+            ensure!(valid, InvalidSnafu { rule: s });
+            Self::Pubkey(pubkey.to_ascii_lowercase())
+        } else {
+            let valid = !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '*'));
+            ensure!(valid, InvalidSnafu { rule: s });
+            Self::Name(s.to_owned())
+        };
+
+        Ok(rule)
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = RuleError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum RuleError {
+    #[snafu(display("`{rule}` is not a crate name glob, `owner:LOGIN`, or `pubkey:HEX` rule"))]
+    Invalid { rule: String },
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Rules {
+    #[serde(default)]
+    pub include: Vec<Rule>,
+
+    #[serde(default)]
+    pub exclude: Vec<Rule>,
+}
+
+/// What the rules are checked against. Owners and the public key are
+/// only needed when a rule refers to them.
+#[derive(Debug, Default)]
+pub struct Subject<'a> {
+    pub name: &'a str,
+    pub owners: &'a [String],
+    pub pubkey: Option<&'a str>,
+}
+
+impl Rules {
+    pub fn allows(&self, subject: &Subject<'_>) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|r| r.matches(subject));
+        let excluded = self.exclude.iter().any(|r| r.matches(subject));
+        included && !excluded
+    }
+
+    #[cfg(feature = "sync-crates-io")]
+    pub fn needs_owners(&self) -> bool {
+        self.all().any(|r| matches!(r, Rule::Owner(_)))
+    }
+
+    #[cfg(feature = "proxy")]
+    pub fn needs_pubkey(&self) -> bool {
+        self.all().any(|r| matches!(r, Rule::Pubkey(_)))
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Rule> {
+        self.include.iter().chain(&self.exclude)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rules(include: &[&str], exclude: &[&str]) -> Rules {
+        Rules {
+            include: include.iter().map(|r| r.parse().unwrap()).collect(),
+            exclude: exclude.iter().map(|r| r.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let rules = rules(&["tokio*", "owner:alice"], &["tokio-test"]);
+
+        let named = |name| Subject {
+            name,
+            ..Default::default()
+        };
+        assert!(rules.allows(&named("tokio")));
+        assert!(rules.allows(&named("tokio-util")));
+        assert!(!rules.allows(&named("tokio-test")));
+        assert!(!rules.allows(&named("serde")));
+
+        let owners = ["Alice".to_owned()];
+        assert!(rules.allows(&Subject {
+            name: "serde",
+            owners: &owners,
+            pubkey: None,
+        }));
+
+        assert!(Rules::default().allows(&named("anything")));
+    }
+
+    #[test]
+    fn rules_are_parsed() {
+        assert_eq!(Rule::Name("tokio*".to_owned()), "tokio*".parse().unwrap());
+        assert_eq!(
+            Rule::Owner("alice".to_owned()),
+            "owner:alice".parse().unwrap()
+        );
+        assert!("pubkey:abc".parse::<Rule>().is_err());
+        assert!("tokio/*".parse::<Rule>().is_err());
+        assert!("owner:".parse::<Rule>().is_err());
+    }
+}
@@ -0,0 +1,466 @@
+//! Running tools over crates that may be hostile.
+//!
+//! Building documentation runs a crate's build script and procedural
+//! macros, and external scanners read whatever the publisher packed.
+//! Each such tool runs in a [`Sandbox`]: with an empty environment, a
+//! home and temporary directory inside one scratch directory, the
+//! resource limits from `[sandbox]` in `margo-config.toml`, and a
+//! deadline after which it is killed along with every process it
+//! started.
+//!
+//! Where the operating system offers more, it is used: on Linux with
+//! [bubblewrap](https://github.com/containers/bubblewrap) installed,
+//! the tool has no network, sees only the system directories, the
+//! paths it was given to read and the scratch directory, and can only
+//! write to the last. On macOS, `sandbox-exec` takes away the network
+//! and writes outside the scratch directory. Elsewhere only the limits
+//! apply, unless `required = true`, which refuses to run tools at all.
+//!
+//! ```toml
+//! [sandbox]
+//! required = true
+//! cpu-seconds = 600
+//! wall-seconds = 1200
+//! memory-mb = 8192
+//! file-size-mb = 1024
+//! read-only = ["/var/lib/clamav"]
+//! ```
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_CPU_SECONDS: u64 = 600;
+const DEFAULT_WALL_SECONDS: u64 = 1200;
+const DEFAULT_MEMORY_MB: u64 = 8192;
+const DEFAULT_FILE_SIZE_MB: u64 = 1024;
+
+/// The system directories a tool may read under bubblewrap.
+#[cfg(target_os = "linux")]
+const SYSTEM_DIRS: &[&str] = &[
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/etc/alternatives",
+    "/etc/ld.so.cache",
+    "/etc/ld.so.conf",
+    "/etc/ld.so.conf.d",
+];
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Policy {
+    /// Refuse to run tools where no operating system sandbox is
+    /// available, rather than running them with only the limits.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    required: bool,
+
+    /// CPU time each process may use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpu_seconds: Option<u64>,
+
+    /// How long a tool may run before it is killed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wall_seconds: Option<u64>,
+
+    /// Address space each process may map.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memory_mb: Option<u64>,
+
+    /// The largest file a process may write.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_size_mb: Option<u64>,
+
+    /// More paths tools may read, such as a scanner's database.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    read_only: Vec<PathBuf>,
+}
+
+impl Policy {
+    fn wall_time(&self) -> Duration {
+        Duration::from_secs(self.wall_seconds.unwrap_or(DEFAULT_WALL_SECONDS))
+    }
+
+    /// `ulimit` calls, for a POSIX shell, which counts memory in KiB
+    /// and file sizes in 512 byte blocks, and may set only one limit a
+    /// call.
+    #[cfg(unix)]
+    fn ulimit(&self) -> String {
+        let cpu = self.cpu_seconds.unwrap_or(DEFAULT_CPU_SECONDS);
+        let memory = self.memory_mb.unwrap_or(DEFAULT_MEMORY_MB) * 1024;
+        let file_size = self.file_size_mb.unwrap_or(DEFAULT_FILE_SIZE_MB) * 2048;
+        format!("ulimit -t {cpu}; ulimit -v {memory}; ulimit -f {file_size}")
+    }
+}
+
+/// What confines a tool beyond its limits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Bubblewrap(PathBuf),
+    SandboxExec(PathBuf),
+    None,
+}
+
+impl Backend {
+    pub fn detect() -> Self {
+        if cfg!(target_os = "linux") {
+            if let Some(path) = find_program("bwrap") {
+                return Self::Bubblewrap(path);
+            }
+        }
+
+        if cfg!(target_os = "macos") {
+            let path = Path::new("/usr/bin/sandbox-exec");
+            if path.is_file() {
+                return Self::SandboxExec(path.to_owned());
+            }
+        }
+
+        Self::None
+    }
+}
+
+/// The place tools run, rooted at a scratch directory that is the only
+/// one they may write to.
+#[derive(Debug)]
+pub struct Sandbox<'a> {
+    policy: &'a Policy,
+    backend: Backend,
+    root: PathBuf,
+    read_only: Vec<PathBuf>,
+    hidden: Vec<PathBuf>,
+    envs: Vec<(OsString, OsString)>,
+}
+
+impl<'a> Sandbox<'a> {
+    /// `root` must exist. Fails if the policy requires an operating
+    /// system sandbox and there is none.
+    pub fn new(policy: &'a Policy, root: &Path) -> Result<Self, Error> {
+        Self::with_backend(policy, root, Backend::detect())
+    }
+
+    fn with_backend(policy: &'a Policy, root: &Path, backend: Backend) -> Result<Self, Error> {
+        use error::*;
+
+        ensure!(
+            !policy.required || backend != Backend::None,
+            UnavailableSnafu
+        );
+
+        // `sandbox-exec` matches the paths files are really at
+        let root = root.canonicalize().context(RootSnafu { path: root })?;
+
+        let mut sandbox = Self {
+            policy,
+            backend,
+            root,
+            read_only: vec![],
+            hidden: vec![],
+            envs: vec![],
+        };
+        for path in &policy.read_only {
+            sandbox.read_only(path);
+        }
+
+        Ok(sandbox)
+    }
+
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    /// Lets tools read `path`, such as a toolchain. Paths that do not
+    /// exist are skipped.
+    pub fn read_only(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        let path = path.into();
+        if let Ok(path) = path.canonicalize() {
+            self.read_only.push(path);
+        }
+        self
+    }
+
+    /// Keeps tools from reading `path`, such as credentials beside a
+    /// toolchain, even inside a path they may read. Paths that do not
+    /// exist are skipped. Without an operating system sandbox, nothing
+    /// is hidden.
+    pub fn hide(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        let path = path.into();
+        if let Ok(path) = path.canonicalize() {
+            self.hidden.push(path);
+        }
+        self
+    }
+
+    /// Sets an environment variable for tools, which otherwise only
+    /// get `PATH`, `HOME` and `TMPDIR`.
+    pub fn env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
+        self.envs
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
+    }
+
+    /// A command that runs `program` in `dir`, which must be inside
+    /// the scratch directory. Add arguments to it as usual and run it
+    /// with [`Sandbox::run`].
+    pub fn command(&self, program: &Path, dir: &Path) -> Command {
+        let home = self.root.join("home");
+        let tmp = self.root.join("tmp");
+        _ = std::fs::create_dir_all(&home);
+        _ = std::fs::create_dir_all(&tmp);
+        let dir = &dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+
+        let mut path = program
+            .parent()
+            .map(|p| vec![p.to_owned()])
+            .unwrap_or_default();
+        path.extend(["/usr/bin", "/bin"].map(PathBuf::from));
+        let path = env::join_paths(path).unwrap_or_default();
+
+        let mut command = match &self.backend {
+            Backend::Bubblewrap(bwrap) => self.bubblewrap(bwrap, dir),
+            Backend::SandboxExec(sandbox_exec) => self.sandbox_exec(sandbox_exec, dir),
+            Backend::None => {
+                let shell = if cfg!(unix) { Path::new("sh") } else { program };
+                let mut command = Command::new(shell);
+                command.current_dir(dir);
+                command
+            }
+        };
+
+        // The limits are set by the shell, which then becomes the tool.
+        // `$0` names the script; the rest is the tool and the arguments
+        // added later.
+        #[cfg(unix)]
+        {
+            let script = format!("{}; exec \"$@\"", self.policy.ulimit());
+            command.args(["-c", &script, "margo-sandbox"]);
+            command.arg(program);
+        }
+
+        command
+            .env_clear()
+            .env("PATH", path)
+            .env("HOME", home)
+            .env("TMPDIR", tmp)
+            .envs(
+                self.envs
+                    .iter()
+                    .map(|(k, v)| (k.as_os_str(), v.as_os_str())),
+            );
+
+        command
+    }
+
+    fn bubblewrap(&self, bwrap: &Path, dir: &Path) -> Command {
+        let mut command = Command::new(bwrap);
+        command.args(["--unshare-all", "--die-with-parent", "--new-session"]);
+
+        #[cfg(target_os = "linux")]
+        for system_dir in SYSTEM_DIRS {
+            command.args(["--ro-bind-try", system_dir, system_dir]);
+        }
+
+        command.args(["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"]);
+        for path in &self.read_only {
+            command.arg("--ro-bind").arg(path).arg(path);
+        }
+        for path in &self.hidden {
+            if !self.read_only.iter().any(|p| path.starts_with(p)) {
+                continue;
+            }
+            if path.is_dir() {
+                command.arg("--tmpfs").arg(path);
+            } else {
+                command.args(["--ro-bind", "/dev/null"]).arg(path);
+            }
+        }
+        command.arg("--bind").arg(&self.root).arg(&self.root);
+        command.arg("--chdir").arg(dir);
+        command.args(["--", "sh"]);
+        command
+    }
+
+    fn sandbox_exec(&self, sandbox_exec: &Path, dir: &Path) -> Command {
+        const PROFILE: &str = r#"
+            (version 1)
+            (allow default)
+            (deny network*)
+            (deny file-write*)
+            (allow file-write* (subpath (param "ROOT")) (literal "/dev/null"))
+        "#;
+
+        let mut root = OsString::from("ROOT=");
+        root.push(&self.root);
+
+        let mut profile = PROFILE.to_owned();
+        for path in &self.hidden {
+            let path = path.to_string_lossy();
+            _ = writeln!(profile, "(deny file-read* (subpath {path:?}))");
+        }
+
+        let mut command = Command::new(sandbox_exec);
+        command.arg("-D").arg(root).arg("-p").arg(profile).arg("sh");
+        command.current_dir(dir);
+        command
+    }
+
+    /// Runs the command, killing it and whatever it started once the
+    /// policy's time is up.
+    pub fn run(&self, command: &mut Command) -> Result<ExitStatus, Error> {
+        use error::*;
+
+        // Its own process group, so that build scripts and the like go
+        // with it. Under bubblewrap, they are also in its own PID
+        // namespace, which ends when the process bubblewrap started does.
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(command, 0);
+
+        let program = command.get_program().to_owned();
+        let mut child = command.spawn().context(SpawnSnafu { program: &program })?;
+
+        let wall_time = self.policy.wall_time();
+        let started = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait().context(WaitSnafu)? {
+                return Ok(status);
+            }
+
+            if started.elapsed() > wall_time {
+                kill_group(&mut child);
+                _ = child.wait();
+                return TimeoutSnafu { wall_time }.fail();
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+#[cfg(unix)]
+fn kill_group(child: &mut Child) {
+    // The group was created with the child, which has not been waited
+    // for, so its ID has not been reused
+    let group = -(child.id() as libc::pid_t);
+    // SAFETY: `kill` only sends a signal
+    unsafe {
+        libc::kill(group, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) {
+    _ = child.kill();
+}
+
+/// The first `name` on `PATH`.
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    let name = format!("{name}{}", env::consts::EXE_SUFFIX);
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(&name))
+        .find(|path| path.is_file())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display(
+        "`[sandbox] required` is set, but neither bubblewrap nor sandbox-exec is available"
+    ))]
+    Unavailable,
+
+    #[snafu(display("Could not resolve the scratch directory {}", path.display()))]
+    Root { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not run {}", program.to_string_lossy()))]
+    Spawn {
+        source: io::Error,
+        program: OsString,
+    },
+
+    #[snafu(display("Could not wait for the sandboxed tool"))]
+    Wait { source: io::Error },
+
+    #[snafu(display("The sandboxed tool ran for more than {} seconds", wall_time.as_secs()))]
+    Timeout { wall_time: Duration },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Unavailable => "E_SANDBOX_UNAVAILABLE",
+            Self::Root { .. } => "E_STORAGE_WRITE",
+            Self::Spawn { .. } | Self::Wait { .. } => "E_SANDBOX_SPAWN",
+            Self::Timeout { .. } => "E_SANDBOX_TIMEOUT",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn a_required_sandbox_is_not_silently_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let policy = Policy {
+            required: true,
+            ..Policy::default()
+        };
+        let e = Sandbox::with_backend(&policy, dir, Backend::None).unwrap_err();
+        assert_eq!("E_SANDBOX_UNAVAILABLE", e.code());
+
+        let policy = Policy::default();
+        let sandbox = Sandbox::with_backend(&policy, dir, Backend::None).unwrap();
+        let command = sandbox.command(Path::new("/usr/bin/cargo"), dir);
+        let mut envs = command
+            .get_envs()
+            .map(|(k, _)| k.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        envs.sort();
+        assert_eq!(["HOME", "PATH", "TMPDIR"], *envs);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tools_run_with_limits_and_a_deadline() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let policy = Policy {
+            cpu_seconds: Some(7),
+            wall_seconds: Some(1),
+            ..Policy::default()
+        };
+        let sandbox = Sandbox::with_backend(&policy, dir, Backend::None).unwrap();
+        let sh = find_program("sh").unwrap();
+
+        let mut command = sandbox.command(&sh, dir);
+        command.args(["-c", "ulimit -t > limit"]);
+        assert!(sandbox.run(&mut command).unwrap().success());
+        assert_eq!("7", fs::read_to_string(dir.join("limit")).unwrap().trim());
+
+        let mut command = sandbox.command(&sh, dir);
+        command.args(["-c", "(sleep 3; touch survived) & sleep 10"]);
+        let e = sandbox.run(&mut command).unwrap_err();
+        assert_eq!("E_SANDBOX_TIMEOUT", e.code());
+
+        thread::sleep(Duration::from_secs(3));
+        assert!(!dir.join("survived").exists());
+    }
+}
@@ -0,0 +1,460 @@
+//! Red flags in crates as they arrive.
+//!
+//! With `[scan] enabled = true` in `margo-config.toml`, every crate
+//! published to the daemon or added by `margo add` or `margo sync` is
+//! unpacked in memory and each file is shown to every [`Scanner`]. A
+//! crate with any finding is held for review rather than served: a
+//! publish waits for approval in the publish queue, and a crate added
+//! from the command line is quarantined until it is released.
+//!
+//! The built-in scanners are heuristics and will have false positives;
+//! they only decide what a person looks at.
+//!
+//! Other tools can be added as external scanners. Each runs in a
+//! [sandbox](crate::sandbox) with the unpacked package directory as
+//! its last argument, and each line it prints is a finding, as is
+//! failing without printing anything:
+//!
+//! ```toml
+//! [scan]
+//! enabled = true
+//! commands = [["clamscan", "--infected", "--no-summary", "-r"]]
+//! ```
+
+use sha2::Digest;
+use snafu::prelude::*;
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    process::Stdio,
+};
+
+use crate::{extract, sandbox};
+
+/// Files are only read this far, so a tarball that decompresses to
+/// something huge cannot exhaust memory.
+const MAX_FILE_LEN: u64 = 16 * 1024 * 1024;
+
+/// The shortest run of base64 or hex characters that is reported as
+/// an encoded blob.
+const MIN_BLOB_LEN: usize = 1024;
+
+/// How much of an external scanner's output is read.
+const MAX_OUTPUT_LEN: u64 = 64 * 1024;
+
+/// Findings past this many from one external scanner are dropped.
+const MAX_EXTERNAL_FINDINGS: usize = 20;
+
+/// Calls and crates that suggest a build script reaches the network.
+const NETWORK_PATTERNS: &[&str] = &[
+    "std::net",
+    "TcpStream",
+    "UdpSocket",
+    "reqwest",
+    "ureq",
+    "curl",
+    "wget",
+    "Invoke-WebRequest",
+];
+
+/// Inspects crates for anything a person should look at before the
+/// crate is served. Implement it to add a check; each method defaults
+/// to finding nothing.
+pub trait Scanner: Send + Sync {
+    /// Shown with each finding.
+    fn name(&self) -> &'static str;
+
+    /// Looks at the whole `.crate` file.
+    fn scan_crate(&self, _crate_file: &[u8]) -> Vec<String> {
+        vec![]
+    }
+
+    /// Looks at one file of the package. `path` is relative to the
+    /// package's top-level directory.
+    fn scan_file(&self, _path: &Path, _contents: &[u8]) -> Vec<String> {
+        vec![]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub scanner: &'static str,
+    pub path: Option<PathBuf>,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.scanner)?;
+        if let Some(path) = &self.path {
+            write!(f, "{}: ", path.display())?;
+        }
+        self.message.fmt(f)
+    }
+}
+
+/// The scanners that ship with Margo.
+pub fn builtin(known_bad: &BTreeSet<String>) -> Vec<Box<dyn Scanner>> {
+    vec![
+        Box::new(BuildScriptNetwork),
+        Box::new(EncodedBlobs),
+        Box::new(KnownBad {
+            digests: known_bad.iter().map(|d| d.to_ascii_lowercase()).collect(),
+        }),
+    ]
+}
+
+/// Scanners that run the configured commands.
+pub fn external(
+    commands: &[Vec<String>],
+    policy: &sandbox::Policy,
+    limits: extract::Limits,
+) -> Vec<Box<dyn Scanner>> {
+    commands
+        .iter()
+        .filter(|command| !command.is_empty())
+        .map(|command| {
+            Box::new(External {
+                command: command.clone(),
+                policy: policy.clone(),
+                limits,
+            }) as Box<dyn Scanner>
+        })
+        .collect()
+}
+
+/// Unpacks the crate and runs every scanner over it.
+pub fn scan(scanners: &[Box<dyn Scanner>], crate_file: &[u8]) -> Result<Vec<Finding>, Error> {
+    use error::*;
+
+    let mut findings = vec![];
+    for scanner in scanners {
+        let found = scanner.scan_crate(crate_file).into_iter();
+        findings.extend(found.map(|message| Finding {
+            scanner: scanner.name(),
+            path: None,
+            message,
+        }));
+    }
+
+    let decoder = flate2::read::GzDecoder::new(crate_file);
+    let mut archive = tar::Archive::new(decoder);
+
+    for entry in archive.entries().context(EntriesSnafu)? {
+        let entry = entry.context(EntriesSnafu)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        // Relative to the package's top-level directory
+        let path = entry.path().context(EntriesSnafu)?;
+        let path = path
+            .components()
+            .skip(1)
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect::<PathBuf>();
+
+        let mut contents = vec![];
+        entry
+            .take(MAX_FILE_LEN)
+            .read_to_end(&mut contents)
+            .context(ReadSnafu { path: &path })?;
+
+        for scanner in scanners {
+            let found = scanner.scan_file(&path, &contents).into_iter();
+            findings.extend(found.map(|message| Finding {
+                scanner: scanner.name(),
+                path: Some(path.clone()),
+                message,
+            }));
+        }
+    }
+
+    Ok(findings)
+}
+
+/// One line summing up the findings, for queue entries and the audit
+/// log.
+pub fn summary(findings: &[Finding]) -> String {
+    let first = findings
+        .first()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+
+    match findings.len() {
+        0 | 1 => first,
+        n => format!("{first} (and {} more)", n - 1),
+    }
+}
+
+/// Build scripts that look like they download something.
+struct BuildScriptNetwork;
+
+impl Scanner for BuildScriptNetwork {
+    fn name(&self) -> &'static str {
+        "build-script-network"
+    }
+
+    fn scan_file(&self, path: &Path, contents: &[u8]) -> Vec<String> {
+        if path.file_name().map_or(true, |n| n != "build.rs") {
+            return vec![];
+        }
+
+        let text = String::from_utf8_lossy(contents);
+        NETWORK_PATTERNS
+            .iter()
+            .filter(|p| text.contains(*p))
+            .map(|p| format!("the build script mentions `{p}`"))
+            .collect()
+    }
+}
+
+/// Long base64 or hex strings in source code, which can hide a payload.
+struct EncodedBlobs;
+
+impl Scanner for EncodedBlobs {
+    fn name(&self) -> &'static str {
+        "encoded-blob"
+    }
+
+    fn scan_file(&self, path: &Path, contents: &[u8]) -> Vec<String> {
+        if path.extension().map_or(true, |e| e != "rs") {
+            return vec![];
+        }
+
+        let is_encoded = |b: &u8| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=');
+
+        let mut found = vec![];
+        for (i, line) in contents.split(|&b| b == b'\n').enumerate() {
+            let longest = line
+                .split(|b| !is_encoded(b))
+                .map(<[u8]>::len)
+                .max()
+                .unwrap_or(0);
+            if longest >= MIN_BLOB_LEN {
+                let line = i + 1;
+                found.push(format!(
+                    "a {longest}-character encoded string on line {line}"
+                ));
+            }
+        }
+
+        found
+    }
+}
+
+/// The SHA-256 of the crate or of any of its files is on a list of
+/// known-bad digests.
+struct KnownBad {
+    digests: BTreeSet<String>,
+}
+
+impl KnownBad {
+    fn check(&self, data: &[u8]) -> Vec<String> {
+        let digest = hex::encode(sha2::Sha256::digest(data));
+
+        if self.digests.contains(&digest) {
+            vec![format!("the SHA-256 {digest} is known to be bad")]
+        } else {
+            vec![]
+        }
+    }
+}
+
+impl Scanner for KnownBad {
+    fn name(&self) -> &'static str {
+        "known-bad"
+    }
+
+    fn scan_crate(&self, crate_file: &[u8]) -> Vec<String> {
+        self.check(crate_file)
+    }
+
+    fn scan_file(&self, _path: &Path, contents: &[u8]) -> Vec<String> {
+        self.check(contents)
+    }
+}
+
+/// A command run over the unpacked package in a sandbox.
+struct External {
+    command: Vec<String>,
+    policy: sandbox::Policy,
+    limits: extract::Limits,
+}
+
+impl External {
+    fn run(&self, crate_file: &[u8], scratch: &Path) -> Result<Vec<String>, ExternalError> {
+        use external_error::*;
+
+        let prefix = extract::package_dir(crate_file)?;
+        let files = extract::files(crate_file, &prefix, &self.limits)?;
+        let package_dir = scratch.join(&prefix);
+        extract::write(&files, &package_dir)?;
+
+        let output_path = scratch.join("output");
+        let output = fs::File::create(&output_path).context(OutputSnafu)?;
+
+        let program = &self.command[0];
+        let program = sandbox::find_program(program).unwrap_or_else(|| PathBuf::from(program));
+        let mut sandbox = sandbox::Sandbox::new(&self.policy, scratch)?;
+        if let Some(dir) = program.parent() {
+            sandbox.read_only(dir);
+        }
+        let mut command = sandbox.command(&program, &package_dir);
+        command
+            .args(&self.command[1..])
+            .arg(&package_dir)
+            .stdin(Stdio::null())
+            .stdout(output)
+            .stderr(Stdio::null());
+        let status = sandbox.run(&mut command)?;
+
+        let mut output = String::new();
+        fs::File::open(&output_path)
+            .and_then(|f| f.take(MAX_OUTPUT_LEN).read_to_string(&mut output))
+            .context(OutputSnafu)?;
+
+        let mut findings = output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .take(MAX_EXTERNAL_FINDINGS)
+            .map(|line| format!("`{}`: {line}", self.command[0]))
+            .collect::<Vec<_>>();
+        if findings.is_empty() && !status.success() {
+            findings.push(format!("`{}` failed ({status})", self.command[0]));
+        }
+
+        Ok(findings)
+    }
+}
+
+impl Scanner for External {
+    fn name(&self) -> &'static str {
+        "external"
+    }
+
+    /// A scanner that cannot run finds that the crate was not scanned,
+    /// which holds it for review like any other finding.
+    fn scan_crate(&self, crate_file: &[u8]) -> Vec<String> {
+        // Only this user can enter it, and nobody can have made it
+        // beforehand
+        let result = tempfile::Builder::new()
+            .prefix("margo-scan-")
+            .tempdir()
+            .context(external_error::OutputSnafu)
+            .and_then(|scratch| self.run(crate_file, scratch.path()));
+
+        result.unwrap_or_else(|e| vec![format!("`{}` could not run: {e}", self.command[0])])
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum ExternalError {
+    #[snafu(transparent)]
+    Unpack { source: extract::Error },
+
+    #[snafu(transparent)]
+    Sandbox { source: sandbox::Error },
+
+    #[snafu(display("Could not keep the scanner's output"))]
+    Output { source: io::Error },
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not unpack the crate to scan it"))]
+    Entries { source: io::Error },
+
+    #[snafu(display("Could not read {} from the crate to scan it", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Entries { .. } | Self::Read { .. } => "E_BAD_PACKAGE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn crate_file(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("demo-1.0.0/{path}"), *contents)
+                .unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn suspicious_files_are_found() {
+        let blob = "QUFB".repeat(MIN_BLOB_LEN / 4);
+        let bad = b"payload";
+        let known_bad = [hex::encode(sha2::Sha256::digest(bad))].into();
+        let scanners = builtin(&known_bad);
+
+        let clean = crate_file(&[
+            ("Cargo.toml", b"[package]\nname = \"demo\"\n"),
+            (
+                "build.rs",
+                b"fn main() { println!(\"cargo:rerun-if-changed=build.rs\"); }",
+            ),
+            ("src/lib.rs", b"pub fn demo() {}\n"),
+        ]);
+        assert!(scan(&scanners, &clean).unwrap().is_empty());
+
+        let suspicious = crate_file(&[
+            ("build.rs", b"use std::net::TcpStream;\n"),
+            (
+                "src/lib.rs",
+                format!("const X: &str = \"{blob}\";\n").as_bytes(),
+            ),
+            ("assets/data.bin", bad),
+        ]);
+        let findings = scan(&scanners, &suspicious).unwrap();
+        let scanners = findings.iter().map(|f| f.scanner).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "build-script-network",
+                "build-script-network",
+                "encoded-blob",
+                "known-bad"
+            ],
+            scanners,
+        );
+        assert!(summary(&findings).ends_with("(and 3 more)"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn external_scanners_report_what_they_print() {
+        let command = ["sh", "-c", "grep -rl payload \"$0\" | sed 's|.*/||'"];
+        let command = command.map(String::from).to_vec();
+        let scanners = external(&[command], &sandbox::Policy::default());
+
+        let clean = crate_file(&[("src/lib.rs", b"pub fn demo() {}\n")]);
+        assert!(scan(&scanners, &clean).unwrap().is_empty());
+
+        let suspicious = crate_file(&[("assets/data.bin", b"payload")]);
+        let findings = scan(&scanners, &suspicious).unwrap();
+        assert_eq!(1, findings.len(), "{findings:?}");
+        assert_eq!("external: `sh`: data.bin", findings[0].to_string());
+    }
+}
@@ -0,0 +1,287 @@
+//! Searching crate names, in this registry and optionally in trusted
+//! peer registries.
+//!
+//! `GET /api/v1/search?q=serde` searches the tenant's own crates.
+//! Adding `&federated=true` also asks each peer listed in the tenant's
+//! `[tenant.federation]` table, with the `federation` feature, and
+//! merges their matches in. Each hit names the registry that hosts it.
+//! Peers are asked for their own matches only, so federation does not
+//! chain.
+//!
+//! ```toml
+//! [tenant.federation]
+//! timeout-secs = 5
+//!
+//! [[tenant.federation.peers]]
+//! name = "partner"
+//! url = "https://registry.partner.example/"
+//! token = "..."  # sent as-is in `Authorization`, when the peer needs one
+//! ```
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{catalog::Summaries, common::CrateName, names::normalize, Registry};
+
+#[cfg(feature = "federation")]
+use snafu::prelude::*;
+#[cfg(feature = "federation")]
+use std::time::Duration;
+
+/// The most hits returned from each registry.
+const MAX_HITS: usize = 100;
+
+#[derive(Debug, Default)]
+pub struct Params {
+    pub q: String,
+    pub federated: bool,
+}
+
+impl Params {
+    pub fn from_query(query: Option<&str>) -> Self {
+        let mut params = Self::default();
+
+        let pairs = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes());
+        for (key, value) in pairs {
+            match &*key {
+                "q" => params.q = value.into_owned(),
+                "federated" => params.federated = value == "true" || value == "1",
+                _ => {}
+            }
+        }
+
+        params
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub hits: Vec<Hit>,
+
+    /// Peers that could not be searched.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unreachable: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hit {
+    pub name: String,
+
+    pub newest_version: Option<Version>,
+
+    /// The name of the registry hosting the crate: the tenant's name
+    /// for local hits, the peer's configured name for federated ones.
+    pub registry: String,
+
+    /// The base URL of the hosting registry's index.
+    pub index: Url,
+}
+
+/// Matches ignore case and treat `-` and `_` alike, as crates.io does.
+/// Exact matches come first, then prefixes, then other substrings.
+/// Only crates that are `visible` to the searcher are considered.
+pub fn local(
+    registry: &Registry,
+    crates: &Summaries,
+    label: &str,
+    q: &str,
+    visible: impl Fn(&CrateName) -> bool,
+) -> Vec<Hit> {
+    let q = normalize(q);
+
+    let mut ranked = crates
+        .iter()
+        .filter(|(name, _)| visible(name))
+        .filter_map(|(name, summary)| {
+            let rank = rank(&normalize(name.as_str()), &q)?;
+            Some((rank, name, summary))
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by_key(|&(rank, name, _)| (rank, name));
+
+    ranked
+        .into_iter()
+        .take(MAX_HITS)
+        .map(|(_, name, summary)| Hit {
+            name: name.to_string(),
+            newest_version: summary.newest_version.clone(),
+            registry: label.to_owned(),
+            index: registry.config.base_url.clone(),
+        })
+        .collect()
+}
+
+fn rank(name: &str, q: &str) -> Option<u8> {
+    if name == q {
+        Some(0)
+    } else if name.starts_with(q) {
+        Some(1)
+    } else if name.contains(q) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "federation")]
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FederationConfig {
+    peers: Vec<Peer>,
+
+    #[serde(default = "FederationConfig::default_timeout_secs")]
+    timeout_secs: u64,
+}
+
+#[cfg(feature = "federation")]
+impl FederationConfig {
+    fn default_timeout_secs() -> u64 {
+        5
+    }
+}
+
+#[cfg(feature = "federation")]
+#[derive(Debug, Deserialize)]
+struct Peer {
+    name: String,
+    url: Url,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+#[cfg(feature = "federation")]
+#[derive(Debug)]
+pub struct Federation {
+    agent: ureq::Agent,
+    peers: Vec<Peer>,
+}
+
+#[cfg(feature = "federation")]
+impl Federation {
+    pub fn new(config: FederationConfig) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build();
+
+        Self {
+            agent,
+            peers: config.peers,
+        }
+    }
+
+    /// Asks every peer, in parallel. Peers that fail are returned by
+    /// name, after printing a warning.
+    pub fn search(&self, q: &str) -> (Vec<Hit>, Vec<String>) {
+        let results = std::thread::scope(|s| {
+            let searches = self
+                .peers
+                .iter()
+                .map(|peer| s.spawn(move || (peer, self.search_peer(peer, q))))
+                .collect::<Vec<_>>();
+
+            searches
+                .into_iter()
+                .filter_map(|search| search.join().ok())
+                .collect::<Vec<_>>()
+        });
+
+        let mut hits = vec![];
+        let mut unreachable = vec![];
+        for (peer, result) in results {
+            match result {
+                Ok(peer_hits) => hits.extend(peer_hits),
+                Err(e) => {
+                    eprintln!("Warning: {e}");
+                    unreachable.push(peer.name.clone());
+                }
+            }
+        }
+
+        (hits, unreachable)
+    }
+
+    fn search_peer(&self, peer: &Peer, q: &str) -> Result<Vec<Hit>, Error> {
+        use error::*;
+
+        let mut url = peer
+            .url
+            .join("api/v1/search")
+            .context(UrlSnafu { peer: &peer.name })?;
+        url.query_pairs_mut().append_pair("q", q);
+
+        let mut request = self.agent.request_url("GET", &url);
+        if let Some(token) = &peer.token {
+            request = request.set("Authorization", token);
+        }
+
+        let response: Response = request
+            .call()
+            .context(RequestSnafu { peer: &peer.name })?
+            .into_json()
+            .context(ResponseSnafu { peer: &peer.name })?;
+
+        // The peer's labels are its own tenant names; ours are clearer
+        let hits = response
+            .hits
+            .into_iter()
+            .take(MAX_HITS)
+            .map(|hit| Hit {
+                registry: peer.name.clone(),
+                ..hit
+            })
+            .collect();
+
+        Ok(hits)
+    }
+}
+
+#[cfg(feature = "federation")]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum Error {
+    #[snafu(display("The search URL of peer `{peer}` is not valid"))]
+    Url {
+        source: url::ParseError,
+        peer: String,
+    },
+
+    #[snafu(display("Could not search peer `{peer}`"))]
+    Request {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        peer: String,
+    },
+
+    #[snafu(display("Could not deserialize the search results of peer `{peer}`"))]
+    Response {
+        source: std::io::Error,
+        peer: String,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_rank_by_how_closely_they_match() {
+        let q = normalize("Serde_JSON");
+
+        assert_eq!(Some(0), rank(&normalize("serde-json"), &q));
+        assert_eq!(Some(1), rank(&normalize("serde_json_path"), &q));
+        assert_eq!(Some(2), rank(&normalize("simd-serde-json"), &q));
+        assert_eq!(None, rank(&normalize("serde"), &q));
+    }
+
+    #[test]
+    fn query_parameters_are_decoded() {
+        let params = Params::from_query(Some("q=serde%20json&federated=true"));
+        assert_eq!("serde json", params.q);
+        assert!(params.federated);
+
+        let params = Params::from_query(None);
+        assert_eq!("", params.q);
+        assert!(!params.federated);
+    }
+}
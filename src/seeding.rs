@@ -0,0 +1,168 @@
+//! Keeping a registry's most downloaded crates on volunteer peers.
+//!
+//! Every hour, a node offers the public versions its daemon served most
+//! since it started to the peers that said, when they connected, that
+//! they volunteer to seed. A volunteer fetches the ones it does not
+//! have yet, checks each against the checksum it was offered with, and
+//! keeps it in `seeds/` in its registry, removing those it fetched
+//! longest ago once they take up more than `max-bytes`. Peers can then
+//! fetch those crates from the volunteers as well as from the registry
+//! they came from.
+//!
+//! ```toml
+//! [announcements.seeding]
+//! # Consent to keeping other registries' crates and handing them out
+//! volunteer = true
+//! max-bytes = 1073741824
+//! # How many of this registry's versions to offer
+//! top = 20
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const DIR_NAME: &str = "seeds";
+
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_TOP: usize = 20;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Policy {
+    /// Keep the crates peers offer and hand them to other peers. Off
+    /// unless the operator turns it on.
+    #[serde(default)]
+    pub volunteer: bool,
+
+    /// The most the kept crates may take up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<u64>,
+
+    /// How many of this registry's versions are offered to volunteers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top: Option<usize>,
+}
+
+impl Policy {
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes.unwrap_or(DEFAULT_MAX_BYTES)
+    }
+
+    pub fn top(&self) -> usize {
+        self.top.unwrap_or(DEFAULT_TOP)
+    }
+}
+
+/// The `top` versions downloaded most, most first, as name and version.
+pub fn popular(
+    downloads: &BTreeMap<String, BTreeMap<String, u64>>,
+    top: usize,
+) -> Vec<(&str, &str)> {
+    let mut versions = downloads
+        .iter()
+        .flat_map(|(name, versions)| versions.iter().map(move |(vers, n)| (*n, name, vers)))
+        .collect::<Vec<_>>();
+
+    // Stable, so ties stay in name order
+    versions.sort_by(|a, b| b.0.cmp(&a.0));
+
+    versions
+        .into_iter()
+        .take(top)
+        .map(|(_, name, vers)| (name.as_str(), vers.as_str()))
+        .collect()
+}
+
+/// Where the crate with the checksum is kept below `dir`.
+pub fn path_of(dir: &Path, cksum: &str) -> PathBuf {
+    dir.join(format!("{cksum}.crate"))
+}
+
+pub fn has(dir: &Path, cksum: &str) -> bool {
+    path_of(dir, cksum).exists()
+}
+
+/// The kept crate with the checksum, if there is one.
+pub fn get(dir: &Path, cksum: &str) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path_of(dir, cksum)) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Keeps the crate, whose checksum has been checked, then removes the
+/// crates kept longest ago until they take up at most `max_bytes`.
+pub fn store(dir: &Path, cksum: &str, data: &[u8], max_bytes: u64) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let path = path_of(dir, cksum);
+    let tmp_path = path.with_extension("crate.tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, &path)?;
+
+    let mut kept = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "crate") {
+            let metadata = fs::metadata(&path)?;
+            kept.push((metadata.modified()?, metadata.len(), path));
+        }
+    }
+
+    // Oldest first
+    kept.sort();
+
+    let mut total = kept.iter().map(|(_, len, _)| len).sum::<u64>();
+    for (_, len, path) in kept {
+        if total <= max_bytes {
+            break;
+        }
+        fs::remove_file(&path)?;
+        total -= len;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_most_downloaded_versions_are_offered() {
+        let downloads = serde_json::from_value(serde_json::json!({
+            "a": {"1.0.0": 5, "2.0.0": 50},
+            "b": {"0.1.0": 20},
+            "c": {"0.1.0": 5},
+        }))
+        .unwrap();
+
+        assert_eq!(
+            vec![("a", "2.0.0"), ("b", "0.1.0"), ("a", "1.0.0")],
+            popular(&downloads, 3),
+        );
+        assert!(popular(&downloads, 0).is_empty());
+    }
+
+    #[test]
+    fn the_crates_kept_longest_ago_go_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        store(dir, "aa", &[0; 40], 100).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store(dir, "bb", &[0; 40], 100).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store(dir, "cc", &[0; 40], 100).unwrap();
+
+        assert!(!has(dir, "aa"));
+        assert!(has(dir, "bb"));
+        assert_eq!(Some(vec![0; 40]), get(dir, "cc").unwrap());
+        assert_eq!(None, get(dir, "dd").unwrap());
+    }
+}
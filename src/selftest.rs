@@ -0,0 +1,385 @@
+//! Checking a registry end to end, after setting it up.
+//!
+//! `margo selftest` packages a throwaway crate, `margo-selftest`, and
+//! adds a new version of it through each frontend: the CLI, and, when
+//! margo is built with the `api-client` feature and given `--url`, the
+//! daemon's HTTP API. For each version, it checks that the index lists
+//! it, downloads the `.crate` file back and compares it with the
+//! index's checksum, then builds a project depending on the newest one,
+//! as `margo new-consumer --build` would. The versions are removed
+//! again unless `--keep` is given.
+//!
+//! P2P is not exercised: peers fetch what the other frontends add, on
+//! their own schedule.
+
+use semver::Version;
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+
+use crate::{
+    client, common::CrateName, new_consumer, AddError, FindCrateError, Global, Registry,
+    RemoveError,
+};
+
+#[cfg(feature = "api-client")]
+use crate::api;
+#[cfg(feature = "api-client")]
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const CRATE_NAME: &str = "margo-selftest";
+
+/// How long the daemon may take to serve a version it accepted.
+#[cfg(feature = "api-client")]
+const INDEX_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[cfg(feature = "api-client")]
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct Options<'a> {
+    /// Where the daemon serves the registry, ending in `/`, to publish
+    /// through its HTTP API.
+    pub url: Option<&'a Url>,
+
+    pub token: Option<&'a str>,
+
+    /// What the consumer's Cargo configuration calls the registry.
+    pub registry_name: &'a str,
+
+    pub build: bool,
+    pub keep: bool,
+}
+
+/// Runs every check, removing what was published afterwards unless
+/// told to keep it.
+pub fn run(global: &Global, registry: &Registry, options: &Options<'_>) -> Result<(), Error> {
+    use error::*;
+
+    let dir = tempfile::Builder::new()
+        .prefix("margo-selftest-")
+        .tempdir()
+        .context(WriteSnafu {
+            path: std::env::temp_dir(),
+        })?;
+
+    let mut published = vec![];
+    let checked = check(global, registry, options, dir.path(), &mut published);
+
+    if !options.keep {
+        for version in published {
+            let name = crate_name();
+            registry
+                .remove(name, version.clone())
+                .context(RemoveSnafu { version })?;
+        }
+    }
+
+    checked
+}
+
+fn check(
+    global: &Global,
+    registry: &Registry,
+    options: &Options<'_>,
+    dir: &Path,
+    published: &mut Vec<Version>,
+) -> Result<(), Error> {
+    use error::*;
+
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    // Through the CLI
+    let version = Version::new(0, 0, secs);
+    let data = package(&version).context(PackSnafu)?;
+    let path = dir.join(format!("{CRATE_NAME}-{version}.crate"));
+    fs::write(&path, &data).context(WriteSnafu { path: &path })?;
+
+    registry.add(global, &path).context(AddSnafu)?;
+    published.push(version.clone());
+    pass("published through the CLI");
+
+    let found = registry.find_crate(&crate_name()).context(IndexSnafu)?;
+    let entry = found.as_ref().and_then(|(_, index)| index.get(&version));
+    let entry = entry.context(NotIndexedSnafu {
+        version: version.clone(),
+    })?;
+    ensure!(
+        !entry.withheld,
+        WithheldSnafu {
+            version: version.clone()
+        }
+    );
+    pass("listed in the index");
+
+    let path = registry.crate_file_path_for(&entry.name, &version);
+    let stored = fs::read(&path).context(ReadSnafu { path })?;
+    client::Client::verify(entry, &stored).context(ChecksumSnafu)?;
+    pass("stored with the checksum in the index");
+
+    // Through the HTTP API
+    let served_at = match options.url {
+        #[cfg(feature = "api-client")]
+        Some(url) => {
+            let version = Version::new(0, 0, secs + 1);
+            let data = package(&version).context(PackSnafu)?;
+            daemon::check(url, options.token, &data, &version, published)?;
+            url
+        }
+        #[cfg(not(feature = "api-client"))]
+        Some(_) => {
+            skip("the HTTP API: margo was built without `api-client`");
+            &registry.config.base_url
+        }
+        None => {
+            skip("the HTTP API: no --url given");
+            &registry.config.base_url
+        }
+    };
+    skip("P2P: peers replicate on their own schedule");
+
+    if !options.build {
+        skip("building a consumer: --no-build given");
+        return Ok(());
+    }
+
+    let version = published.last().unwrap_or(&version);
+    let crate_name = crate_name();
+    let consumer = new_consumer::Consumer {
+        crate_name: &crate_name,
+        version,
+        registry_name: options.registry_name,
+        index: format!("sparse+{served_at}"),
+    };
+    let out = dir.join(consumer.package_name());
+    consumer.write(&out).context(ConsumerSnafu)?;
+    new_consumer::build(&out, options.registry_name, options.token).context(ConsumerSnafu)?;
+    pass("built a consumer that depends on it");
+
+    Ok(())
+}
+
+fn pass(check: &str) {
+    println!("ok    {check}");
+}
+
+fn skip(check: &str) {
+    println!("skip  {check}");
+}
+
+fn crate_name() -> CrateName {
+    CRATE_NAME
+        .parse()
+        .expect("the self-test crate name is valid")
+}
+
+/// A `.crate` file holding an empty library.
+fn package(version: &Version) -> io::Result<Vec<u8>> {
+    let manifest = format!(
+        "[package]\n\
+         name = \"{CRATE_NAME}\"\n\
+         version = \"{version}\"\n\
+         edition = \"2021\"\n\
+         description = \"Published by `margo selftest`\"\n\
+         license = \"MIT OR Apache-2.0\"\n"
+    );
+    let lib = "//! Published by `margo selftest`.\n";
+
+    let gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    let mut tar = tar::Builder::new(gz);
+    for (path, data) in [("Cargo.toml", manifest.as_str()), ("src/lib.rs", lib)] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        let path = format!("{CRATE_NAME}-{version}/{path}");
+        tar.append_data(&mut header, path, data.as_bytes())?;
+    }
+
+    tar.into_inner()?.finish()
+}
+
+#[cfg(feature = "api-client")]
+mod daemon {
+    use super::*;
+
+    /// Publishes `data` through the daemon's API, then fetches the
+    /// version back as Cargo would.
+    pub fn check(
+        url: &Url,
+        token: Option<&str>,
+        data: &[u8],
+        version: &Version,
+        published: &mut Vec<Version>,
+    ) -> Result<(), Error> {
+        use error::*;
+
+        let token = token.map(str::to_owned);
+
+        let api = api::Api::new(url.clone(), token.clone());
+        api.publish(data).context(ApiSnafu)?;
+        published.push(version.clone());
+        pass("published through the HTTP API");
+
+        let agent = ureq::AgentBuilder::new().timeout(INDEX_TIMEOUT).build();
+        let get = |url: Url| -> Result<Option<Vec<u8>>, Error> {
+            let mut request = agent.request_url("GET", &url);
+            if let Some(token) = &token {
+                request = request.set("Authorization", token);
+            }
+
+            let response = match request.call() {
+                Ok(response) => response,
+                Err(ureq::Error::Status(404, _)) => return Ok(None),
+                Err(e) => return Err(e).context(FetchSnafu { url }),
+            };
+            let mut body = vec![];
+            io::copy(&mut response.into_reader(), &mut body).context(BodySnafu { url })?;
+            Ok(Some(body))
+        };
+
+        let config_url = client::Client::config_url(url).context(ClientSnafu)?;
+        let config = get(config_url.clone())?.context(NotServedSnafu { url: config_url })?;
+        let client = client::Client::new(url.clone(), &config).context(ClientSnafu)?;
+
+        let index_url = client.index_url(&crate_name()).context(ClientSnafu)?;
+        let start = Instant::now();
+        let entry = loop {
+            if let Some(index) = get(index_url.clone())? {
+                let mut index = client.parse_index(&index).context(ClientSnafu)?;
+                if let Some(entry) = index.remove(version) {
+                    break entry;
+                }
+            }
+            ensure!(
+                start.elapsed() < INDEX_TIMEOUT,
+                NotServedSnafu { url: index_url }
+            );
+            thread::sleep(POLL_INTERVAL);
+        };
+        pass("served in the sparse index");
+
+        let crate_url = client.crate_url(&entry).context(ClientSnafu)?;
+        let downloaded = get(crate_url.clone())?.context(NotServedSnafu { url: crate_url })?;
+        client::Client::verify(&entry, &downloaded).context(ChecksumSnafu)?;
+        pass("downloaded with the checksum in the index");
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not package the self-test crate"))]
+    Pack { source: io::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not add the self-test crate"))]
+    Add { source: AddError },
+
+    #[snafu(display("Could not read the self-test crate's index file"))]
+    Index { source: FindCrateError },
+
+    #[snafu(display("The index does not list {CRATE_NAME} {version}"))]
+    NotIndexed { version: Version },
+
+    #[snafu(display(
+        "{CRATE_NAME} {version} was withheld, probably by a scanner; see `margo quarantine`"
+    ))]
+    Withheld { version: Version },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("The self-test crate came back changed"))]
+    Checksum { source: client::Error },
+
+    #[cfg(feature = "api-client")]
+    #[snafu(display("The daemon refused the self-test crate"))]
+    Api { source: api::Error },
+
+    #[cfg(feature = "api-client")]
+    #[snafu(display("Could not fetch {url}"))]
+    Fetch {
+        #[snafu(source(from(ureq::Error, Box::new)))]
+        source: Box<ureq::Error>,
+        url: Url,
+    },
+
+    #[cfg(feature = "api-client")]
+    #[snafu(display("Could not read the response from {url}"))]
+    Body { source: io::Error, url: Url },
+
+    #[cfg(feature = "api-client")]
+    #[snafu(display("Could not use what the daemon serves"))]
+    Client { source: client::Error },
+
+    #[cfg(feature = "api-client")]
+    #[snafu(display("The daemon does not serve {url}"))]
+    NotServed { url: Url },
+
+    #[snafu(display("Could not build a consumer of the self-test crate"))]
+    Consumer { source: new_consumer::Error },
+
+    #[snafu(display("Could not remove {CRATE_NAME} {version} again"))]
+    Remove {
+        source: RemoveError,
+        version: Version,
+    },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Pack { .. } => "E_INTERNAL",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Add { source } => source.code(),
+            Self::Index { source } => source.code(),
+            Self::NotIndexed { .. } | Self::Withheld { .. } => "E_SELFTEST_FAILED",
+            Self::Read { .. } => "E_CRATE_READ",
+            Self::Checksum { source } => source.code(),
+            #[cfg(feature = "api-client")]
+            Self::Api { source } => source.code(),
+            #[cfg(feature = "api-client")]
+            Self::Client { source } => source.code(),
+            #[cfg(feature = "api-client")]
+            Self::Fetch { .. } | Self::Body { .. } => "E_UPSTREAM",
+            #[cfg(feature = "api-client")]
+            Self::NotServed { .. } => "E_SELFTEST_FAILED",
+            Self::Consumer { source } => source.code(),
+            Self::Remove { source, .. } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_package_is_a_library() {
+        let version = Version::new(0, 0, 1);
+        let data = package(&version).unwrap();
+
+        let files = crate::extract::files(&data, "margo-selftest-0.0.1", &Default::default());
+        let files = files.unwrap();
+        let mut paths = files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(vec!["Cargo.toml", "src/lib.rs"], paths);
+
+        let cargo_toml = crate::read_cargo_toml(&data).unwrap();
+        assert_eq!(CRATE_NAME, cargo_toml.package.name.as_str());
+        assert_eq!(version, cargo_toml.package.version);
+    }
+}
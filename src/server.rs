@@ -0,0 +1,3147 @@
+//! The HTTP frontend of the `serve` daemon.
+//!
+//! Serves the registry files themselves, a JSON API under `/api/v1`,
+//! and a dashboard under `/ui`. Each [`Tenant`] gets its own copy of
+//! these below its base path. Tenants with publishing enabled also
+//! accept `cargo publish`.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Path, Request, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{delete, get, put},
+    Extension, Json, Router,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    convert::Infallible,
+    fs, io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tower_http::services::ServeDir;
+use url::Url;
+
+use crate::{
+    artifact, attestation, audit,
+    auth::{self, Grant, UserId},
+    blob, catalog,
+    common::{CrateName, CrateNameError, RustVersion, RustVersionError},
+    discovery, docs_queue, features, html, index_entry, maintenance, parse_index_lines,
+    publish_queue, read_cargo_toml, regenerate, registry_info, resolve_versions, scan, search,
+    telemetry,
+    tenant::Tenant,
+    tier,
+    timestamp::Timestamp,
+    upload, usage,
+    visibility::{self, Visibility},
+    AddError, ErrorBody, FindCrateError, Global, Index, ListAllError, ParseIndexError, Registry,
+    VisibilityError, YankError,
+};
+
+/// The largest metadata `cargo publish` may send, and the largest
+/// `.crate` file when the registry sets no `max-crate-size`, matching
+/// crates.io.
+const PUBLISH_BODY_LIMIT: u64 = 10 * 1024 * 1024;
+
+/// How much of a file is read at a time when serving it, and so
+/// roughly how much memory each download holds, however large the
+/// file. hyper writes bodies from memory, so files are not handed to
+/// the kernel with `sendfile`.
+const FILE_CHUNK_LEN: usize = 256 * 1024;
+
+/// The largest prebuilt binary that may be uploaded.
+const ARTIFACT_BODY_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// The largest blob that may be uploaded.
+const BLOB_BODY_LIMIT: u64 = 1024 * 1024 * 1024;
+
+/// How often queued publishes are looked for once maintenance ends or
+/// they are approved.
+const QUEUE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How often unused `.crate` files are moved to the cold tier.
+const DEMOTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// The most audit log entries returned at once.
+const AUDIT_PAGE_SIZE: usize = 500;
+
+/// How often `/api/v1/events` looks for new audit log entries and
+/// peers.
+const EVENTS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The most index files returned by one `/index-bundle` request.
+const INDEX_BUNDLE_LIMIT: usize = 5000;
+
+/// How many requests one HTTP/2 connection may have in flight. Cargo
+/// asks for every index file it needs at once.
+const MAX_CONCURRENT_STREAMS: u32 = 256;
+
+/// How often an idle HTTP/2 connection is pinged, and how long the
+/// client has to answer before it is closed.
+const HTTP2_KEEP_ALIVE: std::time::Duration = std::time::Duration::from_secs(20);
+
+/// How long a client has to send each request's headers, including
+/// the next request on a kept-alive HTTP/1 connection.
+const HEADER_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How long to wait before accepting connections again after failing
+/// to, such as when out of file descriptors.
+const ACCEPT_RETRY: std::time::Duration = std::time::Duration::from_secs(1);
+
+pub async fn run(
+    addr: SocketAddr,
+    tenants: Vec<Tenant>,
+    global: &'static Global,
+) -> Result<(), Error> {
+    use error::*;
+
+    let directory = Arc::new(tenants.clone());
+    let mut app = Router::new().route(
+        discovery::WELL_KNOWN_PATH,
+        get(move |uri: Uri| well_known(directory, uri)),
+    );
+
+    for tenant in tenants {
+        app = match tenant.name.clone() {
+            Some(name) => app.nest_service(&format!("/{name}"), tenant_router(tenant, global)),
+            None => app.fallback_service(tenant_router(tenant, global)),
+        };
+    }
+
+    #[cfg(feature = "telemetry")]
+    let app = app.layer(middleware::from_fn(telemetry::trace_requests));
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .context(BindSnafu { addr })?;
+
+    println!("Serving HTTP on http://{addr}");
+
+    let connections = connection_builder();
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Warning: Could not accept an HTTP connection: {e}");
+                tokio::time::sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        // Index files are small; sending them at once beats batching
+        if let Err(e) = stream.set_nodelay(true) {
+            eprintln!("Warning: Could not disable Nagle's algorithm: {e}");
+        }
+
+        let app = app.clone().layer(Extension(ConnectInfo(remote)));
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            let io = TokioIo::new(stream);
+            let service = TowerToHyperService::new(app);
+            // Clients going away mid-request are not worth a warning
+            let _ = connections
+                .serve_connection_with_upgrades(io, service)
+                .await;
+        });
+    }
+}
+
+/// Speaks HTTP/1.1 with keep-alive, or HTTP/2 to clients that start
+/// with its preface, as a reverse proxy passing on HTTP/2 does.
+fn connection_builder() -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .keep_alive(true)
+        .timer(TokioTimer::new())
+        .header_read_timeout(HEADER_READ_TIMEOUT);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .keep_alive_interval(HTTP2_KEEP_ALIVE)
+        .keep_alive_timeout(HTTP2_KEEP_ALIVE)
+        .adaptive_window(true);
+    builder
+}
+
+fn tenant_router(tenant: Tenant, global: &'static Global) -> Router {
+    let files = ServeDir::new(&tenant.registry().path).with_buf_chunk_size(FILE_CHUNK_LEN);
+
+    let read = Router::new()
+        .route("/api/v1/crates", get(api_crates))
+        .route("/api/v1/events", get(api_events))
+        .route("/api/v1/crates/:name", get(api_crate))
+        .route("/api/v1/crates/:name/versions", get(api_crate_versions))
+        .route(
+            "/api/v1/crates/:name/:version/attestations",
+            get(api_attestations),
+        )
+        .route(
+            "/api/v1/crates/:name/:version/artifacts",
+            get(api_artifacts),
+        )
+        .route("/api/v1/blobs/:digest", get(api_blob))
+        .route("/api/v1/audit", get(api_audit))
+        .route("/api/v1/changes", get(api_changes))
+        .route("/api/v1/index-snapshot", get(api_index_snapshot))
+        .route("/api/v1/registry", get(api_registry))
+        .route("/api/v1/search", get(api_search))
+        .route("/api/v1/status", get(api_status))
+        .route("/api/v1/transfers", get(api_transfers))
+        .route("/api/v1/usage", get(api_usage))
+        .route("/index-bundle", get(index_bundle))
+        .route("/ui", get(ui_index))
+        .route("/ui/crates/:name", get(ui_crate));
+
+    #[cfg(feature = "proxy")]
+    let read = match tenant.proxy.clone() {
+        Some(proxy) => {
+            let upstream = get(move |uri: Uri| proxy_fetch(proxy, uri));
+            read.fallback_service(files.fallback(upstream))
+        }
+        None => read.fallback_service(files),
+    };
+
+    #[cfg(not(feature = "proxy"))]
+    let read = read.fallback_service(files);
+
+    let read = read.layer(middleware::from_fn_with_state(
+        tenant.clone(),
+        promote_demoted,
+    ));
+
+    #[cfg(feature = "signed-urls")]
+    let read = read.layer(middleware::from_fn_with_state(
+        tenant.clone(),
+        redirect_to_signed_url,
+    ));
+
+    // Outside the signed URLs, so a healthy mirror is preferred
+    #[cfg(feature = "download-mirrors")]
+    let read = read.layer(middleware::from_fn_with_state(
+        tenant.clone(),
+        redirect_to_mirror,
+    ));
+
+    let read = read
+        .layer(middleware::from_fn_with_state(
+            tenant.clone(),
+            count_downloads,
+        ))
+        .layer(middleware::from_fn_with_state(
+            tenant.clone(),
+            account_downloads,
+        ))
+        .layer(middleware::from_fn_with_state(
+            tenant.clone(),
+            refuse_quarantined,
+        ))
+        .layer(middleware::from_fn_with_state(
+            tenant.clone(),
+            enforce_visibility,
+        ))
+        .layer(middleware::from_fn_with_state(
+            tenant.clone(),
+            require_token,
+        ));
+
+    // These check credentials themselves
+    let write = Router::new()
+        .route(
+            "/api/v1/crates/new",
+            put(
+                move |state: State<Tenant>, headers: HeaderMap, body: Body| {
+                    publish(state, global, headers, body)
+                },
+            ),
+        )
+        .route("/api/v1/crates/:name/:version/yank", delete(yank))
+        .route("/api/v1/crates/:name/:version/unyank", put(unyank))
+        .route(
+            "/api/v1/crates/:name/:version/approve",
+            put(
+                move |state: State<Tenant>, path: Path<(String, Version)>, headers: HeaderMap| {
+                    approve(state, global, path, headers)
+                },
+            ),
+        )
+        .route("/api/v1/crates/:name/:version/reject", put(reject))
+        .route("/api/v1/crates/:name/visibility", put(set_visibility))
+        .route("/api/v1/crates/:name/:version/attestations", put(attest))
+        .route(
+            "/api/v1/crates/:name/:version/artifacts/:target/:file",
+            put(add_artifact),
+        )
+        .route("/api/v1/blobs", put(put_blob));
+
+    #[cfg(any(feature = "oidc", feature = "ldap"))]
+    let write = write.route("/api/v1/tokens", axum::routing::post(mint_token));
+
+    let write = if tenant.registry().is_read_only(global) {
+        write.layer(middleware::from_fn(refuse_writes))
+    } else {
+        write
+    };
+
+    let router = write
+        .merge(read)
+        .layer(middleware::from_fn_with_state(tenant.clone(), lockout))
+        // Outside `require_token`, for load balancers to poll
+        .route("/api/v1/ready", get(api_ready));
+
+    // Outside `require_token` too; it is the same for every registry
+    #[cfg(feature = "openapi")]
+    let router = router
+        .route("/api/openapi.json", get(api_openapi))
+        .route("/api/docs", get(api_docs));
+
+    router.with_state(tenant)
+}
+
+/// The OpenAPI document of the JSON API, generated from the handlers
+/// and the types they answer with. Paths are relative to the tenant's
+/// base path. Errors are all [`ErrorBody`]s.
+#[cfg(feature = "openapi")]
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    paths(
+        api_crates,
+        api_crate,
+        api_crate_versions,
+        api_attestations,
+        api_artifacts,
+        api_blob,
+        api_audit,
+        api_changes,
+        api_events,
+        api_index_snapshot,
+        api_search,
+        api_status,
+        api_registry,
+        api_transfers,
+        api_usage,
+        api_ready,
+        index_bundle,
+        publish,
+        yank,
+        unyank,
+        approve,
+        reject,
+        set_visibility,
+        attest,
+        add_artifact,
+        put_blob,
+    ),
+    components(schemas(
+        Readiness,
+        OkResponse,
+        PublishResponse,
+        PublishWarnings,
+        BlobResponse,
+        VisibilityRequest,
+        Visibility,
+        ErrorBody,
+        registry_info::Info,
+        registry_info::Feature,
+    )),
+    modifiers(&TokenAuth),
+    security(("token" = []))
+)]
+struct ApiDoc;
+
+#[cfg(all(feature = "openapi", any(feature = "oidc", feature = "ldap")))]
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(mint_token))]
+struct MintDoc;
+
+/// Cargo sends tokens as-is in the `Authorization` header, without a
+/// `Bearer` scheme.
+#[cfg(feature = "openapi")]
+struct TokenAuth;
+
+#[cfg(feature = "openapi")]
+impl utoipa::Modify for TokenAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+
+        let scheme = SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("Authorization")));
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme("token", scheme);
+    }
+}
+
+#[cfg(feature = "openapi")]
+async fn api_openapi(State(state): State<Tenant>) -> Response {
+    use utoipa::OpenApi;
+
+    let mut doc = ApiDoc::openapi();
+
+    #[cfg(any(feature = "oidc", feature = "ldap"))]
+    if state.publish.is_some() {
+        doc.merge(MintDoc::openapi());
+    }
+
+    if let Some(name) = &state.name {
+        doc.servers = Some(vec![utoipa::openapi::Server::new(format!("/{name}"))]);
+    }
+
+    ([(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], Json(doc)).into_response()
+}
+
+/// Swagger UI, loaded from a CDN, showing `openapi.json` beside it.
+#[cfg(feature = "openapi")]
+async fn api_docs() -> Html<String> {
+    Html(html::swagger_ui("openapi.json").into_string())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not listen for HTTP connections on {addr}"))]
+    Bind { source: io::Error, addr: SocketAddr },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Bind { .. } => "E_BAD_ADDRESS",
+        }
+    }
+}
+
+/// Cargo sends the token configured for the registry as-is in the
+/// `Authorization` header.
+async fn require_token(State(state): State<Tenant>, request: Request, next: Next) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+
+    if state.accepts(token) {
+        return next.run(request).await;
+    }
+
+    unauthorized(&state)
+}
+
+fn unauthorized(state: &Tenant) -> Response {
+    let body = ErrorBody {
+        code: "E_UNAUTHORIZED",
+        message: "A valid token is required to access this registry".to_owned(),
+        causes: vec![],
+    };
+    let realm = state.name.as_deref().unwrap_or("margo");
+
+    let mut response = ApiError(StatusCode::UNAUTHORIZED, body).into_response();
+    if let Ok(value) = format!(r#"Cargo realm="{realm}""#).parse() {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, value);
+    }
+    response
+}
+
+/// What the request's token lets it see.
+struct Viewer {
+    levels: visibility::Levels,
+    grant: Option<Grant>,
+}
+
+impl Viewer {
+    fn new(state: &Tenant, headers: &HeaderMap) -> Result<Self, ApiError> {
+        let levels = state
+            .registry()
+            .visibility()
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e))?;
+
+        // Most registries have every crate public; skip the token lookup
+        let grant = if levels.is_empty() {
+            None
+        } else {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|t| state.grant_for_token(t))
+        };
+
+        Ok(Self { levels, grant })
+    }
+
+    fn may_see(&self, state: &Tenant, name: &str) -> bool {
+        match self.levels.of(name) {
+            Visibility::Public => true,
+            Visibility::Internal => self.grant.is_some(),
+            Visibility::Private => self.grant.as_ref().is_some_and(|grant| {
+                grant.is_admin()
+                    || state.publish.as_ref().is_some_and(|publisher| {
+                        name.parse::<CrateName>()
+                            .is_ok_and(|name| publisher.lock_owners().is_owner(&name, &grant.user))
+                    })
+            }),
+        }
+    }
+
+    /// Whether nothing is hidden from the request, as whole-registry
+    /// views such as the audit log need.
+    fn sees_everything(&self) -> bool {
+        self.levels.is_empty() || self.grant.as_ref().is_some_and(Grant::is_admin)
+    }
+}
+
+/// Refuses the files and API routes of crates the request may not see.
+/// Private crates are not found rather than forbidden, so that their
+/// names are not given away, and `visibility.json` is never served.
+async fn enforce_visibility(State(state): State<Tenant>, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if path.trim_start_matches('/') == visibility::FILE_NAME {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let name = path
+        .strip_prefix("/api/v1/crates/")
+        .or_else(|| path.strip_prefix("/ui/crates/"))
+        .and_then(|rest| rest.split('/').next())
+        .or_else(|| visibility::crate_of(path));
+    let Some(name) = name else {
+        return next.run(request).await;
+    };
+
+    let viewer = match Viewer::new(&state, request.headers()) {
+        Ok(viewer) => viewer,
+        Err(e) => return e.into_response(),
+    };
+    if viewer.may_see(&state, name) {
+        return next.run(request).await;
+    }
+
+    match viewer.levels.of(name) {
+        Visibility::Internal if viewer.grant.is_none() => unauthorized(&state),
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Refuses whole-registry views unless the request may see every crate.
+fn require_everything(viewer: &Viewer) -> Result<(), ApiError> {
+    if viewer.sees_everything() {
+        return Ok(());
+    }
+
+    let body = ErrorBody {
+        code: "E_FORBIDDEN",
+        message: "Only admins may read this while some crates are not public".to_owned(),
+        causes: vec![],
+    };
+    Err(ApiError(StatusCode::FORBIDDEN, body))
+}
+
+/// Layered over the write endpoints of a read-only registry.
+async fn refuse_writes(_request: Request, _next: Next) -> Response {
+    let body = ErrorBody {
+        code: "E_READ_ONLY",
+        message: "This registry is read-only".to_owned(),
+        causes: vec![],
+    };
+
+    ApiError(StatusCode::SERVICE_UNAVAILABLE, body).into_response()
+}
+
+/// Describes the hosted registries for `margo discover`. Like NIP-05,
+/// `?name=` narrows the document to one registry, and any origin may
+/// read it.
+async fn well_known(tenants: Arc<Vec<Tenant>>, uri: Uri) -> Response {
+    let wanted = uri
+        .query()
+        .and_then(|q| q.split('&').find_map(|pair| pair.strip_prefix("name=")));
+
+    let registries = tenants
+        .iter()
+        .map(|tenant| {
+            let name = tenant.name.as_deref().unwrap_or(discovery::ROOT_NAME);
+            (name, tenant)
+        })
+        .filter(|(name, _)| wanted.map_or(true, |wanted| wanted == *name))
+        .map(|(name, tenant)| {
+            let (peer_id, multiaddrs) = tenant
+                .status
+                .update(|s| (s.local_peer_id.clone(), s.listen_addrs.clone()));
+
+            let entry = discovery::Entry {
+                index: format!("sparse+{}", tenant.registry().config.base_url),
+                #[cfg(feature = "nostr")]
+                pubkey: tenant.nostr_pubkey(),
+                #[cfg(not(feature = "nostr"))]
+                pubkey: None,
+                peer_id,
+                multiaddrs,
+            };
+            (name.to_owned(), entry)
+        })
+        .collect();
+
+    let document = discovery::Document { registries };
+
+    ([(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], Json(document)).into_response()
+}
+
+/// Refuses sources that keep presenting bad credentials. Each failure
+/// past the first few doubles how long the source is locked out.
+async fn lockout(State(state): State<Tenant>, request: Request, next: Next) -> Response {
+    let source = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let Some(source) = source else {
+        return next.run(request).await;
+    };
+
+    if let Some(secs) = state.status.update(|s| s.lockout_remaining(&source)) {
+        let body = ErrorBody {
+            code: "E_LOCKED_OUT",
+            message: format!("Too many failed authentications; try again in {secs} seconds"),
+            causes: vec![],
+        };
+
+        let mut response = ApiError(StatusCode::TOO_MANY_REQUESTS, body).into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, secs.into());
+        return response;
+    }
+
+    let presented = request.headers().contains_key(header::AUTHORIZATION);
+    let response = next.run(request).await;
+
+    if presented {
+        if response.status() == StatusCode::UNAUTHORIZED {
+            auth_failed(&state, source);
+        } else if response.status().is_success() {
+            state.status.update(|s| s.clear_auth_failures(&source));
+        }
+    }
+
+    response
+}
+
+fn auth_failed(state: &Tenant, source: String) {
+    let lockout = state.status.update(|s| s.record_auth_failure(&source));
+    let Some((failures, seconds)) = lockout else {
+        return;
+    };
+
+    println!("Locking out {source} for {seconds} seconds after {failures} failed authentications");
+
+    let event = audit::Event::AuthLockout {
+        source,
+        failures,
+        seconds,
+    };
+    if let Err(e) = state.registry().record(event) {
+        eprintln!("Warning: {e}");
+    }
+}
+
+async fn count_downloads(State(state): State<Tenant>, request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_owned();
+    let response = next.run(request).await;
+
+    // Redirects to a signed URL are downloads, too
+    let status = response.status();
+    if status.is_success() || status.is_redirection() {
+        if let Some((name, version)) = crate_download(&path) {
+            state.status.update(|s| s.count_download(name, version));
+        }
+    }
+
+    response
+}
+
+/// Counts `.crate` downloads made with a token against the token's
+/// team, and refuses them once the team has used up its quota for the
+/// month.
+async fn account_downloads(State(state): State<Tenant>, request: Request, next: Next) -> Response {
+    let Some(usage) = state.usage.clone() else {
+        return next.run(request).await;
+    };
+    if crate_download(request.uri().path()).is_none() {
+        return next.run(request).await;
+    }
+    let grant = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|t| state.grant_for_token(t));
+    let Some(grant) = grant else {
+        return next.run(request).await;
+    };
+
+    let now = Timestamp::now();
+    if !usage.allows(&grant.user, now) {
+        let body = ErrorBody {
+            code: "E_QUOTA_EXCEEDED",
+            message: format!(
+                "The team of {} has used up its downloads for {}",
+                grant.user,
+                usage::month_of(now),
+            ),
+            causes: vec![],
+        };
+        return ApiError(StatusCode::TOO_MANY_REQUESTS, body).into_response();
+    }
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+    if status.is_success() || status.is_redirection() {
+        usage.record(&grant.user, now);
+    }
+
+    response
+}
+
+/// Copies a demoted `.crate` file back from the cold tier before it is
+/// served, and marks served files as used so they stay on local disk.
+async fn promote_demoted(State(state): State<Tenant>, request: Request, next: Next) -> Response {
+    let registry = &state.registry();
+    let path = request.uri().path();
+    let Some(cold_dir) = registry.config.tiering.cold_dir.clone() else {
+        return next.run(request).await;
+    };
+    if crate_download(path).is_none() {
+        return next.run(request).await;
+    }
+
+    let root = registry.path.clone();
+    let relative = std::path::PathBuf::from(path.trim_start_matches('/'));
+    let prepared = telemetry::spawn_blocking("tier.prepare_download", move || {
+        tier::prepare_download(&root, &cold_dir, &relative)
+    })
+    .await;
+
+    // The file is served from wherever it is, or not found, regardless
+    match prepared {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("Warning: {e}"),
+        Err(e) => eprintln!("Warning: {e}"),
+    }
+
+    next.run(request).await
+}
+
+/// Sends `.crate` downloads to object storage rather than serving them,
+/// once the token and quarantine checks have passed.
+#[cfg(feature = "signed-urls")]
+async fn redirect_to_signed_url(
+    State(state): State<Tenant>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let Some(signer) = &state.url_signer else {
+        return next.run(request).await;
+    };
+    if crate_download(path).is_none() {
+        return next.run(request).await;
+    }
+
+    let url = signer.sign(path, Timestamp::now());
+    let headers = [
+        (header::LOCATION, url.to_string()),
+        // The URL stops working long before a cache would let it go
+        (header::CACHE_CONTROL, "no-store".to_owned()),
+    ];
+    (StatusCode::TEMPORARY_REDIRECT, headers).into_response()
+}
+
+/// Sends `.crate` downloads to the nearest healthy mirror, once the
+/// token and quarantine checks have passed. Served here when no mirror
+/// is healthy.
+#[cfg(feature = "download-mirrors")]
+async fn redirect_to_mirror(State(state): State<Tenant>, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    let Some(mirrors) = &state.download_mirrors else {
+        return next.run(request).await;
+    };
+    if crate_download(path).is_none() {
+        return next.run(request).await;
+    }
+
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let region = mirrors
+        .region_header()
+        .and_then(|name| request.headers().get(name))
+        .and_then(|value| value.to_str().ok());
+
+    let Some(url) = mirrors.redirect(path, client, region) else {
+        return next.run(request).await;
+    };
+    let headers = [
+        (header::LOCATION, url.to_string()),
+        // Another mirror may be nearer or healthier next time
+        (header::CACHE_CONTROL, "no-store".to_owned()),
+    ];
+    (StatusCode::TEMPORARY_REDIRECT, headers).into_response()
+}
+
+/// Versions quarantined for a checksum conflict or by an operator are
+/// not served, whether the `.crate` file would come from the registry
+/// or from an upstream.
+async fn refuse_quarantined(State(state): State<Tenant>, request: Request, next: Next) -> Response {
+    let download = crate_download(request.uri().path())
+        .and_then(|(name, version)| Some((name.to_owned(), version.parse::<Version>().ok()?)));
+    let Some((name, version)) = download else {
+        return next.run(request).await;
+    };
+
+    match quarantine_message(&state.registry(), &name, &version) {
+        Ok(None) => next.run(request).await,
+        Ok(Some(message)) => {
+            let body = ErrorBody {
+                code: "E_QUARANTINED",
+                message,
+                causes: vec![],
+            };
+            ApiError(StatusCode::FORBIDDEN, body).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Why the version may not be downloaded, if it may not.
+fn quarantine_message(
+    registry: &Registry,
+    name: &str,
+    version: &Version,
+) -> Result<Option<String>, ApiError> {
+    let internal = StatusCode::INTERNAL_SERVER_ERROR;
+
+    let conflicted = registry
+        .is_quarantined(name, version)
+        .map_err(|e| ApiError::new(internal, e.code(), &e))?;
+    if conflicted {
+        return Ok(Some(format!(
+            "{name} {version} is quarantined; its sources disagree about its checksum"
+        )));
+    }
+
+    let withheld = registry
+        .find_withheld(name, version)
+        .map_err(|e| ApiError::new(internal, e.code(), &e))?;
+
+    Ok(withheld.map(|w| match w.reason {
+        Some(reason) => format!("{name} {version} is quarantined: {reason}"),
+        None => format!("{name} {version} is quarantined"),
+    }))
+}
+
+/// Answers from the upstream registry what the local files could not.
+#[cfg(feature = "proxy")]
+async fn proxy_fetch(proxy: Arc<crate::proxy::Proxy>, uri: Uri) -> Response {
+    let path = uri.path().to_owned();
+
+    let fetched = telemetry::spawn_blocking("proxy.fetch", move || proxy.fetch(&path)).await;
+
+    match fetched {
+        Ok(Ok(Some(fetched))) => {
+            ([(header::CONTENT_TYPE, fetched.content_type)], fetched.data).into_response()
+        }
+        Ok(Ok(None)) => StatusCode::NOT_FOUND.into_response(),
+        Ok(Err(e)) => {
+            eprintln!("Warning: {e}");
+            ApiError::new(StatusCode::BAD_GATEWAY, e.code(), &e).into_response()
+        }
+        Err(e) => {
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e).into_response()
+        }
+    }
+}
+
+/// Recognizes `/crates/{prefix...}/{name}/{version}.crate`.
+fn crate_download(path: &str) -> Option<(&str, &str)> {
+    let rest = path.strip_prefix("/crates/")?;
+    let (dirs, file) = rest.rsplit_once('/')?;
+    let version = file.strip_suffix(".crate")?;
+    let name = dirs.rsplit('/').next()?;
+    Some((name, version))
+}
+
+/// Cargo ignores everything except the warnings.
+#[derive(Serialize, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+struct PublishResponse {
+    warnings: PublishWarnings,
+}
+
+#[derive(Serialize, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+struct PublishWarnings {
+    invalid_categories: Vec<String>,
+    invalid_badges: Vec<String>,
+    other: Vec<String>,
+}
+
+/// The publishing state of the tenant and what the request's token
+/// allows.
+fn authorize(
+    state: &Tenant,
+    headers: &HeaderMap,
+) -> Result<(Arc<auth::Publisher>, Grant), WriteError> {
+    use write_error::*;
+
+    let publisher = state.publish.clone().context(DisabledSnafu)?;
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .context(MissingTokenSnafu)?;
+    let grant = state.grant_for_token(token).context(InvalidTokenSnafu)?;
+
+    Ok((publisher, grant))
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/api/v1/crates/new",
+        responses(
+            (status = 200, description = "Published, perhaps with warnings", body = PublishResponse),
+        ),
+    )
+)]
+async fn publish(
+    State(state): State<Tenant>,
+    global: &'static Global,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<PublishResponse>, ApiError> {
+    use write_error::*;
+
+    let (publisher, grant) = authorize(&state, &headers)?;
+
+    // Only the `.crate` file is kept, and on disk until it is added
+    let max_crate_len = state
+        .registry()
+        .config
+        .limits
+        .max_crate_size
+        .unwrap_or(PUBLISH_BODY_LIMIT);
+    let dir = publisher.data_dir().join(upload::DIR_NAME);
+    let upload = upload::receive(body, &dir, PUBLISH_BODY_LIMIT, max_crate_len)
+        .await
+        .context(UploadSnafu)?;
+
+    let response = telemetry::spawn_blocking("publish", move || {
+        let crate_file = upload.map().context(UploadSnafu)?;
+        let cksum = &upload.sha256;
+        publish_blocking(&state, &publisher, global, &grant, &crate_file, cksum)
+    })
+    .await
+    .context(JoinSnafu)??;
+
+    Ok(Json(response))
+}
+
+fn publish_blocking(
+    state: &Tenant,
+    publisher: &auth::Publisher,
+    global: &Global,
+    grant: &Grant,
+    crate_file: &[u8],
+    cksum: &str,
+) -> Result<PublishResponse, WriteError> {
+    use write_error::*;
+
+    let registry = &state.registry();
+    registry.check_size(crate_file).context(PackageSnafu)?;
+    let package = read_cargo_toml(crate_file).context(PackageSnafu)?.package;
+    let name = package.name;
+    let version = package.version;
+    let user = &grant.user;
+
+    ensure!(
+        grant.may_publish(&name),
+        ScopeSnafu {
+            scope: format!("publish:{name}")
+        }
+    );
+
+    let data_dir = publisher.data_dir();
+    let under_maintenance = maintenance::status(data_dir)
+        .context(MaintenanceSnafu)?
+        .is_some();
+    let needs_approval = publisher.requires_approval();
+
+    // Crates queued earlier go first, so they are not overtaken
+    let mut added = if under_maintenance {
+        vec![]
+    } else {
+        add_queued(state, publisher, global, data_dir)?
+    };
+
+    let crate_lock = publisher.lock_crate(&name);
+
+    ensure!(
+        grant.is_admin() || publisher.lock_owners().may_publish(&name, user),
+        NotOwnerSnafu {
+            name,
+            user: user.clone()
+        }
+    );
+
+    let index =
+        Registry::parse_index_file(&registry.index_file_path_for(&name)).context(IndexSnafu)?;
+    ensure!(
+        !index.contains_key(&version),
+        DuplicateSnafu { name, version }
+    );
+
+    // Admins may take any name
+    if !grant.is_admin() {
+        registry.check_name(crate_file).context(PackageSnafu)?;
+    }
+
+    let findings =
+        telemetry::in_span("registry.scan", || registry.scan(crate_file)).context(ScanSnafu)?;
+    let held = !findings.is_empty();
+
+    let mut response = PublishResponse::default();
+
+    if under_maintenance || needs_approval || held {
+        let queue = publish_queue::list(data_dir).context(PublishQueueSnafu)?;
+        ensure!(
+            !queue
+                .iter()
+                .any(|(_, q)| q.name == name && q.vers == version),
+            DuplicateSnafu { name, version }
+        );
+
+        let queued = publish_queue::Queued {
+            name,
+            vers: version,
+            user: user.clone(),
+            queued_at: Timestamp::now(),
+            needs_approval: needs_approval || held,
+            approval: None,
+            findings: findings.iter().map(ToString::to_string).collect(),
+        };
+        publish_queue::enqueue(data_dir, &queued, crate_file).context(PublishQueueSnafu)?;
+
+        let (name, vers) = (&queued.name, &queued.vers);
+        let warning = if held {
+            println!("{user} queued {name} {vers} for review after a scan");
+            let summary = scan::summary(&findings);
+            format!("{name} {vers} was held for review: {summary}")
+        } else if needs_approval {
+            println!("{user} queued {name} {vers} for approval");
+            format!("{name} {vers} will be added once a reviewer approves it")
+        } else {
+            println!("{user} queued {name} {vers} during maintenance");
+            format!("The registry is under maintenance; {name} {vers} will be added when it ends")
+        };
+        response.warnings.other.push(warning);
+    } else {
+        let cksum = Some(cksum);
+        let published = add_published(state, publisher, global, user, crate_file, cksum)?;
+        added.push(published);
+    }
+
+    drop(crate_lock);
+
+    finish_publishing(state, &added);
+
+    Ok(response)
+}
+
+/// Adds the crate and makes the user an owner of it, with the crate
+/// already locked. `cksum` is the crate's digest when it was taken as
+/// the crate was received.
+fn add_published(
+    state: &Tenant,
+    publisher: &auth::Publisher,
+    global: &Global,
+    user: &UserId,
+    crate_file: &[u8],
+    cksum: Option<&str>,
+) -> Result<(CrateName, Version), WriteError> {
+    use write_error::*;
+
+    let registry = &state.registry();
+    let (name, version) = telemetry::in_span("registry.add", || match cksum {
+        Some(cksum) => registry.add_package_with_cksum(global, crate_file, cksum.to_owned()),
+        None => registry.add_package(global, crate_file),
+    })
+    .context(AddSnafu)?;
+    let mut owners = publisher.lock_owners();
+    owners.claim(&name, user).context(OwnersSnafu)?;
+
+    #[cfg(feature = "nostr")]
+    if let Some(notifier) = &state.notifier {
+        let message = format!(
+            "{user} published {name} {version} to {}",
+            registry.config.base_url
+        );
+        notifier.notify(&owners.owners_of(&name), &message);
+    }
+    drop(owners);
+
+    println!("{user} published {name} {version}");
+    let (vers, by) = (version.to_string(), user.to_string());
+    state
+        .status
+        .update(|s| s.record_publish(name.as_str(), &vers, &by));
+
+    Ok((name, version))
+}
+
+/// Adds the queued crates that are not waiting for approval, oldest
+/// first. One that can no longer be added is set aside.
+fn add_queued(
+    state: &Tenant,
+    publisher: &auth::Publisher,
+    global: &Global,
+    data_dir: &std::path::Path,
+) -> Result<Vec<(CrateName, Version)>, WriteError> {
+    use write_error::*;
+
+    let _queue_lock = publisher.lock_queue();
+
+    let mut added = vec![];
+    for (id, queued) in publish_queue::list(data_dir).context(PublishQueueSnafu)? {
+        if !queued.is_ready() {
+            continue;
+        }
+
+        let _crate_lock = publisher.lock_crate(&queued.name);
+
+        let index_path = state.registry().index_file_path_for(&queued.name);
+        let index = Registry::parse_index_file(&index_path).context(IndexSnafu)?;
+        let may_publish = publisher
+            .lock_owners()
+            .may_publish(&queued.name, &queued.user);
+
+        if index.contains_key(&queued.vers) || !may_publish {
+            eprintln!(
+                "Warning: {} {} queued by {} can no longer be added; set it aside",
+                queued.name, queued.vers, queued.user,
+            );
+            publish_queue::set_aside(data_dir, id).context(PublishQueueSnafu)?;
+            continue;
+        }
+
+        if let Some(approval) = &queued.approval {
+            let event = audit::Event::Approve {
+                name: queued.name.clone(),
+                vers: queued.vers.clone(),
+                publisher: queued.user.to_string(),
+                reviewer: approval.reviewer.clone(),
+            };
+            state.registry().record(event).context(AuditSnafu)?;
+        }
+
+        let crate_file = publish_queue::crate_file(data_dir, id).context(PublishQueueSnafu)?;
+        let user = &queued.user;
+        let published = add_published(state, publisher, global, user, &crate_file, None)?;
+        added.push(published);
+        publish_queue::dequeue(data_dir, id).context(PublishQueueSnafu)?;
+    }
+
+    Ok(added)
+}
+
+/// The slower work after adding crates, done once they are unlocked.
+fn finish_publishing(state: &Tenant, added: &[(CrateName, Version)]) {
+    if added.is_empty() {
+        return;
+    }
+
+    // Built by the docs worker, so that the publish does not wait for
+    // `cargo doc`
+    let publisher = state.publish.as_ref();
+    if let Some(publisher) = publisher.filter(|_| state.registry().builds_docs()) {
+        for (name, version) in added {
+            if let Err(e) = docs_queue::enqueue(publisher.data_dir(), name, version) {
+                eprintln!("Warning: {e}");
+            }
+        }
+        state.docs_queue.request();
+    }
+    state.regenerator.request();
+}
+
+/// Adds queued crates soon after maintenance ends or they are approved,
+/// even if nobody publishes again.
+pub fn start_queue_worker(tenant: Tenant, global: &'static Global) {
+    let Some(publisher) = tenant.publish.clone() else {
+        return;
+    };
+
+    std::thread::spawn(move || loop {
+        std::thread::sleep(QUEUE_INTERVAL);
+
+        let data_dir = publisher.data_dir();
+        let pending = match (maintenance::status(data_dir), publish_queue::list(data_dir)) {
+            (Ok(None), Ok(queue)) => queue.iter().any(|(_, q)| q.is_ready()),
+            (Err(e), _) => {
+                eprintln!("Warning: {e}");
+                false
+            }
+            (_, Err(e)) => {
+                eprintln!("Warning: {e}");
+                false
+            }
+            _ => false,
+        };
+        if !pending {
+            continue;
+        }
+
+        match add_queued(&tenant, &publisher, global, data_dir) {
+            Ok(added) => finish_publishing(&tenant, &added),
+            Err(e) => eprintln!("Warning: {e}"),
+        }
+    });
+}
+
+/// Demotes `.crate` files nobody has downloaded for a while, when the
+/// registry has a cold tier.
+pub fn start_demotion_worker(tenant: Tenant) {
+    if tenant.registry().config.tiering.cold_dir.is_none() {
+        return;
+    }
+
+    std::thread::spawn(move || loop {
+        std::thread::sleep(DEMOTION_INTERVAL);
+
+        match tenant.registry().demote(false) {
+            Ok(summary) if summary.demoted > 0 => println!(
+                "Demoted {} crates, {} bytes, to the cold tier",
+                summary.demoted, summary.bytes,
+            ),
+            Ok(_) => {}
+            Err(e) => eprintln!("Warning: {e}"),
+        }
+    });
+}
+
+/// Loads the tenant's catalog in the background, so the daemon answers
+/// while it loads.
+pub fn start_catalog_loader(tenant: Tenant) {
+    std::thread::spawn(move || {
+        let base_path = tenant.base_path();
+        if let Err(e) = tenant.catalog.load(&tenant.registry()) {
+            eprintln!("Warning: Could not load the crates at {base_path}: {e}");
+        } else if let Some((crates, loaded_in)) = tenant.catalog.loaded() {
+            println!("Loaded {crates} crates at {base_path} in {loaded_in:.3?}");
+        }
+    });
+}
+
+/// Regenerates the HTML, feed and snapshot of the tenant once changes
+/// made through the daemon stop.
+pub fn start_regenerator(tenant: Tenant) {
+    std::thread::spawn(move || loop {
+        let quiet_ms = tenant.registry().config.regenerate.quiet_ms;
+        let quiet = Duration::from_millis(quiet_ms.unwrap_or(regenerate::DEFAULT_QUIET_MS));
+        tenant.regenerator.wait(quiet);
+
+        let registry = tenant.registry();
+        let passed =
+            telemetry::in_span("registry.regenerate", || tenant.regenerator.pass(&registry));
+        if let Err(e) = passed {
+            eprintln!("Warning: {e}");
+        }
+    });
+}
+
+/// Builds the documentation publishes queued, starting with any left
+/// from before the daemon started.
+pub fn start_docs_worker(tenant: Tenant) {
+    let Some(publisher) = tenant.publish.clone() else {
+        return;
+    };
+
+    std::thread::spawn(move || loop {
+        let data_dir = publisher.data_dir();
+        match docs_queue::list(data_dir) {
+            Ok(queued) => {
+                for (name, version) in queued {
+                    telemetry::in_span("registry.build_docs", || {
+                        tenant.registry().maybe_build_docs(&name, &version)
+                    });
+                    if let Err(e) = docs_queue::dequeue(data_dir, &name, &version) {
+                        eprintln!("Warning: {e}");
+                    }
+                }
+            }
+            Err(e) => eprintln!("Warning: {e}"),
+        }
+
+        tenant.docs_queue.wait();
+    });
+}
+
+/// Every crate's summary, from the catalog once it is loaded and from
+/// the index files until then.
+fn summaries(state: &Tenant) -> Result<Arc<catalog::Summaries>, ApiError> {
+    let registry = state.registry();
+    match state.catalog.get(&registry)? {
+        Some(summaries) => Ok(summaries),
+        None => Ok(Arc::new(registry.list_summaries()?)),
+    }
+}
+
+/// Cargo only checks that the response is successful.
+#[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+struct OkResponse {
+    ok: bool,
+}
+
+/// Approves a publish that is waiting for approval and, unless the
+/// registry is under maintenance, adds it.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/api/v1/crates/{name}/{version}/approve",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("version" = String, Path),
+        ),
+        responses(
+            (status = 200, description = "Approved", body = OkResponse),
+        ),
+    )
+)]
+async fn approve(
+    State(state): State<Tenant>,
+    global: &'static Global,
+    Path((name, version)): Path<(String, Version)>,
+    headers: HeaderMap,
+) -> Result<Json<OkResponse>, ApiError> {
+    use write_error::*;
+
+    let (publisher, grant) = authorize(&state, &headers)?;
+    ensure!(grant.may_approve(), ScopeSnafu { scope: "approve" });
+
+    let name = name.parse::<CrateName>().context(lookup_error::NameSnafu)?;
+
+    telemetry::spawn_blocking("approve", move || -> Result<(), WriteError> {
+        let data_dir = publisher.data_dir();
+        let reviewer = grant.user.to_string();
+        publish_queue::approve(data_dir, &name, &version, &reviewer).context(PublishQueueSnafu)?;
+        println!("{reviewer} approved {name} {version}");
+
+        let under_maintenance = maintenance::status(data_dir)
+            .context(MaintenanceSnafu)?
+            .is_some();
+        if under_maintenance {
+            return Ok(());
+        }
+
+        let added = add_queued(&state, &publisher, global, data_dir)?;
+        finish_publishing(&state, &added);
+
+        Ok(())
+    })
+    .await
+    .context(JoinSnafu)??;
+
+    Ok(Json(OkResponse { ok: true }))
+}
+
+/// Sets aside a publish that is waiting for approval.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/api/v1/crates/{name}/{version}/reject",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("version" = String, Path),
+        ),
+        responses(
+            (status = 200, description = "Rejected", body = OkResponse),
+        ),
+    )
+)]
+async fn reject(
+    State(state): State<Tenant>,
+    Path((name, version)): Path<(String, Version)>,
+    headers: HeaderMap,
+) -> Result<Json<OkResponse>, ApiError> {
+    use write_error::*;
+
+    let (publisher, grant) = authorize(&state, &headers)?;
+    ensure!(grant.may_approve(), ScopeSnafu { scope: "approve" });
+
+    let name = name.parse::<CrateName>().context(lookup_error::NameSnafu)?;
+
+    telemetry::spawn_blocking("reject", move || -> Result<(), WriteError> {
+        publish_queue::reject(publisher.data_dir(), &name, &version).context(PublishQueueSnafu)?;
+        println!("{} rejected {name} {version}", grant.user);
+
+        Ok(())
+    })
+    .await
+    .context(JoinSnafu)??;
+
+    Ok(Json(OkResponse { ok: true }))
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        delete,
+        path = "/api/v1/crates/{name}/{version}/yank",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("version" = String, Path),
+        ),
+        responses(
+            (status = 200, description = "Yanked", body = OkResponse),
+        ),
+    )
+)]
+async fn yank(
+    state: State<Tenant>,
+    path: Path<(String, Version)>,
+    headers: HeaderMap,
+) -> Result<Json<OkResponse>, ApiError> {
+    set_yanked(state, path, headers, true).await
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/api/v1/crates/{name}/{version}/unyank",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("version" = String, Path),
+        ),
+        responses(
+            (status = 200, description = "Unyanked", body = OkResponse),
+        ),
+    )
+)]
+async fn unyank(
+    state: State<Tenant>,
+    path: Path<(String, Version)>,
+    headers: HeaderMap,
+) -> Result<Json<OkResponse>, ApiError> {
+    set_yanked(state, path, headers, false).await
+}
+
+async fn set_yanked(
+    State(state): State<Tenant>,
+    Path((name, version)): Path<(String, Version)>,
+    headers: HeaderMap,
+    yanked: bool,
+) -> Result<Json<OkResponse>, ApiError> {
+    use write_error::*;
+
+    let (publisher, grant) = authorize(&state, &headers)?;
+    ensure!(grant.may_yank(), ScopeSnafu { scope: "yank" });
+
+    let (name, _) = lookup(&state.registry(), &name)?;
+
+    telemetry::spawn_blocking("yank", move || -> Result<(), WriteError> {
+        let crate_lock = publisher.lock_crate(&name);
+        let is_owner = publisher.lock_owners().is_owner(&name, &grant.user);
+        ensure!(
+            grant.is_admin() || is_owner,
+            NotOwnerSnafu {
+                name,
+                user: grant.user
+            }
+        );
+
+        let registry = &state.registry();
+        registry
+            .yank(name.clone(), version.clone(), yanked)
+            .context(YankSnafu)?;
+
+        let action = if yanked { "yanked" } else { "unyanked" };
+
+        #[cfg(feature = "nostr")]
+        if let Some(notifier) = &state.notifier {
+            let message = format!(
+                "{} {action} {name} {version} in {}",
+                grant.user, registry.config.base_url,
+            );
+            notifier.notify(&publisher.lock_owners().owners_of(&name), &message);
+        }
+
+        drop(crate_lock);
+
+        println!("{} {action} {name} {version}", grant.user);
+
+        state.regenerator.request();
+
+        Ok(())
+    })
+    .await
+    .context(JoinSnafu)??;
+
+    Ok(Json(OkResponse { ok: true }))
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+struct VisibilityRequest {
+    visibility: Visibility,
+}
+
+/// Owners set who may see their crate with a body such as
+/// `{"visibility": "private"}`.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/api/v1/crates/{name}/visibility",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+        ),
+        request_body = VisibilityRequest,
+        responses(
+            (status = 200, description = "Changed", body = OkResponse),
+        ),
+    )
+)]
+async fn set_visibility(
+    State(state): State<Tenant>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<OkResponse>, ApiError> {
+    use write_error::*;
+
+    let (publisher, grant) = authorize(&state, &headers)?;
+
+    let VisibilityRequest { visibility } = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "E_BAD_REQUEST", &e))?;
+
+    let (name, _) = lookup(&state.registry(), &name)?;
+    ensure!(
+        grant.may_publish(&name),
+        ScopeSnafu {
+            scope: format!("publish:{name}")
+        }
+    );
+
+    telemetry::spawn_blocking("set_visibility", move || -> Result<(), WriteError> {
+        let _crate_lock = publisher.lock_crate(&name);
+        let owners = publisher.lock_owners();
+        ensure!(
+            grant.is_admin() || owners.is_owner(&name, &grant.user),
+            NotOwnerSnafu {
+                name,
+                user: grant.user
+            }
+        );
+        drop(owners);
+
+        let registry = &state.registry();
+        registry
+            .set_visibility(name.clone(), visibility)
+            .context(VisibilitySnafu)?;
+
+        println!("{} made {name} {visibility}", grant.user);
+
+        state.regenerator.request();
+
+        Ok(())
+    })
+    .await
+    .context(JoinSnafu)??;
+
+    Ok(Json(OkResponse { ok: true }))
+}
+
+/// The body is the attestation itself, as `margo attestation add`
+/// would read it from a file.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/api/v1/crates/{name}/{version}/attestations",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("version" = String, Path),
+        ),
+        responses(
+            (status = 200, description = "Stored", content_type = "application/json"),
+        ),
+    )
+)]
+async fn attest(
+    State(state): State<Tenant>,
+    Path((name, version)): Path<(String, Version)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<AttestationDetail>, ApiError> {
+    use write_error::*;
+
+    let (publisher, grant) = authorize(&state, &headers)?;
+
+    let (name, _) = lookup(&state.registry(), &name)?;
+    ensure!(
+        grant.may_publish(&name),
+        ScopeSnafu {
+            scope: format!("publish:{name}")
+        }
+    );
+
+    let detail = telemetry::spawn_blocking("attest", move || -> Result<_, WriteError> {
+        let _crate_lock = publisher.lock_crate(&name);
+        let owners = publisher.lock_owners();
+        ensure!(
+            grant.is_admin() || owners.is_owner(&name, &grant.user),
+            NotOwnerSnafu {
+                name,
+                user: grant.user
+            }
+        );
+        drop(owners);
+
+        let registry = &state.registry();
+        let attestation =
+            attestation::attach(registry, &name, &version, &body).context(AttestSnafu)?;
+
+        println!(
+            "{} attached {} `{}` to {name} {version}",
+            grant.user, attestation.predicate_type, attestation.digest,
+        );
+
+        Ok(AttestationDetail::new(
+            registry,
+            &name,
+            &version,
+            attestation,
+        ))
+    })
+    .await
+    .context(JoinSnafu)??;
+
+    Ok(Json(detail))
+}
+
+/// The body is the file itself.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/api/v1/crates/{name}/{version}/artifacts/{target}/{file}",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("version" = String, Path),
+            ("target" = String, Path, description = "The target triple"),
+            ("file" = String, Path),
+        ),
+        responses(
+            (status = 200, description = "Stored", content_type = "application/json"),
+        ),
+    )
+)]
+async fn add_artifact(
+    State(state): State<Tenant>,
+    Path((name, version, target, file)): Path<(String, Version, String, String)>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<ArtifactDetail>, ApiError> {
+    use write_error::*;
+
+    let (publisher, grant) = authorize(&state, &headers)?;
+
+    let (name, _) = lookup(&state.registry(), &name)?;
+    ensure!(
+        grant.may_publish(&name),
+        ScopeSnafu {
+            scope: format!("publish:{name}")
+        }
+    );
+
+    let dir = publisher.data_dir().join(upload::DIR_NAME);
+    let upload = upload::receive_file(body, &dir, ARTIFACT_BODY_LIMIT)
+        .await
+        .context(UploadSnafu)?;
+
+    let detail = telemetry::spawn_blocking("add_artifact", move || -> Result<_, WriteError> {
+        let _crate_lock = publisher.lock_crate(&name);
+        let owners = publisher.lock_owners();
+        ensure!(
+            grant.is_admin() || owners.is_owner(&name, &grant.user),
+            NotOwnerSnafu {
+                name,
+                user: grant.user
+            }
+        );
+        drop(owners);
+
+        let registry = &state.registry();
+        let body = upload.map().context(UploadSnafu)?;
+        let artifact = artifact::add(registry, &name, &version, &target, &file, &body)
+            .context(ArtifactSnafu)?;
+
+        println!(
+            "{} added {} for {} to {name} {version}",
+            grant.user, artifact.file, artifact.target,
+        );
+
+        Ok(ArtifactDetail::new(registry, &name, &version, artifact))
+    })
+    .await
+    .context(JoinSnafu)??;
+
+    Ok(Json(detail))
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+struct BlobResponse {
+    digest: String,
+    size: u64,
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    url: Option<Url>,
+}
+
+/// The body is the blob itself; the response names it.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/api/v1/blobs",
+        responses(
+            (status = 200, description = "Stored", body = BlobResponse),
+        ),
+    )
+)]
+async fn put_blob(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<BlobResponse>, ApiError> {
+    use write_error::*;
+
+    let (publisher, grant) = authorize(&state, &headers)?;
+    ensure!(grant.may_put_blobs(), ScopeSnafu { scope: "blob" });
+
+    let dir = publisher.data_dir().join(upload::DIR_NAME);
+    let upload = upload::receive_file(body, &dir, BLOB_BODY_LIMIT)
+        .await
+        .context(UploadSnafu)?;
+
+    let response = telemetry::spawn_blocking("put_blob", move || -> Result<_, WriteError> {
+        let registry = &state.registry();
+        let data = upload.map().context(UploadSnafu)?;
+        let (digest, new) = blob::put(registry, &data).context(BlobSnafu)?;
+
+        if new {
+            println!("{} stored blob {digest}", grant.user);
+        }
+
+        Ok(BlobResponse {
+            url: registry.blob_url_for(&digest),
+            digest,
+            size: upload.len,
+        })
+    })
+    .await
+    .context(JoinSnafu)??;
+
+    Ok(Json(response))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum WriteError {
+    #[snafu(display("Publishing is not enabled for this registry"))]
+    Disabled,
+
+    #[snafu(display("A token is required to modify this registry"))]
+    MissingToken,
+
+    #[snafu(display("The token is not valid for this registry"))]
+    InvalidToken,
+
+    #[snafu(display("The token does not have the `{scope}` scope"))]
+    Scope { scope: String },
+
+    #[snafu(display("Could not receive the `cargo publish` upload"))]
+    Upload { source: upload::Error },
+
+    #[snafu(display("The crate package is not valid"))]
+    Package { source: AddError },
+
+    #[snafu(display("`{user}` is not an owner of `{name}`"))]
+    NotOwner { name: CrateName, user: UserId },
+
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: ParseIndexError },
+
+    #[snafu(display("`{name}` {version} has already been published"))]
+    Duplicate { name: CrateName, version: Version },
+
+    #[snafu(display("Could not add the crate"))]
+    Add { source: AddError },
+
+    #[snafu(display("Could not change whether the version is yanked"))]
+    Yank { source: YankError },
+
+    #[snafu(display("Could not change who may see the crate"))]
+    Visibility { source: VisibilityError },
+
+    #[snafu(display("Could not record the crate's owner"))]
+    Owners { source: auth::Error },
+
+    #[snafu(display("Could not attach the attestation"))]
+    Attest { source: attestation::Error },
+
+    #[snafu(display("Could not add the artifact"))]
+    Artifact { source: artifact::Error },
+
+    #[snafu(display("Could not store the blob"))]
+    Blob { source: blob::Error },
+
+    #[snafu(display("Could not check whether the registry is under maintenance"))]
+    Maintenance { source: maintenance::Error },
+
+    #[snafu(display("Could not update the publish queue"))]
+    PublishQueue { source: publish_queue::Error },
+
+    #[snafu(display("Could not scan the crate"))]
+    Scan { source: scan::Error },
+
+    #[snafu(display("Could not record the approval in the audit log"))]
+    Audit { source: audit::Error },
+
+    #[snafu(display("The request's task did not complete"))]
+    Join { source: tokio::task::JoinError },
+}
+
+impl From<WriteError> for ApiError {
+    fn from(e: WriteError) -> Self {
+        use WriteError::*;
+
+        let (status, code) = match &e {
+            Disabled => (StatusCode::NOT_FOUND, "E_PUBLISH_DISABLED"),
+            MissingToken | InvalidToken => (StatusCode::UNAUTHORIZED, "E_UNAUTHORIZED"),
+            Scope { .. } => (StatusCode::FORBIDDEN, "E_FORBIDDEN_SCOPE"),
+            Upload { source } => (upload_status(source), source.code()),
+            Package { source } if source.code() == "E_CRATE_TOO_LARGE" => {
+                (StatusCode::PAYLOAD_TOO_LARGE, source.code())
+            }
+            Package { source } => (StatusCode::BAD_REQUEST, source.code()),
+            NotOwner { .. } => (StatusCode::FORBIDDEN, "E_NOT_OWNER"),
+            Duplicate { .. } => (StatusCode::CONFLICT, "E_DUP_VERSION"),
+            Yank { source } if source.code() == "E_VERSION_NOT_FOUND" => {
+                (StatusCode::NOT_FOUND, source.code())
+            }
+            Index { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "E_INDEX_CORRUPT"),
+            Add { source } => (StatusCode::INTERNAL_SERVER_ERROR, source.code()),
+            Yank { source } => (StatusCode::INTERNAL_SERVER_ERROR, source.code()),
+            Visibility { source } => (StatusCode::INTERNAL_SERVER_ERROR, source.code()),
+            Owners { source } => (StatusCode::INTERNAL_SERVER_ERROR, source.code()),
+            Attest { source } => (attestation_status(source), source.code()),
+            Artifact { source } => (artifact_status(source), source.code()),
+            Blob { source } => (StatusCode::INTERNAL_SERVER_ERROR, source.code()),
+            Maintenance { source } => (StatusCode::INTERNAL_SERVER_ERROR, source.code()),
+            PublishQueue { source } => (publish_queue_status(source), source.code()),
+            Scan { source } => (StatusCode::BAD_REQUEST, source.code()),
+            Audit { source } => (StatusCode::INTERNAL_SERVER_ERROR, source.code()),
+            Join { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL"),
+        };
+
+        Self::new(status, code, &e)
+    }
+}
+
+#[cfg(any(feature = "oidc", feature = "ldap"))]
+#[derive(Serialize)]
+struct MintResponse {
+    #[serde(flatten)]
+    summary: auth::TokenSummary,
+    token: String,
+}
+
+/// Exchanges SSO credentials for a registry token that `cargo login`
+/// can store. The optional JSON body is an [`auth::MintRequest`].
+#[cfg(any(feature = "oidc", feature = "ldap"))]
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        post,
+        path = "/api/v1/tokens",
+        responses(
+            (status = 200, description = "A new token for `cargo login`", content_type = "application/json"),
+        ),
+    )
+)]
+async fn mint_token(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<MintResponse>, ApiError> {
+    use write_error::*;
+
+    let publisher = state.publish.clone().context(DisabledSnafu)?;
+
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .context(MissingTokenSnafu)?
+        .to_owned();
+
+    let request = if body.is_empty() {
+        auth::MintRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "E_BAD_REQUEST", &e))?
+    };
+
+    let (summary, token) = telemetry::spawn_blocking("mint_token", move || {
+        publisher.mint(&authorization, request)
+    })
+    .await
+    .context(JoinSnafu)?
+    .map_err(|e| {
+        let status = match e.code() {
+            "E_UNAUTHORIZED" => StatusCode::UNAUTHORIZED,
+            "E_FORBIDDEN" => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError::new(status, e.code(), &e)
+    })?;
+
+    Ok(Json(MintResponse { summary, token }))
+}
+
+#[derive(Serialize)]
+struct CrateSummary<'a> {
+    name: &'a CrateName,
+    newest_version: Option<&'a Version>,
+    versions: usize,
+    downloads: u64,
+}
+
+#[derive(Serialize)]
+struct CrateDetail<'a> {
+    name: &'a CrateName,
+    versions: Vec<VersionDetail<'a>>,
+}
+
+#[derive(Serialize)]
+struct VersionDetail<'a> {
+    #[serde(flatten)]
+    entry: &'a index_entry::Root,
+    downloads: u64,
+    readme: Option<Url>,
+    docs: Option<Url>,
+
+    /// `features` and `features2` together, with the features implied
+    /// by optional dependencies.
+    all_features: features::Features,
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/crates",
+        responses(
+            (status = 200, description = "Every crate the token may see, with its newest version", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_crates(State(state): State<Tenant>, headers: HeaderMap) -> Result<Response, ApiError> {
+    let viewer = Viewer::new(&state, &headers)?;
+    let crates = summaries(&state)?;
+    let visible = crates
+        .iter()
+        .filter(|(name, _)| viewer.may_see(&state, name.as_str()))
+        .collect::<Vec<_>>();
+
+    let summaries = state.status.update(|status| {
+        visible
+            .into_iter()
+            .map(|(name, summary)| CrateSummary {
+                name,
+                newest_version: summary.newest_version.as_ref(),
+                versions: summary.versions,
+                downloads: status.downloads_of(name.as_str()),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    Ok(Json(summaries).into_response())
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/crates/{name}",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+        ),
+        responses(
+            (status = 200, description = "The crate's index entries, with downloads and links", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_crate(
+    State(state): State<Tenant>,
+    Path(name): Path<String>,
+) -> Result<Response, ApiError> {
+    let (name, index) = lookup(&state.registry(), &name)?;
+    let downloads = state
+        .status
+        .update(|s| s.downloads.get(name.as_str()).cloned())
+        .unwrap_or_default();
+
+    let versions = index
+        .values()
+        .map(|entry| VersionDetail {
+            entry,
+            downloads: downloads.get(&entry.vers.to_string()).copied().unwrap_or(0),
+            readme: state.registry().readme_url_for(&name, &entry.vers),
+            docs: state.registry().docs_url_for(&name, &entry.vers),
+            all_features: features::all(entry),
+        })
+        .collect();
+
+    Ok(Json(CrateDetail {
+        name: &name,
+        versions,
+    })
+    .into_response())
+}
+
+/// `?req=^1.2` resolves the requirement the way Cargo would; without
+/// it, every version that is not yanked matches. `&msrv-compatible=1.70`
+/// leaves out versions whose `rust-version` is newer.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/crates/{name}/versions",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("req" = Option<String>, Query, description = "A version requirement, as `^1.2`"),
+            ("msrv-compatible" = Option<String>, Query, description = "Only versions this toolchain meets, as `1.70`"),
+        ),
+        responses(
+            (status = 200, description = "The best and every match of the requirement", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_crate_versions(
+    State(state): State<Tenant>,
+    Path(name): Path<String>,
+    uri: Uri,
+) -> Result<Response, ApiError> {
+    use resolve_error::*;
+
+    let (name, mut index) = lookup(&state.registry(), &name)?;
+
+    let query = url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes());
+    let param = |name: &str| {
+        query
+            .clone()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    };
+
+    let req = param("req")
+        .map(|req| req.parse::<VersionReq>())
+        .transpose()
+        .context(ReqSnafu)?
+        .unwrap_or(VersionReq::STAR);
+
+    let toolchain = param("msrv-compatible")
+        .map(|v| v.parse::<RustVersion>())
+        .transpose()
+        .context(RustVersionSnafu)?;
+    if let Some(toolchain) = &toolchain {
+        index.retain(|_, entry| entry.builds_with(toolchain));
+    }
+
+    let resolution = resolve_versions(&index, &req);
+    ensure!(
+        resolution.best.is_some(),
+        NoMatchSnafu {
+            name,
+            req: req.clone()
+        }
+    );
+
+    Ok(Json(resolution).into_response())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum ResolveError {
+    #[snafu(display("The version requirement is not valid"))]
+    Req { source: semver::Error },
+
+    #[snafu(display("The Rust version is not valid"))]
+    RustVersion { source: RustVersionError },
+
+    #[snafu(display("No version of `{name}` matches `{req}`"))]
+    NoMatch { name: CrateName, req: VersionReq },
+}
+
+impl From<ResolveError> for ApiError {
+    fn from(e: ResolveError) -> Self {
+        let (status, code) = match &e {
+            ResolveError::Req { .. } => (StatusCode::BAD_REQUEST, "E_BAD_VERSION_REQ"),
+            ResolveError::RustVersion { .. } => (StatusCode::BAD_REQUEST, "E_BAD_RUST_VERSION"),
+            ResolveError::NoMatch { .. } => (StatusCode::NOT_FOUND, "E_NO_MATCHING_VERSION"),
+        };
+
+        Self::new(status, code, &e)
+    }
+}
+
+#[derive(Serialize)]
+struct IndexBundle {
+    files: Vec<BundledIndexFile>,
+
+    /// Crates without an index file here, or that the request may not
+    /// see.
+    missing: Vec<String>,
+
+    /// Whether files were left out to stay within the limit.
+    truncated: bool,
+}
+
+#[derive(Serialize)]
+struct BundledIndexFile {
+    /// Where Cargo would fetch the file from, relative to the index.
+    path: String,
+
+    content: String,
+}
+
+/// `?crates=a,b,c` returns the index files of many crates in one
+/// response. `&closure=true` adds those of their normal and build
+/// dependencies from this registry, and of theirs, so that a client
+/// can fill its index cache without a round trip per file.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/index-bundle",
+        params(
+            ("crates" = String, Query, description = "Crate names, separated by commas"),
+            ("closure" = Option<bool>, Query, description = "Also every crate they depend on"),
+        ),
+        responses(
+            (status = 200, description = "Many index files, with their paths", content_type = "application/json"),
+        ),
+    )
+)]
+async fn index_bundle(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, ApiError> {
+    use index_bundle_error::*;
+
+    let mut crates = None;
+    let mut closure = false;
+    for (key, value) in url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+        match &*key {
+            "crates" => crates = Some(value.into_owned()),
+            "closure" => closure = value == "true",
+            _ => {}
+        }
+    }
+
+    let requested = crates
+        .iter()
+        .flat_map(|c| c.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| name.parse::<CrateName>().context(NameSnafu { name }))
+        .collect::<Result<Vec<_>, _>>()?;
+    ensure!(!requested.is_empty(), CratesSnafu);
+    ensure!(requested.len() <= INDEX_BUNDLE_LIMIT, TooManySnafu);
+
+    let viewer = Viewer::new(&state, &headers)?;
+    let registry = state.registry();
+
+    // Names are compared as Cargo compares them, ignoring case
+    let mut seen = BTreeSet::new();
+    let mut queue = VecDeque::new();
+    for name in requested {
+        if seen.insert(name.as_str().to_ascii_lowercase()) {
+            queue.push_back(name);
+        }
+    }
+
+    let mut bundle = IndexBundle {
+        files: vec![],
+        missing: vec![],
+        truncated: false,
+    };
+
+    while let Some(name) = queue.pop_front() {
+        if bundle.files.len() == INDEX_BUNDLE_LIMIT {
+            bundle.truncated = true;
+            break;
+        }
+
+        if !viewer.may_see(&state, name.as_str()) {
+            bundle.missing.push(name.to_string());
+            continue;
+        }
+
+        let content = match fs::read_to_string(registry.index_file_path_for(&name)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                bundle.missing.push(name.to_string());
+                continue;
+            }
+            Err(e) => Err(e).context(ReadSnafu { name })?,
+        };
+
+        if closure {
+            let index =
+                parse_index_lines(content.as_bytes()).context(ParseSnafu { name: &name })?;
+            let dependencies = index
+                .values()
+                .flat_map(|entry| &entry.deps)
+                .filter(|dep| dep.registry.is_none())
+                .filter(|dep| !matches!(dep.kind, index_entry::DependencyKind::Dev))
+                .filter_map(|dep| {
+                    let name = dep.package.as_deref().unwrap_or(&dep.name);
+                    name.parse::<CrateName>().ok()
+                });
+
+            for dependency in dependencies {
+                if seen.insert(dependency.as_str().to_ascii_lowercase()) {
+                    queue.push_back(dependency);
+                }
+            }
+        }
+
+        let path = format!("{}/{name}", name.prefix_directories().join("/"));
+        bundle.files.push(BundledIndexFile {
+            path: path.to_ascii_lowercase(),
+            content,
+        });
+    }
+
+    Ok(Json(bundle).into_response())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum IndexBundleError {
+    #[snafu(display("`crates` must list at least one crate name"))]
+    Crates,
+
+    #[snafu(display("At most {INDEX_BUNDLE_LIMIT} crates may be asked for at once"))]
+    TooMany,
+
+    #[snafu(display("`{name}` is not a valid crate name"))]
+    Name {
+        source: CrateNameError,
+        name: String,
+    },
+
+    #[snafu(display("Could not read the index file of `{name}`"))]
+    Read { source: io::Error, name: CrateName },
+
+    #[snafu(display("Could not parse the index file of `{name}`"))]
+    Parse {
+        source: ParseIndexError,
+        name: CrateName,
+    },
+}
+
+impl From<IndexBundleError> for ApiError {
+    fn from(e: IndexBundleError) -> Self {
+        let (status, code) = match &e {
+            IndexBundleError::Crates | IndexBundleError::TooMany => {
+                (StatusCode::BAD_REQUEST, "E_BAD_REQUEST")
+            }
+            IndexBundleError::Name { .. } => (StatusCode::BAD_REQUEST, "E_BAD_CRATE_NAME"),
+            IndexBundleError::Read { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "E_STORAGE_READ"),
+            IndexBundleError::Parse { .. } => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "E_INDEX_CORRUPT")
+            }
+        };
+
+        Self::new(status, code, &e)
+    }
+}
+
+#[derive(Serialize)]
+struct AttestationDetail {
+    #[serde(flatten)]
+    attestation: attestation::Attestation,
+    url: Option<Url>,
+}
+
+impl AttestationDetail {
+    fn new(
+        registry: &Registry,
+        name: &CrateName,
+        version: &Version,
+        attestation: attestation::Attestation,
+    ) -> Self {
+        let url = registry.attestation_url_for(name, version, &attestation.digest);
+        Self { attestation, url }
+    }
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/crates/{name}/{version}/attestations",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("version" = String, Path),
+        ),
+        responses(
+            (status = 200, description = "The version's attestations and their URLs", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_attestations(
+    State(state): State<Tenant>,
+    Path((name, version)): Path<(String, Version)>,
+) -> Result<Response, ApiError> {
+    let (name, _) = lookup(&state.registry(), &name)?;
+
+    let details = tokio::task::spawn_blocking(move || {
+        let registry = &state.registry();
+        attestation::list(registry, &name, &version).map(|attestations| {
+            attestations
+                .into_iter()
+                .map(|a| AttestationDetail::new(registry, &name, &version, a))
+                .collect::<Vec<_>>()
+        })
+    })
+    .await
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e))?
+    .map_err(|e| ApiError::new(attestation_status(&e), e.code(), &e))?;
+
+    Ok(Json(details).into_response())
+}
+
+fn publish_queue_status(e: &publish_queue::Error) -> StatusCode {
+    match e.code() {
+        "E_NOT_QUEUED" => StatusCode::NOT_FOUND,
+        "E_NOT_PENDING" => StatusCode::CONFLICT,
+        "E_SELF_APPROVAL" => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn upload_status(e: &upload::Error) -> StatusCode {
+    match e.code() {
+        "E_BAD_REQUEST" => StatusCode::BAD_REQUEST,
+        "E_METADATA_TOO_LARGE" | "E_CRATE_TOO_LARGE" | "E_UPLOAD_TOO_LARGE" => {
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn attestation_status(e: &attestation::Error) -> StatusCode {
+    match e.code() {
+        "E_VERSION_NOT_FOUND" => StatusCode::NOT_FOUND,
+        "E_BAD_ATTESTATION" | "E_ATTESTATION_MISMATCH" => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Serialize)]
+struct ArtifactDetail {
+    #[serde(flatten)]
+    artifact: artifact::Artifact,
+    url: Option<Url>,
+}
+
+impl ArtifactDetail {
+    fn new(
+        registry: &Registry,
+        name: &CrateName,
+        version: &Version,
+        artifact: artifact::Artifact,
+    ) -> Self {
+        let url = registry.artifact_url_for(name, version, &artifact);
+        Self { artifact, url }
+    }
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/crates/{name}/{version}/artifacts",
+        params(
+            ("name" = String, Path, description = "The crate, in any spelling Cargo accepts"),
+            ("version" = String, Path),
+        ),
+        responses(
+            (status = 200, description = "The version's prebuilt binaries and their URLs", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_artifacts(
+    State(state): State<Tenant>,
+    Path((name, version)): Path<(String, Version)>,
+) -> Result<Response, ApiError> {
+    let (name, _) = lookup(&state.registry(), &name)?;
+
+    let details = tokio::task::spawn_blocking(move || {
+        let registry = &state.registry();
+        artifact::list(registry, &name, &version).map(|artifacts| {
+            artifacts
+                .into_iter()
+                .map(|a| ArtifactDetail::new(registry, &name, &version, a))
+                .collect::<Vec<_>>()
+        })
+    })
+    .await
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e))?
+    .map_err(|e| ApiError::new(artifact_status(&e), e.code(), &e))?;
+
+    Ok(Json(details).into_response())
+}
+
+fn artifact_status(e: &artifact::Error) -> StatusCode {
+    match e.code() {
+        "E_VERSION_NOT_FOUND" | "E_ARTIFACT_NOT_FOUND" => StatusCode::NOT_FOUND,
+        "E_BAD_ARTIFACT" => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Checks the blob against its digest before answering, unlike the
+/// same file under `/blobs/`. Blobs can be toolchains hundreds of
+/// megabytes long, so both the check and the answer go a chunk at a
+/// time.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/blobs/{digest}",
+        params(
+            ("digest" = String, Path, description = "The SHA-256 of the blob, hex encoded"),
+        ),
+        responses(
+            (status = 200, description = "The blob", content_type = "application/octet-stream"),
+            (status = 404, description = "No such blob", body = ErrorBody),
+        ),
+    )
+)]
+async fn api_blob(
+    State(state): State<Tenant>,
+    Path(digest): Path<String>,
+) -> Result<Response, ApiError> {
+    let blobs_dir = state.registry().blobs_dir();
+    let (file, len) = tokio::task::spawn_blocking(move || blob::open(&blobs_dir, &digest))
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e))?
+        .map_err(|e| {
+            let status = match e.code() {
+                "E_BAD_DIGEST" => StatusCode::BAD_REQUEST,
+                "E_BLOB_NOT_FOUND" => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            ApiError::new(status, e.code(), &e)
+        })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_owned()),
+            (header::CONTENT_LENGTH, len.to_string()),
+        ],
+        file_body(tokio::fs::File::from_std(file)),
+    )
+        .into_response())
+}
+
+/// Streams the file from where it is, [`FILE_CHUNK_LEN`] bytes at a
+/// time, and only as fast as the client takes it.
+fn file_body(file: tokio::fs::File) -> Body {
+    use tokio::io::AsyncReadExt;
+
+    let chunks = futures_util::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut chunk = vec![0; FILE_CHUNK_LEN];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(Bytes::from(chunk)), Some(file)))
+            }
+            // Ends the body after the error
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Body::from_stream(chunks)
+}
+
+/// The audit log entries after `?after=SEQ`, which standbys replay to
+/// stay in step with this registry.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/audit",
+        params(
+            ("after" = Option<u64>, Query, description = "Only entries after this sequence number"),
+        ),
+        responses(
+            (status = 200, description = "Audit log entries, for standbys to replay; needs an admin token", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_audit(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, ApiError> {
+    require_everything(&Viewer::new(&state, &headers)?)?;
+
+    let after = uri
+        .query()
+        .and_then(|q| q.split('&').find_map(|pair| pair.strip_prefix("after=")))
+        .map_or(Ok(0), str::parse::<u64>)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "E_BAD_REQUEST", &e))?;
+
+    let path = state.registry().audit_log_path();
+    let page = tokio::task::spawn_blocking(move || audit::page(&path, after, AUDIT_PAGE_SIZE))
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e))?
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e))?;
+
+    Ok(Json(page).into_response())
+}
+
+/// `?since={seq}` lists the audit log entries after `seq` that changed
+/// the index, for mirrors to fetch the index files they name again.
+/// A page with fewer than the most entries is the last; polling again
+/// with `since` set to its `head` picks up where it left off.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/changes",
+        params(
+            ("since" = Option<u64>, Query, description = "Only entries after this sequence number"),
+        ),
+        responses(
+            (status = 200, description = "Audit log entries that changed the index", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_changes(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, ApiError> {
+    let since = uri
+        .query()
+        .and_then(|q| q.split('&').find_map(|pair| pair.strip_prefix("since=")))
+        .map_or(Ok(0), str::parse::<u64>)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "E_BAD_REQUEST", &e))?;
+
+    let viewer = Viewer::new(&state, &headers)?;
+    let path = state.registry().audit_log_path();
+    let page = tokio::task::spawn_blocking(move || {
+        let visible = |name: &CrateName| viewer.may_see(&state, name.as_str());
+        audit::changes(&path, since, AUDIT_PAGE_SIZE, visible)
+    })
+    .await
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e))?
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e))?;
+
+    Ok(Json(page).into_response())
+}
+
+/// `?month=2026-10` reports the downloads each team made in the month,
+/// the current one by default. Only admins may read it.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/usage",
+        params(
+            ("month" = Option<String>, Query, description = "As `2024-06`; the current month when missing"),
+        ),
+        responses(
+            (status = 200, description = "Downloads per team in the month; needs an admin token", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_usage(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, ApiError> {
+    let grant = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|t| state.grant_for_token(t));
+    let Some(grant) = grant else {
+        return Ok(unauthorized(&state));
+    };
+    if !grant.is_admin() {
+        let body = ErrorBody {
+            code: "E_FORBIDDEN",
+            message: "Only admins may read the download usage".to_owned(),
+            causes: vec![],
+        };
+        return Err(ApiError(StatusCode::FORBIDDEN, body));
+    }
+
+    let Some(accountant) = &state.usage else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let month = uri
+        .query()
+        .and_then(|q| q.split('&').find_map(|pair| pair.strip_prefix("month=")))
+        .map_or_else(|| usage::month_of(Timestamp::now()), str::to_owned);
+
+    Ok(Json(accountant.report(&month)).into_response())
+}
+
+/// Streams server-sent events: an `audit` event for each new audit log
+/// entry the request may see, with its sequence number as the event
+/// ID, and `peer-connected` and `peer-disconnected` as P2P peers come
+/// and go. A client reconnecting with `Last-Event-ID` first gets the
+/// entries it missed.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/events",
+        responses(
+            (status = 200, description = "Audit log entries and peer changes as they happen", content_type = "text/event-stream"),
+        ),
+    )
+)]
+async fn api_events(State(state): State<Tenant>, headers: HeaderMap) -> Result<Response, ApiError> {
+    let last_seen = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::parse::<u64>)
+        .transpose()
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "E_BAD_REQUEST", &e))?;
+
+    let viewer = Viewer::new(&state, &headers)?;
+    let path = state.registry().audit_log_path();
+    let log = path.clone();
+    let (entries, offset) = tokio::task::spawn_blocking(move || audit::tail(&log, 0))
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e))?
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e))?;
+
+    // Without an ID to resume from, only what happens next is sent
+    let seq = last_seen.unwrap_or_else(|| entries.last().map_or(0, |e| e.seq));
+    let peers = state.status.update(|s| s.peers.keys().cloned().collect());
+
+    let mut feed = EventFeed {
+        state,
+        viewer,
+        path,
+        offset,
+        seq,
+        peers,
+        pending: VecDeque::new(),
+    };
+    feed.push_entries(entries);
+
+    let events = futures_util::stream::unfold(feed, |mut feed| async move {
+        loop {
+            if let Some(event) = feed.pending.pop_front() {
+                return Some((Ok::<_, Infallible>(event), feed));
+            }
+            tokio::time::sleep(EVENTS_INTERVAL).await;
+            feed.poll().await;
+        }
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// What one `/api/v1/events` client has been sent.
+struct EventFeed {
+    state: Tenant,
+    viewer: Viewer,
+    path: PathBuf,
+
+    /// How much of the audit log has been read.
+    offset: u64,
+
+    /// The sequence number of the newest entry sent.
+    seq: u64,
+
+    peers: BTreeSet<String>,
+    pending: VecDeque<sse::Event>,
+}
+
+impl EventFeed {
+    async fn poll(&mut self) {
+        let path = self.path.clone();
+        let offset = self.offset;
+        match tokio::task::spawn_blocking(move || audit::tail(&path, offset)).await {
+            Ok(Ok((entries, offset))) => {
+                self.offset = offset;
+                self.push_entries(entries);
+            }
+            Ok(Err(e)) => eprintln!("Warning: {e}"),
+            Err(e) => eprintln!("Warning: {e}"),
+        }
+
+        let peers = self
+            .state
+            .status
+            .update(|s| s.peers.keys().cloned().collect::<BTreeSet<_>>());
+        for (kind, changed) in [
+            ("peer-connected", peers.difference(&self.peers)),
+            ("peer-disconnected", self.peers.difference(&peers)),
+        ] {
+            for peer in changed {
+                let data = serde_json::json!({ "peer": peer });
+                self.pending
+                    .push_back(sse::Event::default().event(kind).data(data.to_string()));
+            }
+        }
+        self.peers = peers;
+    }
+
+    fn push_entries(&mut self, entries: Vec<audit::Entry>) {
+        for entry in entries {
+            // Also skips entries sent before the log was replaced
+            if entry.seq <= self.seq {
+                continue;
+            }
+            self.seq = entry.seq;
+
+            let visible = self.viewer.sees_everything()
+                || entry
+                    .event
+                    .crate_name()
+                    .is_some_and(|name| self.viewer.may_see(&self.state, name.as_str()));
+            if !visible {
+                continue;
+            }
+
+            let event = sse::Event::default()
+                .event("audit")
+                .id(entry.seq.to_string())
+                .json_data(&entry);
+            match event {
+                Ok(event) => self.pending.push_back(event),
+                Err(e) => eprintln!("Warning: Could not send audit log entry {}: {e}", entry.seq),
+            }
+        }
+    }
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/index-snapshot",
+        responses(
+            (status = 200, description = "The whole index as a gzipped tarball; needs an admin token", content_type = "application/gzip"),
+        ),
+    )
+)]
+async fn api_index_snapshot(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    require_everything(&Viewer::new(&state, &headers)?)?;
+
+    let built = tokio::task::spawn_blocking(move || state.regenerator.snapshot(&state.registry()))
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e))?
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e))?;
+
+    Ok(([(header::CONTENT_TYPE, "application/gzip")], built).into_response())
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/search",
+        params(
+            ("q" = String, Query),
+            ("federated" = Option<bool>, Query, description = "Also ask peer registries"),
+        ),
+        responses(
+            (status = 200, description = "Crates whose names contain the query", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_search(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, ApiError> {
+    let params = search::Params::from_query(uri.query());
+    let label = state.name.as_deref().unwrap_or(discovery::ROOT_NAME);
+    let viewer = Viewer::new(&state, &headers)?;
+    let visible = |name: &CrateName| viewer.may_see(&state, name.as_str());
+
+    let crates = summaries(&state)?;
+    let response = search::Response {
+        hits: search::local(&state.registry(), &crates, label, &params.q, visible),
+        unreachable: vec![],
+    };
+
+    #[cfg(feature = "federation")]
+    let response = federate(&state, params, response).await?;
+
+    Ok(Json(response).into_response())
+}
+
+#[cfg(feature = "federation")]
+async fn federate(
+    state: &Tenant,
+    params: search::Params,
+    mut response: search::Response,
+) -> Result<search::Response, ApiError> {
+    let federation = state.federation.clone().filter(|_| params.federated);
+    let Some(federation) = federation else {
+        return Ok(response);
+    };
+
+    let (hits, unreachable) = tokio::task::spawn_blocking(move || federation.search(&params.q))
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "E_INTERNAL", &e))?;
+
+    response.hits.extend(hits);
+    response.unreachable = unreachable;
+
+    Ok(response)
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+struct Readiness {
+    ready: bool,
+    crates: Option<usize>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<u64>))]
+    loaded_in_ms: Option<u128>,
+}
+
+/// `200` once the catalog is loaded and `503` until then, for load
+/// balancers and deployments to wait on. Needs no token.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/ready",
+        responses(
+            (status = 200, description = "The crate summaries are loaded", body = Readiness),
+            (status = 503, description = "The crate summaries are still loading", body = Readiness),
+        ),
+    )
+)]
+async fn api_ready(State(state): State<Tenant>) -> Response {
+    let loaded = state.catalog.loaded();
+    let readiness = Readiness {
+        ready: loaded.is_some(),
+        crates: loaded.map(|(crates, _)| crates),
+        loaded_in_ms: loaded.map(|(_, loaded_in)| loaded_in.as_millis()),
+    };
+
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness)).into_response()
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/status",
+        responses(
+            (status = 200, description = "Connected peers, announcements and downloads", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_status(State(state): State<Tenant>, headers: HeaderMap) -> Result<Response, ApiError> {
+    let viewer = Viewer::new(&state, &headers)?;
+
+    let status = if viewer.sees_everything() {
+        state.status.to_json()
+    } else {
+        let visible = state
+            .status
+            .update(|s| s.visible(|name| viewer.may_see(&state, name)));
+        serde_json::to_vec(&visible).expect("The status is always serializable")
+    };
+
+    Ok(([(header::CONTENT_TYPE, "application/json")], status).into_response())
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/registry",
+        responses(
+            (status = 200, description = "What the daemon offers", body = registry_info::Info),
+        ),
+    )
+)]
+async fn api_registry(State(state): State<Tenant>) -> Response {
+    use registry_info::Feature;
+
+    let registry = state.registry();
+    let peer_id = state.status.update(|s| s.local_peer_id.clone());
+
+    let mut features = BTreeSet::from([
+        Feature::Sparse,
+        Feature::Bundles,
+        Feature::IndexSnapshot,
+        Feature::Changes,
+        Feature::Attestations,
+    ]);
+
+    #[cfg(feature = "nostr")]
+    let pubkey = state.nostr_pubkey();
+    #[cfg(not(feature = "nostr"))]
+    let pubkey = None;
+
+    let optional = [
+        (Feature::Publish, state.publish.is_some()),
+        (Feature::Signatures, pubkey.is_some()),
+        #[cfg(feature = "proxy")]
+        (Feature::Proxy, state.proxy.is_some()),
+        (Feature::Encryption, registry.encrypts()),
+        (Feature::P2p, peer_id.is_some()),
+    ];
+    features.extend(optional.into_iter().filter(|(_, on)| *on).map(|(f, _)| f));
+
+    let info = registry_info::Info {
+        margo_version: env!("CARGO_PKG_VERSION").to_owned(),
+        api: registry_info::API_VERSION,
+        format: registry.config.format,
+        features,
+        pubkey,
+        peer_id,
+    };
+
+    Json(info).into_response()
+}
+
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/api/v1/transfers",
+        responses(
+            (status = 200, description = "P2P transfers in progress", content_type = "application/json"),
+        ),
+    )
+)]
+async fn api_transfers(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let viewer = Viewer::new(&state, &headers)?;
+
+    let transfers = state.status.update(|s| {
+        s.transfers
+            .values()
+            .filter(|t| {
+                t.crate_name()
+                    .map_or(true, |name| viewer.may_see(&state, name))
+            })
+            .cloned()
+            .collect::<Vec<_>>()
+    });
+
+    Ok(Json(transfers).into_response())
+}
+
+async fn ui_index(
+    State(state): State<Tenant>,
+    headers: HeaderMap,
+) -> Result<Html<String>, ApiError> {
+    let viewer = Viewer::new(&state, &headers)?;
+    let mut crates = state.registry().list_all()?;
+    crates.retain(|name, _| viewer.may_see(&state, name.as_str()));
+    let base_path = state.base_path();
+    let page = state
+        .status
+        .update(|status| html::dashboard(&base_path, &crates, status));
+
+    Ok(Html(page.into_string()))
+}
+
+async fn ui_crate(
+    State(state): State<Tenant>,
+    Path(name): Path<String>,
+) -> Result<Html<String>, ApiError> {
+    let registry = state.registry();
+    let (name, index) = lookup(&registry, &name)?;
+    let base_path = state.base_path();
+    let page = state
+        .status
+        .update(|status| html::dashboard_crate(&base_path, &registry, &name, &index, status));
+
+    Ok(Html(page.into_string()))
+}
+
+fn lookup(registry: &Registry, name: &str) -> Result<(CrateName, Index), LookupError> {
+    use lookup_error::*;
+
+    let name = name.parse::<CrateName>().context(NameSnafu)?;
+    let found = registry.find_crate(&name).context(IndexSnafu)?;
+
+    found.context(NotFoundSnafu { name })
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum LookupError {
+    #[snafu(display("The crate name is not valid"))]
+    Name { source: CrateNameError },
+
+    #[snafu(display("Could not read the crate's index file"))]
+    Index { source: FindCrateError },
+
+    #[snafu(display("The crate `{name}` does not exist"))]
+    NotFound { name: CrateName },
+}
+
+impl From<LookupError> for ApiError {
+    fn from(e: LookupError) -> Self {
+        let (status, code) = match &e {
+            LookupError::Name { .. } => (StatusCode::BAD_REQUEST, "E_BAD_CRATE_NAME"),
+            LookupError::Index { source } => (StatusCode::INTERNAL_SERVER_ERROR, source.code()),
+            LookupError::NotFound { .. } => (StatusCode::NOT_FOUND, "E_CRATE_NOT_FOUND"),
+        };
+
+        Self::new(status, code, &e)
+    }
+}
+
+impl From<catalog::Error> for ApiError {
+    fn from(e: catalog::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e)
+    }
+}
+
+impl From<ListAllError> for ApiError {
+    fn from(e: ListAllError) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e)
+    }
+}
+
+/// An [`ErrorBody`] sent as the JSON body of an HTTP error response.
+struct ApiError(StatusCode, ErrorBody);
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, error: &dyn std::error::Error) -> Self {
+        Self(status, ErrorBody::new(code, error))
+    }
+}
+
+/// Cargo shows the `detail` of each entry in `errors` to the user.
+#[derive(Serialize)]
+struct ApiErrorJson<'a> {
+    #[serde(flatten)]
+    body: &'a ErrorBody,
+    errors: [CargoErrorDetail<'a>; 1],
+}
+
+#[derive(Serialize)]
+struct CargoErrorDetail<'a> {
+    detail: &'a str,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let Self(status, body) = self;
+        let json = ApiErrorJson {
+            body: &body,
+            errors: [CargoErrorDetail {
+                detail: &body.message,
+            }],
+        };
+        (status, Json(json)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crate_downloads_are_recognized() {
+        assert_eq!(
+            Some(("serde", "1.0.0")),
+            crate_download("/crates/se/rd/serde/1.0.0.crate"),
+        );
+        assert_eq!(Some(("a", "0.1.0")), crate_download("/crates/1/a/0.1.0.crate"));
+        assert_eq!(None, crate_download("/se/rd/serde"));
+        assert_eq!(None, crate_download("/crates/se/rd/serde/1.0.0.tar"));
+    }
+
+    #[tokio::test]
+    async fn files_are_served_a_chunk_at_a_time() {
+        use futures_util::StreamExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let data = (0..FILE_CHUNK_LEN * 2 + 1)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        fs::write(&path, &data).unwrap();
+
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let mut chunks = file_body(file).into_data_stream();
+        let mut served = vec![];
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= FILE_CHUNK_LEN);
+            served.extend_from_slice(&chunk);
+        }
+        assert_eq!(data, served);
+    }
+}
@@ -0,0 +1,255 @@
+//! The Cargo configuration for using a registry.
+//!
+//! `margo setup-cargo --name NAME` adds `[registries.NAME]` with the
+//! registry's sparse index and `cargo:token` as its credential provider,
+//! so that `cargo login --registry NAME` stores the token Cargo sends.
+//! With `--replace-crates-io`, it also points `[source.crates-io]` at the
+//! registry, for a registry that mirrors crates.io. Whatever is already
+//! in the file is left untouched, and running it again adds nothing.
+
+use serde::Serialize;
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::cargo_context;
+
+/// The provider that keeps the token in Cargo's `credentials.toml`.
+const CREDENTIAL_PROVIDER: &str = "cargo:token";
+
+const CRATES_IO: &str = "crates-io";
+
+#[derive(Debug)]
+pub struct Setup<'a> {
+    /// What Cargo should call the registry.
+    pub name: &'a str,
+
+    /// The index URL as Cargo expects it, including `sparse+`.
+    pub index: String,
+
+    pub replace_crates_io: bool,
+}
+
+#[derive(Default, Serialize)]
+struct CargoConfig<'a> {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    registries: BTreeMap<&'a str, CargoRegistry<'a>>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    source: BTreeMap<&'a str, CargoSource<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CargoRegistry<'a> {
+    index: &'a str,
+    credential_provider: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CargoSource<'a> {
+    replace_with: &'a str,
+}
+
+impl Setup<'_> {
+    /// The configuration to add to a new file.
+    pub fn config(&self) -> Result<String, Error> {
+        self.missing(&toml::Table::new(), Path::new(""))
+    }
+
+    /// The parts of the configuration that `existing`, read from
+    /// `path`, does not have yet.
+    fn missing(&self, existing: &toml::Table, path: &Path) -> Result<String, Error> {
+        use error::*;
+
+        ensure!(is_valid_name(self.name), NameSnafu { name: self.name });
+
+        let mut config = CargoConfig::default();
+
+        let registry = existing.get("registries").and_then(|r| r.get(self.name));
+        match registry {
+            None => {
+                let registry = CargoRegistry {
+                    index: &self.index,
+                    credential_provider: CREDENTIAL_PROVIDER,
+                };
+                config.registries.insert(self.name, registry);
+            }
+            Some(registry) => {
+                let index = registry.get("index").and_then(toml::Value::as_str);
+                ensure!(
+                    index == Some(self.index.as_str()),
+                    ConflictSnafu {
+                        key: format!("registries.{}.index", self.name),
+                        path,
+                        existing: index.unwrap_or_default(),
+                    }
+                );
+            }
+        }
+
+        if self.replace_crates_io {
+            let source = existing.get("source").and_then(|s| s.get(CRATES_IO));
+            match source {
+                None => {
+                    let source = CargoSource {
+                        replace_with: self.name,
+                    };
+                    config.source.insert(CRATES_IO, source);
+                }
+                Some(source) => {
+                    let replace_with = source.get("replace-with").and_then(toml::Value::as_str);
+                    ensure!(
+                        replace_with == Some(self.name),
+                        ConflictSnafu {
+                            key: "source.crates-io.replace-with",
+                            path,
+                            existing: replace_with.unwrap_or_default(),
+                        }
+                    );
+                }
+            }
+        }
+
+        toml::to_string(&config).context(SerializeSnafu)
+    }
+
+    /// Appends what the Cargo configuration at `path` is missing.
+    /// Returns `false` if it already had all of it.
+    pub fn write(&self, path: &Path) -> Result<bool, Error> {
+        use error::*;
+
+        let mut config = match fs::read_to_string(path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context(ReadSnafu { path }),
+        };
+        let existing: toml::Table = toml::from_str(&config).context(ParseSnafu { path })?;
+
+        let missing = self.missing(&existing, path)?;
+        if missing.is_empty() {
+            return Ok(false);
+        }
+
+        if !config.is_empty() && !config.ends_with('\n') {
+            config.push('\n');
+        }
+        if !config.is_empty() {
+            config.push('\n');
+        }
+        config.push_str(&missing);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context(WriteSnafu { path })?;
+        }
+        fs::write(path, config).context(WriteSnafu { path })?;
+
+        Ok(true)
+    }
+}
+
+/// The user's Cargo configuration file.
+pub fn default_path() -> Result<PathBuf, Error> {
+    let cargo_home = cargo_context::cargo_home().context(error::CargoHomeSnafu)?;
+    Ok(cargo_home.join("config.toml"))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("`{name}` is not a valid Cargo registry name"))]
+    Name { name: String },
+
+    #[snafu(display("Could not find Cargo's home directory; pass --cargo-config"))]
+    CargoHome,
+
+    #[snafu(display("Could not read the Cargo configuration at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the Cargo configuration at {}", path.display()))]
+    Parse {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display(
+        "`{key}` is already set in {} to `{existing}`",
+        path.display()
+    ))]
+    Conflict {
+        key: String,
+        path: PathBuf,
+        existing: String,
+    },
+
+    #[snafu(display("Could not serialize the Cargo configuration"))]
+    Serialize { source: toml::ser::Error },
+
+    #[snafu(display("Could not write the Cargo configuration at {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Name { .. } => "E_BAD_ADDRESS",
+            Self::CargoHome | Self::Read { .. } => "E_CONFIG_READ",
+            Self::Parse { .. } | Self::Conflict { .. } => "E_CONFIG_INVALID",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_what_is_missing_is_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            "# keep me\n[registries.internal]\nindex = \"sparse+https://example.com/\"",
+        )
+        .unwrap();
+
+        let setup = Setup {
+            name: "internal",
+            index: "sparse+https://example.com/".into(),
+            replace_crates_io: true,
+        };
+        assert!(setup.write(&path).unwrap());
+        assert!(!setup.write(&path).unwrap());
+
+        let config = fs::read_to_string(&path).unwrap();
+        assert!(config.starts_with("# keep me\n"), "{config}");
+        assert!(config.contains(r#"replace-with = "internal""#), "{config}");
+
+        let other = Setup {
+            name: "internal",
+            index: "sparse+https://other.example/".into(),
+            replace_crates_io: false,
+        };
+        assert!(matches!(other.write(&path), Err(Error::Conflict { .. })));
+
+        let fresh = other.config().unwrap();
+        assert!(
+            fresh.contains(r#"credential-provider = "cargo:token""#),
+            "{fresh}"
+        );
+        assert!(!fresh.contains("source"), "{fresh}");
+    }
+}
@@ -0,0 +1,478 @@
+//! Signing nostr events with the operator's key, wherever it is kept.
+//!
+//! A tenant signs with one of:
+//!
+//! - `nostr-key`, a secret key file that the daemon reads into memory;
+//! - `nostr-bunker`, a NIP-46 remote signer that holds the key
+//!   elsewhere and is asked over a nostr relay to sign each event.
+//!
+//! ```toml
+//! [[tenant]]
+//! name = "acme"
+//! registry = "/srv/registries/acme"
+//! nostr-bunker = "bunker://<signer pubkey>?relay=wss://relay.example.com&secret=..."
+//! ```
+//!
+//! The daemon talks to the bunker with a key of its own, generated
+//! when it starts, which the bunker approves on `connect`. A `secret`
+//! in the URI lets the bunker approve it without asking. A bunker can
+//! sign events and encrypt NIP-04 messages, but not gift-wrap NIP-17
+//! ones, which need the secret key itself.
+//!
+//! PKCS#11 tokens are not supported: nostr signatures are BIP-340
+//! Schnorr signatures over secp256k1, which PKCS#11 does not define. A
+//! key on a hardware token is used through a bunker that can sign
+//! with it.
+
+use nostr::{
+    nips::nip04, ClientMessage, Event, EventBuilder, Filter, JsonUtil, Keys, Kind, PublicKey,
+    RelayMessage, SubscriptionId, Tag, UnsignedEvent,
+};
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tungstenite::Message;
+use url::Url;
+
+use crate::notify;
+
+/// How long a remote signer has to answer, which includes the
+/// operator approving the request.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub trait Signer: fmt::Debug + Send + Sync {
+    /// The public key events are signed with.
+    fn public_key(&self) -> Result<PublicKey, Error>;
+
+    fn sign(&self, event: UnsignedEvent) -> Result<Event, Error>;
+
+    /// Encrypts `content` for `receiver`, as for a NIP-04 direct
+    /// message.
+    fn nip04_encrypt(&self, receiver: &PublicKey, content: &str) -> Result<String, Error>;
+
+    /// The secret key itself, when the signer holds it.
+    fn keys(&self) -> Option<Keys> {
+        None
+    }
+
+    /// Finds out the key again after it was
+    /// [rotated](crate::key_rotation), and returns its public half.
+    fn reload(&self) -> Result<PublicKey, Error>;
+}
+
+/// The operator's signer, shared by everything that signs for a tenant
+/// so that a rotated key takes effect everywhere at once.
+pub type SharedSigner = Arc<dyn Signer>;
+
+/// Builds the event and has `signer` sign it.
+pub fn sign(signer: &dyn Signer, builder: EventBuilder) -> Result<Event, Error> {
+    let unsigned = builder.to_unsigned_event(signer.public_key()?);
+    signer.sign(unsigned)
+}
+
+/// Reads a secret key file, hex or `nsec`.
+pub fn read_keys(path: &Path) -> Result<Keys, Error> {
+    use error::*;
+
+    let key = fs::read_to_string(path).context(ReadKeySnafu { path })?;
+    Keys::parse(key.trim()).context(ParseKeySnafu { path })
+}
+
+/// Signs with a secret key held in memory.
+#[derive(Debug)]
+pub struct LocalSigner {
+    /// Where the key is read again from on reload.
+    path: Option<PathBuf>,
+    keys: RwLock<Keys>,
+}
+
+impl LocalSigner {
+    pub fn new(keys: Keys) -> Self {
+        Self {
+            path: None,
+            keys: RwLock::new(keys),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            path: Some(path.to_owned()),
+            keys: RwLock::new(read_keys(path)?),
+        })
+    }
+
+    fn get(&self) -> Keys {
+        self.keys
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Signer for LocalSigner {
+    fn public_key(&self) -> Result<PublicKey, Error> {
+        Ok(self.get().public_key())
+    }
+
+    fn sign(&self, event: UnsignedEvent) -> Result<Event, Error> {
+        event.sign(&self.get()).context(error::SignSnafu)
+    }
+
+    fn nip04_encrypt(&self, receiver: &PublicKey, content: &str) -> Result<String, Error> {
+        nip04::encrypt(self.get().secret_key(), receiver, content).context(error::EncryptSnafu)
+    }
+
+    fn keys(&self) -> Option<Keys> {
+        Some(self.get())
+    }
+
+    fn reload(&self) -> Result<PublicKey, Error> {
+        let Some(path) = &self.path else {
+            return self.public_key();
+        };
+
+        let keys = read_keys(path)?;
+        let pubkey = keys.public_key();
+        *self.keys.write().unwrap_or_else(PoisonError::into_inner) = keys;
+
+        Ok(pubkey)
+    }
+}
+
+/// Asks a NIP-46 remote signer to sign.
+#[derive(Debug)]
+pub struct RemoteSigner {
+    /// The bunker's own key, which requests are addressed to. It may
+    /// differ from the key it signs with.
+    remote: PublicKey,
+    relays: Vec<Url>,
+    secret: Option<String>,
+
+    /// Identifies the daemon to the bunker.
+    client: Keys,
+
+    /// The key the bunker signs with, once it has said which.
+    user: Mutex<Option<PublicKey>>,
+
+    next_id: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+struct RemoteRequest<'a> {
+    id: String,
+    method: &'a str,
+    params: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoteResponse {
+    id: String,
+
+    #[serde(default)]
+    result: String,
+
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl RemoteSigner {
+    /// Parses a `bunker://` URI. Nothing is sent until the first
+    /// request.
+    pub fn new(uri: &str) -> Result<Self, Error> {
+        use error::*;
+
+        let url = Url::parse(uri).ok().filter(|u| u.scheme() == "bunker");
+        let url = url.context(UriSnafu)?;
+        let remote = PublicKey::parse(url.host_str().unwrap_or_default())
+            .ok()
+            .context(UriSnafu)?;
+
+        let mut relays = vec![];
+        let mut secret = None;
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "relay" => relays.push(Url::parse(&value).ok().context(UriSnafu)?),
+                "secret" => secret = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+        ensure!(!relays.is_empty(), UriSnafu);
+
+        Ok(Self {
+            remote,
+            relays,
+            secret,
+            client: Keys::generate(),
+            user: Mutex::new(None),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Connects once and learns the key the bunker signs with.
+    fn connect(&self) -> Result<PublicKey, Error> {
+        use error::*;
+
+        let mut user = self.user.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(user) = *user {
+            return Ok(user);
+        }
+
+        let params = [self.remote.to_hex()]
+            .into_iter()
+            .chain(self.secret.clone())
+            .collect();
+        self.call("connect", params)?;
+
+        let pubkey = self.call("get_public_key", vec![])?;
+        let pubkey = PublicKey::parse(&pubkey).ok().context(ResponseSnafu {
+            method: "get_public_key",
+        })?;
+        *user = Some(pubkey);
+
+        Ok(pubkey)
+    }
+
+    /// Tries each relay in turn.
+    fn call(&self, method: &str, params: Vec<String>) -> Result<String, Error> {
+        let mut last_error = None;
+        for relay in &self.relays {
+            match self.call_via(relay, method, params.clone()) {
+                Ok(result) => return Ok(result),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.expect("A bunker URI always names a relay"))
+    }
+
+    fn call_via(&self, relay: &Url, method: &str, params: Vec<String>) -> Result<String, Error> {
+        use error::*;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let request = RemoteRequest {
+            id: id.clone(),
+            method,
+            params,
+        };
+        let request = serde_json::to_string(&request).context(SerializeSnafu)?;
+        let content = nip04::encrypt(self.client.secret_key(), &self.remote, request)
+            .context(EncryptSnafu)?;
+        let event = EventBuilder::new(Kind::NostrConnect, content, [Tag::public_key(self.remote)])
+            .to_event(&self.client)
+            .context(BuildSnafu)?;
+
+        let mut socket = notify::connect(relay).context(RelaySnafu)?;
+
+        // Subscribed first so that the answer cannot be missed
+        let subscription = SubscriptionId::generate();
+        let filter = Filter::new()
+            .kind(Kind::NostrConnect)
+            .author(self.remote)
+            .pubkey(self.client.public_key())
+            .since(nostr::Timestamp::now());
+        let messages = [
+            ClientMessage::req(subscription.clone(), vec![filter]),
+            ClientMessage::event(event),
+        ];
+        for message in messages {
+            socket
+                .send(Message::Text(message.as_json()))
+                .context(QuerySnafu { relay })?;
+        }
+
+        let deadline = Instant::now() + REMOTE_TIMEOUT;
+        let response = loop {
+            ensure!(Instant::now() < deadline, TimeoutSnafu { method });
+
+            let reply = match socket.read() {
+                Ok(Message::Text(reply)) => reply,
+                Ok(_) => continue,
+                // Reads time out so that the deadline is checked
+                Err(tungstenite::Error::Io(e))
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e).context(QuerySnafu { relay }),
+            };
+
+            let Ok(RelayMessage::Event {
+                subscription_id,
+                event,
+            }) = RelayMessage::from_json(&reply)
+            else {
+                continue;
+            };
+            if subscription_id != subscription || event.verify().is_err() {
+                continue;
+            }
+
+            let Some(response) =
+                nip04::decrypt(self.client.secret_key(), &self.remote, &event.content)
+                    .ok()
+                    .and_then(|r| serde_json::from_str::<RemoteResponse>(&r).ok())
+                    .filter(|r| r.id == id)
+            else {
+                continue;
+            };
+
+            if response.result == "auth_url" {
+                let url = response.error.unwrap_or_default();
+                eprintln!("Warning: The nostr signer asks for approval at {url}");
+                continue;
+            }
+
+            break response;
+        };
+
+        _ = socket.send(Message::Text(ClientMessage::close(subscription).as_json()));
+        _ = socket.close(None);
+
+        match response.error {
+            Some(message) if !message.is_empty() => RefusedSnafu { method, message }.fail(),
+            _ => Ok(response.result),
+        }
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn public_key(&self) -> Result<PublicKey, Error> {
+        self.connect()
+    }
+
+    fn sign(&self, event: UnsignedEvent) -> Result<Event, Error> {
+        use error::*;
+
+        let user = self.connect()?;
+        let signed = self.call("sign_event", vec![event.as_json()])?;
+        let signed = Event::from_json(signed)
+            .ok()
+            .filter(|e| e.pubkey == user && e.verify().is_ok())
+            .context(ResponseSnafu {
+                method: "sign_event",
+            })?;
+
+        Ok(signed)
+    }
+
+    fn nip04_encrypt(&self, receiver: &PublicKey, content: &str) -> Result<String, Error> {
+        self.connect()?;
+        self.call("nip04_encrypt", vec![receiver.to_hex(), content.to_owned()])
+    }
+
+    fn reload(&self) -> Result<PublicKey, Error> {
+        *self.user.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        self.connect()
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the nostr key at {}", path.display()))]
+    ReadKey { source: io::Error, path: PathBuf },
+
+    #[snafu(display("The nostr key at {} is not a valid secret key", path.display()))]
+    ParseKey {
+        source: nostr::key::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not sign the nostr event"))]
+    Sign {
+        source: nostr::event::unsigned::Error,
+    },
+
+    #[snafu(display("Could not encrypt the nostr message"))]
+    Encrypt { source: nip04::Error },
+
+    #[snafu(display(
+        "The nostr bunker URI must be `bunker://<pubkey>?relay=<url>`, with at least one relay"
+    ))]
+    Uri,
+
+    #[snafu(display("Could not serialize the request to the nostr signer"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not build the request to the nostr signer"))]
+    Build {
+        source: nostr::event::builder::Error,
+    },
+
+    #[snafu(display("Could not reach the nostr signer"))]
+    Relay {
+        #[snafu(source(from(notify::Error, Box::new)))]
+        source: Box<notify::Error>,
+    },
+
+    #[snafu(display("Could not talk to the nostr signer over {relay}"))]
+    Query {
+        #[snafu(source(from(tungstenite::Error, Box::new)))]
+        source: Box<tungstenite::Error>,
+        relay: Url,
+    },
+
+    #[snafu(display("The nostr signer did not answer `{method}` in time"))]
+    Timeout { method: String },
+
+    #[snafu(display("The nostr signer refused `{method}`: {message}"))]
+    Refused { method: String, message: String },
+
+    #[snafu(display("The nostr signer's answer to `{method}` is not valid"))]
+    Response { method: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ReadKey { .. } => "E_KEY_READ",
+            Self::ParseKey { .. } | Self::Uri => "E_CONFIG_INVALID",
+            Self::Sign { .. }
+            | Self::Encrypt { .. }
+            | Self::Serialize { .. }
+            | Self::Build { .. } => "E_INTERNAL",
+            Self::Relay { .. } | Self::Query { .. } | Self::Timeout { .. } => {
+                "E_SIGNER_UNREACHABLE"
+            }
+            Self::Refused { .. } | Self::Response { .. } => "E_SIGNER_REFUSED",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bunker_uris_name_the_signer_and_its_relays() {
+        let remote = Keys::generate().public_key();
+        let uri = format!(
+            "bunker://{}?relay=wss://a.example.com&relay=wss://b.example.com&secret=s3cret",
+            remote.to_hex()
+        );
+        let signer = RemoteSigner::new(&uri).unwrap();
+        assert_eq!(remote, signer.remote);
+        assert_eq!(2, signer.relays.len());
+        assert_eq!(Some("s3cret"), signer.secret.as_deref());
+
+        let no_relay = format!("bunker://{}", remote.to_hex());
+        assert!(RemoteSigner::new(&no_relay).is_err());
+        assert!(RemoteSigner::new("nostrconnect://abc?relay=wss://a.example.com").is_err());
+
+        let local = LocalSigner::new(Keys::generate());
+        let event = sign(&local, EventBuilder::text_note("hello", [])).unwrap();
+        assert!(event.verify().is_ok());
+        assert_eq!(local.public_key().unwrap(), event.pubkey);
+    }
+}
@@ -0,0 +1,200 @@
+//! A whole index in one download, for bootstrapping a mirror.
+//!
+//! `GET /api/v1/index-snapshot` answers with a gzipped tarball. Its
+//! first entry, `margo-snapshot.json`, is a [`Manifest`] carrying the
+//! [Merkle root](crate::merkle) of the index; the rest are the index
+//! files under `index/`. A mirror unpacks it, recomputes the root, and
+//! only trusts the files if the roots agree. From then on it updates
+//! index files one at a time.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::io;
+
+use crate::{merkle, timestamp::Timestamp, Registry};
+
+#[cfg(feature = "proxy")]
+use crate::common::CrateName;
+
+#[cfg(feature = "proxy")]
+use std::io::Read;
+
+pub const MANIFEST_NAME: &str = "margo-snapshot.json";
+
+const INDEX_DIR: &str = "index/";
+
+/// Bounds how much a hostile snapshot can make us hold in memory.
+#[cfg(feature = "proxy")]
+const MAX_UNPACKED_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The Merkle root of the index files, hex encoded.
+    pub root: String,
+    pub files: usize,
+    pub created_at: Timestamp,
+}
+
+pub fn build(registry: &Registry) -> Result<Vec<u8>, Error> {
+    build_at(registry, Timestamp::generated())
+}
+
+/// Builds the snapshot as of `created_at`. Index files are packed in
+/// order with fixed metadata, so the same index and time always give
+/// the same bytes.
+pub fn build_at(registry: &Registry, created_at: Timestamp) -> Result<Vec<u8>, Error> {
+    use error::*;
+
+    let files = merkle::read_index_files(registry).context(ReadSnafu)?;
+
+    let manifest = Manifest {
+        root: hex::encode(merkle::files_root(&files)),
+        files: files.len(),
+        created_at,
+    };
+    let manifest = serde_json::to_vec(&manifest).context(ManifestSnafu)?;
+
+    let gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    let mut tar = tar::Builder::new(gz);
+
+    let entries = [(MANIFEST_NAME.to_owned(), &manifest)].into_iter().chain(
+        files
+            .iter()
+            .map(|(path, data)| (format!("{INDEX_DIR}{path}"), data)),
+    );
+    for (path, data) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, path, &data[..])
+            .context(PackSnafu)?;
+    }
+
+    tar.into_inner()
+        .and_then(|gz| gz.finish())
+        .context(PackSnafu)
+}
+
+/// Unpacks the snapshot and checks it against its manifest.
+#[cfg(feature = "proxy")]
+pub fn unpack(data: &[u8]) -> Result<(Manifest, merkle::IndexFiles), Error> {
+    use error::*;
+
+    let gz = flate2::read::GzDecoder::new(data).take(MAX_UNPACKED_BYTES);
+    let mut tar = tar::Archive::new(gz);
+
+    let mut manifest = None;
+    let mut files = merkle::IndexFiles::new();
+
+    for entry in tar.entries().context(UnpackSnafu)? {
+        let mut entry = entry.context(UnpackSnafu)?;
+        // As written, with `/` separators on every platform
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+
+        let mut data = vec![];
+        entry.read_to_end(&mut data).context(UnpackSnafu)?;
+
+        if path == MANIFEST_NAME {
+            manifest = Some(serde_json::from_slice::<Manifest>(&data).context(ManifestSnafu)?);
+            continue;
+        }
+
+        let relative = path
+            .strip_prefix(INDEX_DIR)
+            .filter(|p| is_index_path(p))
+            .context(PathSnafu { path: &path })?;
+        files.insert(relative.to_owned(), data);
+    }
+
+    let manifest = manifest.context(NoManifestSnafu)?;
+    let actual = hex::encode(merkle::files_root(&files));
+    ensure!(
+        actual.eq_ignore_ascii_case(&manifest.root) && files.len() == manifest.files,
+        RootSnafu {
+            expected: &manifest.root,
+            actual,
+        }
+    );
+
+    Ok((manifest, files))
+}
+
+/// Only the index file of a crate, under the prefix directories of its
+/// name, so that unpacking cannot write anywhere else. The snapshot
+/// comes from another registry, so names are held to the
+/// [strict rules](CrateName::from_untrusted).
+#[cfg(feature = "proxy")]
+fn is_index_path(path: &str) -> bool {
+    let Some((prefix, name)) = path.rsplit_once('/') else {
+        return false;
+    };
+    let Ok(name) = CrateName::from_untrusted(name) else {
+        return false;
+    };
+
+    prefix.eq_ignore_ascii_case(&name.prefix_directories().join("/"))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the index"))]
+    Read { source: merkle::Error },
+
+    #[snafu(display("Could not serialize or deserialize the snapshot manifest"))]
+    Manifest { source: serde_json::Error },
+
+    #[snafu(display("Could not pack the index snapshot"))]
+    Pack { source: io::Error },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("Could not unpack the index snapshot"))]
+    Unpack { source: io::Error },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("The index snapshot contains the unexpected file `{path}`"))]
+    Path { path: String },
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display("The index snapshot has no {MANIFEST_NAME}"))]
+    NoManifest,
+
+    #[cfg(feature = "proxy")]
+    #[snafu(display(
+        "The index snapshot's Merkle root is {actual}, but its manifest says {expected}"
+    ))]
+    Root { expected: String, actual: String },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Manifest { .. } | Self::Pack { .. } => "E_INTERNAL",
+            #[cfg(feature = "proxy")]
+            Self::Unpack { .. } | Self::Path { .. } | Self::NoManifest => "E_BAD_SNAPSHOT",
+            #[cfg(feature = "proxy")]
+            Self::Root { .. } => "E_BAD_CHECKSUM",
+        }
+    }
+}
+
+#[cfg(all(test, feature = "proxy"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn index_paths_stay_inside_the_index() {
+        assert!(is_index_path("se/rd/serde"));
+        assert!(is_index_path("3/s/syn"));
+        assert!(is_index_path("1/a"));
+
+        assert!(!is_index_path("serde"));
+        assert!(!is_index_path("se/rd/../serde"));
+        assert!(!is_index_path("/etc/passwd"));
+        assert!(!is_index_path("se/rd/se/rde"));
+        assert!(!is_index_path("se/rd/tokio"));
+        assert!(!is_index_path("3/c/con"));
+        assert!(!is_index_path("xn/--/xn--serde"));
+    }
+}
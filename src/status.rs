@@ -0,0 +1,523 @@
+//! Runtime state of a running `serve` daemon.
+//!
+//! The P2P node writes into this as peers come and go; the HTTP
+//! server reads from it to answer API and dashboard requests.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// How many announcements are kept for display.
+#[cfg(feature = "p2p")]
+const MAX_ANNOUNCEMENTS: usize = 50;
+
+/// How many publishes are kept for display.
+#[cfg(feature = "server")]
+const MAX_PUBLISHES: usize = 50;
+
+/// Failed authentications a source may make before it is locked out.
+#[cfg(feature = "server")]
+const FREE_AUTH_FAILURES: u32 = 5;
+
+/// The first lockout; each further failure doubles it.
+#[cfg(feature = "server")]
+const BASE_LOCKOUT_SECS: u64 = 30;
+
+#[cfg(feature = "server")]
+const MAX_LOCKOUT_SECS: u64 = 60 * 60;
+
+/// A source that stops failing is forgotten after this long.
+#[cfg(feature = "server")]
+const FORGET_AUTH_FAILURES_SECS: u64 = 60 * 60;
+
+/// Bounds the memory an attacker with many addresses can use.
+#[cfg(feature = "server")]
+const MAX_AUTH_FAILURE_SOURCES: usize = 10_000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Status {
+    /// Connected peers, keyed by peer ID.
+    pub peers: BTreeMap<String, Peer>,
+
+    /// The P2P node's peer ID, once it has started.
+    pub local_peer_id: Option<String>,
+
+    /// Addresses the P2P node is listening on, including its peer ID.
+    pub listen_addrs: Vec<String>,
+
+    /// Most recent announcement first.
+    pub announcements: VecDeque<Announcement>,
+
+    /// Announcements rejected since the daemon started, by the step of
+    /// validation that rejected them.
+    pub announcement_rejects: BTreeMap<String, u64>,
+
+    /// Downloads served since the daemon started, by crate name and
+    /// version.
+    pub downloads: BTreeMap<String, BTreeMap<String, u64>>,
+
+    /// Failed token authentications since the daemon started.
+    pub auth_failures_total: u64,
+
+    /// Recent failed authentications, by source IP address. Left out of
+    /// `/api/v1/status`, which anyone who may read can see; the control
+    /// socket adds them back.
+    #[serde(skip)]
+    pub auth_failures: BTreeMap<String, AuthFailures>,
+
+    /// P2P transfers in progress, by direction and what is being
+    /// transferred.
+    pub transfers: BTreeMap<String, Transfer>,
+
+    /// Most recent publish first.
+    pub publishes: VecDeque<Publish>,
+
+    /// How the latest announcement to each nostr relay went, by relay
+    /// URL.
+    pub relays: BTreeMap<String, Relay>,
+
+    /// How many peers hold each pinned version, by
+    /// `{name}@{version}`.
+    pub pins: BTreeMap<String, Replication>,
+
+    /// Requests to peers for crates and blobs that are not answered
+    /// yet.
+    pub backlog: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthFailures {
+    pub count: u32,
+    /// Seconds since the Unix epoch.
+    pub last_at: u64,
+    /// Seconds since the Unix epoch.
+    pub locked_until: Option<u64>,
+}
+
+impl Status {
+    #[cfg(feature = "p2p")]
+    pub fn announce(&mut self, from: impl Into<String>, message: impl Into<String>) {
+        self.announcements.push_front(Announcement {
+            from: from.into(),
+            message: message.into(),
+            received_at: unix_now(),
+        });
+        self.announcements.truncate(MAX_ANNOUNCEMENTS);
+    }
+
+    #[cfg(feature = "p2p")]
+    pub fn count_reject(&mut self, step: &str) {
+        *self
+            .announcement_rejects
+            .entry(step.to_owned())
+            .or_default() += 1;
+    }
+
+    /// Records that `what` is about to be sent to or received from
+    /// `peer`, before the transfer itself starts.
+    #[cfg(feature = "p2p")]
+    pub fn expect_transfer(&mut self, direction: Direction, what: &str, peer: String) {
+        let transfer = Transfer {
+            peer: Some(peer),
+            ..Transfer::new(direction, what)
+        };
+        self.transfers.insert(transfer.key(), transfer);
+    }
+
+    #[cfg(feature = "p2p")]
+    pub fn progress_transfer(&mut self, direction: Direction, what: &str, bytes: u64, total: u64) {
+        let transfer = Transfer::new(direction, what);
+        self.transfers
+            .entry(transfer.key())
+            .or_insert(transfer)
+            .advance(bytes, total, unix_now());
+    }
+
+    #[cfg(feature = "p2p")]
+    pub fn finish_transfer(&mut self, direction: Direction, what: &str) {
+        self.transfers.remove(&Transfer::new(direction, what).key());
+    }
+
+    /// Drops the transfers of a peer that has gone away.
+    #[cfg(feature = "p2p")]
+    pub fn forget_transfers_of(&mut self, peer: &str) {
+        self.transfers
+            .retain(|_, t| t.peer.as_deref() != Some(peer));
+    }
+
+    #[cfg(feature = "server")]
+    pub fn record_publish(&mut self, name: &str, version: &str, user: &str) {
+        self.publishes.push_front(Publish {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            user: user.to_owned(),
+            published_at: unix_now(),
+        });
+        self.publishes.truncate(MAX_PUBLISHES);
+    }
+
+    #[cfg(feature = "nostr")]
+    pub fn record_relay(&mut self, relay: &str, error: Option<String>) {
+        let relay = self.relays.entry(relay.to_owned()).or_default();
+        relay.attempted_at = unix_now();
+        match error {
+            Some(error) => relay.error = Some(error),
+            None => {
+                relay.error = None;
+                relay.succeeded_at = Some(relay.attempted_at);
+            }
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub fn count_download(&mut self, name: &str, version: &str) {
+        *self
+            .downloads
+            .entry(name.to_owned())
+            .or_default()
+            .entry(version.to_owned())
+            .or_default() += 1;
+    }
+
+    /// How many seconds remain of the source's lockout, if any.
+    #[cfg(feature = "server")]
+    pub fn lockout_remaining(&self, source: &str) -> Option<u64> {
+        let now = unix_now();
+        let until = self.auth_failures.get(source)?.locked_until?;
+        until.checked_sub(now).filter(|&secs| secs > 0)
+    }
+
+    /// Returns the length of the lockout if this failure starts one.
+    #[cfg(feature = "server")]
+    pub fn record_auth_failure(&mut self, source: &str) -> Option<(u32, u64)> {
+        let now = unix_now();
+
+        self.auth_failures_total += 1;
+        self.auth_failures
+            .retain(|_, f| now.saturating_sub(f.last_at) < FORGET_AUTH_FAILURES_SECS);
+
+        if self.auth_failures.len() >= MAX_AUTH_FAILURE_SOURCES
+            && !self.auth_failures.contains_key(source)
+        {
+            let oldest = self
+                .auth_failures
+                .iter()
+                .min_by_key(|(_, f)| f.last_at)
+                .map(|(s, _)| s.clone());
+            if let Some(oldest) = oldest {
+                self.auth_failures.remove(&oldest);
+            }
+        }
+
+        let failures = self.auth_failures.entry(source.to_owned()).or_default();
+        failures.count += 1;
+        failures.last_at = now;
+
+        let excess = failures.count.checked_sub(FREE_AUTH_FAILURES + 1)?;
+        let secs = 2u64
+            .saturating_pow(excess)
+            .saturating_mul(BASE_LOCKOUT_SECS)
+            .min(MAX_LOCKOUT_SECS);
+        failures.locked_until = Some(now + secs);
+
+        Some((failures.count, secs))
+    }
+
+    #[cfg(feature = "server")]
+    pub fn clear_auth_failures(&mut self, source: &str) {
+        self.auth_failures.remove(source);
+    }
+
+    #[cfg(feature = "server")]
+    pub fn downloads_of(&self, name: &str) -> u64 {
+        self.downloads
+            .get(name)
+            .map_or(0, |versions| versions.values().sum())
+    }
+
+    /// A copy of the status without what names a crate that `may_see`
+    /// refuses, for requests that may not see every crate.
+    #[cfg(feature = "server")]
+    pub fn visible(&self, may_see: impl Fn(&str) -> bool) -> Self {
+        let pinned_crate = |pin: &str| pin.split_once('@').map_or(pin, |(name, _)| name);
+
+        Self {
+            peers: self.peers.clone(),
+            local_peer_id: self.local_peer_id.clone(),
+            listen_addrs: self.listen_addrs.clone(),
+            announcements: self.announcements.clone(),
+            announcement_rejects: self.announcement_rejects.clone(),
+            downloads: self
+                .downloads
+                .iter()
+                .filter(|(name, _)| may_see(name))
+                .map(|(name, versions)| (name.clone(), versions.clone()))
+                .collect(),
+            auth_failures_total: self.auth_failures_total,
+            auth_failures: self.auth_failures.clone(),
+            transfers: self
+                .transfers
+                .iter()
+                .filter(|(_, t)| t.crate_name().map_or(true, &may_see))
+                .map(|(key, t)| (key.clone(), t.clone()))
+                .collect(),
+            publishes: self
+                .publishes
+                .iter()
+                .filter(|p| may_see(&p.name))
+                .cloned()
+                .collect(),
+            relays: self.relays.clone(),
+            pins: self
+                .pins
+                .iter()
+                .filter(|(pin, _)| may_see(pinned_crate(pin)))
+                .map(|(pin, r)| (pin.clone(), r.clone()))
+                .collect(),
+            backlog: self.backlog,
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub fn downloads_of_version(&self, name: &str, version: &str) -> u64 {
+        self.downloads
+            .get(name)
+            .and_then(|versions| versions.get(version))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Peer {
+    pub address: Option<String>,
+    pub agent: Option<String>,
+
+    /// The round-trip time of the latest ping.
+    pub rtt_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    pub from: String,
+    pub message: String,
+    /// Seconds since the Unix epoch.
+    pub received_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Publish {
+    pub name: String,
+    pub version: String,
+    pub user: String,
+    /// Seconds since the Unix epoch.
+    pub published_at: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Relay {
+    /// Seconds since the Unix epoch.
+    pub attempted_at: u64,
+
+    /// Seconds since the Unix epoch.
+    pub succeeded_at: Option<u64>,
+
+    /// Why the latest attempt failed, if it did.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Replication {
+    /// The connected peers that said they hold the version.
+    pub providers: usize,
+
+    /// How many should.
+    pub replicas: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    pub direction: Direction,
+
+    /// What is being transferred, such as `blob {digest}` or
+    /// `commit {hash}`.
+    pub what: String,
+
+    /// The peer ID, when it is known.
+    pub peer: Option<String>,
+
+    pub bytes: u64,
+
+    /// Known once the first bytes have been sent or received.
+    pub total: Option<u64>,
+
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+
+    pub bytes_per_sec: u64,
+
+    /// Seconds until the transfer finishes at its current rate.
+    pub eta_secs: Option<u64>,
+}
+
+impl Transfer {
+    /// The crate being transferred, if it is one.
+    #[cfg(feature = "server")]
+    pub fn crate_name(&self) -> Option<&str> {
+        self.what.strip_prefix("crate ")?.split(' ').next()
+    }
+
+    #[cfg(feature = "p2p")]
+    fn new(direction: Direction, what: &str) -> Self {
+        Self {
+            direction,
+            what: what.to_owned(),
+            peer: None,
+            bytes: 0,
+            total: None,
+            started_at: unix_now(),
+            bytes_per_sec: 0,
+            eta_secs: None,
+        }
+    }
+
+    #[cfg(feature = "p2p")]
+    fn key(&self) -> String {
+        format!("{} {}", self.direction, self.what)
+    }
+
+    #[cfg(feature = "p2p")]
+    fn advance(&mut self, bytes: u64, total: u64, now: u64) {
+        let elapsed = now.saturating_sub(self.started_at).max(1);
+
+        self.bytes = bytes;
+        self.total = Some(total);
+        self.bytes_per_sec = bytes / elapsed;
+        self.eta_secs = match self.bytes_per_sec {
+            0 => None,
+            rate => Some(total.saturating_sub(bytes) / rate),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Send => "send".fmt(f),
+            Self::Receive => "receive".fmt(f),
+        }
+    }
+}
+
+/// A cheaply-cloneable handle to the daemon's [`Status`].
+#[derive(Debug, Clone, Default)]
+pub struct SharedStatus(Arc<Mutex<Status>>);
+
+impl SharedStatus {
+    pub fn update<T>(&self, f: impl FnOnce(&mut Status) -> T) -> T {
+        let mut status = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut status)
+    }
+
+    /// Serializes the status while the lock is held, rather than
+    /// copying it out first.
+    #[cfg(feature = "server")]
+    pub fn to_json(&self) -> Vec<u8> {
+        self.update(|status| serde_json::to_vec(status).expect("The status is always serializable"))
+    }
+}
+
+pub fn unix_now() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lockouts_double_after_the_free_failures() {
+        let mut status = Status::default();
+        let source = "192.0.2.1";
+
+        for _ in 0..FREE_AUTH_FAILURES {
+            assert_eq!(None, status.record_auth_failure(source));
+        }
+        assert_eq!(None, status.lockout_remaining(source));
+
+        assert_eq!(Some((6, 30)), status.record_auth_failure(source));
+        assert_eq!(Some((7, 60)), status.record_auth_failure(source));
+        assert!(status.lockout_remaining(source).is_some());
+        assert_eq!(None, status.lockout_remaining("192.0.2.2"));
+
+        for _ in 0..100 {
+            status.record_auth_failure(source);
+        }
+        let (_, secs) = status.record_auth_failure(source).unwrap();
+        assert_eq!(MAX_LOCKOUT_SECS, secs);
+
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(108, json["auth_failures_total"]);
+        assert!(json.get("auth_failures").is_none());
+
+        status.clear_auth_failures(source);
+        assert_eq!(None, status.lockout_remaining(source));
+        assert_eq!(108, status.auth_failures_total);
+    }
+
+    #[test]
+    fn hidden_crates_are_left_out() {
+        let mut status = Status::default();
+        for name in ["public", "private"] {
+            status.record_publish(name, "1.0.0", "static:aa");
+            status.count_download(name, "1.0.0");
+            let pin = format!("{name}@1.0.0");
+            status.pins.insert(pin, Replication::default());
+        }
+
+        let visible = status.visible(|name| name == "public");
+        let names = visible.publishes.iter().map(|p| p.name.as_str());
+        assert_eq!(["public"], *names.collect::<Vec<_>>());
+        assert_eq!(["public"], *visible.downloads.keys().collect::<Vec<_>>());
+        assert_eq!(["public@1.0.0"], *visible.pins.keys().collect::<Vec<_>>());
+        assert_eq!(2, status.publishes.len());
+    }
+
+    #[cfg(feature = "p2p")]
+    #[test]
+    fn transfers_report_their_rate_until_finished() {
+        let mut status = Status::default();
+        let what = "blob abc";
+
+        status.expect_transfer(Direction::Send, what, "peer-a".to_owned());
+        status.progress_transfer(Direction::Send, what, 1000, 4000);
+        let transfer = &status.transfers["send blob abc"];
+        assert_eq!(Some("peer-a"), transfer.peer.as_deref());
+        assert_eq!((1000, Some(4000)), (transfer.bytes, transfer.total));
+
+        let mut transfer = transfer.clone();
+        transfer.advance(2000, 4000, transfer.started_at + 4);
+        assert_eq!((500, Some(4)), (transfer.bytes_per_sec, transfer.eta_secs));
+
+        status.progress_transfer(Direction::Receive, what, 10, 20);
+        status.forget_transfers_of("peer-a");
+        assert_eq!(1, status.transfers.len());
+        status.finish_transfer(Direction::Receive, what);
+        assert!(status.transfers.is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! Where `margo sync` left off.
+//!
+//! `sync-cursor.json` in the registry records the crates of the run in
+//! progress and how many of them are done, and for every crate a
+//! watermark: the highest crates.io version ID synced so far. Running
+//! `margo sync` again with the same crates after an interruption resumes
+//! with the first unfinished crate. Versions at or below a crate's
+//! watermark are not looked at again; only newer ones are compared with
+//! the registry. Version IDs grow in publication order, so a backported
+//! release is still newer than the watermark even when its version
+//! number is lower.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::timestamp::Timestamp;
+
+pub const FILE_NAME: &str = "sync-cursor.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cursor {
+    /// `None` once the last run finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    run: Option<Run>,
+
+    #[serde(default)]
+    crates: BTreeMap<String, Watermark>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Run {
+    crates: Vec<String>,
+    done: usize,
+    started_at: Timestamp,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Watermark {
+    version_id: u64,
+    synced_at: Timestamp,
+}
+
+impl Cursor {
+    /// A missing file is an empty cursor.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        use error::*;
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).context(ReadSnafu { path }),
+        };
+
+        serde_json::from_slice(&data).context(ParseSnafu { path })
+    }
+
+    /// Written to a temporary file first, so an interruption never
+    /// leaves a truncated cursor behind.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        use error::*;
+
+        let data = serde_json::to_vec_pretty(self).context(SerializeSnafu)?;
+
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+        fs::rename(&tmp, path).context(WriteSnafu { path })
+    }
+
+    /// Returns how many of `crates` an interrupted run with the same
+    /// crates already finished, or starts a new run and returns 0.
+    pub fn resume(&mut self, crates: &[String]) -> usize {
+        match &self.run {
+            Some(run) if run.crates == crates => run.done,
+            _ => {
+                self.run = Some(Run {
+                    crates: crates.to_vec(),
+                    done: 0,
+                    started_at: Timestamp::now(),
+                });
+                0
+            }
+        }
+    }
+
+    /// Forgets the run in progress and every watermark.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn watermark(&self, crate_name: &str) -> Option<u64> {
+        self.crates.get(crate_name).map(|w| w.version_id)
+    }
+
+    /// Marks the next crate of the run as done, raising its watermark to
+    /// `newest` when given.
+    pub fn finish_crate(&mut self, crate_name: &str, newest: Option<u64>) {
+        if let Some(run) = &mut self.run {
+            run.done += 1;
+        }
+
+        if let Some(newest) = newest {
+            let raise = self.watermark(crate_name).map_or(true, |w| w < newest);
+            if raise {
+                let watermark = Watermark {
+                    version_id: newest,
+                    synced_at: Timestamp::now(),
+                };
+                self.crates.insert(crate_name.to_owned(), watermark);
+            }
+        }
+    }
+
+    pub fn finish_run(&mut self) {
+        self.run = None;
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the sync cursor at {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the sync cursor at {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the sync cursor"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write the sync cursor to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_SYNC_CURSOR_CORRUPT",
+            Self::Serialize { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_resume_only_with_the_same_crates() {
+        let crates = ["serde".to_owned(), "tokio".to_owned()];
+
+        let mut cursor = Cursor::default();
+        assert_eq!(0, cursor.resume(&crates));
+        cursor.finish_crate("serde", Some(41));
+        assert_eq!(1, cursor.resume(&crates));
+        assert_eq!(Some(41), cursor.watermark("serde"));
+
+        // A different set of crates starts over, keeping the watermarks
+        assert_eq!(0, cursor.resume(&crates[1..]));
+        assert_eq!(Some(41), cursor.watermark("serde"));
+
+        cursor.finish_crate("tokio", None);
+        cursor.finish_run();
+        assert_eq!(0, cursor.resume(&crates[1..]));
+        assert_eq!(None, cursor.watermark("tokio"));
+    }
+}
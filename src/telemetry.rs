@@ -0,0 +1,233 @@
+//! Tracing requests through the daemon with OpenTelemetry.
+//!
+//! With the `telemetry` feature, `margo serve --otlp-endpoint
+//! http://127.0.0.1:4318/v1/traces` sends spans to an OTLP/HTTP
+//! collector, as does setting `OTEL_EXPORTER_OTLP_ENDPOINT`. Each HTTP
+//! request gets a span, continuing the trace of a caller that sends a
+//! `traceparent` header, and the registry work it does gets spans
+//! below it, so a slow publish can be followed from the request through
+//! the scan, the index update, and the HTML and feed regeneration. P2P
+//! transfers get a span each.
+//!
+//! Without the feature, or without an endpoint, the spans cost nothing.
+
+#[cfg(feature = "telemetry")]
+pub use enabled::*;
+
+#[cfg(not(feature = "telemetry"))]
+pub use disabled::*;
+
+/// Runs blocking work off the async threads, in a span below the
+/// current one.
+#[cfg(feature = "server")]
+pub fn spawn_blocking<F, T>(name: &'static str, f: F) -> tokio::task::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let parent = Parent::current();
+    tokio::task::spawn_blocking(move || parent.in_span(name, f))
+}
+
+/// Runs `f` in a span below the current one.
+pub fn in_span<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    Parent::current().in_span(name, f)
+}
+
+#[cfg(feature = "telemetry")]
+mod enabled {
+    use axum::{extract::Request, http::HeaderMap, middleware::Next, response::Response};
+    use opentelemetry::{
+        global,
+        propagation::Extractor,
+        trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+        Context, KeyValue,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+    use snafu::prelude::*;
+    use url::Url;
+
+    #[cfg(feature = "p2p")]
+    use opentelemetry::trace::Span as _;
+    #[cfg(feature = "p2p")]
+    use std::fmt;
+
+    const TRACER_NAME: &str = "margo";
+
+    /// Flushes the spans not yet sent when dropped.
+    #[derive(Debug)]
+    pub struct Telemetry(trace::TracerProvider);
+
+    impl Drop for Telemetry {
+        fn drop(&mut self) {
+            if let Err(e) = self.0.shutdown() {
+                eprintln!("Warning: Could not send the last spans: {e}");
+            }
+        }
+    }
+
+    /// Starts sending spans to `endpoint`, or to the collector the
+    /// `OTEL_EXPORTER_OTLP_*` variables name. Returns `None` when
+    /// neither is given. Must be called within the Tokio runtime.
+    pub fn init(endpoint: Option<&Url>) -> Result<Option<Telemetry>, Error> {
+        use error::*;
+
+        let from_env = [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some());
+        if endpoint.is_none() && !from_env {
+            return Ok(None);
+        }
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+        let exporter = match endpoint {
+            Some(endpoint) => exporter.with_endpoint(endpoint.as_str()),
+            None => exporter,
+        };
+        let exporter = exporter.build().context(ExporterSnafu)?;
+
+        let provider = trace::TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new("service.name", "margo")]))
+            .build();
+
+        global::set_tracer_provider(provider.clone());
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        Ok(Some(Telemetry(provider)))
+    }
+
+    /// Where new spans go: below the span that was current when this
+    /// was made, even on another thread.
+    pub struct Parent(Context);
+
+    impl Parent {
+        pub fn current() -> Self {
+            Self(Context::current())
+        }
+
+        pub fn in_span<T>(self, name: &'static str, f: impl FnOnce() -> T) -> T {
+            let _attached = self.0.attach();
+            global::tracer(TRACER_NAME).in_span(name, |_| f())
+        }
+    }
+
+    /// A span that lasts until it is dropped, for work that does not
+    /// fit in one closure.
+    #[cfg(feature = "p2p")]
+    pub struct Span(global::BoxedSpan);
+
+    #[cfg(feature = "p2p")]
+    impl Span {
+        pub fn start(name: &'static str) -> Self {
+            Self(global::tracer(TRACER_NAME).start(name))
+        }
+
+        pub fn set(&mut self, key: &'static str, value: impl fmt::Display) {
+            self.0.set_attribute(KeyValue::new(key, value.to_string()));
+        }
+    }
+
+    #[cfg(feature = "p2p")]
+    impl fmt::Debug for Span {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Span").finish_non_exhaustive()
+        }
+    }
+
+    /// Gives each request a span, below the caller's when it sent a
+    /// `traceparent` header.
+    pub async fn trace_requests(request: Request, next: Next) -> Response {
+        let parent = global::get_text_map_propagator(|p| p.extract(&Headers(request.headers())));
+
+        let tracer = global::tracer(TRACER_NAME);
+        let method = request.method().to_string();
+        let span = tracer
+            .span_builder(method.clone())
+            .with_kind(SpanKind::Server)
+            .with_attributes([
+                KeyValue::new("http.request.method", method),
+                KeyValue::new("url.path", request.uri().path().to_owned()),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+
+        let response = next.run(request).with_context(cx.clone()).await;
+
+        let status = response.status();
+        let span = cx.span();
+        span.set_attribute(KeyValue::new(
+            "http.response.status_code",
+            i64::from(status.as_u16()),
+        ));
+        if status.is_server_error() {
+            span.set_status(Status::error(status.to_string()));
+        }
+
+        response
+    }
+
+    struct Headers<'a>(&'a HeaderMap);
+
+    impl Extractor for Headers<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|v| v.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|k| k.as_str()).collect()
+        }
+    }
+
+    #[derive(Debug, Snafu)]
+    #[snafu(module)]
+    pub enum Error {
+        #[snafu(display("Could not set up the OTLP exporter"))]
+        Exporter {
+            source: opentelemetry::trace::TraceError,
+        },
+    }
+
+    impl Error {
+        pub fn code(&self) -> &'static str {
+            match self {
+                Self::Exporter { .. } => "E_TELEMETRY",
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "telemetry"))]
+mod disabled {
+    #[cfg(feature = "p2p")]
+    use std::fmt;
+
+    pub struct Parent;
+
+    impl Parent {
+        pub fn current() -> Self {
+            Self
+        }
+
+        pub fn in_span<T>(self, _name: &'static str, f: impl FnOnce() -> T) -> T {
+            f()
+        }
+    }
+
+    #[cfg(feature = "p2p")]
+    #[derive(Debug)]
+    pub struct Span;
+
+    #[cfg(feature = "p2p")]
+    impl Span {
+        pub fn start(_name: &'static str) -> Self {
+            Self
+        }
+
+        pub fn set(&mut self, _key: &'static str, _value: impl fmt::Display) {}
+    }
+}
@@ -0,0 +1,239 @@
+//! Moving `.crate` files nobody downloads to cheaper storage.
+//!
+//! With `[tiering] cold-dir` set in `margo-config.toml`, the registry's
+//! `crates/` directory is the hot tier and `cold-dir` the cold one. The
+//! cold directory is usually object storage mounted as a file system,
+//! such as an S3 bucket through `s3fs` or `rclone mount`, or IPFS
+//! through its FUSE mount. A `.crate` file has the same relative path in
+//! both.
+//!
+//! `margo tier demote`, and the daemon every hour, move `.crate` files
+//! that have not been downloaded for `demote-after-days` to the cold
+//! tier. The index is not changed. When a demoted crate is asked for,
+//! the daemon copies it back before serving it, and the cold copy is
+//! kept, so demoting it again only removes the local file. The daemon
+//! marks a file as used by updating its modification time, at most once
+//! a day.
+
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::CRATE_DIR_NAME;
+
+pub const DEFAULT_DEMOTE_AFTER_DAYS: u64 = 90;
+
+/// How stale a modification time may be before serving the file
+/// updates it.
+const TOUCH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// How many `.crate` files were moved to the cold tier, or would be.
+    pub demoted: usize,
+
+    /// The bytes they took up on local disk.
+    pub bytes: u64,
+}
+
+/// Moves the `.crate` files below `root` that have not been used for
+/// `after` to `cold_dir`. With `dry_run`, only reports what would move.
+pub fn demote(
+    root: &Path,
+    cold_dir: &Path,
+    after: Duration,
+    dry_run: bool,
+) -> Result<Summary, Error> {
+    use error::*;
+
+    let mut summary = Summary::default();
+
+    let crates = root.join(CRATE_DIR_NAME);
+    if !crates.exists() {
+        return Ok(summary);
+    }
+
+    let now = SystemTime::now();
+    for entry in walkdir::WalkDir::new(&crates) {
+        let entry = entry.context(WalkSnafu { path: &crates })?;
+        let path = entry.path();
+        if !entry.file_type().is_file() || path.extension().map_or(true, |e| e != "crate") {
+            continue;
+        }
+
+        let metadata = entry.metadata().context(WalkSnafu { path })?;
+        let unused_for = metadata
+            .modified()
+            .ok()
+            .and_then(|m| now.duration_since(m).ok())
+            .unwrap_or_default();
+        if unused_for < after {
+            continue;
+        }
+
+        summary.demoted += 1;
+        summary.bytes += metadata.len();
+        if dry_run {
+            continue;
+        }
+
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let cold = cold_dir.join(relative);
+
+        // A crate that was promoted still has its cold copy
+        let cold_len = fs::metadata(&cold).ok().map(|m| m.len());
+        if cold_len != Some(metadata.len()) {
+            copy(path, &cold)?;
+        }
+        fs::remove_file(path).context(WriteSnafu { path })?;
+    }
+
+    Ok(summary)
+}
+
+/// Copies the `.crate` file at `relative` back from `cold_dir` if it is
+/// not on local disk. Returns whether it is on local disk now.
+pub fn promote(root: &Path, cold_dir: &Path, relative: &Path) -> Result<bool, Error> {
+    use error::*;
+
+    ensure!(is_relative(relative), PathSnafu { path: relative });
+
+    let local = root.join(relative);
+    if local.exists() {
+        return Ok(true);
+    }
+
+    let cold = cold_dir.join(relative);
+    if !cold.exists() {
+        return Ok(false);
+    }
+
+    copy(&cold, &local)?;
+
+    Ok(true)
+}
+
+/// Promotes the `.crate` file at `relative` if needed and marks it as
+/// used, before it is served.
+pub fn prepare_download(root: &Path, cold_dir: &Path, relative: &Path) -> Result<(), Error> {
+    if promote(root, cold_dir, relative)? {
+        touch(&root.join(relative))?;
+    }
+
+    Ok(())
+}
+
+fn touch(path: &Path) -> Result<(), Error> {
+    use error::*;
+
+    let file = fs::File::options()
+        .write(true)
+        .open(path)
+        .context(WriteSnafu { path })?;
+    let modified = file
+        .metadata()
+        .and_then(|m| m.modified())
+        .context(ReadSnafu { path })?;
+
+    let is_stale = modified.elapsed().map_or(true, |age| age > TOUCH_INTERVAL);
+    if is_stale {
+        file.set_modified(SystemTime::now())
+            .context(WriteSnafu { path })?;
+    }
+
+    Ok(())
+}
+
+/// Copied under a temporary name first, so a reader never sees part of
+/// a file.
+fn copy(from: &Path, to: &Path) -> Result<(), Error> {
+    use error::*;
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).context(WriteSnafu { path: parent })?;
+    }
+
+    let tmp = to.with_extension("crate.tmp");
+    fs::copy(from, &tmp).context(CopySnafu { from, to: &tmp })?;
+    fs::rename(&tmp, to).context(WriteSnafu { path: to })
+}
+
+fn is_relative(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("The registry has no `[tiering] cold-dir`"))]
+    NotConfigured,
+
+    #[snafu(display("{} is not a path within the registry", path.display()))]
+    Path { path: PathBuf },
+
+    #[snafu(display("Could not walk {}", path.display()))]
+    Walk {
+        source: walkdir::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not copy {} to {}", from.display(), to.display()))]
+    Copy {
+        source: io::Error,
+        from: PathBuf,
+        to: PathBuf,
+    },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotConfigured => "E_TIERING_NOT_CONFIGURED",
+            Self::Path { .. } => "E_BAD_PATH",
+            Self::Walk { .. } | Self::Read { .. } => "E_STORAGE_READ",
+            Self::Copy { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unused_crates_move_to_the_cold_tier_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let root = dir.join("registry");
+        let cold_dir = dir.join("cold");
+
+        let relative = Path::new("crates/de/mo/demo/1.0.0.crate");
+        let local = root.join(relative);
+        fs::create_dir_all(local.parent().unwrap()).unwrap();
+        fs::write(&local, "demo").unwrap();
+
+        let summary = demote(&root, &cold_dir, Duration::from_secs(3600), false).unwrap();
+        assert_eq!(0, summary.demoted);
+
+        let summary = demote(&root, &cold_dir, Duration::ZERO, false).unwrap();
+        assert_eq!((1, 4), (summary.demoted, summary.bytes));
+        assert!(!local.exists());
+        assert_eq!("demo", fs::read_to_string(cold_dir.join(relative)).unwrap());
+
+        prepare_download(&root, &cold_dir, relative).unwrap();
+        assert_eq!("demo", fs::read_to_string(&local).unwrap());
+
+        let missing = Path::new("crates/de/mo/demo/2.0.0.crate");
+        assert!(!promote(&root, &cold_dir, missing).unwrap());
+        assert!(promote(&root, &cold_dir, Path::new("crates/../../etc/passwd")).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Whole-second UTC timestamps, without pulling in a date library.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    env, fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Seconds since the Unix epoch.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self(secs)
+    }
+
+    /// The time to record in files margo generates: `SOURCE_DATE_EPOCH`
+    /// when it is set, so that the same inputs give the same bytes, and
+    /// the current time otherwise.
+    pub fn generated() -> Self {
+        env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map_or_else(Self::now, Self)
+    }
+}
+
+/// Formats as RFC 3339, e.g. `2024-06-01T12:00:00Z`.
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.0 / SECS_PER_DAY;
+        let secs = self.0 % SECS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+        )
+    }
+}
+
+/// Accepts `2024-06-01`, `2024-06-01T12:00:00Z`, or a number of
+/// seconds since the Unix epoch.
+impl FromStr for Timestamp {
+    type Err = TimestampError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use timestamp_error::*;
+
+        if let Ok(secs) = s.parse() {
+            return Ok(Self(secs));
+        }
+
+        let (date, time) = match s.split_once('T') {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None),
+        };
+
+        let mut date = date.splitn(3, '-').map(str::parse::<u64>);
+        let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) =
+            (date.next(), date.next(), date.next())
+        else {
+            return MalformedSnafu { value: s }.fail();
+        };
+        ensure!(
+            year >= 1970 && (1..=12).contains(&month) && (1..=31).contains(&day),
+            MalformedSnafu { value: s }
+        );
+
+        let secs = match time {
+            None => 0,
+            Some(time) => {
+                let time = time.strip_suffix('Z').context(TimezoneSnafu { value: s })?;
+                let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+                let (Some(Ok(h)), Some(Ok(m)), Some(Ok(sec))) =
+                    (time.next(), time.next(), time.next())
+                else {
+                    return MalformedSnafu { value: s }.fail();
+                };
+                ensure!(h < 24 && m < 60 && sec < 60, MalformedSnafu { value: s });
+                h * 3600 + m * 60 + sec
+            }
+        };
+
+        let days = days_from_civil(year, month, day);
+
+        Ok(Self(days * SECS_PER_DAY + secs))
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum TimestampError {
+    #[snafu(display("`{value}` is not a date like 2024-06-01 or 2024-06-01T12:00:00Z"))]
+    Malformed { value: String },
+
+    #[snafu(display("`{value}` must be in UTC (end with `Z`)"))]
+    Timezone { value: String },
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Conversions between days since the epoch and the proleptic
+// Gregorian calendar, from
+// https://howardhinnant.github.io/date_algorithms.html. Only dates
+// after the epoch are needed, so the negative-era handling is
+// omitted.
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_as_rfc3339() {
+        assert_eq!("1970-01-01T00:00:00Z", Timestamp(0).to_string());
+        assert_eq!("2000-02-29T23:59:59Z", Timestamp(951_868_799).to_string());
+        assert_eq!("2024-06-01T12:34:56Z", Timestamp(1_717_245_296).to_string());
+    }
+
+    #[test]
+    fn parses_what_it_formats() {
+        for secs in [0, 951_868_799, 1_717_245_296, 4_102_444_800] {
+            let ts = Timestamp(secs);
+            assert_eq!(ts, ts.to_string().parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn parses_bare_dates() {
+        assert_eq!(Timestamp(1_717_200_000), "2024-06-01".parse().unwrap());
+        assert!("2024-13-01".parse::<Timestamp>().is_err());
+        assert!("2024-06-01T12:00:00+01:00".parse::<Timestamp>().is_err());
+    }
+}
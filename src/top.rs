@@ -0,0 +1,292 @@
+//! A live view of a running daemon in the terminal.
+//!
+//! `margo top` asks the daemon's [control socket](crate::control) for
+//! its status every second and shows the connected peers, the P2P
+//! transfers in progress with their rates, the latest publishes, and
+//! how the latest announcement to each nostr relay went. `q` or `Esc`
+//! quits. While the daemon cannot be reached, the reason is shown
+//! until it comes back.
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::Stylize,
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use snafu::prelude::*;
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    control::{self, Request, Response},
+    status::{self, Direction, Status},
+};
+
+pub fn run(registry: &Path, interval: Duration) -> Result<(), Error> {
+    use error::*;
+
+    let mut terminal = ratatui::try_init().context(TerminalSnafu)?;
+    let result = watch(&mut terminal, registry, interval);
+    ratatui::restore();
+
+    result
+}
+
+fn watch(terminal: &mut DefaultTerminal, registry: &Path, interval: Duration) -> Result<(), Error> {
+    use error::*;
+
+    loop {
+        let status = fetch(registry);
+        let now = status::unix_now();
+        terminal
+            .draw(|frame| draw(frame, registry, &status, now))
+            .context(TerminalSnafu)?;
+
+        // Waits for the next refresh, unless told to quit first
+        let next = Instant::now() + interval;
+        while let Some(timeout) = next.checked_duration_since(Instant::now()) {
+            if !event::poll(timeout).context(TerminalSnafu)? {
+                break;
+            }
+            if let Event::Key(key) = event::read().context(TerminalSnafu)? {
+                if is_quit(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn is_quit(key: KeyEvent) -> bool {
+    let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+    key.kind == KeyEventKind::Press
+        && (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) || ctrl_c)
+}
+
+fn fetch(registry: &Path) -> Result<Status, String> {
+    match control::request(registry, &Request::Status) {
+        Ok(Response::Status { status }) => {
+            serde_json::from_value(status).map_err(|e| e.to_string())
+        }
+        Ok(_) => Err("The daemon answered with something other than its status".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn draw(frame: &mut Frame<'_>, registry: &Path, status: &Result<Status, String>, now: u64) {
+    let [header, peers, transfers, bottom, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Fill(1),
+        Constraint::Fill(1),
+        Constraint::Fill(1),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [publishes, relays] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(bottom);
+
+    let title = format!(" margo top: {} ", registry.display());
+    frame.render_widget(Paragraph::new("q to quit").dim(), help);
+
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            let message = Paragraph::new(format!("Could not reach the daemon: {e}"))
+                .red()
+                .block(Block::bordered().title(title));
+            frame.render_widget(message, header);
+            return;
+        }
+    };
+
+    draw_header(frame, header, title, status);
+    draw_peers(frame, peers, status);
+    draw_transfers(frame, transfers, status, now);
+    draw_publishes(frame, publishes, status, now);
+    draw_relays(frame, relays, status, now);
+}
+
+fn draw_header(frame: &mut Frame<'_>, area: Rect, title: String, status: &Status) {
+    let rate_of = |direction| {
+        status
+            .transfers
+            .values()
+            .filter(|t| t.direction == direction)
+            .map(|t| t.bytes_per_sec)
+            .sum::<u64>()
+    };
+    let downloads = status
+        .downloads
+        .values()
+        .flat_map(|versions| versions.values())
+        .sum::<u64>();
+
+    let line = format!(
+        "peer {}  peers {}  up {}/s  down {}/s  downloads {}",
+        status.local_peer_id.as_deref().unwrap_or("-"),
+        status.peers.len(),
+        human_bytes(rate_of(Direction::Send)),
+        human_bytes(rate_of(Direction::Receive)),
+        downloads,
+    );
+    frame.render_widget(
+        Paragraph::new(line).block(Block::bordered().title(title)),
+        area,
+    );
+}
+
+fn draw_peers(frame: &mut Frame<'_>, area: Rect, status: &Status) {
+    let rows = status.peers.iter().map(|(id, peer)| {
+        Row::new([
+            id.clone(),
+            peer.address.clone().unwrap_or_default(),
+            peer.agent.clone().unwrap_or_default(),
+            peer.rtt_ms.map(|ms| format!("{ms} ms")).unwrap_or_default(),
+        ])
+    });
+    let widths = [
+        Constraint::Fill(3),
+        Constraint::Fill(2),
+        Constraint::Fill(2),
+        Constraint::Length(8),
+    ];
+
+    let table = Table::new(rows, widths)
+        .header(Row::new(["Peer", "Address", "Agent", "RTT"]).bold())
+        .block(Block::bordered().title(" Peers "));
+    frame.render_widget(table, area);
+}
+
+fn draw_transfers(frame: &mut Frame<'_>, area: Rect, status: &Status, now: u64) {
+    let rows = status.transfers.values().map(|t| {
+        let size = match t.total {
+            Some(total) => format!("{} / {}", human_bytes(t.bytes), human_bytes(total)),
+            None => human_bytes(t.bytes),
+        };
+        Row::new([
+            t.direction.to_string(),
+            t.what.clone(),
+            t.peer.clone().unwrap_or_default(),
+            size,
+            format!("{}/s", human_bytes(t.bytes_per_sec)),
+            t.eta_secs.map(|eta| ago(eta, 0)).unwrap_or_default(),
+        ])
+    });
+    let widths = [
+        Constraint::Length(8),
+        Constraint::Fill(2),
+        Constraint::Fill(2),
+        Constraint::Length(22),
+        Constraint::Length(12),
+        Constraint::Length(6),
+    ];
+
+    let table = Table::new(rows, widths)
+        .header(Row::new(["", "Transfer", "Peer", "Bytes", "Rate", "ETA"]).bold())
+        .block(Block::bordered().title(" Transfers "));
+    frame.render_widget(table, area);
+}
+
+fn draw_publishes(frame: &mut Frame<'_>, area: Rect, status: &Status, now: u64) {
+    let lines = status
+        .publishes
+        .iter()
+        .map(|p| {
+            let when = ago(now, p.published_at);
+            Line::from(format!("{when:>4} {} {} by {}", p.name, p.version, p.user))
+        })
+        .collect::<Vec<_>>();
+
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Recent publishes ")),
+        area,
+    );
+}
+
+fn draw_relays(frame: &mut Frame<'_>, area: Rect, status: &Status, now: u64) {
+    let lines = status
+        .relays
+        .iter()
+        .map(|(url, relay)| match &relay.error {
+            Some(error) => Line::from(format!(
+                "{url} failed {} ago: {error}",
+                ago(now, relay.attempted_at)
+            ))
+            .red(),
+            None => Line::from(format!("{url} ok {} ago", ago(now, relay.attempted_at))),
+        })
+        .collect::<Vec<_>>();
+
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Relays ")),
+        area,
+    );
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{value:.1} {unit}")
+}
+
+/// How long before `now` the time `then` was, both in seconds since
+/// the Unix epoch, to the largest whole unit.
+fn ago(now: u64, then: u64) -> String {
+    let secs = now.saturating_sub(then);
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not draw in the terminal"))]
+    Terminal { source: io::Error },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Terminal { .. } => "E_TERMINAL",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes_and_times_are_shown_in_the_largest_whole_unit() {
+        assert_eq!("512 B", human_bytes(512));
+        assert_eq!("1.5 KiB", human_bytes(1536));
+        assert_eq!("3.0 GiB", human_bytes(3 * 1024 * 1024 * 1024));
+
+        assert_eq!("42s", ago(100, 58));
+        assert_eq!("2m", ago(1000, 850));
+        assert_eq!("3d", ago(3 * 86400 + 5, 0));
+        assert_eq!("0s", ago(10, 20));
+    }
+}
@@ -0,0 +1,378 @@
+//! Receiving `cargo publish` uploads without holding them in memory.
+//!
+//! `cargo publish` sends the JSON metadata and then the `.crate` file,
+//! each preceded by its length as a little-endian `u32`. Everything
+//! needed is in the `.crate` file, so the metadata is skipped as it
+//! arrives, and the `.crate` file is written to a temporary file in
+//! the publisher's data directory and hashed as it goes. The body is
+//! only read as fast as the file is written, so a slow disk slows the
+//! client down instead of filling memory, and reading stops as soon as
+//! either part says it is longer than allowed. The file is then mapped
+//! into memory rather than read back, and the digest taken as it
+//! arrived is the one the index records.
+//!
+//! Blobs and prebuilt binaries are uploaded as the whole body, which
+//! [`receive_file`] writes out the same way.
+
+use axum::body::Body;
+use futures_util::StreamExt;
+use memmap2::Mmap;
+use sha2::Digest;
+use snafu::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::io::AsyncWriteExt;
+
+pub const DIR_NAME: &str = "uploads";
+
+/// A file received from a client, which is deleted when dropped.
+#[derive(Debug)]
+pub struct Upload {
+    path: PathBuf,
+    pub len: u64,
+    pub sha256: String,
+}
+
+impl Upload {
+    /// Maps the file, failing if it is no longer as long as
+    /// what was received. The mapping must be dropped before the
+    /// upload.
+    pub fn map(&self) -> Result<Mmap, Error> {
+        use error::*;
+
+        let path = &self.path;
+        let file = fs::File::open(path).context(ReadSnafu { path })?;
+        let len = file.metadata().context(ReadSnafu { path })?.len();
+        ensure!(len == self.len, ChangedSnafu { path });
+
+        // SAFETY: the file is in the publisher's data directory, under
+        // a name only this upload uses, and nothing writes to it once
+        // it has been received
+        let map = unsafe { Mmap::map(&file) }.context(ReadSnafu { path })?;
+        Ok(map)
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Streams the `.crate` file in `body` to a file in `dir`.
+pub async fn receive(
+    body: Body,
+    dir: &Path,
+    max_metadata_len: u64,
+    max_crate_len: u64,
+) -> Result<Upload, Error> {
+    use error::*;
+
+    let mut receiving = Receiving::start(dir).await?;
+    let mut framing = Framing::new(max_metadata_len, max_crate_len);
+
+    let mut chunks = body.into_data_stream();
+    while !framing.is_done() {
+        let chunk = chunks.next().await.context(TruncatedSnafu)?;
+        let chunk = chunk.context(ReceiveSnafu)?;
+
+        receiving.write(framing.feed(&chunk)?).await?;
+    }
+
+    receiving.finish().await
+}
+
+/// Streams all of `body` to a file in `dir`, refusing it as soon as it
+/// is longer than `max_len`.
+pub async fn receive_file(body: Body, dir: &Path, max_len: u64) -> Result<Upload, Error> {
+    use error::*;
+
+    let mut receiving = Receiving::start(dir).await?;
+
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.context(ReceiveSnafu)?;
+
+        let len = receiving.upload.len + chunk.len() as u64;
+        ensure!(len <= max_len, FileTooLargeSnafu { max: max_len });
+        receiving.write(&chunk).await?;
+    }
+
+    receiving.finish().await
+}
+
+/// An upload being written and hashed.
+struct Receiving {
+    upload: Upload,
+    file: tokio::fs::File,
+    sha256: sha2::Sha256,
+}
+
+impl Receiving {
+    async fn start(dir: &Path) -> Result<Self, Error> {
+        use error::*;
+
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        fs::create_dir_all(dir).context(WriteSnafu { path: dir })?;
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}-{n}.tmp", std::process::id()));
+
+        // Removes the file however this ends
+        let upload = Upload {
+            path,
+            len: 0,
+            sha256: String::new(),
+        };
+
+        let file = tokio::fs::File::create(&upload.path)
+            .await
+            .context(WriteSnafu { path: &upload.path })?;
+
+        Ok(Self {
+            upload,
+            file,
+            sha256: sha2::Sha256::new(),
+        })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        use error::*;
+
+        self.sha256.update(data);
+        self.file.write_all(data).await.context(WriteSnafu {
+            path: &self.upload.path,
+        })?;
+        self.upload.len += data.len() as u64;
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<Upload, Error> {
+        use error::*;
+
+        self.file.flush().await.context(WriteSnafu {
+            path: &self.upload.path,
+        })?;
+
+        self.upload.sha256 = hex::encode(self.sha256.finalize());
+        Ok(self.upload)
+    }
+}
+
+/// Which part of the body the next byte belongs to, and how many bytes
+/// of it are left.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Part {
+    MetadataLen,
+    Metadata(u64),
+    CrateLen,
+    Crate(u64),
+    Done,
+}
+
+/// Splits a `cargo publish` body, however it is chunked.
+#[derive(Debug)]
+struct Framing {
+    part: Part,
+    len_bytes: Vec<u8>,
+    max_metadata_len: u64,
+    max_crate_len: u64,
+}
+
+impl Framing {
+    fn new(max_metadata_len: u64, max_crate_len: u64) -> Self {
+        Self {
+            part: Part::MetadataLen,
+            len_bytes: Vec::with_capacity(4),
+            max_metadata_len,
+            max_crate_len,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.part == Part::Done
+    }
+
+    /// Takes in the next chunk of the body, returning the bytes of it
+    /// that are part of the `.crate` file. Anything after the `.crate`
+    /// file is ignored.
+    fn feed<'a>(&mut self, mut chunk: &'a [u8]) -> Result<&'a [u8], Error> {
+        use error::*;
+
+        loop {
+            match self.part {
+                Part::MetadataLen | Part::CrateLen => {
+                    let wanted = 4 - self.len_bytes.len();
+                    let (taken, rest) = chunk.split_at(chunk.len().min(wanted));
+                    self.len_bytes.extend_from_slice(taken);
+                    chunk = rest;
+
+                    let Ok(len) = <[u8; 4]>::try_from(&self.len_bytes[..]) else {
+                        return Ok(&[]);
+                    };
+                    let len = u64::from(u32::from_le_bytes(len));
+                    self.len_bytes.clear();
+
+                    self.part = if self.part == Part::MetadataLen {
+                        let max = self.max_metadata_len;
+                        ensure!(len <= max, MetadataTooLargeSnafu { len, max });
+                        Part::Metadata(len)
+                    } else {
+                        let max = self.max_crate_len;
+                        ensure!(len <= max, TooLargeSnafu { len, max });
+                        Part::Crate(len)
+                    };
+                }
+
+                Part::Metadata(left) => {
+                    let n = left.min(chunk.len() as u64);
+                    chunk = &chunk[n as usize..];
+                    if n < left {
+                        self.part = Part::Metadata(left - n);
+                        return Ok(&[]);
+                    }
+                    self.part = Part::CrateLen;
+                }
+
+                Part::Crate(left) => {
+                    let n = left.min(chunk.len() as u64);
+                    self.part = if n < left {
+                        Part::Crate(left - n)
+                    } else {
+                        Part::Done
+                    };
+                    return Ok(&chunk[..n as usize]);
+                }
+
+                Part::Done => return Ok(&[]),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not receive the upload"))]
+    Receive { source: axum::Error },
+
+    #[snafu(display("The upload ended before the end of the crate file"))]
+    Truncated,
+
+    #[snafu(display("The publish metadata is {len} bytes, more than the {max} allowed"))]
+    MetadataTooLarge { len: u64, max: u64 },
+
+    #[snafu(display("The crate file is {len} bytes, more than the {max} allowed"))]
+    TooLarge { len: u64, max: u64 },
+
+    #[snafu(display("The upload is more than the {max} bytes allowed"))]
+    FileTooLarge { max: u64 },
+
+    #[snafu(display("Could not write the upload to {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not read the upload from {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("The upload at {} changed after it was received", path.display()))]
+    Changed { path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Receive { .. } | Self::Truncated => "E_BAD_REQUEST",
+            Self::MetadataTooLarge { .. } => "E_METADATA_TOO_LARGE",
+            Self::TooLarge { .. } => "E_CRATE_TOO_LARGE",
+            Self::FileTooLarge { .. } => "E_UPLOAD_TOO_LARGE",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Read { .. } | Self::Changed { .. } => "E_STORAGE_READ",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn body(metadata: &[u8], crate_file: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        body.extend((metadata.len() as u32).to_le_bytes());
+        body.extend(metadata);
+        body.extend((crate_file.len() as u32).to_le_bytes());
+        body.extend(crate_file);
+        body
+    }
+
+    #[test]
+    fn bodies_are_split_however_they_are_chunked() {
+        let body = body(b"{}", b"abc");
+
+        for chunk_len in 1..=body.len() {
+            let mut framing = Framing::new(16, 16);
+            let mut crate_file = vec![];
+            for chunk in body.chunks(chunk_len) {
+                crate_file.extend(framing.feed(chunk).unwrap());
+            }
+            assert!(framing.is_done(), "chunks of {chunk_len}");
+            assert_eq!(b"abc", &*crate_file, "chunks of {chunk_len}");
+        }
+
+        let mut framing = Framing::new(16, 16);
+        framing.feed(&body[..body.len() - 1]).unwrap();
+        assert!(!framing.is_done());
+    }
+
+    #[test]
+    fn oversized_parts_are_refused_before_they_arrive() {
+        let body = body(b"{}", b"abc");
+
+        let e = Framing::new(1, 16).feed(&body).unwrap_err();
+        assert_eq!("E_METADATA_TOO_LARGE", e.code());
+
+        let e = Framing::new(16, 2).feed(&body).unwrap_err();
+        assert_eq!("E_CRATE_TOO_LARGE", e.code());
+    }
+
+    #[tokio::test]
+    async fn uploads_are_written_and_hashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let body = Body::from(body(b"{}", b"abc"));
+
+        let upload = receive(body, dir, 16, 16).await.unwrap();
+        assert_eq!(3, upload.len);
+        assert_eq!(hex::encode(sha2::Sha256::digest(b"abc")), upload.sha256);
+        assert_eq!(b"abc", &*upload.map().unwrap());
+
+        let path = upload.path.clone();
+        drop(upload);
+        assert!(!path.exists());
+
+        let truncated = Body::from(&b"\x02\x00\x00\x00{}"[..]);
+        let e = receive(truncated, dir, 16, 16).await.unwrap_err();
+        assert_eq!("E_BAD_REQUEST", e.code());
+    }
+
+    #[tokio::test]
+    async fn whole_bodies_are_written_up_to_their_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let data = b"toolchain";
+        let upload = receive_file(Body::from(&data[..]), dir, 9).await.unwrap();
+        assert_eq!(9, upload.len);
+        assert_eq!(hex::encode(sha2::Sha256::digest(data)), upload.sha256);
+        assert_eq!(data, &*upload.map().unwrap());
+
+        let longer = Body::from("toolchains");
+        let e = receive_file(longer, dir, 9).await.unwrap_err();
+        assert_eq!("E_UPLOAD_TOO_LARGE", e.code());
+        drop(upload);
+        assert_eq!(0, fs::read_dir(dir).unwrap().count());
+    }
+}
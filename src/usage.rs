@@ -0,0 +1,336 @@
+//! Who downloads how much, for charging the costs back to teams.
+//!
+//! ```toml
+//! [tenant.usage]
+//! data-dir = "/var/lib/margo/acme"
+//!
+//! [tenant.usage.teams]
+//! platform = ["oidc:https://accounts.example.com/#1234", "token:3f2a9c81d04e7b65"]
+//!
+//! # Downloads per calendar month, in UTC
+//! [tenant.usage.quotas]
+//! platform = 100000
+//! ```
+//!
+//! Each `.crate` download made with a token counts against the token's
+//! user, and the team the user is listed in, for the month it happened
+//! in. Downloads without a token are not counted. The counts are kept
+//! in memory and written to `usage.json` in `data-dir` every minute, so
+//! up to a minute of them is lost if the daemon is killed.
+//!
+//! A team that has used up its quota is refused downloads until the
+//! month ends.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use crate::{auth::UserId, timestamp::Timestamp};
+
+pub const FILE_NAME: &str = "usage.json";
+
+/// What users in no team are counted under.
+pub const NO_TEAM: &str = "(none)";
+
+/// How often counts are written to disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UsageConfig {
+    data_dir: PathBuf,
+
+    /// The users in each team.
+    #[serde(default)]
+    teams: BTreeMap<String, BTreeSet<UserId>>,
+
+    /// The most downloads each team may make in a month.
+    #[serde(default)]
+    quotas: BTreeMap<String, u64>,
+}
+
+/// Downloads by month, as in `2026-10`, then team.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Usage(BTreeMap<String, BTreeMap<String, TeamUsage>>);
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TeamUsage {
+    pub downloads: u64,
+    pub users: BTreeMap<UserId, u64>,
+}
+
+impl Usage {
+    pub fn report(&self, month: &str, quotas: &BTreeMap<String, u64>) -> Report {
+        let teams = self.0.get(month).cloned().unwrap_or_default();
+        let teams = teams
+            .into_iter()
+            .map(|(team, usage)| TeamReport {
+                quota: quotas.get(&team).copied(),
+                team,
+                downloads: usage.downloads,
+                users: usage.users,
+            })
+            .collect();
+
+        Report {
+            month: month.to_owned(),
+            teams,
+        }
+    }
+
+    fn downloads_of(&self, month: &str, team: &str) -> u64 {
+        self.0
+            .get(month)
+            .and_then(|teams| teams.get(team))
+            .map_or(0, |usage| usage.downloads)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub month: String,
+    pub teams: Vec<TeamReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TeamReport {
+    pub team: String,
+    pub downloads: u64,
+    pub quota: Option<u64>,
+    pub users: BTreeMap<UserId, u64>,
+}
+
+/// The month `time` is in, as in `2026-10`.
+pub fn month_of(time: Timestamp) -> String {
+    // `2026-10-15T12:00:00Z`
+    time.to_string()[..7].to_owned()
+}
+
+#[derive(Debug)]
+pub struct Accountant {
+    path: PathBuf,
+    teams: BTreeMap<UserId, String>,
+    quotas: BTreeMap<String, u64>,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug)]
+struct Counts {
+    usage: Usage,
+
+    /// Whether there are counts that are not on disk yet.
+    dirty: bool,
+}
+
+impl Accountant {
+    pub fn new(config: UsageConfig) -> Result<Self, Error> {
+        use error::*;
+
+        let UsageConfig {
+            data_dir,
+            teams: members,
+            quotas,
+        } = config;
+
+        let mut teams = BTreeMap::new();
+        for (team, users) in members {
+            for user in users {
+                if let Some(other) = teams.insert(user.clone(), team.clone()) {
+                    return TwoTeamsSnafu { user, team, other }.fail();
+                }
+            }
+        }
+
+        fs::create_dir_all(&data_dir).context(DataDirSnafu { path: &data_dir })?;
+        let path = data_dir.join(FILE_NAME);
+        let usage = read(&path)?;
+
+        Ok(Self {
+            path,
+            teams,
+            quotas,
+            counts: Mutex::new(Counts {
+                usage,
+                dirty: false,
+            }),
+        })
+    }
+
+    fn team_of(&self, user: &UserId) -> &str {
+        self.teams.get(user).map_or(NO_TEAM, String::as_str)
+    }
+
+    /// Whether the user's team has downloads left in the month `now` is
+    /// in.
+    pub fn allows(&self, user: &UserId, now: Timestamp) -> bool {
+        let team = self.team_of(user);
+        let Some(&quota) = self.quotas.get(team) else {
+            return true;
+        };
+
+        let counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        counts.usage.downloads_of(&month_of(now), team) < quota
+    }
+
+    pub fn record(&self, user: &UserId, now: Timestamp) {
+        let team = self.team_of(user).to_owned();
+
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let usage = counts
+            .usage
+            .0
+            .entry(month_of(now))
+            .or_default()
+            .entry(team)
+            .or_default();
+        usage.downloads += 1;
+        *usage.users.entry(user.clone()).or_default() += 1;
+        counts.dirty = true;
+    }
+
+    pub fn report(&self, month: &str) -> Report {
+        let counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        counts.usage.report(month, &self.quotas)
+    }
+
+    /// Writes the counts to disk every so often until the daemon
+    /// stops.
+    pub fn start(self: Arc<Self>) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(FLUSH_INTERVAL);
+            if let Err(e) = self.flush() {
+                eprintln!("Warning: {e}");
+            }
+        });
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        if !counts.dirty {
+            return Ok(());
+        }
+
+        write(&self.path, &counts.usage)?;
+        counts.dirty = false;
+        Ok(())
+    }
+}
+
+/// A missing file has no downloads.
+pub fn read(path: &Path) -> Result<Usage, Error> {
+    use error::*;
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Usage::default()),
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+
+    serde_json::from_slice(&data).context(ParseSnafu { path })
+}
+
+fn write(path: &Path, usage: &Usage) -> Result<(), Error> {
+    use error::*;
+
+    let data = serde_json::to_vec_pretty(usage).context(SerializeSnafu)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, path).context(WriteSnafu { path })
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("`{user}` is in both team `{other}` and team `{team}`"))]
+    TwoTeams {
+        user: UserId,
+        team: String,
+        other: String,
+    },
+
+    #[snafu(display("Could not create the usage data directory {}", path.display()))]
+    DataDir { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the download usage in {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the download usage"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::TwoTeams { .. } => "E_CONFIG_INVALID",
+            Self::DataDir { .. } | Self::Write { .. } => "E_STORAGE_WRITE",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_USAGE_CORRUPT",
+            Self::Serialize { .. } => "E_INTERNAL",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn downloads_count_against_the_team_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let config = toml::from_str(&format!(
+            r#"
+            data-dir = "{}"
+
+            [teams]
+            platform = ["token:aaaa", "token:bbbb"]
+
+            [quotas]
+            platform = 2
+            "#,
+            dir.display(),
+        ))
+        .unwrap();
+        let accountant = Arc::new(Accountant::new(config).unwrap());
+
+        let user = |id: &str| serde_json::from_value::<UserId>(id.into()).unwrap();
+        let october = "2026-10-15T12:00:00Z".parse().unwrap();
+        let november = "2026-11-01T00:00:00Z".parse().unwrap();
+
+        assert!(accountant.allows(&user("token:aaaa"), october));
+        accountant.record(&user("token:aaaa"), october);
+        accountant.record(&user("token:bbbb"), october);
+        accountant.record(&user("token:cccc"), october);
+        assert!(!accountant.allows(&user("token:aaaa"), october));
+        assert!(accountant.allows(&user("token:aaaa"), november));
+        assert!(accountant.allows(&user("token:cccc"), october));
+
+        accountant.flush().unwrap();
+        let report = read(&dir.join(FILE_NAME))
+            .unwrap()
+            .report("2026-10", &Default::default());
+        let downloads = report
+            .teams
+            .iter()
+            .map(|t| (t.team.as_str(), t.downloads))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(NO_TEAM, 1), ("platform", 2)], downloads);
+    }
+}
@@ -0,0 +1,290 @@
+//! The checks an announcement passes before the node acts on it.
+//!
+//! Announcements arrive from peers over gossipsub and from nostr relays.
+//! Each goes through the same steps, in order, and the first one that
+//! fails rejects it:
+//!
+//! 1. **schema**: the payload parses as an announcement, of a
+//!    [kind](crate::payload) this node knows, and names a crate by the
+//!    [strict rules](crate::common::CrateName::from_untrusted) for names from peers;
+//! 2. **signature**: it is signed by the key it claims to come from;
+//! 3. **trust**: when a list of trusted signers is configured, the
+//!    signer is on it;
+//! 4. **dedupe**: the same announcement, such as the same crate name,
+//!    version and checksum, was not accepted recently.
+//!
+//! Only then is an announcement shown, cached or forwarded. Rejects are
+//! counted by the step that rejected them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    fmt,
+};
+
+#[cfg(feature = "p2p")]
+use crate::{
+    common::CrateName,
+    payload::{Body, CrateAnnouncement, Payload},
+};
+
+/// How many accepted announcements are remembered to recognize
+/// duplicates.
+const MAX_SEEN: usize = 10_000;
+
+/// What an announcement is about. Two announcements about the same
+/// thing are duplicates.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    /// A registry's git HEAD.
+    #[cfg(feature = "p2p")]
+    Commit(String),
+
+    /// A newly available crate version.
+    #[cfg(feature = "p2p")]
+    Crate(CrateAnnouncement),
+
+    /// A registry listed on a nostr relay, by event ID.
+    #[cfg(feature = "nostr")]
+    Listing(String),
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "p2p")]
+            Self::Commit(commit) => commit.fmt(f),
+            #[cfg(feature = "p2p")]
+            Self::Crate(c) => write!(f, "{} {} ({})", c.name, c.vers, c.cksum),
+            #[cfg(feature = "nostr")]
+            Self::Listing(id) => write!(f, "listing {id}"),
+        }
+    }
+}
+
+/// Parses a gossiped [payload](crate::payload). A bare commit hash, as
+/// sent before payloads were versioned, is read as a `head` payload.
+#[cfg(feature = "p2p")]
+pub fn parse_gossip(data: &[u8]) -> Result<Subject, Reject> {
+    let legacy = std::str::from_utf8(data)
+        .ok()
+        .map(str::trim)
+        .filter(|t| is_hex(t, &[40, 64]));
+    if let Some(commit) = legacy {
+        return Ok(Subject::Commit(commit.to_owned()));
+    }
+
+    let payload = Payload::parse(data).ok_or(Reject::Schema)?;
+    match payload.body {
+        Body::Head { commit } if is_hex(&commit, &[40, 64]) => Ok(Subject::Commit(commit)),
+        Body::Crate(c) if is_hex(&c.cksum, &[64]) && CrateName::from_untrusted(&c.name).is_ok() => {
+            Ok(Subject::Crate(c))
+        }
+        Body::Unknown => Err(Reject::Unsupported),
+        _ => Err(Reject::Schema),
+    }
+}
+
+#[cfg(feature = "p2p")]
+fn is_hex(s: &str, lens: &[usize]) -> bool {
+    lens.contains(&s.len()) && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reject {
+    Schema,
+    /// A kind of payload from a newer version. It is not malformed, so
+    /// the sender is not penalized, but it is not passed on either.
+    Unsupported,
+    Signature,
+    Untrusted,
+    Duplicate,
+}
+
+impl Reject {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Schema => "schema",
+            Self::Unsupported => "unsupported",
+            Self::Signature => "signature",
+            Self::Untrusted => "untrusted",
+            Self::Duplicate => "duplicate",
+        }
+    }
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Validator {
+    /// Signers whose announcements are accepted. Empty accepts any.
+    trusted: BTreeSet<String>,
+
+    seen: HashSet<Subject>,
+    seen_order: VecDeque<Subject>,
+
+    rejects: BTreeMap<Reject, u64>,
+}
+
+impl Validator {
+    pub fn new(trusted: BTreeSet<String>) -> Self {
+        Self {
+            trusted,
+            ..Self::default()
+        }
+    }
+
+    /// Replaces the trusted signers, as when the configuration is
+    /// reloaded.
+    #[cfg(feature = "p2p")]
+    pub fn trust(&mut self, trusted: BTreeSet<String>) {
+        self.trusted = trusted;
+    }
+
+    /// Also trusts the keys that trusted signers
+    /// [rotated](crate::key_rotation) to.
+    #[cfg(feature = "nostr")]
+    pub fn follow(&mut self, rotations: &[crate::key_rotation::Rotation]) {
+        crate::key_rotation::follow(&mut self.trusted, rotations);
+    }
+
+    /// Runs an announcement through every step. `subject` is the result
+    /// of parsing the payload, and `signer` is `None` when the signature
+    /// is missing or wrong.
+    pub fn check(
+        &mut self,
+        signer: Option<&str>,
+        subject: Result<Subject, Reject>,
+    ) -> Result<Subject, Reject> {
+        let result = self.steps(signer, subject);
+        if let Err(reject) = result {
+            *self.rejects.entry(reject).or_default() += 1;
+        }
+
+        result
+    }
+
+    fn steps(
+        &mut self,
+        signer: Option<&str>,
+        subject: Result<Subject, Reject>,
+    ) -> Result<Subject, Reject> {
+        let subject = subject?;
+        let signer = signer.ok_or(Reject::Signature)?;
+
+        if !self.trusted.is_empty() && !self.trusted.contains(signer) {
+            return Err(Reject::Untrusted);
+        }
+
+        if !self.seen.insert(subject.clone()) {
+            return Err(Reject::Duplicate);
+        }
+        self.seen_order.push_back(subject.clone());
+        if self.seen_order.len() > MAX_SEEN {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        Ok(subject)
+    }
+
+    /// One line summing up the rejects, such as
+    /// `3 announcement(s) rejected (2 duplicate, 1 untrusted)`.
+    pub fn summary(&self) -> Option<String> {
+        let total = self.rejects.values().sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+
+        let reasons = self
+            .rejects
+            .iter()
+            .map(|(reject, n)| format!("{n} {reject}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        Some(format!("{total} announcement(s) rejected ({reasons})"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "p2p")]
+    #[test]
+    fn announcements_pass_each_step_in_order() {
+        let commit = "ab".repeat(20);
+        let mut validator = Validator::new(["trusted".to_owned()].into());
+
+        let parse = |data: &str| Ok(Subject::Commit(data.to_owned()));
+        assert_eq!(
+            Err(Reject::Schema),
+            validator.check(Some("trusted"), Err(Reject::Schema))
+        );
+        assert_eq!(
+            Err(Reject::Signature),
+            validator.check(None, parse(&commit))
+        );
+        assert_eq!(
+            Err(Reject::Untrusted),
+            validator.check(Some("other"), parse(&commit))
+        );
+        assert!(validator.check(Some("trusted"), parse(&commit)).is_ok());
+        assert_eq!(
+            Err(Reject::Duplicate),
+            validator.check(Some("trusted"), parse(&commit))
+        );
+
+        assert_eq!(
+            Some("4 announcement(s) rejected (1 schema, 1 signature, 1 untrusted, 1 duplicate)"),
+            validator.summary().as_deref()
+        );
+    }
+
+    #[cfg(feature = "p2p")]
+    #[test]
+    fn gossip_is_a_commit_or_a_crate() {
+        let commit = "ab".repeat(20);
+        assert_eq!(
+            Ok(Subject::Commit(commit.clone())),
+            parse_gossip(commit.as_bytes())
+        );
+        let head = Payload::new(Body::Head {
+            commit: commit.clone(),
+        });
+        assert_eq!(Ok(Subject::Commit(commit)), parse_gossip(&head.to_vec()));
+
+        let cksum = "cd".repeat(32);
+        let json =
+            format!(r#"{{"v":1,"kind":"crate","name":"demo","vers":"1.0.0","cksum":"{cksum}"}}"#);
+        assert!(matches!(
+            parse_gossip(json.as_bytes()),
+            Ok(Subject::Crate(_))
+        ));
+
+        for name in [
+            "../../etc/passwd",
+            "dēmo",
+            "xn--dmo-6na",
+            "con",
+            &"a".repeat(65),
+        ] {
+            let json = format!(
+                r#"{{"v":1,"kind":"crate","name":"{name}","vers":"1.0.0","cksum":"{cksum}"}}"#
+            );
+            assert_eq!(Err(Reject::Schema), parse_gossip(json.as_bytes()), "{name}");
+        }
+
+        let short = r#"{"v":1,"kind":"crate","name":"demo","vers":"1.0.0","cksum":"cd"}"#;
+        assert_eq!(Err(Reject::Schema), parse_gossip(short.as_bytes()));
+        assert_eq!(Err(Reject::Schema), parse_gossip(b"hello"));
+
+        let newer = r#"{"v":2,"kind":"owner-change"}"#;
+        assert_eq!(Err(Reject::Unsupported), parse_gossip(newer.as_bytes()));
+    }
+}
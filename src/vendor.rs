@@ -0,0 +1,271 @@
+//! Vendoring a lockfile's crates out of the registry.
+//!
+//! `margo vendor` writes the same layout as `cargo vendor`: one
+//! directory per package, `{name}-{version}`, holding the unpacked
+//! package and a `.cargo-checksum.json` with the SHA-256 of every file
+//! and of the `.crate` file itself. Cargo checks the latter against the
+//! lockfile, so each `.crate` file is checked here first.
+
+use semver::Version;
+use serde::Serialize;
+use sha2::Digest;
+use snafu::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{common::CrateName, extract, lockfile::Lockfile, Registry};
+
+pub const CHECKSUM_FILE_NAME: &str = ".cargo-checksum.json";
+
+/// The name `cargo vendor` gives the replacement source.
+const SOURCE_NAME: &str = "vendored-sources";
+
+const CRATES_IO_SOURCE: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+#[derive(Debug, Serialize)]
+struct Checksums {
+    files: BTreeMap<String, String>,
+    package: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Vendored {
+    pub packages: usize,
+
+    /// The lockfile sources that the vendored packages replace.
+    pub sources: BTreeSet<String>,
+}
+
+/// Unpacks every registry package of the lockfile into `out`, replacing
+/// any previous copy.
+pub fn vendor(registry: &Registry, lockfile: &Path, out: &Path) -> Result<Vendored, Error> {
+    use error::*;
+
+    let lockfile = Lockfile::read(lockfile).context(LockfileSnafu)?;
+
+    let mut vendored = Vendored::default();
+    for package in lockfile.package.iter().filter(|p| p.from_registry()) {
+        registry
+            .promote(&package.name, &package.version)
+            .context(PromoteSnafu)?;
+        let path = registry.crate_file_path_for(&package.name, &package.version);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return MissingSnafu {
+                    name: package.name.clone(),
+                    version: package.version.clone(),
+                }
+                .fail()
+            }
+            Err(e) => return Err(e).context(ReadSnafu { path }),
+        };
+
+        let cksum = hex::encode(sha2::Sha256::digest(&data));
+        if let Some(locked) = &package.checksum {
+            ensure!(
+                locked.eq_ignore_ascii_case(&cksum),
+                ChecksumSnafu {
+                    name: package.name.clone(),
+                    version: package.version.clone(),
+                    locked,
+                    actual: cksum,
+                }
+            );
+        }
+
+        let limits = registry.unpack_limits();
+        unpack(&package.name, &package.version, &data, cksum, &limits, out)?;
+
+        vendored.packages += 1;
+        vendored.sources.extend(package.source.clone());
+    }
+
+    Ok(vendored)
+}
+
+fn unpack(
+    name: &CrateName,
+    version: &Version,
+    data: &[u8],
+    cksum: String,
+    limits: &extract::Limits,
+    out: &Path,
+) -> Result<(), Error> {
+    use error::*;
+
+    let prefix = format!("{name}-{version}");
+    let package_dir = out.join(&prefix);
+    match fs::remove_dir_all(&package_dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(WriteSnafu { path: package_dir }),
+    }
+
+    let mut checksums = Checksums {
+        files: BTreeMap::new(),
+        package: cksum,
+    };
+
+    let files = extract::files(data, &prefix, limits).context(UnpackSnafu { name: &prefix })?;
+
+    // Ours would overwrite it
+    if let Some(file) = files.iter().find(|f| f.path == CHECKSUM_FILE_NAME) {
+        return PathSnafu {
+            name: &prefix,
+            path: &file.path,
+        }
+        .fail();
+    }
+
+    extract::write(&files, &package_dir).context(UnpackSnafu { name: &prefix })?;
+    for file in files {
+        let digest = hex::encode(sha2::Sha256::digest(&file.data));
+        checksums.files.insert(file.path, digest);
+    }
+
+    let path = package_dir.join(CHECKSUM_FILE_NAME);
+    let checksums = serde_json::to_vec(&checksums).context(SerializeSnafu)?;
+    fs::create_dir_all(&package_dir).context(WriteSnafu { path: &package_dir })?;
+    fs::write(&path, checksums).context(WriteSnafu { path })
+}
+
+/// The `.cargo/config.toml` that points Cargo at the vendored packages
+/// instead of the registries they came from.
+pub fn cargo_config(sources: &BTreeSet<String>, out: &Path) -> String {
+    let mut config = String::new();
+
+    for source in sources {
+        if source == CRATES_IO_SOURCE {
+            _ = writeln!(config, "[source.crates-io]");
+        } else {
+            let url = source.strip_prefix("registry+").unwrap_or(source);
+            _ = writeln!(config, "[source.{source:?}]");
+            _ = writeln!(config, "registry = {url:?}");
+        }
+        _ = writeln!(config, "replace-with = {SOURCE_NAME:?}");
+        _ = writeln!(config);
+    }
+
+    _ = writeln!(config, "[source.{SOURCE_NAME}]");
+    _ = write!(config, "directory = {:?}", out.display().to_string());
+
+    config
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Could not read the lockfile"))]
+    Lockfile { source: crate::lockfile::Error },
+
+    #[snafu(display("{name} {version} is not in the registry"))]
+    Missing { name: CrateName, version: Version },
+
+    #[snafu(display("Could not bring the crate back from the cold tier"))]
+    Promote { source: crate::tier::Error },
+
+    #[snafu(display("Could not read the crate file {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display(
+        "The checksum of {name} {version} is {actual} in the registry but {locked} in the lockfile"
+    ))]
+    Checksum {
+        name: CrateName,
+        version: Version,
+        locked: String,
+        actual: String,
+    },
+
+    #[snafu(display("Could not unpack {name}"))]
+    Unpack {
+        source: extract::Error,
+        name: String,
+    },
+
+    #[snafu(display("{name} contains the unexpected file `{path}`"))]
+    Path { name: String, path: String },
+
+    #[snafu(display("Could not serialize the checksums"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Lockfile { source } => source.code(),
+            Self::Missing { .. } => "E_VERSION_NOT_FOUND",
+            Self::Promote { source } => source.code(),
+            Self::Read { .. } => "E_CRATE_READ",
+            Self::Checksum { .. } => "E_LOCKFILE_MISMATCH",
+            Self::Unpack { source, .. } => source.code(),
+            Self::Path { .. } => "E_BAD_CRATE",
+            Self::Serialize { .. } => "E_INTERNAL",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn packages_cannot_bring_their_own_checksums() {
+        let package = |path: &str| {
+            let gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            let mut tar = tar::Builder::new(gz);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(2);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, &b"{}"[..]).unwrap();
+            tar.into_inner().unwrap().finish().unwrap()
+        };
+
+        let out = tempfile::tempdir().unwrap();
+        let out = out.path();
+        let name = "demo".parse().unwrap();
+        let version = Version::new(1, 0, 0);
+        let limits = extract::Limits::default();
+
+        let data = package("demo-1.0.0/.cargo-checksum.json");
+        let e = unpack(&name, &version, &data, "00".into(), &limits, out).unwrap_err();
+        assert!(matches!(e, Error::Path { .. }), "{e:?}");
+
+        let data = package("demo-1.0.0/Cargo.toml");
+        unpack(&name, &version, &data, "00".into(), &limits, out).unwrap();
+        assert!(out.join("demo-1.0.0").join(CHECKSUM_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn every_source_is_replaced() {
+        let sources = [
+            CRATES_IO_SOURCE.to_owned(),
+            "sparse+https://registry.example.com/".to_owned(),
+        ]
+        .into();
+
+        assert_eq!(
+            concat!(
+                "[source.crates-io]\n",
+                "replace-with = \"vendored-sources\"\n",
+                "\n",
+                "[source.\"sparse+https://registry.example.com/\"]\n",
+                "registry = \"sparse+https://registry.example.com/\"\n",
+                "replace-with = \"vendored-sources\"\n",
+                "\n",
+                "[source.vendored-sources]\n",
+                "directory = \"vendor\"",
+            ),
+            cargo_config(&sources, Path::new("vendor")),
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Who may see each crate.
+//!
+//! Every crate is `public` until an owner or the operator says otherwise.
+//! An `internal` crate is only served to requests with a valid token,
+//! even by a tenant that needs none, and a `private` one only to its
+//! owners and admins. The levels are kept in `visibility.json` at the
+//! root of the registry, which the daemon does not serve.
+//!
+//! The daemon checks the level of the crate that an index file, a
+//! `.crate` file, or any other file of a crate belongs to before serving
+//! it, and leaves the crates a reader may not see out of
+//! `/api/v1/crates`, searches, and the dashboard. Crates that are not
+//! public are also left out of the static HTML index and the feed, and
+//! their files are not handed to P2P peers.
+
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::common::CrateName;
+
+pub const FILE_NAME: &str = "visibility.json";
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum Visibility {
+    #[default]
+    Public,
+
+    /// Readable with any valid token.
+    Internal,
+
+    /// Readable by the crate's owners and admins.
+    Private,
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Public => "public",
+            Self::Internal => "internal",
+            Self::Private => "private",
+        })
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Self::Public),
+            "internal" => Ok(Self::Internal),
+            "private" => Ok(Self::Private),
+            _ => error::LevelSnafu { level: s }.fail(),
+        }
+    }
+}
+
+/// The crates that are not public.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Levels(BTreeMap<CrateName, Visibility>);
+
+impl Levels {
+    pub fn of(&self, name: &str) -> Visibility {
+        self.0
+            .iter()
+            .find(|(n, _)| n.as_str().eq_ignore_ascii_case(name))
+            .map_or(Visibility::Public, |(_, &v)| v)
+    }
+
+    pub fn is_public(&self, name: &str) -> bool {
+        self.of(name) == Visibility::Public
+    }
+
+    /// Whether every crate is public.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&CrateName, Visibility)> {
+        self.0.iter().map(|(n, &v)| (n, v))
+    }
+}
+
+/// A missing file leaves every crate public.
+pub fn read(path: &Path) -> Result<Levels, Error> {
+    use error::*;
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Levels::default()),
+        Err(e) => return Err(e).context(ReadSnafu { path }),
+    };
+
+    serde_json::from_slice(&data).context(ParseSnafu { path })
+}
+
+/// Returns whether the level changed.
+pub fn set(path: &Path, name: &CrateName, visibility: Visibility) -> Result<bool, Error> {
+    let mut levels = read(path)?;
+    if levels.of(name.as_str()) == visibility {
+        return Ok(false);
+    }
+
+    levels
+        .0
+        .retain(|n, _| !n.as_str().eq_ignore_ascii_case(name.as_str()));
+    if visibility != Visibility::Public {
+        levels.0.insert(name.clone(), visibility);
+    }
+
+    write(path, &levels)?;
+    Ok(true)
+}
+
+fn write(path: &Path, levels: &Levels) -> Result<(), Error> {
+    use error::*;
+
+    let data = serde_json::to_vec_pretty(levels).context(SerializeSnafu)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data).context(WriteSnafu { path: &tmp })?;
+    fs::rename(&tmp, path).context(WriteSnafu { path })
+}
+
+/// The crate that `relative`, a path within the registry, belongs to:
+/// an index file, anything below the crate's directory in `crates/`, or
+/// its documentation.
+#[cfg(any(feature = "p2p", feature = "server"))]
+pub fn crate_of(relative: &str) -> Option<&str> {
+    let segments = relative
+        .trim_start_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+
+    match segments.as_slice() {
+        [dir, name, _, ..] if *dir == crate::docs::DIR_NAME => Some(*name),
+        [dir, path @ ..] if *dir == crate::CRATE_DIR_NAME => match split_prefix(path)? {
+            (name, [_, ..]) => Some(name),
+            _ => None,
+        },
+        path => match split_prefix(path)? {
+            (name, []) => Some(name),
+            _ => None,
+        },
+    }
+}
+
+/// Splits the prefix directories off the path of a crate's index file
+/// or directory.
+#[cfg(any(feature = "p2p", feature = "server"))]
+fn split_prefix<'a, 'b>(path: &'b [&'a str]) -> Option<(&'a str, &'b [&'a str])> {
+    let starts_with = |name: &str, range: std::ops::Range<usize>, prefix: &str| {
+        name.get(range)
+            .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+    };
+
+    match path {
+        ["1", name, rest @ ..] if name.len() == 1 => Some((*name, rest)),
+        ["2", name, rest @ ..] if name.len() == 2 => Some((*name, rest)),
+        ["3", a, name, rest @ ..] if name.len() == 3 && starts_with(*name, 0..1, *a) => {
+            Some((*name, rest))
+        }
+        [ab, cd, name, rest @ ..]
+            if name.len() >= 4
+                && starts_with(*name, 0..2, *ab)
+                && starts_with(*name, 2..4, *cd) =>
+        {
+            Some((*name, rest))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("`{level}` is not `public`, `internal`, or `private`"))]
+    Level { level: String },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse the crate visibility in {}", path.display()))]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Could not serialize the crate visibility"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Level { .. } => "E_BAD_VISIBILITY",
+            Self::Read { .. } => "E_STORAGE_READ",
+            Self::Parse { .. } => "E_VISIBILITY_CORRUPT",
+            Self::Serialize { .. } => "E_INTERNAL",
+            Self::Write { .. } => "E_STORAGE_WRITE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels_are_kept_for_crates_that_are_not_public() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join(FILE_NAME);
+        let name: CrateName = "Secret_Sauce".parse().unwrap();
+
+        assert!(set(&path, &name, Visibility::Private).unwrap());
+        assert!(!set(&path, &name, Visibility::Private).unwrap());
+        assert_eq!(Visibility::Private, read(&path).unwrap().of("secret_sauce"));
+
+        assert!(set(&path, &name, Visibility::Public).unwrap());
+        assert!(read(&path).unwrap().is_empty());
+    }
+
+    #[cfg(any(feature = "p2p", feature = "server"))]
+    #[test]
+    fn files_are_traced_to_their_crate() {
+        assert_eq!(Some("a"), crate_of("/1/a"));
+        assert_eq!(Some("ab"), crate_of("2/ab"));
+        assert_eq!(Some("abc"), crate_of("3/a/abc"));
+        assert_eq!(Some("serde"), crate_of("/se/rd/serde"));
+        assert_eq!(Some("serde"), crate_of("crates/se/rd/serde/1.0.0.crate"));
+        assert_eq!(Some("abc"), crate_of("crates/3/a/abc/0.1.0.readme.html"));
+        assert_eq!(Some("serde"), crate_of("docs/serde/1.0.0/index.html"));
+
+        assert_eq!(None, crate_of("config.json"));
+        assert_eq!(None, crate_of("assets/ab/style.css"));
+        assert_eq!(None, crate_of("se/rd/serde/extra"));
+        assert_eq!(None, crate_of("crates/se/rd/serde"));
+    }
+}
@@ -69,5 +69,14 @@ cargo build
 echo "==> Running test-consumer..."
 cargo run
 
+# ── Step 7: Generate and build a consumer ─────────────────────────
+echo "==> Generating a consumer with new-consumer..."
+"$MARGO" new-consumer \
+    --registry "$REGISTRY_DIR" \
+    --name test-margo \
+    --out "$WORK_DIR/gnostr-consumer" \
+    --build \
+    gnostr
+
 echo ""
 echo "=== All tests passed ==="
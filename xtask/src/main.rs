@@ -8,7 +8,7 @@ use std::{
     process::Command,
     sync::mpsc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use toml_edit::{DocumentMut, Item};
 
@@ -23,6 +23,9 @@ struct Args {
 #[argh(subcommand)]
 enum Subcommand {
     Assets(AssetsArgs),
+    BenchDownload(BenchDownloadArgs),
+    BenchIndex(BenchIndexArgs),
+    FfiHeader(FfiHeaderArgs),
     PrepareRelease(PrepareReleaseArgs),
 }
 
@@ -36,6 +39,54 @@ struct AssetsArgs {
     watch: bool,
 }
 
+/// Time downloading one large file from a running registry, several
+/// times at once, and report how much memory the daemon used
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "bench-download")]
+struct BenchDownloadArgs {
+    /// the file's URL, such as http://127.0.0.1:8080/api/v1/blobs/{digest}
+    #[argh(positional)]
+    url: String,
+
+    /// how many downloads to run at once
+    #[argh(option, default = "4")]
+    parallel: u32,
+
+    /// how many times to run the downloads
+    #[argh(option, default = "3")]
+    rounds: u32,
+
+    /// the daemon's process ID, to read its memory use from /proc
+    #[argh(option)]
+    pid: Option<u32>,
+}
+
+/// Time fetching the index files of a lockfile's packages from a
+/// running registry over HTTP/1.1 and HTTP/2
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "bench-index")]
+struct BenchIndexArgs {
+    /// the index URL, such as http://127.0.0.1:8080/
+    #[argh(positional)]
+    index: String,
+
+    /// the lockfile listing the packages
+    #[argh(option, default = "PathBuf::from(\"Cargo.lock\")")]
+    lockfile: PathBuf,
+
+    /// how many times to fetch the files with each protocol
+    #[argh(option, default = "5")]
+    rounds: u32,
+}
+
+/// Regenerate the C header of the `ffi` crate
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+#[argh(name = "ffi-header")]
+struct FfiHeaderArgs {}
+
 /// Prepare a release
 #[derive(Debug, argh::FromArgs)]
 #[argh(subcommand)]
@@ -51,6 +102,9 @@ fn main() -> Result<(), Error> {
 
     match args.subcommand {
         Subcommand::Assets(args) => do_assets(args)?,
+        Subcommand::BenchDownload(args) => do_bench_download(args)?,
+        Subcommand::BenchIndex(args) => do_bench_index(args)?,
+        Subcommand::FfiHeader(args) => do_ffi_header(args)?,
         Subcommand::PrepareRelease(args) => do_prepare_release(args)?,
     }
 
@@ -62,6 +116,15 @@ enum Error {
     #[snafu(transparent)]
     Assets { source: AssetsError },
 
+    #[snafu(transparent)]
+    BenchDownload { source: BenchDownloadError },
+
+    #[snafu(transparent)]
+    BenchIndex { source: BenchIndexError },
+
+    #[snafu(transparent)]
+    FfiHeader { source: FfiHeaderError },
+
     #[snafu(transparent)]
     PrepareRelease { source: PrepareReleaseError },
 }
@@ -375,6 +438,227 @@ macro_rules! join {
 }
 use join;
 
+fn do_bench_download(args: BenchDownloadArgs) -> Result<(), BenchDownloadError> {
+    use bench_download_error::*;
+
+    let BenchDownloadArgs {
+        url,
+        parallel,
+        rounds,
+        pid,
+    } = args;
+
+    ensure!(rounds > 0 && parallel > 0, RoundsSnafu);
+
+    // Fetching it once to disk gives its size, and warms the page cache
+    let sample = env::temp_dir().join("margo-bench-download.bin");
+    curl!("--silent", "--fail", "--output", &sample, &url).context(CurlSnafu)?;
+    let len = fs::metadata(&sample)
+        .context(ReadSnafu { path: &sample })?
+        .len();
+    fs::remove_file(&sample).context(WriteSnafu { path: &sample })?;
+
+    let config = env::temp_dir().join("margo-bench-download.curl");
+    let urls = format!("url = \"{url}\"\noutput = \"/dev/null\"\n").repeat(parallel as usize);
+    fs::write(&config, urls).context(WriteSnafu { path: &config })?;
+
+    println!(
+        "Downloading {} MiB from {url}, {parallel} at a time, {rounds} times",
+        len / (1024 * 1024),
+    );
+
+    let mut times = Vec::new();
+    for _ in 0..rounds {
+        let start = Instant::now();
+        curl!(
+            "--silent",
+            "--fail",
+            "--parallel",
+            "--parallel-max",
+            parallel.to_string(),
+            "--config",
+            &config,
+        )
+        .context(CurlSnafu)?;
+        times.push(start.elapsed());
+    }
+
+    let best = times.iter().min().copied().unwrap_or_default();
+    let mean = times.iter().sum::<Duration>() / rounds;
+    let throughput = (len * u64::from(parallel)) as f64 / best.as_secs_f64() / (1024.0 * 1024.0);
+    println!("best {best:>10.3?}  mean {mean:>10.3?}  {throughput:>8.1} MiB/s");
+
+    // The peak resident memory, which should stay far below the file's
+    // size however many downloads run at once
+    if let Some(pid) = pid {
+        let path = PathBuf::from(format!("/proc/{pid}/status"));
+        let status = fs::read_to_string(&path).context(ReadSnafu { path: &path })?;
+        for line in status.lines() {
+            if line.starts_with("VmHWM:") || line.starts_with("VmRSS:") {
+                println!("{line}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum BenchDownloadError {
+    #[snafu(display("At least one download must be run"))]
+    Rounds,
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not write {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not download the file"))]
+    Curl { source: CurlError },
+}
+
+fn do_bench_index(args: BenchIndexArgs) -> Result<(), BenchIndexError> {
+    use bench_index_error::*;
+
+    let BenchIndexArgs {
+        index,
+        lockfile,
+        rounds,
+    } = args;
+
+    ensure!(rounds > 0, RoundsSnafu);
+    let index = index.trim_end_matches('/');
+
+    let lock = fs::read_to_string(&lockfile).context(ReadSnafu { path: &lockfile })?;
+    let lock: DocumentMut = lock.parse().context(ParseSnafu { path: &lockfile })?;
+
+    // Path dependencies have no source and no index file
+    let mut paths = lock
+        .get("package")
+        .and_then(Item::as_array_of_tables)
+        .into_iter()
+        .flatten()
+        .filter(|p| p.contains_key("source"))
+        .filter_map(|p| p.get("name")?.as_str())
+        .map(index_path)
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    ensure!(!paths.is_empty(), PackagesSnafu { path: &lockfile });
+
+    // curl writes each response to the `output` following its `url`
+    let config = env::temp_dir().join("margo-bench-index.curl");
+    let urls = paths
+        .iter()
+        .map(|p| format!("url = \"{index}/{p}\"\noutput = \"/dev/null\"\n"))
+        .collect::<String>();
+    fs::write(&config, urls).context(WriteSnafu { path: &config })?;
+
+    println!(
+        "Fetching {} index files from {index}/, {rounds} times each",
+        paths.len(),
+    );
+
+    // What Cargo does: every file at once, multiplexed when possible
+    for (protocol, flag) in [
+        ("HTTP/1.1", "--http1.1"),
+        ("HTTP/2", "--http2-prior-knowledge"),
+    ] {
+        let mut times = Vec::new();
+        for _ in 0..rounds {
+            let start = Instant::now();
+            curl!(
+                "--silent",
+                "--parallel",
+                "--parallel-max",
+                "100",
+                flag,
+                "--config",
+                &config,
+            )
+            .context(CurlSnafu { protocol })?;
+            times.push(start.elapsed());
+        }
+
+        let best = times.iter().min().copied().unwrap_or_default();
+        let mean = times.iter().sum::<Duration>() / rounds;
+        println!("{protocol:<8}  best {best:>10.3?}  mean {mean:>10.3?}");
+    }
+
+    Ok(())
+}
+
+/// Where the index file of the package is, relative to the index root.
+fn index_path(name: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    match name.len() {
+        1 => format!("1/{name}"),
+        2 => format!("2/{name}"),
+        3 => format!("3/{}/{name}", &name[..1]),
+        _ => format!("{}/{}/{name}", &name[..2], &name[2..4]),
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum BenchIndexError {
+    #[snafu(display("At least one round must be run"))]
+    Rounds,
+
+    #[snafu(display("Could not read {}", path.display()))]
+    Read { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not parse {}", path.display()))]
+    Parse {
+        source: toml_edit::TomlError,
+        path: PathBuf,
+    },
+
+    #[snafu(display("{} lists no packages from a registry", path.display()))]
+    Packages { path: PathBuf },
+
+    #[snafu(display("Could not write the curl configuration {}", path.display()))]
+    Write { source: io::Error, path: PathBuf },
+
+    #[snafu(display("Could not fetch the index files over {protocol}"))]
+    Curl {
+        source: CurlError,
+        protocol: &'static str,
+    },
+}
+
+fn do_ffi_header(_args: FfiHeaderArgs) -> Result<(), FfiHeaderError> {
+    use ffi_header_error::*;
+
+    let root = env::var("CARGO_MANIFEST_DIR").context(CargoManifestSnafu)?;
+    let mut root = PathBuf::from(root);
+    root.pop(); // Exit the `xtask` directory
+
+    let crate_dir = join!(&root, "ffi");
+    let header = join!(&crate_dir, "include", "margo.h");
+
+    let config = cbindgen::Config::from_root_or_default(&crate_dir);
+    cbindgen::generate_with_config(&crate_dir, config)
+        .context(GenerateSnafu)?
+        .write_to_file(&header);
+
+    println!("Wrote {}", header.display());
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+enum FfiHeaderError {
+    #[snafu(display("`CARGO_MANIFEST_DIR` must be set"))]
+    CargoManifest { source: env::VarError },
+
+    #[snafu(display("Could not generate the header"))]
+    Generate { source: cbindgen::Error },
+}
+
 fn do_prepare_release(args: PrepareReleaseArgs) -> Result<(), PrepareReleaseError> {
     use prepare_release_error::*;
 
@@ -520,6 +804,20 @@ struct CargoError {
     source: ProcessError,
 }
 
+macro_rules! curl {
+    ($cmd:expr $(, $arg:expr)* $(,)?) => {
+        command!("curl", $cmd $(, $arg)*).map_err(CurlError::from)
+    };
+}
+use curl;
+
+#[derive(Debug, Snafu)]
+#[snafu(display("Executing `curl` failed"))]
+#[snafu(context(false))]
+struct CurlError {
+    source: ProcessError,
+}
+
 macro_rules! command {
     ($cmd:expr $(, $arg:expr)* $(,)?) => {
         (|| -> Result<(), ProcessError> {